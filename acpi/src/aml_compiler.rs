@@ -1204,7 +1204,7 @@ pub struct AmlRelease {
 }
 
 impl AmlRelease {
-    fn new<T: AmlBuilder>(mtx: T) -> AmlRelease {
+    pub fn new<T: AmlBuilder>(mtx: T) -> AmlRelease {
         AmlRelease {
             mutex: mtx.aml_bytes(),
         }
@@ -10,10 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use arc_swap::ArcSwap;
 use util::byte_code::ByteCode;
+use util::unix::host_page_size;
 
 use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::{
@@ -47,6 +50,10 @@ pub struct AddressSpace {
     listeners: Arc<Mutex<Vec<Box<dyn Listener>>>>,
     /// The current layout of ioeventfds, which is compared with new ones in topology-update stage.
     ioeventfds: Arc<Mutex<Vec<RegionIoEventFd>>>,
+    /// Whether the guest pages written by VMM are logged, used by live migration.
+    dirty_log: Arc<AtomicBool>,
+    /// Guest addresses of the pages written by VMM since dirty log is got last time.
+    dirty_pages: Arc<Mutex<BTreeSet<u64>>>,
 }
 
 impl AddressSpace {
@@ -61,6 +68,8 @@ impl AddressSpace {
             flat_view: ArcSwap::new(Arc::new(FlatView::default())),
             listeners: Arc::new(Mutex::new(Vec::new())),
             ioeventfds: Arc::new(Mutex::new(Vec::new())),
+            dirty_log: Arc::new(AtomicBool::new(false)),
+            dirty_pages: Arc::new(Mutex::new(BTreeSet::new())),
         });
 
         root.set_belonged_address_space(&space);
@@ -379,7 +388,12 @@ impl AddressSpace {
                     region_base.raw_value(),
                     offset_in_region,
                     count
-                ))
+                ))?;
+
+        if fr.owner.region_type() == RegionType::Ram {
+            self.mark_dirty(addr, count);
+        }
+        Ok(())
     }
 
     /// Write an object to memory.
@@ -415,6 +429,86 @@ impl AddressSpace {
         Ok(obj)
     }
 
+    /// Return the ranges of all Ram regions in AddressSpace.
+    pub fn ram_ranges(&self) -> Vec<AddressRange> {
+        self.flat_view
+            .load()
+            .0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+            .map(|fr| fr.addr_range)
+            .collect()
+    }
+
+    /// Start to log the guest pages dirtied by both guest and VMM.
+    pub fn start_dirty_log(&self) -> Result<()> {
+        self.dirty_pages.lock().unwrap().clear();
+        self.dirty_log.store(true, Ordering::SeqCst);
+        for listener in self.listeners.lock().unwrap().iter() {
+            listener
+                .set_dirty_log(true)
+                .chain_err(|| "Failed to start dirty log")?;
+        }
+        Ok(())
+    }
+
+    /// Stop logging dirty guest pages.
+    pub fn stop_dirty_log(&self) -> Result<()> {
+        for listener in self.listeners.lock().unwrap().iter() {
+            listener
+                .set_dirty_log(false)
+                .chain_err(|| "Failed to stop dirty log")?;
+        }
+        self.dirty_log.store(false, Ordering::SeqCst);
+        self.dirty_pages.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Mark the guest memory as dirty if dirty log is started. It must be called
+    /// after VMM writes guest memory by host address directly.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start guest address of the memory.
+    /// * `len` - Length of the memory.
+    pub fn mark_dirty(&self, addr: GuestAddress, len: u64) {
+        if len == 0 || !self.dirty_log.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let page_size = host_page_size();
+        let mut page = addr.raw_value() & !(page_size - 1);
+        let end = addr.raw_value() + len;
+        let mut dirty_pages = self.dirty_pages.lock().unwrap();
+        while page < end {
+            dirty_pages.insert(page);
+            page += page_size;
+        }
+    }
+
+    /// Get the ranges of guest memory dirtied since last call, and clear the
+    /// dirty log. Adjacent dirty pages are merged into one range.
+    pub fn get_dirty_ranges(&self) -> Result<Vec<AddressRange>> {
+        let mut pages = std::mem::take(&mut *self.dirty_pages.lock().unwrap());
+        for listener in self.listeners.lock().unwrap().iter() {
+            pages.extend(
+                listener
+                    .get_dirty_log()
+                    .chain_err(|| "Failed to get dirty log")?,
+            );
+        }
+
+        let page_size = host_page_size();
+        let mut ranges: Vec<AddressRange> = Vec::new();
+        for page in pages {
+            match ranges.last_mut() {
+                Some(range) if range.end_addr().raw_value() == page => range.size += page_size,
+                _ => ranges.push(AddressRange::new(GuestAddress(page), page_size)),
+            }
+        }
+        Ok(ranges)
+    }
+
     /// Update the topology of memory.
     pub fn update_topology(&self) -> Result<()> {
         let old_fv = self.flat_view.load();
@@ -782,4 +876,45 @@ mod test {
         assert_eq!(data1, 10000);
         assert!(space.write_object(&data, GuestAddress(993)).is_err());
     }
+
+    #[test]
+    fn test_dirty_log() {
+        let page_size = host_page_size();
+        let root = Region::init_container_region(page_size * 16);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), page_size * 8, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram.clone()), 0)
+            .unwrap();
+        assert_eq!(
+            space.ram_ranges(),
+            vec![AddressRange::from((0, page_size * 8))]
+        );
+
+        // Nothing is logged before dirty log is started.
+        let data: u64 = 0x1234;
+        space.write_object(&data, GuestAddress(0)).unwrap();
+        assert!(space.get_dirty_ranges().unwrap().is_empty());
+
+        space.start_dirty_log().unwrap();
+        space.write_object(&data, GuestAddress(0)).unwrap();
+        space
+            .write_object(&data, GuestAddress(page_size * 2 - 4))
+            .unwrap();
+        space.mark_dirty(GuestAddress(page_size * 5), 1);
+        assert_eq!(
+            space.get_dirty_ranges().unwrap(),
+            vec![
+                AddressRange::from((0, page_size * 3)),
+                AddressRange::from((page_size * 5, page_size))
+            ]
+        );
+        // Dirty log is cleared after it is got.
+        assert!(space.get_dirty_ranges().unwrap().is_empty());
+
+        space.stop_dirty_log().unwrap();
+        space.write_object(&data, GuestAddress(0)).unwrap();
+        assert!(space.get_dirty_ranges().unwrap().is_empty());
+    }
 }
@@ -11,6 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 use std::fs::File;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::Arc;
 
@@ -177,6 +178,51 @@ pub fn create_host_mmaps(
     Ok(mappings)
 }
 
+/// Create HostMemMapping restored from the memory data in snapshot file.
+///
+/// The memory data is mapped privately if it's aligned with host page size, so that
+/// VMs restored from the same snapshot share the page cache of snapshot file, and
+/// pages are copied only when they are written. Otherwise the memory data is read
+/// into anonymous memory.
+///
+/// # Arguments
+///
+/// * `file` - Snapshot memory file.
+/// * `offset` - Offset of memory data in snapshot memory file.
+/// * `guest_addr` - The start address in memory.
+/// * `size` - Size of memory data.
+pub fn create_snapshot_mmap(
+    file: &Arc<File>,
+    offset: u64,
+    guest_addr: GuestAddress,
+    size: u64,
+) -> Result<Arc<HostMemMapping>> {
+    let page_size = host_page_size();
+    if offset % page_size == 0 {
+        let file_back = FileBackend {
+            file: file.clone(),
+            offset,
+            page_size,
+        };
+        return Ok(Arc::new(
+            HostMemMapping::new(guest_addr, size, Some(file_back), false, false, false)
+                .chain_err(|| "Failed to map snapshot memory file")?,
+        ));
+    }
+
+    warn!(
+        "Memory data at offset 0x{:X} of snapshot file is not aligned with page size, copy it",
+        offset
+    );
+    let host_mmap = HostMemMapping::new(guest_addr, size, None, false, false, false)?;
+    // Safe because the memory is mapped above with the length of `size`.
+    let buf = unsafe { std::slice::from_raw_parts_mut(host_mmap.host_addr, size as usize) };
+    file.read_exact_at(buf, offset)
+        .chain_err(|| "Failed to read snapshot memory file")?;
+
+    Ok(Arc::new(host_mmap))
+}
+
 /// Record information of memory mapping.
 pub struct HostMemMapping {
     /// Record the range of one memory segment.
@@ -407,6 +453,37 @@ mod test {
         std::fs::remove_file(file_path).unwrap();
     }
 
+    #[test]
+    fn test_create_snapshot_mmap() {
+        let page_size = host_page_size();
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[1_u8; 16]).unwrap();
+        file.write_all(&vec![2_u8; page_size as usize * 2 - 16])
+            .unwrap();
+        let file = Arc::new(file);
+
+        // Memory data aligned with page size is mapped privately.
+        let ram = create_snapshot_mmap(&file, page_size, GuestAddress(0), page_size).unwrap();
+        assert!(ram.file_backend().is_some());
+        let slice = unsafe { std::slice::from_raw_parts_mut(ram.host_address() as *mut u8, 1) };
+        assert_eq!(slice[0], 2);
+        // Writing to the mapping doesn't change the snapshot file.
+        slice[0] = 3;
+        let mut buf = [0_u8; 1];
+        file.read_exact_at(&mut buf, page_size).unwrap();
+        assert_eq!(buf[0], 2);
+
+        // Memory data not aligned is copied.
+        let ram = create_snapshot_mmap(&file, 8, GuestAddress(0), page_size).unwrap();
+        assert!(ram.file_backend().is_none());
+        let slice = unsafe {
+            std::slice::from_raw_parts(ram.host_address() as *const u8, page_size as usize)
+        };
+        assert_eq!(slice[7], 1);
+        assert_eq!(slice[8], 2);
+        assert!(create_snapshot_mmap(&file, 8, GuestAddress(0), page_size * 2).is_err());
+    }
+
     #[test]
     fn test_create_host_mmaps() {
         let addr_ranges = [(0x0, 0x10_0000), (0x100000, 0x10_0000)];
@@ -85,7 +85,7 @@ mod state;
 
 pub use crate::address_space::AddressSpace;
 pub use address::{AddressRange, GuestAddress};
-pub use host_mmap::{create_host_mmaps, FileBackend, HostMemMapping};
+pub use host_mmap::{create_host_mmaps, create_snapshot_mmap, FileBackend, HostMemMapping};
 #[cfg(target_arch = "x86_64")]
 pub use listener::KvmIoListener;
 pub use listener::KvmMemoryListener;
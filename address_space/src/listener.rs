@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use error_chain::ChainedError;
@@ -22,6 +22,7 @@ use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::{AddressRange, FlatRange, RegionIoEventFd, RegionType};
 use util::{num_ops::round_down, unix::host_page_size};
 
+const MEM_LOG_DIRTY_PAGES: u32 = 1;
 const MEM_READ_ONLY: u32 = 1 << 1;
 
 /// Request type of listener.
@@ -56,6 +57,21 @@ pub trait Listener: Send + Sync {
     ) -> Result<()> {
         Ok(())
     }
+
+    /// Enable or disable logging dirty pages of the regions.
+    ///
+    /// # Arguments
+    ///
+    /// * `_enable` - Start to log dirty pages if true, stop otherwise.
+    fn set_dirty_log(&self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get the guest addresses of pages which are dirtied since last call,
+    /// the dirty log is cleared after it is got.
+    fn get_dirty_log(&self) -> Result<Vec<u64>> {
+        Ok(Vec::new())
+    }
 }
 
 /// Records information that manage the slot resource and current usage.
@@ -82,6 +98,8 @@ pub struct KvmMemoryListener {
     as_id: Arc<AtomicU32>,
     /// Record all MemSlots.
     slots: Arc<Mutex<Vec<MemSlot>>>,
+    /// Whether dirty pages of the slots are logged by KVM.
+    dirty_log: Arc<AtomicBool>,
 }
 
 impl KvmMemoryListener {
@@ -94,6 +112,7 @@ impl KvmMemoryListener {
         KvmMemoryListener {
             as_id: Arc::new(AtomicU32::new(0)),
             slots: Arc::new(Mutex::new(vec![MemSlot::default(); nr_slots as usize])),
+            dirty_log: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -233,14 +252,17 @@ impl KvmMemoryListener {
             + flat_range.offset_in_region
             + align_adjust;
 
-        let slot_idx = self
-            .get_free_slot(aligned_addr.raw_value(), aligned_size, aligned_hva)
-            .chain_err(|| "Failed to get available KVM mem slot")?;
-
         let mut flags = 0_u32;
         if flat_range.owner.get_rom_device_romd().unwrap_or(false) {
             flags |= MEM_READ_ONLY;
+        } else if self.dirty_log.load(Ordering::SeqCst) {
+            flags |= MEM_LOG_DIRTY_PAGES;
         }
+
+        let slot_idx = self
+            .get_free_slot(aligned_addr.raw_value(), aligned_size, aligned_hva)
+            .chain_err(|| "Failed to get available KVM mem slot")?;
+        self.slots.lock().unwrap()[slot_idx as usize].flag = flags;
         let kvm_region = kvm_userspace_memory_region {
             slot: slot_idx | (self.as_id.load(Ordering::SeqCst) << 16),
             guest_phys_addr: aligned_addr.raw_value(),
@@ -321,6 +343,83 @@ impl KvmMemoryListener {
         Ok(())
     }
 
+    /// Update the flag of all writable slots to enable or disable dirty page logging.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Start to log dirty pages if true, stop otherwise.
+    fn update_dirty_log(&self, enable: bool) -> Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        self.dirty_log.store(enable, Ordering::SeqCst);
+        for slot in slots
+            .iter_mut()
+            .filter(|s| s.size != 0 && s.flag & MEM_READ_ONLY == 0)
+        {
+            let flags = if enable { MEM_LOG_DIRTY_PAGES } else { 0 };
+            let kvm_region = kvm_userspace_memory_region {
+                slot: slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
+                guest_phys_addr: slot.guest_addr,
+                memory_size: slot.size,
+                userspace_addr: slot.host_addr,
+                flags,
+            };
+            unsafe {
+                KVM_FDS
+                    .load()
+                    .vm_fd
+                    .as_ref()
+                    .unwrap()
+                    .set_user_memory_region(kvm_region)
+                    .chain_err(|| {
+                        format!(
+                            "KVM update dirty log of memory region failed: addr 0x{:X}",
+                            slot.guest_addr
+                        )
+                    })?;
+            }
+            slot.flag = flags;
+        }
+
+        Ok(())
+    }
+
+    /// Get and clear dirty pages of all slots which are logging dirty pages.
+    fn dirty_pages(&self) -> Result<Vec<u64>> {
+        let page_size = host_page_size();
+        let mut pages = Vec::new();
+        let kvm_fds = KVM_FDS.load();
+        let vm_fd = kvm_fds.vm_fd.as_ref().unwrap();
+        for slot in self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.size != 0 && s.flag & MEM_LOG_DIRTY_PAGES != 0)
+        {
+            let bitmap = vm_fd
+                .get_dirty_log(
+                    slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
+                    slot.size as usize,
+                )
+                .chain_err(|| {
+                    format!(
+                        "KVM get dirty log of memory region failed: addr 0x{:X}",
+                        slot.guest_addr
+                    )
+                })?;
+            for (index, bits) in bitmap.iter().enumerate() {
+                let mut bits = *bits;
+                while bits != 0 {
+                    let bit = u64::from(bits.trailing_zeros());
+                    pages.push(slot.guest_addr + (index as u64 * 64 + bit) * page_size);
+                    bits &= bits - 1;
+                }
+            }
+        }
+
+        Ok(pages)
+    }
+
     /// Register a IoEvent to `/dev/kvm`.
     ///
     /// # Arguments
@@ -439,6 +538,14 @@ impl Listener for KvmMemoryListener {
 
         req_ret.chain_err(|| ErrorKind::ListenerRequest(req_type))
     }
+
+    fn set_dirty_log(&self, enable: bool) -> Result<()> {
+        self.update_dirty_log(enable)
+    }
+
+    fn get_dirty_log(&self) -> Result<Vec<u64>> {
+        self.dirty_pages()
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
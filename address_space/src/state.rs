@@ -11,17 +11,30 @@
 // See the Mulan PSL v2 for more details.
 
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
+use std::thread;
 
-use crate::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region};
+use crate::{
+    create_snapshot_mmap, AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region,
+};
+use error_chain::ChainedError;
 use migration::errors::{ErrorKind, Result};
-use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
+use migration::{
+    DeviceStateDesc, FieldDesc, LazyRestoreStats, MigrationHook, MigrationManager, StateTransfer,
+    MEMORY_MAPPED_ALIGN,
+};
 use util::byte_code::ByteCode;
 use util::unix::host_page_size;
+use util::userfaultfd::{CopyResult, UserfaultFd};
+use vmm_sys_util::eventfd::EventFd;
 
 const MIGRATION_HEADER_LENGTH: usize = 4096;
+/// Number of pages restored at a time by the prefetcher of lazy restore.
+const PREFETCH_PAGES: u64 = 64;
 
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
@@ -39,24 +52,210 @@ pub struct RamRegionState {
     offset: u64,
 }
 
-// To get the offset to memory data in memory snapshot file.
-// It would be changed when pagesize changed.
-fn memory_offset() -> usize {
-    let page_size = host_page_size() as usize;
-    if page_size >= MIGRATION_HEADER_LENGTH + size_of::<AddressSpaceState>() {
-        page_size
-    } else {
-        page_size * 2
+// Memory data is aligned in memory snapshot file, so that it can be mapped
+// as guest memory directly.
+fn align_memory_offset(offset: u64) -> u64 {
+    (offset + MEMORY_MAPPED_ALIGN - 1) & !(MEMORY_MAPPED_ALIGN - 1)
+}
+
+/// Get the ram regions in `state`, and check that the memory data of each
+/// region is within `memory` file if it's given.
+///
+/// # Arguments
+///
+/// * `state` - State of address space in snapshot.
+/// * `memory` - Memory file of snapshot.
+fn ram_regions<'a>(
+    state: &'a AddressSpaceState,
+    memory: Option<&File>,
+) -> Result<&'a [RamRegionState]> {
+    let regions = state
+        .ram_region_state
+        .get(0..state.nr_ram_region as usize)
+        .ok_or_else(|| {
+            ErrorKind::RestoreVmMemoryErr(format!(
+                "Invalid number {} of ram regions",
+                state.nr_ram_region
+            ))
+        })?;
+
+    if let Some(file) = memory {
+        let file_len = file.metadata()?.len();
+        for ram_state in regions {
+            let end = ram_state.offset.checked_add(ram_state.size);
+            if end.map_or(true, |end| end > file_len) {
+                return Err(ErrorKind::SnapshotTruncated(format!(
+                    "memory region 0x{:X} with size 0x{:X}",
+                    ram_state.base_address, ram_state.size
+                ))
+                .into());
+            }
+        }
+    }
+
+    Ok(regions)
+}
+
+/// Guest memory range which is restored lazily from snapshot memory file.
+struct LazyRange {
+    /// Start host address of guest memory, which is registered to userfaultfd.
+    dst: u64,
+    /// Read-only mapping of memory data in snapshot memory file.
+    src: HostMemMapping,
+}
+
+/// Restore guest memory from snapshot memory file on demand. Missing pages
+/// accessed by guest or devices are restored by the fault handler, and the
+/// rest pages are restored by the prefetcher in background.
+struct LazyLoader {
+    uffd: UserfaultFd,
+    ranges: Vec<LazyRange>,
+    page_size: u64,
+    stats: Arc<LazyRestoreStats>,
+    /// Notify fault handler to exit after all pages are restored.
+    exit_evt: EventFd,
+}
+
+impl LazyLoader {
+    /// Handle missing page faults until all pages are restored.
+    fn handle_faults(&self) -> util::errors::Result<()> {
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.uffd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.exit_evt.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            // Safe because fds is valid during the call.
+            let ret = unsafe {
+                libc::ppoll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    std::ptr::null(),
+                    std::ptr::null(),
+                )
+            };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if fds[1].revents & libc::POLLIN != 0 {
+                return Ok(());
+            }
+            while let Some(addr) = self.uffd.read_fault()? {
+                self.restore_page(addr)?;
+            }
+        }
+    }
+
+    fn restore_page(&self, addr: u64) -> util::errors::Result<()> {
+        let page = addr & !(self.page_size - 1);
+        let range = match self
+            .ranges
+            .iter()
+            .find(|r| page >= r.dst && page < r.dst + r.src.size())
+        {
+            Some(r) => r,
+            None => bail!("Page fault at 0x{:X} is out of guest memory", addr),
+        };
+        let src = range.src.host_address() + (page - range.dst);
+        loop {
+            match self.uffd.copy(page, src, self.page_size)? {
+                CopyResult::Done => {
+                    self.stats.add_missing_pages(1);
+                    return Ok(());
+                }
+                // The page is restored by prefetcher already.
+                CopyResult::Exist => return Ok(()),
+                CopyResult::Again(_) => continue,
+            }
+        }
+    }
+
+    /// Restore all pages which are not restored yet, and stop handling page
+    /// faults after that.
+    fn prefetch(&self) -> util::errors::Result<()> {
+        for range in self.ranges.iter() {
+            let size = range.src.size();
+            let mut offset = 0;
+            while offset < size {
+                let len = std::cmp::min(PREFETCH_PAGES * self.page_size, size - offset);
+                let src = range.src.host_address() + offset;
+                match self.uffd.copy(range.dst + offset, src, len)? {
+                    CopyResult::Done => {
+                        self.stats.add_prefetched_pages(len / self.page_size);
+                        offset += len;
+                    }
+                    // Skip the page restored by fault handler.
+                    CopyResult::Exist => offset += self.page_size,
+                    CopyResult::Again(copied) => {
+                        self.stats.add_prefetched_pages(copied / self.page_size);
+                        offset += copied;
+                    }
+                }
+            }
+            self.uffd.unregister(range.dst, size)?;
+        }
+        self.exit_evt.write(1)?;
+
+        Ok(())
+    }
+}
+
+impl AddressSpace {
+    /// Restore the ram region which has been created before restoring, such as
+    /// the ROM of firmware on x86_64. Returns false if there's no such region.
+    ///
+    /// # Arguments
+    ///
+    /// * `ram_state` - State of ram region in snapshot.
+    /// * `memory` - Memory file of snapshot, the data is loaded later if it's None.
+    fn restore_existing_region(
+        &self,
+        ram_state: &RamRegionState,
+        memory: Option<&File>,
+    ) -> Result<bool> {
+        let region = match self.root().subregions().into_iter().find(|r| {
+            r.start_addr() == Some(GuestAddress(ram_state.base_address))
+                && r.size() == ram_state.size
+        }) {
+            Some(region) => region,
+            None => return Ok(false),
+        };
+
+        if let Some(file) = memory {
+            let mut data = vec![0_u8; ram_state.size as usize];
+            file.read_exact_at(&mut data, ram_state.offset)?;
+            region
+                .write(
+                    &mut data.as_slice(),
+                    GuestAddress(ram_state.base_address),
+                    0,
+                    ram_state.size,
+                )
+                .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
+        }
+        Ok(true)
     }
 }
 
 impl StateTransfer for AddressSpace {
     fn get_state_vec(&self) -> Result<Vec<u8>> {
         let mut state = AddressSpaceState::default();
-        let mut offset = memory_offset() as u64;
+        let mut offset = MIGRATION_HEADER_LENGTH as u64;
 
         for region in self.root().subregions().iter() {
             if let Some(start_addr) = region.start_addr() {
+                offset = align_memory_offset(offset);
                 state.ram_region_state[state.nr_ram_region as usize] = RamRegionState {
                     base_address: start_addr.0,
                     size: region.size(),
@@ -79,15 +278,16 @@ impl MigrationHook for AddressSpace {
     fn pre_save(&self, _id: u64, writer: &mut dyn Write) -> Result<()> {
         let ram_state = self.get_state_vec()?;
         writer.write_all(&ram_state)?;
-        let padding_buffer =
-            [0].repeat(memory_offset() - MIGRATION_HEADER_LENGTH - size_of::<AddressSpaceState>());
-        writer.write_all(&padding_buffer)?;
+        let mut offset = (MIGRATION_HEADER_LENGTH + ram_state.len()) as u64;
 
         for region in self.root().subregions().iter() {
             if let Some(base_addr) = region.start_addr() {
+                let padding = align_memory_offset(offset) - offset;
+                writer.write_all(&[0].repeat(padding as usize))?;
                 region
                     .read(writer, base_addr, 0, region.size())
                     .map_err(|e| ErrorKind::SaveVmMemoryErr(e.to_string()))?;
+                offset += padding + region.size();
             }
         }
 
@@ -98,28 +298,96 @@ impl MigrationHook for AddressSpace {
         let address_space_state: &AddressSpaceState =
             AddressSpaceState::from_bytes(&state[0..size_of::<AddressSpaceState>()])
                 .ok_or(ErrorKind::FromBytesError("MEMORY"))?;
-        let memfile_arc = Arc::new(memory.unwrap().try_clone().unwrap());
+        let memfile_arc = match memory {
+            Some(file) => Some(Arc::new(file.try_clone()?)),
+            None => None,
+        };
 
-        for ram_state in address_space_state.ram_region_state
-            [0..address_space_state.nr_ram_region as usize]
-            .iter()
-        {
+        for ram_state in ram_regions(address_space_state, memory)? {
+            if self.restore_existing_region(ram_state, memory)? {
+                continue;
+            }
+            let host_mmap = match &memfile_arc {
+                Some(file) => create_snapshot_mmap(
+                    file,
+                    ram_state.offset,
+                    GuestAddress(ram_state.base_address),
+                    ram_state.size,
+                ),
+                // Memory data is loaded by `load_memory_range` later without memory file.
+                None => HostMemMapping::new(
+                    GuestAddress(ram_state.base_address),
+                    ram_state.size,
+                    None,
+                    false,
+                    false,
+                    false,
+                )
+                .map(Arc::new),
+            }
+            .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
+            self.root()
+                .add_subregion(
+                    Region::init_ram_region(host_mmap.clone()),
+                    host_mmap.start_address().raw_value(),
+                )
+                .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn pre_load_lazy(
+        &self,
+        state: &[u8],
+        memory: &File,
+        stats: Arc<LazyRestoreStats>,
+    ) -> Result<()> {
+        let address_space_state: &AddressSpaceState =
+            AddressSpaceState::from_bytes(&state[0..size_of::<AddressSpaceState>()])
+                .ok_or(ErrorKind::FromBytesError("MEMORY"))?;
+        let memfile_arc = Arc::new(memory.try_clone()?);
+        let uffd = UserfaultFd::new().map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
+        let page_size = host_page_size();
+
+        let mut ranges = Vec::new();
+        for ram_state in ram_regions(address_space_state, Some(memory))? {
+            if self.restore_existing_region(ram_state, Some(memory))? {
+                continue;
+            }
             let file_backend = FileBackend {
                 file: memfile_arc.clone(),
                 offset: ram_state.offset,
-                page_size: host_page_size(),
+                page_size,
             };
+            let src = HostMemMapping::new(
+                GuestAddress(ram_state.base_address),
+                ram_state.size,
+                Some(file_backend),
+                false,
+                false,
+                true,
+            )
+            .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
             let host_mmap = Arc::new(
                 HostMemMapping::new(
                     GuestAddress(ram_state.base_address),
                     ram_state.size,
-                    Some(file_backend),
+                    None,
                     false,
                     false,
                     false,
                 )
                 .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?,
             );
+            uffd.register(host_mmap.host_address(), ram_state.size)
+                .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
+            ranges.push(LazyRange {
+                dst: host_mmap.host_address(),
+                src,
+            });
+            stats.add_total_pages(ram_state.size / page_size);
+
             self.root()
                 .add_subregion(
                     Region::init_ram_region(host_mmap.clone()),
@@ -128,6 +396,235 @@ impl MigrationHook for AddressSpace {
                 .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()))?;
         }
 
+        let loader = Arc::new(LazyLoader {
+            uffd,
+            ranges,
+            page_size,
+            stats,
+            exit_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        });
+        let handler = loader.clone();
+        thread::Builder::new()
+            .name("uffd handler".to_string())
+            .spawn(move || {
+                if let Err(e) = handler.handle_faults() {
+                    error!("Failed to handle page faults: {}", e.display_chain());
+                }
+            })?;
+        thread::Builder::new()
+            .name("uffd prefetcher".to_string())
+            .spawn(move || {
+                if let Err(e) = loader.prefetch() {
+                    error!("Failed to prefetch snapshot memory: {}", e.display_chain());
+                } else {
+                    info!("All snapshot memory is restored");
+                }
+            })?;
+
         Ok(())
     }
+
+    fn start_dirty_log(&self) -> Result<()> {
+        AddressSpace::start_dirty_log(self)
+            .map_err(|e| ErrorKind::SaveVmMemoryErr(e.to_string()).into())
+    }
+
+    fn stop_dirty_log(&self) -> Result<()> {
+        AddressSpace::stop_dirty_log(self)
+            .map_err(|e| ErrorKind::SaveVmMemoryErr(e.to_string()).into())
+    }
+
+    fn get_memory_ranges(&self, dirty: bool) -> Result<Vec<(u64, u64)>> {
+        let ranges = if dirty {
+            self.get_dirty_ranges()
+                .map_err(|e| ErrorKind::SaveVmMemoryErr(e.to_string()))?
+        } else {
+            self.ram_ranges()
+        };
+
+        Ok(ranges
+            .iter()
+            .map(|range| (range.base.raw_value(), range.size))
+            .collect())
+    }
+
+    fn save_memory_range(&self, writer: &mut dyn Write, addr: u64, len: u64) -> Result<()> {
+        self.read(writer, GuestAddress(addr), len)
+            .map_err(|e| ErrorKind::SaveVmMemoryErr(e.to_string()).into())
+    }
+
+    fn load_memory_range(&self, reader: &mut dyn Read, addr: u64, len: u64) -> Result<()> {
+        if !self.address_in_memory(GuestAddress(addr), len) {
+            return Err(ErrorKind::RestoreVmMemoryErr(format!(
+                "range 0x{:X} with length 0x{:X} is not in memory",
+                addr, len
+            ))
+            .into());
+        }
+        self.write(reader, GuestAddress(addr), len)
+            .map_err(|e| ErrorKind::RestoreVmMemoryErr(e.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use std::time::Duration;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Save two memory regions whose pages are filled with their page index.
+    fn create_snapshot(nr_pages: u64) -> (File, Vec<u8>) {
+        let page_size = host_page_size();
+        let root = Region::init_container_region(page_size * nr_pages * 2);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        for base in [0, page_size * nr_pages].iter() {
+            let ram = Arc::new(
+                HostMemMapping::new(
+                    GuestAddress(*base),
+                    page_size * nr_pages / 2,
+                    None,
+                    false,
+                    false,
+                    false,
+                )
+                .unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram), *base)
+                .unwrap();
+        }
+        for i in 0..nr_pages {
+            space.write_object(&i, guest_page(i, nr_pages)).unwrap();
+        }
+
+        let mut memory = TempFile::new().unwrap().into_file();
+        memory.write_all(&[0_u8; MIGRATION_HEADER_LENGTH]).unwrap();
+        space.pre_save(0, &mut memory).unwrap();
+        (memory, space.get_state_vec().unwrap())
+    }
+
+    fn guest_page(index: u64, nr_pages: u64) -> GuestAddress {
+        let page_size = host_page_size();
+        if index < nr_pages / 2 {
+            GuestAddress(index * page_size)
+        } else {
+            GuestAddress((index + nr_pages / 2) * page_size)
+        }
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let nr_pages = 512;
+        let (memory, state) = create_snapshot(nr_pages);
+        let address_space_state =
+            AddressSpaceState::from_bytes(&state[0..size_of::<AddressSpaceState>()]).unwrap();
+        assert_eq!(address_space_state.nr_ram_region, 2);
+        for ram_state in address_space_state.ram_region_state[0..2].iter() {
+            assert_eq!(ram_state.offset % MEMORY_MAPPED_ALIGN, 0);
+        }
+
+        let page_size = host_page_size();
+        let root = Region::init_container_region(page_size * nr_pages * 2);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        space.pre_load(&state, Some(&memory)).unwrap();
+        for region in root.subregions().iter() {
+            assert!(region.get_host_address().is_some());
+        }
+        for i in 0..nr_pages {
+            assert_eq!(
+                space.read_object::<u64>(guest_page(i, nr_pages)).unwrap(),
+                i
+            );
+        }
+
+        // Guest memory is mapped privately, so snapshot file is not changed.
+        space
+            .write_object(&0xFF_u64, guest_page(1, nr_pages))
+            .unwrap();
+        let first_region = address_space_state.ram_region_state[0..2]
+            .iter()
+            .find(|r| r.base_address == 0)
+            .unwrap();
+        let mut buf = [0_u8; 8];
+        memory
+            .read_exact_at(&mut buf, first_region.offset + page_size)
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(buf), 1);
+
+        // Existing ram region is restored instead of being added again.
+        let root = Region::init_container_region(page_size * nr_pages * 2);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                page_size * nr_pages / 2,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram.clone()), 0)
+            .unwrap();
+        space.pre_load(&state, Some(&memory)).unwrap();
+        assert_eq!(root.subregions().len(), 2);
+        assert_eq!(
+            root.subregions()
+                .iter()
+                .filter(|r| r.get_host_address() == Some(ram.host_address()))
+                .count(),
+            1
+        );
+        assert_eq!(
+            space.read_object::<u64>(guest_page(1, nr_pages)).unwrap(),
+            1
+        );
+
+        // Guest memory is zero if there's no memory file.
+        let root = Region::init_container_region(page_size * nr_pages * 2);
+        let space = AddressSpace::new(root).unwrap();
+        space.pre_load(&state, None).unwrap();
+        assert_eq!(
+            space.read_object::<u64>(guest_page(1, nr_pages)).unwrap(),
+            0
+        );
+
+        // Truncated memory file is refused before mapping it as guest memory.
+        memory
+            .set_len(memory.metadata().unwrap().len() - page_size)
+            .unwrap();
+        let root = Region::init_container_region(page_size * nr_pages * 2);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        assert!(space.pre_load(&state, Some(&memory)).is_err());
+        assert!(root.subregions().is_empty());
+    }
+
+    #[test]
+    fn test_lazy_restore() {
+        // Userfaultfd may be disabled for unprivileged user.
+        if UserfaultFd::new().is_err() {
+            return;
+        }
+        let nr_pages = 512;
+        let (memory, state) = create_snapshot(nr_pages);
+
+        let page_size = host_page_size();
+        let space =
+            AddressSpace::new(Region::init_container_region(page_size * nr_pages * 2)).unwrap();
+        let stats = Arc::new(LazyRestoreStats::default());
+        space.pre_load_lazy(&state, &memory, stats.clone()).unwrap();
+        assert_eq!(stats.total_pages(), nr_pages);
+        for i in (0..nr_pages).rev() {
+            assert_eq!(
+                space.read_object::<u64>(guest_page(i, nr_pages)).unwrap(),
+                i
+            );
+        }
+
+        while stats.remaining_pages() != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stats.missing_pages() + stats.prefetched_pages(), nr_pages);
+    }
 }
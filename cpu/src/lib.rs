@@ -283,6 +283,11 @@ impl CPU {
         *data = task;
     }
 
+    /// Wait for the thread of this `CPU` to exit after it's destroyed.
+    pub fn join(&self) {
+        self.set_task(None);
+    }
+
     /// Get this `CPU`'s thread id.
     pub fn tid(&self) -> u64 {
         (*self.tid.lock().unwrap()).unwrap_or(0)
@@ -328,8 +333,14 @@ impl CPUInterface for CPU {
 
     fn start(cpu: Arc<CPU>, thread_barrier: Arc<Barrier>, paused: bool) -> Result<()> {
         let (cpu_state, _) = &*cpu.state;
-        if *cpu_state.lock().unwrap() == CpuLifecycleState::Running {
-            return Err(ErrorKind::StartVcpu("Cpu is already running".to_string()).into());
+        match *cpu_state.lock().unwrap() {
+            CpuLifecycleState::Running => {
+                return Err(ErrorKind::StartVcpu("Cpu is already running".to_string()).into());
+            }
+            CpuLifecycleState::Stopping => {
+                return Err(ErrorKind::StartVcpu("Cpu thread is still exiting".to_string()).into());
+            }
+            _ => {}
         }
         if paused {
             *cpu_state.lock().unwrap() = CpuLifecycleState::Paused;
@@ -690,9 +701,9 @@ pub struct CpuTopology {
     pub cores: u8,
     /// Number of threads in VM.
     pub threads: u8,
-    /// Number of vcpus in VM.
+    /// Number of vcpus online at boot in VM.
     pub nrcpus: u8,
-    /// Number of online vcpus in VM.
+    /// Maximum number of vcpus in VM, including the hotpluggable ones.
     pub max_cpus: u8,
     /// Online mask number of all vcpus.
     pub online_mask: Arc<Mutex<Vec<u8>>>,
@@ -703,15 +714,17 @@ impl CpuTopology {
     ///
     /// # Arguments
     ///
-    /// * `nr_cpus`: Number of vcpus.
-    pub fn new(nr_cpus: u8) -> Self {
-        let mask: Vec<u8> = vec![1; nr_cpus as usize];
+    /// * `nr_cpus`: Number of vcpus online at boot.
+    /// * `max_cpus`: Maximum number of vcpus, including the hotpluggable ones.
+    pub fn new(nr_cpus: u8, max_cpus: u8) -> Self {
+        let mut mask: Vec<u8> = vec![0; max_cpus as usize];
+        mask[..nr_cpus as usize].iter_mut().for_each(|m| *m = 1);
         Self {
-            sockets: nr_cpus,
+            sockets: max_cpus,
             cores: 1,
             threads: 1,
             nrcpus: nr_cpus,
-            max_cpus: nr_cpus,
+            max_cpus,
             online_mask: Arc::new(Mutex::new(mask)),
         }
     }
@@ -731,6 +744,17 @@ impl CpuTopology {
         mask[vcpu_id]
     }
 
+    /// Set online mask for a cpu, it's changed when the cpu is hotplugged
+    /// or hot-unplugged.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - ID of vcpu.
+    /// * `mask` - Online mask, `1` means online and `0` means offline.
+    pub fn set_mask(&self, vcpu_id: usize, mask: u8) {
+        self.online_mask.lock().unwrap()[vcpu_id] = mask;
+    }
+
     /// Get single cpu topology for vcpu, return this vcpu's `socket-id`,
     /// `core-id` and `thread-id`.
     ///
@@ -907,6 +931,21 @@ mod tests {
         drop(cpu_state);
     }
 
+    #[test]
+    fn test_cpu_online_mask() {
+        let cpu_topo = CpuTopology::new(2, 4);
+        assert_eq!(cpu_topo.max_cpus, 4);
+        assert_eq!(cpu_topo.sockets, 4);
+        assert_eq!(cpu_topo.get_mask(1), 1);
+        assert_eq!(cpu_topo.get_mask(2), 0);
+        assert_eq!(cpu_topo.get_mask(3), 0);
+
+        cpu_topo.set_mask(3, 1);
+        assert_eq!(cpu_topo.get_mask(3), 1);
+        cpu_topo.set_mask(3, 0);
+        assert_eq!(cpu_topo.get_mask(3), 0);
+    }
+
     #[test]
     fn test_cpu_get_topu() {
         let test_nr_cpus: u8 = 16;
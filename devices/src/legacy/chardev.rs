@@ -35,6 +35,9 @@ pub trait InputReceiver: Send {
     fn input_handle(&mut self, buffer: &[u8]);
 
     fn get_remain_space_size(&mut self) -> usize;
+
+    /// Notify the receiver that the backend is connected or disconnected.
+    fn set_connected(&mut self, _connected: bool) {}
 }
 
 /// Character device structure.
@@ -53,6 +56,8 @@ pub struct Chardev {
     receive: Option<Arc<dyn Fn(&[u8]) + Send + Sync>>,
     /// Return the remain space size of receiver buffer.
     get_remain_space_size: Option<Arc<dyn Fn() -> usize + Send + Sync>>,
+    /// Notify the receiver of the connection state of socket-type backend.
+    set_connected: Option<Arc<dyn Fn(bool) + Send + Sync>>,
 }
 
 impl Chardev {
@@ -65,6 +70,7 @@ impl Chardev {
             output: None,
             receive: None,
             get_remain_space_size: None,
+            set_connected: None,
         }
     }
 
@@ -102,6 +108,12 @@ impl Chardev {
                     )
                 })?;
             }
+            ChardevType::ClientSocket(path) => {
+                bail!(
+                    "Socket in client mode is not supported for chardev, path:{}",
+                    path
+                );
+            }
             ChardevType::File(path) => {
                 let file = Arc::new(Mutex::new(
                     OpenOptions::new()
@@ -125,6 +137,10 @@ impl Chardev {
         self.get_remain_space_size = Some(Arc::new(move || {
             cloned_dev.lock().unwrap().get_remain_space_size()
         }));
+        let cloned_dev = dev.clone();
+        self.set_connected = Some(Arc::new(move |connected: bool| {
+            cloned_dev.lock().unwrap().set_connected(connected)
+        }));
     }
 }
 
@@ -205,6 +221,11 @@ fn get_notifier_handler(
             let stream_arc = Arc::new(Mutex::new(stream));
             locked_chardev.input = Some(stream_arc.clone());
             locked_chardev.output = Some(stream_arc);
+            let set_connected = locked_chardev.set_connected.clone();
+            drop(locked_chardev);
+            if let Some(set_connected) = set_connected {
+                set_connected(true);
+            }
 
             let cloned_chardev = chardev.clone();
             let inner_handler = Box::new(move |event, _| {
@@ -223,8 +244,14 @@ fn get_notifier_handler(
                     }
                 }
                 if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                    cloned_chardev.lock().unwrap().input = None;
-                    cloned_chardev.lock().unwrap().output = None;
+                    let mut locked_chardev = cloned_chardev.lock().unwrap();
+                    locked_chardev.input = None;
+                    locked_chardev.output = None;
+                    let set_connected = locked_chardev.set_connected.clone();
+                    drop(locked_chardev);
+                    if let Some(set_connected) = set_connected {
+                        set_connected(false);
+                    }
                     Some(vec![EventNotifier::new(
                         NotifierOperation::Delete,
                         stream_fd,
@@ -244,7 +271,7 @@ fn get_notifier_handler(
                 vec![Arc::new(Mutex::new(inner_handler))],
             )])
         }),
-        ChardevType::File(_) => Box::new(move |_, _| None),
+        ChardevType::ClientSocket(_) | ChardevType::File(_) => Box::new(move |_, _| None),
     }
 }
 
@@ -282,7 +309,7 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
-            ChardevType::File(_) => (),
+            ChardevType::ClientSocket(_) | ChardevType::File(_) => (),
         }
         notifiers
     }
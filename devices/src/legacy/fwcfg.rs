@@ -23,6 +23,7 @@ use address_space::{AddressSpace, GuestAddress};
 use byteorder::LittleEndian;
 use byteorder::{BigEndian, ByteOrder};
 use error_chain::ChainedError;
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
 use util::byte_code::ByteCode;
 use util::num_ops::extract_u64;
@@ -304,6 +305,22 @@ fn write_dma_result(addr_space: &Arc<AddressSpace>, addr: GuestAddress, value: u
     Ok(())
 }
 
+/// Register status of FwCfg device.
+///
+/// The content of entries is not included, because they are only used by
+/// firmware when booting, and the guest memory has been filled with them.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct FwCfgState {
+    /// The current entry index selected.
+    cur_entry: u16,
+    /// The current entry data offset of the entry selected.
+    cur_offset: u32,
+    /// DMA guest address.
+    dma_addr: u64,
+}
+
 pub struct FwCfgCommon {
     // Firmware file slot count
     file_slots: u16,
@@ -719,6 +736,21 @@ impl FwCfgCommon {
         Ok(value)
     }
 
+    fn get_state(&self) -> FwCfgState {
+        FwCfgState {
+            cur_entry: self.cur_entry,
+            cur_offset: self.cur_offset,
+            dma_addr: self.dma_addr.raw_value(),
+        }
+    }
+
+    fn set_state(&mut self, state: &FwCfgState) {
+        // The entry is selected directly, so that the select callback isn't triggered again.
+        self.cur_entry = state.cur_entry;
+        self.cur_offset = state.cur_offset;
+        self.dma_addr = GuestAddress(state.dma_addr);
+    }
+
     fn common_realize(&mut self) -> Result<()> {
         // Firmware configurations add Signature item
         let sig = &[b'Q', b'E', b'M', b'U'];
@@ -799,6 +831,7 @@ impl FwCfgMem {
         sysbus
             .attach_device(&dev, region_base, region_size)
             .chain_err(|| "Failed to attach FwCfg device to system bus.")?;
+        MigrationManager::register_device_instance_mutex(FwCfgState::descriptor(), dev.clone());
         Ok(dev)
     }
 }
@@ -949,6 +982,7 @@ impl FwCfgIO {
         sysbus
             .attach_device(&dev, region_base, region_size)
             .chain_err(|| "Failed to attach FwCfg device to system bus.")?;
+        MigrationManager::register_device_instance_mutex(FwCfgState::descriptor(), dev.clone());
         Ok(dev)
     }
 }
@@ -1166,6 +1200,58 @@ impl AmlBuilder for FwCfgIO {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+impl StateTransfer for FwCfgMem {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        Ok(self.fwcfg.get_state().as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let fwcfg_state = FwCfgState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("FWCFG"))?;
+        self.fwcfg.set_state(fwcfg_state);
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&FwCfgState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl MigrationHook for FwCfgMem {}
+
+#[cfg(target_arch = "x86_64")]
+impl StateTransfer for FwCfgIO {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        Ok(self.fwcfg.get_state().as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let fwcfg_state = FwCfgState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("FWCFG"))?;
+        self.fwcfg.set_state(fwcfg_state);
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&FwCfgState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MigrationHook for FwCfgIO {}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -18,11 +18,33 @@ use acpi::AmlBuilder;
 use address_space::{GuestAddress, HostMemMapping, Region};
 use byteorder::{ByteOrder, LittleEndian};
 use error_chain::ChainedError;
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use sysbus::{errors::Result as SysBusResult, SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::byte_code::ByteCode;
 use util::num_ops::{deposit_u32, extract_u32};
 
 use super::errors::{ErrorKind, Result, ResultExt};
 
+/// Status of command sequence of `PFlash` device.
+///
+/// The content of PFlash is not included, it's reloaded from the backend file
+/// which is updated on every write.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct PFlashState {
+    /// If 0, the PFlash is read normally.
+    write_cycle: i32,
+    /// Command to control PFlash.
+    cmd: u8,
+    /// PFlash status.
+    status: u8,
+    /// Counter for writing block.
+    counter: u32,
+    /// ROM region is read directly or by device ops.
+    read_array: bool,
+}
+
 /// PFlash structure
 pub struct PFlash {
     /// File to save data in PFlash ROM.
@@ -229,7 +251,9 @@ impl PFlash {
             .add_subregion(rom_region.clone(), region_base)
             .chain_err(|| "Failed to attach PFlash to system bus")?;
         dev.lock().unwrap().set_rom_mem(Arc::new(rom_region))?;
-        sysbus.devices.push(dev);
+        sysbus.devices.push(dev.clone());
+
+        MigrationManager::register_device_instance_mutex(PFlashState::descriptor(), dev);
 
         Ok(())
     }
@@ -913,6 +937,55 @@ impl AmlBuilder for PFlash {
     }
 }
 
+impl StateTransfer for PFlash {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let state = PFlashState {
+            write_cycle: self.write_cycle,
+            cmd: self.cmd,
+            status: self.status,
+            counter: self.counter,
+            // Unwrap is safe, because after realize function, rom isn't none.
+            read_array: self
+                .rom
+                .as_ref()
+                .unwrap()
+                .get_rom_device_romd()
+                .unwrap_or(true),
+        };
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let pflash_state = *PFlashState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("PFLASH"))?;
+        self.write_cycle = pflash_state.write_cycle;
+        self.cmd = pflash_state.cmd;
+        self.status = pflash_state.status;
+        self.counter = pflash_state.counter;
+        if let Err(e) = self
+            .rom
+            .as_ref()
+            .unwrap()
+            .set_rom_device_romd(pflash_state.read_array)
+        {
+            bail!("Failed to set mode of PFlash rom: {}", e.display_chain());
+        }
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&PFlashState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for PFlash {}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1110,4 +1183,37 @@ mod test {
 
         fs::remove_file(file_name).unwrap();
     }
+
+    #[test]
+    fn test_pflash_migration_interface() {
+        let file_name = "flash_vars_for_migration.fd";
+        let dev = pflash_dev_init(file_name);
+
+        let base = GuestAddress(0x0000);
+        let offset = 0_u64;
+        // Start writing to buffer, so that the rom is accessed by device ops.
+        let data = vec![0xe8, 0, 0, 0];
+        assert!(dev.lock().unwrap().write(data.as_ref(), base, offset));
+        let state = dev.lock().unwrap().get_state_vec().unwrap();
+        let pflash_state = PFlashState::from_bytes(&state).unwrap();
+        assert_eq!(pflash_state.write_cycle, 1);
+        assert_eq!(pflash_state.cmd, 0xe8);
+        assert_eq!(pflash_state.status, 0x80);
+        assert!(!pflash_state.read_array);
+
+        let file_name_2 = "flash_vars_for_migration_2.fd";
+        let dev_2 = pflash_dev_init(file_name_2);
+        dev_2.lock().unwrap().set_state_mut(&state).unwrap();
+        let locked_dev = dev_2.lock().unwrap();
+        assert_eq!(locked_dev.write_cycle, 1);
+        assert_eq!(locked_dev.cmd, 0xe8);
+        assert_eq!(locked_dev.status, 0x80);
+        assert_eq!(
+            locked_dev.rom.as_ref().unwrap().get_rom_device_romd(),
+            Some(false)
+        );
+
+        fs::remove_file(file_name).unwrap();
+        fs::remove_file(file_name_2).unwrap();
+    }
 }
@@ -17,7 +17,9 @@ use acpi::{
     AmlResTemplate, AmlScopeBuilder,
 };
 use address_space::GuestAddress;
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::byte_code::ByteCode;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::errors::Result;
@@ -90,6 +92,18 @@ fn bin_to_bcd(src: u8) -> u8 {
     ((src / 10) << 4) + (src % 10)
 }
 
+#[allow(clippy::upper_case_acronyms)]
+/// Status of `RTC` device.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct RTCState {
+    /// Static CMOS RAM.
+    cmos_data: [u8; 128],
+    /// Index of Selected register.
+    cur_index: u8,
+}
+
 #[allow(clippy::upper_case_acronyms)]
 /// RTC device.
 pub struct RTC {
@@ -229,6 +243,9 @@ impl RTC {
 
         let dev = Arc::new(Mutex::new(self));
         sysbus.attach_device(&dev, region_base, region_size)?;
+
+        MigrationManager::register_device_instance_mutex(RTCState::descriptor(), dev);
+
         Ok(())
     }
 }
@@ -288,3 +305,33 @@ impl AmlBuilder for RTC {
         acpi_dev.aml_bytes()
     }
 }
+
+impl StateTransfer for RTC {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let state = RTCState {
+            cmos_data: self.cmos_data,
+            cur_index: self.cur_index,
+        };
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let rtc_state = *RTCState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("RTC"))?;
+        self.cmos_data = rtc_state.cmos_data;
+        self.cur_index = rtc_state.cur_index;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&RTCState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for RTC {}
@@ -17,6 +17,8 @@ extern crate log;
 #[macro_use]
 extern crate machine_manager;
 #[macro_use]
+extern crate migration_derive;
+#[macro_use]
 extern crate vmm_sys_util;
 
 pub mod errors {
@@ -106,12 +108,13 @@ pub use micro_vm::LightMachine;
 use pci::{PciBus, PciDevOps, PciHost, RootPort};
 pub use standard_vm::StdMachine;
 use virtio::{
-    BlockState, RngState, VhostKern, VirtioConsoleState, VirtioMmioState, VirtioNetState,
+    BlockState, RngState, VhostKern, VhostUser, VirtioConsoleState, VirtioDevice, VirtioMmioState,
+    VirtioNetState,
 };
 
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::sync::{Arc, Barrier, Mutex, Weak};
+use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 
 #[cfg(target_arch = "x86_64")]
 use address_space::KvmIoListener;
@@ -120,21 +123,28 @@ use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPU};
 use devices::legacy::FwCfgOps;
 #[cfg(target_arch = "aarch64")]
 use devices::InterruptController;
+use error_chain::ChainedError;
 use hypervisor::KVM_FDS;
 use kvm_ioctls::VcpuFd;
 use machine_manager::config::{
     get_multi_function, get_pci_bdf, parse_balloon, parse_blk, parse_net, parse_rng_dev,
-    parse_root_port, parse_vfio, parse_virtconsole, parse_virtio_serial, parse_vsock,
-    MachineMemConfig, PFlashConfig, PciBdf, SerialConfig, VfioConfig, VmConfig,
+    parse_root_port, parse_vfio, parse_vhost_user_blk, parse_virtio_mem, parse_virtio_serial,
+    parse_virtserialport, parse_vsock, MachineMemConfig, PFlashConfig, PciBdf, SerialConfig,
+    VfioConfig, VirtioSerialInfo, VmConfig,
 };
 use machine_manager::event_loop::EventLoop;
-use machine_manager::machine::{KvmVmState, MachineInterface};
-use migration::MigrationManager;
+use machine_manager::machine::{GuestAgent, KvmVmState, MachineInterface, BLOCK_DEVICES};
+use machine_manager::qmp::QmpChannel;
+use migration::{MigrationManager, PauseVmHook, ResumeVmHook};
 use util::loop_context::{EventNotifier, NotifierCallback, NotifierOperation};
+use util::num_ops::round_up;
 use util::seccomp::{BpfRule, SeccompOpt, SyscallFilter};
-use vfio::vfio_pci::create_vfio_container;
+use util::unix::UnixPath;
 use vfio::{VfioContainer, VfioPciDevice};
-use virtio::{balloon_allow_list, Balloon, Block, Console, Rng, VirtioMmioDevice, VirtioPciDevice};
+use virtio::{
+    balloon_allow_list, qmp_query_virtio_mem, Balloon, Block, Console, Rng, VirtioMem,
+    VirtioMmioDevice, VirtioPciDevice,
+};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -153,6 +163,22 @@ pub trait MachineOps {
     /// On x86_64, there is a gap ranged from (4G - 768M) to 4G, which will be skipped.
     fn arch_ram_ranges(&self, mem_size: u64) -> Vec<(u64, u64)>;
 
+    /// Range where hotpluggable memory regions can be placed.
+    ///
+    /// # Returns
+    ///
+    /// (start_addr, end_addr) of the range, the regions are placed above
+    /// both `start_addr` and the end of boot memory.
+    fn hotplug_mem_range(&self) -> (u64, u64);
+
+    /// Ranges of the memory layout which are occupied by devices, hotpluggable
+    /// memory regions must not overlap with them.
+    ///
+    /// # Returns
+    ///
+    /// A array of ranges, it's element represents (start_addr, size).
+    fn reserved_mem_ranges(&self) -> Vec<(u64, u64)>;
+
     fn load_boot_source(&self, fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>) -> Result<CPUBootConfig>;
 
     /// Init I/O & memory address space and mmap guest memory.
@@ -319,6 +345,53 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Add vhost-user block device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_vhost_user_blk(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_vhost_user_blk(cfg_args)?;
+        if !vm_config.machine_config.mem_config.mem_share {
+            bail!("Vhost-user blk device requires mem-share=on for guest memory");
+        }
+        let sys_mem = self.get_sys_mem().clone();
+        let device = Arc::new(Mutex::new(VhostUser::Block::new(&device_cfg, &sys_mem)));
+        if cfg_args.contains("vhost-user-blk-device") {
+            let device = VirtioMmioDevice::new(&sys_mem, device);
+            self.realize_virtio_mmio_device(device)
+                .chain_err(|| ErrorKind::RlzVirtioMmioErr)?;
+        } else {
+            let bdf = get_pci_bdf(cfg_args)?;
+            let multi_func = get_multi_function(cfg_args)?;
+            let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+            let virtio_pci_device = VirtioPciDevice::new(
+                device_cfg.id,
+                devfn,
+                sys_mem,
+                device,
+                parent_bus,
+                multi_func,
+            );
+            virtio_pci_device
+                .realize()
+                .chain_err(|| "Failed to add vhost-user blk pci device")?;
+        }
+
+        Ok(())
+    }
+
+    /// Add vhost-user fs device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_vhost_user_fs(&mut self, _vm_config: &mut VmConfig, _cfg_args: &str) -> Result<()> {
+        bail!("Vhost-user fs device not supported!");
+    }
+
     fn realize_virtio_mmio_device(
         &mut self,
         _dev: VirtioMmioDevice,
@@ -328,6 +401,14 @@ pub trait MachineOps {
 
     fn get_sys_mem(&mut self) -> &Arc<AddressSpace>;
 
+    /// Set the channel to guest agent, by which the QMP commands of guest agent
+    /// are executed.
+    ///
+    /// # Arguments
+    ///
+    /// * `agent` - The channel to guest agent.
+    fn set_guest_agent(&mut self, agent: GuestAgent);
+
     /// Add net device.
     ///
     /// # Arguments
@@ -343,6 +424,8 @@ pub trait MachineOps {
         let sys_mem = self.get_sys_mem();
         let balloon = Arc::new(Mutex::new(Balloon::new(&device_cfg, sys_mem.clone())));
         Balloon::object_init(balloon.clone());
+        Balloon::policy_init(balloon.clone(), &device_cfg)
+            .chain_err(|| "Failed to start balloon policy")?;
         if cfg_args.contains("virtio-balloon-device") {
             let device = VirtioMmioDevice::new(sys_mem, balloon);
             self.realize_virtio_mmio_device(device)?;
@@ -362,52 +445,67 @@ pub trait MachineOps {
         Ok(())
     }
 
-    /// Add console device.
+    /// Add virtio-mem device, whose memory region is placed after boot memory and
+    /// the regions of previously added virtio-mem devices.
     ///
     /// # Arguments
     ///
     /// * `vm_config` - VM configuration.
     /// * `cfg_args` - Device configuration args.
-    fn add_virtio_console(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
-        let device_cfg = parse_virtconsole(vm_config, cfg_args)?;
-        let sys_mem = self.get_sys_mem();
-        let console = Arc::new(Mutex::new(Console::new(device_cfg.clone())));
-        if let Some(serial) = &vm_config.virtio_serial {
-            if serial.pci_bdf.is_none() {
-                let device = VirtioMmioDevice::new(sys_mem, console.clone());
-                MigrationManager::register_device_instance_mutex(
-                    VirtioMmioState::descriptor(),
-                    self.realize_virtio_mmio_device(device)
-                        .chain_err(|| ErrorKind::RlzVirtioMmioErr)?,
-                );
-            } else {
-                let name = device_cfg.id;
-                let virtio_serial_info = if let Some(serial_info) = &vm_config.virtio_serial {
-                    serial_info
-                } else {
-                    bail!("No virtio-serial-pci device configured for virtconsole");
-                };
-                // Reasonable, because for virtio-serial-pci device, the bdf has been checked.
-                let bdf = virtio_serial_info.pci_bdf.clone().unwrap();
-                let multi_func = virtio_serial_info.multifunction;
-                let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
-                let sys_mem = self.get_sys_mem().clone();
-                let virtio_pci_device = VirtioPciDevice::new(
-                    name,
-                    devfn,
-                    sys_mem,
-                    console.clone(),
-                    parent_bus,
-                    multi_func,
-                );
-                virtio_pci_device
-                    .realize()
-                    .chain_err(|| "Failed  to add virtio pci console device")?;
-            }
+    fn add_virtio_mem(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let device_cfg = parse_virtio_mem(vm_config, cfg_args)?;
+        let sys_mem = self.get_sys_mem().clone();
+        let (hotplug_start, hotplug_end) = self.hotplug_mem_range();
+        let region_end = qmp_query_virtio_mem()
+            .iter()
+            .map(|info| info.addr + info.region_size)
+            .fold(sys_mem.memory_end_address().raw_value(), std::cmp::max);
+        let base = round_up(std::cmp::max(region_end, hotplug_start), 1 << 30)
+            .chain_err(|| "Failed to align base address of virtio-mem region")?;
+        if base + device_cfg.size > hotplug_end {
+            bail!(
+                "No enough space for virtio-mem region, base 0x{:x}, size 0x{:x}",
+                base,
+                device_cfg.size
+            );
+        }
+        if let Some((start, size)) = self
+            .reserved_mem_ranges()
+            .into_iter()
+            .find(|(start, size)| base < start + size && *start < base + device_cfg.size)
+        {
+            bail!(
+                "Virtio-mem region (0x{:x}, 0x{:x}) overlaps with reserved range (0x{:x}, 0x{:x})",
+                base,
+                device_cfg.size,
+                start,
+                size
+            );
+        }
+
+        // The backend file of boot memory is mapped from offset 0, so virtio-mem
+        // region only supports anonymous and shared memory.
+        let mut mem_config = vm_config.machine_config.mem_config.clone();
+        mem_config.mem_path = None;
+        let mapping = create_host_mmaps(&[(base, device_cfg.size)], &mem_config)
+            .chain_err(|| "Failed to mmap memory for virtio-mem")?
+            .remove(0);
+        let mem = Arc::new(Mutex::new(VirtioMem::new(&device_cfg, &sys_mem, mapping)));
+        VirtioMem::object_init(mem.clone());
+        if cfg_args.contains("virtio-mem-device") {
+            let device = VirtioMmioDevice::new(&sys_mem, mem);
+            self.realize_virtio_mmio_device(device)?;
         } else {
-            bail!("No virtio-serial-bus specified");
+            let name = device_cfg.id;
+            let bdf = get_pci_bdf(cfg_args)?;
+            let multi_func = get_multi_function(cfg_args)?;
+            let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+            let virtio_pci_device =
+                VirtioPciDevice::new(name, devfn, sys_mem, mem, parent_bus, multi_func);
+            virtio_pci_device
+                .realize()
+                .chain_err(|| "Failed to add virtio pci mem device")?;
         }
-        MigrationManager::register_device_instance_mutex(VirtioConsoleState::descriptor(), console);
 
         Ok(())
     }
@@ -417,6 +515,62 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Add port to virtio-serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Port configuration args.
+    /// * `is_console` - Whether the port is virtconsole.
+    fn add_virtio_serial_port(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        is_console: bool,
+    ) -> Result<()> {
+        parse_virtserialport(vm_config, cfg_args, is_console)?;
+        Ok(())
+    }
+
+    /// Add virtio-serial device with all its ports, which is realized after all the
+    /// ports are parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial_cfg` - Configuration of virtio-serial device and its ports.
+    fn add_virtio_console(&mut self, serial_cfg: &VirtioSerialInfo) -> Result<()> {
+        let sys_mem = self.get_sys_mem();
+        let console = Arc::new(Mutex::new(Console::new(serial_cfg)));
+        if let Some(bdf) = &serial_cfg.pci_bdf {
+            let (devfn, parent_bus) = self.get_devfn_and_parent_bus(bdf)?;
+            let sys_mem = self.get_sys_mem().clone();
+            let virtio_pci_device = VirtioPciDevice::new(
+                serial_cfg.id.clone(),
+                devfn,
+                sys_mem,
+                console.clone(),
+                parent_bus,
+                serial_cfg.multifunction,
+            );
+            virtio_pci_device
+                .realize()
+                .chain_err(|| "Failed  to add virtio pci console device")?;
+        } else {
+            let device = VirtioMmioDevice::new(sys_mem, console.clone());
+            MigrationManager::register_device_instance_mutex(
+                VirtioMmioState::descriptor(),
+                self.realize_virtio_mmio_device(device)
+                    .chain_err(|| ErrorKind::RlzVirtioMmioErr)?,
+            );
+        }
+        if let Some(agent) = console.lock().unwrap().guest_agent() {
+            self.set_guest_agent(agent);
+        }
+        MigrationManager::register_device_instance_mutex(VirtioConsoleState::descriptor(), console);
+
+        Ok(())
+    }
+
     /// Add virtio-rng device.
     ///
     /// # Arguments
@@ -474,6 +628,7 @@ pub trait MachineOps {
         pcidev
             .realize()
             .chain_err(|| "Failed to add virtio pci blk device")?;
+        BLOCK_DEVICES.lock().unwrap().push(device.clone());
         MigrationManager::register_device_instance_mutex(BlockState::descriptor(), device);
         Ok(())
     }
@@ -485,7 +640,15 @@ pub trait MachineOps {
         let sys_mem = self.get_sys_mem();
         let device_cfg = parse_net(vm_config, cfg_args)?;
         let virtio_pci_device = if device_cfg.vhost_type.is_some() {
-            let device = Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &sys_mem)));
+            let device: Arc<Mutex<dyn VirtioDevice>> =
+                if device_cfg.vhost_type == Some(String::from("vhost-user")) {
+                    if !vm_config.machine_config.mem_config.mem_share {
+                        bail!("Vhost-user net device requires mem-share=on for guest memory");
+                    }
+                    Arc::new(Mutex::new(VhostUser::Net::new(&device_cfg, &sys_mem)))
+                } else {
+                    Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &sys_mem)))
+                };
             VirtioPciDevice::new(
                 device_cfg.id,
                 devfn,
@@ -515,6 +678,11 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Get the VFIO container shared by all the VFIO devices.
+    fn get_vfio_container(&mut self) -> Result<Arc<VfioContainer>> {
+        bail!("Vfio devices not supported");
+    }
+
     fn add_vfio_device(
         &mut self,
         vm_config: &VmConfig,
@@ -598,7 +766,6 @@ pub trait MachineOps {
                 .chain_err(|| ErrorKind::AddDevErr("pflash".to_string()))?;
         }
 
-        let mut container: Option<Arc<VfioContainer>> = None;
         for dev in &cloned_vm_config.devices {
             let cfg_args = dev.1.as_str();
             match dev.0.as_str() {
@@ -608,6 +775,12 @@ pub trait MachineOps {
                 "virtio-blk-pci" => {
                     self.add_virtio_pci_blk(vm_config, cfg_args)?;
                 }
+                "vhost-user-blk-device" | "vhost-user-blk-pci" => {
+                    self.add_vhost_user_blk(vm_config, cfg_args)?;
+                }
+                "vhost-user-fs-device" => {
+                    self.add_vhost_user_fs(vm_config, cfg_args)?;
+                }
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args)?;
                 }
@@ -623,23 +796,21 @@ pub trait MachineOps {
                 "virtio-balloon-device" | "virtio-balloon-pci" => {
                     self.add_virtio_balloon(vm_config, cfg_args)?;
                 }
+                "virtio-mem-device" | "virtio-mem-pci" => {
+                    self.add_virtio_mem(vm_config, cfg_args)?;
+                }
                 "virtio-serial-device" | "virtio-serial-pci" => {
                     self.add_virtio_serial(vm_config, cfg_args)?;
                 }
-                "virtconsole" => {
-                    self.add_virtio_console(vm_config, cfg_args)?;
+                "virtconsole" | "virtserialport" => {
+                    self.add_virtio_serial_port(vm_config, cfg_args, dev.0 == "virtconsole")?;
                 }
                 "virtio-rng-device" | "virtio-rng-pci" => {
                     self.add_virtio_rng(vm_config, cfg_args)?;
                 }
                 "vfio-pci" => {
-                    if container.is_none() {
-                        container = Some(
-                            create_vfio_container(self.get_sys_mem().clone())
-                                .chain_err(|| "Failed to create vfio container")?,
-                        );
-                    }
-                    self.add_vfio_device(&vm_config, cfg_args, container.clone().unwrap())?;
+                    let container = self.get_vfio_container()?;
+                    self.add_vfio_device(&vm_config, cfg_args, container)?;
                 }
                 _ => {
                     bail!("Unsupported device: {:?}", dev.0.as_str());
@@ -647,6 +818,11 @@ pub trait MachineOps {
             }
         }
 
+        if let Some(serial_cfg) = vm_config.virtio_serial.clone() {
+            self.add_virtio_console(&serial_cfg)
+                .chain_err(|| ErrorKind::AddDevErr("virtio-serial".to_string()))?;
+        }
+
         Ok(())
     }
 
@@ -869,4 +1045,63 @@ pub trait MachineOps {
 
         Ok(())
     }
+
+    /// Start live migration to the destination at `addr`. VM is paused by
+    /// migration before the stop-and-copy phase, and resumed if migration fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_type` - Socket type of destination, `Tcp` or `Unix`.
+    /// * `addr` - Address of destination.
+    /// * `cpus` - Cpus vector restore cpu structure.
+    /// * `irq_chip` - The interrupt controller of VM.
+    /// * `vm_state` - Vm kvm vm state.
+    fn start_live_migration(
+        path_type: UnixPath,
+        addr: &str,
+        cpus: Vec<Arc<CPU>>,
+        #[cfg(target_arch = "aarch64")] irq_chip: Option<Arc<InterruptController>>,
+        vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+    ) -> Result<()>
+    where
+        Self: Sized + 'static,
+    {
+        let pause_cpus = cpus.clone();
+        let pause_state = vm_state.clone();
+        #[cfg(target_arch = "aarch64")]
+        let pause_irq_chip = irq_chip.clone();
+        let pause: PauseVmHook = Box::new(move || {
+            let mut state = pause_state.0.lock().unwrap();
+            if *state != KvmVmState::Running {
+                return Ok(false);
+            }
+            <Self as MachineOps>::vm_state_transfer(
+                &pause_cpus,
+                #[cfg(target_arch = "aarch64")]
+                &pause_irq_chip,
+                &mut state,
+                KvmVmState::Running,
+                KvmVmState::Paused,
+            )
+            .map_err(|e| e.display_chain().to_string())?;
+            event!(Stop);
+            Ok(true)
+        });
+        let resume: ResumeVmHook = Box::new(move || {
+            <Self as MachineOps>::vm_state_transfer(
+                &cpus,
+                #[cfg(target_arch = "aarch64")]
+                &irq_chip,
+                &mut vm_state.0.lock().unwrap(),
+                KvmVmState::Paused,
+                KvmVmState::Running,
+            )
+            .map_err(|e| e.display_chain().to_string())?;
+            event!(Resume);
+            Ok(())
+        });
+
+        MigrationManager::send_migration(path_type, addr, pause, resume)
+            .chain_err(|| "Failed to start live migration")
+    }
 }
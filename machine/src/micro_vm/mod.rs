@@ -79,27 +79,30 @@ use hypervisor::KVM_FDS;
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use machine_manager::config::parse_blk;
+use machine_manager::config::parse_fs;
 use machine_manager::config::parse_net;
-use machine_manager::config::BlkDevConfig;
+use machine_manager::config::{BlkDevConfig, DiskFormat, ThrottleConfig, WriteZeroesState};
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
-    MachineInterface, MachineLifecycle, MigrateInterface,
+    DeviceInterface, GuestAgent, KvmVmState, MachineAddressInterface, MachineExternalInterface,
+    MachineInterface, MachineLifecycle, MigrateInterface, BLOCK_DEVICES,
 };
 use machine_manager::{
     config::{BootSource, ConfigCheck, NetworkInterfaceConfig, SerialConfig, VmConfig},
     qmp::{qmp_schema, QmpChannel, Response},
 };
-use migration::{MigrationManager, MigrationStatus};
+use migration::{Compression, MigrationManager, MigrationStatus};
 use sysbus::SysBus;
 #[cfg(target_arch = "aarch64")]
 use sysbus::{SysBusDevType, SysRes};
+use util::aio::AioEngine;
 #[cfg(target_arch = "aarch64")]
 use util::device_tree::{self, CompileFDT, FdtBuilder};
 use util::loop_context::EventLoopManager;
 use util::seccomp::BpfRule;
 use util::set_termi_canon_mode;
 use virtio::{
-    create_tap, qmp_balloon, qmp_query_balloon, Block, BlockState, Net, VhostKern, VirtioDevice,
+    create_tap, qmp_balloon, qmp_query_balloon, qmp_query_balloon_stats, qmp_query_virtio_mem,
+    qmp_virtio_mem_resize, Block, BlockState, Net, VhostKern, VhostUser, VirtioDevice,
     VirtioMmioDevice, VirtioMmioState, VirtioNetState,
 };
 use vmm_sys_util::eventfd::EventFd;
@@ -182,6 +185,10 @@ pub struct LightMachine {
     boot_source: Arc<Mutex<BootSource>>,
     // VM power button, handle VM `Shutdown` event.
     power_button: EventFd,
+    // Base of the free space for shared memory regions of virtio devices.
+    shm_free_base: u64,
+    // Channel to guest agent.
+    guest_agent: Option<GuestAgent>,
 }
 
 impl LightMachine {
@@ -193,6 +200,10 @@ impl LightMachine {
     pub fn new(vm_config: &VmConfig) -> MachineResult<Self> {
         use crate::errors::ResultExt;
 
+        if vm_config.machine_config.max_cpus != vm_config.machine_config.nr_cpus {
+            bail!("CPU hotplug is not supported by micro VM, \'maxcpus\' should equal to \'cpus\'");
+        }
+
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
             .chain_err(|| MachineErrorKind::CrtMemSpaceErr)?;
         #[cfg(target_arch = "x86_64")]
@@ -224,7 +235,10 @@ impl LightMachine {
         }
 
         Ok(LightMachine {
-            cpu_topo: CpuTopology::new(vm_config.machine_config.nr_cpus),
+            cpu_topo: CpuTopology::new(
+                vm_config.machine_config.nr_cpus,
+                vm_config.machine_config.max_cpus,
+            ),
             cpus: Vec::new(),
             #[cfg(target_arch = "aarch64")]
             irq_chip: None,
@@ -236,6 +250,8 @@ impl LightMachine {
             boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
             vm_state,
             power_button,
+            shm_free_base: MEM_LAYOUT[LayoutEntryType::VirtioShm as usize].0,
+            guest_agent: None,
         })
     }
 
@@ -269,6 +285,7 @@ impl LightMachine {
             let virtio_mmio = VirtioMmioDevice::new(&self.sys_mem, block.clone());
             rpl_devs.push(virtio_mmio);
 
+            BLOCK_DEVICES.lock().unwrap().push(block.clone());
             MigrationManager::register_device_instance_mutex(BlockState::descriptor(), block);
         }
         for _ in 0..MMIO_REPLACEABLE_NET_NR {
@@ -470,6 +487,37 @@ impl MachineOps for LightMachine {
         ranges
     }
 
+    fn hotplug_mem_range(&self) -> (u64, u64) {
+        #[cfg(target_arch = "aarch64")]
+        let range = (
+            MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
+            MEM_LAYOUT[LayoutEntryType::HighGicRedist as usize].0,
+        );
+        #[cfg(target_arch = "x86_64")]
+        let range = (
+            MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0,
+            MEM_LAYOUT[LayoutEntryType::VirtioShm as usize].0,
+        );
+        range
+    }
+
+    fn reserved_mem_ranges(&self) -> Vec<(u64, u64)> {
+        #[cfg(target_arch = "aarch64")]
+        let ranges = MEM_LAYOUT[LayoutEntryType::GicDist as usize..LayoutEntryType::Mem as usize]
+            .iter()
+            .chain(MEM_LAYOUT[LayoutEntryType::HighGicRedist as usize..].iter())
+            .copied()
+            .collect();
+        #[cfg(target_arch = "x86_64")]
+        let ranges = MEM_LAYOUT
+            [LayoutEntryType::Mmio as usize..LayoutEntryType::MemAbove4g as usize]
+            .iter()
+            .chain(MEM_LAYOUT[LayoutEntryType::VirtioShm as usize..].iter())
+            .copied()
+            .collect();
+        ranges
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn init_interrupt_controller(&mut self, _vcpu_count: u64) -> MachineResult<()> {
         use crate::errors::ResultExt;
@@ -601,6 +649,10 @@ impl MachineOps for LightMachine {
         &self.sys_mem
     }
 
+    fn set_guest_agent(&mut self, agent: GuestAgent) {
+        self.guest_agent = Some(agent);
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn add_rtc_device(&mut self) -> MachineResult<()> {
         use crate::errors::ResultExt;
@@ -651,8 +703,19 @@ impl MachineOps for LightMachine {
         cfg_args: &str,
     ) -> MachineResult<()> {
         let device_cfg = parse_net(vm_config, cfg_args)?;
+        if device_cfg.queues > 1 {
+            bail!("Multi-queue is not supported for virtio mmio net device.");
+        }
         if device_cfg.vhost_type.is_some() {
-            let net = Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)));
+            let net: Arc<Mutex<dyn VirtioDevice>> =
+                if device_cfg.vhost_type == Some(String::from("vhost-user")) {
+                    if !vm_config.machine_config.mem_config.mem_share {
+                        bail!("Vhost-user net device requires mem-share=on for guest memory");
+                    }
+                    Arc::new(Mutex::new(VhostUser::Net::new(&device_cfg, &self.sys_mem)))
+                } else {
+                    Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)))
+                };
             let device = VirtioMmioDevice::new(&self.sys_mem, net);
             self.realize_virtio_mmio_device(device)?;
         } else {
@@ -675,6 +738,9 @@ impl MachineOps for LightMachine {
         cfg_args: &str,
     ) -> MachineResult<()> {
         let device_cfg = parse_blk(vm_config, cfg_args)?;
+        if device_cfg.queues > 1 {
+            bail!("Multi-queue is not supported for replaceable block device.");
+        }
         if self.replaceable_info.block_count >= MMIO_REPLACEABLE_BLK_NR {
             bail!(
                 "A maximum of {} block replaceable devices are supported.",
@@ -687,6 +753,28 @@ impl MachineOps for LightMachine {
         Ok(())
     }
 
+    fn add_vhost_user_fs(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> MachineResult<()> {
+        let device_cfg = parse_fs(vm_config, cfg_args)?;
+        if !vm_config.machine_config.mem_config.mem_share {
+            bail!("Vhost-user fs device requires mem-share=on for guest memory");
+        }
+        let shm_end = MEM_LAYOUT[LayoutEntryType::VirtioShm as usize].0
+            + MEM_LAYOUT[LayoutEntryType::VirtioShm as usize].1;
+        if self.shm_free_base + device_cfg.cache_size > shm_end {
+            bail!("No enough space for DAX cache window of vhost-user fs device.");
+        }
+        let cache_base = GuestAddress(self.shm_free_base);
+        let fs = Arc::new(Mutex::new(VhostUser::Fs::new(
+            &device_cfg,
+            &self.sys_mem,
+            cache_base,
+        )));
+        let device = VirtioMmioDevice::new(&self.sys_mem, fs);
+        self.realize_virtio_mmio_device(device)?;
+        self.shm_free_base += device_cfg.cache_size;
+        Ok(())
+    }
+
     fn syscall_whitelist(&self) -> Vec<BpfRule> {
         syscall_whitelist()
     }
@@ -996,22 +1084,49 @@ impl DeviceInterface for LightMachine {
         )
     }
 
-    fn device_add(
-        &self,
-        id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> Response {
+    fn query_balloon_stats(&self) -> Response {
+        if let Some(stats) = qmp_query_balloon_stats() {
+            return Response::create_response(serde_json::to_value(&stats).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn virtio_mem_resize(&self, id: String, requested_size: u64) -> Response {
+        match qmp_virtio_mem_resize(&id, requested_size) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn query_virtio_mem(&self) -> Response {
+        let infos = qmp_query_virtio_mem();
+        Response::create_response(serde_json::to_value(&infos).unwrap(), None)
+    }
+
+    fn device_add(&self, args: Box<qmp_schema::device_add>) -> Response {
+        let id = args.id;
+        let driver = args.driver;
+
         // get slot of bus by addr or lun
         let mut slot = 0;
-        if let Some(addr) = addr {
+        if let Some(addr) = args.addr {
             let slot_str = addr.as_str().trim_start_matches("0x");
 
             if let Ok(n) = usize::from_str_radix(slot_str, 16) {
                 slot = n;
             }
-        } else if let Some(lun) = lun {
+        } else if let Some(lun) = args.lun {
             slot = lun + 1;
         }
 
@@ -1055,17 +1170,29 @@ impl DeviceInterface for LightMachine {
         file: qmp_schema::FileOptions,
         cache: Option<qmp_schema::CacheOptions>,
         read_only: Option<bool>,
+        driver: Option<String>,
     ) -> Response {
         const MAX_STRING_LENGTH: usize = 255;
         let read_only = if let Some(ro) = read_only { ro } else { false };
 
+        let format = match driver.as_deref().unwrap_or("raw").parse::<DiskFormat>() {
+            Ok(format) => format,
+            Err(_) => {
+                error!("Block driver {:?} is not supported", driver);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError("Unsupported block driver".to_string()),
+                    None,
+                );
+            }
+        };
+
         let direct = if let Some(cache) = cache {
             match cache.direct {
                 Some(direct) => direct,
-                _ => true,
+                _ => format == DiskFormat::Raw,
             }
         } else {
-            true
+            format == DiskFormat::Raw
         };
 
         let blk = Path::new(&file.filename);
@@ -1115,8 +1242,24 @@ impl DeviceInterface for LightMachine {
             direct,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            format,
+            aio: if direct {
+                AioEngine::Native
+            } else {
+                AioEngine::Threads
+            },
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+            queues: 1,
         };
+        if let Err(ref e) = config.check() {
+            error!("{}", e.display_chain());
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
         match self.add_replaceable_config(&node_name, Arc::new(config)) {
             Ok(()) => Response::create_empty_response(),
             Err(ref e) => {
@@ -1138,6 +1281,8 @@ impl DeviceInterface for LightMachine {
             vhost_type: None,
             vhost_fd: None,
             iothread: None,
+            queues: 1,
+            socket_path: None,
         };
 
         if let Some(fds) = fds {
@@ -1171,7 +1316,7 @@ impl DeviceInterface for LightMachine {
             }
         } else if let Some(if_name) = if_name {
             config.host_dev_name = if_name.clone();
-            if create_tap(None, Some(&if_name)).is_err() {
+            if create_tap(None, Some(&if_name), 1).is_err() {
                 return Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(
                         "Tap device already in use".to_string(),
@@ -1203,15 +1348,44 @@ impl DeviceInterface for LightMachine {
             Response::create_error_response(err_resp, None)
         }
     }
+
+    fn get_guest_agent(&self) -> Option<GuestAgent> {
+        self.guest_agent.clone()
+    }
 }
 
 impl MigrateInterface for LightMachine {
-    fn migrate(&self, uri: String) -> Response {
+    fn migrate(
+        &self,
+        uri: String,
+        incremental: Option<bool>,
+        dirty_log: Option<bool>,
+        compress: Option<String>,
+    ) -> Response {
         use util::unix::{parse_uri, UnixPath};
 
+        let compression = match compress.as_ref().map(|c| c.parse::<Compression>()) {
+            Some(Ok(compression)) => Some(compression),
+            Some(Err(())) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!(
+                        "Invalid compression algorithm: {}",
+                        compress.unwrap()
+                    )),
+                    None,
+                );
+            }
+            None => None,
+        };
+
         match parse_uri(&uri) {
             Ok((UnixPath::File, path)) => {
-                if let Err(e) = MigrationManager::save_snapshot(&path) {
+                if let Err(e) = MigrationManager::save_snapshot(
+                    &path,
+                    incremental.unwrap_or(false),
+                    dirty_log.unwrap_or(false),
+                    compression,
+                ) {
                     error!(
                         "Failed to migrate to path \'{:?}\': {}",
                         path,
@@ -1225,6 +1399,35 @@ impl MigrateInterface for LightMachine {
                     );
                 }
             }
+            Ok(_)
+                if incremental.unwrap_or(false)
+                    || dirty_log.unwrap_or(false)
+                    || compression.is_some() =>
+            {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(
+                        "Options incremental, dirty-log and compress are only for snapshot"
+                            .to_string(),
+                    ),
+                    None,
+                );
+            }
+            Ok((path_type, addr)) if path_type == UnixPath::Tcp || path_type == UnixPath::Unix => {
+                if let Err(e) = <Self as MachineOps>::start_live_migration(
+                    path_type,
+                    &addr,
+                    self.cpus.clone(),
+                    #[cfg(target_arch = "aarch64")]
+                    self.irq_chip.clone(),
+                    self.vm_state.clone(),
+                ) {
+                    error!("Failed to migrate to \'{}\': {}", addr, e.display_chain());
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            }
             _ => {
                 return Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
@@ -1237,10 +1440,31 @@ impl MigrateInterface for LightMachine {
     }
 
     fn query_migrate(&self) -> Response {
-        let status_str = MigrationManager::migration_get_status().to_string();
-        let migration_info = qmp_schema::MigrationInfo {
-            status: Some(status_str),
+        let status = MigrationManager::migration_get_status();
+        let mut migration_info = qmp_schema::MigrationInfo {
+            status: Some(status.to_string()),
+            ..Default::default()
         };
+        if let Some(stats) = MigrationManager::migration_get_stats() {
+            migration_info.total_time = Some(stats.total_time);
+            if status == MigrationStatus::Completed {
+                migration_info.downtime = Some(stats.downtime);
+            }
+            migration_info.ram = Some(qmp_schema::MigrationStats {
+                transferred: stats.transferred_ram,
+                remaining: stats.remaining_ram,
+                total: stats.total_ram,
+                dirty_sync_count: stats.dirty_sync_count,
+            });
+        }
+        if let Some(stats) = MigrationManager::lazy_restore_stats() {
+            migration_info.lazy_restore = Some(qmp_schema::LazyRestoreStats {
+                total_pages: stats.total_pages(),
+                missing_pages: stats.missing_pages(),
+                prefetched_pages: stats.prefetched_pages(),
+                remaining_pages: stats.remaining_pages(),
+            });
+        }
 
         Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
     }
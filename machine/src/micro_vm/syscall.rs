@@ -19,10 +19,11 @@ use kvm_bindings::{
 };
 #[cfg(target_arch = "aarch64")]
 use kvm_bindings::{kvm_device_attr, kvm_one_reg, kvm_reg_list};
-use kvm_bindings::{kvm_mp_state, kvm_vcpu_events};
+use kvm_bindings::{kvm_dirty_log, kvm_mp_state, kvm_vcpu_events};
 
 use util::seccomp::{BpfRule, SeccompCmpOpt};
 use util::tap::{TUNSETIFF, TUNSETOFFLOAD, TUNSETVNETHDRSZ};
+use util::userfaultfd::{UFFDIO_COPY, UFFDIO_UNREGISTER};
 use virtio::VhostKern::*;
 
 /// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/futex.h
@@ -54,11 +55,14 @@ const KVM_RUN: u32 = 0xae80;
 
 // See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/asm-generic/kvm.h
 const KVM_SET_DEVICE_ATTR: u32 = 0x4018_aee1;
+const KVM_SET_USER_MEMORY_REGION: u32 = 0x4020_ae46;
 
 const KVMIO: c_uint = 0xAE;
 ioctl_io_nr!(KVM_GET_API_VERSION, KVMIO, 0x00);
 ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvm_mp_state);
 ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvm_vcpu_events);
+// Used to get dirty pages of guest memory in live migration.
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
 #[cfg(target_arch = "x86_64")]
 ioctl_ior_nr!(KVM_GET_PIT2, KVMIO, 0x9f, kvm_pit_state2);
 #[cfg(target_arch = "x86_64")]
@@ -92,10 +96,10 @@ ioctl_iowr_nr!(KVM_GET_REG_LIST, KVMIO, 0xb0, kvm_reg_list);
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 41 syscalls
-/// * x86_64-unknown-musl: 41 syscalls
-/// * aarch64-unknown-gnu: 39 syscalls
-/// * aarch64-unknown-musl: 40 syscalls
+/// * x86_64-unknown-gnu: 57 syscalls
+/// * x86_64-unknown-musl: 54 syscalls
+/// * aarch64-unknown-gnu: 55 syscalls
+/// * aarch64-unknown-musl: 53 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -112,12 +116,17 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_close),
         BpfRule::new(libc::SYS_eventfd2),
         BpfRule::new(libc::SYS_epoll_ctl),
+        // Used to handle page faults of lazy restore.
+        BpfRule::new(libc::SYS_ppoll),
         BpfRule::new(libc::SYS_fdatasync),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         BpfRule::new(libc::SYS_recvfrom),
         BpfRule::new(libc::SYS_mremap),
         BpfRule::new(libc::SYS_io_setup),
+        BpfRule::new(libc::SYS_io_uring_setup),
+        BpfRule::new(libc::SYS_io_uring_enter),
+        BpfRule::new(libc::SYS_io_uring_register),
         BpfRule::new(libc::SYS_brk),
         BpfRule::new(libc::SYS_fcntl)
             .add_constraint(SeccompCmpOpt::Eq, 1, F_DUPFD_CLOEXEC)
@@ -129,8 +138,15 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_openat),
         BpfRule::new(libc::SYS_sigaltstack),
         BpfRule::new(libc::SYS_mmap),
+        #[cfg(target_env = "gnu")]
+        BpfRule::new(libc::SYS_mprotect),
         BpfRule::new(libc::SYS_munmap),
+        BpfRule::new(libc::SYS_nanosleep),
+        BpfRule::new(libc::SYS_clock_nanosleep),
+        BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_accept4),
+        BpfRule::new(libc::SYS_socket),
+        BpfRule::new(libc::SYS_connect),
         BpfRule::new(libc::SYS_lseek),
         BpfRule::new(libc::SYS_futex)
             .add_constraint(SeccompCmpOpt::Eq, 1, FUTEX_WAKE_PRIVATE)
@@ -141,6 +157,15 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_exit),
         BpfRule::new(libc::SYS_exit_group),
         BpfRule::new(libc::SYS_rt_sigreturn),
+        // Used to spawn the thread of live migration.
+        BpfRule::new(libc::SYS_clone),
+        #[cfg(target_env = "gnu")]
+        BpfRule::new(libc::SYS_clone3),
+        BpfRule::new(libc::SYS_set_robust_list),
+        #[cfg(target_env = "gnu")]
+        BpfRule::new(libc::SYS_rseq),
+        BpfRule::new(libc::SYS_prctl),
+        BpfRule::new(libc::SYS_rt_sigaction),
         #[cfg(target_env = "musl")]
         BpfRule::new(libc::SYS_tkill),
         #[cfg(target_env = "gnu")]
@@ -167,7 +192,8 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_mkdirat),
         BpfRule::new(libc::SYS_madvise)
             .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTNEED as u32)
-            .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32),
+            .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32)
+            .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32),
     ]
 }
 
@@ -181,6 +207,7 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, FIONBIO)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_RUN)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_DEVICE_ATTR)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_USER_MEMORY_REGION)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_GUEST_CID() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_RUNNING() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_CALL() as u32)
@@ -196,9 +223,12 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETOFFLOAD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETVNETHDRSZ() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_COPY() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_UNREGISTER() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_API_VERSION() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_MP_STATE() as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_VCPU_EVENTS() as u32);
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_VCPU_EVENTS() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DIRTY_LOG() as u32);
     ioctl_arch_allow_list(bpf_rule)
 }
 
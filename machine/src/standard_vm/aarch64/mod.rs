@@ -26,24 +26,28 @@ use devices::legacy::{
 use devices::{InterruptController, InterruptControllerConfig};
 use error_chain::ChainedError;
 use hypervisor::KVM_FDS;
-use machine_manager::config::{BootSource, PFlashConfig, SerialConfig, VmConfig};
+use machine_manager::config::{BootSource, PFlashConfig, Param, SerialConfig, VmConfig};
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
+    DeviceInterface, GuestAgent, KvmVmState, MachineAddressInterface, MachineExternalInterface,
     MachineInterface, MachineLifecycle, MigrateInterface,
 };
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
-use migration::{MigrationManager, MigrationStatus};
-use pci::{PciDevOps, PciHost};
+use migration::{Compression, MigrationManager, MigrationStatus};
+use pci::{PciBus, PciDevOps, PciHost};
 use sysbus::{SysBus, SysBusDevType, SysRes};
 use util::byte_code::ByteCode;
 use util::device_tree::{self, CompileFDT, FdtBuilder};
 use util::loop_context::EventLoopManager;
 use util::seccomp::BpfRule;
 use util::set_termi_canon_mode;
-use virtio::{qmp_balloon, qmp_query_balloon};
+use vfio::VfioContainer;
+use virtio::{
+    qmp_balloon, qmp_query_balloon, qmp_query_balloon_stats, qmp_query_virtio_mem,
+    qmp_virtio_mem_resize,
+};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{AcpiBuilder, StdMachineOps};
+use super::{get_or_create_vfio_container, AcpiBuilder, StdMachineOps, CPU_TYPE};
 use crate::errors::{ErrorKind, Result};
 use crate::MachineOps;
 use crate::{errors::Result as MachineResult, standard_vm::open_pflash_file};
@@ -112,13 +116,24 @@ pub struct StdMachine {
     boot_source: Arc<Mutex<BootSource>>,
     /// VM power button, handle VM `Shutdown` event.
     power_button: EventFd,
+    /// VM configuration, holds the drives and netdevs for hotplugged devices.
+    vm_config: Mutex<VmConfig>,
+    /// Device ids of the hotplugged `vCPU`s, indexed by `vCPU` id.
+    cpu_ids: Arc<Mutex<Vec<Option<String>>>>,
+    /// VFIO container shared by all the VFIO devices.
+    vfio_container: Mutex<Option<Arc<VfioContainer>>>,
+    /// Channel to guest agent.
+    guest_agent: Option<GuestAgent>,
 }
 
 impl StdMachine {
     pub fn new(vm_config: &VmConfig) -> Result<Self> {
         use crate::errors::ResultExt;
 
-        let cpu_topo = CpuTopology::new(vm_config.machine_config.nr_cpus);
+        let cpu_topo = CpuTopology::new(
+            vm_config.machine_config.nr_cpus,
+            vm_config.machine_config.max_cpus,
+        );
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
             .chain_err(|| ErrorKind::CrtIoSpaceErr)?;
         let sysbus = SysBus::new(
@@ -145,6 +160,14 @@ impl StdMachine {
             vm_state: Arc::new((Mutex::new(KvmVmState::Created), Condvar::new())),
             power_button: EventFd::new(libc::EFD_NONBLOCK)
                 .chain_err(|| ErrorKind::InitPwrBtnErr)?,
+
+            vm_config: Mutex::new(vm_config.clone()),
+            cpu_ids: Arc::new(Mutex::new(vec![
+                None;
+                vm_config.machine_config.max_cpus as usize
+            ])),
+            vfio_container: Mutex::new(None),
+            guest_agent: None,
         })
     }
 
@@ -154,7 +177,11 @@ impl StdMachine {
     ///
     /// * `paused` - Flag for `paused` when `LightMachine` starts to run.
     pub fn run(&self, paused: bool) -> Result<()> {
-        <Self as MachineOps>::vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())
+        <Self as MachineOps>::vm_start(
+            paused,
+            &self.online_cpus(),
+            &mut self.vm_state.0.lock().unwrap(),
+        )
     }
 }
 
@@ -188,7 +215,7 @@ impl StdMachineOps for StdMachine {
         use super::errors::ResultExt;
 
         let mut fwcfg = FwCfgMem::new(self.sys_mem.clone());
-        let ncpus = self.cpus.len();
+        let ncpus = self.cpu_topo.nrcpus as usize;
         fwcfg
             .add_data_entry(FwCfgEntryType::NbCpus, ncpus.as_bytes().to_vec())
             .chain_err(|| DevErrorKind::AddEntryErr("NbCpus".to_string()))?;
@@ -224,6 +251,50 @@ impl StdMachineOps for StdMachine {
 
         Ok(fwcfg_dev)
     }
+
+    fn get_vm_config(&self) -> &Mutex<VmConfig> {
+        &self.vm_config
+    }
+
+    fn get_pci_root_bus(&self) -> Arc<Mutex<PciBus>> {
+        self.pci_host.lock().unwrap().root_bus.clone()
+    }
+
+    fn get_guest_memory(&self) -> &Arc<AddressSpace> {
+        &self.sys_mem
+    }
+
+    fn get_cpu_topo(&self) -> &CpuTopology {
+        &self.cpu_topo
+    }
+
+    fn get_cpus(&self) -> &[Arc<CPU>] {
+        &self.cpus
+    }
+
+    fn get_cpu_ids(&self) -> &Arc<Mutex<Vec<Option<String>>>> {
+        &self.cpu_ids
+    }
+
+    fn get_vfio_container(&self) -> super::errors::Result<Arc<VfioContainer>> {
+        get_or_create_vfio_container(&self.vfio_container, &self.sys_mem)
+    }
+
+    fn get_vm_state(&self) -> &Arc<(Mutex<KvmVmState>, Condvar)> {
+        &self.vm_state
+    }
+
+    fn notify_cpu_plug(&self, _cpu_index: u8) -> super::errors::Result<()> {
+        // The plugged vcpu is described in FDT already, and is brought up by
+        // the guest through PSCI CPU_ON.
+        Ok(())
+    }
+
+    fn request_cpu_unplug(&self, _cpu_index: u8) -> super::errors::Result<()> {
+        // Without an eject handshake, the vcpu may be removed while the guest
+        // is still running on it.
+        bail!("Cpu hot unplug is not supported on aarch64");
+    }
 }
 
 impl MachineOps for StdMachine {
@@ -231,6 +302,21 @@ impl MachineOps for StdMachine {
         vec![(MEM_LAYOUT[LayoutEntryType::Mem as usize].0, mem_size)]
     }
 
+    fn hotplug_mem_range(&self) -> (u64, u64) {
+        (
+            MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
+            MEM_LAYOUT[LayoutEntryType::HighGicRedist as usize].0,
+        )
+    }
+
+    fn reserved_mem_ranges(&self) -> Vec<(u64, u64)> {
+        MEM_LAYOUT[LayoutEntryType::Flash as usize..LayoutEntryType::Mem as usize]
+            .iter()
+            .chain(MEM_LAYOUT[LayoutEntryType::HighGicRedist as usize..].iter())
+            .copied()
+            .collect()
+    }
+
     fn init_interrupt_controller(&mut self, vcpu_count: u64) -> Result<()> {
         use crate::errors::ResultExt;
 
@@ -333,9 +419,12 @@ impl MachineOps for StdMachine {
             is_migrate,
         )?;
 
+        // The hotpluggable vcpus are created at boot too, so that the GICv3
+        // redistributors are allocated for them.
+        let max_cpus = vm_config.machine_config.max_cpus;
         let vcpu_fds = {
             let mut fds = vec![];
-            for vcpu_id in 0..vm_config.machine_config.nr_cpus {
+            for vcpu_id in 0..max_cpus {
                 fds.push(Arc::new(
                     KVM_FDS
                         .load()
@@ -349,16 +438,30 @@ impl MachineOps for StdMachine {
         };
 
         // Interrupt Controller Chip init
-        locked_vm.init_interrupt_controller(u64::from(vm_config.machine_config.nr_cpus))?;
+        locked_vm.init_interrupt_controller(u64::from(max_cpus))?;
         locked_vm
             .init_pci_host()
             .chain_err(|| StdErrorKind::InitPCIeHostErr)?;
         locked_vm
             .add_devices(vm_config)
             .chain_err(|| "Failed to add devices")?;
+        *locked_vm.vm_config.lock().unwrap() = vm_config.clone();
+
+        // All the vcpus are described in FDT, only boot `cpus` of them.
+        let nr_cpus = vm_config.machine_config.nr_cpus;
+        if max_cpus > nr_cpus {
+            let mut boot_source = locked_vm.boot_source.lock().unwrap();
+            if !boot_source.kernel_cmdline.contains("maxcpus") {
+                boot_source.kernel_cmdline.push(Param {
+                    param_type: "maxcpus".to_string(),
+                    value: nr_cpus.to_string(),
+                });
+            }
+        }
 
+        // FwCfg device is created when restoring too, so that it's still accessible to guest.
+        let fwcfg = locked_vm.add_fwcfg_device()?;
         let boot_config = if !is_migrate {
-            let fwcfg = locked_vm.add_fwcfg_device()?;
             Some(locked_vm.load_boot_source(Some(&fwcfg))?)
         } else {
             None
@@ -366,7 +469,7 @@ impl MachineOps for StdMachine {
 
         locked_vm.cpus.extend(<Self as MachineOps>::init_vcpu(
             vm.clone(),
-            vm_config.machine_config.nr_cpus,
+            max_cpus,
             &vcpu_fds,
             &boot_config,
         )?);
@@ -428,16 +531,31 @@ impl MachineOps for StdMachine {
     }
 
     fn run(&self, paused: bool) -> Result<()> {
-        <Self as MachineOps>::vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())
+        <Self as MachineOps>::vm_start(
+            paused,
+            &self.online_cpus(),
+            &mut self.vm_state.0.lock().unwrap(),
+        )
     }
 
     fn get_sys_mem(&mut self) -> &Arc<AddressSpace> {
         &self.sys_mem
     }
 
+    fn set_guest_agent(&mut self, agent: GuestAgent) {
+        self.guest_agent = Some(agent);
+    }
+
     fn get_pci_host(&mut self) -> MachineResult<&Arc<Mutex<PciHost>>> {
         Ok(&self.pci_host)
     }
+
+    fn get_vfio_container(&mut self) -> MachineResult<Arc<VfioContainer>> {
+        Ok(get_or_create_vfio_container(
+            &self.vfio_container,
+            &self.sys_mem,
+        )?)
+    }
 }
 
 impl AcpiBuilder for StdMachine {}
@@ -474,7 +592,7 @@ impl MachineLifecycle for StdMachine {
 
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
         <Self as MachineOps>::vm_state_transfer(
-            &self.cpus,
+            &self.online_cpus(),
             #[cfg(target_arch = "aarch64")]
             &self.irq_chip,
             &mut self.vm_state.0.lock().unwrap(),
@@ -551,7 +669,12 @@ impl DeviceInterface for StdMachine {
     }
 
     fn query_hotpluggable_cpus(&self) -> Response {
-        Response::create_empty_response()
+        let hotplug_vec: Vec<serde_json::Value> = self
+            .get_hotpluggable_cpus()
+            .iter()
+            .map(|cpu| serde_json::to_value(cpu).unwrap())
+            .collect();
+        Response::create_response(hotplug_vec.into(), None)
     }
 
     fn balloon(&self, value: u64) -> Response {
@@ -579,44 +702,103 @@ impl DeviceInterface for StdMachine {
         )
     }
 
-    fn device_add(
-        &self,
-        _id: String,
-        _driver: String,
-        _addr: Option<String>,
-        _lun: Option<usize>,
-    ) -> Response {
+    fn query_balloon_stats(&self) -> Response {
+        if let Some(stats) = qmp_query_balloon_stats() {
+            return Response::create_response(serde_json::to_value(&stats).unwrap(), None);
+        }
         Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("device_add not supported yet".to_string()),
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
             None,
         )
     }
 
-    fn device_del(&self, _device_id: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("device_del not supported yet".to_string()),
-            None,
-        )
+    fn virtio_mem_resize(&self, id: String, requested_size: u64) -> Response {
+        match qmp_virtio_mem_resize(&id, requested_size) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn query_virtio_mem(&self) -> Response {
+        let infos = qmp_query_virtio_mem();
+        Response::create_response(serde_json::to_value(&infos).unwrap(), None)
+    }
+
+    fn device_add(&self, args: Box<qmp_schema::device_add>) -> Response {
+        let ret = if args.driver == CPU_TYPE {
+            self.hotplug_cpu(&args)
+        } else {
+            self.hotplug_pci_device(&args)
+        };
+        match ret {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                error!("Failed to add device: id {}, type {}", args.id, args.driver);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn device_del(&self, device_id: String) -> Response {
+        let ret = match self.find_cpu_by_id(&device_id) {
+            Some(cpu_index) => self.request_cpu_unplug(cpu_index),
+            None => self.hot_unplug_pci_device(&device_id),
+        };
+        match ret {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to delete device: {}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
     fn blockdev_add(
         &self,
-        _node_name: String,
-        _file: qmp_schema::FileOptions,
-        _cache: Option<qmp_schema::CacheOptions>,
-        _read_only: Option<bool>,
+        node_name: String,
+        file: qmp_schema::FileOptions,
+        cache: Option<qmp_schema::CacheOptions>,
+        read_only: Option<bool>,
+        driver: Option<String>,
     ) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("blockdev_add not supported yet".to_string()),
-            None,
-        )
+        match self.add_hotplug_drive(&node_name, &file, cache, read_only, driver) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
-    fn netdev_add(&self, _id: String, _if_name: Option<String>, _fds: Option<String>) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("netdev_add not supported yet".to_string()),
-            None,
-        )
+    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> Response {
+        match self.add_hotplug_netdev(&id, if_name, fds) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
@@ -629,15 +811,44 @@ impl DeviceInterface for StdMachine {
             Response::create_error_response(err_resp, None)
         }
     }
+
+    fn get_guest_agent(&self) -> Option<GuestAgent> {
+        self.guest_agent.clone()
+    }
 }
 
 impl MigrateInterface for StdMachine {
-    fn migrate(&self, uri: String) -> Response {
+    fn migrate(
+        &self,
+        uri: String,
+        incremental: Option<bool>,
+        dirty_log: Option<bool>,
+        compress: Option<String>,
+    ) -> Response {
         use util::unix::{parse_uri, UnixPath};
 
+        let compression = match compress.as_ref().map(|c| c.parse::<Compression>()) {
+            Some(Ok(compression)) => Some(compression),
+            Some(Err(())) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!(
+                        "Invalid compression algorithm: {}",
+                        compress.unwrap()
+                    )),
+                    None,
+                );
+            }
+            None => None,
+        };
+
         match parse_uri(&uri) {
             Ok((UnixPath::File, path)) => {
-                if let Err(e) = MigrationManager::save_snapshot(&path) {
+                if let Err(e) = MigrationManager::save_snapshot(
+                    &path,
+                    incremental.unwrap_or(false),
+                    dirty_log.unwrap_or(false),
+                    compression,
+                ) {
                     error!(
                         "Failed to migrate to path \'{:?}\': {}",
                         path,
@@ -651,6 +862,34 @@ impl MigrateInterface for StdMachine {
                     );
                 }
             }
+            Ok(_)
+                if incremental.unwrap_or(false)
+                    || dirty_log.unwrap_or(false)
+                    || compression.is_some() =>
+            {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(
+                        "Options incremental, dirty-log and compress are only for snapshot"
+                            .to_string(),
+                    ),
+                    None,
+                );
+            }
+            Ok((path_type, addr)) if path_type == UnixPath::Tcp || path_type == UnixPath::Unix => {
+                if let Err(e) = <Self as MachineOps>::start_live_migration(
+                    path_type,
+                    &addr,
+                    self.online_cpus(),
+                    self.irq_chip.clone(),
+                    self.vm_state.clone(),
+                ) {
+                    error!("Failed to migrate to \'{}\': {}", addr, e.display_chain());
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            }
             _ => {
                 return Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
@@ -663,10 +902,31 @@ impl MigrateInterface for StdMachine {
     }
 
     fn query_migrate(&self) -> Response {
-        let status_str = MigrationManager::migration_get_status().to_string();
-        let migration_info = qmp_schema::MigrationInfo {
-            status: Some(status_str),
+        let status = MigrationManager::migration_get_status();
+        let mut migration_info = qmp_schema::MigrationInfo {
+            status: Some(status.to_string()),
+            ..Default::default()
         };
+        if let Some(stats) = MigrationManager::migration_get_stats() {
+            migration_info.total_time = Some(stats.total_time);
+            if status == MigrationStatus::Completed {
+                migration_info.downtime = Some(stats.downtime);
+            }
+            migration_info.ram = Some(qmp_schema::MigrationStats {
+                transferred: stats.transferred_ram,
+                remaining: stats.remaining_ram,
+                total: stats.total_ram,
+                dirty_sync_count: stats.dirty_sync_count,
+            });
+        }
+        if let Some(stats) = MigrationManager::lazy_restore_stats() {
+            migration_info.lazy_restore = Some(qmp_schema::LazyRestoreStats {
+                total_pages: stats.total_pages(),
+                missing_pages: stats.missing_pages(),
+                prefetched_pages: stats.prefetched_pages(),
+                remaining_pages: stats.remaining_pages(),
+            });
+        }
 
         Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
     }
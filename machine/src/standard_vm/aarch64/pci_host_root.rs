@@ -12,6 +12,7 @@
 
 use std::sync::{Arc, Mutex, Weak};
 
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use pci::{
     config::{
         PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, PCI_CONFIG_SPACE_SIZE, PCI_VENDOR_ID_REDHAT,
@@ -20,9 +21,18 @@ use pci::{
     errors::Result as PciResult,
     le_write_u16, PciBus, PciDevOps,
 };
+use util::byte_code::ByteCode;
 
 const DEVICE_ID_PCIE_HOST: u16 = 0x0008;
 
+/// Device state of PciHost root.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct PciHostRootState {
+    config_space: [u8; 256],
+}
+
 /// PciHost root (Device 0:Function 0).
 pub struct PciHostRoot {
     /// Pci config space.
@@ -71,11 +81,16 @@ impl PciDevOps for PciHostRoot {
         le_write_u16(&mut self.config.config, REVISION_ID as usize, 0)?;
 
         let parent_bus = self.parent_bus.upgrade().unwrap();
+        let pcihost_root = Arc::new(Mutex::new(self));
         parent_bus
             .lock()
             .unwrap()
             .devices
-            .insert(0, Arc::new(Mutex::new(self)));
+            .insert(0, pcihost_root.clone());
+        MigrationManager::register_device_instance_mutex(
+            PciHostRootState::descriptor(),
+            pcihost_root,
+        );
         Ok(())
     }
 
@@ -121,3 +136,32 @@ impl PciDevOps for PciHostRoot {
         "PCI Host Root".to_string()
     }
 }
+
+impl StateTransfer for PciHostRoot {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let mut state = PciHostRootState::default();
+        state.config_space.copy_from_slice(&self.config.config);
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let root_state = PciHostRootState::from_bytes(state).ok_or(
+            migration::errors::ErrorKind::FromBytesError("PCI_HOST_ROOT"),
+        )?;
+        self.config.config = root_state.config_space.to_vec();
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&PciHostRootState::descriptor().name)
+        {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for PciHostRoot {}
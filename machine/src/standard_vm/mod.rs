@@ -46,7 +46,11 @@ pub mod errors {
     }
 }
 
-use std::sync::{Arc, Mutex};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+#[cfg(target_arch = "x86_64")]
+use std::thread;
 use std::{fs::File, mem::size_of};
 
 #[cfg(target_arch = "x86_64")]
@@ -55,15 +59,38 @@ use acpi::{
     AcpiRsdp, AcpiTable, AmlBuilder, TableLoader, ACPI_RSDP_FILE, ACPI_TABLE_FILE,
     ACPI_TABLE_LOADER_FILE, TABLE_CHECKSUM_OFFSET,
 };
+use address_space::AddressSpace;
+use cpu::{CPUInterface, CpuTopology, CPU};
 use devices::legacy::FwCfgOps;
 use errors::{Result, ResultExt};
+use machine_manager::config::{get_pci_bdf, parse_blk, parse_net, parse_vfio, VmConfig};
+use machine_manager::machine::{KvmVmState, BLOCK_DEVICES};
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use migration::MigrationManager;
+use pci::hotplug::{handle_unplug_request, UnplugCallback};
+use pci::{PciBus, PciDevOps};
 use util::byte_code::ByteCode;
+use vfio::vfio_pci::create_vfio_container;
+use vfio::{VfioContainer, VfioPciDevice};
+use virtio::{
+    Block, BlockState, VhostKern, VhostUser, VirtioDevice, VirtioNetState, VirtioPciDevice,
+};
 
 #[cfg(target_arch = "aarch64")]
 use aarch64::{LayoutEntryType, MEM_LAYOUT};
 #[cfg(target_arch = "x86_64")]
 use x86_64::{LayoutEntryType, MEM_LAYOUT};
 
+/// Driver name of the hotpluggable `vCPU` used in `device_add`.
+#[cfg(target_arch = "x86_64")]
+const CPU_TYPE: &str = "host-x86-cpu";
+#[cfg(target_arch = "aarch64")]
+const CPU_TYPE: &str = "host-aarch64-cpu";
+
+/// The state of hotplugged `vCPU`s, such as online mask and pending ACPI
+/// events, is not saved in snapshot.
+const CPU_HOTPLUG_MIGRATION_BLOCKER: &str = "Hotplugged vcpu state is not saved";
+
 fn open_pflash_file(file_name: &str, unit: usize) -> Result<File> {
     let fd = if unit == 0 {
         std::fs::OpenOptions::new().read(true).open(file_name)?
@@ -133,6 +160,446 @@ trait StdMachineOps: AcpiBuilder {
     fn add_fwcfg_device(&mut self) -> Result<Arc<Mutex<dyn FwCfgOps>>> {
         bail!("Not implemented");
     }
+
+    /// Get the VM configuration, whose drives and netdevs are consumed by hotplugged devices.
+    fn get_vm_config(&self) -> &Mutex<VmConfig>;
+
+    /// Get the root bus of the PCIe host bridge.
+    fn get_pci_root_bus(&self) -> Arc<Mutex<PciBus>>;
+
+    /// Get the guest memory address space.
+    fn get_guest_memory(&self) -> &Arc<AddressSpace>;
+
+    /// Get the `vCPU` topology.
+    fn get_cpu_topo(&self) -> &CpuTopology;
+
+    /// Get all the `vCPU`s indexed by `vCPU` id, including the ones not plugged yet.
+    fn get_cpus(&self) -> &[Arc<CPU>];
+
+    /// Get the device ids of the hotplugged `vCPU`s, indexed by `vCPU` id.
+    fn get_cpu_ids(&self) -> &Arc<Mutex<Vec<Option<String>>>>;
+
+    /// Get the VFIO container shared by all the VFIO devices.
+    fn get_vfio_container(&self) -> Result<Arc<VfioContainer>>;
+
+    /// Get the VM running state.
+    fn get_vm_state(&self) -> &Arc<(Mutex<KvmVmState>, Condvar)>;
+
+    /// Notify the guest that the `vCPU` is plugged.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_index` - Id of the plugged `vCPU`.
+    fn notify_cpu_plug(&self, cpu_index: u8) -> Result<()>;
+
+    /// Request the guest to release the `vCPU`, which is removed by `eject_cpu`
+    /// after the guest ejects it.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_index` - Id of the `vCPU` to be removed.
+    fn request_cpu_unplug(&self, cpu_index: u8) -> Result<()>;
+
+    /// Get the `vCPU`s which are online now.
+    fn online_cpus(&self) -> Vec<Arc<CPU>> {
+        let cpu_topo = self.get_cpu_topo();
+        self.get_cpus()
+            .iter()
+            .filter(|cpu| cpu_topo.get_mask(cpu.id() as usize) == 1)
+            .cloned()
+            .collect()
+    }
+
+    /// Find the hotplugged `vCPU` by its device id, and return the `vCPU` id.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device id of the `vCPU`.
+    fn find_cpu_by_id(&self, id: &str) -> Option<u8> {
+        self.get_cpu_ids()
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|cpu_id| cpu_id.as_deref() == Some(id))
+            .map(|index| index as u8)
+    }
+
+    /// Hotplug a `vCPU` to the slot specified by `socket-id`, `core-id` and `thread-id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Arguments of QMP command `device_add`.
+    fn hotplug_cpu(&self, args: &qmp_schema::device_add) -> Result<()> {
+        let cpu_topo = self.get_cpu_topo();
+        let socket_id = match args.socket_id {
+            Some(socket_id) => socket_id,
+            None => bail!("Argument socket-id is required for {}", CPU_TYPE),
+        };
+        let core_id = args.core_id.unwrap_or(0);
+        let thread_id = args.thread_id.unwrap_or(0);
+        if socket_id >= cpu_topo.sockets
+            || core_id >= cpu_topo.cores
+            || thread_id >= cpu_topo.threads
+        {
+            bail!(
+                "Invalid cpu slot: socket-id {}, core-id {}, thread-id {}",
+                socket_id,
+                core_id,
+                thread_id
+            );
+        }
+        let cpu_index = (socket_id as usize * cpu_topo.cores as usize + core_id as usize)
+            * cpu_topo.threads as usize
+            + thread_id as usize;
+        if cpu_index >= cpu_topo.max_cpus as usize {
+            bail!(
+                "Cpu index {} exceeds maxcpus {}",
+                cpu_index,
+                cpu_topo.max_cpus
+            );
+        }
+        if cpu_topo.get_mask(cpu_index) == 1 {
+            bail!("Cpu {} is already plugged", cpu_index);
+        }
+        if self.find_cpu_by_id(&args.id).is_some() {
+            bail!("Device id {} already exists", args.id);
+        }
+
+        let paused = match *self.get_vm_state().0.lock().unwrap() {
+            KvmVmState::Running => false,
+            KvmVmState::Paused => true,
+            state => bail!("Cpu hotplug is not allowed in {:?} state", state),
+        };
+        let cpu = self.get_cpus()[cpu_index].clone();
+        CPU::start(cpu, Arc::new(Barrier::new(1)), paused)
+            .chain_err(|| format!("Failed to start vcpu{}", cpu_index))?;
+        cpu_topo.set_mask(cpu_index, 1);
+        self.get_cpu_ids().lock().unwrap()[cpu_index] = Some(args.id.clone());
+        MigrationManager::register_blocker(&args.id, CPU_HOTPLUG_MIGRATION_BLOCKER, true);
+
+        self.notify_cpu_plug(cpu_index as u8)
+    }
+
+    /// Get the `vCPU` slots, the plugged ones have `qom-path`.
+    fn get_hotpluggable_cpus(&self) -> Vec<qmp_schema::HotpluggableCPU> {
+        let cpu_topo = self.get_cpu_topo();
+        let cpu_ids = self.get_cpu_ids().lock().unwrap();
+        (0..cpu_topo.max_cpus)
+            .map(|cpu_index| {
+                let (socketid, coreid, threadid) = cpu_topo.get_topo(cpu_index as usize);
+                let qom_path = if cpu_index < cpu_topo.nrcpus {
+                    Some(format!("/machine/unattached/device[{}]", cpu_index))
+                } else {
+                    cpu_ids[cpu_index as usize]
+                        .as_ref()
+                        .map(|id| format!("/machine/peripheral/{}", id))
+                };
+                qmp_schema::HotpluggableCPU {
+                    type_: CPU_TYPE.to_string(),
+                    vcpus_count: 1,
+                    props: qmp_schema::CpuInstanceProperties {
+                        node_id: None,
+                        socket_id: Some(socketid as isize),
+                        core_id: Some(coreid as isize),
+                        thread_id: Some(threadid as isize),
+                    },
+                    qom_path,
+                }
+            })
+            .collect()
+    }
+
+    /// Add a drive which can be used by hotplugged block devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_name` - Id of the drive.
+    /// * `file` - The backend file information.
+    /// * `cache` - If use direct io.
+    /// * `read_only` - If readonly.
+    /// * `driver` - Format of the image.
+    fn add_hotplug_drive(
+        &self,
+        node_name: &str,
+        file: &qmp_schema::FileOptions,
+        cache: Option<qmp_schema::CacheOptions>,
+        read_only: Option<bool>,
+        driver: Option<String>,
+    ) -> Result<()> {
+        let mut drive_args = format!("id={},file={}", node_name, file.filename);
+        if let Some(read_only) = read_only {
+            drive_args.push_str(&format!(",readonly={}", read_only));
+        }
+        if let Some(direct) = cache.and_then(|cache| cache.direct) {
+            drive_args.push_str(&format!(",direct={}", direct));
+        }
+        if let Some(format) = driver {
+            drive_args.push_str(&format!(",format={}", format));
+        }
+
+        self.get_vm_config()
+            .lock()
+            .unwrap()
+            .add_drive(&drive_args)
+            .chain_err(|| format!("Failed to add drive {}", node_name))?;
+        Ok(())
+    }
+
+    /// Add a tap netdev which can be used by hotplugged net devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the netdev.
+    /// * `if_name` - Name of the tap device on host.
+    /// * `fds` - Name of the tap fd received by `getfd`, or the fd number.
+    fn add_hotplug_netdev(
+        &self,
+        id: &str,
+        if_name: Option<String>,
+        fds: Option<String>,
+    ) -> Result<()> {
+        let mut netdev_args = format!("tap,id={}", id);
+        if let Some(fds) = fds {
+            let netdev_fd = fds.rsplit(':').next().unwrap_or_default();
+            let fd: RawFd = if let Some(fd) = QmpChannel::get_fd(netdev_fd) {
+                fd
+            } else if let Ok(fd) = netdev_fd.parse::<RawFd>() {
+                fd
+            } else {
+                bail!("Failed to convert {} to RawFd", netdev_fd);
+            };
+            netdev_args.push_str(&format!(",fd={}", fd));
+        } else if let Some(if_name) = if_name {
+            netdev_args.push_str(&format!(",ifname={}", if_name));
+        }
+
+        self.get_vm_config()
+            .lock()
+            .unwrap()
+            .add_netdev(&netdev_args)
+            .chain_err(|| format!("Failed to add netdev {}", id))?;
+        Ok(())
+    }
+
+    /// Hotplug a pci device to the pcie root port it specifies.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Arguments of QMP command `device_add`.
+    fn hotplug_pci_device(&self, args: &qmp_schema::device_add) -> Result<()> {
+        let root_bus = self.get_pci_root_bus();
+        if PciBus::find_attached_bus(&root_bus, &args.id).is_some() {
+            bail!("Device id {} already exists", args.id);
+        }
+
+        let mut cfg_args = format!("{},id={}", args.driver, args.id);
+        let options = [
+            ("bus", &args.bus),
+            ("addr", &args.addr),
+            ("drive", &args.drive),
+            ("netdev", &args.netdev),
+            ("mac", &args.mac),
+            ("host", &args.host),
+        ];
+        for (key, value) in options.iter() {
+            if let Some(value) = value {
+                cfg_args.push_str(&format!(",{}={}", key, value));
+            }
+        }
+
+        let bdf = get_pci_bdf(&cfg_args)?;
+        let pci_bus = match PciBus::find_bus_by_name(&root_bus, &bdf.bus) {
+            Some(bus) => bus,
+            None => bail!("Parent bus {} not found", bdf.bus),
+        };
+        if pci_bus.lock().unwrap().hotplug_controller.is_none() {
+            bail!("Bus {} does not support hotplug", bdf.bus);
+        }
+        let parent_bus = Arc::downgrade(&pci_bus);
+        let devfn = (bdf.addr.0 << 3) + bdf.addr.1;
+        let sys_mem = self.get_guest_memory().clone();
+
+        // Backends are only consumed when the device is plugged successfully.
+        let mut vm_config = self.get_vm_config().lock().unwrap();
+        let mut new_config = vm_config.clone();
+        match args.driver.as_str() {
+            "virtio-blk-pci" => {
+                let device_cfg = parse_blk(&mut new_config, &cfg_args)?;
+                let device = Arc::new(Mutex::new(Block::new(device_cfg)));
+                VirtioPciDevice::new(
+                    args.id.clone(),
+                    devfn,
+                    sys_mem,
+                    device.clone(),
+                    parent_bus,
+                    false,
+                )
+                .realize()
+                .chain_err(|| "Failed to hotplug virtio pci blk device")?;
+                BLOCK_DEVICES.lock().unwrap().push(device.clone());
+                MigrationManager::register_device_instance_mutex(BlockState::descriptor(), device);
+            }
+            "virtio-net-pci" => {
+                let device_cfg = parse_net(&mut new_config, &cfg_args)?;
+                let device: Arc<Mutex<dyn VirtioDevice>> =
+                    if device_cfg.vhost_type == Some(String::from("vhost-user")) {
+                        if !new_config.machine_config.mem_config.mem_share {
+                            bail!("Vhost-user net device requires mem-share=on for guest memory");
+                        }
+                        Arc::new(Mutex::new(VhostUser::Net::new(&device_cfg, &sys_mem)))
+                    } else if device_cfg.vhost_type.is_some() {
+                        Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &sys_mem)))
+                    } else {
+                        let device = Arc::new(Mutex::new(virtio::Net::new(device_cfg)));
+                        MigrationManager::register_device_instance_mutex(
+                            VirtioNetState::descriptor(),
+                            device.clone(),
+                        );
+                        device
+                    };
+                VirtioPciDevice::new(args.id.clone(), devfn, sys_mem, device, parent_bus, false)
+                    .realize()
+                    .chain_err(|| "Failed to hotplug virtio pci net device")?;
+            }
+            "vfio-pci" => {
+                let device_cfg = parse_vfio(&new_config, &cfg_args)?;
+                let path = "/sys/bus/pci/devices/".to_string() + &device_cfg.host;
+                let container = self.get_vfio_container()?;
+                let vfio_pci_dev = VfioPciDevice::new(
+                    Path::new(&path),
+                    container,
+                    devfn,
+                    args.id.clone(),
+                    parent_bus,
+                    false,
+                )
+                .chain_err(|| "Failed to create vfio pci device")?;
+                VfioPciDevice::realize(vfio_pci_dev)
+                    .chain_err(|| "Failed to hotplug vfio pci device")?;
+            }
+            _ => bail!("Device driver {} does not support hotplug", args.driver),
+        }
+        *vm_config = new_config;
+        Ok(())
+    }
+
+    /// Request the guest to eject the pci device, the device is removed and `DEVICE_DELETED`
+    /// event is sent after the guest powers off the slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the device.
+    fn hot_unplug_pci_device(&self, id: &str) -> Result<()> {
+        let root_bus = self.get_pci_root_bus();
+        let (bus, dev) = match PciBus::find_attached_bus(&root_bus, id) {
+            Some(attached) => attached,
+            None => bail!("Device {} not found", id),
+        };
+        // The block device is no longer reported to QMP once it's removed.
+        let block = BLOCK_DEVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|blk| {
+                blk.lock()
+                    .unwrap()
+                    .query_block_info()
+                    .map_or(false, |info| info.device == id)
+            })
+            .cloned();
+        let on_removed: UnplugCallback = Box::new(move || {
+            if let Some(block) = block {
+                let blk_ptr = Arc::as_ptr(&block) as *const u8;
+                BLOCK_DEVICES
+                    .lock()
+                    .unwrap()
+                    .retain(|blk| Arc::as_ptr(blk) as *const u8 != blk_ptr);
+            }
+        });
+        handle_unplug_request(&bus, &dev, on_removed)
+            .chain_err(|| format!("Failed to unplug device {}", id))?;
+        Ok(())
+    }
+}
+
+/// Get the VFIO container shared by all the VFIO devices, it's created when
+/// the first VFIO device is added.
+///
+/// # Arguments
+///
+/// * `container` - The VFIO container of VM.
+/// * `sys_mem` - The guest memory address space.
+fn get_or_create_vfio_container(
+    container: &Mutex<Option<Arc<VfioContainer>>>,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<Arc<VfioContainer>> {
+    let mut locked_container = container.lock().unwrap();
+    if locked_container.is_none() {
+        *locked_container = Some(
+            create_vfio_container(sys_mem.clone())
+                .chain_err(|| "Failed to create vfio container")?,
+        );
+    }
+    Ok(locked_container.as_ref().unwrap().clone())
+}
+
+/// Stop the hot-unplugged `vCPU`, the slot is released and `DEVICE_DELETED`
+/// event is sent after the `vCPU` thread exits.
+///
+/// # Arguments
+///
+/// * `cpu` - The `vCPU` to be removed.
+/// * `cpu_topo` - `vCPU` topology whose online mask is updated.
+/// * `cpu_ids` - Device ids of the hotplugged `vCPU`s.
+#[cfg(target_arch = "x86_64")]
+fn eject_cpu(cpu: &Arc<CPU>, cpu_topo: &CpuTopology, cpu_ids: &Arc<Mutex<Vec<Option<String>>>>) {
+    let cpu_index = cpu.id() as usize;
+    if let Err(e) = cpu.destroy() {
+        // The vcpu thread exits by itself once it leaves kvm, the slot is kept
+        // busy until then so that it can't be plugged again.
+        warn!("Failed to stop vcpu{} in time: {}", cpu_index, e);
+        let cpu = cpu.clone();
+        let cpu_topo = cpu_topo.clone();
+        let cpu_ids = cpu_ids.clone();
+        let waiter = thread::Builder::new()
+            .name(format!("CPU {} eject", cpu_index))
+            .spawn(move || {
+                cpu.join();
+                release_cpu_slot(cpu_index, &cpu_topo, &cpu_ids);
+            });
+        if let Err(e) = waiter {
+            error!("Failed to wait for vcpu{} to exit: {}", cpu_index, e);
+        }
+        return;
+    }
+    release_cpu_slot(cpu_index, cpu_topo, cpu_ids);
+}
+
+/// Release the slot of the removed `vCPU` and send `DEVICE_DELETED` event.
+///
+/// # Arguments
+///
+/// * `cpu_index` - Id of the removed `vCPU`.
+/// * `cpu_topo` - `vCPU` topology whose online mask is updated.
+/// * `cpu_ids` - Device ids of the hotplugged `vCPU`s.
+#[cfg(target_arch = "x86_64")]
+fn release_cpu_slot(
+    cpu_index: usize,
+    cpu_topo: &CpuTopology,
+    cpu_ids: &Mutex<Vec<Option<String>>>,
+) {
+    cpu_topo.set_mask(cpu_index, 0);
+
+    if let Some(id) = cpu_ids.lock().unwrap()[cpu_index].take() {
+        MigrationManager::unregister_blocker(&id);
+        if QmpChannel::is_connected() {
+            let device_deleted = qmp_schema::DeviceDeleted {
+                device: Some(id.clone()),
+                path: format!("/machine/peripheral/{}", id),
+            };
+            event!(DeviceDeleted; device_deleted);
+        }
+    }
 }
 
 /// Trait that helps to build ACPI tables.
@@ -0,0 +1,371 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use acpi::{
+    AcpiLocalApic, AmlAcquire, AmlAddressSpaceType, AmlArg, AmlBuffer, AmlBuilder,
+    AmlCallWithArgs1, AmlCallWithArgs2, AmlDevice, AmlEisaId, AmlElse, AmlEqual, AmlField,
+    AmlFieldAccessType, AmlFieldLockRule, AmlFieldUnit, AmlFieldUpdateRule, AmlIf, AmlIncrement,
+    AmlInteger, AmlLLess, AmlLocal, AmlMethod, AmlMutex, AmlName, AmlNameDecl, AmlNotify, AmlOne,
+    AmlOpRegion, AmlRelease, AmlReturn, AmlScopeBuilder, AmlStore, AmlString, AmlWhile, AmlZero,
+};
+use address_space::GuestAddress;
+use cpu::{CpuTopology, CPU};
+use sysbus::{SysBus, SysBusDevOps, SysRes};
+
+use super::ged::{Ged, GED_EVENT_CPU_HOTPLUG};
+use crate::standard_vm::eject_cpu;
+use crate::standard_vm::errors::{Result, ResultExt};
+
+/// Size of the cpu hotplug register block.
+pub const CPU_CONTROLLER_REGION_SIZE: u64 = 0x8;
+
+/// Offset of the register which selects the `vCPU` to operate on.
+const CPU_SELECTOR_OFFSET: u64 = 0x0;
+/// Offset of the status (read) and command (write) register of the selected `vCPU`.
+const CPU_STATUS_OFFSET: u64 = 0x4;
+
+/// The selected `vCPU` is enabled.
+const CPU_ENABLED: u8 = 1 << 0;
+/// The selected `vCPU` has a pending insert event. Writing it clears the event.
+const CPU_INSERTING: u8 = 1 << 1;
+/// The selected `vCPU` has a pending remove event. Writing it clears the event.
+const CPU_REMOVING: u8 = 1 << 2;
+/// Writing it ejects the selected `vCPU`.
+const CPU_EJECT: u8 = 1 << 3;
+
+/// Value of `_STA` for an enabled processor: present, enabled, shown in UI and functioning.
+const CPU_STA_ENABLED: u64 = 0xF;
+
+/// ACPI CPU hotplug controller. The guest scans the pending insert/remove events through
+/// the register block when the GED interrupt is raised, and ejects the `vCPU` by `_EJ0`.
+pub struct CpuController {
+    /// All the `vCPU`s indexed by `vCPU` id.
+    cpus: Vec<Arc<CPU>>,
+    /// `vCPU` topology which holds the online mask.
+    cpu_topo: CpuTopology,
+    /// Device ids of the hotplugged `vCPU`s.
+    cpu_ids: Arc<Mutex<Vec<Option<String>>>>,
+    /// Id of the selected `vCPU`.
+    selector: u32,
+    /// Pending insert events.
+    inserting: Vec<bool>,
+    /// Pending remove events.
+    removing: Vec<bool>,
+    /// GED which signals the events to the guest.
+    ged: Arc<Mutex<Ged>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl CpuController {
+    pub fn new(
+        cpus: Vec<Arc<CPU>>,
+        cpu_topo: CpuTopology,
+        cpu_ids: Arc<Mutex<Vec<Option<String>>>>,
+        ged: Arc<Mutex<Ged>>,
+    ) -> Self {
+        let max_cpus = cpu_topo.max_cpus as usize;
+        CpuController {
+            cpus,
+            cpu_topo,
+            cpu_ids,
+            selector: 0,
+            inserting: vec![false; max_cpus],
+            removing: vec![false; max_cpus],
+            ged,
+            res: SysRes::default(),
+        }
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .chain_err(|| "Failed to set system resource for cpu controller")?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, region_base, region_size)
+            .chain_err(|| "Failed to attach cpu controller to system bus")?;
+        Ok(dev)
+    }
+
+    /// Send the insert event of the plugged `vCPU` to the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_index` - Id of the plugged `vCPU`.
+    pub fn plug(&mut self, cpu_index: u8) -> Result<()> {
+        self.inserting[cpu_index as usize] = true;
+        self.removing[cpu_index as usize] = false;
+        self.ged.lock().unwrap().inject_event(GED_EVENT_CPU_HOTPLUG)
+    }
+
+    /// Send the remove event of the `vCPU` to the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_index` - Id of the `vCPU` to be removed.
+    pub fn unplug_request(&mut self, cpu_index: u8) -> Result<()> {
+        self.removing[cpu_index as usize] = true;
+        self.ged.lock().unwrap().inject_event(GED_EVENT_CPU_HOTPLUG)
+    }
+
+    fn selected_cpu(&self) -> Option<usize> {
+        let cpu_index = self.selector as usize;
+        if cpu_index < self.cpus.len() {
+            Some(cpu_index)
+        } else {
+            None
+        }
+    }
+
+    fn read_status(&self) -> u8 {
+        let cpu_index = match self.selected_cpu() {
+            Some(index) => index,
+            None => return 0,
+        };
+
+        let mut status = 0;
+        if self.cpu_topo.get_mask(cpu_index) == 1 {
+            status |= CPU_ENABLED;
+        }
+        if self.inserting[cpu_index] {
+            status |= CPU_INSERTING;
+        }
+        if self.removing[cpu_index] {
+            status |= CPU_REMOVING;
+        }
+        status
+    }
+
+    fn write_command(&mut self, command: u8) {
+        let cpu_index = match self.selected_cpu() {
+            Some(index) => index,
+            None => return,
+        };
+
+        if command & CPU_INSERTING != 0 {
+            self.inserting[cpu_index] = false;
+        }
+        if command & CPU_REMOVING != 0 {
+            self.removing[cpu_index] = false;
+        }
+        if command & CPU_EJECT != 0 {
+            // Only the hotplugged vcpus can be ejected.
+            if cpu_index < self.cpu_topo.nrcpus as usize || self.cpu_topo.get_mask(cpu_index) == 0 {
+                warn!(
+                    "Guest tries to eject vcpu{} which is not removable",
+                    cpu_index
+                );
+                return;
+            }
+            self.removing[cpu_index] = false;
+            eject_cpu(&self.cpus[cpu_index], &self.cpu_topo, &self.cpu_ids);
+        }
+    }
+
+    /// Build the `_MAT` buffer of the `vCPU`, which is a MADT Local APIC structure.
+    fn lapic_buffer(cpu_index: u8, enabled: bool) -> AmlBuffer {
+        let lapic = AcpiLocalApic {
+            type_id: 0,
+            length: size_of::<AcpiLocalApic>() as u8,
+            processor_uid: cpu_index,
+            apic_id: cpu_index,
+            flags: if enabled { 1 } else { 0 },
+        };
+        AmlBuffer(lapic.aml_bytes())
+    }
+
+    /// Build the processor device of the `vCPU`.
+    fn cpu_device(&self, cpu_index: u8) -> AmlDevice {
+        let mut dev = AmlDevice::new(format!("C{:03}", cpu_index).as_str());
+        dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0007".to_string())));
+        dev.append_child(AmlNameDecl::new("_UID", AmlInteger(u64::from(cpu_index))));
+
+        let mut sta = AmlMethod::new("_STA", 0, false);
+        sta.append_child(AmlReturn::with_value(AmlCallWithArgs1::new(
+            "CSTA",
+            AmlInteger(u64::from(cpu_index)),
+        )));
+        dev.append_child(sta);
+
+        let mut mat = AmlMethod::new("_MAT", 0, false);
+        let mut if_scope = AmlIf::new(AmlEqual::new(
+            AmlCallWithArgs1::new("CSTA", AmlInteger(u64::from(cpu_index))),
+            AmlInteger(CPU_STA_ENABLED),
+        ));
+        if_scope.append_child(AmlReturn::with_value(Self::lapic_buffer(cpu_index, true)));
+        mat.append_child(if_scope);
+        mat.append_child(AmlReturn::with_value(Self::lapic_buffer(cpu_index, false)));
+        dev.append_child(mat);
+
+        if cpu_index >= self.cpu_topo.nrcpus {
+            let mut ej0 = AmlMethod::new("_EJ0", 1, false);
+            ej0.append_child(AmlCallWithArgs1::new(
+                "CEJ0",
+                AmlInteger(u64::from(cpu_index)),
+            ));
+            dev.append_child(ej0);
+        }
+        dev
+    }
+}
+
+impl SysBusDevOps for CpuController {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        match (offset, data.len()) {
+            (CPU_SELECTOR_OFFSET, 4) => data.copy_from_slice(&self.selector.to_le_bytes()),
+            (CPU_STATUS_OFFSET, 1) => data[0] = self.read_status(),
+            _ => {
+                error!(
+                    "Invalid cpu controller read: offset 0x{:x}, data length {}",
+                    offset,
+                    data.len()
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+        match (offset, data.len()) {
+            (CPU_SELECTOR_OFFSET, 4) => {
+                let mut bytes = [0_u8; 4];
+                bytes.copy_from_slice(data);
+                self.selector = u32::from_le_bytes(bytes);
+            }
+            (CPU_STATUS_OFFSET, 1) => self.write_command(data[0]),
+            _ => {
+                error!(
+                    "Invalid cpu controller write: offset 0x{:x}, data length {}",
+                    offset,
+                    data.len()
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+}
+
+impl AmlBuilder for CpuController {
+    fn aml_bytes(&self) -> Vec<u8> {
+        let mut cpus_dev = AmlDevice::new("CPUS");
+        cpus_dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0010".to_string())));
+        cpus_dev.append_child(AmlNameDecl::new("_CID", AmlEisaId::new("PNP0A05")));
+        cpus_dev.append_child(AmlMutex::new("CPLK", 0));
+
+        cpus_dev.append_child(AmlOpRegion::new(
+            "PRST",
+            AmlAddressSpaceType::SystemMemory,
+            self.res.region_base,
+            self.res.region_size,
+        ));
+        let mut field = AmlField::new(
+            "PRST",
+            AmlFieldAccessType::DWord,
+            AmlFieldLockRule::NoLock,
+            AmlFieldUpdateRule::Preserve,
+        );
+        field.append_child(AmlFieldUnit::new(Some("CSEL"), 32));
+        cpus_dev.append_child(field);
+        // Write the command bits as zeros, so that clearing one event won't touch the others.
+        let mut field = AmlField::new(
+            "PRST",
+            AmlFieldAccessType::Byte,
+            AmlFieldLockRule::NoLock,
+            AmlFieldUpdateRule::WriteAsZeros,
+        );
+        field.append_child(AmlFieldUnit::new(None, 32));
+        field.append_child(AmlFieldUnit::new(Some("CPEN"), 1));
+        field.append_child(AmlFieldUnit::new(Some("CINS"), 1));
+        field.append_child(AmlFieldUnit::new(Some("CRMV"), 1));
+        field.append_child(AmlFieldUnit::new(Some("CEJF"), 1));
+        cpus_dev.append_child(field);
+
+        // Method (CSTA, 1, Serialized): return the _STA value of the vcpu Arg0.
+        let mut csta = AmlMethod::new("CSTA", 1, true);
+        csta.append_child(AmlAcquire::new(AmlName("CPLK".to_string()), 0xFFFF));
+        csta.append_child(AmlStore::new(AmlArg(0), AmlName("CSEL".to_string())));
+        csta.append_child(AmlStore::new(AmlZero, AmlLocal(0)));
+        let mut if_scope = AmlIf::new(AmlEqual::new(AmlName("CPEN".to_string()), AmlOne));
+        if_scope.append_child(AmlStore::new(AmlInteger(CPU_STA_ENABLED), AmlLocal(0)));
+        csta.append_child(if_scope);
+        csta.append_child(AmlRelease::new(AmlName("CPLK".to_string())));
+        csta.append_child(AmlReturn::with_value(AmlLocal(0)));
+        cpus_dev.append_child(csta);
+
+        // Method (CEJ0, 1, Serialized): eject the vcpu Arg0.
+        let mut cej0 = AmlMethod::new("CEJ0", 1, true);
+        cej0.append_child(AmlAcquire::new(AmlName("CPLK".to_string()), 0xFFFF));
+        cej0.append_child(AmlStore::new(AmlArg(0), AmlName("CSEL".to_string())));
+        cej0.append_child(AmlStore::new(AmlOne, AmlName("CEJF".to_string())));
+        cej0.append_child(AmlRelease::new(AmlName("CPLK".to_string())));
+        cpus_dev.append_child(cej0);
+
+        // Method (CNOT, 2): notify the vcpu Arg0 with event Arg1.
+        let mut cnot = AmlMethod::new("CNOT", 2, false);
+        for cpu_index in self.cpu_topo.nrcpus..self.cpu_topo.max_cpus {
+            let mut if_scope =
+                AmlIf::new(AmlEqual::new(AmlArg(0), AmlInteger(u64::from(cpu_index))));
+            if_scope.append_child(AmlNotify::new(
+                AmlName(format!("C{:03}", cpu_index)),
+                AmlArg(1),
+            ));
+            cnot.append_child(if_scope);
+        }
+        cpus_dev.append_child(cnot);
+
+        // Method (CSCN, 0, Serialized): scan the pending events of all vcpus,
+        // device check (1) for inserting and eject request (3) for removing.
+        let mut cscn = AmlMethod::new("CSCN", 0, true);
+        cscn.append_child(AmlAcquire::new(AmlName("CPLK".to_string()), 0xFFFF));
+        cscn.append_child(AmlStore::new(AmlZero, AmlLocal(0)));
+        let mut while_scope = AmlWhile::new(AmlLLess::new(
+            AmlLocal(0),
+            AmlInteger(u64::from(self.cpu_topo.max_cpus)),
+        ));
+        while_scope.append_child(AmlStore::new(AmlLocal(0), AmlName("CSEL".to_string())));
+        let mut if_ins = AmlIf::new(AmlEqual::new(AmlName("CINS".to_string()), AmlOne));
+        if_ins.append_child(AmlCallWithArgs2::new("CNOT", AmlLocal(0), AmlInteger(1)));
+        if_ins.append_child(AmlStore::new(AmlOne, AmlName("CINS".to_string())));
+        while_scope.append_child(if_ins);
+        let mut else_scope = AmlElse::new();
+        let mut if_rmv = AmlIf::new(AmlEqual::new(AmlName("CRMV".to_string()), AmlOne));
+        if_rmv.append_child(AmlCallWithArgs2::new("CNOT", AmlLocal(0), AmlInteger(3)));
+        if_rmv.append_child(AmlStore::new(AmlOne, AmlName("CRMV".to_string())));
+        else_scope.append_child(if_rmv);
+        while_scope.append_child(else_scope);
+        while_scope.append_child(AmlIncrement::new(AmlLocal(0)));
+        cscn.append_child(while_scope);
+        cscn.append_child(AmlRelease::new(AmlName("CPLK".to_string())));
+        cpus_dev.append_child(cscn);
+
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            cpus_dev.append_child(self.cpu_device(cpu_index));
+        }
+
+        cpus_dev.aml_bytes()
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use acpi::{
+    AmlActiveLevel, AmlAddressSpaceType, AmlAnd, AmlBuilder, AmlDevice, AmlEdgeLevel, AmlEqual,
+    AmlExtendedInterrupt, AmlField, AmlFieldAccessType, AmlFieldLockRule, AmlFieldUnit,
+    AmlFieldUpdateRule, AmlIf, AmlIntShare, AmlInteger, AmlLocal, AmlMethod, AmlName, AmlNameDecl,
+    AmlOpRegion, AmlResTemplate, AmlResourceUsage, AmlScopeBuilder, AmlStore, AmlString,
+};
+use address_space::GuestAddress;
+use sysbus::{SysBus, SysBusDevOps, SysRes};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::standard_vm::errors::{Result, ResultExt};
+
+/// Size of the event selector register block.
+pub const GED_REGION_SIZE: u64 = 0x4;
+/// Event selector bit of CPU hotplug.
+pub const GED_EVENT_CPU_HOTPLUG: u32 = 1 << 0;
+
+/// Generic Event Device (ACPI0013), which signals the hotplug events to the guest
+/// on hardware-reduced ACPI platforms.
+pub struct Ged {
+    /// Pending events, cleared when the guest reads the event selector.
+    events: u32,
+    /// Interrupt event file descriptor.
+    interrupt_evt: EventFd,
+    /// System resource.
+    res: SysRes,
+}
+
+impl Ged {
+    pub fn new() -> Result<Self> {
+        Ok(Ged {
+            events: 0,
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)
+                .chain_err(|| "Failed to create interrupt eventfd for GED")?,
+            res: SysRes::default(),
+        })
+    }
+
+    pub fn realize(
+        mut self,
+        sysbus: &mut SysBus,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .chain_err(|| "Failed to set system resource for GED")?;
+
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, region_base, region_size)
+            .chain_err(|| "Failed to attach GED to system bus")?;
+        Ok(dev)
+    }
+
+    /// Record the event and interrupt the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - Event selector bit, such as `GED_EVENT_CPU_HOTPLUG`.
+    pub fn inject_event(&mut self, event: u32) -> Result<()> {
+        self.events |= event;
+        self.interrupt_evt
+            .write(1)
+            .chain_err(|| "Failed to trigger GED interrupt")?;
+        Ok(())
+    }
+}
+
+impl SysBusDevOps for Ged {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        if offset != 0 || data.len() != 4 {
+            error!(
+                "Invalid GED read: offset 0x{:x}, data length {}",
+                offset,
+                data.len()
+            );
+            return false;
+        }
+        data.copy_from_slice(&self.events.to_le_bytes());
+        self.events = 0;
+        true
+    }
+
+    fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> bool {
+        true
+    }
+
+    fn interrupt_evt(&self) -> Option<&EventFd> {
+        Some(&self.interrupt_evt)
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+}
+
+impl AmlBuilder for Ged {
+    fn aml_bytes(&self) -> Vec<u8> {
+        let mut acpi_dev = AmlDevice::new("GED0");
+        acpi_dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0013".to_string())));
+        acpi_dev.append_child(AmlNameDecl::new("_UID", AmlInteger(0)));
+
+        let mut res = AmlResTemplate::new();
+        res.append_child(AmlExtendedInterrupt::new(
+            AmlResourceUsage::Consumer,
+            AmlEdgeLevel::Edge,
+            AmlActiveLevel::High,
+            AmlIntShare::Exclusive,
+            vec![self.res.irq as u32],
+        ));
+        acpi_dev.append_child(AmlNameDecl::new("_CRS", res));
+
+        acpi_dev.append_child(AmlOpRegion::new(
+            "EREG",
+            AmlAddressSpaceType::SystemMemory,
+            self.res.region_base,
+            self.res.region_size,
+        ));
+        let mut field = AmlField::new(
+            "EREG",
+            AmlFieldAccessType::DWord,
+            AmlFieldLockRule::NoLock,
+            AmlFieldUpdateRule::WriteAsZeros,
+        );
+        field.append_child(AmlFieldUnit::new(Some("ESEL"), 32));
+        acpi_dev.append_child(field);
+
+        // Method (_EVT, 1, Serialized) {
+        //     Local0 = ESEL
+        //     If ((Local0 & GED_EVENT_CPU_HOTPLUG) == GED_EVENT_CPU_HOTPLUG) {
+        //         \_SB.CPUS.CSCN()
+        //     }
+        // }
+        let mut method = AmlMethod::new("_EVT", 1, true);
+        method.append_child(AmlStore::new(AmlName("ESEL".to_string()), AmlLocal(0)));
+        method.append_child(AmlAnd::new(
+            AmlLocal(0),
+            AmlInteger(u64::from(GED_EVENT_CPU_HOTPLUG)),
+            AmlLocal(1),
+        ));
+        let mut if_scope = AmlIf::new(AmlEqual::new(
+            AmlLocal(1),
+            AmlInteger(u64::from(GED_EVENT_CPU_HOTPLUG)),
+        ));
+        if_scope.append_child(AmlName("\\_SB.CPUS.CSCN".to_string()));
+        method.append_child(if_scope);
+        acpi_dev.append_child(method);
+
+        acpi_dev.aml_bytes()
+    }
+}
@@ -15,6 +15,7 @@ use std::sync::{Arc, Mutex, Weak};
 use acpi::AcpiPMTimer;
 use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
 use error_chain::ChainedError;
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use pci::config::{
     PciConfig, DEVICE_ID, HEADER_TYPE, HEADER_TYPE_BRIDGE, HEADER_TYPE_MULTIFUNC,
     PCI_CONFIG_SPACE_SIZE, SUB_CLASS_CODE, VENDOR_ID,
@@ -32,6 +33,15 @@ const DEVICE_ID_INTEL_ICH9: u16 = 0x2918;
 const PM_BASE_OFFSET: u8 = 0x40;
 const PM_TIMER_OFFSET: u8 = 8;
 
+/// Device state of LPC bridge.
+#[allow(clippy::upper_case_acronyms)]
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct LPCBridgeState {
+    config_space: [u8; 256],
+}
+
 /// LPC bridge of ICH9 (IO controller hub 9), Device 1F : Function 0
 #[allow(clippy::upper_case_acronyms)]
 pub struct LPCBridge {
@@ -106,13 +116,15 @@ impl PciDevOps for LPCBridge {
         )?;
 
         let parent_bus = self.parent_bus.clone();
+        let lpc = Arc::new(Mutex::new(self));
         parent_bus
             .upgrade()
             .unwrap()
             .lock()
             .unwrap()
             .devices
-            .insert(0x1F << 3, Arc::new(Mutex::new(self)));
+            .insert(0x1F << 3, lpc.clone());
+        MigrationManager::register_device_instance_mutex(LPCBridgeState::descriptor(), lpc);
         Ok(())
     }
 
@@ -157,3 +169,44 @@ impl PciDevOps for LPCBridge {
         "ICH9 LPC bridge".to_string()
     }
 }
+
+impl StateTransfer for LPCBridge {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let mut state = LPCBridgeState::default();
+        state.config_space.copy_from_slice(&self.config.config);
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let lpc_state = LPCBridgeState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("LPC_BRIDGE"))?;
+        self.config.config = lpc_state.config_space.to_vec();
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&LPCBridgeState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for LPCBridge {
+    fn resume(&mut self) -> migration::errors::Result<()> {
+        let mut pm_base_addr = 0_u32;
+        self.config
+            .read(PM_BASE_OFFSET as usize, pm_base_addr.as_mut_bytes());
+        // PM timer is mapped only if PM base is set by guest.
+        if pm_base_addr != 0 {
+            if let Err(e) = self.update_pm_base() {
+                bail!("Failed to update PM base addr: {}", e.display_chain());
+            }
+        }
+
+        Ok(())
+    }
+}
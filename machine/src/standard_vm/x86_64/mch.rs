@@ -14,6 +14,7 @@ use std::sync::{Arc, Mutex, Weak};
 
 use address_space::{Region, RegionOps};
 use error_chain::ChainedError;
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use pci::{
     config::{
         PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, PCI_CONFIG_SPACE_SIZE, SUB_CLASS_CODE,
@@ -22,6 +23,7 @@ use pci::{
     errors::Result as PciResult,
     le_read_u64, le_write_u16, ranges_overlap, PciBus, PciDevOps,
 };
+use util::byte_code::ByteCode;
 
 use super::VENDOR_ID_INTEL;
 use crate::standard_vm::errors::Result;
@@ -38,6 +40,14 @@ const PCIEXBAR_LENGTH_64MB: u64 = 0x4;
 const PCIEXBAR_128MB_ADDR_MASK: u64 = 1 << 26;
 const PCIEXBAR_64MB_ADDR_MASK: u64 = 1 << 25;
 
+/// Device state of memory controller hub.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct MchState {
+    config_space: [u8; 256],
+}
+
 /// Memory controller hub (Device 0:Function 0)
 pub struct Mch {
     config: PciConfig,
@@ -97,7 +107,8 @@ impl Mch {
                 .lock()
                 .unwrap()
                 .mem_region
-                .add_subregion(region, base_addr)?;
+                .add_subregion(region.clone(), base_addr)?;
+            self.mmconfig_region = Some(region);
         }
         Ok(())
     }
@@ -129,13 +140,15 @@ impl PciDevOps for Mch {
         )?;
 
         let parent_bus = self.parent_bus.clone();
+        let mch = Arc::new(Mutex::new(self));
         parent_bus
             .upgrade()
             .unwrap()
             .lock()
             .unwrap()
             .devices
-            .insert(0, Arc::new(Mutex::new(self)));
+            .insert(0, mch.clone());
+        MigrationManager::register_device_instance_mutex(MchState::descriptor(), mch);
         Ok(())
     }
 
@@ -188,3 +201,43 @@ impl PciDevOps for Mch {
         "Memory Controller Hub".to_string()
     }
 }
+
+impl StateTransfer for Mch {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let mut state = MchState::default();
+        state.config_space.copy_from_slice(&self.config.config);
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let mch_state = MchState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("MCH"))?;
+        self.config.config = mch_state.config_space.to_vec();
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&MchState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for Mch {
+    fn resume(&mut self) -> migration::errors::Result<()> {
+        // The default ECAM region is kept if PCIEXBAR is never set by guest.
+        // Unwrap is safe because PCIEXBAR is inside the config space.
+        let pciexbar = le_read_u64(&self.config.config, PCIEXBAR as usize).unwrap();
+        if pciexbar != 0 {
+            if let Err(e) = self.update_pciexbar_mapping() {
+                bail!("Failed to update PCIEXBAR mapping: {}", e.display_chain());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -10,6 +10,8 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod cpu_controller;
+mod ged;
 mod ich9_lpc;
 mod mch;
 mod syscall;
@@ -29,27 +31,34 @@ use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
 use boot_loader::{load_linux, BootLoaderConfig};
 use cpu::{CPUBootConfig, CpuTopology, CPU};
 use devices::legacy::{FwCfgEntryType, FwCfgIO, FwCfgOps, PFlash, Serial, RTC, SERIAL_ADDR};
+use error_chain::ChainedError;
 use hypervisor::KVM_FDS;
 use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use machine_manager::config::{BootSource, PFlashConfig, SerialConfig, VmConfig};
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
+    DeviceInterface, GuestAgent, KvmVmState, MachineAddressInterface, MachineExternalInterface,
     MachineInterface, MachineLifecycle, MigrateInterface,
 };
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
-use migration::{MigrationManager, MigrationStatus};
-use pci::{PciDevOps, PciHost};
+use migration::{Compression, MigrationManager, MigrationStatus};
+use pci::{PciBus, PciDevOps, PciHost, PciHostState};
 use sysbus::SysBus;
 use util::loop_context::EventLoopManager;
 use util::seccomp::BpfRule;
 use util::set_termi_canon_mode;
-use virtio::{qmp_balloon, qmp_query_balloon};
+use vfio::VfioContainer;
+use virtio::{
+    qmp_balloon, qmp_query_balloon, qmp_query_balloon_stats, qmp_query_virtio_mem,
+    qmp_virtio_mem_resize,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 use super::errors::{ErrorKind, Result};
-use super::{AcpiBuilder, StdMachineOps};
+use super::{get_or_create_vfio_container, AcpiBuilder, StdMachineOps, CPU_TYPE};
 use crate::errors::{ErrorKind as MachineErrorKind, Result as MachineResult};
 use crate::{standard_vm::open_pflash_file, MachineOps};
+use cpu_controller::{CpuController, CPU_CONTROLLER_REGION_SIZE};
+use ged::{Ged, GED_REGION_SIZE};
 use mch::Mch;
 use syscall::syscall_whitelist;
 use util::byte_code::ByteCode;
@@ -102,13 +111,26 @@ pub struct StdMachine {
     boot_source: Arc<Mutex<BootSource>>,
     /// VM power button, handle VM `Shutdown` event.
     power_button: EventFd,
+    /// VM configuration, holds the drives and netdevs for hotplugged devices.
+    vm_config: Mutex<VmConfig>,
+    /// Device ids of the hotplugged `vCPU`s, indexed by `vCPU` id.
+    cpu_ids: Arc<Mutex<Vec<Option<String>>>>,
+    /// VFIO container shared by all the VFIO devices.
+    vfio_container: Mutex<Option<Arc<VfioContainer>>>,
+    /// Channel to guest agent.
+    guest_agent: Option<GuestAgent>,
+    /// ACPI CPU hotplug controller, only exists when `maxcpus` is larger than `cpus`.
+    cpu_controller: Option<Arc<Mutex<CpuController>>>,
 }
 
 impl StdMachine {
     pub fn new(vm_config: &VmConfig) -> MachineResult<Self> {
         use crate::errors::ResultExt;
 
-        let cpu_topo = CpuTopology::new(vm_config.machine_config.nr_cpus);
+        let cpu_topo = CpuTopology::new(
+            vm_config.machine_config.nr_cpus,
+            vm_config.machine_config.max_cpus,
+        );
         let sys_io = AddressSpace::new(Region::init_container_region(1 << 16))
             .chain_err(|| MachineErrorKind::CrtMemSpaceErr)?;
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
@@ -141,6 +163,15 @@ impl StdMachine {
             vm_state,
             power_button: EventFd::new(libc::EFD_NONBLOCK)
                 .chain_err(|| MachineErrorKind::InitPwrBtnErr)?,
+
+            vm_config: Mutex::new(vm_config.clone()),
+            cpu_ids: Arc::new(Mutex::new(vec![
+                None;
+                vm_config.machine_config.max_cpus as usize
+            ])),
+            vfio_container: Mutex::new(None),
+            guest_agent: None,
+            cpu_controller: None,
         })
     }
 
@@ -175,6 +206,36 @@ impl StdMachine {
             .chain_err(|| MachineErrorKind::CrtPitErr)?;
         Ok(())
     }
+
+    /// Add the GED and the ACPI CPU hotplug controller, the hotplugged `vCPU`s are
+    /// announced to the guest through them.
+    fn add_cpu_controller(&mut self) -> Result<()> {
+        use super::errors::ResultExt;
+
+        let ged_base = self.sysbus.min_free_base;
+        let ged = Ged::new()?
+            .realize(&mut self.sysbus, ged_base, GED_REGION_SIZE)
+            .chain_err(|| "Failed to realize GED")?;
+        self.sysbus.min_free_base += GED_REGION_SIZE;
+
+        let controller_base = self.sysbus.min_free_base;
+        let controller = CpuController::new(
+            self.cpus.clone(),
+            self.cpu_topo.clone(),
+            self.cpu_ids.clone(),
+            ged,
+        )
+        .realize(
+            &mut self.sysbus,
+            controller_base,
+            CPU_CONTROLLER_REGION_SIZE,
+        )
+        .chain_err(|| "Failed to realize cpu controller")?;
+        self.sysbus.min_free_base += CPU_CONTROLLER_REGION_SIZE;
+
+        self.cpu_controller = Some(controller);
+        Ok(())
+    }
 }
 
 impl StdMachineOps for StdMachine {
@@ -207,6 +268,10 @@ impl StdMachineOps for StdMachine {
             .root()
             .add_subregion(pio_data_region, 0xcfc)
             .chain_err(|| "Failed to register CONFIG_DATA port in I/O space.")?;
+        MigrationManager::register_device_instance_mutex(
+            PciHostState::descriptor(),
+            self.pci_host.clone(),
+        );
 
         let mch = Mch::new(root_bus.clone(), mmconfig_region, mmconfig_region_ops);
         PciDevOps::realize(mch)?;
@@ -220,9 +285,10 @@ impl StdMachineOps for StdMachine {
         use super::errors::ResultExt;
 
         let mut fwcfg = FwCfgIO::new(self.sys_mem.clone());
-        let ncpus = self.cpus.len();
+        let ncpus = self.cpu_topo.nrcpus as usize;
+        let max_cpus = self.cpu_topo.max_cpus as usize;
         fwcfg.add_data_entry(FwCfgEntryType::NbCpus, ncpus.as_bytes().to_vec())?;
-        fwcfg.add_data_entry(FwCfgEntryType::MaxCpus, ncpus.as_bytes().to_vec())?;
+        fwcfg.add_data_entry(FwCfgEntryType::MaxCpus, max_cpus.as_bytes().to_vec())?;
         fwcfg.add_data_entry(FwCfgEntryType::Irq0Override, 1_u32.as_bytes().to_vec())?;
 
         let fwcfg_dev = FwCfgIO::realize(fwcfg, &mut self.sysbus)
@@ -230,6 +296,52 @@ impl StdMachineOps for StdMachine {
 
         Ok(fwcfg_dev)
     }
+
+    fn get_vm_config(&self) -> &Mutex<VmConfig> {
+        &self.vm_config
+    }
+
+    fn get_pci_root_bus(&self) -> Arc<Mutex<PciBus>> {
+        self.pci_host.lock().unwrap().root_bus.clone()
+    }
+
+    fn get_guest_memory(&self) -> &Arc<AddressSpace> {
+        &self.sys_mem
+    }
+
+    fn get_cpu_topo(&self) -> &CpuTopology {
+        &self.cpu_topo
+    }
+
+    fn get_cpus(&self) -> &[Arc<CPU>] {
+        &self.cpus
+    }
+
+    fn get_cpu_ids(&self) -> &Arc<Mutex<Vec<Option<String>>>> {
+        &self.cpu_ids
+    }
+
+    fn get_vfio_container(&self) -> Result<Arc<VfioContainer>> {
+        get_or_create_vfio_container(&self.vfio_container, &self.sys_mem)
+    }
+
+    fn get_vm_state(&self) -> &Arc<(Mutex<KvmVmState>, Condvar)> {
+        &self.vm_state
+    }
+
+    fn notify_cpu_plug(&self, cpu_index: u8) -> Result<()> {
+        match &self.cpu_controller {
+            Some(controller) => controller.lock().unwrap().plug(cpu_index),
+            None => bail!("Cpu hotplug is not enabled, 'maxcpus' should be larger than 'cpus'"),
+        }
+    }
+
+    fn request_cpu_unplug(&self, cpu_index: u8) -> Result<()> {
+        match &self.cpu_controller {
+            Some(controller) => controller.lock().unwrap().unplug_request(cpu_index),
+            None => bail!("Cpu hotplug is not enabled, 'maxcpus' should be larger than 'cpus'"),
+        }
+    }
 }
 
 impl MachineOps for StdMachine {
@@ -245,6 +357,16 @@ impl MachineOps for StdMachine {
         ranges
     }
 
+    fn hotplug_mem_range(&self) -> (u64, u64) {
+        let (start, size) = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize];
+        (start, start + size)
+    }
+
+    fn reserved_mem_ranges(&self) -> Vec<(u64, u64)> {
+        MEM_LAYOUT[LayoutEntryType::PcieEcam as usize..LayoutEntryType::MemAbove4g as usize]
+            .to_vec()
+    }
+
     fn init_interrupt_controller(&mut self, _vcpu_count: u64) -> MachineResult<()> {
         use crate::errors::ResultExt;
 
@@ -347,12 +469,13 @@ impl MachineOps for StdMachine {
             is_migrate,
         )?;
 
-        locked_vm.init_interrupt_controller(u64::from(vm_config.machine_config.nr_cpus))?;
+        locked_vm.init_interrupt_controller(u64::from(vm_config.machine_config.max_cpus))?;
         let kvm_fds = KVM_FDS.load();
         let vm_fd = kvm_fds.vm_fd.as_ref().unwrap();
-        let nr_cpus = vm_config.machine_config.nr_cpus;
+        // The hotpluggable vcpus are created at boot too, and started when plugged.
+        let max_cpus = vm_config.machine_config.max_cpus;
         let mut vcpu_fds = vec![];
-        for cpu_id in 0..nr_cpus {
+        for cpu_id in 0..max_cpus {
             vcpu_fds.push(Arc::new(vm_fd.create_vcpu(cpu_id)?));
         }
 
@@ -360,21 +483,28 @@ impl MachineOps for StdMachine {
             .init_pci_host()
             .chain_err(|| ErrorKind::InitPCIeHostErr)?;
         locked_vm.add_devices(vm_config)?;
+        *locked_vm.vm_config.lock().unwrap() = vm_config.clone();
 
-        let (boot_config, fwcfg) = if !is_migrate {
-            let fwcfg = locked_vm.add_fwcfg_device()?;
-            (Some(locked_vm.load_boot_source(Some(&fwcfg))?), Some(fwcfg))
+        // FwCfg device is created when restoring too, so that it's still accessible to guest.
+        let fwcfg = locked_vm.add_fwcfg_device()?;
+        let boot_config = if !is_migrate {
+            Some(locked_vm.load_boot_source(Some(&fwcfg))?)
         } else {
-            (None, None)
+            None
         };
         locked_vm.cpus.extend(<Self as MachineOps>::init_vcpu(
             vm.clone(),
-            vm_config.machine_config.nr_cpus,
+            max_cpus,
             &vcpu_fds,
             &boot_config,
         )?);
+        if max_cpus > vm_config.machine_config.nr_cpus {
+            locked_vm
+                .add_cpu_controller()
+                .chain_err(|| "Failed to add cpu hotplug controller")?;
+        }
 
-        if let Some(fwcfg) = fwcfg {
+        if !is_migrate {
             locked_vm
                 .build_acpi_tables(&fwcfg)
                 .chain_err(|| "Failed to create ACPI tables")?;
@@ -445,16 +575,31 @@ impl MachineOps for StdMachine {
     }
 
     fn run(&self, paused: bool) -> MachineResult<()> {
-        <Self as MachineOps>::vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())
+        <Self as MachineOps>::vm_start(
+            paused,
+            &self.online_cpus(),
+            &mut self.vm_state.0.lock().unwrap(),
+        )
     }
 
     fn get_sys_mem(&mut self) -> &Arc<AddressSpace> {
         &self.sys_mem
     }
 
+    fn set_guest_agent(&mut self, agent: GuestAgent) {
+        self.guest_agent = Some(agent);
+    }
+
     fn get_pci_host(&mut self) -> MachineResult<&Arc<Mutex<PciHost>>> {
         Ok(&self.pci_host)
     }
+
+    fn get_vfio_container(&mut self) -> MachineResult<Arc<VfioContainer>> {
+        Ok(get_or_create_vfio_container(
+            &self.vfio_container,
+            &self.sys_mem,
+        )?)
+    }
 }
 
 impl AcpiBuilder for StdMachine {
@@ -465,14 +610,17 @@ impl AcpiBuilder for StdMachine {
     ) -> super::errors::Result<u64> {
         let mut dsdt = AcpiTable::new(*b"DSDT", 2, *b"STRATO", *b"VIRTDSDT", 1);
 
-        // 1. CPU info.
+        // 1. CPU info. The processor devices are under the cpu hotplug controller
+        // which is attached to system bus, if cpu hotplug is enabled.
         let cpus_count = self.cpus.len() as u64;
         let mut sb_scope = AmlScope::new("\\_SB");
-        for cpu_id in 0..cpus_count {
-            let mut dev = AmlDevice::new(format!("C{:03}", cpu_id).as_str());
-            dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0007".to_string())));
-            dev.append_child(AmlNameDecl::new("_UID", AmlInteger(cpu_id)));
-            sb_scope.append_child(dev);
+        if self.cpu_controller.is_none() {
+            for cpu_id in 0..cpus_count {
+                let mut dev = AmlDevice::new(format!("C{:03}", cpu_id).as_str());
+                dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0007".to_string())));
+                dev.append_child(AmlNameDecl::new("_UID", AmlInteger(cpu_id)));
+                sb_scope.append_child(dev);
+            }
         }
 
         // 2. Create pci host bridge node.
@@ -527,7 +675,13 @@ impl AcpiBuilder for StdMachine {
                 length: size_of::<AcpiLocalApic>() as u8,
                 processor_uid: cpu.id(),
                 apic_id: cpu.id(),
-                flags: 1, // Flags: enabled.
+                // Flags: enabled for online cpus, and online capable for the
+                // hotpluggable ones.
+                flags: if self.cpu_topo.get_mask(cpu.id() as usize) == 1 {
+                    1
+                } else {
+                    2
+                },
             };
             madt.append_child(&lapic.aml_bytes());
         });
@@ -585,7 +739,7 @@ impl MachineLifecycle for StdMachine {
 
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
         <Self as MachineOps>::vm_state_transfer(
-            &self.cpus,
+            &self.online_cpus(),
             &mut self.vm_state.0.lock().unwrap(),
             old,
             new,
@@ -689,7 +843,12 @@ impl DeviceInterface for StdMachine {
     }
 
     fn query_hotpluggable_cpus(&self) -> Response {
-        Response::create_empty_response()
+        let hotplug_vec: Vec<serde_json::Value> = self
+            .get_hotpluggable_cpus()
+            .iter()
+            .map(|cpu| serde_json::to_value(cpu).unwrap())
+            .collect();
+        Response::create_response(hotplug_vec.into(), None)
     }
 
     fn balloon(&self, value: u64) -> Response {
@@ -717,32 +876,103 @@ impl DeviceInterface for StdMachine {
         )
     }
 
-    fn device_add(
-        &self,
-        _id: String,
-        _driver: String,
-        _addr: Option<String>,
-        _lun: Option<usize>,
-    ) -> Response {
-        Response::create_empty_response()
+    fn query_balloon_stats(&self) -> Response {
+        if let Some(stats) = qmp_query_balloon_stats() {
+            return Response::create_response(serde_json::to_value(&stats).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
     }
 
-    fn device_del(&self, _device_id: String) -> Response {
-        Response::create_empty_response()
+    fn virtio_mem_resize(&self, id: String, requested_size: u64) -> Response {
+        match qmp_virtio_mem_resize(&id, requested_size) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn query_virtio_mem(&self) -> Response {
+        let infos = qmp_query_virtio_mem();
+        Response::create_response(serde_json::to_value(&infos).unwrap(), None)
+    }
+
+    fn device_add(&self, args: Box<qmp_schema::device_add>) -> Response {
+        let ret = if args.driver == CPU_TYPE {
+            self.hotplug_cpu(&args)
+        } else {
+            self.hotplug_pci_device(&args)
+        };
+        match ret {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                error!("Failed to add device: id {}, type {}", args.id, args.driver);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn device_del(&self, device_id: String) -> Response {
+        let ret = match self.find_cpu_by_id(&device_id) {
+            Some(cpu_index) => self.request_cpu_unplug(cpu_index),
+            None => self.hot_unplug_pci_device(&device_id),
+        };
+        match ret {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("Failed to delete device: {}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
     fn blockdev_add(
         &self,
-        _node_name: String,
-        _file: qmp_schema::FileOptions,
-        _cache: Option<qmp_schema::CacheOptions>,
-        _read_only: Option<bool>,
+        node_name: String,
+        file: qmp_schema::FileOptions,
+        cache: Option<qmp_schema::CacheOptions>,
+        read_only: Option<bool>,
+        driver: Option<String>,
     ) -> Response {
-        Response::create_empty_response()
+        match self.add_hotplug_drive(&node_name, &file, cache, read_only, driver) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
-    fn netdev_add(&self, _id: String, _if_name: Option<String>, _fds: Option<String>) -> Response {
-        Response::create_empty_response()
+    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> Response {
+        match self.add_hotplug_netdev(&id, if_name, fds) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{}", e.display_chain());
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
@@ -755,16 +985,45 @@ impl DeviceInterface for StdMachine {
             Response::create_error_response(err_resp, None)
         }
     }
+
+    fn get_guest_agent(&self) -> Option<GuestAgent> {
+        self.guest_agent.clone()
+    }
 }
 
 impl MigrateInterface for StdMachine {
-    fn migrate(&self, uri: String) -> Response {
+    fn migrate(
+        &self,
+        uri: String,
+        incremental: Option<bool>,
+        dirty_log: Option<bool>,
+        compress: Option<String>,
+    ) -> Response {
         use crate::error_chain::ChainedError;
         use util::unix::{parse_uri, UnixPath};
 
+        let compression = match compress.as_ref().map(|c| c.parse::<Compression>()) {
+            Some(Ok(compression)) => Some(compression),
+            Some(Err(())) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(format!(
+                        "Invalid compression algorithm: {}",
+                        compress.unwrap()
+                    )),
+                    None,
+                );
+            }
+            None => None,
+        };
+
         match parse_uri(&uri) {
             Ok((UnixPath::File, path)) => {
-                if let Err(e) = MigrationManager::save_snapshot(&path) {
+                if let Err(e) = MigrationManager::save_snapshot(
+                    &path,
+                    incremental.unwrap_or(false),
+                    dirty_log.unwrap_or(false),
+                    compression,
+                ) {
                     error!(
                         "Failed to migrate to path \'{:?}\': {}",
                         path,
@@ -778,6 +1037,33 @@ impl MigrateInterface for StdMachine {
                     );
                 }
             }
+            Ok(_)
+                if incremental.unwrap_or(false)
+                    || dirty_log.unwrap_or(false)
+                    || compression.is_some() =>
+            {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(
+                        "Options incremental, dirty-log and compress are only for snapshot"
+                            .to_string(),
+                    ),
+                    None,
+                );
+            }
+            Ok((path_type, addr)) if path_type == UnixPath::Tcp || path_type == UnixPath::Unix => {
+                if let Err(e) = <Self as MachineOps>::start_live_migration(
+                    path_type,
+                    &addr,
+                    self.online_cpus(),
+                    self.vm_state.clone(),
+                ) {
+                    error!("Failed to migrate to \'{}\': {}", addr, e.display_chain());
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            }
             _ => {
                 return Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
@@ -790,10 +1076,31 @@ impl MigrateInterface for StdMachine {
     }
 
     fn query_migrate(&self) -> Response {
-        let status_str = MigrationManager::migration_get_status().to_string();
-        let migration_info = qmp_schema::MigrationInfo {
-            status: Some(status_str),
+        let status = MigrationManager::migration_get_status();
+        let mut migration_info = qmp_schema::MigrationInfo {
+            status: Some(status.to_string()),
+            ..Default::default()
         };
+        if let Some(stats) = MigrationManager::migration_get_stats() {
+            migration_info.total_time = Some(stats.total_time);
+            if status == MigrationStatus::Completed {
+                migration_info.downtime = Some(stats.downtime);
+            }
+            migration_info.ram = Some(qmp_schema::MigrationStats {
+                transferred: stats.transferred_ram,
+                remaining: stats.remaining_ram,
+                total: stats.total_ram,
+                dirty_sync_count: stats.dirty_sync_count,
+            });
+        }
+        if let Some(stats) = MigrationManager::lazy_restore_stats() {
+            migration_info.lazy_restore = Some(qmp_schema::LazyRestoreStats {
+                total_pages: stats.total_pages(),
+                missing_pages: stats.missing_pages(),
+                prefetched_pages: stats.prefetched_pages(),
+                remaining_pages: stats.remaining_pages(),
+            });
+        }
 
         Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
     }
@@ -225,10 +225,17 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("incoming")
                 .long("incoming")
-                .help("wait for the URI to be specified via migrate_incoming")
+                .help("wait for the URI to be specified via migrate_incoming, such as file:/path, tcp:host:port or unix:/path")
                 .value_name("incoming")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("lazy-restore")
+                .long("lazy-restore")
+                .help("restore snapshot memory on demand with userfaultfd, used with -incoming file:/path")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("object")
                 .multiple(true)
@@ -16,12 +16,22 @@ use super::{
     errors::{ErrorKind, Result},
     pci_args_check, ConfigCheck, MAX_STRING_LENGTH,
 };
-use crate::config::{CmdParser, ExBool, VmConfig};
+use crate::config::{memory_unit_conversion, CmdParser, ExBool, VmConfig};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BalloonConfig {
     pub id: String,
     pub deflate_on_oom: bool,
+    /// Interval in seconds to poll the memory statistics of guest, 0 means disabled.
+    pub stats_polling_interval: u64,
+    pub free_page_reporting: bool,
+    /// Whether to inflate and deflate balloon automatically by host memory pressure.
+    pub auto_balloon: bool,
+    /// Minimum memory size of guest in bytes kept by the automatic balloon policy.
+    pub auto_balloon_min: u64,
+    /// Maximum memory size of guest in bytes restored by the automatic balloon policy,
+    /// 0 means the whole memory of guest.
+    pub auto_balloon_max: u64,
 }
 
 impl ConfigCheck for BalloonConfig {
@@ -33,6 +43,16 @@ impl ConfigCheck for BalloonConfig {
             )
             .into());
         }
+        if self.auto_balloon
+            && self.auto_balloon_max != 0
+            && self.auto_balloon_min > self.auto_balloon_max
+        {
+            bail!(
+                "auto-balloon-min {} is bigger than auto-balloon-max {}",
+                self.auto_balloon_min,
+                self.auto_balloon_max
+            );
+        }
 
         Ok(())
     }
@@ -49,7 +69,12 @@ pub fn parse_balloon(vm_config: &mut VmConfig, balloon_config: &str) -> Result<B
         .push("addr")
         .push("multifunction")
         .push("id")
-        .push("deflate-on-oom");
+        .push("deflate-on-oom")
+        .push("guest-stats-polling-interval")
+        .push("free-page-reporting")
+        .push("auto-balloon")
+        .push("auto-balloon-min")
+        .push("auto-balloon-max");
     cmd_parser.parse(balloon_config)?;
 
     pci_args_check(&cmd_parser)?;
@@ -57,6 +82,23 @@ pub fn parse_balloon(vm_config: &mut VmConfig, balloon_config: &str) -> Result<B
     if let Some(default) = cmd_parser.get_value::<ExBool>("deflate-on-oom")? {
         balloon.deflate_on_oom = default.into();
     }
+    if let Some(interval) = cmd_parser.get_value::<u64>("guest-stats-polling-interval")? {
+        balloon.stats_polling_interval = interval;
+    }
+    if let Some(reporting) = cmd_parser.get_value::<ExBool>("free-page-reporting")? {
+        balloon.free_page_reporting = reporting.into();
+    }
+    if let Some(auto_balloon) = cmd_parser.get_value::<ExBool>("auto-balloon")? {
+        balloon.auto_balloon = auto_balloon.into();
+    }
+    if let Some(min) = cmd_parser.get_value::<String>("auto-balloon-min")? {
+        balloon.auto_balloon_min = memory_unit_conversion(&min)?;
+    } else if balloon.auto_balloon {
+        return Err(ErrorKind::FieldIsMissing("auto-balloon-min", "virtio-balloon").into());
+    }
+    if let Some(max) = cmd_parser.get_value::<String>("auto-balloon-max")? {
+        balloon.auto_balloon_max = memory_unit_conversion(&max)?;
+    }
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
         balloon.id = id;
     }
@@ -82,6 +124,50 @@ mod tests {
         let balloon_configs = bln_cfg_res.unwrap();
         assert_eq!(balloon_configs.id, "balloon0".to_string());
         assert_eq!(balloon_configs.deflate_on_oom, true);
+        assert_eq!(balloon_configs.stats_polling_interval, 0);
+        assert_eq!(balloon_configs.free_page_reporting, false);
+
+        let mut vm_config = VmConfig::default();
+        let bln_cfg_res = parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,guest-stats-polling-interval=5,free-page-reporting=true",
+        );
+        assert!(bln_cfg_res.is_ok());
+        let balloon_configs = bln_cfg_res.unwrap();
+        assert_eq!(balloon_configs.stats_polling_interval, 5);
+        assert_eq!(balloon_configs.free_page_reporting, true);
+
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,guest-stats-polling-interval=-1",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_auto_balloon_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        let bln_cfg_res = parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,auto-balloon=on,auto-balloon-min=512M,auto-balloon-max=2G",
+        );
+        assert!(bln_cfg_res.is_ok());
+        let balloon_configs = bln_cfg_res.unwrap();
+        assert_eq!(balloon_configs.auto_balloon, true);
+        assert_eq!(balloon_configs.auto_balloon_min, 512 * 1024 * 1024);
+        assert_eq!(balloon_configs.auto_balloon_max, 2 * 1024 * 1024 * 1024);
+
+        // Minimum memory size is required.
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(&mut vm_config, "virtio-balloon-device,auto-balloon=on").is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,auto-balloon=on,auto-balloon-min=2G,auto-balloon-max=1G",
+        )
+        .is_err());
     }
 
     #[test]
@@ -22,21 +22,33 @@ const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_GUEST_CID: u64 = 4_294_967_295;
 const MIN_GUEST_CID: u64 = 3;
+/// Max number of ports of virtio-serial.
+const MAX_SERIAL_PORTS: u32 = 31;
+/// Max number of ports of virtio-serial-device, limited by the virtqueues of virtio mmio.
+const MAX_MMIO_SERIAL_PORTS: u32 = 3;
 
 /// Charecter device options.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChardevType {
     Stdio,
     Pty,
+    /// Unix socket in server mode, with `server` and `nowait`.
     Socket(String),
+    /// Unix socket in client mode, which connects to an external backend.
+    ClientSocket(String),
     File(String),
 }
 
-/// Config structure for virtio-console.
+/// Config structure for the port of virtio-serial, either virtconsole or virtserialport.
 #[derive(Debug, Clone)]
-pub struct VirtioConsole {
+pub struct VirtioSerialPort {
     pub id: String,
     pub chardev: ChardevConfig,
+    /// Port number in the virtio-serial device.
+    pub nr: u32,
+    /// Name of the port, by which the guest application finds it.
+    pub name: Option<String>,
+    pub is_console: bool,
 }
 
 /// Config structure for character device.
@@ -56,7 +68,10 @@ impl ConfigCheck for ChardevConfig {
             .into());
         }
 
-        if let ChardevType::Socket(path) | ChardevType::File(path) = &self.backend {
+        if let ChardevType::Socket(path)
+        | ChardevType::ClientSocket(path)
+        | ChardevType::File(path) = &self.backend
+        {
             if path.len() > MAX_PATH_LENGTH {
                 return Err(ErrorKind::StringLengthTooLong(
                     "socket path".to_string(),
@@ -90,19 +105,24 @@ fn check_chardev_args(cmd_parser: CmdParser) -> Result<()> {
                 }
             }
             "socket" => {
-                if let Some(server) = server {
-                    if server.ne("") {
-                        bail!("No parameter needed for server");
+                // Socket without both `server` and `nowait` is used in client mode, e.g.
+                // by vhost-user devices connecting to an external backend.
+                match (server, nowait) {
+                    (Some(server), Some(nowait)) => {
+                        if server.ne("") {
+                            bail!("No parameter needed for server");
+                        }
+                        if nowait.ne("") {
+                            bail!("No parameter needed for nowait");
+                        }
                     }
-                } else {
-                    bail!("Argument \'server\' is needed for socket-type chardev.");
-                }
-                if let Some(nowait) = nowait {
-                    if nowait.ne("") {
-                        bail!("No parameter needed for nowait");
+                    (Some(_), None) => {
+                        bail!("Argument \'nowait\' is needed for socket-type chardev.");
                     }
-                } else {
-                    bail!("Argument \'nowait\' is needed for socket-type chardev.");
+                    (None, Some(_)) => {
+                        bail!("Argument \'server\' is needed for socket-type chardev.");
+                    }
+                    (None, None) => (),
                 }
             }
             _ => (),
@@ -119,6 +139,7 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     };
     let backend = cmd_parser.get_value::<String>("")?;
     let path = cmd_parser.get_value::<String>("path")?;
+    let server = cmd_parser.get_value::<String>("server")?.is_some();
     check_chardev_args(cmd_parser)?;
     let chardev_type = if let Some(backend) = backend {
         match backend.as_str() {
@@ -126,7 +147,11 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
             "pty" => ChardevType::Pty,
             "socket" => {
                 if let Some(path) = path {
-                    ChardevType::Socket(path)
+                    if server {
+                        ChardevType::Socket(path)
+                    } else {
+                        ChardevType::ClientSocket(path)
+                    }
                 } else {
                     return Err(ErrorKind::FieldIsMissing("path", "socket-type chardev").into());
                 }
@@ -150,28 +175,88 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     })
 }
 
-pub fn parse_virtconsole(vm_config: &mut VmConfig, config_args: &str) -> Result<VirtioConsole> {
-    let mut cmd_parser = CmdParser::new("virtconsole");
-    cmd_parser.push("").push("id").push("chardev");
+pub fn parse_virtserialport(
+    vm_config: &mut VmConfig,
+    config_args: &str,
+    is_console: bool,
+) -> Result<()> {
+    let dev_name = if is_console {
+        "virtconsole"
+    } else {
+        "virtserialport"
+    };
+    let mut cmd_parser = CmdParser::new(dev_name);
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("chardev")
+        .push("nr")
+        .push("name");
     cmd_parser.parse(config_args)?;
 
     let chardev_name = if let Some(chardev) = cmd_parser.get_value::<String>("chardev")? {
         chardev
     } else {
-        return Err(ErrorKind::FieldIsMissing("chardev", "virtconsole").into());
+        return Err(ErrorKind::FieldIsMissing("chardev", dev_name).into());
     };
 
     let id = if let Some(chardev_id) = cmd_parser.get_value::<String>("id")? {
         chardev_id
     } else {
-        return Err(ErrorKind::FieldIsMissing("id", "virtconsole").into());
+        return Err(ErrorKind::FieldIsMissing("id", dev_name).into());
     };
 
+    let name = cmd_parser.get_value::<String>("name")?;
+    if let Some(name) = &name {
+        if name.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "virtio-serial port name".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+    }
+
+    let serial = if let Some(serial) = vm_config.virtio_serial.as_mut() {
+        serial
+    } else {
+        bail!("No virtio-serial-bus specified for {}", dev_name);
+    };
+    // Port 0 is reserved for virtconsole, as the guest treats it as console by default.
+    let nr = if let Some(nr) = cmd_parser.get_value::<u32>("nr")? {
+        nr
+    } else {
+        let start = if is_console { 0 } else { 1 };
+        (start..serial.max_ports)
+            .find(|nr| serial.ports.iter().all(|port| port.nr != *nr))
+            .unwrap_or(serial.max_ports)
+    };
+    if nr >= serial.max_ports {
+        return Err(ErrorKind::IllegalValue(
+            "virtio-serial port nr".to_string(),
+            0,
+            true,
+            u64::from(serial.max_ports),
+            false,
+        )
+        .into());
+    }
+    if serial.ports.iter().any(|port| port.nr == nr) {
+        bail!("Port nr {} of virtio-serial has been used", nr);
+    }
+    if nr == 0 && !is_console {
+        bail!("Port nr 0 of virtio-serial is reserved for virtconsole");
+    }
+
     if let Some(char_dev) = vm_config.chardev.remove(&chardev_name) {
-        return Ok(VirtioConsole {
+        serial.ports.push(VirtioSerialPort {
             id,
             chardev: char_dev,
+            nr,
+            name,
+            is_console,
         });
+        return Ok(());
     }
     bail!("Chardev {:?} not found or is in use", &chardev_name);
 }
@@ -306,6 +391,10 @@ pub struct VirtioSerialInfo {
     pub id: String,
     pub pci_bdf: Option<PciBdf>,
     pub multifunction: bool,
+    /// Max number of ports, multiport is disabled if it is 1.
+    pub max_ports: u32,
+    /// Ports attached to the virtio-serial device.
+    pub ports: Vec<VirtioSerialPort>,
 }
 
 impl ConfigCheck for VirtioSerialInfo {
@@ -318,6 +407,23 @@ impl ConfigCheck for VirtioSerialInfo {
             .into());
         }
 
+        // Each port takes two virtqueues, and multiport takes two more for control.
+        let max_ports = if self.pci_bdf.is_some() {
+            MAX_SERIAL_PORTS
+        } else {
+            MAX_MMIO_SERIAL_PORTS
+        };
+        if self.max_ports < 1 || self.max_ports > max_ports {
+            return Err(ErrorKind::IllegalValue(
+                "virtio-serial max_ports".to_string(),
+                1,
+                true,
+                u64::from(max_ports),
+                true,
+            )
+            .into());
+        }
+
         Ok(())
     }
 }
@@ -329,7 +435,8 @@ pub fn parse_virtio_serial(vm_config: &mut VmConfig, serial_config: &str) -> Res
         .push("id")
         .push("bus")
         .push("addr")
-        .push("multifunction");
+        .push("multifunction")
+        .push("max_ports");
     cmd_parser.parse(serial_config)?;
     pci_args_check(&cmd_parser)?;
 
@@ -350,12 +457,20 @@ pub fn parse_virtio_serial(vm_config: &mut VmConfig, serial_config: &str) -> Res
                 id,
                 pci_bdf: Some(pci_bdf),
                 multifunction,
+                max_ports: cmd_parser
+                    .get_value::<u32>("max_ports")?
+                    .unwrap_or(MAX_SERIAL_PORTS),
+                ports: Vec::new(),
             }
         } else {
             VirtioSerialInfo {
                 id,
                 pci_bdf: None,
                 multifunction,
+                max_ports: cmd_parser
+                    .get_value::<u32>("max_ports")?
+                    .unwrap_or(MAX_MMIO_SERIAL_PORTS),
+                ports: Vec::new(),
             }
         };
         virtio_serial.check()?;
@@ -379,13 +494,18 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console,id=console1",
+            true,
         );
         assert!(virt_console.is_ok());
-        let console_cfg = virt_console.unwrap();
+        let serial_info = vm_config.virtio_serial.clone().unwrap();
+        assert_eq!(serial_info.max_ports, 3);
+        let console_cfg = &serial_info.ports[0];
         assert_eq!(console_cfg.id, "console1");
+        assert_eq!(console_cfg.nr, 0);
+        assert!(console_cfg.is_console);
         assert_eq!(
             console_cfg.chardev.backend,
             ChardevType::Socket("/path/to/socket".to_string())
@@ -405,12 +525,79 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console1,id=console1",
+            true,
         );
         // test_console1 does not exist.
         assert!(virt_console.is_err());
+
+        // Virtio mmio supports 3 ports at most.
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_serial(&mut vm_config, "virtio-serial-device,max_ports=4").is_err());
+    }
+
+    #[test]
+    fn test_virtserialport_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_chardev("stdio,id=chardev0").is_ok());
+        // No virtio-serial device is specified.
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=chardev0,id=port0",
+            false
+        )
+        .is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_serial(&mut vm_config, "virtio-serial-device,max_ports=3").is_ok());
+        for i in 0..4 {
+            let chardev = format!("socket,id=chardev{},path=/path/to/socket{}", i, i);
+            assert!(vm_config.add_chardev(&chardev).is_ok());
+        }
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=chardev0,id=port0,name=org.qemu.guest_agent.0",
+            false
+        )
+        .is_ok());
+        // Port 0 is reserved for virtconsole.
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=chardev1,id=port1,nr=0",
+            false
+        )
+        .is_err());
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtconsole,chardev=chardev1,id=console0",
+            true
+        )
+        .is_ok());
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=chardev2,id=port2",
+            false
+        )
+        .is_ok());
+        // All ports are in use.
+        assert!(parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=chardev3,id=port3",
+            false
+        )
+        .is_err());
+
+        let ports = vm_config.virtio_serial.unwrap().ports;
+        assert_eq!(ports.len(), 3);
+        assert_eq!(ports[0].nr, 1);
+        assert_eq!(ports[0].name, Some("org.qemu.guest_agent.0".to_string()));
+        assert!(!ports[0].is_console);
+        assert_eq!(ports[1].nr, 0);
+        assert!(ports[1].is_console);
+        assert_eq!(ports[2].nr, 2);
+        assert_eq!(ports[2].name, None);
     }
 
     #[test]
@@ -423,15 +610,16 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console,id=console1",
+            true,
         );
         assert!(virt_console.is_ok());
-        let console_cfg = virt_console.unwrap();
-
-        assert_eq!(console_cfg.id, "console1");
         let serial_info = vm_config.virtio_serial.clone().unwrap();
+        assert_eq!(serial_info.max_ports, 31);
+        let console_cfg = serial_info.ports[0].clone();
+        assert_eq!(console_cfg.id, "console1");
         assert!(serial_info.pci_bdf.is_some());
         let bdf = serial_info.pci_bdf.unwrap();
         assert_eq!(bdf.bus, "pcie.0");
@@ -449,6 +637,31 @@ mod tests {
         .is_ok());
     }
 
+    #[test]
+    fn test_socket_chardev_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=server,path=/path/to/socket,server,nowait")
+            .is_ok());
+        assert_eq!(
+            vm_config.chardev.get("server").unwrap().backend,
+            ChardevType::Socket("/path/to/socket".to_string())
+        );
+        assert!(vm_config
+            .add_chardev("socket,id=client,path=/path/to/socket")
+            .is_ok());
+        assert_eq!(
+            vm_config.chardev.get("client").unwrap().backend,
+            ChardevType::ClientSocket("/path/to/socket".to_string())
+        );
+        assert!(vm_config
+            .add_chardev("socket,id=chardev0,path=/path/to/socket,server")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardev0,path=/path/to/socket,nowait")
+            .is_err());
+    }
+
     #[test]
     fn test_vsock_config_cmdline_parser() {
         let vsock_cfg_op = parse_vsock("vhost-vsock-device,id=test_vsock,guest-cid=3");
@@ -13,7 +13,10 @@
 extern crate serde;
 extern crate serde_json;
 
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
+use util::aio::AioEngine;
 
 use super::{
     errors::{ErrorKind, Result},
@@ -25,8 +28,56 @@ const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_SERIAL_NUM: usize = 20;
 const MAX_IOPS: u64 = 1_000_000;
+const MAX_BPS: u64 = 1 << 40;
+const MAX_QUEUES_BLK: u16 = 32;
 const MAX_UNIT_ID: usize = 2;
 
+/// Format of the disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl Default for DiskFormat {
+    fn default() -> Self {
+        DiskFormat::Raw
+    }
+}
+
+impl FromStr for DiskFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether to detect the write request with zeroes data, and convert it to write zeroes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteZeroesState {
+    Off,
+    On,
+    Unmap,
+}
+
+impl FromStr for WriteZeroesState {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(WriteZeroesState::Off),
+            "on" => Ok(WriteZeroesState::On),
+            "unmap" => Ok(WriteZeroesState::Unmap),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlkDevConfig {
@@ -36,7 +87,24 @@ pub struct BlkDevConfig {
     pub direct: bool,
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    #[serde(default)]
+    pub format: DiskFormat,
+    pub aio: AioEngine,
+    pub discard: bool,
+    pub write_zeroes: WriteZeroesState,
+    pub queues: u16,
+}
+
+impl BlkDevConfig {
+    /// Get the iothreads which the virtqueues are spread across, the names
+    /// of iothreads are separated by ':' in `iothread`.
+    pub fn iothreads(&self) -> Vec<String> {
+        self.iothread
+            .as_ref()
+            .map(|iothread| iothread.split(':').map(String::from).collect())
+            .unwrap_or_default()
+    }
 }
 
 impl Default for BlkDevConfig {
@@ -48,8 +116,79 @@ impl Default for BlkDevConfig {
             direct: true,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            format: DiskFormat::Raw,
+            aio: AioEngine::Native,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+            queues: 1,
+        }
+    }
+}
+
+/// IO throttle of block device, the limits are per second and zero means unlimited.
+/// The `*_max` limits are the bursts which are allowed to exceed the base limits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops_max: u64,
+    pub iops_rd_max: u64,
+    pub iops_wr_max: u64,
+    pub bps_max: u64,
+    pub bps_rd_max: u64,
+    pub bps_wr_max: u64,
+}
+
+impl ThrottleConfig {
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        *self != ThrottleConfig::default()
+    }
+}
+
+impl ConfigCheck for ThrottleConfig {
+    fn check(&self) -> Result<()> {
+        let limits = [
+            ("iops", self.iops, self.iops_max, MAX_IOPS),
+            ("iops_rd", self.iops_rd, self.iops_rd_max, MAX_IOPS),
+            ("iops_wr", self.iops_wr, self.iops_wr_max, MAX_IOPS),
+            ("bps", self.bps, self.bps_max, MAX_BPS),
+            ("bps_rd", self.bps_rd, self.bps_rd_max, MAX_BPS),
+            ("bps_wr", self.bps_wr, self.bps_wr_max, MAX_BPS),
+        ];
+        for (name, limit, burst, max) in limits.iter() {
+            if *limit > *max || *burst > *max {
+                return Err(ErrorKind::IllegalValue(
+                    format!("{} of block device", name),
+                    0,
+                    true,
+                    *max,
+                    true,
+                )
+                .into());
+            }
+            if *burst != 0 && (*limit == 0 || *burst < *limit) {
+                bail!(
+                    "{}_max of block device requires {} to be set and not larger than it",
+                    name,
+                    name
+                );
+            }
         }
+
+        if self.iops != 0 && (self.iops_rd != 0 || self.iops_wr != 0) {
+            bail!("iops total of block device can't be set with iops read or write");
+        }
+        if self.bps != 0 && (self.bps_rd != 0 || self.bps_wr != 0) {
+            bail!("bps total of block device can't be set with bps read or write");
+        }
+
+        Ok(())
     }
 }
 
@@ -62,7 +201,11 @@ pub struct DriveConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    pub format: DiskFormat,
+    pub aio: AioEngine,
+    pub discard: bool,
+    pub write_zeroes: WriteZeroesState,
 }
 
 impl Default for DriveConfig {
@@ -72,7 +215,11 @@ impl Default for DriveConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            format: DiskFormat::Raw,
+            aio: AioEngine::Native,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
         }
     }
 }
@@ -103,36 +250,73 @@ impl ConfigCheck for BlkDevConfig {
             .into());
         }
 
-        if self.iothread.is_some() && self.iothread.as_ref().unwrap().len() > MAX_STRING_LENGTH {
-            return Err(ErrorKind::StringLengthTooLong(
-                "iothread name".to_string(),
-                MAX_STRING_LENGTH,
-            )
-            .into());
+        for iothread in self.iothreads() {
+            if iothread.is_empty() || iothread.len() > MAX_STRING_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "iothread name".to_string(),
+                    MAX_STRING_LENGTH,
+                )
+                .into());
+            }
         }
 
-        if self.iops.is_some() && self.iops.unwrap() > MAX_IOPS {
+        if self.queues < 1 || self.queues > MAX_QUEUES_BLK {
             return Err(ErrorKind::IllegalValue(
-                "iops of block device".to_string(),
-                0,
+                "number queues of block device".to_string(),
+                1,
                 true,
-                MAX_IOPS,
+                MAX_QUEUES_BLK as u64,
                 true,
             )
             .into());
         }
 
+        self.throttle.check()?;
+
+        if self.direct && self.format == DiskFormat::Qcow2 {
+            bail!("Direct io is not supported for qcow2 image");
+        }
+
+        if self.aio == AioEngine::Native && !self.direct {
+            bail!("Aio engine native requires direct io to be enabled");
+        }
+
+        if self.aio == AioEngine::IoUring && self.format == DiskFormat::Qcow2 {
+            bail!("Aio engine io_uring is not supported for qcow2 image");
+        }
+
         Ok(())
     }
 }
 
+fn parse_throttle(cmd_parser: &CmdParser) -> Result<ThrottleConfig> {
+    let get = |name: &str| -> Result<u64> {
+        Ok(cmd_parser
+            .get_value::<u64>(&format!("throttling.{}", name))?
+            .unwrap_or(0))
+    };
+
+    Ok(ThrottleConfig {
+        iops: get("iops-total")?,
+        iops_rd: get("iops-read")?,
+        iops_wr: get("iops-write")?,
+        bps: get("bps-total")?,
+        bps_rd: get("bps-read")?,
+        bps_wr: get("bps-write")?,
+        iops_max: get("iops-total-max")?,
+        iops_rd_max: get("iops-read-max")?,
+        iops_wr_max: get("iops-write-max")?,
+        bps_max: get("bps-total-max")?,
+        bps_rd_max: get("bps-read-max")?,
+        bps_wr_max: get("bps-write-max")?,
+    })
+}
+
 pub fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     let mut drive = DriveConfig::default();
 
-    if let Some(format) = cmd_parser.get_value::<String>("format")? {
-        if format.ne("raw") {
-            bail!("Only \'raw\' type of block is supported");
-        }
+    if let Some(format) = cmd_parser.get_value::<DiskFormat>("format")? {
+        drive.format = format;
     }
 
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
@@ -152,8 +336,40 @@ pub fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     }
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
+        if drive.direct && drive.format == DiskFormat::Qcow2 {
+            bail!("Direct io is not supported for qcow2 image");
+        }
+    } else if drive.format == DiskFormat::Qcow2 {
+        // Metadata of qcow2 image is not aligned to sector, use buffered io.
+        drive.direct = false;
+    }
+    drive.aio = if let Some(aio) = cmd_parser.get_value::<AioEngine>("aio")? {
+        if aio == AioEngine::Native && !drive.direct {
+            bail!("Aio engine native requires direct io to be enabled");
+        }
+        if aio == AioEngine::IoUring && drive.format == DiskFormat::Qcow2 {
+            bail!("Aio engine io_uring is not supported for qcow2 image");
+        }
+        aio
+    } else if drive.direct {
+        AioEngine::Native
+    } else {
+        AioEngine::Threads
+    };
+    if let Some(discard) = cmd_parser.get_value::<String>("discard")? {
+        drive.discard = match discard.as_str() {
+            "unmap" => true,
+            "ignore" => false,
+            _ => return Err(ErrorKind::ConvertValueFailed("discard".to_string(), discard).into()),
+        };
+    }
+    if let Some(write_zeroes) = cmd_parser.get_value::<WriteZeroesState>("detect-zeroes")? {
+        if write_zeroes == WriteZeroesState::Unmap && !drive.discard {
+            bail!("Detect-zeroes unmap requires discard to be unmap");
+        }
+        drive.write_zeroes = write_zeroes;
     }
-    drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    drive.throttle = parse_throttle(&cmd_parser)?;
     Ok(drive)
 }
 
@@ -168,7 +384,8 @@ pub fn parse_blk(vm_config: &mut VmConfig, drive_config: &str) -> Result<BlkDevC
         .push("drive")
         .push("bootindex")
         .push("serial")
-        .push("iothread");
+        .push("iothread")
+        .push("num-queues");
 
     cmd_parser.parse(drive_config)?;
 
@@ -193,12 +410,20 @@ pub fn parse_blk(vm_config: &mut VmConfig, drive_config: &str) -> Result<BlkDevC
         blkdevcfg.serial_num = Some(serial);
     }
 
+    if let Some(queues) = cmd_parser.get_value::<u16>("num-queues")? {
+        blkdevcfg.queues = queues;
+    }
+
     if let Some(drive_arg) = &vm_config.drives.remove(&blkdrive) {
         blkdevcfg.id = drive_arg.id.clone();
         blkdevcfg.path_on_host = drive_arg.path_on_host.clone();
         blkdevcfg.read_only = drive_arg.read_only;
         blkdevcfg.direct = drive_arg.direct;
-        blkdevcfg.iops = drive_arg.iops;
+        blkdevcfg.throttle = drive_arg.throttle;
+        blkdevcfg.format = drive_arg.format;
+        blkdevcfg.aio = drive_arg.aio;
+        blkdevcfg.discard = drive_arg.discard;
+        blkdevcfg.write_zeroes = drive_arg.write_zeroes;
     } else {
         bail!("No drive configured matched for blk device");
     }
@@ -206,6 +431,93 @@ pub fn parse_blk(vm_config: &mut VmConfig, drive_config: &str) -> Result<BlkDevC
     Ok(blkdevcfg)
 }
 
+/// Config struct for vhost-user block device, the image is opened and
+/// processed by the backend connected through `socket_path`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostUserBlkDevConfig {
+    pub id: String,
+    pub socket_path: String,
+    pub queues: u16,
+}
+
+impl Default for VhostUserBlkDevConfig {
+    fn default() -> Self {
+        VhostUserBlkDevConfig {
+            id: "".to_string(),
+            socket_path: "".to_string(),
+            queues: 1,
+        }
+    }
+}
+
+impl ConfigCheck for VhostUserBlkDevConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "vhost-user blk device id".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+
+        if self.socket_path.len() > MAX_PATH_LENGTH {
+            return Err(
+                ErrorKind::StringLengthTooLong("socket path".to_string(), MAX_PATH_LENGTH).into(),
+            );
+        }
+
+        if self.queues < 1 || self.queues > MAX_QUEUES_BLK {
+            return Err(ErrorKind::IllegalValue(
+                "number queues of vhost-user block device".to_string(),
+                1,
+                true,
+                MAX_QUEUES_BLK as u64,
+                true,
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_vhost_user_blk(blk_config: &str) -> Result<VhostUserBlkDevConfig> {
+    let mut cmd_parser = CmdParser::new("vhost-user-blk");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("socket")
+        .push("num-queues");
+
+    cmd_parser.parse(blk_config)?;
+
+    pci_args_check(&cmd_parser)?;
+
+    let mut blkdevcfg = VhostUserBlkDevConfig::default();
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        blkdevcfg.id = id;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("id", "vhost-user-blk").into());
+    }
+
+    if let Some(socket_path) = cmd_parser.get_value::<String>("socket")? {
+        blkdevcfg.socket_path = socket_path;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("socket", "vhost-user-blk").into());
+    }
+
+    if let Some(queues) = cmd_parser.get_value::<u16>("num-queues")? {
+        blkdevcfg.queues = queues;
+    }
+
+    blkdevcfg.check()?;
+    Ok(blkdevcfg)
+}
+
 /// Config struct for `pflash`.
 /// Contains pflash device's attr.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -280,7 +592,21 @@ impl VmConfig {
             .push("format")
             .push("if")
             .push("throttling.iops-total")
-            .push("serial");
+            .push("throttling.iops-read")
+            .push("throttling.iops-write")
+            .push("throttling.bps-total")
+            .push("throttling.bps-read")
+            .push("throttling.bps-write")
+            .push("throttling.iops-total-max")
+            .push("throttling.iops-read-max")
+            .push("throttling.iops-write-max")
+            .push("throttling.bps-total-max")
+            .push("throttling.bps-read-max")
+            .push("throttling.bps-write-max")
+            .push("serial")
+            .push("aio")
+            .push("discard")
+            .push("detect-zeroes");
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -388,6 +714,199 @@ mod tests {
         assert!(blk_cfg_res.is_err()); // Can not find drive named "rootfs1".
     }
 
+    #[test]
+    fn test_drive_num_queues_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let blk_device_config = parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,drive=rootfs,bus=pcie.0,addr=0x1.0x2,num-queues=4,iothread=iothread1:iothread2",
+        )
+        .unwrap();
+        assert_eq!(blk_device_config.queues, 4);
+        assert_eq!(
+            blk_device_config.iothreads(),
+            vec![String::from("iothread1"), String::from("iothread2")]
+        );
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.queues, 1);
+        assert!(blk_device_config.iothreads().is_empty());
+
+        for cfg in [
+            "virtio-blk-device,drive=rootfs,num-queues=0",
+            "virtio-blk-device,drive=rootfs,num-queues=33",
+            "virtio-blk-device,drive=rootfs,iothread=iothread1::iothread2",
+        ]
+        .iter()
+        {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config
+                .add_drive("id=rootfs,file=/path/to/rootfs")
+                .is_ok());
+            assert!(parse_blk(&mut vm_config, cfg).is_err());
+        }
+    }
+
+    #[test]
+    fn test_drive_throttle_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "id=rootfs,file=/path/to/rootfs,throttling.iops-read=100,throttling.iops-write=50,\
+                throttling.bps-total=1048576,throttling.bps-total-max=2097152"
+            )
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        let throttle = blk_device_config.throttle;
+        assert!(throttle.is_enabled());
+        assert_eq!(throttle.iops, 0);
+        assert_eq!(throttle.iops_rd, 100);
+        assert_eq!(throttle.iops_wr, 50);
+        assert_eq!(throttle.bps, 1048576);
+        assert_eq!(throttle.bps_max, 2097152);
+        assert_eq!(throttle.bps_rd, 0);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert!(!blk_device_config.throttle.is_enabled());
+
+        // Total limit can't be set with read or write limit, burst requires a smaller base limit.
+        for cfg in [
+            "id=rootfs,file=/path/to/rootfs,throttling.iops-total=100,throttling.iops-read=50",
+            "id=rootfs,file=/path/to/rootfs,throttling.bps-total=100,throttling.bps-write=50",
+            "id=rootfs,file=/path/to/rootfs,throttling.iops-total-max=100",
+            "id=rootfs,file=/path/to/rootfs,throttling.iops-total=100,throttling.iops-total-max=50",
+            "id=rootfs,file=/path/to/rootfs,throttling.iops-total=1000001",
+        ]
+        .iter()
+        {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.add_drive(cfg).is_ok());
+            assert!(parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").is_err());
+        }
+    }
+
+    #[test]
+    fn test_drive_format_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2")
+            .is_ok());
+        let blk_cfg_res = parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs");
+        assert!(blk_cfg_res.is_ok());
+        let blk_device_config = blk_cfg_res.unwrap();
+        assert_eq!(blk_device_config.format, DiskFormat::Qcow2);
+        assert_eq!(blk_device_config.direct, false);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=raw")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.format, DiskFormat::Raw);
+        assert_eq!(blk_device_config.direct, true);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2,direct=on")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=vmdk")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_discard_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=unmap,detect-zeroes=unmap")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.discard, true);
+        assert_eq!(blk_device_config.write_zeroes, WriteZeroesState::Unmap);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=ignore,detect-zeroes=on")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.discard, false);
+        assert_eq!(blk_device_config.write_zeroes, WriteZeroesState::On);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "id=rootfs,file=/path/to/rootfs,format=qcow2,discard=unmap,detect-zeroes=unmap"
+            )
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.discard, true);
+        assert_eq!(blk_device_config.write_zeroes, WriteZeroesState::Unmap);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,detect-zeroes=unmap")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=on")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_aio_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,direct=off,aio=io_uring")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.aio, AioEngine::IoUring);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,direct=off")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.aio, AioEngine::Threads);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let blk_device_config =
+            parse_blk(&mut vm_config, "virtio-blk-device,drive=rootfs").unwrap();
+        assert_eq!(blk_device_config.aio, AioEngine::Native);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,direct=off,aio=native")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2,aio=io_uring")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,aio=posix")
+            .is_err());
+    }
+
     #[test]
     fn test_pci_block_config_cmdline_parser() {
         let mut vm_config = VmConfig::default();
@@ -423,6 +942,32 @@ mod tests {
         assert!(parse_blk(&mut vm_config, blk_cfg).is_ok());
     }
 
+    #[test]
+    fn test_vhost_user_blk_config_cmdline_parser() {
+        let blk_cfg = "vhost-user-blk-pci,id=blk0,bus=pcie.0,addr=0x3.0x0,socket=/tmp/vhost.sock,num-queues=4";
+        let blk_cfg_res = parse_vhost_user_blk(blk_cfg);
+        assert!(blk_cfg_res.is_ok());
+        let blk_cfg = blk_cfg_res.unwrap();
+        assert_eq!(blk_cfg.id, "blk0");
+        assert_eq!(blk_cfg.socket_path, "/tmp/vhost.sock");
+        assert_eq!(blk_cfg.queues, 4);
+
+        let blk_cfg = parse_vhost_user_blk("vhost-user-blk-device,id=blk0,socket=/tmp/vhost.sock");
+        assert!(blk_cfg.is_ok());
+        assert_eq!(blk_cfg.unwrap().queues, 1);
+
+        assert!(parse_vhost_user_blk("vhost-user-blk-device,id=blk0").is_err());
+        assert!(parse_vhost_user_blk("vhost-user-blk-device,socket=/tmp/vhost.sock").is_err());
+        assert!(parse_vhost_user_blk(
+            "vhost-user-blk-device,id=blk0,bus=pcie.0,addr=0x3.0x0,socket=/tmp/vhost.sock"
+        )
+        .is_err());
+        assert!(parse_vhost_user_blk(
+            "vhost-user-blk-pci,id=blk0,bus=pcie.0,addr=0x3.0x0,socket=/tmp/vhost.sock,num-queues=33"
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_pflash_config_cmdline_parser() {
         let mut vm_config = VmConfig::default();
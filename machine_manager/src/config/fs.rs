@@ -0,0 +1,182 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    errors::{ErrorKind, Result},
+    memory_unit_conversion, ChardevType, ConfigCheck, MAX_STRING_LENGTH,
+};
+use crate::config::{CmdParser, VmConfig};
+
+const MAX_PATH_LENGTH: usize = 4096;
+/// Max length of the mount tag, refer to Virtio Spec.
+const MAX_TAG_LENGTH: usize = 36;
+/// The DAX cache window must be aligned with 2M.
+const FS_CACHE_ALIGN: u64 = 0x20_0000;
+
+/// Config structure for virtio-fs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FsConfig {
+    pub id: String,
+    /// Mount tag used by the guest to identify the file system.
+    pub tag: String,
+    /// Path of the socket which virtiofsd listens on.
+    pub socket_path: String,
+    /// Size of the DAX cache window, 0 means DAX is disabled.
+    pub cache_size: u64,
+}
+
+impl ConfigCheck for FsConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(
+                ErrorKind::StringLengthTooLong("fs id".to_string(), MAX_STRING_LENGTH).into(),
+            );
+        }
+
+        if self.tag.is_empty() || self.tag.len() > MAX_TAG_LENGTH {
+            return Err(
+                ErrorKind::StringLengthTooLong("fs tag".to_string(), MAX_TAG_LENGTH).into(),
+            );
+        }
+
+        if self.socket_path.len() > MAX_PATH_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "fs socket path".to_string(),
+                MAX_PATH_LENGTH,
+            )
+            .into());
+        }
+
+        if self.cache_size % FS_CACHE_ALIGN != 0 {
+            return Err(ErrorKind::Unaligned(
+                "fs cache-size".to_string(),
+                self.cache_size,
+                FS_CACHE_ALIGN,
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_fs(vm_config: &mut VmConfig, fs_config: &str) -> Result<FsConfig> {
+    let mut cmd_parser = CmdParser::new("vhost-user-fs");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("chardev")
+        .push("tag")
+        .push("cache-size");
+    cmd_parser.parse(fs_config)?;
+
+    let mut fs_cfg = FsConfig::default();
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        fs_cfg.id = id;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("id", "vhost-user-fs").into());
+    }
+    if let Some(tag) = cmd_parser.get_value::<String>("tag")? {
+        fs_cfg.tag = tag;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("tag", "vhost-user-fs").into());
+    }
+    if let Some(cache_size) = cmd_parser.get_value::<String>("cache-size")? {
+        fs_cfg.cache_size = memory_unit_conversion(&cache_size)?;
+    }
+
+    let chardev_name = if let Some(chardev) = cmd_parser.get_value::<String>("chardev")? {
+        chardev
+    } else {
+        return Err(ErrorKind::FieldIsMissing("chardev", "vhost-user-fs").into());
+    };
+    if let Some(char_dev) = vm_config.chardev.remove(&chardev_name) {
+        if let ChardevType::ClientSocket(path) = char_dev.backend {
+            fs_cfg.socket_path = path;
+        } else {
+            bail!(
+                "Chardev {:?} of vhost-user-fs must be socket-type in client mode",
+                &chardev_name
+            );
+        }
+    } else {
+        bail!("Chardev {:?} not found or is in use", &chardev_name);
+    }
+
+    fs_cfg.check()?;
+    Ok(fs_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=char0,path=/tmp/vhost-fs.sock")
+            .is_ok());
+        let fs_cfg = parse_fs(
+            &mut vm_config,
+            "vhost-user-fs-device,id=fs0,chardev=char0,tag=myfs,cache-size=1G",
+        );
+        assert!(fs_cfg.is_ok());
+        let fs_cfg = fs_cfg.unwrap();
+        assert_eq!(fs_cfg.id, "fs0");
+        assert_eq!(fs_cfg.tag, "myfs");
+        assert_eq!(fs_cfg.socket_path, "/tmp/vhost-fs.sock");
+        assert_eq!(fs_cfg.cache_size, 1 << 30);
+
+        // The chardev has been consumed by the previous device.
+        assert!(parse_fs(
+            &mut vm_config,
+            "vhost-user-fs-device,id=fs1,chardev=char0,tag=myfs"
+        )
+        .is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_chardev("stdio,id=char0").is_ok());
+        assert!(parse_fs(
+            &mut vm_config,
+            "vhost-user-fs-device,id=fs0,chardev=char0,tag=myfs"
+        )
+        .is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=char0,path=/tmp/vhost-fs.sock")
+            .is_ok());
+        assert!(parse_fs(&mut vm_config, "vhost-user-fs-device,id=fs0,chardev=char0").is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=char0,path=/tmp/vhost-fs.sock")
+            .is_ok());
+        let tag = "t".repeat(MAX_TAG_LENGTH + 1);
+        let fs_cfg = format!("vhost-user-fs-device,id=fs0,chardev=char0,tag={}", tag);
+        assert!(parse_fs(&mut vm_config, &fs_cfg).is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=char0,path=/tmp/vhost-fs.sock")
+            .is_ok());
+        // Cache size is in MiB by default and must be aligned with 2M.
+        assert!(parse_fs(
+            &mut vm_config,
+            "vhost-user-fs-device,id=fs0,chardev=char0,tag=myfs,cache-size=3"
+        )
+        .is_err());
+    }
+}
@@ -78,6 +78,7 @@ impl Default for MachineMemConfig {
 pub struct MachineConfig {
     pub mach_type: MachineType,
     pub nr_cpus: u8,
+    pub max_cpus: u8,
     pub mem_config: MachineMemConfig,
 }
 
@@ -87,6 +88,7 @@ impl Default for MachineConfig {
         MachineConfig {
             mach_type: MachineType::MicroVm,
             nr_cpus: DEFAULT_CPUS,
+            max_cpus: DEFAULT_CPUS,
             mem_config: MachineMemConfig::default(),
         }
     }
@@ -189,7 +191,8 @@ impl VmConfig {
             .push("sockets")
             .push("cores")
             .push("threads")
-            .push("cpus");
+            .push("cpus")
+            .push("maxcpus");
 
         cmd_parser.parse(cpu_config)?;
 
@@ -201,9 +204,18 @@ impl VmConfig {
             return Err(ErrorKind::FieldIsMissing("cpus", "smp").into());
         };
 
+        let max_cpus = if let Some(max_cpus) = cmd_parser.get_value::<u64>("maxcpus")? {
+            if max_cpus < cpu {
+                bail!("Invalid \'maxcpus\' arguments for \'smp\', it should not be less than the number of cpus");
+            }
+            max_cpus
+        } else {
+            cpu
+        };
+
         if let Some(sockets) = cmd_parser.get_value::<u64>("sockets")? {
-            if sockets.ne(&cpu) {
-                bail!("Invalid \'sockets\' arguments for \'smp\', it should equal to the number of cpus");
+            if sockets.ne(&max_cpus) {
+                bail!("Invalid \'sockets\' arguments for \'smp\', it should equal to the maximum number of cpus");
             }
         }
         if let Some(cores) = cmd_parser.get_value::<u64>("cores")? {
@@ -228,9 +240,20 @@ impl VmConfig {
             )
             .into());
         }
+        if max_cpus > MAX_NR_CPUS {
+            return Err(ErrorKind::IllegalValue(
+                "Max CPU number".to_string(),
+                MIN_NR_CPUS,
+                true,
+                MAX_NR_CPUS,
+                true,
+            )
+            .into());
+        }
 
         // it is safe, as value limited before
         self.machine_config.nr_cpus = cpu as u8;
+        self.machine_config.max_cpus = max_cpus as u8;
 
         Ok(())
     }
@@ -241,7 +264,7 @@ impl VmConfig {
     }
 }
 
-fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
+pub(crate) fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
     if (origin_value.ends_with('M') | origin_value.ends_with('m'))
         && (origin_value.contains('M') ^ origin_value.contains('m'))
     {
@@ -302,6 +325,7 @@ mod tests {
         let mut machine_config = MachineConfig {
             mach_type: MachineType::MicroVm,
             nr_cpus: MIN_NR_CPUS as u8,
+            max_cpus: MIN_NR_CPUS as u8,
             mem_config: memory_config,
         };
         assert!(machine_config.check().is_ok());
@@ -319,4 +343,24 @@ mod tests {
 
         assert!(machine_config.check().is_ok());
     }
+
+    #[test]
+    fn test_cpu_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_cpu("cpus=4").is_ok());
+        assert_eq!(vm_config.machine_config.nr_cpus, 4);
+        assert_eq!(vm_config.machine_config.max_cpus, 4);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_cpu("2,maxcpus=8,sockets=8,cores=1,threads=1")
+            .is_ok());
+        assert_eq!(vm_config.machine_config.nr_cpus, 2);
+        assert_eq!(vm_config.machine_config.max_cpus, 8);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_cpu("cpus=4,maxcpus=2").is_err());
+        assert!(vm_config.add_cpu("cpus=2,maxcpus=255").is_err());
+        assert!(vm_config.add_cpu("cpus=2,maxcpus=8,sockets=2").is_err());
+    }
 }
@@ -18,12 +18,14 @@ mod boot_source;
 mod chardev;
 mod devices;
 mod drive;
+mod fs;
 mod iothread;
 mod machine_config;
 mod network;
 mod pci;
 mod rng;
 mod vfio;
+mod virtio_mem;
 
 use std::any::Any;
 use std::collections::HashMap;
@@ -38,12 +40,14 @@ pub use boot_source::*;
 pub use chardev::*;
 pub use devices::*;
 pub use drive::*;
+pub use fs::*;
 pub use iothread::*;
 pub use machine_config::*;
 pub use network::*;
 pub use pci::*;
 pub use rng::*;
 pub use vfio::*;
+pub use virtio_mem::*;
 
 pub mod errors {
     error_chain! {
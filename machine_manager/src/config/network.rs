@@ -22,7 +22,9 @@ use super::{
 use crate::config::{CmdParser, ConfigCheck, ExBool, VmConfig};
 
 const MAX_STRING_LENGTH: usize = 255;
+const MAX_PATH_LENGTH: usize = 4096;
 const MAC_ADDRESS_LENGTH: usize = 17;
+const MAX_QUEUE_PAIRS: u16 = 16;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetDevcfg {
@@ -31,6 +33,8 @@ pub struct NetDevcfg {
     pub vhost_type: Option<String>,
     pub vhost_fd: Option<i32>,
     pub ifname: String,
+    pub queues: u16,
+    pub socket_path: Option<String>,
 }
 
 impl Default for NetDevcfg {
@@ -41,6 +45,8 @@ impl Default for NetDevcfg {
             vhost_type: None,
             vhost_fd: None,
             ifname: "".to_string(),
+            queues: 1,
+            socket_path: None,
         }
     }
 }
@@ -57,12 +63,23 @@ pub struct NetworkInterfaceConfig {
     pub vhost_type: Option<String>,
     pub vhost_fd: Option<i32>,
     pub iothread: Option<String>,
+    pub queues: u16,
+    pub socket_path: Option<String>,
 }
 
 impl NetworkInterfaceConfig {
     pub fn set_mac(&mut self, mac_addr: String) {
         self.mac = Some(mac_addr);
     }
+
+    /// Get the iothreads which the queue pairs are spread across, the names
+    /// of iothreads are separated by ':' in `iothread`.
+    pub fn iothreads(&self) -> Vec<String> {
+        self.iothread
+            .as_ref()
+            .map(|iothread| iothread.split(':').map(String::from).collect())
+            .unwrap_or_default()
+    }
 }
 
 impl Default for NetworkInterfaceConfig {
@@ -75,6 +92,8 @@ impl Default for NetworkInterfaceConfig {
             vhost_type: None,
             vhost_fd: None,
             iothread: None,
+            queues: 1,
+            socket_path: None,
         }
     }
 }
@@ -98,19 +117,50 @@ impl ConfigCheck for NetworkInterfaceConfig {
         }
 
         if let Some(vhost_type) = self.vhost_type.as_ref() {
-            if vhost_type != "vhost-kernel" {
+            if vhost_type != "vhost-kernel" && vhost_type != "vhost-user" {
                 return Err(ErrorKind::UnknownVhostType.into());
             }
         }
 
-        if self.iothread.is_some() && self.iothread.as_ref().unwrap().len() > MAX_STRING_LENGTH {
-            return Err(ErrorKind::StringLengthTooLong(
-                "iothread name".to_string(),
-                MAX_STRING_LENGTH,
+        if let Some(socket_path) = self.socket_path.as_ref() {
+            if socket_path.len() > MAX_PATH_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "socket path".to_string(),
+                    MAX_PATH_LENGTH,
+                )
+                .into());
+            }
+        }
+
+        for iothread in self.iothreads() {
+            if iothread.is_empty() || iothread.len() > MAX_STRING_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "iothread name".to_string(),
+                    MAX_STRING_LENGTH,
+                )
+                .into());
+            }
+        }
+
+        if self.queues < 1 || self.queues > MAX_QUEUE_PAIRS {
+            return Err(ErrorKind::IllegalValue(
+                "number queue pairs of net device".to_string(),
+                1,
+                true,
+                MAX_QUEUE_PAIRS as u64,
+                true,
             )
             .into());
         }
 
+        if self.queues > 1 && self.vhost_type.is_some() {
+            bail!("Multi-queue is not supported for vhost net device");
+        }
+
+        if self.queues > 1 && self.host_dev_name.is_empty() {
+            bail!("Multi-queue net device needs \'ifname\' to open the tap queues");
+        }
+
         Ok(())
     }
 }
@@ -139,10 +189,28 @@ pub fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
             net.vhost_type = Some(String::from("vhost-kernel"));
         }
     }
+    if let Some(vhost_type) = cmd_parser.get_value::<String>("vhost_type")? {
+        if vhost_type.ne("vhost-kernel") && vhost_type.ne("vhost-user") {
+            return Err(ErrorKind::UnknownVhostType.into());
+        }
+        net.vhost_type = Some(vhost_type);
+    }
     net.tap_fd = cmd_parser.get_value::<i32>("fd")?;
     net.vhost_fd = cmd_parser.get_value::<i32>("vhostfd")?;
-    if net.vhost_fd.is_some() && net.vhost_type.is_none() {
-        bail!("Argument \'vhostfd\' is not needed for virtio-net device");
+    if net.vhost_fd.is_some() && net.vhost_type != Some(String::from("vhost-kernel")) {
+        bail!("Argument \'vhostfd\' is only needed for vhost-kernel net device");
+    }
+    if let Some(queues) = cmd_parser.get_value::<u16>("queues")? {
+        net.queues = queues;
+    }
+    net.socket_path = cmd_parser.get_value::<String>("socket")?;
+    if net.vhost_type == Some(String::from("vhost-user")) {
+        if net.socket_path.is_none() {
+            return Err(ErrorKind::FieldIsMissing("socket", "vhost-user netdev").into());
+        }
+        return Ok(net);
+    } else if net.socket_path.is_some() {
+        bail!("Argument \'socket\' is only needed for vhost-user net device");
     }
     if net.tap_fd.is_none() && net.ifname.eq("") {
         bail!("Tap device is missing, use \'ifname\' or \'fd\' to configure a tap device");
@@ -186,6 +254,8 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         netdevinterfacecfg.tap_fd = netcfg.tap_fd;
         netdevinterfacecfg.vhost_fd = netcfg.vhost_fd;
         netdevinterfacecfg.vhost_type = netcfg.vhost_type.clone();
+        netdevinterfacecfg.queues = netcfg.queues;
+        netdevinterfacecfg.socket_path = netcfg.socket_path.clone();
     } else {
         bail!("Netdev: {:?} not found for net device", &netdev);
     }
@@ -203,7 +273,10 @@ impl VmConfig {
             .push("fd")
             .push("vhost")
             .push("ifname")
-            .push("vhostfd");
+            .push("vhostfd")
+            .push("queues")
+            .push("vhost_type")
+            .push("socket");
 
         cmd_parser.parse(netdev_config)?;
         let drive_cfg = parse_netdev(cmd_parser)?;
@@ -316,6 +389,38 @@ mod tests {
         assert!(net_cfg_res.is_err());
     }
 
+    #[test]
+    fn test_network_queues_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,ifname=tap0,queues=4")
+            .is_ok());
+        let net_cfg_res = parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net0,netdev=eth0,bus=pcie.0,addr=0x1.0x2,iothread=iothread1:iothread2",
+        );
+        assert!(net_cfg_res.is_ok());
+        let network_configs = net_cfg_res.unwrap();
+        assert_eq!(network_configs.queues, 4);
+        assert_eq!(
+            network_configs.iothreads(),
+            vec![String::from("iothread1"), String::from("iothread2")]
+        );
+
+        for netdev in [
+            "tap,id=eth0,ifname=tap0,queues=0",
+            "tap,id=eth0,ifname=tap0,queues=17",
+            "tap,id=eth0,ifname=tap0,queues=2,vhost=on",
+            "tap,id=eth0,fd=35,queues=2",
+        ]
+        .iter()
+        {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.add_netdev(netdev).is_ok());
+            assert!(parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=eth0").is_err());
+        }
+    }
+
     #[test]
     fn test_pci_network_config_cmdline_parser() {
         let mut vm_config = VmConfig::default();
@@ -354,4 +459,43 @@ mod tests {
             "virtio-net-pci,id=net1,netdev=eth1,bus=pcie.0,addr=0x1.0x2,mac=12:34:56:78:9A:BC,multifunction=on";
         assert!(parse_net(&mut vm_config, net_cfg).is_ok());
     }
+
+    #[test]
+    fn test_vhost_user_network_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,vhost_type=vhost-user,socket=/tmp/vhost-user.sock")
+            .is_ok());
+        let net_cfg_res = parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net0,netdev=eth0,bus=pcie.0,addr=0x1.0x0",
+        );
+        assert!(net_cfg_res.is_ok());
+        let network_configs = net_cfg_res.unwrap();
+        assert_eq!(network_configs.vhost_type, Some(String::from("vhost-user")));
+        assert_eq!(
+            network_configs.socket_path,
+            Some(String::from("/tmp/vhost-user.sock"))
+        );
+        assert!(network_configs.host_dev_name.is_empty());
+
+        let mut vm_config = VmConfig::default();
+        // The socket path is missing.
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,vhost_type=vhost-user")
+            .is_err());
+        // The socket is only for vhost-user.
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,ifname=tap0,socket=/tmp/vhost-user.sock")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,vhost_type=vhost-user,socket=/tmp/vhost-user.sock,vhostfd=4")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,vhost_type=vhost-unknown,socket=/tmp/vhost-user.sock")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,ifname=tap0,vhost_type=vhost-kernel,vhostfd=4")
+            .is_ok());
+    }
 }
@@ -0,0 +1,201 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    errors::{ErrorKind, Result},
+    memory_unit_conversion, pci_args_check, ConfigCheck, MAX_STRING_LENGTH,
+};
+use crate::config::{CmdParser, VmConfig};
+
+/// Default size of the blocks that guest plugs and unplugs.
+const DEFAULT_BLOCK_SIZE: u64 = 0x20_0000;
+/// Minimum size of the blocks that guest plugs and unplugs.
+const MIN_BLOCK_SIZE: u64 = 0x10_0000;
+
+/// Config structure for virtio-mem.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VirtioMemConfig {
+    pub id: String,
+    /// Size of the hotpluggable memory region.
+    pub size: u64,
+    /// Size of memory which guest is requested to plug at startup.
+    pub requested_size: u64,
+    /// Granularity of plugging and unplugging memory.
+    pub block_size: u64,
+}
+
+impl ConfigCheck for VirtioMemConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "virtio-mem id".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+
+        if !self.block_size.is_power_of_two() || self.block_size < MIN_BLOCK_SIZE {
+            bail!(
+                "block-size {} of virtio-mem should be a power of 2 and not less than {}",
+                self.block_size,
+                MIN_BLOCK_SIZE
+            );
+        }
+
+        if self.size == 0 || self.size % self.block_size != 0 {
+            return Err(ErrorKind::Unaligned(
+                "virtio-mem size".to_string(),
+                self.size,
+                self.block_size,
+            )
+            .into());
+        }
+
+        if self.requested_size % self.block_size != 0 {
+            return Err(ErrorKind::Unaligned(
+                "virtio-mem requested-size".to_string(),
+                self.requested_size,
+                self.block_size,
+            )
+            .into());
+        }
+
+        if self.requested_size > self.size {
+            bail!(
+                "requested-size {} of virtio-mem is bigger than size {}",
+                self.requested_size,
+                self.size
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_virtio_mem(vm_config: &mut VmConfig, mem_config: &str) -> Result<VirtioMemConfig> {
+    let mut cmd_parser = CmdParser::new("virtio-mem");
+    cmd_parser
+        .push("")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("id")
+        .push("size")
+        .push("requested-size")
+        .push("block-size");
+    cmd_parser.parse(mem_config)?;
+
+    pci_args_check(&cmd_parser)?;
+    let mut mem_cfg = VirtioMemConfig {
+        block_size: DEFAULT_BLOCK_SIZE,
+        ..Default::default()
+    };
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        mem_cfg.id = id;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("id", "virtio-mem").into());
+    }
+    if let Some(size) = cmd_parser.get_value::<String>("size")? {
+        mem_cfg.size = memory_unit_conversion(&size)?;
+    } else {
+        return Err(ErrorKind::FieldIsMissing("size", "virtio-mem").into());
+    }
+    if let Some(requested_size) = cmd_parser.get_value::<String>("requested-size")? {
+        mem_cfg.requested_size = memory_unit_conversion(&requested_size)?;
+    }
+    if let Some(block_size) = cmd_parser.get_value::<String>("block-size")? {
+        mem_cfg.block_size = memory_unit_conversion(&block_size)?;
+    }
+    mem_cfg.check()?;
+
+    if vm_config.dev_name.get(&mem_cfg.id).is_some() {
+        return Err(ErrorKind::IdRepeat("virtio-mem".to_string(), mem_cfg.id).into());
+    }
+    vm_config.dev_name.insert(mem_cfg.id.clone(), 1);
+    Ok(mem_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtio_mem_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        let mem_cfg = parse_virtio_mem(
+            &mut vm_config,
+            "virtio-mem-device,id=mem0,size=4G,requested-size=1G",
+        );
+        assert!(mem_cfg.is_ok());
+        let mem_cfg = mem_cfg.unwrap();
+        assert_eq!(mem_cfg.id, "mem0");
+        assert_eq!(mem_cfg.size, 4 << 30);
+        assert_eq!(mem_cfg.requested_size, 1 << 30);
+        assert_eq!(mem_cfg.block_size, DEFAULT_BLOCK_SIZE);
+
+        // The id has been used by the previous device.
+        assert!(parse_virtio_mem(&mut vm_config, "virtio-mem-device,id=mem0,size=4G").is_err());
+
+        let mut vm_config = VmConfig::default();
+        let mem_cfg = parse_virtio_mem(
+            &mut vm_config,
+            "virtio-mem-pci,id=mem0,bus=pcie.0,addr=0x5.0x0,size=1024,block-size=128M",
+        );
+        assert!(mem_cfg.is_ok());
+        let mem_cfg = mem_cfg.unwrap();
+        assert_eq!(mem_cfg.size, 1 << 30);
+        assert_eq!(mem_cfg.requested_size, 0);
+        assert_eq!(mem_cfg.block_size, 128 << 20);
+
+        // Id and size are required.
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_mem(&mut vm_config, "virtio-mem-device,size=4G").is_err());
+        assert!(parse_virtio_mem(&mut vm_config, "virtio-mem-device,id=mem0").is_err());
+        // Mmio device does not support bus.
+        assert!(parse_virtio_mem(
+            &mut vm_config,
+            "virtio-mem-device,id=mem0,bus=pcie.0,size=4G"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_virtio_mem_config_check() {
+        let mut mem_cfg = VirtioMemConfig {
+            id: "mem0".to_string(),
+            size: 1 << 30,
+            requested_size: 512 << 20,
+            block_size: DEFAULT_BLOCK_SIZE,
+        };
+        assert!(mem_cfg.check().is_ok());
+
+        // Block size must be a power of 2 and not less than 1M.
+        mem_cfg.block_size = 3 << 20;
+        assert!(mem_cfg.check().is_err());
+        mem_cfg.block_size = 512 << 10;
+        assert!(mem_cfg.check().is_err());
+        mem_cfg.block_size = DEFAULT_BLOCK_SIZE;
+
+        // Sizes must be aligned with block size.
+        mem_cfg.size = (1 << 30) + (1 << 20);
+        assert!(mem_cfg.check().is_err());
+        mem_cfg.size = 1 << 30;
+        mem_cfg.requested_size = 1 << 20;
+        assert!(mem_cfg.check().is_err());
+
+        // Requested size can not exceed the region.
+        mem_cfg.requested_size = 2 << 30;
+        assert!(mem_cfg.check().is_err());
+    }
+}
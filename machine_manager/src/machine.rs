@@ -17,12 +17,15 @@ use std::sync::{Arc, Mutex};
 
 use strum::VariantNames;
 
+use crate::config::{ConfigCheck, ThrottleConfig};
+use crate::errors::{Result, ResultExt};
 use crate::qmp::qmp_schema::{
-    CacheOptions, ChardevInfo, Cmd, CmdLine, DeviceProps, Events, FileOptions, GicCap,
-    IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities, PropList, QmpCommand, QmpEvent,
-    Target, TypeLists,
+    block_set_io_throttle, device_add, Any, BlockDeviceInfo, BlockInfo, BlockStats, CacheOptions,
+    ChardevInfo, Cmd, CmdLine, DeviceProps, Events, FileOptions, GicCap, GuestNetworkInterface,
+    IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities, PropList, QmpCommand, QmpErrorClass,
+    QmpEvent, Target, TypeLists,
 };
-use crate::qmp::{Response, Version};
+use crate::qmp::{QmpChannel, Response, Version};
 
 #[derive(Clone)]
 pub struct PathInfo {
@@ -146,13 +149,7 @@ pub trait DeviceInterface {
     fn query_hotpluggable_cpus(&self) -> Response;
 
     /// Add a device with configuration.
-    fn device_add(
-        &self,
-        device_id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> Response;
+    fn device_add(&self, args: Box<device_add>) -> Response;
 
     /// Delete a device with device id.
     fn device_del(&self, device_id: String) -> Response;
@@ -164,6 +161,7 @@ pub trait DeviceInterface {
         file: FileOptions,
         cache: Option<CacheOptions>,
         read_only: Option<bool>,
+        driver: Option<String>,
     ) -> Response;
 
     /// Create a new network device.
@@ -178,6 +176,15 @@ pub trait DeviceInterface {
     /// Set balloon's size.
     fn balloon(&self, size: u64) -> Response;
 
+    /// Query the memory statistics of guest reported by balloon.
+    fn query_balloon_stats(&self) -> Response;
+
+    /// Set the size of memory which guest is requested to plug by virtio-mem device.
+    fn virtio_mem_resize(&self, id: String, requested_size: u64) -> Response;
+
+    /// Query the memory region and plugged size of virtio-mem devices.
+    fn query_virtio_mem(&self) -> Response;
+
     /// Query the version of StratoVirt.
     fn query_version(&self) -> Response {
         let version = Version::new(1, 0, 5);
@@ -371,18 +378,73 @@ pub trait DeviceInterface {
     }
 
     fn query_block(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
+        let vec_block: Vec<BlockInfo> = BLOCK_DEVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|block| block.lock().unwrap().query_block_info())
+            .collect();
+        Response::create_response(serde_json::to_value(&vec_block).unwrap(), None)
     }
 
     fn query_named_block_nodes(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
+        let vec_node: Vec<BlockDeviceInfo> = BLOCK_DEVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|block| block.lock().unwrap().query_block_info())
+            .filter_map(|info| info.inserted)
+            .collect();
+        Response::create_response(serde_json::to_value(&vec_node).unwrap(), None)
     }
 
     fn query_blockstats(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
+        let vec_stats: Vec<BlockStats> = BLOCK_DEVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|block| block.lock().unwrap().query_block_stats())
+            .collect();
+        Response::create_response(serde_json::to_value(&vec_stats).unwrap(), None)
+    }
+
+    /// Change the IO throttle of a block device.
+    fn block_set_io_throttle(&self, args: block_set_io_throttle) -> Response {
+        let throttle = ThrottleConfig {
+            iops: args.iops,
+            iops_rd: args.iops_rd,
+            iops_wr: args.iops_wr,
+            bps: args.bps,
+            bps_rd: args.bps_rd,
+            bps_wr: args.bps_wr,
+            iops_max: args.iops_max.unwrap_or(0),
+            iops_rd_max: args.iops_rd_max.unwrap_or(0),
+            iops_wr_max: args.iops_wr_max.unwrap_or(0),
+            bps_max: args.bps_max.unwrap_or(0),
+            bps_rd_max: args.bps_rd_max.unwrap_or(0),
+            bps_wr_max: args.bps_wr_max.unwrap_or(0),
+        };
+        if let Err(ref e) = throttle.check() {
+            return Response::create_error_response(
+                QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+
+        for block in BLOCK_DEVICES.lock().unwrap().iter() {
+            let mut locked_block = block.lock().unwrap();
+            if let Some(info) = locked_block.query_block_info() {
+                if info.device == args.device {
+                    locked_block.set_io_throttle(throttle);
+                    return Response::create_empty_response();
+                }
+            }
+        }
+
+        Response::create_error_response(
+            QmpErrorClass::DeviceNotFound(format!("Block device {} is not found", args.device)),
+            None,
+        )
     }
 
     fn query_gic_capabilities(&self) -> Response {
@@ -398,6 +460,70 @@ pub trait DeviceInterface {
         }
         Response::create_response(serde_json::to_value(&vec_iothreads).unwrap(), None)
     }
+
+    /// Get the channel to guest agent, by which the QMP commands of guest agent
+    /// are executed in guest.
+    fn get_guest_agent(&self) -> Option<GuestAgent> {
+        None
+    }
+}
+
+/// Execute the command in guest agent without blocking the main loop, the
+/// response is sent to QMP client once guest agent responds. Return the error
+/// response if the command can't be sent to guest agent.
+///
+/// # Arguments
+///
+/// * `agent` - The channel to guest agent.
+/// * `execute` - Name of the command of guest agent.
+/// * `arguments` - Arguments of the command.
+/// * `wait_response` - Whether to wait for the response of guest agent.
+/// * `id` - The `id` of QMP request.
+pub fn guest_agent_execute(
+    agent: Option<GuestAgent>,
+    execute: &str,
+    arguments: Option<Any>,
+    wait_response: bool,
+    id: Option<String>,
+) -> Option<Response> {
+    let agent = match agent {
+        Some(agent) => agent,
+        None => {
+            return Some(guest_agent_response(
+                Err("No guest agent channel is configured".into()),
+                id,
+            ))
+        }
+    };
+
+    let is_network_interfaces = execute == "guest-network-get-interfaces";
+    let resp_id = id.clone();
+    let callback: GuestAgentCallback = Box::new(move |result| {
+        let result = if is_network_interfaces {
+            // Only the fields in QMP schema are reported.
+            result.and_then(|ret| {
+                let interfaces: Vec<GuestNetworkInterface> = serde_json::from_value(ret)
+                    .chain_err(|| "Invalid response of guest-network-get-interfaces")?;
+                Ok(serde_json::to_value(&interfaces)?)
+            })
+        } else {
+            result
+        };
+        QmpChannel::send_response(&guest_agent_response(result, resp_id));
+    });
+
+    let mut locked_agent = agent.lock().unwrap();
+    match locked_agent.execute(execute, arguments, wait_response, callback) {
+        Ok(()) => None,
+        Err(e) => Some(guest_agent_response(Err(e), id)),
+    }
+}
+
+fn guest_agent_response(result: Result<Any>, id: Option<String>) -> Response {
+    match result {
+        Ok(ret) => Response::create_response(ret, id),
+        Err(e) => Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), id),
+    }
 }
 
 /// Migrate external api
@@ -407,7 +533,13 @@ pub trait DeviceInterface {
 /// Some external api for migration.
 pub trait MigrateInterface {
     /// Migrates the current running guest to another VM or file.
-    fn migrate(&self, _uri: String) -> Response {
+    fn migrate(
+        &self,
+        _uri: String,
+        _incremental: Option<bool>,
+        _dirty_log: Option<bool>,
+        _compress: Option<String>,
+    ) -> Response {
         Response::create_empty_response()
     }
 
@@ -417,6 +549,60 @@ pub trait MigrateInterface {
     }
 }
 
+/// Block device interface
+///
+/// # Notes
+///
+/// Implemented by block devices to report their information and statistics to
+/// QMP and to be tuned by QMP, the devices are registered in `BLOCK_DEVICES`.
+pub trait BlockDeviceInterface {
+    /// Query the information of the block device, return `None` if no drive
+    /// is attached to the device.
+    fn query_block_info(&self) -> Option<BlockInfo>;
+
+    /// Query the IO statistics of the block device, return `None` if no drive
+    /// is attached to the device.
+    fn query_block_stats(&self) -> Option<BlockStats>;
+
+    /// Change the IO throttle of the block device without reset, the limits
+    /// have been checked by caller.
+    fn set_io_throttle(&mut self, throttle: ThrottleConfig);
+}
+
+/// Block device registered in `BLOCK_DEVICES`.
+pub type BlockDevice = Arc<Mutex<dyn BlockDeviceInterface + Send>>;
+
+/// Guest agent interface
+///
+/// # Notes
+///
+/// Implemented by the channel to guest agent, by which QMP commands of guest agent
+/// are executed in guest.
+pub trait GuestAgentInterface {
+    /// Send the command to guest agent, `callback` is called with the result of it
+    /// once guest agent responds or the command times out.
+    ///
+    /// # Arguments
+    ///
+    /// * `execute` - Name of the command of guest agent.
+    /// * `arguments` - Arguments of the command.
+    /// * `wait_response` - Whether to wait for the response, empty result is returned if not.
+    /// * `callback` - Called with the result of the command.
+    fn execute(
+        &mut self,
+        execute: &str,
+        arguments: Option<Any>,
+        wait_response: bool,
+        callback: GuestAgentCallback,
+    ) -> Result<()>;
+}
+
+/// Channel to guest agent.
+pub type GuestAgent = Arc<Mutex<dyn GuestAgentInterface + Send>>;
+
+/// Callback to complete the command of guest agent with its result.
+pub type GuestAgentCallback = Box<dyn FnOnce(Result<Any>) + Send>;
+
 /// Machine interface which is exposed to inner hypervisor.
 pub trait MachineInterface: MachineLifecycle + MachineAddressInterface {}
 
@@ -426,4 +612,5 @@ pub trait MachineExternalInterface: MachineLifecycle + DeviceInterface + Migrate
 lazy_static! {
     pub static ref PTY_PATH: Arc<Mutex<Vec<PathInfo>>> = Arc::new(Mutex::new(Vec::new()));
     pub static ref IOTHREADS: Arc<Mutex<Vec<IothreadInfo>>> = Arc::new(Mutex::new(Vec::new()));
+    pub static ref BLOCK_DEVICES: Arc<Mutex<Vec<BlockDevice>>> = Arc::new(Mutex::new(Vec::new()));
 }
@@ -46,7 +46,7 @@ use util::leak_bucket::LeakBucket;
 use util::set_termi_canon_mode;
 
 use crate::event_loop::EventLoop;
-use crate::machine::MachineExternalInterface;
+use crate::machine::{guest_agent_execute, MachineExternalInterface};
 use crate::socket::SocketRWHandler;
 use crate::{
     errors::{Result, ResultExt},
@@ -367,8 +367,11 @@ pub fn handle_qmp(
             info!("QMP: <-- {:?}", buffer);
             let qmp_command: schema::QmpCommand = buffer.unwrap();
             let (return_msg, shutdown_flag) = qmp_command_exec(qmp_command, controller, if_fd);
-            info!("QMP: --> {:?}", return_msg);
-            qmp_service.send_str(&return_msg)?;
+            // The response of guest agent command is sent once guest agent responds.
+            if let Some(return_msg) = return_msg {
+                info!("QMP: --> {:?}", return_msg);
+                qmp_service.send_str(&return_msg)?;
+            }
 
             // handle shutdown command
             if shutdown_flag {
@@ -402,10 +405,45 @@ fn qmp_command_exec(
     qmp_command: QmpCommand,
     controller: &Arc<Mutex<dyn MachineExternalInterface>>,
     if_fd: Option<RawFd>,
-) -> (String, bool) {
+) -> (Option<String>, bool) {
     let mut qmp_response = Response::create_empty_response();
     let mut shutdown_flag = false;
 
+    // Commands of guest agent are not blocked by the guest.
+    let guest_agent_cmd = match &qmp_command {
+        QmpCommand::guest_ping { id, .. } => Some(("guest-ping", None, true, id.clone())),
+        QmpCommand::guest_fsfreeze_freeze { id, .. } => {
+            Some(("guest-fsfreeze-freeze", None, true, id.clone()))
+        }
+        QmpCommand::guest_fsfreeze_thaw { id, .. } => {
+            Some(("guest-fsfreeze-thaw", None, true, id.clone()))
+        }
+        QmpCommand::guest_network_get_interfaces { id, .. } => {
+            Some(("guest-network-get-interfaces", None, true, id.clone()))
+        }
+        QmpCommand::guest_shutdown { arguments, id } => {
+            let arguments = arguments
+                .mode
+                .as_ref()
+                .map(|mode| serde_json::json!({ "mode": mode }));
+            // Guest agent does not respond to guest-shutdown if it succeeds.
+            Some(("guest-shutdown", arguments, false, id.clone()))
+        }
+        QmpCommand::guest_set_time { arguments, id } => {
+            let arguments = arguments
+                .time
+                .map(|time| serde_json::json!({ "time": time }));
+            Some(("guest-set-time", arguments, true, id.clone()))
+        }
+        _ => None,
+    };
+    if let Some((execute, arguments, wait_response, id)) = guest_agent_cmd {
+        let agent = controller.lock().unwrap().get_guest_agent();
+        let return_msg = guest_agent_execute(agent, execute, arguments, wait_response, id)
+            .map(|resp| serde_json::to_string(&resp).unwrap() + "\r");
+        return (return_msg, false);
+    }
+
     // Use macro create match to cover most Qmp command
     let mut id = create_command_matches!(
         qmp_command.clone(); controller.lock().unwrap(); qmp_response;
@@ -435,15 +473,17 @@ fn qmp_command_exec(
         (query_migrate, query_migrate),
         (query_cpus, query_cpus),
         (query_balloon, query_balloon),
+        (query_balloon_stats, query_balloon_stats),
+        (query_virtio_mem, query_virtio_mem),
         (list_type, list_type),
         (query_hotpluggable_cpus, query_hotpluggable_cpus);
-        (device_add, device_add, id, driver, addr, lun),
         (device_list_properties, device_list_properties, typename),
         (device_del, device_del, id),
-        (blockdev_add, blockdev_add, node_name, file, cache, read_only),
+        (blockdev_add, blockdev_add, node_name, file, cache, read_only, driver),
         (netdev_add, netdev_add, id, if_name, fds),
         (balloon, balloon, value),
-        (migrate, migrate, uri)
+        (virtio_mem_resize, virtio_mem_resize, id, requested_size),
+        (migrate, migrate, uri, incremental, dirty_log, compress)
     );
 
     // Handle the Qmp command which macro can't cover
@@ -458,6 +498,14 @@ fn qmp_command_exec(
                 qmp_response = controller.lock().unwrap().getfd(arguments.fd_name, if_fd);
                 id
             }
+            QmpCommand::device_add { arguments, id } => {
+                qmp_response = controller.lock().unwrap().device_add(arguments);
+                id
+            }
+            QmpCommand::block_set_io_throttle { arguments, id } => {
+                qmp_response = controller.lock().unwrap().block_set_io_throttle(*arguments);
+                id
+            }
             _ => None,
         }
     }
@@ -465,7 +513,7 @@ fn qmp_command_exec(
     // Change response id with input qmp message
     qmp_response.change_id(id);
     (
-        serde_json::to_string(&qmp_response).unwrap() + "\r",
+        Some(serde_json::to_string(&qmp_response).unwrap() + "\r"),
         shutdown_flag,
     )
 }
@@ -551,6 +599,25 @@ impl QmpChannel {
         }
     }
 
+    /// Send a `Response` to client, which is not sent as soon as the command
+    /// is received.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The `Response` sent to client.
+    #[allow(clippy::unused_io_amount)]
+    pub fn send_response(response: &Response) {
+        if Self::is_connected() {
+            let resp_str = serde_json::to_string(response).unwrap();
+            let mut writer_unlocked = Self::inner().event_writer.write().unwrap();
+            let writer = writer_unlocked.as_mut().unwrap();
+            writer.flush().unwrap();
+            writer.write(resp_str.as_bytes()).unwrap();
+            writer.write(b"\r\n").unwrap();
+            info!("QMP: --> {:?}", response);
+        }
+    }
+
     fn inner() -> &'static std::sync::Arc<QmpChannel> {
         unsafe {
             match &QMP_CHANNEL {
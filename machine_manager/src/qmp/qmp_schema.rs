@@ -139,6 +139,12 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "block_set_io_throttle")]
+    block_set_io_throttle {
+        arguments: Box<block_set_io_throttle>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "blockdev-del")]
     blockdev_del {
         arguments: blockdev_del,
@@ -159,6 +165,29 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-balloon-stats")]
+    #[strum(serialize = "query-balloon-stats")]
+    query_balloon_stats {
+        #[serde(default)]
+        arguments: query_balloon_stats,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "virtio-mem-resize")]
+    #[strum(serialize = "virtio-mem-resize")]
+    virtio_mem_resize {
+        arguments: virtio_mem_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-virtio-mem")]
+    #[strum(serialize = "query-virtio-mem")]
+    query_virtio_mem {
+        #[serde(default)]
+        arguments: query_virtio_mem,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "migrate")]
     migrate {
         arguments: migrate,
@@ -343,6 +372,54 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "guest-ping")]
+    #[strum(serialize = "guest-ping")]
+    guest_ping {
+        #[serde(default)]
+        arguments: guest_ping,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "guest-fsfreeze-freeze")]
+    #[strum(serialize = "guest-fsfreeze-freeze")]
+    guest_fsfreeze_freeze {
+        #[serde(default)]
+        arguments: guest_fsfreeze_freeze,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "guest-fsfreeze-thaw")]
+    #[strum(serialize = "guest-fsfreeze-thaw")]
+    guest_fsfreeze_thaw {
+        #[serde(default)]
+        arguments: guest_fsfreeze_thaw,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "guest-shutdown")]
+    #[strum(serialize = "guest-shutdown")]
+    guest_shutdown {
+        #[serde(default)]
+        arguments: guest_shutdown,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "guest-set-time")]
+    #[strum(serialize = "guest-set-time")]
+    guest_set_time {
+        #[serde(default)]
+        arguments: guest_set_time,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "guest-network-get-interfaces")]
+    #[strum(serialize = "guest-network-get-interfaces")]
+    guest_network_get_interfaces {
+        #[serde(default)]
+        arguments: guest_network_get_interfaces,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
 }
 
 /// qmp_capabilities
@@ -452,6 +529,14 @@ impl Command for cont {
 /// -> { "execute": "device_add",
 ///      "arguments": { "id": "net-0", "driver": "virtio-net-mmio", "addr": "0x0"}}
 /// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "drive-0", "driver": "virtio-blk-pci", "drive": "drive-0",
+///                     "bus": "pcie.1", "addr": "0x0"}}
+/// <- { "return": {} }
+/// -> { "execute": "device_add",
+///      "arguments": { "id": "cpu-2", "driver": "host-x86-cpu", "socket-id": 2,
+///                     "core-id": 0, "thread-id": 0}}
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -482,6 +567,14 @@ pub struct device_add {
     pub mq: Option<String>,
     #[serde(rename = "vectors")]
     pub vectors: Option<String>,
+    #[serde(rename = "host")]
+    pub host: Option<String>,
+    #[serde(rename = "socket-id")]
+    pub socket_id: Option<u8>,
+    #[serde(rename = "core-id")]
+    pub core_id: Option<u8>,
+    #[serde(rename = "thread-id")]
+    pub thread_id: Option<u8>,
 }
 
 impl Command for device_add {
@@ -638,6 +731,65 @@ impl Command for device_del {
     }
 }
 
+/// block_set_io_throttle
+///
+/// Change the IO throttle of a block device, the limits are per second and zero
+/// means unlimited.
+///
+/// # Arguments
+///
+/// * `device` - the drive ID of the block device.
+/// * `bps` - total throughput limit in bytes.
+/// * `bps_rd` - read throughput limit in bytes.
+/// * `bps_wr` - write throughput limit in bytes.
+/// * `iops` - total IO operations limit.
+/// * `iops_rd` - read IO operations limit.
+/// * `iops_wr` - write IO operations limit.
+/// * `bps_max` - total throughput burst in bytes (optional).
+/// * `bps_rd_max` - read throughput burst in bytes (optional).
+/// * `bps_wr_max` - write throughput burst in bytes (optional).
+/// * `iops_max` - total IO operations burst (optional).
+/// * `iops_rd_max` - read IO operations burst (optional).
+/// * `iops_wr_max` - write IO operations burst (optional).
+///
+/// # Errors
+///
+/// If the drive is not found, DeviceNotFound.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_set_io_throttle",
+///      "arguments": { "device": "drive-0", "bps": 0, "bps_rd": 0, "bps_wr": 0,
+///                     "iops": 200, "iops_rd": 0, "iops_wr": 0, "iops_max": 400 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_set_io_throttle {
+    pub device: String,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    pub bps_max: Option<u64>,
+    pub bps_rd_max: Option<u64>,
+    pub bps_wr_max: Option<u64>,
+    pub iops_max: Option<u64>,
+    pub iops_rd_max: Option<u64>,
+    pub iops_wr_max: Option<u64>,
+}
+
+impl Command for block_set_io_throttle {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct blockdev_del {
@@ -929,10 +1081,23 @@ impl Default for RunState {
 /// # Arguments
 ///
 /// * `uri` - the Uniform Resource Identifier of the destination VM or file.
+/// * `incremental` - only save the memory dirtied since the latest snapshot,
+///   valid for file uri only.
+/// * `dirty_log` - log dirty memory after the snapshot, so that the next snapshot
+///   can be incremental, valid for file uri only.
+/// * `compress` - compression algorithm of snapshot memory, "none", "lz4" or
+///   "zstd", valid for file uri only.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct migrate {
     #[serde(rename = "uri")]
     pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incremental: Option<bool>,
+    #[serde(rename = "dirty-log")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirty_log: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress: Option<String>,
 }
 
 impl Command for migrate {
@@ -961,6 +1126,52 @@ impl Command for query_migrate {
 pub struct MigrationInfo {
     #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    /// Total time of live migration in milliseconds.
+    #[serde(
+        rename = "total-time",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_time: Option<u64>,
+    /// Downtime of VM during live migration in milliseconds.
+    #[serde(rename = "downtime", default, skip_serializing_if = "Option::is_none")]
+    pub downtime: Option<u64>,
+    /// Statistics of guest memory transferred by live migration.
+    #[serde(rename = "ram", default, skip_serializing_if = "Option::is_none")]
+    pub ram: Option<MigrationStats>,
+    /// Statistics of guest memory restored lazily from snapshot.
+    #[serde(
+        rename = "lazy-restore",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub lazy_restore: Option<LazyRestoreStats>,
+}
+
+/// Statistics of guest memory transferred by live migration, sizes are in bytes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationStats {
+    #[serde(rename = "transferred")]
+    pub transferred: u64,
+    #[serde(rename = "remaining")]
+    pub remaining: u64,
+    #[serde(rename = "total")]
+    pub total: u64,
+    #[serde(rename = "dirty-sync-count")]
+    pub dirty_sync_count: u64,
+}
+
+/// Statistics of guest memory restored lazily from snapshot, sizes are in pages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LazyRestoreStats {
+    #[serde(rename = "total-pages")]
+    pub total_pages: u64,
+    #[serde(rename = "missing-pages")]
+    pub missing_pages: u64,
+    #[serde(rename = "prefetched-pages")]
+    pub prefetched_pages: u64,
+    #[serde(rename = "remaining-pages")]
+    pub remaining_pages: u64,
 }
 
 /// getfd
@@ -1096,6 +1307,16 @@ pub enum QmpEvent {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "BALLOON_POLICY")]
+    BalloonPolicy {
+        data: BalloonPolicyInfo,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "MEMORY_DEVICE_SIZE_CHANGE")]
+    MemoryDeviceSizeChange {
+        data: MemoryDeviceSizeChange,
+        timestamp: TimeStamp,
+    },
 }
 
 /// query-balloon:
@@ -1126,6 +1347,88 @@ pub struct BalloonInfo {
     pub actual: u64,
 }
 
+/// Decision made by the automatic balloon policy.
+///
+/// # Example
+///
+/// ```text
+/// <- { "event": "BALLOON_POLICY",
+///      "data": { "action": "inflate", "target": 1946157056, "actual": 2080374784,
+///                "pressure": 12.5 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BalloonPolicyInfo {
+    /// "inflate" or "deflate".
+    pub action: String,
+    /// New target memory size of guest.
+    pub target: u64,
+    /// Actual memory size of guest when the decision is made.
+    pub actual: u64,
+    /// Percentage of time in the last 10 seconds that host tasks stalled on memory.
+    pub pressure: f64,
+}
+
+/// query-balloon-stats:
+///
+/// Query the memory statistics of guest reported by balloon device, which is
+/// the same as the `guest-stats` property got by `qom-get` in QEMU.
+///
+/// # Returns
+///
+/// `BalloonStats` includes the latest statistics and the time they are updated,
+/// statistics not reported by guest are zero.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-balloon-stats" }
+/// <- {"return":{"stats":{"stat-swap-in":0,"stat-swap-out":0,"stat-major-faults":254,
+///     "stat-minor-faults":53217,"stat-free-memory":1800912896,"stat-total-memory":2083479552,
+///     "stat-available-memory":1852100608,"stat-disk-caches":96337920,"stat-htlb-pgalloc":0,
+///     "stat-htlb-pgfail":0},"last-update":1618905600}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_balloon_stats {}
+impl Command for query_balloon_stats {
+    type Res = BalloonStats;
+    fn back(self) -> BalloonStats {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct GuestMemoryStats {
+    #[serde(rename = "stat-swap-in")]
+    pub swap_in: u64,
+    #[serde(rename = "stat-swap-out")]
+    pub swap_out: u64,
+    #[serde(rename = "stat-major-faults")]
+    pub major_faults: u64,
+    #[serde(rename = "stat-minor-faults")]
+    pub minor_faults: u64,
+    #[serde(rename = "stat-free-memory")]
+    pub free_memory: u64,
+    #[serde(rename = "stat-total-memory")]
+    pub total_memory: u64,
+    #[serde(rename = "stat-available-memory")]
+    pub available_memory: u64,
+    #[serde(rename = "stat-disk-caches")]
+    pub disk_caches: u64,
+    #[serde(rename = "stat-htlb-pgalloc")]
+    pub htlb_pgalloc: u64,
+    #[serde(rename = "stat-htlb-pgfail")]
+    pub htlb_pgfail: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BalloonStats {
+    pub stats: GuestMemoryStats,
+    /// Seconds since the Epoch when the statistics are updated, 0 if never.
+    #[serde(rename = "last-update")]
+    pub last_update: u64,
+}
+
 /// balloon:
 ///
 /// Advice VM to change memory size with the argument `value`.
@@ -1158,6 +1461,93 @@ impl Command for balloon {
     }
 }
 
+/// virtio-mem-resize:
+///
+/// Set the size of memory which guest is requested to plug by virtio-mem device.
+///
+/// # Arguments
+///
+/// * `id` - The id of virtio-mem device.
+/// * `requested-size` - Memory size, aligned with the block size of device.
+///
+/// # Notes
+///
+/// Guest plugs or unplugs memory blocks asynchronously, the actual size is
+/// reported by `MEMORY_DEVICE_SIZE_CHANGE` event.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "virtio-mem-resize", "arguments": { "id": "mem0", "requested-size": 1073741824 } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct virtio_mem_resize {
+    pub id: String,
+    #[serde(rename = "requested-size")]
+    pub requested_size: u64,
+}
+
+impl Command for virtio_mem_resize {
+    type Res = Empty;
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-virtio-mem:
+///
+/// Query the memory region and plugged size of virtio-mem devices.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-virtio-mem" }
+/// <- {"return":[{"id":"mem0","addr":68719476736,"region-size":4294967296,
+///     "block-size":2097152,"plugged-size":1073741824,"requested-size":1073741824}]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_virtio_mem {}
+
+impl Command for query_virtio_mem {
+    type Res = Vec<VirtioMemInfo>;
+    fn back(self) -> Vec<VirtioMemInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VirtioMemInfo {
+    pub id: String,
+    /// Guest physical address of the memory region.
+    pub addr: u64,
+    #[serde(rename = "region-size")]
+    pub region_size: u64,
+    #[serde(rename = "block-size")]
+    pub block_size: u64,
+    #[serde(rename = "plugged-size")]
+    pub plugged_size: u64,
+    #[serde(rename = "requested-size")]
+    pub requested_size: u64,
+}
+
+/// Emitted when the size of memory plugged by guest through memory device changes.
+///
+/// # Example
+///
+/// ```text
+/// <- { "event": "MEMORY_DEVICE_SIZE_CHANGE",
+///      "data": { "id": "mem0", "size": 1073741824 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryDeviceSizeChange {
+    /// Device id.
+    pub id: String,
+    /// Plugged memory size.
+    pub size: u64,
+}
+
 /// version:
 ///
 /// Query version of StratoVirt.
@@ -1617,34 +2007,90 @@ impl Command for qom_get {
 ///
 /// ```text
 /// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","type":"unknown","removable":false,"locked":false,
+///      "inserted":{"file":"/path/to/rootfs","node-name":"drive-0","ro":false,"drv":"raw",
+///      "encrypted":false,"detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,"iops":0,
+///      "iops_rd":0,"iops_wr":0,"cache":{"writeback":true,"direct":true,"no-flush":false}}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block {}
 
 impl Command for query_block {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub device: String,
+    #[serde(rename = "type")]
+    pub block_type: String,
+    pub removable: bool,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub file: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub ro: bool,
+    pub drv: String,
+    pub encrypted: bool,
+    pub detect_zeroes: String,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_wr_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_wr_max: Option<u64>,
+    pub cache: BlockdevCacheInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockdevCacheInfo {
+    pub writeback: bool,
+    pub direct: bool,
+    #[serde(rename = "no-flush")]
+    pub no_flush: bool,
+}
+
 /// Query named block node.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-named-block-nodes" }
-/// <- {"return":[]}
+/// <- {"return":[{"file":"/path/to/rootfs","node-name":"drive-0","ro":false,"drv":"raw",
+///      "encrypted":false,"detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,"iops":0,
+///      "iops_rd":0,"iops_wr":0,"cache":{"writeback":true,"direct":true,"no-flush":false}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_named_block_nodes {}
 
 impl Command for query_named_block_nodes {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockDeviceInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockDeviceInfo> {
         Default::default()
     }
 }
@@ -1655,19 +2101,51 @@ impl Command for query_named_block_nodes {
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","node-name":"drive-0","stats":{"rd_bytes":4096,
+///      "wr_bytes":0,"unmap_bytes":0,"rd_operations":1,"wr_operations":0,
+///      "flush_operations":0,"unmap_operations":0,"rd_total_time_ns":120000,
+///      "wr_total_time_ns":0,"flush_total_time_ns":0,"unmap_total_time_ns":0,
+///      "failed_rd_operations":0,"failed_wr_operations":0,"failed_flush_operations":0,
+///      "failed_unmap_operations":0}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStats>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStats> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub device: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub unmap_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub unmap_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub unmap_total_time_ns: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+    pub failed_unmap_operations: u64,
+}
+
 /// Query capabilities of gic.
 ///
 /// # Example
@@ -1726,6 +2204,204 @@ impl Command for query_iothreads {
     }
 }
 
+/// guest-ping
+///
+/// Ping the guest agent, a non-error return implies success.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-ping" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_ping {}
+
+impl Command for guest_ping {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// guest-fsfreeze-freeze
+///
+/// Sync and freeze all freezable and local guest filesystems.
+///
+/// # Returns
+///
+/// Number of filesystems frozen.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-fsfreeze-freeze" }
+/// <- { "return": 2 }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_fsfreeze_freeze {}
+
+impl Command for guest_fsfreeze_freeze {
+    type Res = u64;
+
+    fn back(self) -> u64 {
+        Default::default()
+    }
+}
+
+/// guest-fsfreeze-thaw
+///
+/// Unfreeze all frozen guest filesystems.
+///
+/// # Returns
+///
+/// Number of filesystems thawed.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-fsfreeze-thaw" }
+/// <- { "return": 2 }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_fsfreeze_thaw {}
+
+impl Command for guest_fsfreeze_thaw {
+    type Res = u64;
+
+    fn back(self) -> u64 {
+        Default::default()
+    }
+}
+
+/// guest-shutdown
+///
+/// Initiate guest-activated shutdown, the guest agent gives no response
+/// once the shutdown has been initiated.
+///
+/// # Arguments
+///
+/// * `mode` - "halt", "powerdown" (default), or "reboot".
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-shutdown", "arguments": { "mode": "reboot" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_shutdown {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+}
+
+impl Command for guest_shutdown {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// guest-set-time
+///
+/// Set guest time.
+///
+/// # Arguments
+///
+/// * `time` - Time of nanoseconds, relative to the Epoch of 1970-01-01 in UTC.
+///   If omitted, the guest sets its system time from the RTC.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-set-time", "arguments": { "time": 1618905600000000000 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_set_time {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>,
+}
+
+impl Command for guest_set_time {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// guest-network-get-interfaces
+///
+/// Get list of guest IP addresses, MAC addresses and netmasks.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "guest-network-get-interfaces" }
+/// <- { "return": [{"name":"lo","hardware-address":"00:00:00:00:00:00",
+///                  "ip-addresses":[{"ip-address-type":"ipv4","ip-address":"127.0.0.1",
+///                  "prefix":8}]}] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct guest_network_get_interfaces {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct GuestIpAddress {
+    #[serde(rename = "ip-address")]
+    pub ip_address: String,
+    #[serde(rename = "ip-address-type")]
+    pub ip_address_type: String,
+    pub prefix: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct GuestNetworkInterfaceStat {
+    #[serde(rename = "rx-bytes")]
+    pub rx_bytes: u64,
+    #[serde(rename = "rx-packets")]
+    pub rx_packets: u64,
+    #[serde(rename = "rx-errs")]
+    pub rx_errs: u64,
+    #[serde(rename = "rx-dropped")]
+    pub rx_dropped: u64,
+    #[serde(rename = "tx-bytes")]
+    pub tx_bytes: u64,
+    #[serde(rename = "tx-packets")]
+    pub tx_packets: u64,
+    #[serde(rename = "tx-errs")]
+    pub tx_errs: u64,
+    #[serde(rename = "tx-dropped")]
+    pub tx_dropped: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct GuestNetworkInterface {
+    pub name: String,
+    #[serde(rename = "hardware-address", skip_serializing_if = "Option::is_none")]
+    pub hardware_address: Option<String>,
+    #[serde(rename = "ip-addresses", skip_serializing_if = "Option::is_none")]
+    pub ip_addresses: Option<Vec<GuestIpAddress>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<GuestNetworkInterfaceStat>,
+}
+
+impl Command for guest_network_get_interfaces {
+    type Res = Vec<GuestNetworkInterface>;
+
+    fn back(self) -> Vec<GuestNetworkInterface> {
+        Default::default()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate serde;
@@ -2163,5 +2839,73 @@ mod tests {
         };
         let part_msg = r#"ok"#;
         assert!(err_msg.contains(part_msg));
+
+        // guest-shutdown
+        let json_msg = r#"
+        { 
+            "execute": "guest-shutdown",
+            "arguments": {
+                "mode": "reboot"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // guest-set-time with wrong type of time.
+        let json_msg = r#"
+        { 
+            "execute": "guest-set-time",
+            "arguments": {
+                "time": "now"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"invalid type: string "now", expected i64"#;
+        assert!(err_msg.contains(part_msg));
+
+        // blockdev-del
+        let json_msg = r#"
+        {
+            "execute": "blockdev-del",
+            "arguments": {
+                "node-name": "drive-0"
+            }
+        }
+        "#;
+        match serde_json::from_str::<QmpCommand>(json_msg).unwrap() {
+            QmpCommand::blockdev_del { arguments, .. } => {
+                assert_eq!(arguments.node_name, "drive-0")
+            }
+            _ => panic!("blockdev-del is parsed as other command"),
+        }
+
+        // block_set_io_throttle
+        let json_msg = r#"
+        {
+            "execute": "block_set_io_throttle",
+            "arguments": {
+                "device": "drive-0",
+                "bps": 1000, "bps_rd": 0, "bps_wr": 0,
+                "iops": 200, "iops_rd": 0, "iops_wr": 0
+            }
+        }
+        "#;
+        match serde_json::from_str::<QmpCommand>(json_msg).unwrap() {
+            QmpCommand::block_set_io_throttle { arguments, .. } => {
+                assert_eq!(arguments.device, "drive-0");
+                assert_eq!(arguments.bps, 1000);
+                assert_eq!(arguments.iops, 200);
+            }
+            _ => panic!("block_set_io_throttle is parsed as other command"),
+        }
     }
 }
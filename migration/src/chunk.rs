@@ -0,0 +1,373 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use util::byte_code::ByteCode;
+use util::checksum::crc32;
+use util::unix::host_page_size;
+
+use crate::errors::{ErrorKind, Result, ResultExt};
+use crate::manager::MigrationHook;
+
+/// The max length of memory data in one chunk.
+const CHUNK_SIZE: u64 = 0x4_0000;
+/// Chunk format of all-zero memory data, no data is stored.
+const CHUNK_ZERO: u32 = 0;
+
+/// Compression algorithm of memory data in snapshot.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    /// Memory data is stored as it is.
+    None = 1,
+    /// Memory data is compressed with lz4 block format.
+    Lz4 = 2,
+    /// Memory data is compressed with zstd.
+    Zstd = 3,
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Compression {
+    fn from_format(format: u32) -> Option<Self> {
+        match format {
+            1 => Some(Compression::None),
+            2 => Some(Compression::Lz4),
+            3 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .chain_err(|| "Failed to compress memory data with zstd"),
+        }
+    }
+
+    /// Decompress `data` to `buf`, returns `false` if `data` is invalid or the
+    /// length of decompressed data isn't the length of `buf`.
+    fn decompress(self, data: &[u8], buf: &mut [u8]) -> bool {
+        let len = match self {
+            Compression::None => {
+                if data.len() != buf.len() {
+                    return false;
+                }
+                buf.copy_from_slice(data);
+                data.len()
+            }
+            Compression::Lz4 => match lz4_flex::block::decompress_into(data, buf) {
+                Ok(len) => len,
+                Err(_) => return false,
+            },
+            Compression::Zstd => match zstd::bulk::decompress_to_buffer(data, buf) {
+                Ok(len) => len,
+                Err(_) => return false,
+            },
+        };
+        len == buf.len()
+    }
+}
+
+/// Header of memory data chunk, followed by `data_len` bytes stored data.
+/// The header with zero length marks the end of chunks.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct ChunkHeader {
+    /// Guest address of memory data.
+    addr: u64,
+    /// Length of memory data.
+    len: u64,
+    /// Length of stored data following the header.
+    data_len: u64,
+    /// `CHUNK_ZERO` or the `Compression` of stored data.
+    format: u32,
+    /// CRC32 of the header with zero checksum and stored data.
+    checksum: u32,
+}
+
+impl ByteCode for ChunkHeader {}
+
+impl ChunkHeader {
+    fn calc_checksum(&self, data: &[u8]) -> u32 {
+        let mut header = *self;
+        header.checksum = 0;
+        crc32(crc32(0, header.as_bytes()), data)
+    }
+}
+
+fn write_chunk(writer: &mut dyn Write, mut header: ChunkHeader, data: &[u8]) -> Result<()> {
+    header.data_len = data.len() as u64;
+    header.checksum = header.calc_checksum(data);
+    writer.write_all(header.as_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+fn read_exact(reader: &mut dyn Read, buf: &mut [u8], item: &dyn Fn() -> String) -> Result<()> {
+    if let Err(e) = reader.read_exact(buf) {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Err(ErrorKind::SnapshotTruncated(item()).into());
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Save memory data in ranges as chunks, all-zero pages are skipped or saved
+/// as chunks without data. The chunks end with a header with zero length.
+///
+/// # Arguments
+///
+/// * `writer` - The `Write` trait object.
+/// * `memory` - The memory instance.
+/// * `ranges` - Memory ranges to be saved as (address, length).
+/// * `compression` - Compression algorithm of memory data.
+/// * `save_zero` - Save all-zero pages as chunks rather than skip them, which
+///                 is required if memory isn't zero before loading chunks.
+pub(crate) fn save_memory_chunks(
+    writer: &mut dyn Write,
+    memory: &Arc<dyn MigrationHook + Send + Sync>,
+    ranges: &[(u64, u64)],
+    compression: Compression,
+    save_zero: bool,
+) -> Result<()> {
+    let page_size = host_page_size() as usize;
+    let mut buf = Vec::with_capacity(CHUNK_SIZE as usize);
+    for (addr, len) in ranges {
+        let end = addr + len;
+        let mut chunk_addr = *addr;
+        while chunk_addr < end {
+            let chunk_len = std::cmp::min(CHUNK_SIZE, end - chunk_addr);
+            buf.clear();
+            memory.save_memory_range(&mut buf, chunk_addr, chunk_len)?;
+
+            // Split chunk into runs of all-zero pages and other pages.
+            let mut start = 0;
+            while start < buf.len() {
+                let page_end = std::cmp::min(start + page_size, buf.len());
+                let is_zero = buf[start..page_end].iter().all(|b| *b == 0);
+                let run_len: usize = buf[start..]
+                    .chunks(page_size)
+                    .take_while(|page| page.iter().all(|b| *b == 0) == is_zero)
+                    .map(|page| page.len())
+                    .sum();
+                let header = ChunkHeader {
+                    addr: chunk_addr + start as u64,
+                    len: run_len as u64,
+                    ..Default::default()
+                };
+                let data = &buf[start..start + run_len];
+                start += run_len;
+
+                if is_zero {
+                    if save_zero {
+                        write_chunk(
+                            writer,
+                            ChunkHeader {
+                                format: CHUNK_ZERO,
+                                ..header
+                            },
+                            &[],
+                        )?;
+                    }
+                    continue;
+                }
+                let compressed = compression.compress(data)?;
+                // Store data as it is if it can't be compressed.
+                if compressed.len() < data.len() {
+                    let format = compression as u32;
+                    write_chunk(writer, ChunkHeader { format, ..header }, &compressed)?;
+                } else {
+                    let format = Compression::None as u32;
+                    write_chunk(writer, ChunkHeader { format, ..header }, data)?;
+                }
+            }
+            chunk_addr += chunk_len;
+        }
+    }
+    write_chunk(writer, ChunkHeader::default(), &[])
+}
+
+/// Load memory data from chunks saved by `save_memory_chunks`, every chunk is
+/// verified with its checksum before loaded into memory.
+///
+/// # Arguments
+///
+/// * `reader` - The `Read` trait object.
+/// * `memory` - The memory instance.
+pub(crate) fn load_memory_chunks(
+    reader: &mut dyn Read,
+    memory: &Arc<dyn MigrationHook + Send + Sync>,
+) -> Result<()> {
+    let mut data = Vec::with_capacity(CHUNK_SIZE as usize);
+    let mut buf = Vec::with_capacity(CHUNK_SIZE as usize);
+    let mut header = ChunkHeader::default();
+    for index in 0_u64.. {
+        read_exact(reader, header.as_mut_bytes(), &|| {
+            format!("header of memory chunk {}", index)
+        })?;
+        let item = || format!("memory chunk {} at 0x{:X}", index, header.addr);
+        // Stored data is never longer than memory data, check it before reading.
+        if header.len > CHUNK_SIZE || header.data_len > header.len {
+            return Err(ErrorKind::ChecksumMismatch(item()).into());
+        }
+        data.resize(header.data_len as usize, 0);
+        read_exact(reader, &mut data, &item)?;
+        if header.calc_checksum(&data) != header.checksum {
+            return Err(ErrorKind::ChecksumMismatch(item()).into());
+        }
+        if header.len == 0 {
+            break;
+        }
+
+        if header.format == CHUNK_ZERO {
+            if header.data_len != 0 {
+                bail!("Invalid all-zero {}", item());
+            }
+            memory.load_memory_range(
+                &mut std::io::repeat(0).take(header.len),
+                header.addr,
+                header.len,
+            )?;
+            continue;
+        }
+        let compression = Compression::from_format(header.format)
+            .chain_err(|| format!("Invalid format {} of {}", header.format, item()))?;
+        buf.resize(header.len as usize, 0);
+        if !compression.decompress(&data, &mut buf) {
+            bail!("Failed to decompress {} with {:?}", item(), compression);
+        }
+        memory.load_memory_range(&mut buf.as_slice(), header.addr, header.len)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::migration::tests::TestMemory;
+
+    fn test_memory(data: Vec<u8>) -> Arc<dyn MigrationHook + Send + Sync> {
+        Arc::new(TestMemory {
+            data: Mutex::new(data),
+            dirty: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn memory_data(memory: &Arc<dyn MigrationHook + Send + Sync>, len: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        memory.save_memory_range(&mut data, 0, len).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_memory_chunks() {
+        let page_size = host_page_size();
+        let len = page_size * 4;
+        // The second page is all-zero, others are compressible.
+        let mut data: Vec<u8> = (0..len).map(|i| (i % 7) as u8 + 1).collect();
+        data[page_size as usize..(page_size * 2) as usize].fill(0);
+        let src = test_memory(data.clone());
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd].iter() {
+            // Destination memory isn't zero, zero pages must be saved.
+            let dst = test_memory(vec![0xFF; len as usize]);
+            let mut chunks = Vec::new();
+            save_memory_chunks(&mut chunks, &src, &[(0, len)], *compression, true).unwrap();
+            load_memory_chunks(&mut chunks.as_slice(), &dst).unwrap();
+            assert_eq!(memory_data(&dst, len), data);
+
+            let mut skipped = Vec::new();
+            save_memory_chunks(&mut skipped, &src, &[(0, len)], *compression, false).unwrap();
+            assert_eq!(skipped.len(), chunks.len() - size_of::<ChunkHeader>());
+            if *compression != Compression::None {
+                assert!(skipped.len() < len as usize);
+            }
+            let dst = test_memory(vec![0; len as usize]);
+            load_memory_chunks(&mut skipped.as_slice(), &dst).unwrap();
+            assert_eq!(memory_data(&dst, len), data);
+        }
+    }
+
+    #[test]
+    fn test_memory_chunks_corrupted() {
+        let page_size = host_page_size();
+        let src = test_memory((0..page_size).map(|i| i as u8).collect());
+        let dst = test_memory(vec![0; page_size as usize]);
+        let mut chunks = Vec::new();
+        save_memory_chunks(
+            &mut chunks,
+            &src,
+            &[(0, page_size)],
+            Compression::None,
+            false,
+        )
+        .unwrap();
+
+        // Corrupted memory data.
+        let mut corrupted = chunks.clone();
+        corrupted[size_of::<ChunkHeader>() + 1] ^= 1;
+        let err = load_memory_chunks(&mut corrupted.as_slice(), &dst).unwrap_err();
+        match err.kind() {
+            ErrorKind::ChecksumMismatch(item) => assert_eq!(item, "memory chunk 0 at 0x0"),
+            _ => panic!("Unexpected error {}", err),
+        }
+        // Corrupted chunk header.
+        let mut corrupted = chunks.clone();
+        corrupted[0] ^= 1;
+        let err = load_memory_chunks(&mut corrupted.as_slice(), &dst).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ChecksumMismatch(_)));
+        // Truncated memory data.
+        let err = load_memory_chunks(&mut &chunks[..100], &dst).unwrap_err();
+        match err.kind() {
+            ErrorKind::SnapshotTruncated(item) => assert_eq!(item, "memory chunk 0 at 0x0"),
+            _ => panic!("Unexpected error {}", err),
+        }
+        // No data is loaded into memory.
+        assert!(memory_data(&dst, page_size).iter().all(|b| *b == 0));
+        // Missing end of chunks.
+        let len = chunks.len() - size_of::<ChunkHeader>();
+        let err = load_memory_chunks(&mut &chunks[..len], &dst).unwrap_err();
+        match err.kind() {
+            ErrorKind::SnapshotTruncated(item) => {
+                assert_eq!(item, "header of memory chunk 1")
+            }
+            _ => panic!("Unexpected error {}", err),
+        }
+
+        load_memory_chunks(&mut chunks.as_slice(), &dst).unwrap();
+        assert_eq!(
+            memory_data(&dst, page_size),
+            (0..page_size).map(|i| i as u8).collect::<Vec<u8>>()
+        );
+    }
+}
@@ -20,8 +20,10 @@ use super::errors::{ErrorKind, Result};
 const MAGIC_NUMBER: [u8; 16] = [
     0x53, 0x54, 0x52, 0x41, 0x54, 0x4f, 0x56, 0x49, 0x52, 0x54, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
 ];
-const CURRENT_VERSION: u32 = 1;
-const COMPAT_VERSION: u32 = 1;
+/// Version 2 adds `data_len` and `checksum` to `MigrationHeader` and the
+/// memory file formats following `MemoryMapped`.
+const CURRENT_VERSION: u32 = 2;
+const COMPAT_VERSION: u32 = 2;
 #[cfg(target_arch = "x86_64")]
 const EAX_VENDOR_INFO: u32 = 0x0;
 
@@ -29,8 +31,17 @@ const EAX_VENDOR_INFO: u32 = 0x0;
 /// Different file format will have different file layout.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FileFormat {
+    /// Descriptors and states of devices.
     Device = 1,
+    /// Memory state and data, data is aligned with the page size of host.
     MemoryFull = 2,
+    /// Memory state and data, data is aligned with `MEMORY_MAPPED_ALIGN`, so
+    /// that it can be mapped directly as guest memory.
+    MemoryMapped = 3,
+    /// Parent snapshot path and memory data dirtied since the parent snapshot.
+    MemoryDiff = 4,
+    /// Memory state and memory data in checksummed chunks, which may be compressed.
+    MemoryChunked = 5,
 }
 
 /// The endianness of byte order.
@@ -100,6 +111,10 @@ pub struct MigrationHeader {
     pub format: FileFormat,
     /// The length of `DeviceStateDesc`.
     pub desc_len: usize,
+    /// The length of data following the header verified by `checksum`.
+    pub data_len: u64,
+    /// CRC32 of `data_len` bytes data following the header.
+    pub checksum: u32,
 }
 
 impl ByteCode for MigrationHeader {}
@@ -123,6 +138,8 @@ impl Default for MigrationHeader {
             #[cfg(target_arch = "aarch64")]
             arch: [b'a', b'a', b'r', b'c', b'h', b'6', b'4', b'0'],
             desc_len: 0,
+            data_len: 0,
+            checksum: 0,
         }
     }
 }
@@ -138,6 +155,10 @@ impl MigrationHeader {
             return Err(ErrorKind::VersionNotFit(self.compat_version, CURRENT_VERSION).into());
         }
 
+        if self.current_version < COMPAT_VERSION {
+            return Err(ErrorKind::VersionNotFit(COMPAT_VERSION, self.current_version).into());
+        }
+
         #[cfg(target_arch = "x86_64")]
         let current_arch = [b'x', b'8', b'6', b'_', b'6', b'4', b'0', b'0'];
         #[cfg(target_arch = "aarch64")]
@@ -180,7 +201,12 @@ mod tests {
             return;
         }
 
-        let header = MigrationHeader::default();
+        let mut header = MigrationHeader::default();
         assert_eq!(header.check_header().is_ok(), true);
+
+        // Files written before checksums were added can't be parsed.
+        header.current_version = 1;
+        header.compat_version = 1;
+        assert_eq!(header.check_header().is_ok(), false);
     }
 }
@@ -18,18 +18,25 @@
 extern crate error_chain;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 #[cfg(test)]
 #[macro_use]
 extern crate migration_derive;
 
+mod chunk;
 mod device_state;
 mod header;
 mod manager;
+mod migration;
 mod snapshot;
 mod status;
 
+pub use chunk::Compression;
 pub use device_state::{DeviceStateDesc, FieldDesc, StateTransfer};
 pub use manager::{MigrationHook, MigrationManager};
+pub use migration::{IncomingDoneHook, MigrationStats, PauseVmHook, ResumeVmHook};
+pub use snapshot::{LazyRestoreStats, MEMORY_MAPPED_ALIGN};
 pub use status::MigrationStatus;
 
 pub mod errors {
@@ -69,6 +76,15 @@ pub mod errors {
             InvalidSnapshotPath {
                 display("Invalid snapshot path for restoring snapshot")
             }
+            ChecksumMismatch(item: String) {
+                display("Checksum mismatch of {}, snapshot file is corrupted", item)
+            }
+            SnapshotTruncated(item: String) {
+                display("Snapshot file is truncated at {}", item)
+            }
+            MigrationBlocked(id: String, reason: String) {
+                display("Migration is blocked by device {}: {}", id, reason)
+            }
         }
     }
 }
@@ -13,10 +13,13 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
 
 use super::device_state::{DeviceStateDesc, StateTransfer};
-use super::errors::{Result, ResultExt};
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::migration::MigrationStats;
+use super::snapshot::LazyRestoreStats;
 use super::status::MigrationStatus;
 use util::byte_code::ByteCode;
 
@@ -26,6 +29,10 @@ lazy_static! {
         entry: Arc::new(RwLock::new(BTreeMap::<u64, MigrationEntry>::new())),
         desc_db: Arc::new(RwLock::new(HashMap::<String, DeviceStateDesc>::new())),
         status: Arc::new(RwLock::new(MigrationStatus::None)),
+        stats: Arc::new(RwLock::new(None)),
+        lazy_stats: Arc::new(RwLock::new(None)),
+        snapshot_parent: Arc::new(RwLock::new(None)),
+        blockers: Arc::new(RwLock::new(BTreeMap::<String, MigrationBlocker>::new())),
     });
 }
 
@@ -76,6 +83,24 @@ pub trait MigrationHook: StateTransfer {
         self.set_state(state)
     }
 
+    /// Pre load memory state from `[u8]` and restore memory data from `memory`
+    /// file lazily, only for memory instance. Memory data is loaded when guest
+    /// accesses it or by the background prefetcher.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The raw data which can be recovered to memory state.
+    /// * `memory` - The file of memory data.
+    /// * `stats` - The statistics of lazy restore to be updated.
+    fn pre_load_lazy(
+        &self,
+        _state: &[u8],
+        _memory: &File,
+        _stats: Arc<LazyRestoreStats>,
+    ) -> Result<()> {
+        bail!("Lazy restore is not supported")
+    }
+
     /// Pre load device state from `[u8]` to mutable `Device`.
     ///
     /// # Arguments
@@ -95,6 +120,63 @@ pub trait MigrationHook: StateTransfer {
     fn resume(&mut self) -> Result<()> {
         Ok(())
     }
+
+    /// Stop the device from processing requests before its state is saved in
+    /// the stop-and-copy phase of live migration, the requests in flight are
+    /// completed before it returns.
+    fn quiesce(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Restart the device stopped by `quiesce`, called when live migration fails.
+    fn unquiesce(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Start to log dirty pages of memory, only for memory instance.
+    fn start_dirty_log(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stop logging dirty pages of memory, only for memory instance.
+    fn stop_dirty_log(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get the memory ranges to be migrated as (address, length), only for
+    /// memory instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `dirty` - Only return the ranges dirtied since last call if true,
+    ///             otherwise return all ranges of memory.
+    fn get_memory_ranges(&self, _dirty: bool) -> Result<Vec<(u64, u64)>> {
+        Ok(Vec::new())
+    }
+
+    /// Save memory data in the range to `Write` trait object, only for memory
+    /// instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The `Write` trait object to store data.
+    /// * `addr` - Start address of the range.
+    /// * `len` - Length of the range.
+    fn save_memory_range(&self, _writer: &mut dyn Write, _addr: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Load memory data in the range from `Read` trait object, only for memory
+    /// instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The `Read` trait object to receive data.
+    /// * `addr` - Start address of the range.
+    /// * `len` - Length of the range.
+    fn load_memory_range(&self, _reader: &mut dyn Read, _addr: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// The instance id to represent a single object in VM.
@@ -132,6 +214,54 @@ pub enum MigrationEntry {
     Memory(Arc<dyn MigrationHook + Send + Sync>),
 }
 
+impl MigrationEntry {
+    /// Get the address of the instance, which identifies the registered instance.
+    fn as_ptr(&self) -> *const u8 {
+        match self {
+            MigrationEntry::Safe(i) | MigrationEntry::Memory(i) => Arc::as_ptr(i) as *const u8,
+            MigrationEntry::Mutex(i) => Arc::as_ptr(i) as *const u8,
+        }
+    }
+}
+
+/// Remove the instance at `ptr` from entry map, and renumber the entries which
+/// are registered after it as if it had never been registered.
+///
+/// # Notes
+///
+/// The instance id is the size of entry map when the instance is registered,
+/// it's counted down from `!0` for the instance registered in the reverse order.
+fn remove_entry(entry: &mut BTreeMap<u64, MigrationEntry>, ptr: *const u8) -> bool {
+    let order = |id: u64| if id >> 63 == 0 { id } else { !id };
+    let removed = match entry.iter().find(|(_, e)| e.as_ptr() == ptr) {
+        Some((id, _)) => *id,
+        None => return false,
+    };
+    entry.remove(&removed);
+
+    let old_entry = std::mem::take(entry);
+    for (id, e) in old_entry.into_iter() {
+        let new_id = if order(id) < order(removed) {
+            id
+        } else if id >> 63 == 0 {
+            id - 1
+        } else {
+            id + 1
+        };
+        entry.insert(new_id, e);
+    }
+    true
+}
+
+/// The reason why VM can't be migrated, registered by the device which doesn't
+/// support migration.
+struct MigrationBlocker {
+    /// Why the device blocks migration.
+    reason: String,
+    /// If snapshot is blocked as well as live migration.
+    block_snapshot: bool,
+}
+
 /// This structure is to manage all resource during migration.
 /// It is also the only way to call on `MIGRATION_MANAGER`.
 pub struct MigrationManager {
@@ -141,6 +271,15 @@ pub struct MigrationManager {
     pub(crate) desc_db: Arc<RwLock<HashMap<String, DeviceStateDesc>>>,
     /// The status of migration work.
     status: Arc<RwLock<MigrationStatus>>,
+    /// The statistics of the latest live migration.
+    pub(crate) stats: Arc<RwLock<Option<MigrationStats>>>,
+    /// The statistics of restoring snapshot memory lazily.
+    pub(crate) lazy_stats: Arc<RwLock<Option<Arc<LazyRestoreStats>>>>,
+    /// The path of the latest snapshot, which is the parent of next incremental
+    /// snapshot. Dirty pages are logged since it's taken.
+    pub(crate) snapshot_parent: Arc<RwLock<Option<PathBuf>>>,
+    /// The map offers the device id and the reason why it blocks migration.
+    blockers: Arc<RwLock<BTreeMap<String, MigrationBlocker>>>,
 }
 
 impl MigrationManager {
@@ -229,6 +368,17 @@ impl MigrationManager {
             .insert(nr_entry, entry);
     }
 
+    /// Unregister the device instance from entry hashmap when the device is
+    /// removed, the instance is found by the address of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_entry` - Device instance registered before.
+    pub fn unregister_device_instance<T: ?Sized>(device_entry: &Arc<T>) {
+        let ptr = Arc::as_ptr(device_entry) as *const u8;
+        remove_entry(&mut MIGRATION_MANAGER.entry.write().unwrap(), ptr);
+    }
+
     /// Get entry_db's length.
     pub fn entry_db_len() -> u64 {
         MIGRATION_MANAGER.entry.read().unwrap().len() as u64
@@ -324,6 +474,48 @@ impl MigrationManager {
     pub fn migration_get_status() -> MigrationStatus {
         *MIGRATION_MANAGER.status.read().unwrap()
     }
+
+    /// Block migration while the device exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of device which doesn't support migration.
+    /// * `reason` - Why the device blocks migration.
+    /// * `block_snapshot` - Block snapshot as well as live migration.
+    pub fn register_blocker(id: &str, reason: &str, block_snapshot: bool) {
+        MIGRATION_MANAGER.blockers.write().unwrap().insert(
+            id.to_string(),
+            MigrationBlocker {
+                reason: reason.to_string(),
+                block_snapshot,
+            },
+        );
+    }
+
+    /// Remove the migration blocker registered by the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of device.
+    pub fn unregister_blocker(id: &str) {
+        MIGRATION_MANAGER.blockers.write().unwrap().remove(id);
+    }
+
+    /// Check if there is any device blocking migration.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - Only check the devices which block snapshot.
+    pub(crate) fn check_blockers(snapshot: bool) -> Result<()> {
+        let blockers = MIGRATION_MANAGER.blockers.read().unwrap();
+        if let Some((id, blocker)) = blockers
+            .iter()
+            .find(|(_, blocker)| !snapshot || blocker.block_snapshot)
+        {
+            return Err(ErrorKind::MigrationBlocked(id.clone(), blocker.reason.clone()).into());
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -360,4 +552,64 @@ mod tests {
             0
         );
     }
+
+    #[test]
+    fn test_remove_entry() {
+        let mut entry = BTreeMap::<u64, MigrationEntry>::new();
+        let devices: Vec<Arc<Mutex<DeviceV2>>> = (0..3)
+            .map(|_| Arc::new(Mutex::new(DeviceV2::default())))
+            .collect();
+        let gic = Arc::new(DeviceV1::default());
+
+        // The instance ids are the same as the ones assigned in registration.
+        entry.insert(0, MigrationEntry::Mutex(devices[0].clone()));
+        entry.insert(!1, MigrationEntry::Safe(gic.clone()));
+        entry.insert(2, MigrationEntry::Mutex(devices[1].clone()));
+        entry.insert(3, MigrationEntry::Mutex(devices[2].clone()));
+
+        let ptr = Arc::as_ptr(&devices[1]) as *const u8;
+        assert!(remove_entry(&mut entry, ptr));
+        assert!(!remove_entry(&mut entry, ptr));
+        assert_eq!(entry.len(), 3);
+        assert_eq!(
+            entry.get(&0).unwrap().as_ptr(),
+            Arc::as_ptr(&devices[0]) as *const u8
+        );
+        assert_eq!(
+            entry.get(&!1).unwrap().as_ptr(),
+            Arc::as_ptr(&gic) as *const u8
+        );
+        assert_eq!(
+            entry.get(&2).unwrap().as_ptr(),
+            Arc::as_ptr(&devices[2]) as *const u8
+        );
+
+        // The instance registered in the reverse order is renumbered as well.
+        let ptr = Arc::as_ptr(&devices[0]) as *const u8;
+        assert!(remove_entry(&mut entry, ptr));
+        assert_eq!(
+            entry.get(&!0).unwrap().as_ptr(),
+            Arc::as_ptr(&gic) as *const u8
+        );
+        assert_eq!(
+            entry.get(&1).unwrap().as_ptr(),
+            Arc::as_ptr(&devices[2]) as *const u8
+        );
+    }
+
+    #[test]
+    fn test_migration_blocker() {
+        assert!(MigrationManager::check_blockers(false).is_ok());
+
+        MigrationManager::register_blocker("vfio0", "Dirty pages can't be tracked", false);
+        assert!(MigrationManager::check_blockers(false).is_err());
+        assert!(MigrationManager::check_blockers(true).is_ok());
+
+        MigrationManager::register_blocker("mem0", "State isn't saved", true);
+        assert!(MigrationManager::check_blockers(true).is_err());
+
+        MigrationManager::unregister_blocker("vfio0");
+        MigrationManager::unregister_blocker("mem0");
+        assert!(MigrationManager::check_blockers(false).is_ok());
+    }
 }
@@ -0,0 +1,623 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use error_chain::ChainedError;
+use util::byte_code::ByteCode;
+use util::unix::UnixPath;
+
+use crate::errors::{Result, ResultExt};
+use crate::header::FileFormat;
+use crate::manager::{MigrationEntry, MigrationHook, MigrationManager, MIGRATION_MANAGER};
+use crate::snapshot::HEADER_LENGTH;
+use crate::status::MigrationStatus;
+
+/// The expected max downtime of VM in stop-and-copy phase, in milliseconds.
+const MAX_DOWNTIME_MS: u64 = 300;
+/// The max iterations of pre-copy. VM will be paused after that even if the
+/// dirty memory doesn't converge.
+const MAX_ITERATIONS: u64 = 30;
+/// The max length of guest memory in a memory message, larger ranges are
+/// split into multiple messages.
+const MAX_MEMORY_MSG_LEN: u64 = 64 << 20;
+/// The max length of device states in a device state message.
+const MAX_DEVICE_STATE_LEN: u64 = 64 << 20;
+
+/// Hook to pause VM before the stop-and-copy phase of live migration, returns
+/// whether VM is paused by it.
+pub type PauseVmHook = Box<dyn Fn() -> Result<bool> + Send>;
+/// Hook to resume VM which is paused by live migration when migration fails.
+pub type ResumeVmHook = Box<dyn Fn() -> Result<()> + Send>;
+/// Hook called with the result of incoming live migration after it finishes.
+pub type IncomingDoneHook = Box<dyn FnOnce(Result<()>) + Send>;
+
+/// Type of message in migration stream.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum MsgType {
+    /// `MigrationHeader` of the source, followed by `HEADER_LENGTH` bytes.
+    Header = 1,
+    /// Data of a guest memory range, followed by `len` bytes.
+    Memory = 2,
+    /// Descriptors and states of all devices, followed by `len` bytes.
+    DeviceState = 3,
+    /// Destination has loaded all states, sent by destination.
+    Complete = 4,
+}
+
+/// Header of every message in migration stream.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MsgHeader {
+    msg_type: u32,
+    padding: u32,
+    /// Guest address of memory, only used by memory message.
+    addr: u64,
+    /// Length of data following the header.
+    len: u64,
+}
+
+impl ByteCode for MsgHeader {}
+
+impl MsgHeader {
+    fn new(msg_type: MsgType, addr: u64, len: u64) -> Self {
+        MsgHeader {
+            msg_type: msg_type as u32,
+            padding: 0,
+            addr,
+            len,
+        }
+    }
+
+    fn msg_type(&self) -> Option<MsgType> {
+        match self.msg_type {
+            1 => Some(MsgType::Header),
+            2 => Some(MsgType::Memory),
+            3 => Some(MsgType::DeviceState),
+            4 => Some(MsgType::Complete),
+            _ => None,
+        }
+    }
+
+    /// Check the length of data following the header, so that the peer can't
+    /// make the receiver read or allocate more than expected.
+    fn check_len(&self) -> Result<()> {
+        let max_len = match self.msg_type() {
+            Some(MsgType::Header) => HEADER_LENGTH as u64,
+            Some(MsgType::Memory) => MAX_MEMORY_MSG_LEN,
+            Some(MsgType::DeviceState) => MAX_DEVICE_STATE_LEN,
+            _ => 0,
+        };
+        if self.len > max_len {
+            bail!(
+                "Length {} of migration message type {} exceeds the max {}",
+                self.len,
+                self.msg_type,
+                max_len
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Statistics of live migration.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MigrationStats {
+    /// The time when migration starts.
+    start_time: Option<Instant>,
+    /// Total time of migration in milliseconds.
+    pub total_time: u64,
+    /// Downtime of VM in stop-and-copy phase in milliseconds.
+    pub downtime: u64,
+    /// Total size of guest memory in bytes.
+    pub total_ram: u64,
+    /// Size of guest memory which has been transferred in bytes.
+    pub transferred_ram: u64,
+    /// Size of dirty guest memory which remains to be transferred in bytes.
+    pub remaining_ram: u64,
+    /// Number of times that dirty memory is synchronized.
+    pub dirty_sync_count: u64,
+}
+
+impl MigrationManager {
+    /// Start live migration to the destination which is listening on `addr`.
+    ///
+    /// # Notes
+    ///
+    /// The destination is connected in current thread, and the migration
+    /// stream is sent in a new thread. Use `migration_get_status` and
+    /// `migration_get_stats` to get the progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_type` - Socket type of destination, `Tcp` or `Unix`.
+    /// * `addr` - Address of destination, `host:port` or socket path.
+    /// * `pause` - Hook to pause VM before stop-and-copy phase.
+    /// * `resume` - Hook to resume VM if migration fails.
+    pub fn send_migration(
+        path_type: UnixPath,
+        addr: &str,
+        pause: PauseVmHook,
+        resume: ResumeVmHook,
+    ) -> Result<()> {
+        Self::check_blockers(false)?;
+        MigrationManager::set_status(MigrationStatus::Active)?;
+        // Dirty log is stopped after live migration, so incremental snapshot
+        // can't be based on the latest snapshot any more.
+        *MIGRATION_MANAGER.snapshot_parent.write().unwrap() = None;
+
+        let ret = match path_type {
+            UnixPath::Tcp => TcpStream::connect(addr)
+                .chain_err(|| format!("Failed to connect to {}", addr))
+                .and_then(|stream| Self::spawn_sender(stream, pause, resume)),
+            UnixPath::Unix => UnixStream::connect(addr)
+                .chain_err(|| format!("Failed to connect to {}", addr))
+                .and_then(|stream| Self::spawn_sender(stream, pause, resume)),
+            _ => Err("Unsupported path type for live migration".into()),
+        };
+        if ret.is_err() {
+            MigrationManager::set_status(MigrationStatus::Failed)?;
+        }
+
+        ret
+    }
+
+    /// Receive live migration from the source.
+    ///
+    /// # Notes
+    ///
+    /// The address is listened on in current thread, and the migration stream
+    /// is received in a new thread. Use `migration_get_status` to get the
+    /// progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `path_type` - Socket type to listen on, `Tcp` or `Unix`.
+    /// * `addr` - Address to listen on, `host:port` or socket path.
+    /// * `done` - Hook called after VM states are all loaded or migration fails.
+    pub fn recv_migration(path_type: UnixPath, addr: &str, done: IncomingDoneHook) -> Result<()> {
+        MigrationManager::set_status(MigrationStatus::Active)?;
+
+        let ret = match path_type {
+            UnixPath::Tcp => TcpListener::bind(addr)
+                .chain_err(|| format!("Failed to bind tcp address {}", addr))
+                .and_then(|listener| {
+                    let addr = addr.to_string();
+                    Self::spawn_receiver(
+                        move || {
+                            let (mut stream, _) = listener.accept().chain_err(|| {
+                                format!("Failed to accept migration from {}", addr)
+                            })?;
+                            Self::recv_migration_stream(&mut stream)
+                        },
+                        done,
+                    )
+                }),
+            UnixPath::Unix => UnixListener::bind(addr)
+                .chain_err(|| format!("Failed to bind unix socket {}", addr))
+                .and_then(|listener| {
+                    let addr = addr.to_string();
+                    Self::spawn_receiver(
+                        move || {
+                            let accepted = listener.accept();
+                            std::fs::remove_file(&addr)
+                                .chain_err(|| format!("Failed to remove unix socket {}", addr))?;
+                            let (mut stream, _) = accepted.chain_err(|| {
+                                format!("Failed to accept migration from {}", addr)
+                            })?;
+                            Self::recv_migration_stream(&mut stream)
+                        },
+                        done,
+                    )
+                }),
+            _ => Err("Unsupported path type for live migration".into()),
+        };
+        if ret.is_err() {
+            MigrationManager::set_status(MigrationStatus::Failed)?;
+        }
+
+        ret
+    }
+
+    /// Get statistics of the latest live migration, return `None` if live
+    /// migration has never been started.
+    pub fn migration_get_stats() -> Option<MigrationStats> {
+        let mut stats = (*MIGRATION_MANAGER.stats.read().unwrap())?;
+        if Self::migration_get_status() == MigrationStatus::Active {
+            if let Some(start_time) = stats.start_time {
+                stats.total_time = start_time.elapsed().as_millis() as u64;
+            }
+        }
+
+        Some(stats)
+    }
+
+    fn update_stats<F: FnOnce(&mut MigrationStats)>(f: F) {
+        if let Some(stats) = MIGRATION_MANAGER.stats.write().unwrap().as_mut() {
+            f(stats);
+        }
+    }
+
+    pub(crate) fn memory_instance() -> Result<Arc<dyn MigrationHook + Send + Sync>> {
+        for entry in MIGRATION_MANAGER.entry.read().unwrap().values() {
+            if let MigrationEntry::Memory(i) = entry {
+                return Ok(i.clone());
+            }
+        }
+        bail!("No memory instance for migration");
+    }
+
+    /// Stop all the devices from processing requests, and wait for the requests
+    /// in flight to complete.
+    fn quiesce_devices() -> Result<()> {
+        for entry in MIGRATION_MANAGER.entry.read().unwrap().values() {
+            if let MigrationEntry::Mutex(i) = entry {
+                i.lock()
+                    .unwrap()
+                    .quiesce()
+                    .chain_err(|| "Failed to quiesce device")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restart all the devices stopped by `quiesce_devices`.
+    fn unquiesce_devices() {
+        for entry in MIGRATION_MANAGER.entry.read().unwrap().values() {
+            if let MigrationEntry::Mutex(i) = entry {
+                if let Err(e) = i.lock().unwrap().unquiesce() {
+                    error!("Failed to unquiesce device: {}", e.display_chain());
+                }
+            }
+        }
+    }
+
+    fn spawn_receiver<F>(receive: F, done: IncomingDoneHook) -> Result<()>
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        thread::Builder::new()
+            .name("migration".to_string())
+            .spawn(move || {
+                let ret = receive();
+                let status = if ret.is_ok() {
+                    MigrationStatus::Completed
+                } else {
+                    MigrationStatus::Failed
+                };
+                let _ = MigrationManager::set_status(status).map_err(|e| error!("{}", e));
+                done(ret);
+            })
+            .chain_err(|| "Failed to create migration thread")?;
+
+        Ok(())
+    }
+
+    fn spawn_sender<T>(mut stream: T, pause: PauseVmHook, resume: ResumeVmHook) -> Result<()>
+    where
+        T: Read + Write + Send + 'static,
+    {
+        *MIGRATION_MANAGER.stats.write().unwrap() = Some(MigrationStats {
+            start_time: Some(Instant::now()),
+            ..Default::default()
+        });
+
+        thread::Builder::new()
+            .name("migration".to_string())
+            .spawn(move || {
+                let mut paused = false;
+                let mut quiesced = false;
+                let ret =
+                    Self::send_migration_stream(&mut stream, &pause, &mut paused, &mut quiesced);
+                Self::update_stats(|stats| {
+                    if let Some(start_time) = stats.start_time {
+                        stats.total_time = start_time.elapsed().as_millis() as u64;
+                    }
+                });
+
+                if let Err(ref e) = ret {
+                    error!("Failed to migrate: {}", e.display_chain());
+                    if let Ok(memory) = Self::memory_instance() {
+                        let _ = memory
+                            .stop_dirty_log()
+                            .map_err(|e| error!("{}", e.display_chain()));
+                    }
+                    if quiesced {
+                        Self::unquiesce_devices();
+                    }
+                    if paused {
+                        let _ = resume().map_err(|e| error!("{}", e.display_chain()));
+                    }
+                }
+                let status = if ret.is_ok() {
+                    MigrationStatus::Completed
+                } else {
+                    MigrationStatus::Failed
+                };
+                let _ = MigrationManager::set_status(status).map_err(|e| error!("{}", e));
+            })
+            .chain_err(|| "Failed to create migration thread")?;
+
+        Ok(())
+    }
+
+    /// Send the whole migration stream, including iterative pre-copy of memory
+    /// and stop-and-copy of dirty memory and device states.
+    fn send_migration_stream<T: Read + Write>(
+        stream: &mut T,
+        pause: &PauseVmHook,
+        paused: &mut bool,
+        quiesced: &mut bool,
+    ) -> Result<()> {
+        let memory = Self::memory_instance()?;
+
+        Self::send_msg(stream, MsgType::Header, 0, HEADER_LENGTH as u64)?;
+        Self::save_header(FileFormat::MemoryFull, stream)?;
+
+        memory.start_dirty_log()?;
+        let mut ranges = memory.get_memory_ranges(false)?;
+        let total_ram = ranges.iter().map(|(_, len)| len).sum();
+        Self::update_stats(|stats| stats.total_ram = total_ram);
+
+        let mut iterations = 0;
+        loop {
+            let start_time = Instant::now();
+            let sent = Self::send_memory(stream, &memory, &ranges)?;
+            let elapsed = std::cmp::max(start_time.elapsed().as_millis() as u64, 1);
+
+            ranges = memory.get_memory_ranges(true)?;
+            let remaining: u64 = ranges.iter().map(|(_, len)| len).sum();
+            iterations += 1;
+            Self::update_stats(|stats| {
+                stats.remaining_ram = remaining;
+                stats.dirty_sync_count += 1;
+            });
+
+            // Stop VM if the remaining memory could be sent in expected downtime.
+            if remaining <= sent * MAX_DOWNTIME_MS / elapsed || iterations >= MAX_ITERATIONS {
+                break;
+            }
+        }
+
+        let downtime_start = Instant::now();
+        *paused = pause().chain_err(|| "Failed to pause VM for migration")?;
+        // Devices keep running in iothreads after vCPUs are paused, they must
+        // stop writing memory before the last dirty memory is collected.
+        *quiesced = true;
+        Self::quiesce_devices()?;
+        Self::send_memory(stream, &memory, &ranges)?;
+
+        ranges = memory.get_memory_ranges(true)?;
+        Self::update_stats(|stats| stats.dirty_sync_count += 1);
+        Self::send_memory(stream, &memory, &ranges)?;
+        memory.stop_dirty_log()?;
+
+        let mut device_state = Vec::new();
+        Self::save_header(FileFormat::Device, &mut device_state)?;
+        Self::save_descriptor_db(&mut device_state)?;
+        Self::save_device_state(&mut device_state)?;
+
+        Self::send_msg(stream, MsgType::DeviceState, 0, device_state.len() as u64)?;
+        stream
+            .write_all(&device_state)
+            .chain_err(|| "Failed to send device state")?;
+
+        let msg = Self::recv_msg(stream)?;
+        if msg.msg_type() != Some(MsgType::Complete) {
+            bail!("Invalid message type {} from destination", msg.msg_type);
+        }
+        Self::update_stats(|stats| {
+            stats.downtime = downtime_start.elapsed().as_millis() as u64;
+            stats.remaining_ram = 0;
+        });
+
+        Ok(())
+    }
+
+    /// Receive the whole migration stream and load the states.
+    fn recv_migration_stream<T: Read + Write>(stream: &mut T) -> Result<()> {
+        let memory = Self::memory_instance()?;
+        let mut header_checked = false;
+
+        loop {
+            let msg = Self::recv_msg(stream)?;
+            msg.check_len()?;
+            match msg.msg_type() {
+                Some(MsgType::Header) => {
+                    let header = Self::load_header(stream)?;
+                    header.check_header()?;
+                    if header.format != FileFormat::MemoryFull {
+                        bail!("Invalid migration header");
+                    }
+                    header_checked = true;
+                }
+                Some(MsgType::Memory) if header_checked => {
+                    memory.load_memory_range(stream, msg.addr, msg.len)?;
+                }
+                Some(MsgType::DeviceState) if header_checked => {
+                    let mut device_state = vec![0_u8; msg.len as usize];
+                    stream
+                        .read_exact(&mut device_state)
+                        .chain_err(|| "Failed to receive device state")?;
+
+                    let mut reader = device_state.as_slice();
+                    let header = Self::load_header(&mut reader)?;
+                    header.check_header()?;
+                    if header.format != FileFormat::Device {
+                        bail!("Invalid device state header");
+                    }
+                    let desc_db = Self::load_descriptor_db(&mut reader, header.desc_len)
+                        .chain_err(|| "Failed to load device descriptor db")?;
+                    Self::load_vmstate(desc_db, &mut reader)
+                        .chain_err(|| "Failed to load device state")?;
+                    Self::resume()?;
+
+                    return Self::send_msg(stream, MsgType::Complete, 0, 0);
+                }
+                _ => bail!("Unexpected message type {} from source", msg.msg_type),
+            }
+        }
+    }
+
+    /// Send memory ranges, return the total size of sent memory.
+    fn send_memory(
+        writer: &mut dyn Write,
+        memory: &Arc<dyn MigrationHook + Send + Sync>,
+        ranges: &[(u64, u64)],
+    ) -> Result<u64> {
+        let mut sent = 0;
+        for (addr, len) in ranges.iter() {
+            let mut offset = 0;
+            while offset < *len {
+                let msg_len = std::cmp::min(len - offset, MAX_MEMORY_MSG_LEN);
+                Self::send_msg(writer, MsgType::Memory, addr + offset, msg_len)?;
+                memory.save_memory_range(writer, addr + offset, msg_len)?;
+                offset += msg_len;
+            }
+            sent += len;
+        }
+        Self::update_stats(|stats| stats.transferred_ram += sent);
+
+        Ok(sent)
+    }
+
+    fn send_msg(writer: &mut dyn Write, msg_type: MsgType, addr: u64, len: u64) -> Result<()> {
+        writer
+            .write_all(MsgHeader::new(msg_type, addr, len).as_bytes())
+            .chain_err(|| format!("Failed to send migration message {:?}", msg_type))
+    }
+
+    fn recv_msg(reader: &mut dyn Read) -> Result<MsgHeader> {
+        let mut msg = MsgHeader::default();
+        reader
+            .read_exact(msg.as_mut_bytes())
+            .chain_err(|| "Failed to receive migration message")?;
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::device_state::StateTransfer;
+
+    pub struct TestMemory {
+        pub data: Mutex<Vec<u8>>,
+        pub dirty: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl StateTransfer for TestMemory {
+        fn get_state_vec(&self) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn get_device_alias(&self) -> u64 {
+            0
+        }
+    }
+
+    impl MigrationHook for TestMemory {
+        fn get_memory_ranges(&self, dirty: bool) -> Result<Vec<(u64, u64)>> {
+            if dirty {
+                return Ok(std::mem::take(&mut *self.dirty.lock().unwrap()));
+            }
+            Ok(vec![(0, self.data.lock().unwrap().len() as u64)])
+        }
+
+        fn save_memory_range(&self, writer: &mut dyn Write, addr: u64, len: u64) -> Result<()> {
+            let data = self.data.lock().unwrap();
+            writer.write_all(&data[addr as usize..(addr + len) as usize])?;
+            Ok(())
+        }
+
+        fn load_memory_range(&self, reader: &mut dyn Read, addr: u64, len: u64) -> Result<()> {
+            let mut data = self.data.lock().unwrap();
+            reader.read_exact(&mut data[addr as usize..(addr + len) as usize])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_migration_msg() {
+        let mut stream = Vec::new();
+        MigrationManager::send_msg(&mut stream, MsgType::Memory, 0x1000, 0x200).unwrap();
+        assert_eq!(stream.len(), std::mem::size_of::<MsgHeader>());
+
+        let msg = MigrationManager::recv_msg(&mut stream.as_slice()).unwrap();
+        assert_eq!(msg.msg_type(), Some(MsgType::Memory));
+        assert_eq!(msg.addr, 0x1000);
+        assert_eq!(msg.len, 0x200);
+
+        let msg = MsgHeader {
+            msg_type: 5,
+            ..Default::default()
+        };
+        assert_eq!(msg.msg_type(), None);
+
+        // Incomplete message.
+        assert!(MigrationManager::recv_msg(&mut &stream[0..8]).is_err());
+    }
+
+    #[test]
+    fn test_migration_msg_len() {
+        let msg = MsgHeader::new(MsgType::Header, 0, HEADER_LENGTH as u64);
+        assert!(msg.check_len().is_ok());
+        let msg = MsgHeader::new(MsgType::Memory, 0, MAX_MEMORY_MSG_LEN);
+        assert!(msg.check_len().is_ok());
+        let msg = MsgHeader::new(MsgType::Memory, 0, MAX_MEMORY_MSG_LEN + 1);
+        assert!(msg.check_len().is_err());
+        let msg = MsgHeader::new(MsgType::DeviceState, 0, u64::MAX);
+        assert!(msg.check_len().is_err());
+        let msg = MsgHeader::new(MsgType::Complete, 0, 1);
+        assert!(msg.check_len().is_err());
+    }
+
+    #[test]
+    fn test_migration_memory() {
+        let src: Arc<dyn MigrationHook + Send + Sync> = Arc::new(TestMemory {
+            data: Mutex::new((0..=255).collect()),
+            dirty: Mutex::new(Vec::new()),
+        });
+        let dst = TestMemory {
+            data: Mutex::new(vec![0; 256]),
+            dirty: Mutex::new(Vec::new()),
+        };
+
+        let mut stream = Vec::new();
+        let sent = MigrationManager::send_memory(&mut stream, &src, &[(0, 16), (128, 64)]).unwrap();
+        assert_eq!(sent, 80);
+
+        let mut reader = stream.as_slice();
+        while !reader.is_empty() {
+            let msg = MigrationManager::recv_msg(&mut reader).unwrap();
+            assert_eq!(msg.msg_type(), Some(MsgType::Memory));
+            assert!(msg.check_len().is_ok());
+            dst.load_memory_range(&mut reader, msg.addr, msg.len)
+                .unwrap();
+        }
+
+        let data = dst.data.lock().unwrap();
+        assert_eq!(data[0..16], (0..16).collect::<Vec<u8>>()[..]);
+        assert!(data[16..128].iter().all(|b| *b == 0));
+        assert_eq!(data[128..192], (128..192).collect::<Vec<u8>>()[..]);
+        assert!(data[192..].iter().all(|b| *b == 0));
+    }
+}
@@ -10,16 +10,22 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::collections::HashMap;
-use std::fs::{create_dir, File};
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{canonicalize, create_dir, File};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::mem::size_of;
-use std::path::PathBuf;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use util::byte_code::ByteCode;
+use util::checksum::crc32;
 use util::reader::BufferReader;
 use util::unix::host_page_size;
 
+use crate::chunk::{load_memory_chunks, save_memory_chunks, Compression};
 use crate::device_state::{DeviceStateDesc, VersionCheck};
 use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::header::{FileFormat, MigrationHeader};
@@ -27,11 +33,60 @@ use crate::manager::{InstanceId, MigrationEntry, MigrationManager, MIGRATION_MAN
 use crate::status::MigrationStatus;
 
 /// The length of `MigrationHeader` part occupies bytes in snapshot file.
-const HEADER_LENGTH: usize = 4096;
+pub(crate) const HEADER_LENGTH: usize = 4096;
+/// The alignment of memory data in snapshot memory file with `MemoryMapped`
+/// format. It's the max page size supported, so the memory data can be mapped
+/// on hosts with any page size.
+pub const MEMORY_MAPPED_ALIGN: u64 = 0x20_0000;
 /// The suffix used for snapshot memory storage.
 const MEMORY_PATH_SUFFIX: &str = "memory";
 /// The suffix used for snapshot device state storage.
 const DEVICE_PATH_SUFFIX: &str = "state";
+/// The max length of parent snapshot path in `MemoryDiff` snapshot file.
+const MAX_PARENT_PATH_LEN: usize = 4096;
+
+/// Statistics of restoring snapshot memory lazily.
+#[derive(Debug, Default)]
+pub struct LazyRestoreStats {
+    /// Total number of pages to be restored.
+    total_pages: AtomicU64,
+    /// Number of pages restored on demand when they are missing.
+    missing_pages: AtomicU64,
+    /// Number of pages restored by background prefetcher.
+    prefetched_pages: AtomicU64,
+}
+
+impl LazyRestoreStats {
+    pub fn total_pages(&self) -> u64 {
+        self.total_pages.load(Ordering::Acquire)
+    }
+
+    pub fn missing_pages(&self) -> u64 {
+        self.missing_pages.load(Ordering::Acquire)
+    }
+
+    pub fn prefetched_pages(&self) -> u64 {
+        self.prefetched_pages.load(Ordering::Acquire)
+    }
+
+    /// Number of pages which have not been restored yet.
+    pub fn remaining_pages(&self) -> u64 {
+        self.total_pages()
+            .saturating_sub(self.missing_pages() + self.prefetched_pages())
+    }
+
+    pub fn add_total_pages(&self, pages: u64) {
+        self.total_pages.fetch_add(pages, Ordering::AcqRel);
+    }
+
+    pub fn add_missing_pages(&self, pages: u64) {
+        self.missing_pages.fetch_add(pages, Ordering::AcqRel);
+    }
+
+    pub fn add_prefetched_pages(&self, pages: u64) {
+        self.prefetched_pages.fetch_add(pages, Ordering::AcqRel);
+    }
+}
 
 impl MigrationManager {
     /// Do snapshot for `VM`.
@@ -42,12 +97,31 @@ impl MigrationManager {
     /// for input path. It will create two file in snapshot dir - device state file `state`
     /// and memory file `memory`.
     ///
+    /// If `dirty_log` is true, dirty pages are logged since the snapshot is taken, so
+    /// that the following incremental snapshot only saves the memory dirtied since the
+    /// latest snapshot. Otherwise dirty log is stopped and the chain of snapshots ends.
+    ///
+    /// If `compression` is given, memory data is saved in checksummed chunks compressed
+    /// with it and all-zero pages are skipped, otherwise it's saved as it is so that it
+    /// can be mapped directly when restoring.
+    ///
     /// # Argument
     ///
     /// * `path` - snapshot dir path. If path dir not exists, will create it.
-    pub fn save_snapshot(path: &str) -> Result<()> {
+    /// * `incremental` - Only save the memory dirtied since the latest snapshot.
+    /// * `dirty_log` - Log dirty memory for the following incremental snapshot.
+    /// * `compression` - Compression algorithm of memory data.
+    pub fn save_snapshot(
+        path: &str,
+        incremental: bool,
+        dirty_log: bool,
+        compression: Option<Compression>,
+    ) -> Result<()> {
+        Self::check_blockers(true)?;
         // Set status to `Active`
         MigrationManager::set_status(MigrationStatus::Active)?;
+        // Statistics are only for live migration.
+        *MIGRATION_MANAGER.stats.write().unwrap() = None;
 
         // Create snapshot dir.
         if let Err(e) = create_dir(path) {
@@ -55,15 +129,38 @@ impl MigrationManager {
                 bail!("Failed to create snapshot dir: {}", e);
             }
         }
+        let snapshot_path =
+            canonicalize(path).chain_err(|| format!("Failed to get path of {}", path))?;
+
+        let parent = MIGRATION_MANAGER.snapshot_parent.read().unwrap().clone();
+        if incremental {
+            match &parent {
+                None => {
+                    bail!("No parent snapshot for incremental snapshot, take a snapshot with dirty log first")
+                }
+                Some(p) if *p == snapshot_path => {
+                    bail!("Incremental snapshot can't overwrite its parent snapshot")
+                }
+                _ => {}
+            }
+        }
+        // Dirty log is consumed by this snapshot, the parent is valid only if it succeeds.
+        *MIGRATION_MANAGER.snapshot_parent.write().unwrap() = None;
 
         // Save device state
-        let mut vm_state_path = PathBuf::from(path);
+        let mut vm_state_path = snapshot_path.clone();
         vm_state_path.push(DEVICE_PATH_SUFFIX);
         match File::create(vm_state_path) {
             Ok(mut state_file) => {
-                Self::save_header(FileFormat::Device, &mut state_file)?;
-                Self::save_descriptor_db(&mut state_file)?;
-                Self::save_device_state(&mut state_file)?;
+                let mut state = Vec::new();
+                Self::save_descriptor_db(&mut state)?;
+                Self::save_device_state(&mut state)?;
+                Self::save_header_with_data(
+                    FileFormat::Device,
+                    Self::get_desc_db_len()?,
+                    &state,
+                    &mut state_file,
+                )?;
             }
             Err(e) => {
                 bail!("Failed to create snapshot state file: {}", e);
@@ -71,17 +168,73 @@ impl MigrationManager {
         }
 
         // Save memory data
-        let mut vm_memory_path = PathBuf::from(path);
+        let mut vm_memory_path = snapshot_path.clone();
         vm_memory_path.push(MEMORY_PATH_SUFFIX);
+        let memory = Self::memory_instance()?;
         match File::create(vm_memory_path) {
-            Ok(mut memory_file) => {
-                Self::save_header(FileFormat::MemoryFull, &mut memory_file)?;
-                Self::save_memory(&mut memory_file)?;
+            Ok(memory_file) => {
+                let mut writer = BufWriter::new(memory_file);
+                match parent {
+                    Some(parent) if incremental => {
+                        let parent_path = parent.as_os_str().as_bytes();
+                        Self::save_header_with_data(
+                            FileFormat::MemoryDiff,
+                            parent_path.len(),
+                            parent_path,
+                            &mut writer,
+                        )?;
+                        // Memory of the parent isn't zero, so zero pages are saved.
+                        save_memory_chunks(
+                            &mut writer,
+                            &memory,
+                            &memory.get_memory_ranges(true)?,
+                            compression.unwrap_or(Compression::None),
+                            true,
+                        )?;
+                        if !dirty_log {
+                            memory.stop_dirty_log()?;
+                        }
+                    }
+                    _ => {
+                        if dirty_log {
+                            memory.start_dirty_log()?;
+                            // Clear the pages dirtied before this snapshot.
+                            memory.get_memory_ranges(true)?;
+                        } else {
+                            memory.stop_dirty_log()?;
+                        }
+                        if let Some(compression) = compression {
+                            let state = memory.get_state_vec()?;
+                            Self::save_header_with_data(
+                                FileFormat::MemoryChunked,
+                                state.len(),
+                                &state,
+                                &mut writer,
+                            )?;
+                            save_memory_chunks(
+                                &mut writer,
+                                &memory,
+                                &memory.get_memory_ranges(false)?,
+                                compression,
+                                false,
+                            )?;
+                        } else {
+                            Self::save_header(FileFormat::MemoryMapped, &mut writer)?;
+                            Self::save_memory(&mut writer)?;
+                        }
+                    }
+                }
+                writer
+                    .flush()
+                    .chain_err(|| "Failed to write snapshot memory file")?;
             }
             Err(e) => {
                 bail!("Failed to create snapshot memory file: {}", e);
             }
         }
+        if dirty_log {
+            *MIGRATION_MANAGER.snapshot_parent.write().unwrap() = Some(snapshot_path);
+        }
 
         // Set status to `Completed`
         MigrationManager::set_status(MigrationStatus::Completed)?;
@@ -95,11 +248,14 @@ impl MigrationManager {
     ///
     /// Offers a interface for restore snapshot functions. This function will make VM
     /// back to the state restored in snapshot file including both device and memory.
+    /// For incremental snapshot, memory is restored from its full snapshot, and then
+    /// the memory saved in incremental snapshots is applied in turn.
     ///
     /// # Argument
     ///
     /// * `path` - snapshot dir path.
-    pub fn restore_snapshot(path: &str) -> Result<()> {
+    /// * `lazy` - Restore memory on demand rather than before resuming VM.
+    pub fn restore_snapshot(path: &str, lazy: bool) -> Result<()> {
         // Set status to `Active`
         MigrationManager::set_status(MigrationStatus::Active)?;
 
@@ -108,15 +264,7 @@ impl MigrationManager {
             return Err(ErrorKind::InvalidSnapshotPath.into());
         }
 
-        snapshot_path.push(MEMORY_PATH_SUFFIX);
-        let mut memory_file =
-            File::open(&snapshot_path).chain_err(|| "Failed to open memory snapshot file")?;
-        let memory_header = Self::load_header(&mut memory_file)?;
-        memory_header.check_header()?;
-        if memory_header.format != FileFormat::MemoryFull {
-            bail!("Invalid memory snapshot file");
-        }
-        snapshot_path.pop();
+        let (mut memory_file, memory_header, diff_files) = Self::open_memory_files(&snapshot_path)?;
         snapshot_path.push(DEVICE_PATH_SUFFIX);
         let mut device_state_file =
             File::open(&snapshot_path).chain_err(|| "Failed to open device state snapshot file")?;
@@ -125,12 +273,21 @@ impl MigrationManager {
         if device_state_header.format != FileFormat::Device {
             bail!("Invalid device state snapshot file");
         }
-
-        Self::load_memory(&mut memory_file).chain_err(|| "Failed to load snapshot memory")?;
+        let device_state =
+            Self::load_header_data(&mut device_state_file, &device_state_header, "device state")?;
+
+        Self::load_memory(&mut memory_file, &memory_header, lazy)
+            .chain_err(|| "Failed to load snapshot memory")?;
+        let memory = Self::memory_instance()?;
+        for diff_file in diff_files.into_iter().rev() {
+            load_memory_chunks(&mut BufReader::new(diff_file), &memory)
+                .chain_err(|| "Failed to load incremental snapshot memory")?;
+        }
+        let mut device_state = device_state.as_slice();
         let snapshot_desc_db =
-            Self::load_descriptor_db(&mut device_state_file, device_state_header.desc_len)
+            Self::load_descriptor_db(&mut device_state, device_state_header.desc_len)
                 .chain_err(|| "Failed to load device descriptor db")?;
-        Self::load_vmstate(snapshot_desc_db, &mut device_state_file)
+        Self::load_vmstate(snapshot_desc_db, &mut device_state)
             .chain_err(|| "Failed to load snapshot device state")?;
         Self::resume()?;
 
@@ -147,30 +304,79 @@ impl MigrationManager {
     ///
     /// * `file_format` - confirm snapshot file format.
     /// * `writer` - The `Write` trait object to write header message.
-    fn save_header(file_format: FileFormat, writer: &mut dyn Write) -> Result<()> {
-        let mut header = MigrationHeader::default();
-        header.format = file_format;
-        header.desc_len = match file_format {
+    pub(crate) fn save_header(file_format: FileFormat, writer: &mut dyn Write) -> Result<()> {
+        let desc_len = match file_format {
             FileFormat::Device => Self::get_desc_db_len()?,
             FileFormat::MemoryFull => (host_page_size() as usize) * 2 - HEADER_LENGTH,
+            FileFormat::MemoryMapped => MEMORY_MAPPED_ALIGN as usize - HEADER_LENGTH,
+            _ => bail!("Data following header is required for {:?}", file_format),
         };
+        Self::save_header_with_data(file_format, desc_len, &[], writer)
+    }
+
+    /// Write `MigrationHeader` followed by data verified with its checksum.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_format` - confirm snapshot file format.
+    /// * `desc_len` - The length of description following the header.
+    /// * `data` - The data following the header.
+    /// * `writer` - The `Write` trait object to write header message.
+    fn save_header_with_data(
+        file_format: FileFormat,
+        desc_len: usize,
+        data: &[u8],
+        writer: &mut dyn Write,
+    ) -> Result<()> {
+        let mut header = MigrationHeader::default();
+        header.format = file_format;
+        header.desc_len = desc_len;
+        header.data_len = data.len() as u64;
+        header.checksum = crc32(0, data);
         let header_bytes = header.as_bytes();
         let mut input_slice = [0u8; HEADER_LENGTH];
 
         input_slice[0..size_of::<MigrationHeader>()].copy_from_slice(header_bytes);
         writer
-            .write(&input_slice)
+            .write_all(&input_slice)
             .chain_err(|| "Failed to save migration header")?;
+        writer
+            .write_all(data)
+            .chain_err(|| "Failed to save data following migration header")?;
 
         Ok(())
     }
 
+    /// Load the data following `MigrationHeader` and verify it with checksum.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The `Read` trait object.
+    /// * `header` - The `MigrationHeader` loaded from `reader`.
+    /// * `item` - The name of data used in error message.
+    fn load_header_data(
+        reader: &mut dyn Read,
+        header: &MigrationHeader,
+        item: &str,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.take(header.data_len).read_to_end(&mut data)?;
+        if (data.len() as u64) < header.data_len {
+            return Err(ErrorKind::SnapshotTruncated(item.to_string()).into());
+        }
+        if crc32(0, &data) != header.checksum {
+            return Err(ErrorKind::ChecksumMismatch(item.to_string()).into());
+        }
+
+        Ok(data)
+    }
+
     /// Load and parse `MigrationHeader` from `Read` object.
     ///
     /// # Arguments
     ///
     /// * `reader` - The `Read` trait object.
-    fn load_header(reader: &mut dyn Read) -> Result<MigrationHeader> {
+    pub(crate) fn load_header(reader: &mut dyn Read) -> Result<MigrationHeader> {
         let mut header_bytes = [0u8; size_of::<MigrationHeader>()];
         reader.read_exact(&mut header_bytes)?;
 
@@ -197,20 +403,91 @@ impl MigrationManager {
         Ok(())
     }
 
+    /// Open memory files of snapshot and its parents. Returns the memory file of
+    /// the full snapshot with its header, and memory files of incremental snapshots
+    /// from child to parent, whose positions are at the memory data.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - snapshot dir path.
+    fn open_memory_files(path: &Path) -> Result<(File, MigrationHeader, Vec<File>)> {
+        let mut diff_files = Vec::new();
+        let mut visited = HashSet::new();
+        let mut snapshot_path = path.to_path_buf();
+        loop {
+            let memory_path = snapshot_path.join(MEMORY_PATH_SUFFIX);
+            let mut memory_file = File::open(&memory_path)
+                .chain_err(|| format!("Failed to open memory snapshot file {:?}", memory_path))?;
+            if !visited.insert(canonicalize(&memory_path)?) {
+                bail!("Snapshot {:?} is the parent of itself", snapshot_path);
+            }
+            let memory_header = Self::load_header(&mut memory_file)?;
+            memory_header.check_header()?;
+            match memory_header.format {
+                FileFormat::MemoryFull | FileFormat::MemoryMapped | FileFormat::MemoryChunked => {
+                    return Ok((memory_file, memory_header, diff_files));
+                }
+                FileFormat::MemoryDiff if memory_header.data_len <= MAX_PARENT_PATH_LEN as u64 => {
+                    let parent_path = Self::load_header_data(
+                        &mut memory_file,
+                        &memory_header,
+                        &format!("parent path in {:?}", memory_path),
+                    )?;
+                    snapshot_path = PathBuf::from(OsString::from_vec(parent_path));
+                    diff_files.push(memory_file);
+                }
+                _ => bail!("Invalid memory snapshot file {:?}", memory_path),
+            }
+        }
+    }
+
+    /// Get statistics of lazy restore, return `None` if snapshot memory is not
+    /// restored lazily.
+    pub fn lazy_restore_stats() -> Option<Arc<LazyRestoreStats>> {
+        MIGRATION_MANAGER.lazy_stats.read().unwrap().clone()
+    }
+
     /// Load and restore memory from snapshot memory file.
     ///
     /// # Arguments
     ///
     /// * `file` - snapshot memory file.
-    fn load_memory(file: &mut File) -> Result<()> {
-        let mut state_bytes = [0_u8].repeat((host_page_size() as usize) * 2 - HEADER_LENGTH);
+    /// * `header` - Header of snapshot memory file.
+    /// * `lazy` - Restore memory on demand.
+    fn load_memory(file: &mut File, header: &MigrationHeader, lazy: bool) -> Result<()> {
+        if header.format == FileFormat::MemoryChunked {
+            if lazy {
+                bail!("Lazy restore is not supported for snapshot with compressed memory");
+            }
+            let state_bytes = Self::load_header_data(file, header, "memory state")?;
+            let memory = Self::memory_instance()?;
+            memory
+                .pre_load(&state_bytes, None)
+                .chain_err(|| "Failed to load vm memory")?;
+            return load_memory_chunks(&mut BufReader::new(file), &memory);
+        }
+
+        let state_len = header.desc_len;
+        if state_len > MEMORY_MAPPED_ALIGN as usize {
+            bail!("Invalid length {} of memory state", state_len);
+        }
+        let mut state_bytes = [0_u8].repeat(state_len);
         file.read_exact(&mut state_bytes)?;
+        let stats = Arc::new(LazyRestoreStats::default());
         for (_, entry) in MIGRATION_MANAGER.entry.read().unwrap().iter() {
             if let MigrationEntry::Memory(i) = entry {
-                i.pre_load(&state_bytes, Some(file))
-                    .chain_err(|| "Failed to load vm memory")?;
+                if lazy {
+                    i.pre_load_lazy(&state_bytes, file, stats.clone())
+                        .chain_err(|| "Failed to load vm memory lazily")?;
+                } else {
+                    i.pre_load(&state_bytes, Some(file))
+                        .chain_err(|| "Failed to load vm memory")?;
+                }
             }
         }
+        if lazy {
+            *MIGRATION_MANAGER.lazy_stats.write().unwrap() = Some(stats);
+        }
 
         Ok(())
     }
@@ -220,7 +497,7 @@ impl MigrationManager {
     /// # Arguments
     ///
     /// * `writer` - The `Write` trait object.
-    fn save_device_state(writer: &mut dyn Write) -> Result<()> {
+    pub(crate) fn save_device_state(writer: &mut dyn Write) -> Result<()> {
         for (device_id, entry) in MIGRATION_MANAGER.entry.read().unwrap().iter() {
             match entry {
                 MigrationEntry::Safe(i) => i.pre_save(*device_id, writer)?,
@@ -238,7 +515,7 @@ impl MigrationManager {
     ///
     /// * `snap_desc_db` - The snapshot descriptor hashmap read from snapshot file.
     /// * `reader` - The `Read` trait object.
-    fn load_vmstate(
+    pub(crate) fn load_vmstate(
         snap_desc_db: HashMap<u64, DeviceStateDesc>,
         reader: &mut dyn Read,
     ) -> Result<()> {
@@ -287,7 +564,7 @@ impl MigrationManager {
 
     /// Resume recovered device.
     /// This function will be called after restore device state.
-    fn resume() -> Result<()> {
+    pub(crate) fn resume() -> Result<()> {
         for (_, entry) in MIGRATION_MANAGER.entry.read().unwrap().iter() {
             if let MigrationEntry::Mutex(i) = entry {
                 i.lock().unwrap().resume()?
@@ -296,3 +573,35 @@ impl MigrationManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_data() {
+        let data = b"device state".to_vec();
+        let mut file = Vec::new();
+        MigrationManager::save_header_with_data(FileFormat::Device, 0, &data, &mut file).unwrap();
+        assert_eq!(file.len(), HEADER_LENGTH + data.len());
+
+        let mut reader = file.as_slice();
+        let header = MigrationManager::load_header(&mut reader).unwrap();
+        assert_eq!(header.format, FileFormat::Device);
+        assert_eq!(
+            MigrationManager::load_header_data(&mut reader, &header, "state").unwrap(),
+            data
+        );
+
+        // Corrupted data.
+        file[HEADER_LENGTH] ^= 1;
+        let err = MigrationManager::load_header_data(&mut &file[HEADER_LENGTH..], &header, "state")
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::ChecksumMismatch(_)));
+        // Truncated data.
+        let err =
+            MigrationManager::load_header_data(&mut &file[HEADER_LENGTH + 1..], &header, "state")
+                .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::SnapshotTruncated(_)));
+    }
+}
@@ -14,9 +14,12 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex, Weak};
 
 use address_space::Region;
+use migration::MigrationManager;
 
 use super::config::{SECONDARY_BUS_NUM, SUBORDINATE_BUS_NUM};
+use super::hotplug::HotplugOps;
 use super::PciDevOps;
+use crate::errors::{Result, ResultExt};
 
 /// PCI bus structure.
 pub struct PciBus {
@@ -28,6 +31,8 @@ pub struct PciBus {
     pub child_buses: Vec<Arc<Mutex<PciBus>>>,
     /// Pci bridge which the bus orignates from.
     pub parent_bridge: Option<Weak<Mutex<dyn PciDevOps>>>,
+    /// Hotplug controller for the bus.
+    pub hotplug_controller: Option<Weak<Mutex<dyn HotplugOps>>>,
     /// IO region which the parent bridge manages.
     #[cfg(target_arch = "x86_64")]
     pub io_region: Region,
@@ -53,6 +58,7 @@ impl PciBus {
             devices: HashMap::new(),
             child_buses: Vec::new(),
             parent_bridge: None,
+            hotplug_controller: None,
             #[cfg(target_arch = "x86_64")]
             io_region,
             mem_region,
@@ -144,4 +150,58 @@ impl PciBus {
         }
         None
     }
+
+    /// Find the bus and the device by the device name.
+    ///
+    /// # Arguments
+    ///
+    /// * `pci_bus` - Bus to find from.
+    /// * `name` - Device name.
+    #[allow(clippy::type_complexity)]
+    pub fn find_attached_bus(
+        pci_bus: &Arc<Mutex<PciBus>>,
+        name: &str,
+    ) -> Option<(Arc<Mutex<PciBus>>, Arc<Mutex<dyn PciDevOps>>)> {
+        let locked_bus = pci_bus.lock().unwrap();
+        for dev in locked_bus.devices.values() {
+            if dev.lock().unwrap().name() == name {
+                return Some((pci_bus.clone(), dev.clone()));
+            }
+        }
+        for bus in &locked_bus.child_buses {
+            if let Some(found) = PciBus::find_attached_bus(bus, name) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    /// Unrealize the device and detach it from the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - Bus which the device is attached to.
+    /// * `dev` - Device to be detached.
+    pub fn detach_device(bus: &Arc<Mutex<Self>>, dev: &Arc<Mutex<dyn PciDevOps>>) -> Result<()> {
+        let mut locked_dev = dev.lock().unwrap();
+        let devfn = match locked_dev.devfn() {
+            Some(devfn) => devfn,
+            None => bail!("Failed to get devfn of device {}", locked_dev.name()),
+        };
+        locked_dev
+            .unrealize()
+            .chain_err(|| format!("Failed to unrealize device {}", locked_dev.name()))?;
+
+        let mut locked_bus = bus.lock().unwrap();
+        if locked_bus.devices.remove(&devfn).is_none() {
+            bail!(
+                "Device {} not found in bus {}",
+                locked_dev.name(),
+                locked_bus.name
+            );
+        }
+        drop(locked_dev);
+        MigrationManager::unregister_device_instance(dev);
+        Ok(())
+    }
 }
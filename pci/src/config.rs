@@ -158,7 +158,7 @@ const PCIE_CAP_CLS_X1: u16 = 0x0001;
 // Negotiated link width.
 const PCIE_CAP_NLW_2_5GT: u16 = 0x0010;
 // Data link layer link active
-const PCIE_CAP_LINK_DLLLA: u16 = 0x2000;
+pub const PCIE_CAP_LINK_DLLLA: u16 = 0x2000;
 // Attention button present.
 const PCIE_CAP_SLOTCAP_ABP: u32 = 0x0000_0001;
 // Power controller present.
@@ -178,20 +178,27 @@ const PCIE_CAP_SLOT_NUM_SHIFT: u32 = 19;
 const PCIE_CAP_SLOT_AIC_MASK: u16 = 0x00c0;
 const PCIE_CAP_SLOT_AIC_OFF: u16 = 0x00c0;
 // Power Indicator Control.
-const PCIE_CAP_SLOT_PIC_MASK: u16 = 0x0300;
-const PCIE_CAP_SLOT_PIC_OFF: u16 = 0x0300;
+pub const PCIE_CAP_SLOT_PIC_MASK: u16 = 0x0300;
+pub const PCIE_CAP_SLOT_PIC_ON: u16 = 0x0100;
+pub const PCIE_CAP_SLOT_PIC_OFF: u16 = 0x0300;
 // Attention button pressed enable.
-const PCIE_CAP_SLOT_ABP: u16 = 0x0001;
+pub const PCIE_CAP_SLOT_ABP: u16 = 0x0001;
 // Presence detect changed enable.
-const PCIE_CAP_SLOT_PDC: u16 = 0x0008;
+pub const PCIE_CAP_SLOT_PDC: u16 = 0x0008;
 // Command completed interrupt enable.
-const PCIE_CAP_SLOT_CCI: u16 = 0x0010;
+pub const PCIE_CAP_SLOT_CCI: u16 = 0x0010;
 // Hot-Plug interrupt enable.
-const PCIE_CAP_SLOT_HPI: u16 = 0x0020;
+pub const PCIE_CAP_SLOT_HPI: u16 = 0x0020;
 // Power controller control.
-const PCIE_CAP_SLOT_PCC: u16 = 0x0400;
+pub const PCIE_CAP_SLOT_PCC: u16 = 0x0400;
 // Electromechanical interlock control.
 const PCIE_CAP_SLOT_EIC: u16 = 0x0800;
+// Data link layer state changed enable.
+pub const PCIE_CAP_SLOT_DLLSCE: u16 = 0x1000;
+// Presence detect state.
+pub const PCIE_CAP_SLOT_PDS: u16 = 0x0040;
+// Data link layer state changed.
+pub const PCIE_CAP_SLOT_DLLSC: u16 = 0x0100;
 // System error on correctable error enable.
 const PCIE_CAP_ROOT_SECEE: u16 = 0x01;
 // System error on non-fatal error enable.
@@ -238,7 +245,7 @@ pub enum CapId {
 }
 
 /// Offset of registers in PCIe capability register.
-enum PcieCap {
+pub enum PcieCap {
     CapReg = 0x02,
     DevCap = 0x04,
     DevCtl = 0x08,
@@ -572,6 +579,37 @@ impl PciConfig {
         Ok(())
     }
 
+    /// Unmap all the mapped BARs, usually called when the device is removed from the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `io_region`: IO space region which the parent bridge manages.
+    /// * `mem_region`: Memory space region which the parent bridge manages.
+    pub fn unregister_bars(
+        &mut self,
+        #[cfg(target_arch = "x86_64")] io_region: &Region,
+        mem_region: &Region,
+    ) -> Result<()> {
+        for id in 0..self.bars.len() {
+            if self.bars[id].size == 0 || self.bars[id].address == BAR_SPACE_UNMAPPED {
+                continue;
+            }
+            match self.bars[id].region_type {
+                RegionType::Io => {
+                    #[cfg(target_arch = "x86_64")]
+                    io_region
+                        .delete_subregion(self.bars[id].region.as_ref().unwrap())
+                        .chain_err(|| format!("Failed to unmap BAR{} in I/O space.", id))?;
+                }
+                _ => mem_region
+                    .delete_subregion(self.bars[id].region.as_ref().unwrap())
+                    .chain_err(|| ErrorKind::UnregMemBar(id))?,
+            }
+            self.bars[id].address = BAR_SPACE_UNMAPPED;
+        }
+        Ok(())
+    }
+
     /// Add a pci standard capability in the configuration space.
     ///
     /// # Arguments
@@ -715,13 +753,14 @@ impl PciConfig {
                 | PCIE_CAP_SLOT_AIC_MASK
                 | PCIE_CAP_SLOT_PIC_MASK
                 | PCIE_CAP_SLOT_PCC
-                | PCIE_CAP_SLOT_EIC,
+                | PCIE_CAP_SLOT_EIC
+                | PCIE_CAP_SLOT_DLLSCE,
         )?;
         offset = cap_offset + PcieCap::SlotStat as usize;
         le_write_u16(
             &mut self.write_clear_mask,
             offset,
-            PCIE_CAP_SLOT_ABP | PCIE_CAP_SLOT_PDC | PCIE_CAP_SLOT_CCI,
+            PCIE_CAP_SLOT_ABP | PCIE_CAP_SLOT_PDC | PCIE_CAP_SLOT_CCI | PCIE_CAP_SLOT_DLLSC,
         )?;
 
         offset = cap_offset + PcieCap::RootCtl as usize;
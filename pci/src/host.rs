@@ -24,7 +24,11 @@ use acpi::{
     AmlToUuid,
 };
 use address_space::{AddressSpace, GuestAddress, RegionOps};
+#[cfg(target_arch = "x86_64")]
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use sysbus::SysBusDevOps;
+#[cfg(target_arch = "x86_64")]
+use util::byte_code::ByteCode;
 
 use crate::{bus::PciBus, PciDevOps};
 #[cfg(target_arch = "x86_64")]
@@ -48,6 +52,16 @@ const ECAM_DEVFN_SHIFT: u32 = 12;
 #[allow(dead_code)]
 const ECAM_OFFSET_MASK: u64 = 0xfff;
 
+/// Status of PCI host bridge.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct PciHostState {
+    /// Value of CONFIG_ADDR port.
+    config_addr: u32,
+}
+
 #[derive(Clone)]
 pub struct PciHost {
     pub root_bus: Arc<Mutex<PciBus>>,
@@ -353,6 +367,36 @@ impl AmlBuilder for PciHost {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+impl StateTransfer for PciHost {
+    fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
+        let state = PciHostState {
+            config_addr: self.config_addr,
+        };
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
+        let host_state = PciHostState::from_bytes(state)
+            .ok_or(migration::errors::ErrorKind::FromBytesError("PCI_HOST"))?;
+        self.config_addr = host_state.config_addr;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&PciHostState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MigrationHook for PciHost {}
+
 #[cfg(test)]
 pub mod tests {
     use std::sync::Weak;
@@ -0,0 +1,75 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use crate::errors::Result;
+use crate::{PciBus, PciDevOps};
+
+/// Callback invoked once the unplugged device is removed from the bus.
+pub type UnplugCallback = Box<dyn FnOnce() + Send + Sync>;
+
+pub trait HotplugOps: Send {
+    /// Plug device, usually called when the device is realized on the bus.
+    fn plug(&mut self, dev: &Arc<Mutex<dyn PciDevOps>>) -> Result<()>;
+
+    /// Unplug request, usually called when the device is deleted by QMP. The device
+    /// is removed from the bus after the guest acknowledges the request, and then
+    /// `on_removed` is called.
+    fn unplug_request(
+        &mut self,
+        dev: &Arc<Mutex<dyn PciDevOps>>,
+        on_removed: UnplugCallback,
+    ) -> Result<()>;
+}
+
+/// Plug the device into the hotplug controller of the bus, if any.
+///
+/// # Arguments
+///
+/// * `bus` - Bus which the device is attached to.
+/// * `dev` - The plugged device.
+pub fn handle_plug(bus: &Arc<Mutex<PciBus>>, dev: &Arc<Mutex<dyn PciDevOps>>) -> Result<()> {
+    let hpc = bus.lock().unwrap().hotplug_controller.clone();
+    if let Some(hpc) = hpc.and_then(|hpc| hpc.upgrade()) {
+        hpc.lock().unwrap().plug(dev)?;
+    }
+    Ok(())
+}
+
+/// Send the unplug request of the device to the hotplug controller of the bus.
+///
+/// # Arguments
+///
+/// * `bus` - Bus which the device is attached to.
+/// * `dev` - The device to be unplugged.
+/// * `on_removed` - Called once the device is removed from the bus.
+///
+/// # Errors
+///
+/// Return Error if the bus does not support hotplug.
+pub fn handle_unplug_request(
+    bus: &Arc<Mutex<PciBus>>,
+    dev: &Arc<Mutex<dyn PciDevOps>>,
+    on_removed: UnplugCallback,
+) -> Result<()> {
+    let locked_bus = bus.lock().unwrap();
+    let hpc = locked_bus.hotplug_controller.clone();
+    let bus_name = locked_bus.name.clone();
+    drop(locked_bus);
+
+    if let Some(hpc) = hpc.and_then(|hpc| hpc.upgrade()) {
+        hpc.lock().unwrap().unplug_request(dev, on_removed)
+    } else {
+        bail!("No hotplug controller found for bus {}", bus_name);
+    }
+}
@@ -15,6 +15,8 @@ extern crate error_chain;
 #[macro_use]
 extern crate log;
 #[macro_use]
+extern crate machine_manager;
+#[macro_use]
 extern crate migration_derive;
 
 pub mod errors {
@@ -42,12 +44,15 @@ pub mod errors {
 mod bus;
 pub mod config;
 mod host;
+pub mod hotplug;
 pub mod msix;
 mod root_port;
 
 pub use bus::PciBus;
 use config::{HEADER_TYPE, HEADER_TYPE_MULTIFUNC, MAX_FUNC};
 pub use host::PciHost;
+#[cfg(target_arch = "x86_64")]
+pub use host::PciHostState;
 pub use msix::init_msix;
 pub use root_port::RootPort;
 
@@ -164,6 +169,16 @@ pub trait PciDevOps: Send {
 
     /// Get device name.
     fn name(&self) -> String;
+
+    /// Get device devfn, `None` if the device does not expose it.
+    fn devfn(&self) -> Option<u8> {
+        None
+    }
+
+    /// Unrealize PCI/PCIe device, usually called when the device is hot unplugged.
+    fn unrealize(&mut self) -> Result<()> {
+        bail!("Unrealize of the pci device is not implemented");
+    }
 }
 
 /// Init multifunction for pci devices.
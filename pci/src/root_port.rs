@@ -10,25 +10,30 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
 use address_space::Region;
 use error_chain::ChainedError;
+use machine_manager::qmp::{qmp_schema as schema, QmpChannel};
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use util::byte_code::ByteCode;
 
 use super::config::{
-    PciConfig, PcieDevType, BAR_0, CLASS_CODE_PCI_BRIDGE, COMMAND, COMMAND_IO_SPACE,
+    PciConfig, PcieCap, PcieDevType, BAR_0, CLASS_CODE_PCI_BRIDGE, COMMAND, COMMAND_IO_SPACE,
     COMMAND_MEMORY_SPACE, DEVICE_ID, HEADER_TYPE, HEADER_TYPE_BRIDGE, IO_BASE, MEMORY_BASE,
+    PCIE_CAP_LINK_DLLLA, PCIE_CAP_SLOT_ABP, PCIE_CAP_SLOT_CCI, PCIE_CAP_SLOT_DLLSC,
+    PCIE_CAP_SLOT_DLLSCE, PCIE_CAP_SLOT_HPI, PCIE_CAP_SLOT_PCC, PCIE_CAP_SLOT_PDC,
+    PCIE_CAP_SLOT_PDS, PCIE_CAP_SLOT_PIC_MASK, PCIE_CAP_SLOT_PIC_OFF, PCIE_CAP_SLOT_PIC_ON,
     PCIE_CONFIG_SPACE_SIZE, PCI_VENDOR_ID_REDHAT, PREF_MEMORY_BASE, PREF_MEMORY_LIMIT,
-    PREF_MEM_RANGE_64BIT, REG_SIZE, SUB_CLASS_CODE, VENDOR_ID,
+    PREF_MEM_RANGE_64BIT, REG_SIZE, SECONDARY_BUS_NUM, SUB_CLASS_CODE, VENDOR_ID,
 };
 use crate::bus::PciBus;
 use crate::errors::{Result, ResultExt};
+use crate::hotplug::{HotplugOps, UnplugCallback};
 use crate::init_multifunction;
-use crate::msix::init_msix;
-use crate::{le_read_u16, le_write_u16, ranges_overlap, PciDevOps};
+use crate::msix::{init_msix, is_msix_enabled};
+use crate::{le_read_u16, le_write_u16, pci_func, pci_slot, ranges_overlap, PciDevOps};
 
 const DEVICE_ID_RP: u16 = 0x000c;
 
@@ -56,8 +61,12 @@ pub struct RootPort {
     #[cfg(target_arch = "x86_64")]
     io_region: Region,
     mem_region: Region,
-    dev_id: u16,
+    dev_id: Arc<AtomicU16>,
     multifunction: bool,
+    /// Offset of the PCIe capability in configuration space.
+    pcie_cap_offset: usize,
+    /// Callbacks of the pending unplug requests, called once the devices are removed.
+    unplug_callbacks: Vec<UnplugCallback>,
 }
 
 impl RootPort {
@@ -97,8 +106,124 @@ impl RootPort {
             #[cfg(target_arch = "x86_64")]
             io_region,
             mem_region,
-            dev_id: 0,
+            dev_id: Arc::new(AtomicU16::new(0)),
             multifunction,
+            pcie_cap_offset: 0,
+            unplug_callbacks: Vec::new(),
+        }
+    }
+
+    fn read_slot_reg(&self, reg: PcieCap) -> u16 {
+        le_read_u16(&self.config.config, self.pcie_cap_offset + reg as usize).unwrap()
+    }
+
+    fn write_slot_reg(&mut self, reg: PcieCap, value: u16) {
+        le_write_u16(
+            &mut self.config.config,
+            self.pcie_cap_offset + reg as usize,
+            value,
+        )
+        .unwrap();
+    }
+
+    /// Send the hotplug interrupt to the guest if any enabled slot event is pending.
+    fn hotplug_event_notify(&mut self) {
+        let slot_ctl = self.read_slot_reg(PcieCap::SlotCtl);
+        if slot_ctl & PCIE_CAP_SLOT_HPI == 0 {
+            return;
+        }
+
+        let slot_status = self.read_slot_reg(PcieCap::SlotStat);
+        let mut events =
+            slot_status & slot_ctl & (PCIE_CAP_SLOT_ABP | PCIE_CAP_SLOT_PDC | PCIE_CAP_SLOT_CCI);
+        if slot_ctl & PCIE_CAP_SLOT_DLLSCE != 0 {
+            events |= slot_status & PCIE_CAP_SLOT_DLLSC;
+        }
+        if events == 0 {
+            return;
+        }
+
+        if let Some(msix) = &self.config.msix {
+            let mut locked_msix = msix.lock().unwrap();
+            if is_msix_enabled(locked_msix.msix_cap_offset as usize, &self.config.config) {
+                locked_msix.notify(0, self.dev_id.load(Ordering::Acquire));
+            }
+        }
+    }
+
+    /// Set the presence of the slot, and report the change to the guest if `notify` is true.
+    fn set_slot_presence(&mut self, present: bool, notify: bool) {
+        let mut slot_status = self.read_slot_reg(PcieCap::SlotStat);
+        let mut link_status = self.read_slot_reg(PcieCap::LinkStat);
+        if present {
+            slot_status |= PCIE_CAP_SLOT_PDS;
+            link_status |= PCIE_CAP_LINK_DLLLA;
+        } else {
+            slot_status &= !PCIE_CAP_SLOT_PDS;
+            link_status &= !PCIE_CAP_LINK_DLLLA;
+        }
+        if notify {
+            slot_status |= PCIE_CAP_SLOT_PDC | PCIE_CAP_SLOT_DLLSC;
+        }
+        self.write_slot_reg(PcieCap::SlotStat, slot_status);
+        self.write_slot_reg(PcieCap::LinkStat, link_status);
+        if notify {
+            self.hotplug_event_notify();
+        }
+    }
+
+    /// Remove all the devices on the secondary bus, and report DEVICE_DELETED event.
+    fn remove_devices(&mut self) -> Result<()> {
+        let devices: Vec<Arc<Mutex<dyn PciDevOps>>> = self
+            .sec_bus
+            .lock()
+            .unwrap()
+            .devices
+            .values()
+            .cloned()
+            .collect();
+        let mut names = Vec::new();
+        for dev in devices.iter() {
+            names.push(dev.lock().unwrap().name());
+            PciBus::detach_device(&self.sec_bus, dev)?;
+        }
+        for callback in self.unplug_callbacks.drain(..) {
+            callback();
+        }
+        for name in names {
+            let device_deleted = schema::DeviceDeleted {
+                device: Some(name.clone()),
+                path: format!("/machine/peripheral/{}", name),
+            };
+            event!(DeviceDeleted; device_deleted);
+        }
+        self.set_slot_presence(false, true);
+        Ok(())
+    }
+
+    /// Remove the devices once the guest powers off the slot, which completes the unplug
+    /// requested by pressing the attention button.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_ctl` - Value of slot control register before written by the guest.
+    fn do_unplug(&mut self, old_ctl: u16) {
+        let slot_ctl = self.read_slot_reg(PcieCap::SlotCtl);
+        let slot_status = self.read_slot_reg(PcieCap::SlotStat);
+        let is_powered_off = |ctl: u16| {
+            ctl & PCIE_CAP_SLOT_PCC != 0 && ctl & PCIE_CAP_SLOT_PIC_MASK == PCIE_CAP_SLOT_PIC_OFF
+        };
+        if slot_status & PCIE_CAP_SLOT_PDS != 0
+            && is_powered_off(slot_ctl)
+            && !is_powered_off(old_ctl)
+        {
+            if let Err(e) = self.remove_devices() {
+                error!(
+                    "Failed to unplug devices from {}, error is {}",
+                    self.name,
+                    e.display_chain()
+                );
+            }
         }
     }
 }
@@ -131,13 +256,20 @@ impl PciDevOps for RootPort {
             self.devfn,
             self.parent_bus.clone(),
         )?;
-        self.config
-            .add_pcie_cap(self.devfn, self.port_num, PcieDevType::RootPort as u8)?;
+        self.pcie_cap_offset =
+            self.config
+                .add_pcie_cap(self.devfn, self.port_num, PcieDevType::RootPort as u8)?;
 
-        init_msix(0, 1, &mut self.config, Arc::new(AtomicU16::new(0)))?;
+        // The slot is empty until a device is plugged.
+        self.set_slot_presence(false, false);
+
+        init_msix(0, 1, &mut self.config, self.dev_id.clone())?;
 
         let parent_bus = self.parent_bus.upgrade().unwrap();
         let mut locked_parent_bus = parent_bus.lock().unwrap();
+        let bus_num = locked_parent_bus.number(SECONDARY_BUS_NUM as usize);
+        self.dev_id
+            .store(self.set_dev_id(bus_num, self.devfn), Ordering::Release);
         #[cfg(target_arch = "x86_64")]
         locked_parent_bus
             .io_region
@@ -153,6 +285,8 @@ impl PciDevOps for RootPort {
         let mut locked_root_port = root_port.lock().unwrap();
         locked_root_port.sec_bus.lock().unwrap().parent_bridge =
             Some(Arc::downgrade(&root_port) as Weak<Mutex<dyn PciDevOps>>);
+        locked_root_port.sec_bus.lock().unwrap().hotplug_controller =
+            Some(Arc::downgrade(&root_port) as Weak<Mutex<dyn HotplugOps>>);
         let pci_device = locked_parent_bus.devices.get(&locked_root_port.devfn);
         if pci_device.is_none() {
             locked_parent_bus
@@ -199,7 +333,11 @@ impl PciDevOps for RootPort {
             return;
         }
 
-        self.config.write(offset, data, self.dev_id);
+        let cap_offset = self.pcie_cap_offset;
+        let old_ctl = self.read_slot_reg(PcieCap::SlotCtl);
+
+        self.config
+            .write(offset, data, self.dev_id.load(Ordering::Acquire));
         if ranges_overlap(offset, end, COMMAND as usize, (COMMAND + 1) as usize)
             || ranges_overlap(offset, end, BAR_0 as usize, BAR_0 as usize + REG_SIZE * 2)
         {
@@ -251,11 +389,84 @@ impl PciDevOps for RootPort {
                 }
             }
         }
+        if ranges_overlap(
+            offset,
+            end,
+            cap_offset + PcieCap::SlotCtl as usize,
+            cap_offset + PcieCap::SlotCtl as usize + 2,
+        ) {
+            self.do_unplug(old_ctl);
+            // Every write to slot control register is considered as a completed command.
+            let slot_status = self.read_slot_reg(PcieCap::SlotStat);
+            self.write_slot_reg(PcieCap::SlotStat, slot_status | PCIE_CAP_SLOT_CCI);
+            self.hotplug_event_notify();
+        }
     }
 
     fn name(&self) -> String {
         self.name.clone()
     }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+}
+
+impl HotplugOps for RootPort {
+    fn plug(&mut self, dev: &Arc<Mutex<dyn PciDevOps>>) -> Result<()> {
+        let devfn = match dev.lock().unwrap().devfn() {
+            Some(devfn) => devfn,
+            None => bail!("Failed to get devfn"),
+        };
+        // Only slot 0 is valid for the downstream of pcie root port.
+        if pci_slot(devfn) != 0 {
+            bail!(
+                "Slot {} is not valid for {}, only slot 0 is supported",
+                pci_slot(devfn),
+                self.name
+            );
+        }
+        // The presence is reported once function 0 is plugged.
+        if pci_func(devfn) != 0 {
+            return Ok(());
+        }
+
+        let slot_ctl = self.read_slot_reg(PcieCap::SlotCtl);
+        if slot_ctl & PCIE_CAP_SLOT_HPI == 0 {
+            // The guest does not handle hotplug event yet, so the device is considered
+            // as cold plugged and the slot is powered on directly.
+            let slot_ctl =
+                (slot_ctl & !PCIE_CAP_SLOT_PCC & !PCIE_CAP_SLOT_PIC_MASK) | PCIE_CAP_SLOT_PIC_ON;
+            self.write_slot_reg(PcieCap::SlotCtl, slot_ctl);
+            self.set_slot_presence(true, false);
+        } else {
+            self.set_slot_presence(true, true);
+        }
+        Ok(())
+    }
+
+    fn unplug_request(
+        &mut self,
+        dev: &Arc<Mutex<dyn PciDevOps>>,
+        on_removed: UnplugCallback,
+    ) -> Result<()> {
+        let name = dev.lock().unwrap().name();
+        let slot_status = self.read_slot_reg(PcieCap::SlotStat);
+        if slot_status & PCIE_CAP_SLOT_PDS == 0 {
+            bail!("No device {} is plugged in {}", name, self.name);
+        }
+        self.unplug_callbacks.push(on_removed);
+
+        let slot_ctl = self.read_slot_reg(PcieCap::SlotCtl);
+        if slot_ctl & PCIE_CAP_SLOT_PCC != 0 {
+            // The slot is powered off, so the device is not used by the guest.
+            return self.remove_devices();
+        }
+
+        self.write_slot_reg(PcieCap::SlotStat, slot_status | PCIE_CAP_SLOT_ABP);
+        self.hotplug_event_notify();
+        Ok(())
+    }
 }
 
 impl StateTransfer for RootPort {
@@ -298,12 +509,84 @@ impl StateTransfer for RootPort {
     }
 }
 
-impl MigrationHook for RootPort {}
+impl MigrationHook for RootPort {
+    fn resume(&mut self) -> migration::errors::Result<()> {
+        // Map the BARs according to the restored config space.
+        if let Err(e) = self.config.update_bar_mapping(
+            #[cfg(target_arch = "x86_64")]
+            &self.io_region,
+            &self.mem_region,
+        ) {
+            bail!("Failed to update bar, error is {}", e.display_chain());
+        }
+
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::host::tests::create_pci_host;
+    use crate::le_write_u32;
+    use address_space::{AddressSpace, GuestAddress};
+    use std::sync::atomic::AtomicBool;
+
+    struct TestPciDevice {
+        devfn: u8,
+    }
+
+    impl PciDevOps for TestPciDevice {
+        fn init_write_mask(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn init_write_clear_mask(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_config(&self, _offset: usize, _data: &mut [u8]) {}
+
+        fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+
+        fn name(&self) -> String {
+            "test-dev".to_string()
+        }
+
+        fn realize(self) -> Result<()> {
+            Ok(())
+        }
+
+        fn devfn(&self) -> Option<u8> {
+            Some(self.devfn)
+        }
+
+        fn unrealize(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_root_port() -> RootPort {
+        let pci_host = create_pci_host();
+        let root_bus = Arc::downgrade(&pci_host.lock().unwrap().root_bus);
+        let mut root_port = RootPort::new("pcie.1".to_string(), 8, 0, root_bus, false);
+        root_port.pcie_cap_offset = root_port
+            .config
+            .add_pcie_cap(8, 0, PcieDevType::RootPort as u8)
+            .unwrap();
+        root_port
+    }
+
+    fn attach_device(root_port: &RootPort, devfn: u8) -> Arc<Mutex<dyn PciDevOps>> {
+        let dev: Arc<Mutex<dyn PciDevOps>> = Arc::new(Mutex::new(TestPciDevice { devfn }));
+        root_port
+            .sec_bus
+            .lock()
+            .unwrap()
+            .devices
+            .insert(devfn, dev.clone());
+        dev
+    }
 
     #[test]
     fn test_read_config() {
@@ -342,4 +625,81 @@ mod tests {
             .read_config(PCIE_CONFIG_SPACE_SIZE - 1, &mut buf);
         assert_eq!(buf, [0_u8]);
     }
+
+    #[test]
+    fn test_hotplug() {
+        let mut root_port = create_root_port();
+        let dev = attach_device(&root_port, 8);
+        assert!(root_port.plug(&dev).is_err());
+
+        // The slot is powered on directly if the guest has not enabled hotplug interrupt.
+        let dev = attach_device(&root_port, 0);
+        root_port.plug(&dev).unwrap();
+        let slot_status = root_port.read_slot_reg(PcieCap::SlotStat);
+        assert_ne!(slot_status & PCIE_CAP_SLOT_PDS, 0);
+        assert_eq!(slot_status & PCIE_CAP_SLOT_PDC, 0);
+        let slot_ctl = root_port.read_slot_reg(PcieCap::SlotCtl);
+        assert_eq!(slot_ctl & PCIE_CAP_SLOT_PCC, 0);
+        assert_eq!(slot_ctl & PCIE_CAP_SLOT_PIC_MASK, PCIE_CAP_SLOT_PIC_ON);
+        let link_status = root_port.read_slot_reg(PcieCap::LinkStat);
+        assert_ne!(link_status & PCIE_CAP_LINK_DLLLA, 0);
+    }
+
+    #[test]
+    fn test_unplug() {
+        QmpChannel::object_init();
+        let mut root_port = create_root_port();
+        let dev = attach_device(&root_port, 0);
+        assert!(root_port.unplug_request(&dev, Box::new(|| {})).is_err());
+        root_port.plug(&dev).unwrap();
+
+        // Unplug request presses the attention button, the device is kept until the guest
+        // powers off the slot.
+        let removed = Arc::new(AtomicBool::new(false));
+        let removed_clone = removed.clone();
+        root_port
+            .unplug_request(
+                &dev,
+                Box::new(move || removed_clone.store(true, Ordering::SeqCst)),
+            )
+            .unwrap();
+        assert!(!removed.load(Ordering::SeqCst));
+        let slot_status = root_port.read_slot_reg(PcieCap::SlotStat);
+        assert_ne!(slot_status & PCIE_CAP_SLOT_ABP, 0);
+        assert_eq!(root_port.sec_bus.lock().unwrap().devices.len(), 1);
+
+        let slot_ctl = root_port.read_slot_reg(PcieCap::SlotCtl) | PCIE_CAP_SLOT_PCC;
+        let slot_ctl = (slot_ctl & !PCIE_CAP_SLOT_PIC_MASK) | PCIE_CAP_SLOT_PIC_OFF;
+        let offset = root_port.pcie_cap_offset + PcieCap::SlotCtl as usize;
+        root_port.write_config(offset, &slot_ctl.to_le_bytes());
+        assert!(root_port.sec_bus.lock().unwrap().devices.is_empty());
+        assert!(removed.load(Ordering::SeqCst));
+        let slot_status = root_port.read_slot_reg(PcieCap::SlotStat);
+        assert_eq!(slot_status & PCIE_CAP_SLOT_PDS, 0);
+        assert_ne!(slot_status & PCIE_CAP_SLOT_CCI, 0);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut src_port = create_root_port();
+        init_msix(0, 1, &mut src_port.config, src_port.dev_id.clone()).unwrap();
+        le_write_u32(&mut src_port.config.config, BAR_0 as usize, 0x1000_0000).unwrap();
+        le_write_u16(
+            &mut src_port.config.config,
+            COMMAND as usize,
+            COMMAND_MEMORY_SPACE,
+        )
+        .unwrap();
+        let state = src_port.get_state_vec().unwrap();
+
+        let mut dst_port = create_root_port();
+        init_msix(0, 1, &mut dst_port.config, dst_port.dev_id.clone()).unwrap();
+        dst_port.set_state_mut(&state).unwrap();
+        let space = AddressSpace::new(dst_port.mem_region.clone()).unwrap();
+        assert!(space.read_object::<u32>(GuestAddress(0x1000_0000)).is_err());
+
+        // The MSI-X BAR is mapped after resumed.
+        dst_port.resume().unwrap();
+        assert!(space.read_object::<u32>(GuestAddress(0x1000_0000)).is_ok());
+    }
 }
@@ -136,13 +136,22 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
     register_kill_signal();
 
     let listeners = check_api_channel(&cmd_args, vm_config)?;
+    let incoming = match cmd_args.value_of("incoming") {
+        Some(uri) => Some(parse_uri(&uri)?),
+        None => None,
+    };
+    // Guest memory is mapped from snapshot file, so it isn't created when VM is realized.
+    let is_snapshot = matches!(incoming, Some((UnixPath::File, _)));
+    if cmd_args.is_present("lazy-restore") && !is_snapshot {
+        bail!("-lazy-restore must be used with -incoming file:/path together.");
+    }
     let mut sockets = Vec::new();
     let vm: Arc<Mutex<dyn MachineOps + Send + Sync>> = match vm_config.machine_config.mach_type {
         MachineType::MicroVm => {
             let vm = Arc::new(Mutex::new(
                 LightMachine::new(&vm_config).chain_err(|| "Failed to init MicroVM")?,
             ));
-            MachineOps::realize(&vm, vm_config, cmd_args.is_present("incoming"))
+            MachineOps::realize(&vm, vm_config, is_snapshot)
                 .chain_err(|| "Failed to realize micro VM.")?;
             EventLoop::set_manager(vm.clone(), None);
 
@@ -155,7 +164,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
             let vm = Arc::new(Mutex::new(
                 StdMachine::new(&vm_config).chain_err(|| "Failed to init StandardVM")?,
             ));
-            MachineOps::realize(&vm, vm_config, cmd_args.is_present("incoming"))
+            MachineOps::realize(&vm, vm_config, is_snapshot)
                 .chain_err(|| "Failed to realize standard VM.")?;
             EventLoop::set_manager(vm.clone(), None);
 
@@ -176,13 +185,41 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
         }
     };
 
-    if let Some(uri) = cmd_args.value_of("incoming") {
-        if let (UnixPath::File, path) = parse_uri(&uri)? {
-            migration::MigrationManager::restore_snapshot(&path)
-                .chain_err(|| "Failed to start with incoming migration.")?;
-        } else {
-            bail!("Unsupported incoming unix path type.")
+    let paused = cmd_args.is_present("freeze_cpu");
+    let seccomp = !cmd_args.is_present("disable-seccomp");
+    let balloon_switch_on = vm_config.dev_name.get("balloon").is_some();
+    let mut live_incoming = false;
+    match incoming {
+        Some((UnixPath::File, path)) => {
+            migration::MigrationManager::restore_snapshot(
+                &path,
+                cmd_args.is_present("lazy-restore"),
+            )
+            .chain_err(|| "Failed to start with incoming migration.")?;
         }
+        Some((path_type, addr)) => {
+            // VM is started after its states are received in the migration
+            // thread, so that QMP is served during live migration.
+            let incoming_vm = vm.clone();
+            migration::MigrationManager::recv_migration(
+                path_type,
+                &addr,
+                Box::new(move |ret| {
+                    if let Err(ref e) = ret
+                        .chain_err(|| "Failed to receive incoming live migration.")
+                        .and_then(|_| start_vm(&incoming_vm, paused, seccomp, balloon_switch_on))
+                    {
+                        set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
+                        error!("{}", error_chain::ChainedError::display_chain(e));
+                        TempCleaner::clean();
+                        exit_with_code(VM_EXIT_GENE_ERR);
+                    }
+                }),
+            )
+            .chain_err(|| "Failed to start with incoming live migration.")?;
+            live_incoming = true;
+        }
+        None => {}
     }
 
     for socket in sockets {
@@ -193,19 +230,33 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
         .chain_err(|| "Failed to add api event to MainLoop")?;
     }
 
+    if !live_incoming {
+        start_vm(&vm, paused, seccomp, balloon_switch_on)?;
+    }
+
+    EventLoop::loop_run().chain_err(|| "MainLoop exits unexpectedly: error occurs")?;
+    Ok(())
+}
+
+/// Run vcpus of VM and register seccomp rules, the latter is done after all the
+/// setup of VM which needs syscalls out of the rules.
+fn start_vm(
+    vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>,
+    paused: bool,
+    seccomp: bool,
+    balloon_switch_on: bool,
+) -> Result<()> {
     vm.lock()
         .unwrap()
-        .run(cmd_args.is_present("freeze_cpu"))
+        .run(paused)
         .chain_err(|| "Failed to start VM.")?;
 
-    let balloon_switch_on = vm_config.dev_name.get("balloon").is_some();
-    if !cmd_args.is_present("disable-seccomp") {
+    if seccomp {
         vm.lock()
             .unwrap()
             .register_seccomp(balloon_switch_on)
             .chain_err(|| "Failed to register seccomp rules.")?;
     }
 
-    EventLoop::loop_run().chain_err(|| "MainLoop exits unexpectedly: error occurs")?;
     Ok(())
 }
@@ -12,27 +12,57 @@
 
 mod libaio;
 mod raw;
+mod uring;
 
 use std::clone::Clone;
 use std::marker::{Send, Sync};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
 use vmm_sys_util::eventfd::EventFd;
 
 use super::errors::Result;
 use super::link_list::{List, Node};
 pub use libaio::*;
 pub use raw::*;
+use uring::IoUringContext;
 
 type CbList<T> = List<AioCb<T>>;
 type CbNode<T> = Node<AioCb<T>>;
 
 pub type AioCompleteFunc<T> = Box<dyn Fn(&AioCb<T>, i64) + Sync + Send>;
 
+/// Engine to process the asynchronous IO requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AioEngine {
+    /// Process the requests synchronously in the IO thread.
+    Threads,
+    /// Linux native aio, only available for files opened with `O_DIRECT`.
+    Native,
+    /// Linux io_uring.
+    IoUring,
+}
+
+impl FromStr for AioEngine {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(AioEngine::Threads),
+            "native" => Ok(AioEngine::Native),
+            "io_uring" => Ok(AioEngine::IoUring),
+            _ => Err(()),
+        }
+    }
+}
+
 pub struct AioCb<T: Clone> {
     pub last_aio: bool,
     pub file_fd: RawFd,
+    /// Whether the file is opened with `O_DIRECT`.
+    pub direct: bool,
     pub opcode: IoCmd,
     pub iovec: Vec<Iovec>,
     pub offset: usize,
@@ -46,6 +76,7 @@ impl<T: Clone> AioCb<T> {
         AioCb {
             last_aio: true,
             file_fd: 0,
+            direct: false,
             opcode: IoCmd::Noop,
             iovec: Vec::new(),
             offset: 0,
@@ -57,7 +88,13 @@ impl<T: Clone> AioCb<T> {
 }
 
 pub struct Aio<T: Clone + 'static> {
-    pub ctx: Arc<LibaioContext>,
+    /// Context of linux native aio, created when `Native` engine is used.
+    pub ctx: Option<Arc<LibaioContext>>,
+    /// Context of io_uring, created when `IoUring` engine is used.
+    uring: Option<IoUringContext>,
+    /// Engine used to process the new requests.
+    engine: AioEngine,
+    /// Eventfd notified when requests complete, shared by all engines.
     pub fd: EventFd,
     pub aio_in_queue: CbList<T>,
     pub aio_in_flight: CbList<T>,
@@ -66,75 +103,169 @@ pub struct Aio<T: Clone + 'static> {
 }
 
 impl<T: Clone + 'static> Aio<T> {
-    pub fn new(func: Arc<AioCompleteFunc<T>>) -> Result<Self> {
+    pub fn new(func: Arc<AioCompleteFunc<T>>, engine: AioEngine) -> Result<Self> {
         let max_events = 128;
 
-        Ok(Aio {
-            ctx: Arc::new(LibaioContext::new(max_events as i32)?),
+        let mut aio = Aio {
+            ctx: None,
+            uring: None,
+            engine: AioEngine::Threads,
             fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
             aio_in_queue: List::new(),
             aio_in_flight: List::new(),
             max_events,
             complete_func: func,
-        })
+        };
+        aio.set_engine(engine)?;
+
+        Ok(aio)
+    }
+
+    /// Get the engine used to process the new requests.
+    pub fn engine(&self) -> AioEngine {
+        self.engine
+    }
+
+    /// Switch the engine used to process the new requests, the requests in flight
+    /// are still completed by the previous engine.
+    pub fn set_engine(&mut self, engine: AioEngine) -> Result<()> {
+        match engine {
+            AioEngine::Native if self.ctx.is_none() => {
+                self.ctx = Some(Arc::new(LibaioContext::new(self.max_events as i32)?));
+            }
+            AioEngine::IoUring if self.uring.is_none() => {
+                self.uring = Some(IoUringContext::new(self.max_events as u32, &self.fd)?);
+            }
+            _ => {}
+        }
+        self.engine = engine;
+
+        Ok(())
     }
 
     pub fn handle(&mut self) -> Result<()> {
-        let evts = self.ctx.get_events()?;
-        for e in evts.events.iter().take(evts.nr) {
-            if e.res2 == 0 {
+        if let Some(ctx) = self.ctx.as_ref() {
+            let evts = ctx.get_events()?;
+            for e in evts.events.iter().take(evts.nr) {
+                if e.res2 == 0 {
+                    unsafe {
+                        let node = e.data as *mut CbNode<T>;
+
+                        (self.complete_func)(&(*node).value, e.res);
+                        self.aio_in_flight.unlink(&(*node));
+
+                        // free mem
+                        if let Some(i) = (*node).value.iocb {
+                            libc::free((*node).value.iovec.as_ptr() as *mut libc::c_void);
+                            libc::free(i.as_ptr() as *mut libc::c_void);
+                        };
+                        libc::free(node as *mut libc::c_void);
+                    }
+                }
+            }
+        }
+
+        if let Some(uring) = self.uring.as_mut() {
+            for (data, res) in uring.get_events() {
+                // Safe because the node is leaked when it is submitted to io_uring,
+                // and is reclaimed only once here.
                 unsafe {
-                    let node = e.data as *mut CbNode<T>;
+                    let node = data as *mut CbNode<T>;
 
-                    (self.complete_func)(&(*node).value, e.res);
+                    (self.complete_func)(&(*node).value, res);
                     self.aio_in_flight.unlink(&(*node));
-
-                    // free mem
-                    if let Some(i) = (*node).value.iocb {
-                        libc::free((*node).value.iovec.as_ptr() as *mut libc::c_void);
-                        libc::free(i.as_ptr() as *mut libc::c_void);
-                    };
-                    libc::free(node as *mut libc::c_void);
+                    drop(Box::from_raw(node));
                 }
             }
         }
+
         self.process_list()
     }
 
     fn process_list(&mut self) -> Result<()> {
         if self.aio_in_queue.len > 0 && self.aio_in_flight.len < self.max_events {
             let mut iocbs = Vec::new();
+            let mut uring_pushed = false;
 
             for _ in self.aio_in_flight.len..self.max_events {
+                if let Some(uring) = self.uring.as_mut() {
+                    if uring.is_full() {
+                        uring.submit()?;
+                    }
+                }
+
                 match self.aio_in_queue.pop_tail() {
-                    Some(node) => {
-                        iocbs.push(node.value.iocb.unwrap().as_ptr());
+                    Some(mut node) => {
+                        if let Some(iocb) = node.value.iocb {
+                            iocbs.push(iocb.as_ptr());
+                        } else {
+                            let data = (&mut (*node) as *mut CbNode<T>) as u64;
+                            let cb = &node.value;
+                            if let Err(ref e) = self
+                                .uring
+                                .as_mut()
+                                .unwrap()
+                                .push(cb.opcode, cb.file_fd, &cb.iovec, cb.offset, data)
+                            {
+                                // The request is never submitted, complete it with error
+                                // instead of leaving it pending forever.
+                                error!(
+                                    "Failed to push request to io_uring, {}",
+                                    error_chain::ChainedError::display_chain(e)
+                                );
+                                (self.complete_func)(cb, -i64::from(libc::EIO));
+                                continue;
+                            }
+                            uring_pushed = true;
+                        }
                         self.aio_in_flight.add_head(node);
                     }
                     None => break,
                 }
             }
 
+            if uring_pushed {
+                self.uring.as_mut().unwrap().submit()?;
+            }
             if !iocbs.is_empty() {
-                return self.ctx.submit(iocbs.len() as i64, &mut iocbs);
+                return self
+                    .ctx
+                    .as_ref()
+                    .unwrap()
+                    .submit(iocbs.len() as i64, &mut iocbs);
             }
         }
 
         Ok(())
     }
 
+    /// Process the request by the current engine. Requests on file opened with
+    /// `O_DIRECT` must be aligned to `sector_size`, otherwise they are processed
+    /// synchronously with bounce buffers.
     pub fn rw_aio(&mut self, cb: AioCb<T>, sector_size: u64) -> Result<()> {
         let mut misaligned = false;
-        for iov in cb.iovec.iter() {
-            if iov.iov_base % sector_size != 0 || iov.iov_len % sector_size != 0 {
-                misaligned = true;
-                break;
+        if cb.direct || self.engine == AioEngine::Native {
+            for iov in cb.iovec.iter() {
+                if iov.iov_base % sector_size != 0 || iov.iov_len % sector_size != 0 {
+                    misaligned = true;
+                    break;
+                }
             }
         }
         if misaligned {
             return self.handle_misaligned_aio(cb);
         }
 
+        match self.engine {
+            AioEngine::Threads => return self.rw_sync(cb),
+            AioEngine::IoUring => return self.rw_uring(cb),
+            AioEngine::Native => {}
+        }
+        // Linux native aio doesn't support flush on most file systems.
+        if let IoCmd::Fdsync = cb.opcode {
+            return self.rw_sync(cb);
+        }
+
         let last_aio = cb.last_aio;
         let opcode = cb.opcode;
         let file_fd = cb.file_fd;
@@ -164,6 +295,18 @@ impl<T: Clone + 'static> Aio<T> {
         Ok(())
     }
 
+    fn rw_uring(&mut self, cb: AioCb<T>) -> Result<()> {
+        let last_aio = cb.last_aio;
+        let node = Box::new(Node::new(cb));
+
+        self.aio_in_queue.add_head(node);
+        if last_aio || self.aio_in_queue.len + self.aio_in_flight.len >= self.max_events {
+            return self.process_list();
+        }
+
+        Ok(())
+    }
+
     pub fn rw_sync(&mut self, cb: AioCb<T>) -> Result<()> {
         let ret = match cb.opcode {
             IoCmd::Preadv => {
@@ -192,6 +335,12 @@ impl<T: Clone + 'static> Aio<T> {
         Ok(())
     }
 
+    /// Complete the request which has been processed by the caller, `ret` is the
+    /// result of processing.
+    pub fn complete(&mut self, cb: &AioCb<T>, ret: i64) {
+        (self.complete_func)(cb, ret);
+    }
+
     fn handle_misaligned_aio(&mut self, cb: AioCb<T>) -> Result<()> {
         // Safe because we only get the host page size.
         let host_page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
@@ -254,3 +403,90 @@ impl<T: Clone + 'static> Aio<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn build_cb(file: &TempFile, opcode: IoCmd, buf: &mut [u8]) -> AioCb<()> {
+        let mut cb = AioCb::new(());
+        cb.file_fd = file.as_file().as_raw_fd();
+        cb.opcode = opcode;
+        cb.iovec.push(Iovec {
+            iov_base: buf.as_mut_ptr() as u64,
+            iov_len: buf.len() as u64,
+        });
+        cb.offset = 512;
+        cb
+    }
+
+    fn wait_complete(aio: &mut Aio<()>, result: &AtomicI64) {
+        while result.load(Ordering::SeqCst) == i64::MIN {
+            let mut pollfd = libc::pollfd {
+                fd: aio.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Safe because the pollfd is valid during the call.
+            unsafe { libc::poll(&mut pollfd, 1, 1000) };
+            let _ = aio.fd.read();
+            aio.handle().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_aio_engine_parse() {
+        assert_eq!("threads".parse::<AioEngine>(), Ok(AioEngine::Threads));
+        assert_eq!("native".parse::<AioEngine>(), Ok(AioEngine::Native));
+        assert_eq!("io_uring".parse::<AioEngine>(), Ok(AioEngine::IoUring));
+        assert!("posix".parse::<AioEngine>().is_err());
+    }
+
+    #[test]
+    fn test_io_uring_rw() {
+        let result = Arc::new(AtomicI64::new(i64::MIN));
+        let result_clone = result.clone();
+        let complete_func: Arc<AioCompleteFunc<()>> =
+            Arc::new(Box::new(move |_: &AioCb<()>, ret: i64| {
+                result_clone.store(ret, Ordering::SeqCst);
+            }));
+        let mut aio = match Aio::new(complete_func, AioEngine::IoUring) {
+            Ok(aio) => aio,
+            // Kernel without io_uring support.
+            Err(_) => return,
+        };
+        assert_eq!(aio.engine(), AioEngine::IoUring);
+
+        let file = TempFile::new().unwrap();
+        let mut data = vec![0x5a_u8; 4096];
+        aio.rw_aio(build_cb(&file, IoCmd::Pwritev, &mut data), 512)
+            .unwrap();
+        wait_complete(&mut aio, &result);
+        assert_eq!(result.swap(i64::MIN, Ordering::SeqCst), 4096);
+
+        aio.rw_aio(build_cb(&file, IoCmd::Fdsync, &mut []), 512)
+            .unwrap();
+        wait_complete(&mut aio, &result);
+        assert_eq!(result.swap(i64::MIN, Ordering::SeqCst), 0);
+
+        let mut buf = vec![0_u8; 4096];
+        aio.rw_aio(build_cb(&file, IoCmd::Preadv, &mut buf), 512)
+            .unwrap();
+        wait_complete(&mut aio, &result);
+        assert_eq!(result.load(Ordering::SeqCst), 4096);
+        assert_eq!(buf, data);
+
+        // The request which can't be pushed to io_uring is completed with error.
+        result.store(i64::MIN, Ordering::SeqCst);
+        aio.rw_aio(build_cb(&file, IoCmd::Noop, &mut buf), 512)
+            .unwrap();
+        assert_eq!(result.load(Ordering::SeqCst), -i64::from(libc::EIO));
+        assert_eq!(aio.aio_in_queue.len, 0);
+        assert_eq!(aio.aio_in_flight.len, 0);
+    }
+}
@@ -11,9 +11,12 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Result;
-use libc::{c_void, fdatasync, pread, pwrite};
+use libc::{c_void, fallocate, fdatasync, pread, pwrite};
 use std::os::unix::io::RawFd;
 
+/// Size of the buffer used to write zeroes when fallocate is not supported.
+const ZERO_BUF_SIZE: usize = 1 << 20;
+
 pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> Result<i64> {
     let ret = unsafe { pread(fd, buf as *mut c_void, size, offset as i64) as i64 };
     if ret < 0 {
@@ -40,3 +43,83 @@ pub fn raw_datasync(fd: RawFd) -> Result<i64> {
 
     Ok(ret)
 }
+
+fn last_errno() -> i64 {
+    -i64::from(
+        std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO),
+    )
+}
+
+/// Deallocate the space of the file, return 0 or negative errno.
+pub fn raw_discard(fd: RawFd, offset: usize, size: u64) -> i64 {
+    let ret = unsafe {
+        fallocate(
+            fd,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as i64,
+            size as i64,
+        )
+    };
+    if ret < 0 {
+        return last_errno();
+    }
+
+    0
+}
+
+/// Write zeroes to the range of the file, the range is deallocated if `unmap` is true.
+/// Zeroes are written by buffer if fallocate is not supported, return 0 or negative errno.
+pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64, unmap: bool) -> i64 {
+    if unmap && raw_discard(fd, offset, size) == 0 {
+        return 0;
+    }
+
+    let ret = unsafe {
+        fallocate(
+            fd,
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as i64,
+            size as i64,
+        )
+    };
+    if ret == 0 {
+        return 0;
+    }
+    let errno = last_errno();
+    if errno != -i64::from(libc::EOPNOTSUPP) && errno != -i64::from(libc::EINVAL) {
+        return errno;
+    }
+
+    // Safe because we only get the host page size.
+    let host_page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let buf_len = std::cmp::min(size as usize, ZERO_BUF_SIZE);
+    // Buffer is aligned to page size in case the file is opened with O_DIRECT.
+    let buf = unsafe { libc::memalign(host_page_size, buf_len) };
+    if buf.is_null() {
+        return -i64::from(libc::ENOMEM);
+    }
+    unsafe { libc::memset(buf, 0, buf_len) };
+
+    let mut ret = 0;
+    let mut done = 0_u64;
+    while done < size {
+        let len = std::cmp::min(size - done, buf_len as u64) as usize;
+        let written =
+            unsafe { pwrite(fd, buf as *const c_void, len, (offset as u64 + done) as i64) };
+        if written <= 0 {
+            ret = if written < 0 {
+                last_errno()
+            } else {
+                -i64::from(libc::EIO)
+            };
+            break;
+        }
+        done += written as u64;
+    }
+    // Safe because the memory is allocated by us and will not be used anymore.
+    unsafe { libc::free(buf) };
+
+    ret
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{IoCmd, Iovec, Result};
+use crate::errors::ResultExt;
+
+/// Context of io_uring, the completion of requests is notified by the eventfd
+/// registered to the ring.
+pub struct IoUringContext {
+    ring: IoUring,
+}
+
+impl IoUringContext {
+    pub fn new(entries: u32, eventfd: &EventFd) -> Result<Self> {
+        let ring = IoUring::new(entries).chain_err(|| "Failed to create io_uring instance")?;
+        ring.submitter()
+            .register_eventfd(eventfd.as_raw_fd())
+            .chain_err(|| "Failed to register eventfd for io_uring")?;
+
+        Ok(IoUringContext { ring })
+    }
+
+    /// Whether the submission queue has no free entry.
+    pub fn is_full(&mut self) -> bool {
+        self.ring.submission().is_full()
+    }
+
+    /// Push a request to the submission queue, it is not submitted to kernel until
+    /// `submit` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `opcode` - Command of the request.
+    /// * `fd` - File descriptor to operate.
+    /// * `iovec` - Buffers of the request, must be valid until the request completes.
+    /// * `offset` - Offset of the file.
+    /// * `data` - User data returned with the completion of the request.
+    pub fn push(
+        &mut self,
+        opcode: IoCmd,
+        fd: RawFd,
+        iovec: &[Iovec],
+        offset: usize,
+        data: u64,
+    ) -> Result<()> {
+        let entry = match opcode {
+            IoCmd::Preadv => opcode::Readv::new(
+                types::Fd(fd),
+                iovec.as_ptr() as *const libc::iovec,
+                iovec.len() as u32,
+            )
+            .offset(offset as libc::off_t)
+            .build(),
+            IoCmd::Pwritev => opcode::Writev::new(
+                types::Fd(fd),
+                iovec.as_ptr() as *const libc::iovec,
+                iovec.len() as u32,
+            )
+            .offset(offset as libc::off_t)
+            .build(),
+            // Flush must not start before the requests submitted ahead of it complete.
+            IoCmd::Fdsync => opcode::Fsync::new(types::Fd(fd))
+                .flags(types::FsyncFlags::DATASYNC)
+                .build()
+                .flags(squeue::Flags::IO_DRAIN),
+            IoCmd::Fsync => opcode::Fsync::new(types::Fd(fd))
+                .build()
+                .flags(squeue::Flags::IO_DRAIN),
+            _ => bail!("Unsupported io_uring request opcode {}", opcode as u16),
+        };
+
+        // Safe because the buffers are kept alive by the caller until completion.
+        unsafe {
+            if self.ring.submission().push(&entry.user_data(data)).is_err() {
+                bail!("Submission queue of io_uring is full");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Submit all requests in the submission queue to kernel.
+    pub fn submit(&mut self) -> Result<()> {
+        self.ring
+            .submit()
+            .chain_err(|| "Failed to submit io_uring requests")?;
+        Ok(())
+    }
+
+    /// Get all completed requests, return the user data and result of each request.
+    pub fn get_events(&mut self) -> Vec<(u64, i64)> {
+        self.ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), i64::from(cqe.result())))
+            .collect()
+    }
+}
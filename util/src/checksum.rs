@@ -33,3 +33,51 @@ pub fn obj_checksum<T: ByteCode>(t: &T) -> u8 {
 
     (sum & 0xff) as u8
 }
+
+/// Polynomial of CRC32 (IEEE 802.3) in reversed bit order.
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// Calculate CRC32 (IEEE 802.3) of `slice`, continuing from `crc` which is
+/// the CRC32 of preceding data, or 0 for the beginning.
+pub fn crc32(crc: u32, slice: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in slice.iter() {
+        crc = CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, &[]), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        // Calculate in parts.
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+}
@@ -27,6 +27,8 @@ const NANOS_PER_SEC: u64 = 1_000_000_000;
 pub struct LeakBucket {
     /// Indicate the capacity of bucket, which is config by user.
     capacity: u64,
+    /// The max water level allowed before throttling, which is not less than capacity.
+    burst: u64,
     /// Current water level.
     level: u64,
     /// Internal used to calculate the delay of timer.
@@ -47,6 +49,7 @@ impl LeakBucket {
     pub fn new(units_ps: u64) -> Self {
         LeakBucket {
             capacity: units_ps * ACCURACY_SCALE,
+            burst: units_ps * ACCURACY_SCALE,
             level: 0,
             prev_time: Instant::now(),
             timer_started: false,
@@ -54,6 +57,33 @@ impl LeakBucket {
         }
     }
 
+    /// Construct a bucket which allows bursts.
+    ///
+    /// # Arguments
+    ///
+    /// * `units_ps` - units per second.
+    /// * `burst` - max units can be consumed without throttling, it's `units_ps` if
+    ///   less than `units_ps`.
+    pub fn with_burst(units_ps: u64, burst: u64) -> Self {
+        let mut bucket = LeakBucket::new(units_ps);
+        bucket.update(units_ps, burst);
+        bucket
+    }
+
+    /// Change the limits of the bucket, the units already consumed are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `units_ps` - units per second.
+    /// * `burst` - max units can be consumed without throttling.
+    pub fn update(&mut self, units_ps: u64, burst: u64) {
+        self.capacity = units_ps * ACCURACY_SCALE;
+        self.burst = std::cmp::max(units_ps, burst) * ACCURACY_SCALE;
+        if self.capacity == 0 {
+            self.level = 0;
+        }
+    }
+
     /// Return true if the bucket is full, and caller must return directly instead of launching IO.
     /// Otherwise, caller should not be affected.
     ///
@@ -61,6 +91,21 @@ impl LeakBucket {
     ///
     /// * `loop_context` - used for delay function call.
     pub fn throttled(&mut self, loop_context: &mut EventLoopContext, need_units: u64) -> bool {
+        if self.is_full(loop_context) {
+            return true;
+        }
+        self.consume(need_units);
+        false
+    }
+
+    /// Return true if the bucket is full, and the timer is started to wake up the caller
+    /// when the bucket is ready. The units are not consumed, which makes it possible to
+    /// check several buckets before consuming any of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `loop_context` - used for delay function call.
+    pub fn is_full(&mut self, loop_context: &mut EventLoopContext) -> bool {
         // capacity value is zero, indicating that there is no need to limit
         if self.capacity == 0 {
             return false;
@@ -72,16 +117,17 @@ impl LeakBucket {
         // update the water level
         let now = Instant::now();
         let nanos = (now - self.prev_time).as_nanos();
-        if nanos > (self.level * NANOS_PER_SEC / self.capacity) as u128 {
+        // Use u128 to avoid overflow when the bucket is used to limit bytes.
+        if nanos > u128::from(self.level) * u128::from(NANOS_PER_SEC) / u128::from(self.capacity) {
             self.level = 0;
         } else {
-            self.level -= nanos as u64 * self.capacity / NANOS_PER_SEC;
+            self.level -= (nanos * u128::from(self.capacity) / u128::from(NANOS_PER_SEC)) as u64;
         }
 
         self.prev_time = now;
 
         // need to be throttled
-        if self.level > self.capacity {
+        if self.level > self.burst {
             let wakeup_clone = self.timer_wakeup.try_clone().unwrap();
             let func = Box::new(move || {
                 wakeup_clone
@@ -91,7 +137,8 @@ impl LeakBucket {
 
             loop_context.delay_call(
                 func,
-                (self.level - self.capacity) * NANOS_PER_SEC / self.capacity,
+                (u128::from(self.level - self.burst) * u128::from(NANOS_PER_SEC)
+                    / u128::from(self.capacity)) as u64,
             );
 
             self.timer_started = true;
@@ -99,11 +146,16 @@ impl LeakBucket {
             return true;
         }
 
-        self.level += need_units * ACCURACY_SCALE;
-
         false
     }
 
+    /// Consume units of the bucket.
+    pub fn consume(&mut self, units: u64) {
+        if self.capacity != 0 {
+            self.level += units * ACCURACY_SCALE;
+        }
+    }
+
     /// Clear the timer state.
     pub fn clear_timer(&mut self) {
         self.timer_started = false;
@@ -114,3 +166,38 @@ impl LeakBucket {
         self.timer_wakeup.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leak_bucket_burst() {
+        let mut ctx = EventLoopContext::new();
+
+        // Unlimited bucket is never full.
+        let mut bucket = LeakBucket::new(0);
+        bucket.consume(1_000_000);
+        assert!(!bucket.is_full(&mut ctx));
+
+        // The units beyond one second are allowed by burst.
+        let mut bucket = LeakBucket::with_burst(10, 100);
+        for _ in 0..=100 {
+            assert!(!bucket.throttled(&mut ctx, 1));
+        }
+        assert!(bucket.throttled(&mut ctx, 1));
+        // Keep throttled until the timer wakes up the caller.
+        assert!(bucket.is_full(&mut ctx));
+        bucket.clear_timer();
+
+        // Bucket without burst is full once the units of one second are consumed.
+        let mut bucket = LeakBucket::with_burst(1_000_000, 0);
+        bucket.consume(2_000_000);
+        assert!(bucket.is_full(&mut ctx));
+
+        // Updating the bucket to unlimited drains it.
+        bucket.clear_timer();
+        bucket.update(0, 0);
+        assert!(!bucket.is_full(&mut ctx));
+    }
+}
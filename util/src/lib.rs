@@ -36,6 +36,7 @@ pub mod reader;
 pub mod seccomp;
 pub mod tap;
 pub mod unix;
+pub mod userfaultfd;
 #[macro_use]
 pub mod logger;
 #[macro_use]
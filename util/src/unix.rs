@@ -12,7 +12,15 @@
 
 extern crate libc;
 
-use super::errors::{ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use libc::{
+    c_void, iovec, msghdr, recvmsg, sendmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN, CMSG_SPACE,
+    MSG_NOSIGNAL, SCM_RIGHTS, SOL_SOCKET,
+};
+
+use super::errors::{ErrorKind, Result, ResultExt};
 
 /// This function returns the caller's thread ID(TID).
 pub fn gettid() -> u64 {
@@ -61,23 +69,210 @@ impl From<&str> for UnixPath {
 ///
 /// # Notions
 ///
-/// Unix uri is the string as `file:/xxx/xxx` or `unix:/xxx/xxx` or `tcp:xxx.xxx.xxx`.
+/// Unix uri is the string as `file:/xxx/xxx` or `unix:/xxx/xxx` or `tcp:host:port`.
 pub fn parse_uri(uri: &str) -> Result<(UnixPath, String)> {
-    let parse_vec: Vec<&str> = uri.split(':').collect();
-    if parse_vec.len() == 2 {
-        match UnixPath::from(parse_vec[0]) {
-            UnixPath::File => Ok((UnixPath::File, String::from(parse_vec[1]))),
-            UnixPath::Unix => Ok((UnixPath::Unix, String::from(parse_vec[1]))),
-            _ => bail!("Unsupported unix path type."),
+    let parse_vec: Vec<&str> = uri.splitn(2, ':').collect();
+    if parse_vec.len() != 2 {
+        bail!("Invalid unix uri: {}", uri);
+    }
+
+    match UnixPath::from(parse_vec[0]) {
+        UnixPath::File | UnixPath::Unix if parse_vec[1].contains(':') => {
+            bail!("Invalid unix uri: {}", uri)
         }
-    } else {
-        bail!("Invalid unix uri: {}", uri)
+        UnixPath::File => Ok((UnixPath::File, String::from(parse_vec[1]))),
+        UnixPath::Unix => Ok((UnixPath::Unix, String::from(parse_vec[1]))),
+        UnixPath::Tcp => {
+            // The address of tcp must contain both host and port.
+            match parse_vec[1].rsplitn(2, ':').collect::<Vec<&str>>()[..] {
+                [port, host] if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok((UnixPath::Tcp, String::from(parse_vec[1])))
+                }
+                _ => bail!("Invalid tcp uri: {}", uri),
+            }
+        }
+        _ => bail!("Unsupported unix path type."),
+    }
+}
+
+/// Unix domain socket stream which is able to pass file descriptors along
+/// with the messages.
+pub struct UnixSock {
+    /// Path of the unix socket.
+    pub path: String,
+    /// The stream connected to the peer.
+    sock: Option<UnixStream>,
+}
+
+impl UnixSock {
+    pub fn new(path: &str) -> Self {
+        UnixSock {
+            path: String::from(path),
+            sock: None,
+        }
+    }
+
+    /// Create with a stream which is already connected, such as one of a
+    /// pair of sockets.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Description of the socket, used in error messages.
+    /// * `stream` - The connected stream.
+    pub fn with_stream(path: &str, stream: UnixStream) -> Self {
+        UnixSock {
+            path: String::from(path),
+            sock: Some(stream),
+        }
+    }
+
+    /// Connect to the unix socket given by `path`, the former connection
+    /// is closed if exists.
+    pub fn connect(&mut self) -> Result<()> {
+        let sock = UnixStream::connect(self.path.as_str())
+            .chain_err(|| format!("Failed to connect the socket {}", self.path))?;
+        self.sock = Some(sock);
+        Ok(())
+    }
+
+    /// Return true if the socket is connected.
+    pub fn is_connected(&self) -> bool {
+        self.sock.is_some()
+    }
+
+    /// Get the raw fd of the connected stream.
+    pub fn get_stream_raw_fd(&self) -> Option<RawFd> {
+        self.sock.as_ref().map(|sock| sock.as_raw_fd())
+    }
+
+    fn get_cmsg_space(fd_num: usize) -> usize {
+        if fd_num == 0 {
+            return 0;
+        }
+        unsafe { CMSG_SPACE((fd_num * std::mem::size_of::<RawFd>()) as u32) as usize }
+    }
+
+    /// Send the data in `iovecs` and the file descriptors in `out_fds` with
+    /// [sendmsg(2)](https://man7.org/linux/man-pages/man2/sendmsg.2.html).
+    /// Return the count of bytes sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `iovecs` - Buffers to be sent, which must be valid during the call.
+    /// * `out_fds` - File descriptors passed in the control message.
+    pub fn send_msg(&self, iovecs: &mut [iovec], out_fds: &[RawFd]) -> Result<usize> {
+        let sock_fd = match self.get_stream_raw_fd() {
+            Some(fd) => fd,
+            None => bail!("Socket {} is not connected", self.path),
+        };
+
+        let cmsg_space = Self::get_cmsg_space(out_fds.len());
+        let mut cmsg_buffer = vec![0_u64; cmsg_space / 8 + 1];
+
+        // In `musl` toolchain, msghdr has private member `__pad0` and `__pad1`, it can't be
+        // initialized in normal way.
+        let mut msg: msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = iovecs.len() as _;
+
+        if !out_fds.is_empty() {
+            msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = cmsg_space as _;
+            let fds_size = std::mem::size_of_val(out_fds);
+            // Safe because the control buffer is large enough for the header and the fds.
+            unsafe {
+                let cmsg = CMSG_FIRSTHDR(&msg as *const msghdr);
+                (*cmsg).cmsg_level = SOL_SOCKET;
+                (*cmsg).cmsg_type = SCM_RIGHTS;
+                (*cmsg).cmsg_len = CMSG_LEN(fds_size as u32) as _;
+                std::ptr::copy_nonoverlapping(
+                    out_fds.as_ptr() as *const u8,
+                    CMSG_DATA(cmsg),
+                    fds_size,
+                );
+            }
+        }
+
+        let ret = unsafe { sendmsg(sock_fd, &msg, MSG_NOSIGNAL) };
+        if ret < 0 {
+            bail!(
+                "Failed to send msg to socket {}, err: {}",
+                self.path,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(ret as usize)
+    }
+
+    /// Receive data to `iovecs` and file descriptors to `in_fds` with
+    /// [recvmsg(2)](https://man7.org/linux/man-pages/man2/recvmsg.2.html).
+    /// Return the count of bytes and file descriptors received.
+    ///
+    /// # Arguments
+    ///
+    /// * `iovecs` - Buffers to receive data, which must be valid during the call.
+    /// * `in_fds` - Buffer to receive file descriptors passed in the control message.
+    pub fn recv_msg(&self, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<(usize, usize)> {
+        let sock_fd = match self.get_stream_raw_fd() {
+            Some(fd) => fd,
+            None => bail!("Socket {} is not connected", self.path),
+        };
+
+        let cmsg_space = Self::get_cmsg_space(in_fds.len());
+        let mut cmsg_buffer = vec![0_u64; cmsg_space / 8 + 1];
+
+        let mut msg: msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = iovecs.len() as _;
+        if !in_fds.is_empty() {
+            msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = cmsg_space as _;
+        }
+
+        let total_len = unsafe { recvmsg(sock_fd, &mut msg, libc::MSG_WAITALL) };
+        if total_len < 0 {
+            bail!(
+                "Failed to recv msg from socket {}, err: {}",
+                self.path,
+                std::io::Error::last_os_error()
+            );
+        }
+        if total_len == 0 && iovecs.iter().any(|iov| iov.iov_len != 0) {
+            bail!("The peer of socket {} is closed", self.path);
+        }
+
+        let mut fd_num = 0;
+        if msg.msg_controllen > 0 {
+            // Safe because the control message is filled by the kernel.
+            let cmsg = unsafe { CMSG_FIRSTHDR(&msg as *const msghdr).as_ref() };
+            if let Some(cmsg) = cmsg {
+                if cmsg.cmsg_level == SOL_SOCKET && cmsg.cmsg_type == SCM_RIGHTS {
+                    // The type of `cmsg_len` differs in `gnu` and `musl` toolchains.
+                    let cmsg_len: usize = cmsg.cmsg_len as _;
+                    let data_len = cmsg_len - unsafe { CMSG_LEN(0) } as usize;
+                    fd_num = std::cmp::min(data_len / std::mem::size_of::<RawFd>(), in_fds.len());
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            CMSG_DATA(cmsg) as *const RawFd,
+                            in_fds.as_mut_ptr(),
+                            fd_num,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok((total_len as usize, fd_num))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_uri, UnixPath};
+    use std::io::Write;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixListener;
+
+    use super::{parse_uri, UnixPath, UnixSock};
 
     #[test]
     fn test_parse_uri() {
@@ -93,5 +288,62 @@ mod tests {
 
         let test_uri_03 = "tcp:127.0.0.1";
         assert!(parse_uri(test_uri_03).is_err());
+
+        let test_uri_04 = "tcp:127.0.0.1:4446";
+        assert_eq!(
+            parse_uri(test_uri_04).unwrap(),
+            (UnixPath::Tcp, String::from("127.0.0.1:4446"))
+        );
+
+        let test_uri_05 = "tcp::4446";
+        assert!(parse_uri(test_uri_05).is_err());
+        let test_uri_06 = "tcp:127.0.0.1:port";
+        assert!(parse_uri(test_uri_06).is_err());
+    }
+
+    #[test]
+    fn test_unix_sock_msg() {
+        let path = "/tmp/test_unix_sock_msg.sock";
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+
+        let mut client = UnixSock::new(path);
+        assert!(!client.is_connected());
+        assert!(client.connect().is_ok());
+        assert!(client.is_connected());
+        let (server_stream, _) = listener.accept().unwrap();
+        let mut server = UnixSock::new(path);
+        server.sock = Some(server_stream);
+
+        let file = std::fs::File::create("/tmp/test_unix_sock_msg.file").unwrap();
+        let mut data = [1_u8, 2, 3, 4];
+        let mut iovecs = [libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        }];
+        assert_eq!(
+            client.send_msg(&mut iovecs, &[file.as_raw_fd()]).unwrap(),
+            4
+        );
+
+        let mut buf = [0_u8; 4];
+        let mut iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        let mut fds = [0; 2];
+        assert_eq!(server.recv_msg(&mut iovecs, &mut fds).unwrap(), (4, 1));
+        assert_eq!(buf, data);
+
+        // The received fd refers to the same file.
+        let mut recv_file = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        recv_file.write_all(b"test").unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 4);
+
+        drop(client);
+        assert!(server.recv_msg(&mut iovecs, &mut fds).is_err());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file("/tmp/test_unix_sock_msg.file").unwrap();
     }
 }
@@ -0,0 +1,291 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::Read;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+
+use super::byte_code::ByteCode;
+use super::errors::{Result, ResultExt};
+
+const UFFDIO: u32 = 0xAA;
+const UFFD_API: u64 = 0xAA;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+/// Message read from userfaultfd, see `struct uffd_msg` in linux/userfaultfd.h.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    feat: u64,
+}
+
+impl ByteCode for UffdMsg {}
+
+/// Result of copying data into range registered in userfaultfd.
+#[derive(Debug, PartialEq)]
+pub enum CopyResult {
+    /// All data has been copied.
+    Done,
+    /// Nothing has been copied because the first page is already populated.
+    Exist,
+    /// Data has been copied partly with the given length, the rest needs to be
+    /// copied again.
+    Again(u64),
+}
+
+/// Wrapper of linux userfaultfd, which handles missing page faults of the
+/// registered memory range in user space.
+pub struct UserfaultFd {
+    file: File,
+}
+
+impl UserfaultFd {
+    /// Create userfaultfd in non-blocking mode and handshake the api version with kernel.
+    pub fn new() -> Result<Self> {
+        // Safe because this syscall only creates a new fd and has no side effect.
+        let fd =
+            unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| "Failed to create userfaultfd");
+        }
+        // Safe because fd is created above and owned by the file only.
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the fd is userfaultfd and api has the expected layout.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .chain_err(|| "Failed to handshake api with userfaultfd");
+        }
+
+        Ok(UserfaultFd { file })
+    }
+
+    /// Register host virtual memory range to handle its missing page faults.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start host virtual address of the range, must be page aligned.
+    /// * `len` - Length of the range, must be page aligned.
+    pub fn register(&self, start: u64, len: u64) -> Result<()> {
+        let mut reg = UffdioRegister {
+            range: UffdioRange { start, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // Safe because the range is checked by kernel and reg has the expected layout.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut reg) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).chain_err(|| {
+                format!(
+                    "Failed to register range 0x{:X} with length 0x{:X} to userfaultfd",
+                    start, len
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Unregister host virtual memory range, page faults of this range are handled
+    /// by kernel after that.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start host virtual address of the range, must be page aligned.
+    /// * `len` - Length of the range, must be page aligned.
+    pub fn unregister(&self, start: u64, len: u64) -> Result<()> {
+        let range = UffdioRange { start, len };
+        // Safe because the range is checked by kernel and range has the expected layout.
+        let ret = unsafe { ioctl_with_ref(&self.file, UFFDIO_UNREGISTER(), &range) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).chain_err(|| {
+                format!(
+                    "Failed to unregister range 0x{:X} with length 0x{:X} from userfaultfd",
+                    start, len
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Copy data to registered range atomically and wake up the threads waiting on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Destination host virtual address in registered range, must be page aligned.
+    /// * `src` - Source host virtual address of data.
+    /// * `len` - Length of data, must be page aligned.
+    pub fn copy(&self, dst: u64, src: u64, len: u64) -> Result<CopyResult> {
+        let mut copy = UffdioCopy {
+            dst,
+            src,
+            len,
+            mode: 0,
+            copy: 0,
+        };
+        // Safe because the destination range is checked by kernel, and the source
+        // range is valid memory given by caller.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_COPY(), &mut copy) };
+        if ret == 0 {
+            return Ok(CopyResult::Done);
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EEXIST) => Ok(CopyResult::Exist),
+            // The length copied is returned in `copy`, or negative errno if nothing is copied.
+            Some(libc::EAGAIN) => Ok(CopyResult::Again(std::cmp::max(copy.copy, 0) as u64)),
+            _ => Err(err).chain_err(|| {
+                format!(
+                    "Failed to copy data to 0x{:X} with length 0x{:X} by userfaultfd",
+                    dst, len
+                )
+            }),
+        }
+    }
+
+    /// Read the address of next missing page fault, returns `None` if there is no
+    /// page fault pending.
+    pub fn read_fault(&self) -> Result<Option<u64>> {
+        let mut msg = UffdMsg::default();
+        loop {
+            match (&self.file).read(msg.as_mut_bytes()) {
+                Ok(len) if len == size_of::<UffdMsg>() => {}
+                Ok(len) => bail!("Invalid message length {} of userfaultfd", len),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e).chain_err(|| "Failed to read userfaultfd"),
+            }
+            // Other events are not enabled, skip them.
+            if msg.event == UFFD_EVENT_PAGEFAULT {
+                return Ok(Some(msg.address));
+            }
+        }
+    }
+}
+
+impl AsRawFd for UserfaultFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unix::host_page_size;
+
+    #[test]
+    fn test_userfaultfd_copy() {
+        let uffd = match UserfaultFd::new() {
+            Ok(uffd) => uffd,
+            // Userfaultfd may be disabled for unprivileged user.
+            Err(_) => return,
+        };
+        let page_size = host_page_size();
+        let len = page_size * 4;
+        // Safe because it only maps new anonymous memory.
+        let dst = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(dst, libc::MAP_FAILED);
+        let dst = dst as u64;
+        let src = vec![0x5A_u8; len as usize];
+
+        assert!(uffd.register(dst, len).is_ok());
+        assert_eq!(uffd.read_fault().unwrap(), None);
+        assert_eq!(
+            uffd.copy(dst + page_size, src.as_ptr() as u64, page_size)
+                .unwrap(),
+            CopyResult::Done
+        );
+        // The second page is populated, so only the first page is copied.
+        assert_eq!(
+            uffd.copy(dst, src.as_ptr() as u64, page_size * 2).unwrap(),
+            CopyResult::Again(page_size)
+        );
+        assert_eq!(
+            uffd.copy(dst + page_size, src.as_ptr() as u64, page_size)
+                .unwrap(),
+            CopyResult::Exist
+        );
+        assert!(uffd.unregister(dst, len).is_ok());
+
+        // Safe because the memory is mapped above and the first two pages are populated.
+        unsafe {
+            assert_eq!(*(dst as *const u8), 0x5A);
+            assert_eq!(*((dst + page_size * 2 - 1) as *const u8), 0x5A);
+            // Page faults of unregistered range are handled by kernel.
+            assert_eq!(*((dst + page_size * 3) as *const u8), 0);
+            libc::munmap(dst as *mut libc::c_void, len as libc::size_t);
+        }
+    }
+}
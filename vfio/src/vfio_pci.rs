@@ -27,6 +27,7 @@ use vmm_sys_util::ioctl::ioctl_with_mut_ref;
 use super::errors::{ErrorKind, Result, ResultExt};
 use address_space::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region, RegionOps};
 use hypervisor::{MsiVector, KVM_FDS};
+use migration::MigrationManager;
 #[cfg(target_arch = "aarch64")]
 use pci::config::SECONDARY_BUS_NUM;
 use pci::config::{
@@ -35,6 +36,7 @@ use pci::config::{
     HEADER_TYPE, IO_BASE_ADDR_MASK, MEM_BASE_ADDR_MASK, PCIE_CONFIG_SPACE_SIZE, REG_SIZE,
 };
 use pci::errors::Result as PciResult;
+use pci::hotplug::handle_plug;
 use pci::msix::{
     is_msix_enabled, update_dev_id, Msix, MSIX_CAP_CONTROL, MSIX_CAP_ENABLE, MSIX_CAP_FUNC_MASK,
     MSIX_CAP_ID, MSIX_CAP_SIZE, MSIX_CAP_TABLE, MSIX_TABLE_BIR, MSIX_TABLE_ENTRY_SIZE,
@@ -691,6 +693,38 @@ impl VfioPciDevice {
             .chain_err(|| "Failed disable irqfds in kvm")?;
         Ok(())
     }
+
+    /// Unregister the irqfds from kvm and release the gsi numbers they used.
+    fn release_gsi_routes(&mut self) -> Result<()> {
+        let mut gsi_routes = self.gsi_msi_routes.lock().unwrap();
+        for gsi_route in gsi_routes.iter() {
+            if gsi_route.gsi == -1 {
+                continue;
+            }
+            if let Some(irq_fd) = gsi_route.irq_fd.as_ref() {
+                KVM_FDS
+                    .load()
+                    .vm_fd
+                    .as_ref()
+                    .unwrap()
+                    .unregister_irqfd(irq_fd, gsi_route.gsi as u32)
+                    .chain_err(|| "Failed to unregister irqfd")?;
+            }
+            KVM_FDS
+                .load()
+                .irq_route_table
+                .lock()
+                .unwrap()
+                .release_gsi(gsi_route.gsi as u32)
+                .chain_err(|| "Failed to release gsi")?;
+        }
+        gsi_routes.clear();
+        KVM_FDS
+            .load()
+            .commit_irq_routing()
+            .chain_err(|| "Failed to commit irq routing")?;
+        Ok(())
+    }
 }
 
 impl PciDevOps for VfioPciDevice {
@@ -747,12 +781,13 @@ impl PciDevOps for VfioPciDevice {
         PciResultExt::chain_err(self.register_bars(), || "Failed to register bars")?;
 
         let devfn = self.devfn;
+        let name = self.name.clone();
         let dev = Arc::new(Mutex::new(self));
         let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
         let mut locked_pci_bus = pci_bus.lock().unwrap();
         let pci_device = locked_pci_bus.devices.get(&devfn);
         if pci_device.is_none() {
-            locked_pci_bus.devices.insert(devfn, dev);
+            locked_pci_bus.devices.insert(devfn, dev.clone());
         } else {
             bail!(
                 "Devfn {:?} has been used by {:?}",
@@ -760,10 +795,46 @@ impl PciDevOps for VfioPciDevice {
                 pci_device.unwrap().lock().unwrap().name()
             );
         }
+        drop(locked_pci_bus);
+
+        if let Err(e) = handle_plug(&pci_bus, &(dev as Arc<Mutex<dyn PciDevOps>>)) {
+            pci_bus.lock().unwrap().devices.remove(&devfn);
+            return Err(e);
+        }
+        MigrationManager::register_blocker(
+            &name,
+            "Dirty page tracking of VFIO device is not supported",
+            false,
+        );
 
         Ok(())
     }
 
+    fn unrealize(&mut self) -> PciResult<()> {
+        use pci::errors::ResultExt as PciResultExt;
+
+        if let Some(msix) = &self.pci_config.msix {
+            let cap_offset = msix.lock().unwrap().msix_cap_offset as usize;
+            if is_msix_enabled(cap_offset, &self.pci_config.config) {
+                PciResultExt::chain_err(self.vfio_disable_msix(), || "Failed to disable MSI-X")?;
+            }
+        }
+        PciResultExt::chain_err(self.release_gsi_routes(), || "Failed to release gsi routes")?;
+
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+        self.pci_config.unregister_bars(
+            #[cfg(target_arch = "x86_64")]
+            &locked_parent_bus.io_region,
+            &locked_parent_bus.mem_region,
+        )?;
+        drop(locked_parent_bus);
+
+        PciResultExt::chain_err(self.vfio_device.reset(), || "Failed to reset vfio device")?;
+        MigrationManager::unregister_blocker(&self.name);
+        Ok(())
+    }
+
     /// Read pci data from pci config if it emulate, otherwise read from vfio device.
     fn read_config(&self, offset: usize, data: &mut [u8]) {
         let size = data.len();
@@ -896,6 +967,10 @@ impl PciDevOps for VfioPciDevice {
     fn name(&self) -> String {
         self.name.clone()
     }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
 }
 
 fn get_irq_rawfds(gsi_msi_routes: &[GsiMsiRoute]) -> Vec<RawFd> {
@@ -15,7 +15,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{
     cmp::{self, Reverse},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use address_space::{
@@ -23,7 +23,10 @@ use address_space::{
 };
 use error_chain::ChainedError;
 use machine_manager::{
-    config::BalloonConfig, event_loop::EventLoop, qmp::qmp_schema::BalloonInfo, qmp::QmpChannel,
+    config::BalloonConfig,
+    event_loop::EventLoop,
+    qmp::qmp_schema::{BalloonInfo, BalloonPolicyInfo, BalloonStats},
+    qmp::QmpChannel,
 };
 use util::{
     bitmap::Bitmap,
@@ -39,10 +42,12 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, timerfd::TimerFd};
 
 use super::{
     errors::*, Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
-    VIRTIO_F_VERSION_1, VIRTIO_TYPE_BALLOON,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BALLOON,
 };
 
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
 const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
 const QUEUE_SIZE_BALLOON: u16 = 256;
 const QUEUE_NUM_BALLOON: usize = 2;
@@ -50,6 +55,27 @@ const BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
 const BALLOON_INFLATE_EVENT: bool = true;
 const BALLOON_DEFLATE_EVENT: bool = false;
 const BITS_OF_TYPE_U64: u64 = 64;
+/// Interval in seconds of the automatic balloon policy.
+const BALLOON_POLICY_INTERVAL: u64 = 5;
+/// Memory size by which the automatic balloon policy changes the target each time.
+const BALLOON_POLICY_STEP: u64 = 128 << 20;
+/// The balloon is inflated if host memory pressure is not less than it.
+const MEMORY_PRESSURE_HIGH: f64 = 10.0;
+/// The balloon is deflated if host memory pressure is not more than it.
+const MEMORY_PRESSURE_LOW: f64 = 1.0;
+const MEMORY_PRESSURE_PATH: &str = "/proc/pressure/memory";
+
+// Tags of memory statistics, refer to Virtio Spec.
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
 
 static mut BALLOON_DEV: Option<Arc<Mutex<Balloon>>> = None;
 
@@ -71,8 +97,39 @@ struct VirtioBalloonConfig {
     pub actual: u32,
 }
 
+/// Memory statistic reported by guest in statistics queue.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default)]
+struct VirtioBalloonStat {
+    tag: u16,
+    val: u64,
+}
+
 impl ByteCode for Iovec {}
 impl ByteCode for VirtioBalloonConfig {}
+impl ByteCode for VirtioBalloonStat {}
+
+/// Update `stats` with the memory statistic reported by guest, unknown tag is ignored.
+fn update_balloon_stat(stats: &mut BalloonStats, stat: &VirtioBalloonStat) {
+    let val = stat.val;
+    let field = match stat.tag {
+        VIRTIO_BALLOON_S_SWAP_IN => &mut stats.stats.swap_in,
+        VIRTIO_BALLOON_S_SWAP_OUT => &mut stats.stats.swap_out,
+        VIRTIO_BALLOON_S_MAJFLT => &mut stats.stats.major_faults,
+        VIRTIO_BALLOON_S_MINFLT => &mut stats.stats.minor_faults,
+        VIRTIO_BALLOON_S_MEMFREE => &mut stats.stats.free_memory,
+        VIRTIO_BALLOON_S_MEMTOT => &mut stats.stats.total_memory,
+        VIRTIO_BALLOON_S_AVAIL => &mut stats.stats.available_memory,
+        VIRTIO_BALLOON_S_CACHES => &mut stats.stats.disk_caches,
+        VIRTIO_BALLOON_S_HTLB_PGALLOC => &mut stats.stats.htlb_pgalloc,
+        VIRTIO_BALLOON_S_HTLB_PGFAIL => &mut stats.stats.htlb_pgfail,
+        tag => {
+            debug!("Unknown tag {} of balloon statistics", tag);
+            return;
+        }
+    };
+    *field = val;
+}
 
 /// Bitmap for balloon. It is used if the host page size is bigger than 4k.
 struct BalloonedPageBitmap {
@@ -336,6 +393,19 @@ impl BlnMemInfo {
         None
     }
 
+    /// Get the host address of the guest memory range, which must be in one region.
+    fn get_host_range(&self, addr: GuestAddress, len: u64) -> Option<u64> {
+        let all_regions = self.regions.lock().unwrap();
+        for region in all_regions.iter() {
+            if addr.raw_value() >= region.guest_phys_addr
+                && addr.raw_value() + len <= region.guest_phys_addr + region.memory_size
+            {
+                return Some(region.userspace_addr + addr.raw_value() - region.guest_phys_addr);
+            }
+        }
+        None
+    }
+
     fn has_huge_page(&self) -> bool {
         let all_regions = self.regions.lock().unwrap();
         for reg in all_regions.iter() {
@@ -458,6 +528,20 @@ struct BalloonIoHandler {
     event_timer: Arc<Mutex<TimerFd>>,
     /// Actual balloon size
     balloon_actual: Arc<AtomicU32>,
+    /// Statistics queue.
+    stats_queue: Option<Arc<Mutex<Queue>>>,
+    /// Statistics EventFd.
+    stats_evt: Option<EventFd>,
+    /// The descriptor of statistics queue held until the next polling.
+    stats_desc_index: Option<u16>,
+    /// Timer to poll the memory statistics of guest.
+    stats_timer: Option<TimerFd>,
+    /// The latest memory statistics of guest.
+    stats: Arc<Mutex<BalloonStats>>,
+    /// Free page reporting queue.
+    report_queue: Option<Arc<Mutex<Queue>>>,
+    /// Free page reporting EventFd.
+    report_evt: Option<EventFd>,
 }
 
 impl BalloonIoHandler {
@@ -504,6 +588,108 @@ impl BalloonIoHandler {
         Ok(())
     }
 
+    /// Process statistics queue, the statistics in the buffer are saved and the buffer
+    /// is held until the next polling.
+    fn process_stats_queue(&mut self) -> Result<()> {
+        let queue = match &self.stats_queue {
+            Some(queue) => queue.clone(),
+            None => return Ok(()),
+        };
+        let mut locked_queue = queue.lock().unwrap();
+        while let Ok(elem) = locked_queue
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            if let Some(index) = self.stats_desc_index.replace(elem.index) {
+                // The driver should not post more than one buffer, return the stale one.
+                locked_queue
+                    .vring
+                    .add_used(&self.mem_space, index, 0)
+                    .chain_err(|| "Failed to add balloon statistics into used queue")?;
+            }
+
+            let mut buffer = Vec::new();
+            for elem_iov in elem.out_iovec.iter() {
+                self.mem_space
+                    .read(&mut buffer, elem_iov.addr, elem_iov.len as u64)
+                    .chain_err(|| "Failed to read balloon statistics")?;
+            }
+            let mut locked_stats = self.stats.lock().unwrap();
+            for chunk in buffer.chunks_exact(std::mem::size_of::<VirtioBalloonStat>()) {
+                if let Some(stat) = VirtioBalloonStat::from_bytes(chunk) {
+                    update_balloon_stat(&mut locked_stats, stat);
+                }
+            }
+            locked_stats.last_update = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    /// Return the held buffer of statistics queue, by which the guest is asked to
+    /// report the statistics again.
+    fn poll_stats(&mut self) -> Result<()> {
+        let queue = match &self.stats_queue {
+            Some(queue) => queue.clone(),
+            None => return Ok(()),
+        };
+        let index = match self.stats_desc_index.take() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let locked_queue = &mut queue.lock().unwrap();
+        locked_queue
+            .vring
+            .add_used(&self.mem_space, index, 0)
+            .chain_err(|| "Failed to add balloon statistics into used queue")?;
+        (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(locked_queue))
+            .chain_err(|| ErrorKind::InterruptTrigger("balloon", VirtioInterruptType::Vring))?;
+        Ok(())
+    }
+
+    /// Process free page reporting queue, the free pages reported by guest are released.
+    fn process_report_queue(&mut self) -> Result<()> {
+        let queue = match &self.report_queue {
+            Some(queue) => queue.clone(),
+            None => return Ok(()),
+        };
+        let mut locked_queue = queue.lock().unwrap();
+        while let Ok(elem) = locked_queue
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            if !self.mem_info.has_huge_page() {
+                for elem_iov in elem.in_iovec.iter() {
+                    match self
+                        .mem_info
+                        .get_host_range(elem_iov.addr, elem_iov.len as u64)
+                    {
+                        Some(hva) => memory_advise(
+                            hva as *const libc::c_void as *mut _,
+                            elem_iov.len as usize,
+                            libc::MADV_DONTNEED,
+                        ),
+                        None => error!(
+                            "Can not get host address of free pages, gpa: {}, len: {}",
+                            elem_iov.addr.raw_value(),
+                            elem_iov.len
+                        ),
+                    }
+                }
+            }
+            locked_queue
+                .vring
+                .add_used(&self.mem_space, elem.index, 0)
+                .chain_err(|| "Failed to add free page report into used queue")?;
+        }
+
+        (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue))
+            .chain_err(|| ErrorKind::InterruptTrigger("balloon", VirtioInterruptType::Vring))?;
+        Ok(())
+    }
+
     /// Send balloon changed event.
     fn send_balloon_changed_event(&self) {
         let ram_size = self.mem_info.get_ram_size();
@@ -520,7 +706,7 @@ impl BalloonIoHandler {
     }
 
     fn reset_evt_handler(&self) -> Vec<EventNotifier> {
-        let notifiers = vec![
+        let mut notifiers = vec![
             EventNotifier::new(
                 NotifierOperation::Delete,
                 self.reset_evt,
@@ -550,6 +736,20 @@ impl BalloonIoHandler {
                 Vec::new(),
             ),
         ];
+        let optional_fds = [
+            self.stats_evt.as_ref().map(|evt| evt.as_raw_fd()),
+            self.stats_timer.as_ref().map(|timer| timer.as_raw_fd()),
+            self.report_evt.as_ref().map(|evt| evt.as_raw_fd()),
+        ];
+        for fd in optional_fds.iter().flatten() {
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::Delete,
+                *fd,
+                None,
+                EventSet::IN,
+                Vec::new(),
+            ));
+        }
 
         notifiers
     }
@@ -641,6 +841,45 @@ impl EventNotifierHelper for BalloonIoHandler {
             handler,
         ));
 
+        // register event notifier for statistics event.
+        if let Some(stats_evt) = &locked_balloon_io.stats_evt {
+            let cloned_balloon_io = balloon_io.clone();
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(e) = cloned_balloon_io.lock().unwrap().process_stats_queue() {
+                    error!("Failed to get balloon statistics: {}", e.display_chain());
+                };
+                None
+            });
+            notifiers.push(build_event_notifier(stats_evt.as_raw_fd(), handler));
+        }
+
+        // register event notifier for statistics polling timer.
+        if let Some(stats_timer) = &locked_balloon_io.stats_timer {
+            let cloned_balloon_io = balloon_io.clone();
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(e) = cloned_balloon_io.lock().unwrap().poll_stats() {
+                    error!("Failed to poll balloon statistics: {}", e.display_chain());
+                };
+                None
+            });
+            notifiers.push(build_event_notifier(stats_timer.as_raw_fd(), handler));
+        }
+
+        // register event notifier for free page reporting event.
+        if let Some(report_evt) = &locked_balloon_io.report_evt {
+            let cloned_balloon_io = balloon_io.clone();
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(e) = cloned_balloon_io.lock().unwrap().process_report_queue() {
+                    error!("Failed to release free pages: {}", e.display_chain());
+                };
+                None
+            });
+            notifiers.push(build_event_notifier(report_evt.as_raw_fd(), handler));
+        }
+
         notifiers
     }
 }
@@ -665,6 +904,10 @@ pub struct Balloon {
     event_timer: Arc<Mutex<TimerFd>>,
     /// EventFd for device reset.
     reset_evt: EventFd,
+    /// Interval in seconds to poll the memory statistics of guest.
+    stats_polling_interval: u64,
+    /// The latest memory statistics of guest.
+    stats: Arc<Mutex<BalloonStats>>,
 }
 
 impl Balloon {
@@ -674,10 +917,16 @@ impl Balloon {
     ///
     /// * `bln_cfg` - Balloon configuration.
     pub fn new(bln_cfg: &BalloonConfig, mem_space: Arc<AddressSpace>) -> Balloon {
-        let mut device_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut device_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_PACKED;
         if bln_cfg.deflate_on_oom {
             device_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
         }
+        if bln_cfg.stats_polling_interval > 0 {
+            device_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+        }
+        if bln_cfg.free_page_reporting {
+            device_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+        }
 
         Balloon {
             device_features,
@@ -689,6 +938,8 @@ impl Balloon {
             mem_space,
             event_timer: Arc::new(Mutex::new(TimerFd::new().unwrap())),
             reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            stats_polling_interval: bln_cfg.stats_polling_interval,
+            stats: Arc::new(Mutex::new(BalloonStats::default())),
         }
     }
 
@@ -747,6 +998,86 @@ impl Balloon {
     pub fn get_guest_memory_size(&self) -> u64 {
         self.mem_info.get_ram_size() - self.get_balloon_memory_size()
     }
+
+    /// Get the target memory size of guest.
+    fn get_target_memory_size(&self) -> u64 {
+        self.mem_info.get_ram_size() - ((self.num_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT)
+    }
+
+    /// Start the automatic balloon policy if it is configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev` - Balloon device.
+    /// * `bln_cfg` - Balloon configuration.
+    pub fn policy_init(dev: Arc<Mutex<Balloon>>, bln_cfg: &BalloonConfig) -> Result<()> {
+        if !bln_cfg.auto_balloon {
+            return Ok(());
+        }
+        if read_memory_pressure().is_none() {
+            bail!(
+                "Failed to get host memory pressure from {}",
+                MEMORY_PRESSURE_PATH
+            );
+        }
+
+        let interval = Duration::from_secs(BALLOON_POLICY_INTERVAL);
+        let mut timer = TimerFd::new().chain_err(|| "Failed to create balloon policy timer")?;
+        timer
+            .reset(interval, Some(interval))
+            .chain_err(|| "Failed to reset balloon policy timer")?;
+        let policy = Arc::new(Mutex::new(BalloonPolicy {
+            balloon: dev,
+            min: bln_cfg.auto_balloon_min,
+            max: bln_cfg.auto_balloon_max,
+            timer,
+        }));
+
+        let cloned_policy = policy.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = cloned_policy.lock().unwrap().adjust() {
+                error!("Failed to adjust balloon by policy: {}", e.display_chain());
+            }
+            None
+        });
+        let notifier = build_event_notifier(policy.lock().unwrap().timer.as_raw_fd(), handler);
+        EventLoop::update_event(vec![notifier], None)
+            .chain_err(|| "Failed to register balloon policy event notifier to MainLoop")?;
+        Ok(())
+    }
+
+    /// Get the latest memory statistics of guest.
+    pub fn get_guest_memory_stats(&self) -> BalloonStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Check if the feature is negotiated, or offered if guest hasn't acknowledged
+    /// features yet. The optional queues only exist if their features are negotiated.
+    fn has_feature(&self, feature: u32) -> bool {
+        let features = if self.driver_features == 0 {
+            self.device_features
+        } else {
+            self.driver_features
+        };
+        features & (1u64 << feature) != 0
+    }
+
+    /// Get the indexes of statistics queue and free page reporting queue, which
+    /// follow inflate and deflate queues, and the queue not negotiated is skipped.
+    fn optional_queue_index(&self) -> (Option<usize>, Option<usize>) {
+        let mut queue_index = QUEUE_NUM_BALLOON;
+        let mut stats_index = None;
+        if self.has_feature(VIRTIO_BALLOON_F_STATS_VQ) {
+            stats_index = Some(queue_index);
+            queue_index += 1;
+        }
+        let mut report_index = None;
+        if self.has_feature(VIRTIO_BALLOON_F_REPORTING) {
+            report_index = Some(queue_index);
+        }
+        (stats_index, report_index)
+    }
 }
 
 impl VirtioDevice for Balloon {
@@ -765,9 +1096,12 @@ impl VirtioDevice for Balloon {
         VIRTIO_TYPE_BALLOON as u32
     }
 
-    /// Get the number of balloon-device queues.
+    /// Get the number of balloon-device queues, statistics queue and free page
+    /// reporting queue follow inflate and deflate queues if negotiated.
     fn queue_num(&self) -> usize {
         QUEUE_NUM_BALLOON
+            + self.has_feature(VIRTIO_BALLOON_F_STATS_VQ) as usize
+            + self.has_feature(VIRTIO_BALLOON_F_REPORTING) as usize
     }
 
     /// Get the zise of balloon queue.
@@ -861,14 +1195,34 @@ impl VirtioDevice for Balloon {
         queues: &[Arc<Mutex<Queue>>],
         mut queue_evts: Vec<EventFd>,
     ) -> Result<()> {
-        if queues.len() != QUEUE_NUM_BALLOON {
-            return Err(ErrorKind::IncorrectQueueNum(QUEUE_NUM_BALLOON, queues.len()).into());
+        if queues.len() != self.queue_num() {
+            return Err(ErrorKind::IncorrectQueueNum(self.queue_num(), queues.len()).into());
         }
 
         let inf_queue = queues[0].clone();
         let inf_queue_evt = queue_evts.remove(0);
         let def_queue = queues[1].clone();
         let def_queue_evt = queue_evts.remove(0);
+        let (stats_index, report_index) = self.optional_queue_index();
+
+        let (mut stats_queue, mut stats_evt, mut stats_timer) = (None, None, None);
+        if let Some(index) = stats_index {
+            stats_queue = Some(queues[index].clone());
+            stats_evt = Some(queue_evts.remove(0));
+
+            let interval = Duration::from_secs(self.stats_polling_interval);
+            let mut timer = TimerFd::new().chain_err(|| "Failed to create balloon stats timer")?;
+            timer
+                .reset(interval, Some(interval))
+                .chain_err(|| "Failed to reset balloon stats timer")?;
+            stats_timer = Some(timer);
+        }
+
+        let (mut report_queue, mut report_evt) = (None, None);
+        if let Some(index) = report_index {
+            report_queue = Some(queues[index].clone());
+            report_evt = Some(queue_evts.remove(0));
+        }
 
         self.interrupt_cb = Some(interrupt_cb.clone());
         let handler = BalloonIoHandler {
@@ -883,6 +1237,13 @@ impl VirtioDevice for Balloon {
             mem_info: self.mem_info.clone(),
             event_timer: self.event_timer.clone(),
             balloon_actual: self.actual.clone(),
+            stats_queue,
+            stats_evt,
+            stats_desc_index: None,
+            stats_timer,
+            stats: self.stats.clone(),
+            report_queue,
+            report_evt,
         };
 
         EventLoop::update_event(
@@ -895,6 +1256,8 @@ impl VirtioDevice for Balloon {
     }
 
     fn reset(&mut self) -> Result<()> {
+        // Features are negotiated again after reset, which may change the queues.
+        self.driver_features = 0;
         self.reset_evt
             .write(1)
             .chain_err(|| ErrorKind::EventFdWrite)
@@ -908,6 +1271,108 @@ impl VirtioDevice for Balloon {
     }
 }
 
+/// Read the percentage of time in the last 10 seconds that host tasks stalled on memory.
+fn read_memory_pressure() -> Option<f64> {
+    let content = std::fs::read_to_string(MEMORY_PRESSURE_PATH).ok()?;
+    parse_memory_pressure(&content)
+}
+
+/// Parse the `some avg10` field of PSI, e.g.
+/// "some avg10=0.00 avg60=0.00 avg300=0.00 total=0".
+fn parse_memory_pressure(content: &str) -> Option<f64> {
+    let line = content.lines().find(|line| line.starts_with("some "))?;
+    line.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse::<f64>()
+        .ok()
+}
+
+/// Decide the new target memory size of guest by host memory pressure, return the
+/// action and the new target, or None if the target is unchanged.
+///
+/// # Arguments
+///
+/// * `pressure` - Host memory pressure.
+/// * `target` - Current target memory size of guest.
+/// * `actual` - Actual memory size of guest.
+/// * `min` - Minimum memory size of guest.
+/// * `max` - Maximum memory size of guest.
+fn balloon_policy_decide(
+    pressure: f64,
+    target: u64,
+    actual: u64,
+    min: u64,
+    max: u64,
+) -> Option<(&'static str, u64)> {
+    if target > max {
+        return Some(("inflate", max));
+    }
+    if target < min {
+        return Some(("deflate", min));
+    }
+    // Inflate further only after the guest has reached the previous target.
+    if pressure >= MEMORY_PRESSURE_HIGH && target > min && actual <= target {
+        return Some((
+            "inflate",
+            cmp::max(target.saturating_sub(BALLOON_POLICY_STEP), min),
+        ));
+    }
+    if pressure <= MEMORY_PRESSURE_LOW && target < max {
+        return Some(("deflate", cmp::min(target + BALLOON_POLICY_STEP, max)));
+    }
+    None
+}
+
+/// Policy which inflates and deflates the balloon periodically, the balloon is
+/// inflated under high host memory pressure and deflated under low pressure.
+struct BalloonPolicy {
+    /// Balloon device.
+    balloon: Arc<Mutex<Balloon>>,
+    /// Minimum memory size of guest.
+    min: u64,
+    /// Maximum memory size of guest, 0 means the whole memory.
+    max: u64,
+    /// Timer to run the policy.
+    timer: TimerFd,
+}
+
+impl BalloonPolicy {
+    fn adjust(&mut self) -> Result<()> {
+        let pressure = match read_memory_pressure() {
+            Some(pressure) => pressure,
+            None => bail!("Failed to get host memory pressure"),
+        };
+        let mut locked_balloon = self.balloon.lock().unwrap();
+        // Balloon is not usable until the driver is ready.
+        if locked_balloon.interrupt_cb.is_none() {
+            return Ok(());
+        }
+
+        let ram_size = locked_balloon.mem_info.get_ram_size();
+        let max = if self.max == 0 {
+            ram_size
+        } else {
+            cmp::min(self.max, ram_size)
+        };
+        let min = cmp::min(self.min, max);
+        let target = locked_balloon.get_target_memory_size();
+        let actual = locked_balloon.get_guest_memory_size();
+        if let Some((action, new_target)) =
+            balloon_policy_decide(pressure, target, actual, min, max)
+        {
+            locked_balloon.set_guest_memory_size(new_target)?;
+            let msg = BalloonPolicyInfo {
+                action: action.to_string(),
+                target: new_target,
+                actual,
+                pressure,
+            };
+            event!(BalloonPolicy; msg);
+        }
+        Ok(())
+    }
+}
+
 pub fn qmp_balloon(target: u64) -> bool {
     // Safe, because there is no confliction when writing global variable BALLOON_DEV, in other words,
     // this function will not be called simultaneously.
@@ -940,6 +1405,16 @@ pub fn qmp_query_balloon() -> Option<u64> {
     None
 }
 
+pub fn qmp_query_balloon_stats() -> Option<BalloonStats> {
+    // Safe, because there is no confliction when writing global variable BALLOON_DEV, in other words,
+    // this function will not be called simultaneously.
+    if let Some(dev) = unsafe { &BALLOON_DEV } {
+        let unlocked_dev = dev.lock().unwrap();
+        return Some(unlocked_dev.get_guest_memory_stats());
+    }
+    None
+}
+
 /// Create a syscall bpf rule for device `Balloon`.
 pub fn balloon_allow_list(syscall_allow_list: &mut Vec<BpfRule>) {
     syscall_allow_list.extend(vec![
@@ -958,6 +1433,7 @@ mod tests {
 
     const MEMORY_SIZE: u64 = 1024 * 1024;
     const QUEUE_SIZE: u16 = 256;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x02;
 
     fn address_space_init() -> Arc<AddressSpace> {
         let root = Region::init_container_region(1 << 36);
@@ -995,6 +1471,7 @@ mod tests {
         let bln_cfg = BalloonConfig {
             id: "bln".to_string(),
             deflate_on_oom: true,
+            ..Default::default()
         };
 
         let mem_space = address_space_init();
@@ -1003,7 +1480,9 @@ mod tests {
         assert_eq!(bln.actual.load(Ordering::Acquire), 0);
         assert_eq!(bln.num_pages, 0);
         assert!(bln.interrupt_cb.is_none());
-        let feature = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+        let feature = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_F_RING_PACKED)
+            | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
         assert_eq!(bln.device_features, feature);
 
         let fts = bln.get_device_features(0);
@@ -1036,6 +1515,7 @@ mod tests {
         let bln_cfg = BalloonConfig {
             id: "bln".to_string(),
             deflate_on_oom: true,
+            ..Default::default()
         };
 
         let mem_space = address_space_init();
@@ -1054,6 +1534,7 @@ mod tests {
         let bln_cfg = BalloonConfig {
             id: "bln".to_string(),
             deflate_on_oom: true,
+            ..Default::default()
         };
 
         let mem_space = address_space_init();
@@ -1071,6 +1552,7 @@ mod tests {
         let bln_cfg = BalloonConfig {
             id: "bln".to_string(),
             deflate_on_oom: true,
+            ..Default::default()
         };
         let mut bln = Balloon::new(&bln_cfg, mem_space.clone());
         bln.realize().unwrap();
@@ -1130,6 +1612,13 @@ mod tests {
             mem_info: bln.mem_info.clone(),
             event_timer: bln.event_timer.clone(),
             balloon_actual: bln.actual.clone(),
+            stats_queue: None,
+            stats_evt: None,
+            stats_desc_index: None,
+            stats_timer: None,
+            stats: bln.stats.clone(),
+            report_queue: None,
+            report_evt: None,
         };
 
         let balloon = Arc::new(Mutex::new(bln));
@@ -1194,6 +1683,169 @@ mod tests {
         assert!(handler.process_balloon_queue(BALLOON_DEFLATE_EVENT).is_ok());
     }
 
+    #[test]
+    fn test_balloon_stats_and_report() {
+        let mem_space = address_space_init();
+        let bln_cfg = BalloonConfig {
+            id: "bln".to_string(),
+            stats_polling_interval: 1,
+            free_page_reporting: true,
+            ..Default::default()
+        };
+        let mut bln = Balloon::new(&bln_cfg, mem_space.clone());
+        assert_eq!(bln.queue_num(), 4);
+        bln.realize().unwrap();
+        let blninfo = BlnMemInfo::new();
+        assert!(blninfo
+            .handle_request(
+                Some(&create_flat_range(0, MEMORY_SIZE, 0)),
+                None,
+                ListenerReqType::AddRegion
+            )
+            .is_ok());
+        bln.mem_info = blninfo;
+
+        let cb = Arc::new(Box::new(
+            move |_int_type: &VirtioInterruptType, _queue: Option<&Queue>| Ok(()),
+        ) as VirtioInterrupt);
+        let mut queues = Vec::new();
+        for i in 0..bln.queue_num() as u64 {
+            let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+            queue_config.desc_table = GuestAddress(0x10000 + i * 0x4000);
+            queue_config.avail_ring = GuestAddress(0x11000 + i * 0x4000);
+            queue_config.used_ring = GuestAddress(0x12000 + i * 0x4000);
+            queue_config.ready = true;
+            queue_config.size = QUEUE_SIZE;
+            queues.push(Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())));
+        }
+
+        let mut handler = BalloonIoHandler {
+            driver_features: bln.driver_features,
+            mem_space: mem_space.clone(),
+            inf_queue: queues[0].clone(),
+            inf_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            def_queue: queues[1].clone(),
+            def_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            reset_evt: bln.reset_evt.as_raw_fd(),
+            interrupt_cb: cb,
+            mem_info: bln.mem_info.clone(),
+            event_timer: bln.event_timer.clone(),
+            balloon_actual: bln.actual.clone(),
+            stats_queue: Some(queues[2].clone()),
+            stats_evt: None,
+            stats_desc_index: None,
+            stats_timer: None,
+            stats: bln.stats.clone(),
+            report_queue: Some(queues[3].clone()),
+            report_evt: None,
+        };
+
+        // Guest reports free memory and major faults by statistics queue.
+        let stats = [
+            VirtioBalloonStat {
+                tag: VIRTIO_BALLOON_S_MEMFREE,
+                val: 0x8000,
+            },
+            VirtioBalloonStat {
+                tag: VIRTIO_BALLOON_S_MAJFLT,
+                val: 3,
+            },
+        ];
+        for (i, stat) in stats.iter().enumerate() {
+            mem_space
+                .write_object::<VirtioBalloonStat>(stat, GuestAddress(0x30000 + i as u64 * 10))
+                .unwrap();
+        }
+        let desc = SplitVringDesc {
+            addr: GuestAddress(0x30000),
+            len: 20,
+            flags: 0,
+            next: 0,
+        };
+        mem_space
+            .write_object::<SplitVringDesc>(&desc, GuestAddress(0x18000))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(0x19000 + 2))
+            .unwrap();
+        assert!(handler.process_stats_queue().is_ok());
+        assert_eq!(handler.stats_desc_index, Some(0));
+        let stats = bln.get_guest_memory_stats();
+        assert_eq!(stats.stats.free_memory, 0x8000);
+        assert_eq!(stats.stats.major_faults, 3);
+        assert_ne!(stats.last_update, 0);
+
+        // The buffer is returned to guest when polling.
+        assert!(handler.poll_stats().is_ok());
+        assert_eq!(handler.stats_desc_index, None);
+        let used_idx = mem_space
+            .read_object::<u16>(GuestAddress(0x1a000 + 2))
+            .unwrap();
+        assert_eq!(used_idx, 1);
+
+        // Guest reports free pages by free page reporting queue.
+        let desc = SplitVringDesc {
+            addr: GuestAddress(0x40000),
+            len: 0x1000,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        mem_space
+            .write_object::<SplitVringDesc>(&desc, GuestAddress(0x1c000))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(0x1d000 + 2))
+            .unwrap();
+        assert!(handler.process_report_queue().is_ok());
+        let used_idx = mem_space
+            .read_object::<u16>(GuestAddress(0x1e000 + 2))
+            .unwrap();
+        assert_eq!(used_idx, 1);
+    }
+
+    #[test]
+    fn test_balloon_policy() {
+        let content = "some avg10=12.50 avg60=3.00 avg300=1.00 total=100\n\
+                       full avg10=2.00 avg60=1.00 avg300=0.50 total=50\n";
+        assert_eq!(parse_memory_pressure(content), Some(12.5));
+        assert_eq!(parse_memory_pressure("full avg10=2.00"), None);
+
+        let gib = 1_u64 << 30;
+        let step = BALLOON_POLICY_STEP;
+        // Target is kept in the range.
+        assert_eq!(
+            balloon_policy_decide(5.0, 4 * gib, 4 * gib, gib, 2 * gib),
+            Some(("inflate", 2 * gib))
+        );
+        assert_eq!(
+            balloon_policy_decide(5.0, gib / 2, gib / 2, gib, 2 * gib),
+            Some(("deflate", gib))
+        );
+        // Inflate under high pressure, until the minimum.
+        assert_eq!(
+            balloon_policy_decide(20.0, 2 * gib, 2 * gib, gib, 2 * gib),
+            Some(("inflate", 2 * gib - step))
+        );
+        assert_eq!(
+            balloon_policy_decide(20.0, gib + step / 2, gib + step / 2, gib, 2 * gib),
+            Some(("inflate", gib))
+        );
+        assert_eq!(balloon_policy_decide(20.0, gib, gib, gib, 2 * gib), None);
+        // Guest has not reached the target yet.
+        assert_eq!(
+            balloon_policy_decide(20.0, 2 * gib - step, 2 * gib, gib, 2 * gib),
+            None
+        );
+        // Deflate under low pressure, until the maximum.
+        assert_eq!(
+            balloon_policy_decide(0.0, gib, gib, gib, 2 * gib),
+            Some(("deflate", gib + step))
+        );
+        assert_eq!(balloon_policy_decide(0.0, 2 * gib, gib, gib, 2 * gib), None);
+        // Hold between the thresholds.
+        assert_eq!(balloon_policy_decide(5.0, gib, gib, gib, 2 * gib), None);
+    }
+
     #[test]
     fn test_balloon_activate() {
         let mem_space = address_space_init();
@@ -1226,6 +1878,7 @@ mod tests {
         let bln_cfg = BalloonConfig {
             id: "bln".to_string(),
             deflate_on_oom: true,
+            ..Default::default()
         };
         let mut bln = Balloon::new(&bln_cfg, mem_space.clone());
         assert!(bln
@@ -1233,6 +1886,43 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_balloon_queue_layout() {
+        let bln_cfg = BalloonConfig {
+            id: "bln".to_string(),
+            stats_polling_interval: 5,
+            free_page_reporting: true,
+            ..Default::default()
+        };
+        let mem_space = address_space_init();
+        let mut bln = Balloon::new(&bln_cfg, mem_space);
+        // All the queues offered are exposed before features are negotiated.
+        assert_eq!(bln.queue_num(), 4);
+        assert_eq!(bln.optional_queue_index(), (Some(2), Some(3)));
+
+        // Guest acknowledges free page reporting without statistics queue.
+        bln.set_driver_features(0, 1 << VIRTIO_BALLOON_F_REPORTING);
+        bln.set_driver_features(1, 1 << (VIRTIO_F_VERSION_1 - 32));
+        assert_eq!(bln.queue_num(), 3);
+        assert_eq!(bln.optional_queue_index(), (None, Some(2)));
+
+        // Guest acknowledges statistics queue without free page reporting.
+        bln.reset().unwrap();
+        bln.set_driver_features(0, 1 << VIRTIO_BALLOON_F_STATS_VQ);
+        bln.set_driver_features(1, 1 << (VIRTIO_F_VERSION_1 - 32));
+        assert_eq!(bln.queue_num(), 3);
+        assert_eq!(bln.optional_queue_index(), (Some(2), None));
+
+        // Guest acknowledges both of them.
+        bln.reset().unwrap();
+        bln.set_driver_features(
+            0,
+            1 << VIRTIO_BALLOON_F_STATS_VQ | 1 << VIRTIO_BALLOON_F_REPORTING,
+        );
+        assert_eq!(bln.queue_num(), 4);
+        assert_eq!(bln.optional_queue_index(), (Some(2), Some(3)));
+    }
+
     #[test]
     fn test_balloon_memory_listener() {
         let mut blndef = BlnMemoryRegion::default();
@@ -19,33 +19,44 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use address_space::{AddressSpace, GuestAddress};
 use error_chain::ChainedError;
 use machine_manager::{
-    config::{BlkDevConfig, ConfigCheck},
+    config::{BlkDevConfig, ConfigCheck, DiskFormat, ThrottleConfig, WriteZeroesState},
     event_loop::EventLoop,
+    machine::BlockDeviceInterface,
+    qmp::qmp_schema::{
+        BlockDeviceInfo, BlockDeviceStats, BlockInfo, BlockStats, BlockdevCacheInfo,
+    },
 };
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
-use util::aio::{Aio, AioCb, AioCompleteFunc, IoCmd, Iovec};
+use util::aio::{
+    raw_discard, raw_write_zeroes, Aio, AioCb, AioCompleteFunc, AioEngine, IoCmd, Iovec,
+};
 use util::byte_code::ByteCode;
 use util::leak_bucket::LeakBucket;
 use util::loop_context::{
-    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+    read_fd, EventLoopContext, EventNotifier, EventNotifierHelper, NotifierCallback,
+    NotifierOperation,
 };
 use util::num_ops::{read_u32, write_u32};
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use super::errors::{ErrorKind, Result, ResultExt};
+use super::qcow2::Qcow2Driver;
 use super::{
-    Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_BLK_F_FLUSH,
-    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_ID_BYTES,
-    VIRTIO_BLK_S_OK, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
+    ElemIovec, Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
 };
 
-/// Number of virtqueues.
-const QUEUE_NUM_BLK: usize = 1;
 /// Size of each virtqueue.
 const QUEUE_SIZE_BLK: u16 = 256;
 /// Used to compute the number of sectors.
@@ -54,8 +65,19 @@ const SECTOR_SHIFT: u8 = 9;
 const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 /// Size of the dummy block device.
 const DUMMY_IMG_SIZE: u64 = 0;
-
-type SenderConfig = (Option<Arc<File>>, u64, Option<String>, bool);
+/// Max number of sectors of a discard or write zeroes request.
+const MAX_REQUEST_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+
+type SenderConfig = (
+    Option<Arc<File>>,
+    Option<Arc<Mutex<Qcow2Driver>>>,
+    u64,
+    Option<String>,
+    bool,
+    AioEngine,
+    bool,
+    WriteZeroesState,
+);
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
     let mut id_bytes = vec![0; VIRTIO_BLK_ID_BYTES as usize];
@@ -86,7 +108,12 @@ struct RequestOutHeader {
 impl RequestOutHeader {
     fn is_valid(&self) -> bool {
         match self.request_type {
-            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_FLUSH | VIRTIO_BLK_T_GET_ID => true,
+            VIRTIO_BLK_T_IN
+            | VIRTIO_BLK_T_OUT
+            | VIRTIO_BLK_T_FLUSH
+            | VIRTIO_BLK_T_GET_ID
+            | VIRTIO_BLK_T_DISCARD
+            | VIRTIO_BLK_T_WRITE_ZEROES => true,
             _ => {
                 error!(
                     "request type {} is not supported for block",
@@ -100,6 +127,28 @@ impl RequestOutHeader {
 
 impl ByteCode for RequestOutHeader {}
 
+/// Segment of the discard and write zeroes request, refer to Virtio Spec.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardWriteZeroesSeg {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl ByteCode for DiscardWriteZeroesSeg {}
+
+/// Check whether all data in the buffers are zeroes.
+fn iovec_is_zero(iovec: &[Iovec]) -> bool {
+    iovec.iter().all(|iov| {
+        // Safe because the iovec is translated from guest memory which is mapped
+        // in host and keeps alive during the request.
+        let buf =
+            unsafe { std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize) };
+        buf.iter().all(|b| *b == 0)
+    })
+}
+
 #[derive(Clone)]
 pub struct AioCompleteCb {
     queue: Arc<Mutex<Queue>>,
@@ -109,26 +158,75 @@ pub struct AioCompleteCb {
     req_status_addr: GuestAddress,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
     driver_features: u64,
+    req_type: u32,
+    data_len: u64,
+    /// Guest memory written by the request, which is marked dirty when completed.
+    in_data: Vec<ElemIovec>,
+    /// The time when the request is received, used to account the latency.
+    start: Instant,
+    stats: Arc<Mutex<BlockDeviceStats>>,
 }
 
 impl AioCompleteCb {
     fn new(
         queue: Arc<Mutex<Queue>>,
         mem_space: Arc<AddressSpace>,
-        desc_index: u16,
+        req: &Request,
         rw_len: u32,
-        req_status_addr: GuestAddress,
         interrupt_cb: Option<Arc<VirtioInterrupt>>,
         driver_features: u64,
+        stats: Arc<Mutex<BlockDeviceStats>>,
     ) -> Self {
         AioCompleteCb {
             queue,
             mem_space,
-            desc_index,
+            desc_index: req.desc_index,
             rw_len,
-            req_status_addr,
+            req_status_addr: req.in_header,
             interrupt_cb,
             driver_features,
+            req_type: req.out_header.request_type,
+            data_len: req.data_len,
+            in_data: req.in_data.clone(),
+            start: Instant::now(),
+            stats,
+        }
+    }
+
+    /// Account the completed request in the IO statistics of the block device.
+    fn account(&self, failed: bool) {
+        let latency = self.start.elapsed().as_nanos() as u64;
+        let mut stats = self.stats.lock().unwrap();
+        match self.req_type {
+            VIRTIO_BLK_T_IN if failed => stats.failed_rd_operations += 1,
+            VIRTIO_BLK_T_IN => {
+                stats.rd_operations += 1;
+                stats.rd_bytes += self.data_len;
+                stats.rd_total_time_ns += latency;
+            }
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_WRITE_ZEROES if failed => {
+                stats.failed_wr_operations += 1
+            }
+            VIRTIO_BLK_T_OUT => {
+                stats.wr_operations += 1;
+                stats.wr_bytes += self.data_len;
+                stats.wr_total_time_ns += latency;
+            }
+            VIRTIO_BLK_T_WRITE_ZEROES => {
+                stats.wr_operations += 1;
+                stats.wr_total_time_ns += latency;
+            }
+            VIRTIO_BLK_T_FLUSH if failed => stats.failed_flush_operations += 1,
+            VIRTIO_BLK_T_FLUSH => {
+                stats.flush_operations += 1;
+                stats.flush_total_time_ns += latency;
+            }
+            VIRTIO_BLK_T_DISCARD if failed => stats.failed_unmap_operations += 1,
+            VIRTIO_BLK_T_DISCARD => {
+                stats.unmap_operations += 1;
+                stats.unmap_total_time_ns += latency;
+            }
+            _ => {}
         }
     }
 }
@@ -139,6 +237,8 @@ struct Request {
     iovec: Vec<Iovec>,
     data_len: u64,
     in_header: GuestAddress,
+    /// Guest memory which the data is read to, for read and get-id requests.
+    in_data: Vec<ElemIovec>,
 }
 
 impl Request {
@@ -188,6 +288,7 @@ impl Request {
             iovec: Vec::with_capacity(elem.desc_num as usize),
             data_len: 0,
             in_header: in_iov_elem.addr,
+            in_data: Vec::new(),
         };
 
         match out_header.request_type {
@@ -203,10 +304,11 @@ impl Request {
                         };
                         request.iovec.push(iov);
                         request.data_len += u64::from(elem_iov.len);
+                        request.in_data.push(*elem_iov);
                     }
                 }
             }
-            VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
                 for (index, elem_iov) in elem.out_iovec.iter().enumerate() {
                     if index == 0 {
                         continue;
@@ -233,28 +335,19 @@ impl Request {
         &self,
         aio: &mut Box<Aio<AioCompleteCb>>,
         disk: &File,
+        qcow2: Option<&Arc<Mutex<Qcow2Driver>>>,
         disk_sectors: u64,
         serial_num: &Option<String>,
         direct: bool,
+        discard: bool,
+        write_zeroes: WriteZeroesState,
         last_aio: bool,
         iocompletecb: AioCompleteCb,
     ) -> Result<u32> {
-        let mut top: u64 = self.data_len / SECTOR_SIZE;
-        if self.data_len % SECTOR_SIZE != 0 {
-            top += 1;
-        }
-        top.checked_add(self.out_header.sector)
-            .filter(|off| off <= &disk_sectors)
-            .chain_err(|| {
-                format!(
-                    "offset {} invalid, disk sector {}",
-                    self.out_header.sector, disk_sectors
-                )
-            })?;
-
         let mut aiocb = AioCb {
             last_aio,
             file_fd: disk.as_raw_fd(),
+            direct,
             opcode: IoCmd::Noop,
             iovec: Vec::new(),
             offset: (self.out_header.sector << SECTOR_SHIFT) as usize,
@@ -271,36 +364,60 @@ impl Request {
             aiocb.iovec.push(iovec);
         }
 
+        match self.out_header.request_type {
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let ret = self.execute_discard_write_zeroes(disk, qcow2, disk_sectors, discard);
+                (*aio).as_mut().complete(&aiocb, ret);
+                return Ok(0);
+            }
+            _ => {}
+        }
+
+        let mut top: u64 = self.data_len / SECTOR_SIZE;
+        if self.data_len % SECTOR_SIZE != 0 {
+            top += 1;
+        }
+        top.checked_add(self.out_header.sector)
+            .filter(|off| off <= &disk_sectors)
+            .chain_err(|| {
+                format!(
+                    "offset {} invalid, disk sector {}",
+                    self.out_header.sector, disk_sectors
+                )
+            })?;
+
+        if let Some(driver) = qcow2 {
+            if self.out_header.request_type != VIRTIO_BLK_T_GET_ID {
+                return self.execute_qcow2(aio, driver, aiocb, discard, write_zeroes);
+            }
+        }
+
         match self.out_header.request_type {
             VIRTIO_BLK_T_IN => {
                 aiocb.opcode = IoCmd::Preadv;
-                if direct {
-                    (*aio).as_mut().rw_aio(aiocb, SECTOR_SIZE).chain_err(|| {
-                        "Failed to process block request for reading asynchronously"
-                    })?;
-                } else {
-                    (*aio).as_mut().rw_sync(aiocb).chain_err(|| {
-                        "Failed to process block request for reading synchronously"
-                    })?;
-                }
+                (*aio)
+                    .as_mut()
+                    .rw_aio(aiocb, SECTOR_SIZE)
+                    .chain_err(|| "Failed to process block request for reading")?;
             }
             VIRTIO_BLK_T_OUT => {
-                aiocb.opcode = IoCmd::Pwritev;
-                if direct {
-                    (*aio).as_mut().rw_aio(aiocb, SECTOR_SIZE).chain_err(|| {
-                        "Failed to process block request for writing asynchronously"
-                    })?;
-                } else {
-                    (*aio).as_mut().rw_sync(aiocb).chain_err(|| {
-                        "Failed to process block request for writing synchronously"
-                    })?;
+                if write_zeroes != WriteZeroesState::Off && iovec_is_zero(&aiocb.iovec) {
+                    let unmap = write_zeroes == WriteZeroesState::Unmap && discard;
+                    let ret = raw_write_zeroes(aiocb.file_fd, aiocb.offset, self.data_len, unmap);
+                    (*aio).as_mut().complete(&aiocb, ret);
+                    return Ok(0);
                 }
+                aiocb.opcode = IoCmd::Pwritev;
+                (*aio)
+                    .as_mut()
+                    .rw_aio(aiocb, SECTOR_SIZE)
+                    .chain_err(|| "Failed to process block request for writing")?;
             }
             VIRTIO_BLK_T_FLUSH => {
                 aiocb.opcode = IoCmd::Fdsync;
                 (*aio)
                     .as_mut()
-                    .rw_sync(aiocb)
+                    .rw_aio(aiocb, SECTOR_SIZE)
                     .chain_err(|| "Failed to process block request for flushing")?;
             }
             VIRTIO_BLK_T_GET_ID => {
@@ -329,6 +446,270 @@ impl Request {
         };
         Ok(0)
     }
+
+    /// Process the discard or write zeroes request synchronously, return 0 or negative
+    /// errno which is converted to the status of the request.
+    fn execute_discard_write_zeroes(
+        &self,
+        disk: &File,
+        qcow2: Option<&Arc<Mutex<Qcow2Driver>>>,
+        disk_sectors: u64,
+        discard: bool,
+    ) -> i64 {
+        let seg_size = size_of::<DiscardWriteZeroesSeg>() as u64;
+        if self.data_len == 0 || self.data_len % seg_size != 0 {
+            error!("Invalid data length {} of block request", self.data_len);
+            return -i64::from(libc::EOPNOTSUPP);
+        }
+        let is_discard = self.out_header.request_type == VIRTIO_BLK_T_DISCARD;
+        if is_discard && !discard {
+            return -i64::from(libc::EOPNOTSUPP);
+        }
+
+        let mut data = Vec::with_capacity(self.data_len as usize);
+        for iov in self.iovec.iter() {
+            // Safe because the iovec is translated from guest memory which is mapped
+            // in host and keeps alive during the request.
+            let buf = unsafe {
+                std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize)
+            };
+            data.extend_from_slice(buf);
+        }
+
+        for seg_data in data.chunks_exact(seg_size as usize) {
+            let seg = DiscardWriteZeroesSeg::from_bytes(seg_data).unwrap();
+            let allowed_flags = if is_discard {
+                0
+            } else {
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+            };
+            if seg.flags & !allowed_flags != 0 || seg.num_sectors > MAX_REQUEST_SECTORS {
+                error!(
+                    "Unsupported block request segment: flags {} sectors {}",
+                    seg.flags, seg.num_sectors
+                );
+                return -i64::from(libc::EOPNOTSUPP);
+            }
+            match seg.sector.checked_add(u64::from(seg.num_sectors)) {
+                Some(end) if end <= disk_sectors => {}
+                _ => {
+                    error!(
+                        "Block request segment out of range: sector {} sectors {}, disk sector {}",
+                        seg.sector, seg.num_sectors, disk_sectors
+                    );
+                    return -i64::from(libc::EIO);
+                }
+            }
+
+            let offset = seg.sector << SECTOR_SHIFT;
+            let len = u64::from(seg.num_sectors) << SECTOR_SHIFT;
+            let unmap = discard && seg.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            let ret = if let Some(driver) = qcow2 {
+                let mut driver = driver.lock().unwrap();
+                let ret = if is_discard {
+                    driver.discard(offset, len)
+                } else {
+                    driver.write_zeroes(offset, len, unmap)
+                };
+                match ret {
+                    Ok(()) => 0,
+                    Err(ref e) => {
+                        error!(
+                            "Failed to discard or write zeroes to qcow2 image, {}",
+                            error_chain::ChainedError::display_chain(e)
+                        );
+                        -i64::from(libc::EIO)
+                    }
+                }
+            } else if is_discard {
+                // Discard is only a hint, it's fine if the host doesn't support it.
+                match raw_discard(disk.as_raw_fd(), offset as usize, len) {
+                    ret if ret == -i64::from(libc::EOPNOTSUPP) => 0,
+                    ret => ret,
+                }
+            } else {
+                raw_write_zeroes(disk.as_raw_fd(), offset as usize, len, unmap)
+            };
+            if ret < 0 {
+                error!(
+                    "Failed to process block request type {}, return {}",
+                    self.out_header.request_type, ret
+                );
+                return ret;
+            }
+        }
+
+        0
+    }
+
+    /// Process the request on qcow2 image synchronously, the status of the request
+    /// is written by the completion callback of aio.
+    fn execute_qcow2(
+        &self,
+        aio: &mut Box<Aio<AioCompleteCb>>,
+        driver: &Arc<Mutex<Qcow2Driver>>,
+        aiocb: AioCb<AioCompleteCb>,
+        discard: bool,
+        write_zeroes: WriteZeroesState,
+    ) -> Result<u32> {
+        let mut driver = driver.lock().unwrap();
+        let ret = match self.out_header.request_type {
+            VIRTIO_BLK_T_IN => driver
+                .read_vectored(&aiocb.iovec, aiocb.offset as u64)
+                .chain_err(|| "Failed to read qcow2 image"),
+            VIRTIO_BLK_T_OUT
+                if write_zeroes != WriteZeroesState::Off && iovec_is_zero(&aiocb.iovec) =>
+            {
+                let unmap = write_zeroes == WriteZeroesState::Unmap && discard;
+                driver
+                    .write_zeroes(aiocb.offset as u64, self.data_len, unmap)
+                    .map(|_| self.data_len)
+                    .chain_err(|| "Failed to write zeroes to qcow2 image")
+            }
+            VIRTIO_BLK_T_OUT => driver
+                .write_vectored(&aiocb.iovec, aiocb.offset as u64)
+                .chain_err(|| "Failed to write qcow2 image"),
+            VIRTIO_BLK_T_FLUSH => driver.flush().map(|_| 0),
+            _ => bail!(
+                "The type {} of block request is not supported",
+                self.out_header.request_type
+            ),
+        };
+        drop(driver);
+
+        match ret {
+            Ok(len) => {
+                (*aio).as_mut().complete(&aiocb, len as i64);
+                Ok(0)
+            }
+            Err(e) => {
+                (*aio).as_mut().complete(&aiocb, -1);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// IO throttle of the block device shared by all the virtqueues, each limit is
+/// implemented by a leak bucket.
+struct IoThrottle {
+    iops: LeakBucket,
+    iops_rd: LeakBucket,
+    iops_wr: LeakBucket,
+    bps: LeakBucket,
+    bps_rd: LeakBucket,
+    bps_wr: LeakBucket,
+    /// Eventfds of the throttled virtqueues, which are kicked when more IO is allowed.
+    /// They are indexed by the raw fd of the original eventfd.
+    waiters: Vec<(RawFd, EventFd)>,
+}
+
+impl IoThrottle {
+    fn new(cfg: &ThrottleConfig) -> Self {
+        let mut throttle = IoThrottle {
+            iops: LeakBucket::new(0),
+            iops_rd: LeakBucket::new(0),
+            iops_wr: LeakBucket::new(0),
+            bps: LeakBucket::new(0),
+            bps_rd: LeakBucket::new(0),
+            bps_wr: LeakBucket::new(0),
+            waiters: Vec::new(),
+        };
+        throttle.update(cfg);
+        throttle
+    }
+
+    /// Update the limits, which apply to the whole device.
+    fn update(&mut self, cfg: &ThrottleConfig) {
+        self.iops.update(cfg.iops, cfg.iops_max);
+        self.iops_rd.update(cfg.iops_rd, cfg.iops_rd_max);
+        self.iops_wr.update(cfg.iops_wr, cfg.iops_wr_max);
+        self.bps.update(cfg.bps, cfg.bps_max);
+        self.bps_rd.update(cfg.bps_rd, cfg.bps_rd_max);
+        self.bps_wr.update(cfg.bps_wr, cfg.bps_wr_max);
+    }
+
+    /// Record the eventfd of a throttled virtqueue to kick it later.
+    fn add_waiter(&mut self, queue_evt: &EventFd) -> Result<()> {
+        if self
+            .waiters
+            .iter()
+            .all(|(fd, _)| *fd != queue_evt.as_raw_fd())
+        {
+            let evt = queue_evt
+                .try_clone()
+                .chain_err(|| "Failed to clone the eventfd of throttled virtqueue")?;
+            self.waiters.push((queue_evt.as_raw_fd(), evt));
+        }
+        Ok(())
+    }
+
+    /// Kick all the throttled virtqueues to process their requests again.
+    fn wake_waiters(&mut self) {
+        for (_, evt) in self.waiters.drain(..) {
+            if let Err(e) = evt.write(1) {
+                error!("Failed to kick the throttled virtqueue, {}", e);
+            }
+        }
+    }
+
+    /// Return true if the request must be throttled, otherwise the request is accounted
+    /// in all the limits it is subject to.
+    fn throttled(&mut self, ctx: &mut EventLoopContext, req_type: u32, len: u64) -> bool {
+        let mut buckets = vec![(&mut self.iops, 1)];
+        match req_type {
+            VIRTIO_BLK_T_IN => {
+                buckets.push((&mut self.iops_rd, 1));
+                buckets.push((&mut self.bps, len));
+                buckets.push((&mut self.bps_rd, len));
+            }
+            VIRTIO_BLK_T_OUT => {
+                buckets.push((&mut self.iops_wr, 1));
+                buckets.push((&mut self.bps, len));
+                buckets.push((&mut self.bps_wr, len));
+            }
+            _ => {}
+        }
+
+        if buckets.iter_mut().any(|(bucket, _)| bucket.is_full(ctx)) {
+            return true;
+        }
+        for (bucket, units) in buckets {
+            bucket.consume(units);
+        }
+        false
+    }
+
+    /// Clear the timer state of the bucket which is woken up by `fd`.
+    fn clear_timer(&mut self, fd: RawFd) {
+        for bucket in self.buckets_mut() {
+            if bucket.as_raw_fd() == fd {
+                bucket.clear_timer();
+            }
+        }
+    }
+
+    fn buckets(&self) -> [&LeakBucket; 6] {
+        [
+            &self.iops,
+            &self.iops_rd,
+            &self.iops_wr,
+            &self.bps,
+            &self.bps_rd,
+            &self.bps_wr,
+        ]
+    }
+
+    fn buckets_mut(&mut self) -> [&mut LeakBucket; 6] {
+        [
+            &mut self.iops,
+            &mut self.iops_rd,
+            &mut self.iops_wr,
+            &mut self.bps,
+            &mut self.bps_rd,
+            &mut self.bps_wr,
+        ]
+    }
 }
 
 /// Control block of Block IO.
@@ -341,12 +722,18 @@ struct BlockIoHandler {
     mem_space: Arc<AddressSpace>,
     /// The image file opened by the block device.
     disk_image: Option<Arc<File>>,
+    /// The qcow2 driver of the image, None if the image is raw.
+    qcow2: Option<Arc<Mutex<Qcow2Driver>>>,
     /// The number of sectors of the disk image.
     disk_sectors: u64,
     /// Serial number of the block device.
     serial_num: Option<String>,
     /// if use direct access io.
     direct: bool,
+    /// Whether discard request is supported.
+    discard: bool,
+    /// Whether to convert write request with zeroes data to write zeroes.
+    write_zeroes: WriteZeroesState,
     /// Aio context.
     aio: Option<Box<Aio<AioCompleteCb>>>,
     /// Bit mask of features negotiated by the backend and the frontend.
@@ -361,12 +748,24 @@ struct BlockIoHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     /// thread name of io handler
     iothread: Option<String>,
-    /// Using the leak bucket to implement IO limits
-    leak_bucket: Option<LeakBucket>,
+    /// IO limits of the block device, shared by all the virtqueues and changed at runtime.
+    throttle: Arc<Mutex<IoThrottle>>,
+    /// Whether the timers of IO limits are monitored by this handler, only one
+    /// handler of the block device does it.
+    throttle_owner: bool,
+    /// IO statistics of the block device.
+    stats: Arc<Mutex<BlockDeviceStats>>,
+    /// Whether the handler is quiesced for live migration, no request is
+    /// processed until it's restarted.
+    quiesced: bool,
 }
 
 impl BlockIoHandler {
     fn process_queue(&mut self) -> Result<()> {
+        if self.quiesced {
+            return Ok(());
+        }
+
         let mut req_queue = Vec::new();
         let mut req_index = 0;
         let mut last_aio_req_index = 0;
@@ -375,23 +774,23 @@ impl BlockIoHandler {
         let mut queue = self.queue.lock().unwrap();
 
         while let Ok(elem) = queue.vring.pop_avail(&self.mem_space, self.driver_features) {
-            // limit io operations if iops is configured
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 1_u64) {
-                        queue.vring.push_back();
-                        break;
-                    }
-                } else {
-                    bail!(
-                        "IOThread {:?} of Block is not found in cmdline.",
-                        self.iothread,
-                    );
-                };
-            }
-
             match Request::new(&self.mem_space, &elem) {
                 Ok(req) => {
+                    // limit io operations if throttle is configured
+                    if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                        let mut throttle = self.throttle.lock().unwrap();
+                        if throttle.throttled(ctx, req.out_header.request_type, req.data_len) {
+                            throttle.add_waiter(&self.queue_evt)?;
+                            queue.vring.push_back();
+                            break;
+                        }
+                    } else {
+                        bail!(
+                            "IOThread {:?} of Block is not found in cmdline.",
+                            self.iothread,
+                        );
+                    };
+
                     match req.out_header.request_type {
                         VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
                             last_aio_req_index = req_index;
@@ -433,25 +832,31 @@ impl BlockIoHandler {
                     let aiocompletecb = AioCompleteCb::new(
                         self.queue.clone(),
                         self.mem_space.clone(),
-                        req.desc_index,
+                        req,
                         rw_len,
-                        req.in_header,
                         Some(self.interrupt_cb.clone()),
                         self.driver_features,
+                        self.stats.clone(),
                     );
 
                     match req.execute(
                         aio,
                         disk_img,
+                        self.qcow2.as_ref(),
                         self.disk_sectors,
                         &self.serial_num,
                         self.direct,
+                        self.discard,
+                        self.write_zeroes,
                         last_aio_req_index == req_index,
                         aiocompletecb,
                     ) {
                         Ok(v) => {
                             if v == 1 {
                                 // get device id
+                                for iov in req.in_data.iter() {
+                                    self.mem_space.mark_dirty(iov.addr, u64::from(iov.len));
+                                }
                                 self.mem_space
                                     .write_object(&VIRTIO_BLK_S_OK, req.in_header)
                                     .chain_err(|| "Failed to write result for the request for block with device id")?;
@@ -507,15 +912,25 @@ impl BlockIoHandler {
         Ok(())
     }
 
-    fn build_aio(&self) -> Result<Box<Aio<AioCompleteCb>>> {
+    fn build_aio(&self, engine: AioEngine) -> Result<Box<Aio<AioCompleteCb>>> {
         let complete_func = Arc::new(Box::new(move |aiocb: &AioCb<AioCompleteCb>, ret: i64| {
-            let status = if ret < 0 {
-                ret
+            let status = if ret == -i64::from(libc::EOPNOTSUPP) {
+                VIRTIO_BLK_S_UNSUPP as u8
+            } else if ret < 0 {
+                VIRTIO_BLK_S_IOERR as u8
             } else {
-                i64::from(VIRTIO_BLK_S_OK)
+                VIRTIO_BLK_S_OK as u8
             };
 
             let complete_cb = &aiocb.iocompletecb;
+            complete_cb.account(ret < 0);
+            // The data is read to guest memory by host address directly, so
+            // it is marked dirty explicitly for live migration.
+            for iov in complete_cb.in_data.iter() {
+                complete_cb
+                    .mem_space
+                    .mark_dirty(iov.addr, u64::from(iov.len));
+            }
             if let Err(ref e) = complete_cb
                 .mem_space
                 .write_object(&status, complete_cb.req_status_addr)
@@ -558,22 +973,58 @@ impl BlockIoHandler {
             }
         }) as AioCompleteFunc<AioCompleteCb>);
 
-        Ok(Box::new(Aio::new(complete_func)?))
+        Ok(Box::new(Aio::new(complete_func, engine)?))
+    }
+
+    /// Wait for the requests in flight to complete, the completions are handled
+    /// in the caller's thread.
+    fn drain_aio(&mut self) -> Result<()> {
+        if let Some(aio) = self.aio.as_mut() {
+            while aio.aio_in_queue.len + aio.aio_in_flight.len > 0 {
+                aio.handle().chain_err(|| "Failed to handle aio")?;
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        Ok(())
     }
 
     fn update_evt_handler(&mut self) {
         match self.receiver.recv() {
-            Ok((image, disk_sectors, serial_num, direct)) => {
+            Ok((
+                image,
+                qcow2,
+                disk_sectors,
+                serial_num,
+                direct,
+                aio_engine,
+                discard,
+                write_zeroes,
+            )) => {
                 self.disk_sectors = disk_sectors;
                 self.disk_image = image;
+                self.qcow2 = qcow2;
                 self.serial_num = serial_num;
                 self.direct = direct;
+                self.discard = discard;
+                self.write_zeroes = write_zeroes;
+                if let Some(aio) = self.aio.as_mut() {
+                    if let Err(ref e) = aio.set_engine(aio_engine) {
+                        error!(
+                            "Failed to switch aio engine for block {}",
+                            error_chain::ChainedError::display_chain(e)
+                        );
+                    }
+                }
             }
             Err(_) => {
                 self.disk_sectors = 0;
                 self.disk_image = None;
+                self.qcow2 = None;
                 self.serial_num = None;
                 self.direct = true;
+                self.discard = false;
+                self.write_zeroes = WriteZeroesState::Off;
             }
         };
 
@@ -609,14 +1060,16 @@ impl BlockIoHandler {
                 Vec::new(),
             ),
         ];
-        if let Some(lb) = self.leak_bucket.as_ref() {
-            notifiers.push(EventNotifier::new(
-                NotifierOperation::Delete,
-                lb.as_raw_fd(),
-                None,
-                EventSet::IN,
-                Vec::new(),
-            ));
+        if self.throttle_owner {
+            for bucket in self.throttle.lock().unwrap().buckets().iter() {
+                notifiers.push(EventNotifier::new(
+                    NotifierOperation::Delete,
+                    bucket.as_raw_fd(),
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                ));
+            }
         }
         if let Some(aio) = &self.aio {
             notifiers.push(EventNotifier::new(
@@ -632,6 +1085,10 @@ impl BlockIoHandler {
     }
 }
 
+// Send is not auto-implemented for the raw pointers in the aio context.
+// Implementing it is safe because the handler is only accessed with its mutex held.
+unsafe impl Send for BlockIoHandler {}
+
 fn build_event_notifier(fd: RawFd, handler: Box<NotifierCallback>) -> EventNotifier {
     EventNotifier::new(
         NotifierOperation::AddShared,
@@ -679,25 +1136,21 @@ impl EventNotifierHelper for BlockIoHandler {
         });
         notifiers.push(build_event_notifier(handler_raw.queue_evt.as_raw_fd(), h));
 
-        // Register timer event notifier for IO limits
-        if let Some(lb) = handler_raw.leak_bucket.as_ref() {
-            let h_clone = handler.clone();
-            let h: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
-                read_fd(fd);
-
-                if let Some(lb) = h_clone.lock().unwrap().leak_bucket.as_mut() {
-                    lb.clear_timer();
-                }
-
-                if let Err(ref e) = h_clone.lock().unwrap().process_queue() {
-                    error!(
-                        "Failed to handle block IO {}",
-                        error_chain::ChainedError::display_chain(e)
-                    );
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(lb.as_raw_fd(), h));
+        // Register timer event notifiers for IO limits, the throttled virtqueues
+        // are kicked to process requests in their own threads.
+        if handler_raw.throttle_owner {
+            for bucket in handler_raw.throttle.lock().unwrap().buckets().iter() {
+                let throttle = handler_raw.throttle.clone();
+                let h: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+                    read_fd(fd);
+
+                    let mut locked_throttle = throttle.lock().unwrap();
+                    locked_throttle.clear_timer(fd);
+                    locked_throttle.wake_waiters();
+                    None
+                });
+                notifiers.push(build_event_notifier(bucket.as_raw_fd(), h));
+            }
         }
 
         // Register event notifier for aio.
@@ -726,14 +1179,14 @@ impl EventNotifierHelper for BlockIoHandler {
 /// State of block device.
 #[repr(C)]
 #[derive(Clone, Copy, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.2.0", compat_version = "0.2.0")]
 pub struct BlockState {
     /// Bitmask of features supported by the backend.
     device_features: u64,
     /// Bit mask of features negotiated byu the backend and the frontend.
     driver_features: u64,
     /// Config space of the block device.
-    config_space: [u8; 16],
+    config_space: [u8; 60],
 }
 
 /// Block device structure.
@@ -742,49 +1195,62 @@ pub struct Block {
     blk_cfg: BlkDevConfig,
     /// Image file opened.
     disk_image: Option<Arc<File>>,
+    /// The qcow2 driver of the image file.
+    qcow2: Option<Arc<Mutex<Qcow2Driver>>>,
     /// Number of sectors of the image file.
     disk_sectors: u64,
     /// Status of block device.
     state: BlockState,
     /// Callback to trigger interrupt.
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
-    /// The sending half of Rust's channel to send the image file.
-    sender: Option<Sender<SenderConfig>>,
-    /// Eventfd for config space update.
-    update_evt: EventFd,
-    /// Eventfd for device reset.
-    reset_evt: EventFd,
+    /// The sending halves of Rust's channel to send the image file, one for each virtqueue.
+    senders: Vec<Sender<SenderConfig>>,
+    /// Eventfds for config space update, one for each virtqueue.
+    update_evts: Vec<EventFd>,
+    /// Eventfds for device reset, one for each virtqueue.
+    reset_evts: Vec<EventFd>,
+    /// IO statistics of the block device, shared with the IO handlers.
+    stats: Arc<Mutex<BlockDeviceStats>>,
+    /// IO limits of the block device, shared with the IO handlers.
+    throttle: Arc<Mutex<IoThrottle>>,
+    /// IO handlers of the virtqueues, kept to quiesce them for live migration.
+    handlers: Vec<Arc<Mutex<BlockIoHandler>>>,
 }
 
 impl Default for Block {
     fn default() -> Self {
-        Block {
-            blk_cfg: Default::default(),
-            disk_image: None,
-            disk_sectors: 0,
-            state: BlockState::default(),
-            interrupt_cb: None,
-            sender: None,
-            update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-        }
+        Block::new(BlkDevConfig::default())
     }
 }
 
 impl Block {
     pub fn new(blk_cfg: BlkDevConfig) -> Block {
+        let queues = blk_cfg.queues as usize;
         Self {
-            blk_cfg,
             disk_image: None,
+            qcow2: None,
             disk_sectors: 0,
             state: BlockState::default(),
             interrupt_cb: None,
-            sender: None,
-            update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            senders: Vec::new(),
+            update_evts: (0..queues)
+                .map(|_| EventFd::new(libc::EFD_NONBLOCK).unwrap())
+                .collect(),
+            reset_evts: (0..queues)
+                .map(|_| EventFd::new(libc::EFD_NONBLOCK).unwrap())
+                .collect(),
+            stats: Arc::new(Mutex::new(BlockDeviceStats::default())),
+            throttle: Arc::new(Mutex::new(IoThrottle::new(&blk_cfg.throttle))),
+            handlers: Vec::new(),
+            blk_cfg,
         }
     }
 
+    /// Apply the IO throttle in config to the block device.
+    fn update_throttle(&mut self) {
+        self.throttle.lock().unwrap().update(&self.blk_cfg.throttle);
+    }
+
     fn build_device_config_space(&mut self) {
         // capacity: 64bits
         let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
@@ -796,6 +1262,19 @@ impl Block {
         for i in 0..4 {
             self.state.config_space[12 + i] = (126 >> (8 * i)) as u8;
         }
+
+        // max_discard_sectors, max_discard_seg and discard_sector_alignment: 32bits
+        self.state.config_space[36..40].copy_from_slice(&MAX_REQUEST_SECTORS.to_le_bytes());
+        self.state.config_space[40..44].copy_from_slice(&1_u32.to_le_bytes());
+        self.state.config_space[44..48].copy_from_slice(&1_u32.to_le_bytes());
+        // max_write_zeroes_sectors and max_write_zeroes_seg: 32bits
+        self.state.config_space[48..52].copy_from_slice(&MAX_REQUEST_SECTORS.to_le_bytes());
+        self.state.config_space[52..56].copy_from_slice(&1_u32.to_le_bytes());
+        // write_zeroes_may_unmap: 8bits
+        self.state.config_space[56] = self.blk_cfg.discard as u8;
+
+        // num_queues: 16bits
+        self.state.config_space[34..36].copy_from_slice(&self.blk_cfg.queues.to_le_bytes());
     }
 }
 
@@ -803,23 +1282,32 @@ impl VirtioDevice for Block {
     /// Realize virtio block device.
     fn realize(&mut self) -> Result<()> {
         // if iothread not found, return err
-        if self.blk_cfg.iothread.is_some()
-            && EventLoop::get_ctx(self.blk_cfg.iothread.as_ref()).is_none()
-        {
-            bail!(
-                "IOThread {:?} of Block is not configured in params.",
-                self.blk_cfg.iothread,
-            );
+        for iothread in self.blk_cfg.iothreads() {
+            if EventLoop::get_ctx(Some(&iothread)).is_none() {
+                bail!(
+                    "IOThread {:?} of Block is not configured in params.",
+                    iothread,
+                );
+            }
         }
 
         self.state.device_features = (1_u64 << VIRTIO_F_VERSION_1) | (1_u64 << VIRTIO_BLK_F_FLUSH);
         if self.blk_cfg.read_only {
             self.state.device_features |= 1_u64 << VIRTIO_BLK_F_RO;
+        } else {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+            if self.blk_cfg.discard {
+                self.state.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+            }
         };
         self.state.device_features |= 1_u64 << VIRTIO_F_RING_INDIRECT_DESC;
         self.state.device_features |= 1_u64 << VIRTIO_BLK_F_SIZE_MAX;
         self.state.device_features |= 1_u64 << VIRTIO_BLK_F_SEG_MAX;
         self.state.device_features |= 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+        self.state.device_features |= 1_u64 << VIRTIO_F_RING_PACKED;
+        if self.blk_cfg.queues > 1 {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_MQ;
+        }
 
         self.build_device_config_space();
 
@@ -827,6 +1315,7 @@ impl VirtioDevice for Block {
 
         if !self.blk_cfg.path_on_host.is_empty() {
             self.disk_image = None;
+            self.qcow2 = None;
 
             let mut file = if self.blk_cfg.direct {
                 OpenOptions::new()
@@ -853,13 +1342,32 @@ impl VirtioDevice for Block {
                     })?
             };
 
-            disk_size = file
-                .seek(SeekFrom::End(0))
-                .chain_err(|| "Failed to seek the end for block")? as u64;
+            if self.blk_cfg.format == DiskFormat::Qcow2 {
+                let driver = Qcow2Driver::new(
+                    file.try_clone()
+                        .chain_err(|| "Failed to clone the file for qcow2 image")?,
+                    &self.blk_cfg.path_on_host,
+                    self.blk_cfg.read_only,
+                )
+                .chain_err(|| {
+                    format!(
+                        "failed to open qcow2 image for block {}",
+                        self.blk_cfg.path_on_host
+                    )
+                })?;
+                disk_size = driver.virtual_size();
+                self.qcow2 = Some(Arc::new(Mutex::new(driver)));
+            } else {
+                disk_size = file
+                    .seek(SeekFrom::End(0))
+                    .chain_err(|| "Failed to seek the end for block")?
+                    as u64;
+            }
 
             self.disk_image = Some(Arc::new(file));
         } else {
             self.disk_image = None;
+            self.qcow2 = None;
         }
 
         self.disk_sectors = disk_size >> SECTOR_SHIFT;
@@ -877,7 +1385,7 @@ impl VirtioDevice for Block {
 
     /// Get the count of virtio device queues.
     fn queue_num(&self) -> usize {
-        QUEUE_NUM_BLK
+        self.blk_cfg.queues as usize
     }
 
     /// Get the queue size of virtio device.
@@ -939,41 +1447,70 @@ impl VirtioDevice for Block {
         mut queue_evts: Vec<EventFd>,
     ) -> Result<()> {
         self.interrupt_cb = Some(interrupt_cb.clone());
-        let (sender, receiver) = channel();
-        self.sender = Some(sender);
-
-        let mut handler = BlockIoHandler {
-            queue: queues[0].clone(),
-            queue_evt: queue_evts.remove(0),
-            mem_space,
-            disk_image: self.disk_image.clone(),
-            disk_sectors: self.disk_sectors,
-            direct: self.blk_cfg.direct,
-            serial_num: self.blk_cfg.serial_num.clone(),
-            aio: None,
-            driver_features: self.state.driver_features,
-            receiver,
-            update_evt: self.update_evt.as_raw_fd(),
-            reset_evt: self.reset_evt.as_raw_fd(),
-            interrupt_cb,
-            iothread: self.blk_cfg.iothread.clone(),
-            leak_bucket: self.blk_cfg.iops.map(LeakBucket::new),
-        };
+        self.senders.clear();
+        self.handlers.clear();
+        // The timers of the previous IO handlers are dropped with them.
+        self.throttle = Arc::new(Mutex::new(IoThrottle::new(&self.blk_cfg.throttle)));
+
+        // Spread the virtqueues across the iothreads in turn.
+        let iothreads = self.blk_cfg.iothreads();
+        for (index, queue) in queues.iter().enumerate() {
+            let (sender, receiver) = channel();
+            self.senders.push(sender);
+
+            let iothread = if iothreads.is_empty() {
+                None
+            } else {
+                Some(iothreads[index % iothreads.len()].clone())
+            };
+            let mut handler = BlockIoHandler {
+                queue: queue.clone(),
+                queue_evt: queue_evts.remove(0),
+                mem_space: mem_space.clone(),
+                disk_image: self.disk_image.clone(),
+                qcow2: self.qcow2.clone(),
+                disk_sectors: self.disk_sectors,
+                direct: self.blk_cfg.direct,
+                discard: self.blk_cfg.discard,
+                write_zeroes: self.blk_cfg.write_zeroes,
+                serial_num: self.blk_cfg.serial_num.clone(),
+                aio: None,
+                driver_features: self.state.driver_features,
+                receiver,
+                update_evt: self.update_evts[index].as_raw_fd(),
+                reset_evt: self.reset_evts[index].as_raw_fd(),
+                interrupt_cb: interrupt_cb.clone(),
+                iothread: iothread.clone(),
+                throttle: self.throttle.clone(),
+                throttle_owner: index == 0,
+                stats: self.stats.clone(),
+                quiesced: false,
+            };
 
-        handler.aio = Some(handler.build_aio()?);
+            handler.aio = Some(handler.build_aio(self.blk_cfg.aio)?);
 
-        EventLoop::update_event(
-            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
-            self.blk_cfg.iothread.as_ref(),
-        )?;
+            let handler = Arc::new(Mutex::new(handler));
+            EventLoop::update_event(
+                EventNotifierHelper::internal_notifiers(handler.clone()),
+                iothread.as_ref(),
+            )?;
+            self.handlers.push(handler);
+        }
 
         Ok(())
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.reset_evt
-            .write(1)
-            .chain_err(|| ErrorKind::EventFdWrite)
+        for reset_evt in self.reset_evts.iter() {
+            reset_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        self.disk_image = None;
+        self.qcow2 = None;
+        Ok(())
     }
 
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
@@ -986,22 +1523,31 @@ impl VirtioDevice for Block {
         } else {
             self.blk_cfg = Default::default();
         }
+        // The statistics belong to the drive which is replaced.
+        *self.stats.lock().unwrap() = BlockDeviceStats::default();
 
         self.realize()?;
-
-        if let Some(sender) = &self.sender {
-            sender
-                .send((
-                    self.disk_image.take(),
-                    self.disk_sectors,
-                    self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                ))
-                .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
-
-            self.update_evt
-                .write(1)
-                .chain_err(|| ErrorKind::EventFdWrite)?;
+        self.update_throttle();
+
+        if !self.senders.is_empty() {
+            for (sender, update_evt) in self.senders.iter().zip(self.update_evts.iter()) {
+                sender
+                    .send((
+                        self.disk_image.clone(),
+                        self.qcow2.clone(),
+                        self.disk_sectors,
+                        self.blk_cfg.serial_num.clone(),
+                        self.blk_cfg.direct,
+                        self.blk_cfg.aio,
+                        self.blk_cfg.discard,
+                        self.blk_cfg.write_zeroes,
+                    ))
+                    .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
+
+                update_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+            }
+            self.disk_image = None;
+            self.qcow2 = None;
         }
 
         if let Some(interrupt_cb) = &self.interrupt_cb {
@@ -1013,6 +1559,76 @@ impl VirtioDevice for Block {
     }
 }
 
+impl BlockDeviceInterface for Block {
+    fn query_block_info(&self) -> Option<BlockInfo> {
+        if self.blk_cfg.id.is_empty() {
+            return None;
+        }
+
+        let detect_zeroes = match self.blk_cfg.write_zeroes {
+            WriteZeroesState::Off => "off",
+            WriteZeroesState::On => "on",
+            WriteZeroesState::Unmap => "unmap",
+        };
+        let burst = |max: u64| if max == 0 { None } else { Some(max) };
+        let drv = match self.blk_cfg.format {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+        };
+        let inserted = BlockDeviceInfo {
+            file: self.blk_cfg.path_on_host.clone(),
+            node_name: self.blk_cfg.id.clone(),
+            ro: self.blk_cfg.read_only,
+            drv: drv.to_string(),
+            encrypted: false,
+            detect_zeroes: detect_zeroes.to_string(),
+            iops: self.blk_cfg.throttle.iops,
+            iops_rd: self.blk_cfg.throttle.iops_rd,
+            iops_wr: self.blk_cfg.throttle.iops_wr,
+            bps: self.blk_cfg.throttle.bps,
+            bps_rd: self.blk_cfg.throttle.bps_rd,
+            bps_wr: self.blk_cfg.throttle.bps_wr,
+            iops_max: burst(self.blk_cfg.throttle.iops_max),
+            iops_rd_max: burst(self.blk_cfg.throttle.iops_rd_max),
+            iops_wr_max: burst(self.blk_cfg.throttle.iops_wr_max),
+            bps_max: burst(self.blk_cfg.throttle.bps_max),
+            bps_rd_max: burst(self.blk_cfg.throttle.bps_rd_max),
+            bps_wr_max: burst(self.blk_cfg.throttle.bps_wr_max),
+            cache: BlockdevCacheInfo {
+                writeback: true,
+                direct: self.blk_cfg.direct,
+                no_flush: false,
+            },
+            iothread: self.blk_cfg.iothread.clone(),
+        };
+
+        Some(BlockInfo {
+            device: self.blk_cfg.id.clone(),
+            block_type: "unknown".to_string(),
+            removable: false,
+            locked: false,
+            inserted: Some(inserted),
+        })
+    }
+
+    fn query_block_stats(&self) -> Option<BlockStats> {
+        if self.blk_cfg.id.is_empty() {
+            return None;
+        }
+
+        Some(BlockStats {
+            device: self.blk_cfg.id.clone(),
+            node_name: self.blk_cfg.id.clone(),
+            stats: self.stats.lock().unwrap().clone(),
+        })
+    }
+
+    fn set_io_throttle(&mut self, throttle: ThrottleConfig) {
+        self.blk_cfg.throttle = throttle;
+        self.update_throttle();
+    }
+}
+
 // Send and Sync is not auto-implemented for `Sender` type.
 // Implementing them is safe because `Sender` field of Block won't change in migration
 // workflow.
@@ -1039,7 +1655,34 @@ impl StateTransfer for Block {
     }
 }
 
-impl MigrationHook for Block {}
+impl MigrationHook for Block {
+    fn quiesce(&mut self) -> migration::errors::Result<()> {
+        for handler in self.handlers.iter() {
+            let mut locked_handler = handler.lock().unwrap();
+            locked_handler.quiesced = true;
+            if let Err(e) = locked_handler.drain_aio() {
+                bail!("Failed to drain block IO, error is {}", e.display_chain());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unquiesce(&mut self) -> migration::errors::Result<()> {
+        for handler in self.handlers.iter() {
+            let mut locked_handler = handler.lock().unwrap();
+            if locked_handler.quiesced {
+                locked_handler.quiesced = false;
+                // Kick the virtqueue for the requests which are skipped when quiesced.
+                if let Err(e) = locked_handler.queue_evt.write(1) {
+                    bail!("Failed to kick block virtqueue, error is {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -1051,10 +1694,10 @@ mod tests {
     use std::{thread, time::Duration};
     use vmm_sys_util::tempfile::TempFile;
 
-    const CONFIG_SPACE_SIZE: usize = 16;
     const VIRTQ_DESC_F_NEXT: u16 = 0x01;
     const VIRTQ_DESC_F_WRITE: u16 = 0x02;
     const SYSTEM_SPACE_SIZE: u64 = (1024 * 1024) as u64;
+    const CONFIG_SPACE_SIZE: usize = 60;
 
     // build dummy address space of vm
     fn address_space_init() -> Arc<AddressSpace> {
@@ -1089,10 +1732,10 @@ mod tests {
         assert_eq!(block.disk_sectors, 0);
         assert_eq!(block.state.device_features, 0);
         assert_eq!(block.state.driver_features, 0);
-        assert_eq!(block.state.config_space.len(), 16);
+        assert_eq!(block.state.config_space.len(), CONFIG_SPACE_SIZE);
         assert!(block.disk_image.is_none());
         assert!(block.interrupt_cb.is_none());
-        assert!(block.sender.is_none());
+        assert!(block.senders.is_empty());
 
         // Realize block device: create TempFile as backing file.
         block.blk_cfg.read_only = true;
@@ -1102,8 +1745,121 @@ mod tests {
         assert!(block.realize().is_ok());
 
         assert_eq!(block.device_type(), VIRTIO_TYPE_BLOCK);
-        assert_eq!(block.queue_num(), QUEUE_NUM_BLK);
+        assert_eq!(block.queue_num(), 1);
         assert_eq!(block.queue_size(), QUEUE_SIZE_BLK);
+        assert_eq!(block.state.device_features & (1_u64 << VIRTIO_BLK_F_MQ), 0);
+
+        // Realize block device with multiple queues.
+        let mut blk_cfg = BlkDevConfig::default();
+        blk_cfg.queues = 4;
+        let mut block = Block::new(blk_cfg);
+        assert!(block.realize().is_ok());
+        assert_eq!(block.queue_num(), 4);
+        assert_eq!(block.update_evts.len(), 4);
+        assert_eq!(block.reset_evts.len(), 4);
+        assert_ne!(block.state.device_features & (1_u64 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(block.state.config_space[34..36], 4_u16.to_le_bytes());
+    }
+
+    // Test the information and IO statistics of block device reported to QMP.
+    #[test]
+    fn test_block_query() {
+        let mut block = Block::default();
+        assert!(block.query_block_info().is_none());
+        assert!(block.query_block_stats().is_none());
+
+        let f = TempFile::new().unwrap();
+        block.blk_cfg.id = "drive0".to_string();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        block.blk_cfg.direct = false;
+        block.blk_cfg.throttle.iops = 200;
+        block.realize().unwrap();
+
+        let info = block.query_block_info().unwrap();
+        assert_eq!(info.device, "drive0");
+        let inserted = info.inserted.unwrap();
+        assert_eq!(inserted.file, block.blk_cfg.path_on_host);
+        assert_eq!(inserted.node_name, "drive0");
+        assert_eq!(inserted.drv, "raw");
+        assert_eq!(inserted.ro, false);
+        assert_eq!(inserted.iops, 200);
+        assert_eq!(inserted.cache.direct, false);
+
+        // Change the IO throttle at runtime.
+        let throttle = ThrottleConfig {
+            bps_rd: 1 << 20,
+            bps_rd_max: 1 << 21,
+            ..Default::default()
+        };
+        block.set_io_throttle(throttle);
+        let inserted = block.query_block_info().unwrap().inserted.unwrap();
+        assert_eq!(inserted.iops, 0);
+        assert_eq!(inserted.bps_rd, 1 << 20);
+        assert_eq!(inserted.bps_rd_max, Some(1 << 21));
+        assert_eq!(inserted.iops_max, None);
+
+        let mem_space = address_space_init();
+        let queue = Arc::new(Mutex::new(
+            Queue::new(QueueConfig::new(QUEUE_SIZE_BLK), 1).unwrap(),
+        ));
+        let mut req = Request {
+            desc_index: 0,
+            out_header: RequestOutHeader {
+                request_type: VIRTIO_BLK_T_IN,
+                io_prio: 0,
+                sector: 0,
+            },
+            iovec: Vec::new(),
+            data_len: 4096,
+            in_header: GuestAddress(0),
+            in_data: Vec::new(),
+        };
+        let cb = AioCompleteCb::new(
+            queue.clone(),
+            mem_space.clone(),
+            &req,
+            4096,
+            None,
+            0,
+            block.stats.clone(),
+        );
+        cb.account(false);
+        req.out_header.request_type = VIRTIO_BLK_T_OUT;
+        let cb = AioCompleteCb::new(queue, mem_space, &req, 0, None, 0, block.stats.clone());
+        cb.account(true);
+
+        let stats = block.query_block_stats().unwrap().stats;
+        assert_eq!(stats.rd_operations, 1);
+        assert_eq!(stats.rd_bytes, 4096);
+        assert_eq!(stats.wr_operations, 0);
+        assert_eq!(stats.wr_bytes, 0);
+        assert_eq!(stats.failed_wr_operations, 1);
+    }
+
+    // Test the IO throttle of the block device is not split among the virtqueues.
+    #[test]
+    fn test_block_throttle() {
+        let mut blk_cfg = BlkDevConfig::default();
+        blk_cfg.queues = 4;
+        blk_cfg.throttle.iops = 8;
+        let block = Block::new(blk_cfg);
+        let mut ctx = EventLoopContext::new();
+        let queue_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        // Requests from only one virtqueue reach the full limit of the device.
+        let mut throttle = block.throttle.lock().unwrap();
+        for _ in 0..=8 {
+            assert!(!throttle.throttled(&mut ctx, VIRTIO_BLK_T_IN, 512));
+        }
+        assert!(throttle.throttled(&mut ctx, VIRTIO_BLK_T_IN, 512));
+
+        // The throttled virtqueue is kicked once more IO is allowed.
+        throttle.add_waiter(&queue_evt).unwrap();
+        throttle.add_waiter(&queue_evt).unwrap();
+        assert_eq!(throttle.waiters.len(), 1);
+        throttle.wake_waiters();
+        assert!(throttle.waiters.is_empty());
+        assert_eq!(queue_evt.read().unwrap(), 1);
     }
 
     // Test `write_config` and `read_config`. The main contests include: compare expect data and
@@ -1123,7 +1879,7 @@ mod tests {
         assert!(block
             .write_config(CONFIG_SPACE_SIZE as u64 + 1, &expect_config_space)
             .is_err());
-        let errlen_config_space = [0u8; 17];
+        let errlen_config_space = [0u8; CONFIG_SPACE_SIZE + 1];
         assert!(block.write_config(0, &errlen_config_space).is_err());
         // Invalid read
         read_config_space = expect_config_space;
@@ -1220,7 +1976,7 @@ mod tests {
 
         // config iothread and iops
         block.blk_cfg.iothread = Some(thread_name);
-        block.blk_cfg.iops = Some(100);
+        block.blk_cfg.throttle.iops = 100;
 
         let mem_space = address_space_init();
         let interrupt_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
@@ -1306,8 +2062,18 @@ mod tests {
             .write_object::<u16>(&1, GuestAddress(queue_config.avail_ring.0 + 2 as u64))
             .unwrap();
 
-        // imitating guest OS to send notification.
+        // imitating guest OS to send notification, the request isn't processed
+        // when the device is quiesced.
+        block.quiesce().unwrap();
         event.write(1).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let idx = mem_space
+            .read_object::<u16>(GuestAddress(queue_config.used_ring.0 + 2 as u64))
+            .unwrap();
+        assert_eq!(idx, 0);
+
+        // the request is processed after the device is restarted.
+        block.unquiesce().unwrap();
 
         // waiting for io handled
         let mut wait = 10; // wait for 2 seconds
@@ -10,19 +10,24 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::VecDeque;
 use std::io::Write;
+use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{cmp, usize};
 
 use address_space::AddressSpace;
 use devices::legacy::{Chardev, InputReceiver};
 use error_chain::ChainedError;
 use machine_manager::{
-    config::{ChardevType, VirtioConsole},
+    config::{ChardevType, VirtioSerialInfo, VirtioSerialPort},
     event_loop::EventLoop,
+    machine::{GuestAgent, GuestAgentCallback, GuestAgentInterface},
 };
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
+use serde_json::Value;
 use util::byte_code::ByteCode;
 use util::loop_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
 use util::num_ops::{read_u32, write_u32};
@@ -31,20 +36,42 @@ use vmm_sys_util::eventfd::EventFd;
 
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_CONSOLE_F_SIZE,
-    VIRTIO_F_VERSION_1, VIRTIO_TYPE_CONSOLE,
+    Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_CONSOLE_F_MULTIPORT,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_TYPE_CONSOLE,
 };
 
-/// Number of virtqueues.
-const QUEUE_NUM_CONSOLE: usize = 2;
+/// Number of virtqueues of each port.
+const QUEUE_NUM_PER_PORT: usize = 2;
 /// Size of virtqueue.
 const QUEUE_SIZE_CONSOLE: u16 = 256;
+/// Index of the control receive virtqueue.
+const CTRL_RX_QUEUE: usize = 2;
+/// Index of the control transmit virtqueue.
+const CTRL_TX_QUEUE: usize = 3;
 
 const BUFF_SIZE: usize = 4096;
 
+/// Name of the port which guest agent works on.
+const GUEST_AGENT_PORT_NAME: &str = "org.qemu.guest_agent.0";
+/// Time to wait for guest agent to respond.
+const GUEST_AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delimiter before the response of guest-sync-delimited, by which the stale output
+/// of guest agent is skipped.
+const GUEST_AGENT_DELIMITER: u8 = 0xff;
+
+// Events of control messages, refer to Virtio Spec.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct VirtioConsoleConfig {
+    cols: u16,
+    rows: u16,
     max_nr_ports: u32,
     emerg_wr: u32,
 }
@@ -53,220 +80,493 @@ impl ByteCode for VirtioConsoleConfig {}
 
 impl VirtioConsoleConfig {
     /// Create configuration of virtio-console devices.
-    pub fn new() -> Self {
+    pub fn new(max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
-            max_nr_ports: 1_u32,
+            cols: 0_u16,
+            rows: 0_u16,
+            max_nr_ports,
             emerg_wr: 0_u32,
         }
     }
 }
 
+/// Control message transferred by the control virtqueues.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioConsoleControl {
+    /// Port number.
+    id: u32,
+    /// The kind of control event.
+    event: u16,
+    /// Extra information for the event.
+    value: u16,
+}
+
+impl ByteCode for VirtioConsoleControl {}
+
+/// Get the index of receive virtqueue of the port, the transmit one follows it.
+fn rx_queue_index(nr: u32) -> usize {
+    if nr == 0 {
+        0
+    } else {
+        nr as usize * QUEUE_NUM_PER_PORT + CTRL_RX_QUEUE
+    }
+}
+
+/// Port of virtio-serial device.
+struct SerialPort {
+    /// Port number.
+    nr: u32,
+    /// Name of the port, which is reported to the guest.
+    name: Option<String>,
+    /// Whether the port is a console port.
+    is_console: bool,
+    /// Character device for redirection.
+    chardev: Arc<Mutex<Chardev>>,
+    /// Whether the guest has opened the port.
+    guest_connected: bool,
+    /// Whether the host side of the port is connected.
+    host_connected: bool,
+}
+
+impl SerialPort {
+    fn new(port_cfg: &VirtioSerialPort) -> Self {
+        // Socket-type chardev is connected until the client comes, except that the port of
+        // guest agent is always connected by StratoVirt itself.
+        let host_connected = !matches!(port_cfg.chardev.backend, ChardevType::Socket(_))
+            || port_cfg.name.as_deref() == Some(GUEST_AGENT_PORT_NAME);
+        SerialPort {
+            nr: port_cfg.nr,
+            name: port_cfg.name.clone(),
+            is_console: port_cfg.is_console,
+            chardev: Arc::new(Mutex::new(Chardev::new(port_cfg.chardev.clone()))),
+            guest_connected: false,
+            host_connected,
+        }
+    }
+}
+
 struct ConsoleHandler {
-    input_queue: Arc<Mutex<Queue>>,
-    output_queue: Arc<Mutex<Queue>>,
-    output_queue_evt: EventFd,
+    ports: Vec<SerialPort>,
+    queues: Vec<Arc<Mutex<Queue>>>,
+    queue_evts: Vec<EventFd>,
     reset_evt: RawFd,
-    mem_space: Arc<AddressSpace>,
-    interrupt_cb: Arc<VirtioInterrupt>,
+    mem_space: Option<Arc<AddressSpace>>,
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
     driver_features: u64,
-    chardev: Arc<Mutex<Chardev>>,
+    /// Control messages waiting for the buffers of control receive virtqueue.
+    pending_ctrl: VecDeque<Vec<u8>>,
+    /// The port which guest agent works on.
+    guest_agent: Option<GuestAgentPort>,
 }
 
-impl InputReceiver for ConsoleHandler {
-    #[allow(clippy::useless_asref)]
-    fn input_handle(&mut self, buffer: &[u8]) {
-        let mut queue_lock = self.input_queue.lock().unwrap();
+/// Write `data` to the buffers of one element popped from `queue`, and return the
+/// count of bytes written, or `None` if there is no available element.
+fn write_element(
+    mem_space: &Arc<AddressSpace>,
+    queue: &mut Queue,
+    driver_features: u64,
+    data: &[u8],
+) -> Result<Option<usize>> {
+    let elem = match queue.vring.pop_avail(mem_space, driver_features) {
+        Ok(elem) => elem,
+        Err(_) => return Ok(None),
+    };
+
+    let mut write_count = 0_usize;
+    for elem_iov in elem.in_iovec.iter() {
+        if write_count >= data.len() {
+            break;
+        }
+        let len = cmp::min(elem_iov.len as usize, data.len() - write_count);
+        mem_space
+            .write(
+                &mut &data[write_count..write_count + len],
+                elem_iov.addr,
+                len as u64,
+            )
+            .chain_err(|| {
+                format!(
+                    "Failed to write buffer for console: addr {:X} len {}",
+                    elem_iov.addr.0, len
+                )
+            })?;
+        write_count += len;
+    }
 
-        let count = buffer.len();
-        if count == 0 {
-            return;
+    queue
+        .vring
+        .add_used(mem_space, elem.index, write_count as u32)
+        .chain_err(|| {
+            format!(
+                "Failed to add used ring for console, index: {} len: {}",
+                elem.index, write_count
+            )
+        })?;
+    Ok(Some(write_count))
+}
+
+/// Read all the buffers of the element which are readable for device.
+fn read_element(mem_space: &Arc<AddressSpace>, elem: &Element) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for elem_iov in elem.out_iovec.iter() {
+        mem_space
+            .read(&mut buffer, elem_iov.addr, u64::from(elem_iov.len))
+            .chain_err(|| {
+                format!(
+                    "Failed to read buffer for console: addr: {:X}, len: {}",
+                    elem_iov.addr.0, elem_iov.len
+                )
+            })?;
+    }
+    Ok(buffer)
+}
+
+impl ConsoleHandler {
+    fn multiport(&self) -> bool {
+        self.driver_features & (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0
+    }
+
+    fn notify_guest(&self, queue: &Queue) -> Result<()> {
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(&VirtioInterruptType::Vring, Some(queue))
+                .chain_err(|| ErrorKind::InterruptTrigger("console", VirtioInterruptType::Vring))?;
+        }
+        Ok(())
+    }
+
+    /// Transfer the input of port `nr` to the guest, and return the count of bytes
+    /// transferred, which is less than the input if there is no enough buffers.
+    fn port_input(&mut self, nr: u32, buffer: &[u8]) -> Result<usize> {
+        let mem_space = match &self.mem_space {
+            Some(mem_space) => mem_space.clone(),
+            None => return Ok(0),
+        };
+        if buffer.is_empty() || (nr != 0 && !self.multiport()) {
+            return Ok(0);
         }
 
-        while let Ok(elem) = queue_lock
+        let queue = self.queues[rx_queue_index(nr)].clone();
+        let mut locked_queue = queue.lock().unwrap();
+        let mut write_count = 0_usize;
+        while write_count < buffer.len() {
+            match write_element(
+                &mem_space,
+                &mut locked_queue,
+                self.driver_features,
+                &buffer[write_count..],
+            )? {
+                Some(0) | None => break,
+                Some(count) => write_count += count,
+            }
+        }
+
+        self.notify_guest(&locked_queue)?;
+        Ok(write_count)
+    }
+
+    /// Read the output of the guest from port `nr`.
+    fn port_read(&mut self, nr: u32) -> Result<Vec<u8>> {
+        let mem_space = match &self.mem_space {
+            Some(mem_space) => mem_space.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let queue = self.queues[rx_queue_index(nr) + 1].clone();
+        let mut locked_queue = queue.lock().unwrap();
+        let mut output = Vec::new();
+        while let Ok(elem) = locked_queue
             .vring
-            .pop_avail(&self.mem_space, self.driver_features)
+            .pop_avail(&mem_space, self.driver_features)
         {
-            let mut write_count = 0_usize;
-            for elem_iov in elem.in_iovec.iter() {
-                let allow_write_count = cmp::min(write_count + elem_iov.len as usize, count);
-                let source_slice = &buffer[write_count..allow_write_count];
-
-                let write_result = self.mem_space.write(
-                    &mut source_slice.as_ref(),
-                    elem_iov.addr,
-                    source_slice.len() as u64,
-                );
-                match write_result {
-                    Ok(_) => {
-                        write_count = allow_write_count;
-                    }
-                    Err(ref e) => {
-                        error!(
-                            "Failed to write slice for input console: addr {:X} len {} {}",
-                            elem_iov.addr.0,
-                            source_slice.len(),
-                            e.display_chain()
-                        );
-                        break;
-                    }
-                }
-            }
+            output.append(&mut read_element(&mem_space, &elem)?);
+            locked_queue
+                .vring
+                .add_used(&mem_space, elem.index, 0)
+                .chain_err(|| {
+                    format!(
+                        "Failed to add used ring for output console, index: {} len: {}",
+                        elem.index, 0
+                    )
+                })?;
+        }
 
-            if let Err(ref e) =
-                queue_lock
-                    .vring
-                    .add_used(&self.mem_space, elem.index, write_count as u32)
-            {
-                error!(
-                    "Failed to add used ring for input console, index: {} len: {} {}",
-                    elem.index,
-                    write_count,
-                    e.display_chain()
-                );
-                break;
-            }
+        self.notify_guest(&locked_queue)?;
+        Ok(output)
+    }
 
-            if write_count >= count {
-                break;
-            }
+    /// Transfer the output of the guest to the chardev of port `nr`.
+    fn port_output(&mut self, nr: u32) -> Result<()> {
+        if self.guest_agent.as_ref().map(|agent| agent.nr) == Some(nr) {
+            return self.guest_agent_output();
         }
 
-        if let Err(ref e) = (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock)) {
-            error!(
-                "Failed to trigger interrupt for console, int-type {:?} {} ",
-                VirtioInterruptType::Vring,
-                e.display_chain()
-            )
+        let chardev = match self.ports.iter().find(|port| port.nr == nr) {
+            Some(port) => port.chardev.clone(),
+            None => bail!("Port {} of console is not found", nr),
+        };
+
+        let buffer = self.port_read(nr)?;
+        if buffer.is_empty() {
+            return Ok(());
         }
+        if let Some(output) = &mut chardev.lock().unwrap().output {
+            let mut locked_output = output.lock().unwrap();
+            if let Err(e) = locked_output.write_all(&buffer) {
+                error!("Failed to write to console output: {}", e);
+            }
+            if let Err(e) = locked_output.flush() {
+                error!("Failed to flush console output: {}", e);
+            }
+        } else {
+            debug!("Failed to get output fd");
+        }
+        Ok(())
     }
 
-    fn get_remain_space_size(&mut self) -> usize {
-        BUFF_SIZE
+    /// Queue a control message which is sent to the guest.
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let ctrl = VirtioConsoleControl { id, event, value };
+        let mut msg = ctrl.as_bytes().to_vec();
+        msg.extend_from_slice(extra);
+        self.pending_ctrl.push_back(msg);
     }
-}
 
-impl ConsoleHandler {
-    fn output_handle(&mut self) {
-        let mut queue_lock = self.output_queue.lock().unwrap();
-        let mut buffer = [0_u8; 4096];
+    /// Send the pending control messages to the guest, each one takes an element.
+    fn flush_control(&mut self) -> Result<()> {
+        let mem_space = match &self.mem_space {
+            Some(mem_space) => mem_space.clone(),
+            None => return Ok(()),
+        };
+        if self.pending_ctrl.is_empty() {
+            return Ok(());
+        }
 
-        while let Ok(elem) = queue_lock
-            .vring
-            .pop_avail(&self.mem_space, self.driver_features)
-        {
-            let mut read_count = 0_usize;
-            for elem_iov in elem.out_iovec.iter() {
-                let allow_read_count = cmp::min(read_count + elem_iov.len as usize, buffer.len());
-                let mut slice = &mut buffer[read_count..allow_read_count];
-
-                let read_result = self.mem_space.read(
-                    &mut slice,
-                    elem_iov.addr,
-                    (allow_read_count - read_count) as u64,
-                );
-                match read_result {
-                    Ok(_) => {
-                        read_count = allow_read_count;
-                    }
-                    Err(ref e) => {
-                        error!(
-                            "Failed to read buffer for output console: addr: {:X}, len: {} {}",
-                            elem_iov.addr.0,
-                            allow_read_count - read_count,
-                            e.display_chain()
-                        );
-                        break;
+        let queue = self.queues[CTRL_RX_QUEUE].clone();
+        let mut locked_queue = queue.lock().unwrap();
+        let mut used = false;
+        while let Some(msg) = self.pending_ctrl.front() {
+            if write_element(&mem_space, &mut locked_queue, self.driver_features, msg)?.is_none() {
+                break;
+            }
+            self.pending_ctrl.pop_front();
+            used = true;
+        }
+
+        if used {
+            self.notify_guest(&locked_queue)?;
+        }
+        Ok(())
+    }
+
+    fn handle_control_message(&mut self, ctrl: &VirtioConsoleControl) {
+        match ctrl.event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if ctrl.value == 0 {
+                    error!("Guest failed to initialize virtio-serial device");
+                    return;
+                }
+                let nrs: Vec<u32> = self.ports.iter().map(|port| port.nr).collect();
+                for nr in nrs {
+                    self.send_control(nr, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                let port = match self.ports.iter().find(|port| port.nr == ctrl.id) {
+                    Some(port) => port,
+                    None => {
+                        warn!("Received control message for unknown port {}", ctrl.id);
+                        return;
                     }
                 };
+                if ctrl.value == 0 {
+                    error!("Guest failed to add port {} of virtio-serial", ctrl.id);
+                    return;
+                }
+                let is_console = port.is_console;
+                let name = port.name.clone();
+                let host_connected = port.host_connected;
+
+                if is_console {
+                    self.send_control(ctrl.id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                if let Some(name) = name {
+                    let mut extra = name.into_bytes();
+                    extra.push(0);
+                    self.send_control(ctrl.id, VIRTIO_CONSOLE_PORT_NAME, 1, &extra);
+                }
+                if host_connected {
+                    self.send_control(ctrl.id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
             }
-            if let Some(output) = &mut self.chardev.lock().unwrap().output {
-                let mut locked_output = output.lock().unwrap();
-                if let Err(e) = locked_output.write_all(&buffer[..read_count as usize]) {
-                    error!("Failed to write to console output: {}", e);
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.iter_mut().find(|port| port.nr == ctrl.id) {
+                    port.guest_connected = ctrl.value != 0;
+                } else {
+                    warn!("Received control message for unknown port {}", ctrl.id);
                 }
-                if let Err(e) = locked_output.flush() {
-                    error!("Failed to flush console output: {}", e);
+                if ctrl.value == 0 {
+                    self.guest_agent_cancel(ctrl.id, None, "Guest agent is disconnected");
                 }
-            } else {
-                debug!("Failed to get output fd");
             }
+            _ => {
+                warn!(
+                    "Unsupported control event {} of virtio-serial port {}",
+                    ctrl.event, ctrl.id
+                );
+            }
+        }
+    }
 
-            if let Err(ref e) = queue_lock.vring.add_used(&self.mem_space, elem.index, 0) {
+    /// Handle the control messages from the guest.
+    fn control_output(&mut self) -> Result<()> {
+        let mem_space = match &self.mem_space {
+            Some(mem_space) => mem_space.clone(),
+            None => return Ok(()),
+        };
+
+        let queue = self.queues[CTRL_TX_QUEUE].clone();
+        let mut locked_queue = queue.lock().unwrap();
+        while let Ok(elem) = locked_queue
+            .vring
+            .pop_avail(&mem_space, self.driver_features)
+        {
+            let buffer = read_element(&mem_space, &elem)?;
+            if let Some(ctrl) = VirtioConsoleControl::from_bytes(
+                &buffer[..cmp::min(buffer.len(), size_of::<VirtioConsoleControl>())],
+            ) {
+                let ctrl = *ctrl;
+                self.handle_control_message(&ctrl);
+            } else {
                 error!(
-                    "Failed to add used ring for output console, index: {} len: {} {}",
-                    elem.index,
-                    0,
-                    e.display_chain()
+                    "Invalid control message of virtio-serial, len {}",
+                    buffer.len()
                 );
-                break;
             }
+
+            locked_queue
+                .vring
+                .add_used(&mem_space, elem.index, 0)
+                .chain_err(|| {
+                    format!(
+                        "Failed to add used ring for console control, index: {}",
+                        elem.index
+                    )
+                })?;
         }
+        self.notify_guest(&locked_queue)?;
+        drop(locked_queue);
+
+        self.flush_control()
     }
 
-    fn reset_evt_handler(&self) -> Vec<EventNotifier> {
-        let locked_chardev = self.chardev.lock().unwrap();
-        let mut notifiers = vec![
-            EventNotifier::new(
-                NotifierOperation::Delete,
-                self.reset_evt,
-                None,
-                EventSet::IN,
-                Vec::new(),
-            ),
-            EventNotifier::new(
+    /// Update the connection state of the host side of port `nr`.
+    fn set_host_connected(&mut self, nr: u32, connected: bool) -> Result<()> {
+        if let Some(port) = self.ports.iter_mut().find(|port| port.nr == nr) {
+            if port.name.as_deref() == Some(GUEST_AGENT_PORT_NAME) {
+                return Ok(());
+            }
+            port.host_connected = connected;
+        }
+        if self.mem_space.is_none() || !self.multiport() {
+            return Ok(());
+        }
+
+        self.send_control(nr, VIRTIO_CONSOLE_PORT_OPEN, connected as u16, &[]);
+        self.flush_control()
+    }
+
+    /// Return true if the virtqueue is handled when the guest kicks it. Besides the
+    /// transmit virtqueues, the receive virtqueue of guest agent is handled, because
+    /// the command may wait for its buffers.
+    fn queue_handled(&self, queue_index: usize) -> bool {
+        !is_rx_queue(queue_index)
+            || self
+                .guest_agent
+                .as_ref()
+                .map(|agent| rx_queue_index(agent.nr))
+                == Some(queue_index)
+    }
+
+    fn handle_queue(&mut self, queue_index: usize) -> Result<()> {
+        match queue_index {
+            index if is_rx_queue(index) => self.guest_agent_input(),
+            CTRL_RX_QUEUE => self.flush_control(),
+            CTRL_TX_QUEUE => self.control_output(),
+            1 => self.port_output(0),
+            _ => self.port_output(((queue_index - CTRL_TX_QUEUE) / QUEUE_NUM_PER_PORT) as u32),
+        }
+    }
+
+    fn reset_evt_handler(&mut self) -> Vec<EventNotifier> {
+        let mut notifiers = vec![EventNotifier::new(
+            NotifierOperation::Delete,
+            self.reset_evt,
+            None,
+            EventSet::IN,
+            Vec::new(),
+        )];
+        for (index, queue_evt) in self.queue_evts.iter().enumerate() {
+            if !self.queue_handled(index) {
+                continue;
+            }
+            notifiers.push(EventNotifier::new(
                 NotifierOperation::Delete,
-                self.output_queue_evt.as_raw_fd(),
+                queue_evt.as_raw_fd(),
                 None,
                 EventSet::IN,
                 Vec::new(),
-            ),
-        ];
-        match &locked_chardev.backend {
-            ChardevType::Stdio | ChardevType::Pty => {
-                if let Some(input) = locked_chardev.input.clone() {
-                    notifiers.push(EventNotifier::new(
-                        NotifierOperation::Delete,
-                        input.lock().unwrap().as_raw_fd(),
-                        None,
-                        EventSet::IN,
-                        Vec::new(),
-                    ));
-                }
-            }
-            ChardevType::Socket(_) => {
-                let (stream, _) = locked_chardev.listener.as_ref().unwrap().accept().unwrap();
-                let listener_fd = locked_chardev.listener.as_ref().unwrap().as_raw_fd();
-                let stream_fd = stream.as_raw_fd();
-                notifiers.push(EventNotifier::new(
-                    NotifierOperation::Delete,
-                    stream_fd,
-                    Some(listener_fd),
-                    EventSet::IN | EventSet::HANG_UP,
-                    Vec::new(),
-                ));
-            }
-            _ => (),
+            ));
+        }
+
+        self.mem_space = None;
+        self.interrupt_cb = None;
+        self.queues.clear();
+        self.pending_ctrl.clear();
+        for port in self.ports.iter_mut() {
+            port.guest_connected = false;
+        }
+        if let Some(nr) = self.guest_agent.as_ref().map(|agent| agent.nr) {
+            self.guest_agent_cancel(nr, None, "Guest agent is disconnected");
         }
         notifiers
     }
 }
 
+/// Return true if the virtqueue is used to receive data from the port.
+/// Nothing is done by the device when the guest kicks it.
+fn is_rx_queue(queue_index: usize) -> bool {
+    queue_index == 0 || (queue_index > CTRL_TX_QUEUE && queue_index % QUEUE_NUM_PER_PORT == 0)
+}
+
 impl EventNotifierHelper for ConsoleHandler {
     fn internal_notifiers(console_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
-        let cloned_cls = console_handler.clone();
-        let handler = Box::new(move |_, fd: RawFd| {
-            read_fd(fd);
-            cloned_cls.lock().unwrap().output_handle();
-            None as Option<Vec<EventNotifier>>
-        });
-        notifiers.push(EventNotifier::new(
-            NotifierOperation::AddShared,
-            console_handler.lock().unwrap().output_queue_evt.as_raw_fd(),
-            None,
-            EventSet::IN,
-            vec![Arc::new(Mutex::new(handler))],
-        ));
+        let locked_handler = console_handler.lock().unwrap();
+        for (index, queue_evt) in locked_handler.queue_evts.iter().enumerate() {
+            if !locked_handler.queue_handled(index) {
+                continue;
+            }
+            let cloned_cls = console_handler.clone();
+            let handler = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(ref e) = cloned_cls.lock().unwrap().handle_queue(index) {
+                    error!(
+                        "Failed to handle virtqueue {} of console: {}",
+                        index,
+                        e.display_chain()
+                    );
+                }
+                None as Option<Vec<EventNotifier>>
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                queue_evt.as_raw_fd(),
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            ));
+        }
 
         let cloned_cls = console_handler.clone();
         let handler = Box::new(move |_, fd: RawFd| {
@@ -275,7 +575,7 @@ impl EventNotifierHelper for ConsoleHandler {
         });
         notifiers.push(EventNotifier::new(
             NotifierOperation::AddShared,
-            console_handler.lock().unwrap().reset_evt,
+            locked_handler.reset_evt,
             None,
             EventSet::IN,
             vec![Arc::new(Mutex::new(handler))],
@@ -285,10 +585,330 @@ impl EventNotifierHelper for ConsoleHandler {
     }
 }
 
+/// Receiver of the input from the chardev of a port.
+struct SerialPortReceiver {
+    /// Port number.
+    nr: u32,
+    handler: Arc<Mutex<ConsoleHandler>>,
+}
+
+impl InputReceiver for SerialPortReceiver {
+    fn input_handle(&mut self, buffer: &[u8]) {
+        match self.handler.lock().unwrap().port_input(self.nr, buffer) {
+            Ok(count) if count < buffer.len() => {
+                debug!(
+                    "Dropped {} bytes input of port {} of console",
+                    buffer.len() - count,
+                    self.nr
+                );
+            }
+            Ok(_) => (),
+            Err(ref e) => {
+                error!(
+                    "Failed to handle input of port {} of console: {}",
+                    self.nr,
+                    e.display_chain()
+                );
+            }
+        }
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        BUFF_SIZE
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        if let Err(ref e) = self
+            .handler
+            .lock()
+            .unwrap()
+            .set_host_connected(self.nr, connected)
+        {
+            error!(
+                "Failed to notify connection of port {} of console: {}",
+                self.nr,
+                e.display_chain()
+            );
+        }
+    }
+}
+
+/// Take a line from `buffer` if there is a complete one.
+fn take_line(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let pos = buffer.iter().position(|byte| *byte == b'\n')?;
+    Some(buffer.drain(..=pos).collect())
+}
+
+/// Command of guest agent which is waiting for the response.
+struct GuestAgentRequest {
+    /// Name of the command.
+    execute: String,
+    /// Identifier of the guest-sync-delimited request sent before the command.
+    sync_id: u64,
+    /// Whether the delimiter before the response of guest-sync-delimited is received.
+    delimited: bool,
+    /// Whether the response of guest-sync-delimited is received.
+    synced: bool,
+    /// Whether to wait for the response of the command.
+    wait_response: bool,
+    /// Called with the result of the command.
+    callback: GuestAgentCallback,
+}
+
+/// The port which guest agent works on.
+struct GuestAgentPort {
+    /// Port number of guest agent.
+    nr: u32,
+    /// Identifier of the last guest-sync-delimited request.
+    sync_id: u64,
+    /// Input of guest agent waiting for the buffers of receive virtqueue.
+    pending_input: Vec<u8>,
+    /// Output of guest agent which is not a complete line yet.
+    output: Vec<u8>,
+    /// The command in progress.
+    request: Option<GuestAgentRequest>,
+}
+
+impl GuestAgentPort {
+    fn new(nr: u32) -> Self {
+        GuestAgentPort {
+            nr,
+            sync_id: 0,
+            pending_input: Vec::new(),
+            output: Vec::new(),
+            request: None,
+        }
+    }
+
+    /// Start the command, it's sent after guest-sync-delimited, so that the stale
+    /// output of guest agent is skipped.
+    fn start(
+        &mut self,
+        execute: &str,
+        arguments: Option<Value>,
+        wait_response: bool,
+        callback: GuestAgentCallback,
+    ) -> Result<u64> {
+        if let Some(request) = &self.request {
+            bail!("Guest agent is busy with {}", request.execute);
+        }
+
+        self.sync_id = self.sync_id.wrapping_add(1);
+        let sync = serde_json::json!({
+            "execute": "guest-sync-delimited",
+            "arguments": { "id": self.sync_id },
+        });
+        let mut command = serde_json::json!({ "execute": execute });
+        if let Some(arguments) = arguments {
+            command["arguments"] = arguments;
+        }
+
+        self.pending_input = vec![GUEST_AGENT_DELIMITER];
+        self.pending_input
+            .append(&mut format!("{}\n{}\n", sync, command).into_bytes());
+        self.output.clear();
+        self.request = Some(GuestAgentRequest {
+            execute: execute.to_string(),
+            sync_id: self.sync_id,
+            delimited: false,
+            synced: false,
+            wait_response,
+            callback,
+        });
+        Ok(self.sync_id)
+    }
+
+    /// Handle the output of guest agent, and return the result of the command
+    /// once it's completed.
+    fn receive(&mut self, data: &[u8]) -> Option<Result<Value>> {
+        let request = self.request.as_mut()?;
+        self.output.extend_from_slice(data);
+        if !request.delimited {
+            let pos = match self
+                .output
+                .iter()
+                .position(|byte| *byte == GUEST_AGENT_DELIMITER)
+            {
+                Some(pos) => pos,
+                None => {
+                    self.output.clear();
+                    return None;
+                }
+            };
+            self.output.drain(..=pos);
+            request.delimited = true;
+        }
+
+        while let Some(line) = take_line(&mut self.output) {
+            let resp: Value = match serde_json::from_slice(&line) {
+                Ok(resp) => resp,
+                Err(_) if !request.synced => continue,
+                Err(_) => return Some(Err("Invalid response of guest agent".into())),
+            };
+            if !request.synced {
+                if resp.get("return").and_then(Value::as_u64) == Some(request.sync_id) {
+                    request.synced = true;
+                    if !request.wait_response {
+                        return Some(Ok(serde_json::json!({})));
+                    }
+                }
+                continue;
+            }
+
+            if let Some(ret) = resp.get("return") {
+                return Some(Ok(ret.clone()));
+            }
+            if let Some(desc) = resp.get("error").and_then(|err| err.get("desc")) {
+                return Some(Err(format!(
+                    "Guest agent failed to execute {}: {}",
+                    request.execute, desc
+                )
+                .into()));
+            }
+            return Some(Err(
+                format!("Invalid response of guest agent: {}", resp).into()
+            ));
+        }
+        None
+    }
+}
+
+impl ConsoleHandler {
+    /// Send the command to guest agent, and return the identifier of it.
+    fn guest_agent_execute(
+        &mut self,
+        execute: &str,
+        arguments: Option<Value>,
+        wait_response: bool,
+        callback: GuestAgentCallback,
+    ) -> Result<u64> {
+        let nr = match &self.guest_agent {
+            Some(agent) => agent.nr,
+            None => bail!("No guest agent port is configured"),
+        };
+        let guest_connected = self
+            .ports
+            .iter()
+            .any(|port| port.nr == nr && port.guest_connected);
+        if !guest_connected {
+            bail!("Guest agent is not connected");
+        }
+
+        let sync_id = self.guest_agent.as_mut().unwrap().start(
+            execute,
+            arguments,
+            wait_response,
+            callback,
+        )?;
+        if let Err(e) = self.guest_agent_input() {
+            let agent = self.guest_agent.as_mut().unwrap();
+            agent.request = None;
+            agent.pending_input.clear();
+            return Err(e);
+        }
+        Ok(sync_id)
+    }
+
+    /// Transfer the pending input of guest agent to the guest.
+    fn guest_agent_input(&mut self) -> Result<()> {
+        let (nr, input) = match &mut self.guest_agent {
+            Some(agent) if !agent.pending_input.is_empty() => {
+                (agent.nr, std::mem::take(&mut agent.pending_input))
+            }
+            _ => return Ok(()),
+        };
+        let count = self.port_input(nr, &input)?;
+        self.guest_agent.as_mut().unwrap().pending_input = input[count..].to_vec();
+        Ok(())
+    }
+
+    /// Handle the output of guest agent, the command is completed once guest
+    /// agent responds.
+    fn guest_agent_output(&mut self) -> Result<()> {
+        let nr = match &self.guest_agent {
+            Some(agent) => agent.nr,
+            None => return Ok(()),
+        };
+        let output = self.port_read(nr)?;
+        let agent = self.guest_agent.as_mut().unwrap();
+        if let Some(result) = agent.receive(&output) {
+            let request = agent.request.take().unwrap();
+            (request.callback)(result.map_err(|e| e.to_string().into()));
+        }
+        Ok(())
+    }
+
+    /// Fail the command of guest agent on port `nr`.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr` - Port number.
+    /// * `sync_id` - Identifier of the command, any command is failed if `None`.
+    /// * `reason` - Why the command fails.
+    fn guest_agent_cancel(&mut self, nr: u32, sync_id: Option<u64>, reason: &str) {
+        let agent = match &mut self.guest_agent {
+            Some(agent) if agent.nr == nr => agent,
+            _ => return,
+        };
+        let cancel = match &agent.request {
+            Some(request) => sync_id.map_or(true, |id| id == request.sync_id),
+            None => false,
+        };
+        if cancel {
+            agent.pending_input.clear();
+            let request = agent.request.take().unwrap();
+            (request.callback)(Err(reason.into()));
+        }
+    }
+}
+
+/// Client of guest agent over the port of virtio-serial.
+///
+/// # Notes
+///
+/// The response of guest agent is handled when the guest kicks the transmit
+/// virtqueue of the port, so the main loop is not blocked by the command.
+struct GuestAgentClient {
+    /// Port number of guest agent.
+    nr: u32,
+    handler: Arc<Mutex<ConsoleHandler>>,
+}
+
+impl GuestAgentInterface for GuestAgentClient {
+    fn execute(
+        &mut self,
+        execute: &str,
+        arguments: Option<Value>,
+        wait_response: bool,
+        callback: GuestAgentCallback,
+    ) -> machine_manager::errors::Result<()> {
+        let sync_id = self
+            .handler
+            .lock()
+            .unwrap()
+            .guest_agent_execute(execute, arguments, wait_response, callback)
+            .map_err(|e| e.to_string())?;
+
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            let nr = self.nr;
+            let handler = self.handler.clone();
+            let timeout = Box::new(move || {
+                handler.lock().unwrap().guest_agent_cancel(
+                    nr,
+                    Some(sync_id),
+                    "Timeout to wait for the response of guest agent",
+                );
+            });
+            ctx.delay_call(timeout, GUEST_AGENT_TIMEOUT.as_nanos() as u64);
+        }
+        Ok(())
+    }
+}
+
 /// Status of console device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.2.0", compat_version = "0.2.0")]
 pub struct VirtioConsoleState {
     /// Bit mask of features supported by the backend.
     device_features: u64,
@@ -298,14 +918,16 @@ pub struct VirtioConsoleState {
     config_space: VirtioConsoleConfig,
 }
 
-/// Virtio console device structure.
+/// Virtio console device structure, which is virtio-serial with ports.
 pub struct Console {
     /// Status of console device.
     state: VirtioConsoleState,
     /// EventFd for device reset.
     reset_evt: EventFd,
-    /// Character device for redirection.
-    chardev: Arc<Mutex<Chardev>>,
+    /// Handler of the ports and virtqueues.
+    handler: Arc<Mutex<ConsoleHandler>>,
+    /// Whether the chardevs of ports have been registered to event loop.
+    chardev_registered: bool,
 }
 
 impl Console {
@@ -313,29 +935,68 @@ impl Console {
     ///
     /// # Arguments
     ///
-    /// * `console_cfg` - Device configuration set by user.
-    pub fn new(console_cfg: VirtioConsole) -> Self {
+    /// * `serial_cfg` - Configuration of virtio-serial device and its ports set by user.
+    pub fn new(serial_cfg: &VirtioSerialInfo) -> Self {
+        let reset_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let handler = ConsoleHandler {
+            ports: serial_cfg.ports.iter().map(SerialPort::new).collect(),
+            queues: Vec::new(),
+            queue_evts: Vec::new(),
+            reset_evt: reset_evt.as_raw_fd(),
+            mem_space: None,
+            interrupt_cb: None,
+            driver_features: 0_u64,
+            pending_ctrl: VecDeque::new(),
+            guest_agent: serial_cfg
+                .ports
+                .iter()
+                .find(|port| port.name.as_deref() == Some(GUEST_AGENT_PORT_NAME))
+                .map(|port| GuestAgentPort::new(port.nr)),
+        };
         Console {
             state: VirtioConsoleState {
                 device_features: 0_u64,
                 driver_features: 0_u64,
-                config_space: VirtioConsoleConfig::new(),
+                config_space: VirtioConsoleConfig::new(serial_cfg.max_ports),
             },
-            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-            chardev: Arc::new(Mutex::new(Chardev::new(console_cfg.chardev))),
+            reset_evt,
+            handler: Arc::new(Mutex::new(handler)),
+            chardev_registered: false,
         }
     }
 }
 
+impl Console {
+    /// Get the channel to guest agent, if guest agent works on one of the ports.
+    pub fn guest_agent(&self) -> Option<GuestAgent> {
+        let nr = self.handler.lock().unwrap().guest_agent.as_ref()?.nr;
+        Some(Arc::new(Mutex::new(GuestAgentClient {
+            nr,
+            handler: self.handler.clone(),
+        })))
+    }
+}
+
 impl VirtioDevice for Console {
     /// Realize virtio console device.
     fn realize(&mut self) -> Result<()> {
-        self.state.device_features = 1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
-        self.chardev
-            .lock()
-            .unwrap()
-            .realize()
-            .chain_err(|| "Failed to realize chardev")?;
+        self.state.device_features = 1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_F_RING_PACKED;
+        if self.state.config_space.max_nr_ports > 1 {
+            self.state.device_features |= 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+        }
+
+        let locked_handler = self.handler.lock().unwrap();
+        for port in locked_handler.ports.iter() {
+            let mut locked_chardev = port.chardev.lock().unwrap();
+            locked_chardev
+                .realize()
+                .chain_err(|| format!("Failed to realize chardev of port {}", port.nr))?;
+            let receiver = Arc::new(Mutex::new(SerialPortReceiver {
+                nr: port.nr,
+                handler: self.handler.clone(),
+            }));
+            locked_chardev.set_input_callback(&receiver);
+        }
         Ok(())
     }
 
@@ -346,7 +1007,13 @@ impl VirtioDevice for Console {
 
     /// Get the count of virtio device queues.
     fn queue_num(&self) -> usize {
-        QUEUE_NUM_CONSOLE
+        let max_nr_ports = self.state.config_space.max_nr_ports as usize;
+        if max_nr_ports > 1 {
+            // Control virtqueues take the place of port 1.
+            (max_nr_ports + 1) * QUEUE_NUM_PER_PORT
+        } else {
+            QUEUE_NUM_PER_PORT
+        }
     }
 
     /// Get the queue size of virtio device.
@@ -397,29 +1064,32 @@ impl VirtioDevice for Console {
         mem_space: Arc<AddressSpace>,
         interrupt_cb: Arc<VirtioInterrupt>,
         queues: &[Arc<Mutex<Queue>>],
-        mut queue_evts: Vec<EventFd>,
+        queue_evts: Vec<EventFd>,
     ) -> Result<()> {
-        queue_evts.remove(0); // input_queue_evt never used
+        let mut locked_handler = self.handler.lock().unwrap();
+        locked_handler.queues = queues.to_vec();
+        locked_handler.queue_evts = queue_evts;
+        locked_handler.mem_space = Some(mem_space);
+        locked_handler.interrupt_cb = Some(interrupt_cb);
+        locked_handler.driver_features = self.state.driver_features;
+        drop(locked_handler);
 
-        let handler = ConsoleHandler {
-            input_queue: queues[0].clone(),
-            output_queue: queues[1].clone(),
-            output_queue_evt: queue_evts.remove(0),
-            mem_space,
-            interrupt_cb,
-            driver_features: self.state.driver_features,
-            reset_evt: self.reset_evt.as_raw_fd(),
-            chardev: self.chardev.clone(),
-        };
-
-        let dev = Arc::new(Mutex::new(handler));
-        EventLoop::update_event(EventNotifierHelper::internal_notifiers(dev.clone()), None)?;
-        let locked_dev = dev.lock().unwrap();
-        locked_dev.chardev.lock().unwrap().set_input_callback(&dev);
         EventLoop::update_event(
-            EventNotifierHelper::internal_notifiers(locked_dev.chardev.clone()),
+            EventNotifierHelper::internal_notifiers(self.handler.clone()),
             None,
         )?;
+
+        // The input of chardevs is handled since the device is activated at the first time.
+        if !self.chardev_registered {
+            let locked_handler = self.handler.lock().unwrap();
+            for port in locked_handler.ports.iter() {
+                EventLoop::update_event(
+                    EventNotifierHelper::internal_notifiers(port.chardev.clone()),
+                    None,
+                )?;
+            }
+            self.chardev_registered = true;
+        }
         Ok(())
     }
 
@@ -461,21 +1131,70 @@ mod tests {
     pub use super::*;
     use std::mem::size_of;
 
-    use machine_manager::config::{ChardevConfig, ChardevType};
+    use address_space::{GuestAddress, HostMemMapping, Region};
+    use machine_manager::config::ChardevConfig;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 0x02;
+    const SYSTEM_SPACE_SIZE: u64 = (1024 * 1024) as u64;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                SYSTEM_SPACE_SIZE,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn serial_info_init(max_ports: u32) -> VirtioSerialInfo {
+        VirtioSerialInfo {
+            id: "serial".to_string(),
+            pci_bdf: None,
+            multifunction: false,
+            max_ports,
+            ports: vec![
+                VirtioSerialPort {
+                    id: "console".to_string(),
+                    chardev: ChardevConfig {
+                        id: "chardev0".to_string(),
+                        backend: ChardevType::Stdio,
+                    },
+                    nr: 0,
+                    name: None,
+                    is_console: true,
+                },
+                VirtioSerialPort {
+                    id: "port".to_string(),
+                    chardev: ChardevConfig {
+                        id: "chardev1".to_string(),
+                        backend: ChardevType::Socket("/path/to/socket".to_string()),
+                    },
+                    nr: 1,
+                    name: Some("org.openeuler.port.0".to_string()),
+                    is_console: false,
+                },
+            ],
+        }
+    }
 
     #[test]
     fn test_set_driver_features() {
-        let chardev_cfg = ChardevConfig {
-            id: "chardev".to_string(),
-            backend: ChardevType::Stdio,
-        };
-        let mut console = Console::new(VirtioConsole {
-            id: "console".to_string(),
-            chardev: chardev_cfg.clone(),
-        });
-        let mut chardev = Chardev::new(chardev_cfg);
-        chardev.output = Some(Arc::new(Mutex::new(std::io::stdout())));
-        console.chardev = Arc::new(Mutex::new(chardev));
+        let mut console = Console::new(&serial_info_init(1));
 
         //If the device feature is 0, all driver features are not supported.
         console.state.device_features = 0;
@@ -492,31 +1211,31 @@ mod tests {
         //If both the device feature bit and the front-end driver feature bit are
         //supported at the same time,  this driver feature bit is supported.
         console.state.device_features =
-            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
-        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) as u32;
         let page = 0_u32;
         console.set_driver_features(page, driver_feature);
         assert_eq!(
             console.state.driver_features,
-            (1_u64 << VIRTIO_CONSOLE_F_SIZE)
+            (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT)
         );
         console.state.driver_features = 0;
 
         console.state.device_features = 1_u64 << VIRTIO_F_VERSION_1;
-        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) as u32;
         let page = 0_u32;
         console.set_driver_features(page, driver_feature);
         assert_eq!(console.state.driver_features, 0);
         console.state.driver_features = 0;
 
         console.state.device_features =
-            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
-        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) as u32;
         let page = 0_u32;
         console.set_driver_features(page, driver_feature);
         assert_eq!(
             console.state.driver_features,
-            (1_u64 << VIRTIO_CONSOLE_F_SIZE)
+            (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT)
         );
 
         let driver_feature: u32 = ((1_u64 << VIRTIO_F_VERSION_1) >> 32) as u32;
@@ -524,23 +1243,14 @@ mod tests {
         console.set_driver_features(page, driver_feature);
         assert_eq!(
             console.state.driver_features,
-            (1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE)
+            (1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT)
         );
     }
 
     #[test]
     fn test_read_config() {
-        let chardev_cfg = ChardevConfig {
-            id: "chardev".to_string(),
-            backend: ChardevType::Stdio,
-        };
-        let mut console = Console::new(VirtioConsole {
-            id: "console".to_string(),
-            chardev: chardev_cfg.clone(),
-        });
-        let mut chardev = Chardev::new(chardev_cfg);
-        chardev.output = Some(Arc::new(Mutex::new(std::io::stdout())));
-        console.chardev = Arc::new(Mutex::new(chardev));
+        let console = Console::new(&serial_info_init(1));
+        assert_eq!(console.queue_num(), 2);
 
         //The offset of configuration that needs to be read exceeds the maximum
         let offset = size_of::<VirtioConsoleConfig>() as u64;
@@ -550,14 +1260,162 @@ mod tests {
         //Check the configuration that needs to be read
         let offset = 0_u64;
         let mut read_data: Vec<u8> = vec![0; 8];
-        let expect_data: Vec<u8> = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        let expect_data: Vec<u8> = vec![0, 0, 0, 0, 1, 0, 0, 0];
         assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
         assert_eq!(read_data, expect_data);
 
-        let offset = 0_u64;
+        let offset = 4_u64;
         let mut read_data: Vec<u8> = vec![0; 1];
         let expect_data: Vec<u8> = vec![1];
         assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
         assert_eq!(read_data, expect_data);
+
+        // Two more virtqueues are used for control with multiport.
+        let console = Console::new(&serial_info_init(3));
+        assert_eq!(console.queue_num(), 8);
+        let mut read_data: Vec<u8> = vec![0; 4];
+        assert!(console.read_config(4, &mut read_data).is_ok());
+        assert_eq!(read_data, vec![3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_control_message() {
+        let mem_space = address_space_init();
+        let console = Console::new(&serial_info_init(3));
+        let mut queues = Vec::new();
+        for i in 0..CTRL_TX_QUEUE as u64 + 1 {
+            let mut queue_config = QueueConfig::new(QUEUE_SIZE_CONSOLE);
+            queue_config.desc_table = GuestAddress(i * 0x4000);
+            queue_config.avail_ring = GuestAddress(i * 0x4000 + 0x1000);
+            queue_config.used_ring = GuestAddress(i * 0x4000 + 0x2000);
+            queue_config.size = QUEUE_SIZE_CONSOLE;
+            queue_config.ready = true;
+            queues.push(Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())));
+        }
+        let mut handler = console.handler.lock().unwrap();
+        handler.mem_space = Some(mem_space.clone());
+        handler.queues = queues;
+        handler.driver_features = 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+
+        // Guest posts one buffer to the control receive virtqueue.
+        let rx_base = CTRL_RX_QUEUE as u64 * 0x4000;
+        let desc = SplitVringDesc {
+            addr: GuestAddress(0x20000),
+            len: 64,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        mem_space
+            .write_object::<SplitVringDesc>(&desc, GuestAddress(rx_base))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(rx_base + 0x1000 + 2))
+            .unwrap();
+
+        // Guest sends DEVICE_READY by the control transmit virtqueue.
+        let tx_base = CTRL_TX_QUEUE as u64 * 0x4000;
+        let desc = SplitVringDesc {
+            addr: GuestAddress(0x30000),
+            len: size_of::<VirtioConsoleControl>() as u32,
+            flags: 0,
+            next: 0,
+        };
+        mem_space
+            .write_object::<SplitVringDesc>(&desc, GuestAddress(tx_base))
+            .unwrap();
+        let ctrl = VirtioConsoleControl {
+            id: 0,
+            event: VIRTIO_CONSOLE_DEVICE_READY,
+            value: 1,
+        };
+        mem_space
+            .write_object::<VirtioConsoleControl>(&ctrl, GuestAddress(0x30000))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(tx_base + 0x1000 + 2))
+            .unwrap();
+
+        // DEVICE_ADD is sent for each port, but only one buffer is available.
+        assert!(handler.control_output().is_ok());
+        let used_idx = mem_space
+            .read_object::<u16>(GuestAddress(rx_base + 0x2000 + 2))
+            .unwrap();
+        assert_eq!(used_idx, 1);
+        let ctrl = mem_space
+            .read_object::<VirtioConsoleControl>(GuestAddress(0x20000))
+            .unwrap();
+        assert_eq!(ctrl.id, 0);
+        assert_eq!(ctrl.event, VIRTIO_CONSOLE_DEVICE_ADD);
+        assert_eq!(handler.pending_ctrl.len(), 1);
+
+        // Port 1 gets its name and open event once the guest gets it ready.
+        handler.pending_ctrl.clear();
+        handler.set_host_connected(1, true).unwrap();
+        assert_eq!(handler.pending_ctrl.len(), 1);
+        handler.pending_ctrl.clear();
+        handler.handle_control_message(&VirtioConsoleControl {
+            id: 1,
+            event: VIRTIO_CONSOLE_PORT_READY,
+            value: 1,
+        });
+        assert_eq!(handler.pending_ctrl.len(), 2);
+        let name_msg = &handler.pending_ctrl[0];
+        let ctrl = VirtioConsoleControl::from_bytes(&name_msg[..size_of::<VirtioConsoleControl>()])
+            .unwrap();
+        assert_eq!(ctrl.event, VIRTIO_CONSOLE_PORT_NAME);
+        assert_eq!(
+            &name_msg[size_of::<VirtioConsoleControl>()..],
+            b"org.openeuler.port.0\0"
+        );
+        let ctrl = VirtioConsoleControl::from_bytes(&handler.pending_ctrl[1]).unwrap();
+        assert_eq!(ctrl.event, VIRTIO_CONSOLE_PORT_OPEN);
+        assert_eq!(ctrl.value, 1);
+    }
+
+    #[test]
+    fn test_guest_agent_response() {
+        let mut buffer = b"{\"return\": 1}\n{\"return\"".to_vec();
+        assert_eq!(take_line(&mut buffer), Some(b"{\"return\": 1}\n".to_vec()));
+        assert_eq!(take_line(&mut buffer), None);
+        buffer.extend_from_slice(b": {}}\n");
+        assert_eq!(take_line(&mut buffer), Some(b"{\"return\": {}}\n".to_vec()));
+        assert!(buffer.is_empty());
+
+        // Guest agent is not connected until the guest opens the port.
+        let mut serial_info = serial_info_init(3);
+        serial_info.ports[1].name = Some(GUEST_AGENT_PORT_NAME.to_string());
+        let console = Console::new(&serial_info);
+        let agent = console.guest_agent().unwrap();
+        assert!(agent
+            .lock()
+            .unwrap()
+            .execute("guest-ping", None, true, Box::new(|_| {}))
+            .is_err());
+
+        // The stale output before the delimiter and the response of
+        // guest-sync-delimited are skipped.
+        let mut port = GuestAgentPort::new(1);
+        let sync_id = port
+            .start("guest-ping", None, true, Box::new(|_| {}))
+            .unwrap();
+        assert_eq!(port.pending_input[0], GUEST_AGENT_DELIMITER);
+        assert!(port
+            .start("guest-ping", None, true, Box::new(|_| {}))
+            .is_err());
+        assert!(port.receive(b"{\"return\": {}}\n").is_none());
+        assert!(port.receive(&[GUEST_AGENT_DELIMITER]).is_none());
+        let sync_resp = format!("{{\"return\": {}}}\n{{\"return\"", sync_id);
+        assert!(port.receive(sync_resp.as_bytes()).is_none());
+        let ret = port.receive(b": {}}\n").unwrap().unwrap();
+        assert_eq!(ret, serde_json::json!({}));
+
+        // The command which doesn't wait for response completes once synced.
+        port.request = None;
+        let sync_id = port
+            .start("guest-shutdown", None, false, Box::new(|_| {}))
+            .unwrap();
+        let mut data = vec![GUEST_AGENT_DELIMITER];
+        data.extend_from_slice(format!("{{\"return\": {}}}\n", sync_id).as_bytes());
+        assert!(port.receive(&data).unwrap().is_ok());
     }
 }
@@ -28,6 +28,8 @@
 #[macro_use]
 extern crate error_chain;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 #[macro_use]
 extern crate machine_manager;
@@ -102,7 +104,9 @@ pub mod errors {
 mod balloon;
 mod block;
 mod console;
+mod mem;
 mod net;
+mod qcow2;
 mod queue;
 mod rng;
 mod vhost;
@@ -114,10 +118,12 @@ pub use balloon::*;
 pub use block::{Block, BlockState};
 pub use console::{Console, VirtioConsoleState};
 pub use errors::*;
+pub use mem::*;
 pub use net::*;
 pub use queue::*;
 pub use rng::{Rng, RngState};
 pub use vhost::kernel as VhostKern;
+pub use vhost::user as VhostUser;
 pub use virtio_mmio::{VirtioMmioDevice, VirtioMmioState};
 pub use virtio_pci::VirtioPciDevice;
 
@@ -139,7 +145,8 @@ pub const VIRTIO_TYPE_CONSOLE: u32 = 3;
 pub const VIRTIO_TYPE_RNG: u32 = 4;
 pub const VIRTIO_TYPE_BALLOON: u32 = 5;
 pub const VIRTIO_TYPE_VSOCK: u32 = 19;
-pub const _VIRTIO_TYPE_FS: u32 = 26;
+pub const VIRTIO_TYPE_MEM: u32 = 24;
+pub const VIRTIO_TYPE_FS: u32 = 26;
 
 // The Status of Virtio Device.
 const CONFIG_STATUS_ACKNOWLEDGE: u32 = 0x01;
@@ -176,16 +183,92 @@ pub const VIRTIO_NET_F_GUEST_UFO: u32 = 10;
 pub const VIRTIO_NET_F_HOST_TSO4: u32 = 11;
 /// Device can receive UFO.
 pub const VIRTIO_NET_F_HOST_UFO: u32 = 14;
+/// Configuration status field is available.
+pub const VIRTIO_NET_F_STATUS: u32 = 16;
+/// Control channel is available.
+pub const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+/// Control channel RX mode support.
+pub const VIRTIO_NET_F_CTRL_RX: u32 = 18;
+/// Control channel VLAN filtering.
+pub const VIRTIO_NET_F_CTRL_VLAN: u32 = 19;
+/// Extra RX mode control support.
+pub const VIRTIO_NET_F_CTRL_RX_EXTRA: u32 = 20;
+/// Driver can send gratuitous packets.
+pub const VIRTIO_NET_F_GUEST_ANNOUNCE: u32 = 21;
+/// Device supports multiqueue with automatic receive steering.
+pub const VIRTIO_NET_F_MQ: u32 = 22;
+/// Set MAC address through control channel.
+pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u32 = 23;
+/// The link of device is up.
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// The driver is requested to send gratuitous packets.
+pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+/// The class of control command to configure RX mode.
+pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+/// The control command to turn promiscuous mode on or off.
+pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+/// The control command to turn all-multicast receive on or off.
+pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+/// The control command to turn all-unicast receive on or off.
+pub const VIRTIO_NET_CTRL_RX_ALLUNI: u8 = 2;
+/// The control command to suppress multicast receive.
+pub const VIRTIO_NET_CTRL_RX_NOMULTI: u8 = 3;
+/// The control command to suppress unicast receive.
+pub const VIRTIO_NET_CTRL_RX_NOUNI: u8 = 4;
+/// The control command to suppress broadcast receive.
+pub const VIRTIO_NET_CTRL_RX_NOBCAST: u8 = 5;
+/// The class of control command to configure MAC address.
+pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+/// The control command to set the unicast and multicast MAC filter table.
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+/// The control command to set the default MAC address.
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+/// The class of control command to configure VLAN filter.
+pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+/// The control command to add a VLAN id to the filter.
+pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+/// The control command to remove a VLAN id from the filter.
+pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+/// The class of control command to acknowledge the announcement.
+pub const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
+/// The control command to acknowledge the announcement.
+pub const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
+/// The class of control command to configure multiqueue.
+pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+/// The control command to set the number of queue pairs in use.
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+/// The minimum number of queue pairs which can be set.
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u16 = 1;
+/// The control command is handled successfully.
+pub const VIRTIO_NET_OK: u8 = 0;
+/// The control command fails to be handled.
+pub const VIRTIO_NET_ERR: u8 = 1;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+/// Device has support for multiple ports and control virtqueues.
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
 /// Maximum size of any single segment is in size_max.
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
 /// Maximum number of segments in a request is in seg_max.
 pub const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+/// Legacy geometry available.
+pub const VIRTIO_BLK_F_GEOMETRY: u32 = 4;
 /// Device is read-only.
 pub const VIRTIO_BLK_F_RO: u32 = 5;
+/// Block size of disk is available.
+pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
 /// Cache flush command support.
 pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
+/// Topology information is available.
+pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 10;
+/// Writeback mode is available in config.
+pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11;
+/// Device supports multiqueue.
+pub const VIRTIO_BLK_F_MQ: u32 = 12;
+/// Device can support discard command.
+pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
+/// Device can support write zeroes command.
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
 /// The IO type of virtio block, refer to Virtio Spec.
 /// Read.
@@ -196,10 +279,20 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// Device id
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Discard command.
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+/// Write zeroes command.
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+/// Write zeroes fallocate flag: the sectors can be unmapped.
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 /// Device id length
 pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
 /// Success
 pub const VIRTIO_BLK_S_OK: u32 = 0;
+/// IO error.
+pub const VIRTIO_BLK_S_IOERR: u32 = 1;
+/// Unsupported request.
+pub const VIRTIO_BLK_S_UNSUPP: u32 = 2;
 
 /// Interrupt status: Used Buffer Notification
 pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
@@ -210,6 +303,10 @@ pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x02;
 /// Guest OS uses notify reg to notify the VMM.
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 
+/// The maximum number of virtqueues within a virtio device. It covers a
+/// virtio-serial with 31 ports, which uses 64 queues.
+pub const VIRTIO_MAX_QUEUES: usize = 64;
+
 /// Packet header, refer to Virtio Spec.
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
@@ -284,6 +381,12 @@ pub trait VirtioDevice: Send {
         );
     }
 
+    /// Release the backend resources of virtio device, this function is called
+    /// when the device is hot unplugged.
+    fn unrealize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Update the low level config of MMIO device,
     /// for example: update the images file fd of virtio block device.
     ///
@@ -293,4 +396,13 @@ pub trait VirtioDevice: Send {
     fn update_config(&mut self, _dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         bail!("Unsupported to update configuration")
     }
+
+    /// Get the guest physical base address and length of the shared memory region.
+    ///
+    /// # Arguments
+    ///
+    /// * `_shm_id` - The id of shared memory region, refer to Virtio Spec.
+    fn get_shm_region(&self, _shm_id: u8) -> Option<(u64, u64)> {
+        None
+    }
 }
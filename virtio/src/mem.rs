@@ -0,0 +1,974 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::io::Write;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, HostMemMapping, Region};
+use machine_manager::{
+    config::VirtioMemConfig,
+    event_loop::EventLoop,
+    qmp::qmp_schema::{MemoryDeviceSizeChange, VirtioMemInfo},
+    qmp::QmpChannel,
+};
+use migration::MigrationManager;
+use util::bitmap::Bitmap;
+use util::byte_code::ByteCode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::{read_u32, write_u32};
+use util::unix::host_page_size;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_PACKED,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_MEM,
+};
+
+const QUEUE_NUM_MEM: usize = 1;
+const QUEUE_SIZE_MEM: u16 = 128;
+const BITS_OF_TYPE_U64: u64 = 64;
+
+// Types of request, refer to Virtio Spec.
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+// Types of response, refer to Virtio Spec.
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+// States of memory blocks in response of state request, refer to Virtio Spec.
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+lazy_static! {
+    /// All virtio-mem devices, for QMP commands to query and resize them.
+    static ref VIRTIO_MEM_DEVS: Mutex<Vec<Arc<Mutex<VirtioMem>>>> = Mutex::new(Vec::new());
+}
+
+/// Configuration space of virtio-mem device, refer to Virtio Spec.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtioMemConfigSpace {
+    /// Size of the blocks that guest plugs and unplugs.
+    block_size: u64,
+    /// Proximity domain of the memory region, valid if VIRTIO_MEM_F_ACPI_PXM is negotiated.
+    node_id: u16,
+    padding: [u8; 6],
+    /// Guest physical address of the memory region.
+    addr: u64,
+    /// Size of the memory region.
+    region_size: u64,
+    /// Size of the part of memory region that guest can use.
+    usable_region_size: u64,
+    /// Size of plugged memory.
+    plugged_size: u64,
+    /// Size of memory that guest is requested to plug.
+    requested_size: u64,
+}
+
+/// Request from guest in the request queue.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtioMemReq {
+    req_type: u16,
+    padding: [u16; 3],
+    /// Guest physical address of the first block, unused for UNPLUG_ALL request.
+    addr: u64,
+    /// Number of blocks, unused for UNPLUG_ALL request.
+    nb_blocks: u16,
+    padding_1: [u16; 3],
+}
+
+/// Response to guest in the request queue.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtioMemResp {
+    resp_type: u16,
+    padding: [u16; 3],
+    /// State of the blocks, only valid for STATE request.
+    state: u16,
+}
+
+impl ByteCode for VirtioMemConfigSpace {}
+impl ByteCode for VirtioMemReq {}
+impl ByteCode for VirtioMemResp {}
+
+impl VirtioMemResp {
+    fn new(resp_type: u16) -> Self {
+        VirtioMemResp {
+            resp_type,
+            ..Default::default()
+        }
+    }
+}
+
+/// Release the host memory of the range, the guest reads zero from it after that.
+///
+/// # Arguments
+///
+/// * `host_addr` - Start host virtual address of the range.
+/// * `len` - Length of the range.
+/// * `file_backed` - If the memory is backed by a shared file.
+fn discard_memory(host_addr: u64, len: u64, file_backed: bool) -> Result<()> {
+    // MADV_DONTNEED only drops the mapping of shared memory, while MADV_REMOVE
+    // frees the backing store as well.
+    let advice = if file_backed {
+        libc::MADV_REMOVE
+    } else {
+        libc::MADV_DONTNEED
+    };
+    // Safe, because the range is within the memory region mapped by virtio-mem device.
+    let ret = unsafe { libc::madvise(host_addr as *mut libc::c_void, len as libc::size_t, advice) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).chain_err(|| {
+            format!(
+                "Failed to discard memory 0x{:x}, length 0x{:x}",
+                host_addr, len
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Plugged state of the memory blocks of virtio-mem device.
+struct MemBlocks {
+    /// Guest physical address of the memory region.
+    addr: u64,
+    /// Host virtual address of the memory region.
+    host_addr: u64,
+    /// If the memory region is backed by a shared file.
+    file_backed: bool,
+    /// Size of each block.
+    block_size: u64,
+    /// Number of blocks in the memory region.
+    nr_blocks: u64,
+    /// The bit is set if the block is plugged.
+    bitmap: Bitmap<u64>,
+    /// Size of plugged memory.
+    plugged_size: u64,
+    /// Size of memory that guest is requested to plug.
+    requested_size: u64,
+}
+
+impl MemBlocks {
+    fn new(mapping: &HostMemMapping, block_size: u64, requested_size: u64) -> Self {
+        let nr_blocks = mapping.size() / block_size;
+        MemBlocks {
+            addr: mapping.start_address().raw_value(),
+            host_addr: mapping.host_address(),
+            file_backed: mapping.file_backend().is_some(),
+            block_size,
+            nr_blocks,
+            bitmap: Bitmap::<u64>::new((nr_blocks / BITS_OF_TYPE_U64) as usize + 1),
+            plugged_size: 0,
+            requested_size,
+        }
+    }
+
+    /// Get the index of the first block in the range, return `None` if the
+    /// range is not aligned with blocks or out of the memory region.
+    fn first_block(&self, addr: u64, nb_blocks: u16) -> Option<u64> {
+        if nb_blocks == 0 || addr < self.addr || (addr - self.addr) % self.block_size != 0 {
+            return None;
+        }
+        let first = (addr - self.addr) / self.block_size;
+        if first + nb_blocks as u64 > self.nr_blocks {
+            return None;
+        }
+        Some(first)
+    }
+
+    fn is_plugged(&self, block: u64) -> bool {
+        self.bitmap.contain(block as usize).unwrap_or(false)
+    }
+
+    /// Count the plugged blocks in the range.
+    fn count_plugged(&self, first: u64, nb_blocks: u16) -> u64 {
+        (first..first + nb_blocks as u64)
+            .filter(|block| self.is_plugged(*block))
+            .count() as u64
+    }
+
+    fn plug(&mut self, first: u64, nb_blocks: u16) -> Result<()> {
+        for block in first..first + nb_blocks as u64 {
+            self.bitmap.set(block as usize)?;
+        }
+        self.plugged_size += nb_blocks as u64 * self.block_size;
+        Ok(())
+    }
+
+    fn unplug(&mut self, first: u64, nb_blocks: u16) -> Result<()> {
+        discard_memory(
+            self.host_addr + first * self.block_size,
+            nb_blocks as u64 * self.block_size,
+            self.file_backed,
+        )?;
+        for block in first..first + nb_blocks as u64 {
+            self.bitmap.clear(block as usize)?;
+        }
+        self.plugged_size -= nb_blocks as u64 * self.block_size;
+        Ok(())
+    }
+
+    fn unplug_all(&mut self) -> Result<()> {
+        if self.plugged_size != 0 {
+            discard_memory(
+                self.host_addr,
+                self.nr_blocks * self.block_size,
+                self.file_backed,
+            )?;
+            self.bitmap = Bitmap::<u64>::new((self.nr_blocks / BITS_OF_TYPE_U64) as usize + 1);
+            self.plugged_size = 0;
+        }
+        Ok(())
+    }
+
+    /// Handle the request from guest and return the response.
+    fn handle_request(&mut self, req: &VirtioMemReq) -> VirtioMemResp {
+        let ret = match req.req_type {
+            VIRTIO_MEM_REQ_UNPLUG_ALL => self
+                .unplug_all()
+                .map(|_| VirtioMemResp::new(VIRTIO_MEM_RESP_ACK)),
+            VIRTIO_MEM_REQ_PLUG | VIRTIO_MEM_REQ_UNPLUG | VIRTIO_MEM_REQ_STATE => {
+                let first = match self.first_block(req.addr, req.nb_blocks) {
+                    Some(first) => first,
+                    None => {
+                        error!(
+                            "Invalid range of virtio-mem request: addr 0x{:x}, blocks {}",
+                            req.addr, req.nb_blocks
+                        );
+                        return VirtioMemResp::new(VIRTIO_MEM_RESP_ERROR);
+                    }
+                };
+                self.handle_range_request(req.req_type, first, req.nb_blocks)
+            }
+            req_type => {
+                error!("Unknown type {} of virtio-mem request", req_type);
+                return VirtioMemResp::new(VIRTIO_MEM_RESP_ERROR);
+            }
+        };
+
+        match ret {
+            Ok(resp) => resp,
+            Err(ref e) => {
+                error!(
+                    "Failed to handle virtio-mem request: {}",
+                    error_chain::ChainedError::display_chain(e)
+                );
+                VirtioMemResp::new(VIRTIO_MEM_RESP_ERROR)
+            }
+        }
+    }
+
+    fn handle_range_request(
+        &mut self,
+        req_type: u16,
+        first: u64,
+        nb_blocks: u16,
+    ) -> Result<VirtioMemResp> {
+        let plugged = self.count_plugged(first, nb_blocks);
+        let resp = match req_type {
+            VIRTIO_MEM_REQ_PLUG => {
+                if plugged != 0 {
+                    VirtioMemResp::new(VIRTIO_MEM_RESP_ERROR)
+                } else if self.plugged_size + nb_blocks as u64 * self.block_size
+                    > self.requested_size
+                {
+                    VirtioMemResp::new(VIRTIO_MEM_RESP_NACK)
+                } else {
+                    self.plug(first, nb_blocks)?;
+                    VirtioMemResp::new(VIRTIO_MEM_RESP_ACK)
+                }
+            }
+            VIRTIO_MEM_REQ_UNPLUG => {
+                if plugged != nb_blocks as u64 {
+                    VirtioMemResp::new(VIRTIO_MEM_RESP_ERROR)
+                } else {
+                    self.unplug(first, nb_blocks)?;
+                    VirtioMemResp::new(VIRTIO_MEM_RESP_ACK)
+                }
+            }
+            _ => {
+                let mut resp = VirtioMemResp::new(VIRTIO_MEM_RESP_ACK);
+                resp.state = if plugged == 0 {
+                    VIRTIO_MEM_STATE_UNPLUGGED
+                } else if plugged == nb_blocks as u64 {
+                    VIRTIO_MEM_STATE_PLUGGED
+                } else {
+                    VIRTIO_MEM_STATE_MIXED
+                };
+                resp
+            }
+        };
+        Ok(resp)
+    }
+}
+
+struct VirtioMemHandler {
+    /// Id of virtio-mem device.
+    id: String,
+    queue: Arc<Mutex<Queue>>,
+    queue_evt: EventFd,
+    reset_evt: RawFd,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+    mem_space: Arc<AddressSpace>,
+    blocks: Arc<Mutex<MemBlocks>>,
+}
+
+impl VirtioMemHandler {
+    fn process_queue(&mut self) -> Result<()> {
+        let mut queue_lock = self.queue.lock().unwrap();
+        let mut locked_blocks = self.blocks.lock().unwrap();
+        let old_plugged_size = locked_blocks.plugged_size;
+        let mut need_interrupt = false;
+
+        while let Ok(elem) = queue_lock
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            let req = match elem.out_iovec.get(0) {
+                Some(iov) if iov.len as usize >= size_of::<VirtioMemReq>() => self
+                    .mem_space
+                    .read_object::<VirtioMemReq>(iov.addr)
+                    .chain_err(|| "Failed to read request for virtio-mem")?,
+                _ => bail!("Invalid request for virtio-mem, elem index {}", elem.index),
+            };
+            let resp = locked_blocks.handle_request(&req);
+            match elem.in_iovec.get(0) {
+                Some(iov) if iov.len as usize >= size_of::<VirtioMemResp>() => self
+                    .mem_space
+                    .write_object(&resp, iov.addr)
+                    .chain_err(|| "Failed to write response for virtio-mem")?,
+                _ => bail!("Invalid response for virtio-mem, elem index {}", elem.index),
+            }
+
+            queue_lock
+                .vring
+                .add_used(
+                    &self.mem_space,
+                    elem.index,
+                    size_of::<VirtioMemResp>() as u32,
+                )
+                .chain_err(|| format!("Failed to add used ring, index: {}", elem.index))?;
+            need_interrupt = true;
+        }
+
+        if need_interrupt {
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock))
+                .chain_err(|| ErrorKind::InterruptTrigger("mem", VirtioInterruptType::Vring))?;
+        }
+
+        if locked_blocks.plugged_size != old_plugged_size {
+            let msg = MemoryDeviceSizeChange {
+                id: self.id.clone(),
+                size: locked_blocks.plugged_size,
+            };
+            event!(MemoryDeviceSizeChange; msg);
+        }
+
+        Ok(())
+    }
+
+    fn reset_evt_handler(&self) -> Vec<EventNotifier> {
+        vec![
+            EventNotifier::new(
+                NotifierOperation::Delete,
+                self.reset_evt,
+                None,
+                EventSet::IN,
+                Vec::new(),
+            ),
+            EventNotifier::new(
+                NotifierOperation::Delete,
+                self.queue_evt.as_raw_fd(),
+                None,
+                EventSet::IN,
+                Vec::new(),
+            ),
+        ]
+    }
+}
+
+impl EventNotifierHelper for VirtioMemHandler {
+    fn internal_notifiers(mem_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+
+        // Register event notifier for queue_evt
+        let mem_handler_clone = mem_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+
+            if let Err(ref e) = mem_handler_clone.lock().unwrap().process_queue() {
+                error!(
+                    "Failed to process queue for virtio-mem, err: {}",
+                    error_chain::ChainedError::display_chain(e),
+                );
+            }
+
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            mem_handler.lock().unwrap().queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        ));
+
+        // Register event notifier for reset_evt
+        let mem_handler_clone = mem_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            Some(mem_handler_clone.lock().unwrap().reset_evt_handler())
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            mem_handler.lock().unwrap().reset_evt,
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        ));
+
+        notifiers
+    }
+}
+
+/// Virtio-mem device, which exposes a hotpluggable memory region to guest,
+/// guest plugs and unplugs the memory in the region by blocks.
+pub struct VirtioMem {
+    /// Configuration of virtio-mem device.
+    mem_cfg: VirtioMemConfig,
+    /// Bitmask of features supported by the backend.
+    device_features: u64,
+    /// Bitmask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+    /// Host mapping of the memory region.
+    mapping: Arc<HostMemMapping>,
+    /// Plugged state of the memory blocks, shared with the request handler.
+    blocks: Arc<Mutex<MemBlocks>>,
+    /// Interrupt callback function.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Eventfd for device reset.
+    reset_evt: EventFd,
+    /// If the memory region has been added to guest address space.
+    region_mapped: bool,
+}
+
+impl VirtioMem {
+    /// Create a virtio-mem device.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_cfg` - Configuration of virtio-mem device.
+    /// * `mem_space` - System address space.
+    /// * `mapping` - Host mapping of the memory region, whose start address is
+    ///   the guest physical address of the region.
+    pub fn new(
+        mem_cfg: &VirtioMemConfig,
+        mem_space: &Arc<AddressSpace>,
+        mapping: Arc<HostMemMapping>,
+    ) -> Self {
+        let blocks = MemBlocks::new(&mapping, mem_cfg.block_size, mem_cfg.requested_size);
+        VirtioMem {
+            mem_cfg: mem_cfg.clone(),
+            device_features: 0_u64,
+            driver_features: 0_u64,
+            mem_space: mem_space.clone(),
+            mapping,
+            blocks: Arc::new(Mutex::new(blocks)),
+            interrupt_cb: None,
+            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            region_mapped: false,
+        }
+    }
+
+    /// Init virtio-mem object for global use.
+    ///
+    /// The plugged state of memory blocks isn't saved, so migration and snapshot
+    /// are blocked while virtio-mem device exists.
+    pub fn object_init(dev: Arc<Mutex<VirtioMem>>) {
+        MigrationManager::register_blocker(
+            &dev.lock().unwrap().mem_cfg.id,
+            "Migration of virtio-mem device is not supported",
+            true,
+        );
+        VIRTIO_MEM_DEVS.lock().unwrap().push(dev);
+    }
+
+    /// Add the memory region to guest address space. The region is added when the
+    /// device is activated rather than realized, so that it is not taken as boot
+    /// memory when loading the kernel and building the memory map for guest.
+    fn map_region(&mut self) -> Result<()> {
+        if self.region_mapped {
+            return Ok(());
+        }
+        let addr = self.mapping.start_address().raw_value();
+        self.mem_space
+            .root()
+            .add_subregion(Region::init_ram_region(self.mapping.clone()), addr)
+            .chain_err(|| "Failed to add memory region of virtio-mem to guest address space")?;
+        self.region_mapped = true;
+        Ok(())
+    }
+
+    fn config_space(&self) -> VirtioMemConfigSpace {
+        let locked_blocks = self.blocks.lock().unwrap();
+        VirtioMemConfigSpace {
+            block_size: locked_blocks.block_size,
+            addr: locked_blocks.addr,
+            region_size: self.mapping.size(),
+            usable_region_size: self.mapping.size(),
+            plugged_size: locked_blocks.plugged_size,
+            requested_size: locked_blocks.requested_size,
+            ..Default::default()
+        }
+    }
+
+    /// Set the size of memory which guest is requested to plug, guest is
+    /// notified if the device is activated.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Requested memory size.
+    pub fn set_requested_size(&mut self, size: u64) -> Result<()> {
+        if size % self.mem_cfg.block_size != 0 || size > self.mapping.size() {
+            bail!(
+                "Requested size 0x{:x} should be aligned with block size 0x{:x} and not bigger than region size 0x{:x}",
+                size,
+                self.mem_cfg.block_size,
+                self.mapping.size()
+            );
+        }
+        self.blocks.lock().unwrap().requested_size = size;
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(&VirtioInterruptType::Config, None)
+                .chain_err(|| ErrorKind::InterruptTrigger("mem", VirtioInterruptType::Config))?;
+        }
+        Ok(())
+    }
+
+    /// Get the information of memory region and plugged size.
+    pub fn get_info(&self) -> VirtioMemInfo {
+        let config = self.config_space();
+        VirtioMemInfo {
+            id: self.mem_cfg.id.clone(),
+            addr: config.addr,
+            region_size: config.region_size,
+            block_size: config.block_size,
+            plugged_size: config.plugged_size,
+            requested_size: config.requested_size,
+        }
+    }
+}
+
+impl VirtioDevice for VirtioMem {
+    /// Realize virtio-mem device.
+    fn realize(&mut self) -> Result<()> {
+        let page_size = self
+            .mapping
+            .file_backend()
+            .map_or_else(host_page_size, |fb| fb.page_size);
+        if self.mem_cfg.block_size < page_size {
+            bail!(
+                "Block size 0x{:x} of virtio-mem is smaller than page size 0x{:x}",
+                self.mem_cfg.block_size,
+                page_size
+            );
+        }
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_F_RING_PACKED;
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_MEM
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_MEM
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_MEM
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature: {:x}", v);
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config = self.config_space();
+        let config_slice = config.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, offset: u64, _data: &[u8]) -> Result<()> {
+        bail!(
+            "Writing device config space for virtio-mem is not supported, offset: {}",
+            offset
+        );
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        if queues.len() != QUEUE_NUM_MEM {
+            return Err(ErrorKind::IncorrectQueueNum(QUEUE_NUM_MEM, queues.len()).into());
+        }
+
+        self.map_region()?;
+        self.interrupt_cb = Some(interrupt_cb.clone());
+        let handler = VirtioMemHandler {
+            id: self.mem_cfg.id.clone(),
+            queue: queues[0].clone(),
+            queue_evt: queue_evts.remove(0),
+            reset_evt: self.reset_evt.as_raw_fd(),
+            interrupt_cb,
+            driver_features: self.driver_features,
+            mem_space,
+            blocks: self.blocks.clone(),
+        };
+
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
+            None,
+        )
+        .chain_err(|| "Failed to register virtio-mem event notifier to MainLoop")?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.reset_evt
+            .write(1)
+            .chain_err(|| ErrorKind::EventFdWrite)
+    }
+}
+
+pub fn qmp_virtio_mem_resize(id: &str, requested_size: u64) -> Result<()> {
+    for dev in VIRTIO_MEM_DEVS.lock().unwrap().iter() {
+        let mut locked_dev = dev.lock().unwrap();
+        if locked_dev.mem_cfg.id == id {
+            return locked_dev.set_requested_size(requested_size);
+        }
+    }
+    bail!("Virtio-mem device {} not found", id)
+}
+
+pub fn qmp_query_virtio_mem() -> Vec<VirtioMemInfo> {
+    VIRTIO_MEM_DEVS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|dev| dev.lock().unwrap().get_info())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+
+    use address_space::GuestAddress;
+
+    const MEMORY_SIZE: u64 = 1024 * 1024;
+    const MEM_REGION_BASE: u64 = 0x4000_0000;
+    const MEM_REGION_SIZE: u64 = 16 * BLOCK_SIZE;
+    const BLOCK_SIZE: u64 = 0x20_0000;
+    const VIRTQ_DESC_F_NEXT: u16 = 0x01;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x02;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), MEMORY_SIZE, None, false, false, false).unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn create_virtio_mem(mem_space: &Arc<AddressSpace>, requested_size: u64) -> VirtioMem {
+        let mem_cfg = VirtioMemConfig {
+            id: "mem0".to_string(),
+            size: MEM_REGION_SIZE,
+            requested_size,
+            block_size: BLOCK_SIZE,
+        };
+        let mapping = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(MEM_REGION_BASE),
+                MEM_REGION_SIZE,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        VirtioMem::new(&mem_cfg, mem_space, mapping)
+    }
+
+    fn range_req(req_type: u16, block: u64, nb_blocks: u16) -> VirtioMemReq {
+        VirtioMemReq {
+            req_type,
+            addr: MEM_REGION_BASE + block * BLOCK_SIZE,
+            nb_blocks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_virtio_mem_init() {
+        let mem_space = address_space_init();
+        let mut mem = create_virtio_mem(&mem_space, 0);
+        assert_eq!(mem.device_type(), VIRTIO_TYPE_MEM);
+        assert_eq!(mem.queue_num(), QUEUE_NUM_MEM);
+        assert_eq!(mem.queue_size(), QUEUE_SIZE_MEM);
+        assert_eq!(size_of::<VirtioMemConfigSpace>(), 56);
+        assert_eq!(size_of::<VirtioMemReq>(), 24);
+        assert_eq!(size_of::<VirtioMemResp>(), 10);
+
+        assert!(mem.realize().is_ok());
+        assert_eq!(
+            mem.get_device_features(1),
+            1_u32 << (VIRTIO_F_VERSION_1 - 32) | 1_u32 << (VIRTIO_F_RING_PACKED - 32)
+        );
+        // The memory region is not added to guest address space until activated.
+        assert!(!mem_space.address_in_memory(GuestAddress(MEM_REGION_BASE), MEM_REGION_SIZE));
+        assert!(mem.map_region().is_ok());
+        assert!(mem.map_region().is_ok());
+        assert!(mem_space.address_in_memory(GuestAddress(MEM_REGION_BASE), MEM_REGION_SIZE));
+
+        let mut data = [0_u8; 8];
+        assert!(mem.read_config(16, &mut data).is_ok());
+        assert_eq!(u64::from_le_bytes(data), MEM_REGION_BASE);
+        assert!(mem.read_config(56, &mut data).is_err());
+        assert!(mem.write_config(0, &data).is_err());
+    }
+
+    #[test]
+    fn test_virtio_mem_set_requested_size() {
+        let mem_space = address_space_init();
+        let mut mem = create_virtio_mem(&mem_space, 0);
+        assert!(mem.set_requested_size(4 * BLOCK_SIZE).is_ok());
+        // Unaligned with block size.
+        assert!(mem.set_requested_size(BLOCK_SIZE / 2).is_err());
+        // Bigger than memory region.
+        assert!(mem
+            .set_requested_size(MEM_REGION_SIZE + BLOCK_SIZE)
+            .is_err());
+
+        let info = mem.get_info();
+        assert_eq!(info.id, "mem0");
+        assert_eq!(info.addr, MEM_REGION_BASE);
+        assert_eq!(info.region_size, MEM_REGION_SIZE);
+        assert_eq!(info.block_size, BLOCK_SIZE);
+        assert_eq!(info.plugged_size, 0);
+        assert_eq!(info.requested_size, 4 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_virtio_mem_handle_request() {
+        let mem_space = address_space_init();
+        let mem = create_virtio_mem(&mem_space, 4 * BLOCK_SIZE);
+        let mut blocks = mem.blocks.lock().unwrap();
+
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_PLUG, 0, 2));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.plugged_size, 2 * BLOCK_SIZE);
+        // Plugged blocks can not be plugged again.
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_PLUG, 1, 2));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+        // Plugged size can not exceed the requested size.
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_PLUG, 2, 3));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_NACK);
+        // Out of the memory region.
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_PLUG, 15, 2));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+        // Unaligned with block size.
+        let mut req = range_req(VIRTIO_MEM_REQ_PLUG, 4, 1);
+        req.addr += 0x1000;
+        let resp = blocks.handle_request(&req);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_STATE, 0, 2));
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_PLUGGED);
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_STATE, 1, 2));
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_MIXED);
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_STATE, 2, 2));
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_UNPLUGGED);
+
+        // Only plugged blocks can be unplugged.
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_UNPLUG, 1, 2));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_UNPLUG, 1, 1));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.plugged_size, BLOCK_SIZE);
+
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.plugged_size, 0);
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_STATE, 0, 16));
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_UNPLUGGED);
+
+        let resp = blocks.handle_request(&range_req(4, 0, 1));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+    }
+
+    #[test]
+    fn test_virtio_mem_unplug_discard() {
+        let mem_space = address_space_init();
+        let mut mem = create_virtio_mem(&mem_space, 4 * BLOCK_SIZE);
+        assert!(mem.realize().is_ok());
+        assert!(mem.map_region().is_ok());
+
+        let mut blocks = mem.blocks.lock().unwrap();
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_PLUG, 0, 1));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        let addr = GuestAddress(MEM_REGION_BASE + 0x100);
+        assert!(mem_space.write_object(&0xff_u8, addr).is_ok());
+        assert_eq!(mem_space.read_object::<u8>(addr).unwrap(), 0xff);
+
+        // Unplugged memory is released and reads zero.
+        let resp = blocks.handle_request(&range_req(VIRTIO_MEM_REQ_UNPLUG, 0, 1));
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(mem_space.read_object::<u8>(addr).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_virtio_mem_process_queue() {
+        QmpChannel::object_init();
+        let mem_space = address_space_init();
+        let mem = create_virtio_mem(&mem_space, 4 * BLOCK_SIZE);
+        let interrupt_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let cloned_interrupt_evt = interrupt_evt.try_clone().unwrap();
+        let interrupt_cb = Arc::new(Box::new(
+            move |_int_type: &VirtioInterruptType, _queue: Option<&Queue>| {
+                interrupt_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)
+            },
+        ) as VirtioInterrupt);
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE_MEM);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.avail_ring = GuestAddress(16 * QUEUE_SIZE_MEM as u64);
+        queue_config.used_ring = GuestAddress(32 * QUEUE_SIZE_MEM as u64);
+        queue_config.size = QUEUE_SIZE_MEM;
+        queue_config.ready = true;
+
+        let reset_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut handler = VirtioMemHandler {
+            id: "mem0".to_string(),
+            queue: Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())),
+            queue_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            reset_evt: reset_event.as_raw_fd(),
+            interrupt_cb,
+            driver_features: 0_u64,
+            mem_space: mem_space.clone(),
+            blocks: mem.blocks.clone(),
+        };
+
+        let req_addr = GuestAddress(0x40000);
+        let resp_addr = GuestAddress(0x50000);
+        let desc = SplitVringDesc {
+            addr: req_addr,
+            len: size_of::<VirtioMemReq>() as u32,
+            flags: VIRTQ_DESC_F_NEXT,
+            next: 1,
+        };
+        mem_space
+            .write_object(&desc, queue_config.desc_table)
+            .unwrap();
+        let desc = SplitVringDesc {
+            addr: resp_addr,
+            len: size_of::<VirtioMemResp>() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        mem_space
+            .write_object(
+                &desc,
+                GuestAddress(queue_config.desc_table.0 + size_of::<SplitVringDesc>() as u64),
+            )
+            .unwrap();
+        mem_space
+            .write_object(&range_req(VIRTIO_MEM_REQ_PLUG, 0, 4), req_addr)
+            .unwrap();
+        // write avail_ring idx
+        mem_space
+            .write_object::<u16>(&0, GuestAddress(queue_config.avail_ring.0 + 4 as u64))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(queue_config.avail_ring.0 + 2 as u64))
+            .unwrap();
+
+        assert!(handler.process_queue().is_ok());
+        let resp = mem_space.read_object::<VirtioMemResp>(resp_addr).unwrap();
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(mem.get_info().plugged_size, 4 * BLOCK_SIZE);
+
+        let idx = mem_space
+            .read_object::<u16>(GuestAddress(queue_config.used_ring.0 + 2 as u64))
+            .unwrap();
+        assert_eq!(idx, 1);
+        assert_eq!(cloned_interrupt_evt.read().unwrap(), 1);
+    }
+}
@@ -12,6 +12,7 @@
 
 use std::io::Write;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem};
@@ -32,18 +33,37 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioNetHdr, VIRTIO_F_VERSION_1,
-    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_TYPE_NET,
+    virtio_has_feature, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioNetHdr,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_ANNOUNCE,
+    VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET,
+    VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI,
+    VIRTIO_NET_CTRL_RX_ALLUNI, VIRTIO_NET_CTRL_RX_NOBCAST, VIRTIO_NET_CTRL_RX_NOMULTI,
+    VIRTIO_NET_CTRL_RX_NOUNI, VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN,
+    VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
+    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_RX_EXTRA,
+    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_ANNOUNCE, VIRTIO_NET_S_LINK_UP,
+    VIRTIO_TYPE_NET,
 };
 
-/// Number of virtqueues.
-const QUEUE_NUM_NET: usize = 2;
 /// Size of each virtqueue.
 const QUEUE_SIZE_NET: u16 = 256;
 /// The maximum buffer size when segmentation offload is enabled.
 /// This includes a 12-byte virtio net header, refer to Virtio Spec.
 const FRAME_BUF_SIZE: usize = 65562;
+/// The maximum size of a control command, including the 2-byte class and command header.
+const CTRL_BUF_SIZE: usize = 4096;
+/// Length of MAC address.
+const MAC_ADDR_LEN: usize = 6;
+/// Number of MAC addresses in the MAC filter table.
+const MAC_TABLE_LEN: usize = 64;
+/// The maximum VLAN id.
+const MAX_VLAN: usize = 4096;
+/// Ethernet type of the frame with a 802.1Q VLAN tag.
+const ETH_P_8021Q: u16 = 0x8100;
 
 type SenderConfig = Option<Tap>;
 
@@ -68,6 +88,167 @@ pub struct VirtioNetConfig {
 
 impl ByteCode for VirtioNetConfig {}
 
+/// Receive filter of virtio-net devices, which is set by the guest with control queue.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct RxFilter {
+    /// Bit mask of RX modes, the bit index is the command of `VIRTIO_NET_CTRL_RX` class.
+    rx_mode: u32,
+    /// Number of unicast addresses in the MAC table.
+    uni_num: u32,
+    /// Number of multicast addresses in the MAC table.
+    multi_num: u32,
+    /// The unicast addresses given by guest overflow the MAC table.
+    uni_overflow: u8,
+    /// The multicast addresses given by guest overflow the MAC table.
+    multi_overflow: u8,
+    /// MAC table, the unicast addresses are followed by the multicast addresses.
+    mac_table: [[u8; MAC_ADDR_LEN]; MAC_TABLE_LEN],
+    /// Bitmap of the VLAN ids which are allowed.
+    vlans: [u32; MAX_VLAN / 32],
+}
+
+impl Default for RxFilter {
+    /// All frames are received until the guest sets the filter.
+    fn default() -> Self {
+        RxFilter {
+            rx_mode: 1 << VIRTIO_NET_CTRL_RX_PROMISC,
+            uni_num: 0,
+            multi_num: 0,
+            uni_overflow: 0,
+            multi_overflow: 0,
+            mac_table: [[0_u8; MAC_ADDR_LEN]; MAC_TABLE_LEN],
+            vlans: [0_u32; MAX_VLAN / 32],
+        }
+    }
+}
+
+impl ByteCode for RxFilter {}
+
+impl RxFilter {
+    fn rx_mode_on(&self, mode: u8) -> bool {
+        self.rx_mode & (1 << mode) != 0
+    }
+
+    fn set_rx_mode(&mut self, mode: u8, on: bool) {
+        if on {
+            self.rx_mode |= 1 << mode;
+        } else {
+            self.rx_mode &= !(1 << mode);
+        }
+    }
+
+    /// Set the MAC table with the data of `VIRTIO_NET_CTRL_MAC_TABLE_SET` command, which
+    /// contains the unicast addresses followed by the multicast addresses, both are
+    /// led by a 32-bit number of entries.
+    fn set_mac_table(&mut self, data: &[u8]) -> Result<()> {
+        let mut mac_table = [[0_u8; MAC_ADDR_LEN]; MAC_TABLE_LEN];
+        let mut offset = 0;
+        let mut mac_num = [0_u32; 2];
+        let mut overflow = [0_u8; 2];
+        let mut used = 0;
+        for index in 0..mac_num.len() {
+            if data.len() < offset + mem::size_of::<u32>() {
+                bail!("Invalid length {} of MAC table", data.len());
+            }
+            let mut entries = [0_u8; 4];
+            entries.copy_from_slice(&data[offset..offset + mem::size_of::<u32>()]);
+            let entries = u32::from_le_bytes(entries) as usize;
+            offset += mem::size_of::<u32>();
+
+            let len = entries
+                .checked_mul(MAC_ADDR_LEN)
+                .filter(|len| data.len() - offset >= *len);
+            let len = match len {
+                Some(len) => len,
+                None => bail!("Invalid number {} of MAC table entries", entries),
+            };
+
+            if used + entries > MAC_TABLE_LEN {
+                overflow[index] = 1;
+            } else {
+                for (entry, mac) in data[offset..offset + len].chunks(MAC_ADDR_LEN).enumerate() {
+                    mac_table[used + entry].copy_from_slice(mac);
+                }
+                mac_num[index] = entries as u32;
+                used += entries;
+            }
+            offset += len;
+        }
+
+        self.mac_table = mac_table;
+        self.uni_num = mac_num[0];
+        self.multi_num = mac_num[1];
+        self.uni_overflow = overflow[0];
+        self.multi_overflow = overflow[1];
+
+        Ok(())
+    }
+
+    fn set_vlan(&mut self, vid: u16, allowed: bool) {
+        let (index, bit) = (vid as usize / 32, vid as u32 % 32);
+        if allowed {
+            self.vlans[index] |= 1 << bit;
+        } else {
+            self.vlans[index] &= !(1 << bit);
+        }
+    }
+
+    fn vlan_allowed(&self, vid: u16) -> bool {
+        self.vlans[vid as usize / 32] & (1 << (vid as u32 % 32)) != 0
+    }
+
+    /// Check whether the ethernet frame can be passed to the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - Ethernet frame without the virtio net header.
+    /// * `mac` - MAC address of the device, unicast frames are not filtered by it
+    ///   if it is unknown.
+    /// * `vlan_filter` - Whether the frames are filtered by VLAN id.
+    fn is_allowed(&self, frame: &[u8], mac: &[u8; MAC_ADDR_LEN], vlan_filter: bool) -> bool {
+        if self.rx_mode_on(VIRTIO_NET_CTRL_RX_PROMISC) {
+            return true;
+        }
+        if frame.len() < 2 * MAC_ADDR_LEN + mem::size_of::<u16>() {
+            return false;
+        }
+
+        let eth_type = u16::from_be_bytes([frame[12], frame[13]]);
+        if vlan_filter && eth_type == ETH_P_8021Q && frame.len() >= 16 {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & 0xfff;
+            if !self.vlan_allowed(vid) {
+                return false;
+            }
+        }
+
+        let dest = &frame[..MAC_ADDR_LEN];
+        let uni_num = self.uni_num as usize;
+        let multi_num = self.multi_num as usize;
+        if dest.iter().all(|b| *b == 0xff) {
+            !self.rx_mode_on(VIRTIO_NET_CTRL_RX_NOBCAST)
+        } else if dest[0] & 0x01 != 0 {
+            if self.rx_mode_on(VIRTIO_NET_CTRL_RX_NOMULTI) {
+                return false;
+            }
+            self.rx_mode_on(VIRTIO_NET_CTRL_RX_ALLMULTI)
+                || self.multi_overflow != 0
+                || self.mac_table[uni_num..uni_num + multi_num]
+                    .iter()
+                    .any(|m| m == dest)
+        } else {
+            if self.rx_mode_on(VIRTIO_NET_CTRL_RX_NOUNI) {
+                return false;
+            }
+            self.rx_mode_on(VIRTIO_NET_CTRL_RX_ALLUNI)
+                || self.uni_overflow != 0
+                || mac.iter().all(|b| *b == 0)
+                || mac == dest
+                || self.mac_table[..uni_num].iter().any(|m| m == dest)
+        }
+    }
+}
+
 struct TxVirtio {
     queue: Arc<Mutex<Queue>>,
     queue_evt: EventFd,
@@ -106,17 +287,345 @@ impl RxVirtio {
     }
 }
 
+struct CtrlVirtio {
+    queue: Arc<Mutex<Queue>>,
+    queue_evt: EventFd,
+}
+
+impl CtrlVirtio {
+    fn new(queue: Arc<Mutex<Queue>>, queue_evt: EventFd) -> Self {
+        CtrlVirtio { queue, queue_evt }
+    }
+}
+
+/// Handler of the control virtqueue.
+struct NetCtrlHandler {
+    ctrl: CtrlVirtio,
+    /// Tap devices of all queue pairs, used to attach or detach the tap queues.
+    taps: Vec<Tap>,
+    /// Number of queue pairs in use.
+    curr_queue_pairs: u16,
+    /// The status of net device, which contains the receive filter and MAC address.
+    state: Arc<Mutex<VirtioNetState>>,
+    /// Generation of the receive filter, bumped after it's changed.
+    rx_filter_gen: Arc<AtomicU64>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+    reset_evt: RawFd,
+}
+
+impl NetCtrlHandler {
+    fn handle_ctrl(&mut self) -> Result<()> {
+        let mut need_irq = false;
+
+        loop {
+            let elem = match self
+                .ctrl
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+            {
+                Ok(elem) => elem,
+                Err(_) => break,
+            };
+
+            let mut ctrl_buf = Vec::new();
+            for elem_iov in elem.out_iovec.iter() {
+                if ctrl_buf.len() + elem_iov.len as usize > CTRL_BUF_SIZE {
+                    bail!("Net ctrl: the size of command exceeds {}", CTRL_BUF_SIZE);
+                }
+                self.mem_space
+                    .read(&mut ctrl_buf, elem_iov.addr, elem_iov.len as u64)
+                    .chain_err(|| "Net ctrl: Failed to read command")?;
+            }
+
+            let ack = if ctrl_buf.len() < 2 {
+                error!("Net ctrl: invalid command length {}", ctrl_buf.len());
+                VIRTIO_NET_ERR
+            } else {
+                let (class, cmd) = (ctrl_buf[0], ctrl_buf[1]);
+                let data = &ctrl_buf[2..];
+                match class {
+                    VIRTIO_NET_CTRL_RX => self.handle_ctrl_rx(cmd, data),
+                    VIRTIO_NET_CTRL_MAC => self.handle_ctrl_mac(cmd, data),
+                    VIRTIO_NET_CTRL_VLAN => self.handle_ctrl_vlan(cmd, data),
+                    VIRTIO_NET_CTRL_ANNOUNCE => self.handle_ctrl_announce(cmd),
+                    VIRTIO_NET_CTRL_MQ => self.handle_ctrl_mq(cmd, data),
+                    _ => {
+                        warn!("Net ctrl: unsupported command class {}", class);
+                        VIRTIO_NET_ERR
+                    }
+                }
+            };
+            // Let the rx handlers reload the receive filter.
+            self.rx_filter_gen.fetch_add(1, Ordering::Release);
+
+            let status = match elem.in_iovec.last() {
+                Some(status) => status,
+                None => bail!("Net ctrl: missing status for command"),
+            };
+            self.mem_space
+                .write_object(&ack, status.addr)
+                .chain_err(|| "Net ctrl: Failed to write status")?;
+
+            self.ctrl
+                .queue
+                .lock()
+                .unwrap()
+                .vring
+                .add_used(&self.mem_space, elem.index, mem::size_of::<u8>() as u32)
+                .chain_err(|| format!("Net ctrl: Failed to add used ring {}", elem.index))?;
+            need_irq = true;
+        }
+
+        if need_irq {
+            (self.interrupt_cb)(
+                &VirtioInterruptType::Vring,
+                Some(&self.ctrl.queue.lock().unwrap()),
+            )
+            .chain_err(|| ErrorKind::InterruptTrigger("net", VirtioInterruptType::Vring))?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_ctrl_rx(&mut self, cmd: u8, data: &[u8]) -> u8 {
+        let feature = match cmd {
+            VIRTIO_NET_CTRL_RX_PROMISC | VIRTIO_NET_CTRL_RX_ALLMULTI => VIRTIO_NET_F_CTRL_RX,
+            VIRTIO_NET_CTRL_RX_ALLUNI
+            | VIRTIO_NET_CTRL_RX_NOMULTI
+            | VIRTIO_NET_CTRL_RX_NOUNI
+            | VIRTIO_NET_CTRL_RX_NOBCAST => VIRTIO_NET_F_CTRL_RX_EXTRA,
+            _ => {
+                error!("Net ctrl: invalid rx mode command {}", cmd);
+                return VIRTIO_NET_ERR;
+            }
+        };
+        if !virtio_has_feature(self.driver_features, feature) || data.is_empty() {
+            error!("Net ctrl: rx mode command {} is not supported", cmd);
+            return VIRTIO_NET_ERR;
+        }
+
+        self.state
+            .lock()
+            .unwrap()
+            .rx_filter
+            .set_rx_mode(cmd, data[0] != 0);
+
+        VIRTIO_NET_OK
+    }
+
+    fn handle_ctrl_mac(&mut self, cmd: u8, data: &[u8]) -> u8 {
+        let mut locked_state = self.state.lock().unwrap();
+        match cmd {
+            VIRTIO_NET_CTRL_MAC_TABLE_SET
+                if virtio_has_feature(self.driver_features, VIRTIO_NET_F_CTRL_RX) =>
+            {
+                if let Err(ref e) = locked_state.rx_filter.set_mac_table(data) {
+                    error!(
+                        "Failed to set MAC table for net, {}",
+                        error_chain::ChainedError::display_chain(e)
+                    );
+                    return VIRTIO_NET_ERR;
+                }
+            }
+            VIRTIO_NET_CTRL_MAC_ADDR_SET
+                if virtio_has_feature(self.driver_features, VIRTIO_NET_F_CTRL_MAC_ADDR)
+                    && data.len() >= MAC_ADDR_LEN =>
+            {
+                locked_state
+                    .config_space
+                    .mac
+                    .copy_from_slice(&data[..MAC_ADDR_LEN]);
+            }
+            _ => {
+                error!("Net ctrl: invalid MAC command {}", cmd);
+                return VIRTIO_NET_ERR;
+            }
+        }
+
+        VIRTIO_NET_OK
+    }
+
+    fn handle_ctrl_vlan(&mut self, cmd: u8, data: &[u8]) -> u8 {
+        if !virtio_has_feature(self.driver_features, VIRTIO_NET_F_CTRL_VLAN)
+            || data.len() < mem::size_of::<u16>()
+        {
+            error!("Net ctrl: invalid VLAN command {}", cmd);
+            return VIRTIO_NET_ERR;
+        }
+
+        let vid = u16::from_le_bytes([data[0], data[1]]);
+        if vid as usize >= MAX_VLAN {
+            error!("Net ctrl: invalid VLAN id {}", vid);
+            return VIRTIO_NET_ERR;
+        }
+
+        let mut locked_state = self.state.lock().unwrap();
+        match cmd {
+            VIRTIO_NET_CTRL_VLAN_ADD => locked_state.rx_filter.set_vlan(vid, true),
+            VIRTIO_NET_CTRL_VLAN_DEL => locked_state.rx_filter.set_vlan(vid, false),
+            _ => {
+                error!("Net ctrl: invalid VLAN command {}", cmd);
+                return VIRTIO_NET_ERR;
+            }
+        }
+
+        VIRTIO_NET_OK
+    }
+
+    fn handle_ctrl_announce(&mut self, cmd: u8) -> u8 {
+        let mut locked_state = self.state.lock().unwrap();
+        if cmd != VIRTIO_NET_CTRL_ANNOUNCE_ACK
+            || !virtio_has_feature(self.driver_features, VIRTIO_NET_F_GUEST_ANNOUNCE)
+            || locked_state.config_space.status & VIRTIO_NET_S_ANNOUNCE == 0
+        {
+            error!("Net ctrl: invalid announce command {}", cmd);
+            return VIRTIO_NET_ERR;
+        }
+        locked_state.config_space.status &= !VIRTIO_NET_S_ANNOUNCE;
+
+        VIRTIO_NET_OK
+    }
+
+    fn handle_ctrl_mq(&mut self, cmd: u8, data: &[u8]) -> u8 {
+        if cmd != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET || data.len() < mem::size_of::<u16>() {
+            error!("Net ctrl: invalid multiqueue command {}", cmd);
+            return VIRTIO_NET_ERR;
+        }
+
+        let queue_pairs = u16::from_le_bytes([data[0], data[1]]);
+        if !virtio_has_feature(self.driver_features, VIRTIO_NET_F_MQ)
+            || queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN
+            || queue_pairs as usize > self.taps.len()
+        {
+            error!("Net ctrl: invalid number of queue pairs {}", queue_pairs);
+            return VIRTIO_NET_ERR;
+        }
+
+        if let Err(ref e) = self.set_queue_pairs(queue_pairs) {
+            error!(
+                "Failed to set queue pairs for net, {}",
+                error_chain::ChainedError::display_chain(e)
+            );
+            return VIRTIO_NET_ERR;
+        }
+
+        VIRTIO_NET_OK
+    }
+
+    /// Only the tap queues of the queue pairs in use are attached, so that the kernel
+    /// doesn't deliver packets to the queues which the guest doesn't handle.
+    fn set_queue_pairs(&mut self, queue_pairs: u16) -> Result<()> {
+        for (index, tap) in self.taps.iter().enumerate() {
+            let enable = index < queue_pairs as usize;
+            if enable != (index < self.curr_queue_pairs as usize) {
+                tap.set_queue(enable)
+                    .chain_err(|| format!("Failed to set queue {} of tap", index))?;
+            }
+        }
+        self.curr_queue_pairs = queue_pairs;
+
+        Ok(())
+    }
+
+    fn reset_evt_handler(&mut self) -> Vec<EventNotifier> {
+        if let Err(ref e) = self.set_queue_pairs(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN) {
+            error!(
+                "Failed to reset queue pairs for net, {}",
+                error_chain::ChainedError::display_chain(e)
+            );
+        }
+
+        vec![
+            build_event_notifier(
+                self.reset_evt,
+                None,
+                NotifierOperation::Delete,
+                EventSet::IN,
+            ),
+            build_event_notifier(
+                self.ctrl.queue_evt.as_raw_fd(),
+                None,
+                NotifierOperation::Delete,
+                EventSet::IN,
+            ),
+        ]
+    }
+}
+
+impl EventNotifierHelper for NetCtrlHandler {
+    fn internal_notifiers(ctrl_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let locked_ctrl_handler = ctrl_handler.lock().unwrap();
+        let mut notifiers = Vec::new();
+
+        // Register event notifier for reset_evt.
+        let cloned_ctrl_handler = ctrl_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            Some(cloned_ctrl_handler.lock().unwrap().reset_evt_handler())
+        });
+        notifiers.push(build_event_notifier(
+            locked_ctrl_handler.reset_evt,
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN,
+        ));
+
+        // Register event notifier for ctrl.
+        let cloned_ctrl_handler = ctrl_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(ref e) = cloned_ctrl_handler.lock().unwrap().handle_ctrl() {
+                error!(
+                    "Failed to handle ctrl(ctrl event) for net, {}",
+                    error_chain::ChainedError::display_chain(e)
+                );
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            locked_ctrl_handler.ctrl.queue_evt.as_raw_fd(),
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN,
+        ));
+
+        notifiers
+    }
+}
+
+/// Copy of the receive filter and MAC address used by the rx handler, so that
+/// the device state isn't locked for each frame.
+struct RxFilterCache {
+    filter: RxFilter,
+    mac: [u8; MAC_ADDR_LEN],
+    /// Generation of the receive filter when it's copied.
+    gen: u64,
+}
+
 struct NetIoHandler {
     rx: RxVirtio,
     tx: TxVirtio,
     tap: Option<Tap>,
     tap_fd: RawFd,
+    /// The status of net device, which contains the receive filter and MAC address.
+    state: Arc<Mutex<VirtioNetState>>,
+    /// Generation of the receive filter, bumped after it's changed.
+    rx_filter_gen: Arc<AtomicU64>,
+    rx_filter: RxFilterCache,
     mem_space: Arc<AddressSpace>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
     receiver: Receiver<SenderConfig>,
     update_evt: RawFd,
     reset_evt: RawFd,
+    /// Whether the handler is quiesced for live migration, the virtqueues and
+    /// tap aren't processed until it's restarted.
+    quiesced: bool,
 }
 
 impl NetIoHandler {
@@ -185,6 +694,10 @@ impl NetIoHandler {
     }
 
     fn handle_last_frame_rx(&mut self) -> Result<()> {
+        if self.quiesced {
+            return Ok(());
+        }
+
         if self.handle_frame_rx().is_ok() {
             self.rx.unfinished_frame = false;
             self.handle_rx()?;
@@ -200,11 +713,41 @@ impl NetIoHandler {
         Ok(())
     }
 
+    /// Check whether the frame read from tap passes the receive filter set by the guest.
+    fn rx_filter_allowed(&mut self) -> bool {
+        let hdr_len = mem::size_of::<VirtioNetHdr>();
+        if self.rx.bytes_read < hdr_len {
+            return true;
+        }
+
+        let gen = self.rx_filter_gen.load(Ordering::Acquire);
+        if gen != self.rx_filter.gen {
+            let locked_state = self.state.lock().unwrap();
+            self.rx_filter = RxFilterCache {
+                filter: locked_state.rx_filter,
+                mac: locked_state.config_space.mac,
+                gen,
+            };
+        }
+        self.rx_filter.filter.is_allowed(
+            &self.rx.frame_buf[hdr_len..self.rx.bytes_read],
+            &self.rx_filter.mac,
+            virtio_has_feature(self.driver_features, VIRTIO_NET_F_CTRL_VLAN),
+        )
+    }
+
     fn handle_rx(&mut self) -> Result<()> {
+        if self.quiesced {
+            return Ok(());
+        }
+
         while let Some(tap) = self.tap.as_mut() {
             match tap.read(&mut self.rx.frame_buf) {
                 Ok(count) => {
                     self.rx.bytes_read = count;
+                    if !self.rx_filter_allowed() {
+                        continue;
+                    }
                     if self.handle_frame_rx().is_err() {
                         self.rx.unfinished_frame = true;
                         break;
@@ -237,7 +780,20 @@ impl NetIoHandler {
         Ok(())
     }
 
+    /// Receive the frames from tap, the frame left by the last time goes first.
+    fn handle_tap_rx(&mut self) -> Result<()> {
+        if self.rx.unfinished_frame {
+            self.handle_last_frame_rx()
+        } else {
+            self.handle_rx()
+        }
+    }
+
     fn handle_tx(&mut self) -> Result<()> {
+        if self.quiesced {
+            return Ok(());
+        }
+
         let mut queue = self.tx.queue.lock().unwrap();
         let mut need_irq = false;
 
@@ -468,15 +1024,7 @@ impl EventNotifierHelper for NetIoHandler {
         let cloned_net_io = net_io.clone();
         if let Some(tap) = locked_net_io.tap.as_ref() {
             let handler: Box<NotifierCallback> = Box::new(move |_, _| {
-                let mut locked_net_io = cloned_net_io.lock().unwrap();
-                if locked_net_io.rx.unfinished_frame {
-                    if let Err(ref e) = locked_net_io.handle_last_frame_rx() {
-                        error!(
-                            "Failed to handle last frame(tap event), {}",
-                            error_chain::ChainedError::display_chain(e)
-                        );
-                    }
-                } else if let Err(ref e) = locked_net_io.handle_rx() {
+                if let Err(ref e) = cloned_net_io.lock().unwrap().handle_tap_rx() {
                     error!(
                         "Failed to handle rx(tap event), {}",
                         error_chain::ChainedError::display_chain(e)
@@ -501,7 +1049,7 @@ impl EventNotifierHelper for NetIoHandler {
 /// Status of net device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.2.0", compat_version = "0.2.0")]
 pub struct VirtioNetState {
     /// Bit mask of features supported by the backend.
     device_features: u64,
@@ -509,46 +1057,57 @@ pub struct VirtioNetState {
     driver_features: u64,
     /// Virtio net configurations.
     config_space: VirtioNetConfig,
+    /// Receive filter set by the guest.
+    rx_filter: RxFilter,
 }
 
 /// Network device structure.
 pub struct Net {
     /// Configuration of the network device.
     net_cfg: NetworkInterfaceConfig,
-    /// Tap device opened.
-    tap: Option<Tap>,
+    /// Tap devices opened, one for each queue pair.
+    taps: Option<Vec<Tap>>,
     /// The status of net device.
-    state: VirtioNetState,
-    /// The send half of Rust's channel to send tap information.
-    sender: Option<Sender<SenderConfig>>,
-    /// Eventfd for config space update.
-    update_evt: EventFd,
-    /// Eventfd for device reset.
-    reset_evt: EventFd,
+    state: Arc<Mutex<VirtioNetState>>,
+    /// Generation of the receive filter and MAC address, bumped after they're
+    /// changed, so that the rx handlers reload them.
+    rx_filter_gen: Arc<AtomicU64>,
+    /// The send halves of Rust's channel to send tap information, one for each queue pair.
+    senders: Vec<Sender<SenderConfig>>,
+    /// Eventfds for config space update, one for each queue pair.
+    update_evts: Vec<EventFd>,
+    /// Eventfds for device reset, one for each queue pair and one for the control queue.
+    reset_evts: Vec<EventFd>,
+    /// The guest is requested to announce itself once the device is activated,
+    /// which is set after the device state is restored.
+    need_announce: bool,
+    /// IO handlers of the queue pairs, kept to quiesce them for live migration.
+    handlers: Vec<Arc<Mutex<NetIoHandler>>>,
 }
 
 impl Default for Net {
     fn default() -> Self {
-        Self {
-            net_cfg: Default::default(),
-            tap: None,
-            state: VirtioNetState::default(),
-            sender: None,
-            update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-        }
+        Net::new(NetworkInterfaceConfig::default())
     }
 }
 
 impl Net {
     pub fn new(net_cfg: NetworkInterfaceConfig) -> Self {
+        let queue_pairs = net_cfg.queues as usize;
         Self {
             net_cfg,
-            tap: None,
-            state: VirtioNetState::default(),
-            sender: None,
-            update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
-            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            taps: None,
+            state: Arc::new(Mutex::new(VirtioNetState::default())),
+            rx_filter_gen: Arc::new(AtomicU64::new(0)),
+            senders: Vec::new(),
+            update_evts: (0..queue_pairs)
+                .map(|_| EventFd::new(libc::EFD_NONBLOCK).unwrap())
+                .collect(),
+            reset_evts: (0..queue_pairs + 1)
+                .map(|_| EventFd::new(libc::EFD_NONBLOCK).unwrap())
+                .collect(),
+            need_announce: false,
+            handlers: Vec::new(),
         }
     }
 }
@@ -576,13 +1135,19 @@ pub fn build_device_config_space(device_config: &mut VirtioNetConfig, mac: &str)
     config_features
 }
 
-/// Open tap device if no fd provided, configure and return it.
+/// Open tap devices if no fd provided, configure and return them.
 ///
 /// # Arguments
 ///
 /// * `net_fd` - Fd of tap device opened.
 /// * `host_dev_name` - Path of tap device on host.
-pub fn create_tap(net_fd: Option<i32>, host_dev_name: Option<&str>) -> Result<Option<Tap>> {
+/// * `queue_pairs` - Number of queue pairs, a multi-queue tap device is opened if it's
+///   more than one. Only the queue of the first pair is attached at first.
+pub fn create_tap(
+    net_fd: Option<i32>,
+    host_dev_name: Option<&str>,
+    queue_pairs: u16,
+) -> Result<Option<Vec<Tap>>> {
     if net_fd.is_none() && host_dev_name.is_none() {
         return Ok(None);
     }
@@ -590,73 +1155,96 @@ pub fn create_tap(net_fd: Option<i32>, host_dev_name: Option<&str>) -> Result<Op
         error!("Create tap: fd and file_path exist meanwhile (use fd by default)");
     }
 
-    let tap = if let Some(fd) = net_fd {
-        Tap::new(None, Some(fd)).chain_err(|| "Failed to create tap")?
+    let mut taps = Vec::with_capacity(queue_pairs as usize);
+    if let Some(fd) = net_fd {
+        if queue_pairs > 1 {
+            bail!("Multi-queue tap can't be created with fd");
+        }
+        taps.push(Tap::new(None, Some(fd), false).chain_err(|| "Failed to create tap")?);
     } else {
         // `unwrap()` won't fail because the arguments have been checked
         let dev_name = host_dev_name.unwrap();
-        Tap::new(Some(dev_name), None)
-            .chain_err(|| format!("Failed to create tap with name {}", dev_name))?
-    };
-
-    tap.set_offload(TUN_F_VIRTIO)
-        .chain_err(|| "Failed to set tap offload")?;
+        for _ in 0..queue_pairs {
+            taps.push(
+                Tap::new(Some(dev_name), None, queue_pairs > 1)
+                    .chain_err(|| format!("Failed to create tap with name {}", dev_name))?,
+            );
+        }
+    }
 
     let vnet_hdr_size = mem::size_of::<VirtioNetHdr>() as u32;
-    tap.set_hdr_size(vnet_hdr_size)
-        .chain_err(|| "Failed to set tap hdr size")?;
+    for (index, tap) in taps.iter().enumerate() {
+        tap.set_offload(TUN_F_VIRTIO)
+            .chain_err(|| "Failed to set tap offload")?;
+        tap.set_hdr_size(vnet_hdr_size)
+            .chain_err(|| "Failed to set tap hdr size")?;
+        if index > 0 {
+            tap.set_queue(false)
+                .chain_err(|| format!("Failed to detach queue {} of tap", index))?;
+        }
+    }
 
-    Ok(Some(tap))
+    Ok(Some(taps))
 }
 
 impl VirtioDevice for Net {
     /// Realize virtio network device.
     fn realize(&mut self) -> Result<()> {
         // if iothread not found, return err
-        if self.net_cfg.iothread.is_some()
-            && EventLoop::get_ctx(self.net_cfg.iothread.as_ref()).is_none()
-        {
-            bail!(
-                "IOThread {:?} of Net is not configured in params.",
-                self.net_cfg.iothread,
-            );
+        for iothread in self.net_cfg.iothreads() {
+            if EventLoop::get_ctx(Some(&iothread)).is_none() {
+                bail!(
+                    "IOThread {:?} of Net is not configured in params.",
+                    iothread,
+                );
+            }
         }
 
-        self.state.device_features = 1 << VIRTIO_F_VERSION_1
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.device_features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO;
-
-        if let Some(mac) = &self.net_cfg.mac {
-            self.state.device_features |=
-                build_device_config_space(&mut self.state.config_space, mac);
+            | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_CTRL_RX
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
+            | 1 << VIRTIO_NET_F_CTRL_RX_EXTRA
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
+            | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
+            | 1 << VIRTIO_F_RING_PACKED;
+        locked_state.config_space.status = VIRTIO_NET_S_LINK_UP;
+
+        if self.net_cfg.queues > 1 {
+            locked_state.device_features |= 1 << VIRTIO_NET_F_MQ;
+            locked_state.config_space.max_virtqueue_pairs = self.net_cfg.queues;
         }
 
         if !self.net_cfg.host_dev_name.is_empty() {
-            self.tap = None;
-            self.tap = create_tap(None, Some(&self.net_cfg.host_dev_name))
+            self.taps = None;
+            self.taps = create_tap(None, Some(&self.net_cfg.host_dev_name), self.net_cfg.queues)
                 .chain_err(|| "Failed to open tap with file path")?;
         } else if let Some(fd) = self.net_cfg.tap_fd {
             let mut need_create = true;
-            if let Some(tap) = &self.tap {
-                if fd == tap.as_raw_fd() {
+            if let Some(taps) = &self.taps {
+                if fd == taps[0].as_raw_fd() {
                     need_create = false;
                 }
             }
 
             if need_create {
-                self.tap = create_tap(Some(fd), None).chain_err(|| "Failed to open tap")?;
+                self.taps = create_tap(Some(fd), None, 1).chain_err(|| "Failed to open tap")?;
             }
         } else {
-            self.tap = None;
+            self.taps = None;
         }
 
         if let Some(mac) = &self.net_cfg.mac {
-            self.state.device_features |=
-                build_device_config_space(&mut self.state.config_space, mac);
+            locked_state.device_features |=
+                build_device_config_space(&mut locked_state.config_space, mac);
         }
 
         Ok(())
@@ -669,7 +1257,8 @@ impl VirtioDevice for Net {
 
     /// Get the count of virtio device queues.
     fn queue_num(&self) -> usize {
-        QUEUE_NUM_NET
+        // The control queue follows the receive and transmit queues.
+        self.net_cfg.queues as usize * 2 + 1
     }
 
     /// Get the queue size of virtio device.
@@ -679,23 +1268,25 @@ impl VirtioDevice for Net {
 
     /// Get device features from host.
     fn get_device_features(&self, features_select: u32) -> u32 {
-        read_u32(self.state.device_features, features_select)
+        read_u32(self.state.lock().unwrap().device_features, features_select)
     }
 
     /// Set driver features by guest.
     fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut locked_state = self.state.lock().unwrap();
         let mut v = write_u32(value, page);
-        let unrequested_features = v & !self.state.device_features;
+        let unrequested_features = v & !locked_state.device_features;
         if unrequested_features != 0 {
             warn!("Received acknowledge request with unknown feature: {:x}", v);
             v &= !unrequested_features;
         }
-        self.state.driver_features |= v;
+        locked_state.driver_features |= v;
     }
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        let config_slice = self.state.config_space.as_bytes();
+        let locked_state = self.state.lock().unwrap();
+        let config_slice = locked_state.config_space.as_bytes();
         let config_len = config_slice.len() as u64;
         if offset >= config_len {
             return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
@@ -709,13 +1300,16 @@ impl VirtioDevice for Net {
     /// Write data to config from guest.
     fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
         let data_len = data.len();
-        let config_slice = self.state.config_space.as_mut_bytes();
+        let mut locked_state = self.state.lock().unwrap();
+        let config_slice = locked_state.config_space.as_mut_bytes();
         let config_len = config_slice.len();
         if offset as usize + data_len > config_len {
             return Err(ErrorKind::DevConfigOverflow(offset, config_len as u64).into());
         }
 
         config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+        drop(locked_state);
+        self.rx_filter_gen.fetch_add(1, Ordering::Release);
 
         Ok(())
     }
@@ -729,40 +1323,108 @@ impl VirtioDevice for Net {
         queues: &[Arc<Mutex<Queue>>],
         mut queue_evts: Vec<EventFd>,
     ) -> Result<()> {
-        let rx_queue = queues[0].clone();
-        let rx_queue_evt = queue_evts.remove(0);
-        let tx_queue = queues[1].clone();
-        let tx_queue_evt = queue_evts.remove(0);
-
-        let (sender, receiver) = channel();
-        self.sender = Some(sender);
-
-        let tap_fd = if let Some(tap) = &self.tap {
-            tap.as_raw_fd()
-        } else {
-            -1
-        };
-
-        let handler = NetIoHandler {
-            rx: RxVirtio::new(rx_queue, rx_queue_evt),
-            tx: TxVirtio::new(tx_queue, tx_queue_evt),
-            tap: self.tap.as_ref().map(|t| Tap {
-                file: t.file.try_clone().unwrap(),
-            }),
-            tap_fd,
-            mem_space,
-            interrupt_cb,
-            driver_features: self.state.driver_features,
-            receiver,
-            update_evt: self.update_evt.as_raw_fd(),
-            reset_evt: self.reset_evt.as_raw_fd(),
+        let locked_state = self.state.lock().unwrap();
+        let driver_features = locked_state.driver_features;
+        let (rx_filter, mac) = (locked_state.rx_filter, locked_state.config_space.mac);
+        let rx_filter_gen = self.rx_filter_gen.load(Ordering::Acquire);
+        drop(locked_state);
+        let queue_pairs = queues.len() / 2;
+        let ctrl_handler = NetCtrlHandler {
+            ctrl: CtrlVirtio::new(
+                queues[queue_pairs * 2].clone(),
+                queue_evts.remove(queue_pairs * 2),
+            ),
+            taps: self
+                .taps
+                .iter()
+                .flatten()
+                .map(|t| Tap {
+                    file: t.file.try_clone().unwrap(),
+                })
+                .collect(),
+            curr_queue_pairs: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+            state: self.state.clone(),
+            rx_filter_gen: self.rx_filter_gen.clone(),
+            mem_space: mem_space.clone(),
+            interrupt_cb: interrupt_cb.clone(),
+            driver_features,
+            reset_evt: self.reset_evts[queue_pairs].as_raw_fd(),
         };
 
         EventLoop::update_event(
-            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
-            self.net_cfg.iothread.as_ref(),
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(ctrl_handler))),
+            None,
         )?;
 
+        // Spread the queue pairs across the iothreads in turn.
+        let iothreads = self.net_cfg.iothreads();
+        self.senders.clear();
+        self.handlers.clear();
+        for index in 0..queue_pairs {
+            let rx_queue = queues[index * 2].clone();
+            let rx_queue_evt = queue_evts.remove(0);
+            let tx_queue = queues[index * 2 + 1].clone();
+            let tx_queue_evt = queue_evts.remove(0);
+
+            let (sender, receiver) = channel();
+            self.senders.push(sender);
+
+            let tap = self
+                .taps
+                .as_ref()
+                .and_then(|taps| taps.get(index))
+                .map(|t| Tap {
+                    file: t.file.try_clone().unwrap(),
+                });
+            let tap_fd = if let Some(tap) = &tap {
+                tap.as_raw_fd()
+            } else {
+                -1
+            };
+
+            let handler = NetIoHandler {
+                rx: RxVirtio::new(rx_queue, rx_queue_evt),
+                tx: TxVirtio::new(tx_queue, tx_queue_evt),
+                tap,
+                tap_fd,
+                state: self.state.clone(),
+                rx_filter_gen: self.rx_filter_gen.clone(),
+                rx_filter: RxFilterCache {
+                    filter: rx_filter,
+                    mac,
+                    gen: rx_filter_gen,
+                },
+                mem_space: mem_space.clone(),
+                interrupt_cb: interrupt_cb.clone(),
+                driver_features,
+                receiver,
+                update_evt: self.update_evts[index].as_raw_fd(),
+                reset_evt: self.reset_evts[index].as_raw_fd(),
+                quiesced: false,
+            };
+
+            let iothread = if iothreads.is_empty() {
+                None
+            } else {
+                Some(iothreads[index % iothreads.len()].clone())
+            };
+            let handler = Arc::new(Mutex::new(handler));
+            EventLoop::update_event(
+                EventNotifierHelper::internal_notifiers(handler.clone()),
+                iothread.as_ref(),
+            )?;
+            self.handlers.push(handler);
+        }
+
+        // Request the guest to announce itself after the device state is restored,
+        // so that the network topology learns its new location.
+        if self.need_announce && virtio_has_feature(driver_features, VIRTIO_NET_F_GUEST_ANNOUNCE) {
+            self.state.lock().unwrap().config_space.status |= VIRTIO_NET_S_ANNOUNCE;
+            interrupt_cb(&VirtioInterruptType::Config, None)
+                .chain_err(|| ErrorKind::InterruptTrigger("net", VirtioInterruptType::Config))?;
+        }
+        self.need_announce = false;
+
         Ok(())
     }
 
@@ -779,23 +1441,34 @@ impl VirtioDevice for Net {
 
         self.realize()?;
 
-        if let Some(sender) = &self.sender {
+        let mut taps = self.taps.take().unwrap_or_default().into_iter();
+        for (sender, update_evt) in self.senders.iter().zip(self.update_evts.iter()) {
             sender
-                .send(self.tap.take())
+                .send(taps.next())
                 .chain_err(|| ErrorKind::ChannelSend("tap fd".to_string()))?;
 
-            self.update_evt
-                .write(1)
-                .chain_err(|| ErrorKind::EventFdWrite)?;
+            update_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
         }
 
         Ok(())
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.reset_evt
-            .write(1)
-            .chain_err(|| ErrorKind::EventFdWrite)
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.rx_filter = RxFilter::default();
+        locked_state.config_space.status &= !VIRTIO_NET_S_ANNOUNCE;
+        locked_state.config_space.mac = [0_u8; MAC_ADDR_LEN];
+        if let Some(mac) = &self.net_cfg.mac {
+            build_device_config_space(&mut locked_state.config_space, mac);
+        }
+        drop(locked_state);
+        self.rx_filter_gen.fetch_add(1, Ordering::Release);
+
+        for reset_evt in self.reset_evts.iter() {
+            reset_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -806,12 +1479,13 @@ unsafe impl Sync for Net {}
 
 impl StateTransfer for Net {
     fn get_state_vec(&self) -> migration::errors::Result<Vec<u8>> {
-        Ok(self.state.as_bytes().to_vec())
+        Ok(self.state.lock().unwrap().as_bytes().to_vec())
     }
 
     fn set_state_mut(&mut self, state: &[u8]) -> migration::errors::Result<()> {
-        self.state = *VirtioNetState::from_bytes(state)
+        *self.state.lock().unwrap() = *VirtioNetState::from_bytes(state)
             .ok_or(migration::errors::ErrorKind::FromBytesError("NET"))?;
+        self.need_announce = true;
 
         Ok(())
     }
@@ -825,7 +1499,41 @@ impl StateTransfer for Net {
     }
 }
 
-impl MigrationHook for Net {}
+impl MigrationHook for Net {
+    fn quiesce(&mut self) -> migration::errors::Result<()> {
+        // Wait for the handlers which are running, the frames are either
+        // processed or left in tap and virtqueues.
+        for handler in self.handlers.iter() {
+            handler.lock().unwrap().quiesced = true;
+        }
+
+        Ok(())
+    }
+
+    fn unquiesce(&mut self) -> migration::errors::Result<()> {
+        for handler in self.handlers.iter() {
+            let mut locked_handler = handler.lock().unwrap();
+            if !locked_handler.quiesced {
+                continue;
+            }
+            locked_handler.quiesced = false;
+
+            // The notifications skipped when quiesced are not raised again, so
+            // process the tap and tx virtqueue once.
+            if let Err(ref e) = locked_handler.handle_tap_rx() {
+                bail!(
+                    "Failed to restart net rx, error is {}",
+                    error_chain::ChainedError::display_chain(e)
+                );
+            }
+            if let Err(e) = locked_handler.tx.queue_evt.write(1) {
+                bail!("Failed to kick net tx virtqueue, error is {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -836,11 +1544,11 @@ mod tests {
     fn test_net_init() {
         // test net new method
         let mut net = Net::default();
-        assert_eq!(net.state.device_features, 0);
-        assert_eq!(net.state.driver_features, 0);
+        assert_eq!(net.state.lock().unwrap().device_features, 0);
+        assert_eq!(net.state.lock().unwrap().driver_features, 0);
 
-        assert_eq!(net.tap.is_none(), true);
-        assert_eq!(net.sender.is_none(), true);
+        assert_eq!(net.taps.is_none(), true);
+        assert_eq!(net.senders.is_empty(), true);
         assert_eq!(net.net_cfg.mac.is_none(), true);
         assert_eq!(net.net_cfg.tap_fd.is_none(), true);
         assert_eq!(net.net_cfg.vhost_type.is_none(), true);
@@ -849,7 +1557,7 @@ mod tests {
         // test net realize method
         net.realize().unwrap();
         assert_eq!(net.device_type(), 1);
-        assert_eq!(net.queue_num(), 2);
+        assert_eq!(net.queue_num(), 3);
         assert_eq!(net.queue_size(), 256);
 
         // test read_config and write_config method
@@ -865,8 +1573,7 @@ mod tests {
         net.write_config(0x00, &origin_data).unwrap();
 
         // test boundary condition of offset and data parameters
-        let device_config = net.state.config_space.as_bytes();
-        let len = device_config.len() as u64;
+        let len = net.state.lock().unwrap().config_space.as_bytes().len() as u64;
 
         let mut data: Vec<u8> = vec![0; 10];
         let offset: u64 = len + 1;
@@ -890,4 +1597,102 @@ mod tests {
         let mut data: Vec<u8> = vec![0; len as usize];
         assert_eq!(net.write_config(offset, &mut data).is_ok(), true);
     }
+
+    #[test]
+    fn test_net_multi_queue() {
+        let mut net_cfg = NetworkInterfaceConfig::default();
+        net_cfg.queues = 4;
+        let mut net = Net::new(net_cfg);
+        assert_eq!(net.update_evts.len(), 4);
+        assert_eq!(net.reset_evts.len(), 5);
+
+        // Four queue pairs and one control queue.
+        net.realize().unwrap();
+        assert_eq!(net.queue_num(), 9);
+        assert!(virtio_has_feature(
+            net.state.lock().unwrap().device_features,
+            VIRTIO_NET_F_CTRL_VQ
+        ));
+        assert!(virtio_has_feature(
+            net.state.lock().unwrap().device_features,
+            VIRTIO_NET_F_MQ
+        ));
+        let mut data = [0_u8; 2];
+        net.read_config(8, &mut data).unwrap();
+        assert_eq!(u16::from_le_bytes(data), 4);
+
+        // Single queue pair doesn't support multi-queue.
+        let mut net = Net::default();
+        net.realize().unwrap();
+        assert_eq!(net.queue_num(), 3);
+        assert!(!virtio_has_feature(
+            net.state.lock().unwrap().device_features,
+            VIRTIO_NET_F_MQ
+        ));
+    }
+
+    #[test]
+    fn test_net_rx_filter() {
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let other_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+        let multi_mac = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+        let bcast_mac = [0xff; MAC_ADDR_LEN];
+        let build_frame = |dest: &[u8; MAC_ADDR_LEN], vid: Option<u16>| {
+            let mut frame = dest.to_vec();
+            frame.extend_from_slice(&other_mac);
+            if let Some(vid) = vid {
+                frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+                frame.extend_from_slice(&vid.to_be_bytes());
+            }
+            frame.extend_from_slice(&0x0800_u16.to_be_bytes());
+            frame
+        };
+
+        // All frames are received in promiscuous mode by default.
+        let mut filter = RxFilter::default();
+        assert!(filter.is_allowed(&build_frame(&other_mac, None), &mac, true));
+        assert!(filter.is_allowed(&build_frame(&mac, Some(10)), &mac, true));
+
+        filter.set_rx_mode(VIRTIO_NET_CTRL_RX_PROMISC, false);
+        assert!(filter.is_allowed(&build_frame(&mac, None), &mac, false));
+        assert!(!filter.is_allowed(&build_frame(&other_mac, None), &mac, false));
+        assert!(!filter.is_allowed(&build_frame(&multi_mac, None), &mac, false));
+        assert!(filter.is_allowed(&build_frame(&bcast_mac, None), &mac, false));
+        // Unicast frames are not filtered if the MAC address of device is unknown.
+        assert!(filter.is_allowed(&build_frame(&other_mac, None), &[0; MAC_ADDR_LEN], false));
+
+        filter.set_rx_mode(VIRTIO_NET_CTRL_RX_NOBCAST, true);
+        assert!(!filter.is_allowed(&build_frame(&bcast_mac, None), &mac, false));
+        filter.set_rx_mode(VIRTIO_NET_CTRL_RX_ALLMULTI, true);
+        assert!(filter.is_allowed(&build_frame(&multi_mac, None), &mac, false));
+        filter.set_rx_mode(VIRTIO_NET_CTRL_RX_ALLMULTI, false);
+
+        // One unicast address and one multicast address in MAC table.
+        let mut data = 1_u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&other_mac);
+        data.extend_from_slice(&1_u32.to_le_bytes());
+        data.extend_from_slice(&multi_mac);
+        filter.set_mac_table(&data).unwrap();
+        assert!(filter.is_allowed(&build_frame(&other_mac, None), &mac, false));
+        assert!(filter.is_allowed(&build_frame(&multi_mac, None), &mac, false));
+        assert!(filter.set_mac_table(&data[..data.len() - 1]).is_err());
+
+        // Too many unicast addresses overflow the MAC table.
+        let mut data = (MAC_TABLE_LEN as u32 + 1).to_le_bytes().to_vec();
+        data.extend_from_slice(&[0x02; MAC_ADDR_LEN * (MAC_TABLE_LEN + 1)]);
+        data.extend_from_slice(&0_u32.to_le_bytes());
+        filter.set_mac_table(&data).unwrap();
+        assert_eq!(filter.uni_num, 0);
+        assert_eq!(filter.uni_overflow, 1);
+        assert!(filter.is_allowed(&build_frame(&other_mac, None), &mac, false));
+        assert!(!filter.is_allowed(&build_frame(&multi_mac, None), &mac, false));
+
+        // Tagged frames are only received with the VLAN id added.
+        assert!(!filter.is_allowed(&build_frame(&mac, Some(10)), &mac, true));
+        filter.set_vlan(10, true);
+        assert!(filter.is_allowed(&build_frame(&mac, Some(10)), &mac, true));
+        filter.set_vlan(10, false);
+        assert!(!filter.is_allowed(&build_frame(&mac, Some(10)), &mac, true));
+        assert!(filter.is_allowed(&build_frame(&mac, Some(10)), &mac, false));
+    }
 }
@@ -0,0 +1,1109 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use std::os::unix::io::AsRawFd;
+
+use util::aio::{raw_discard, Iovec};
+
+use super::errors::{Result, ResultExt};
+
+/// Magic number of qcow2 image: "QFI\xfb".
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+/// Supported versions of qcow2 image.
+const QCOW_VERSION_2: u32 = 2;
+const QCOW_VERSION_3: u32 = 3;
+/// Length of the header of version 2 image.
+const QCOW_HEADER_V2_LEN: usize = 72;
+/// Length of the header of version 3 image, without optional fields.
+const QCOW_HEADER_V3_LEN: usize = 104;
+/// Range of the cluster bits allowed by qcow2 spec.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Refcount order used by version 2 image, only 16 bits refcount is supported.
+const REFCOUNT_ORDER: u32 = 4;
+/// Incompatible feature: the refcounts may be inconsistent.
+const INCOMPAT_DIRTY: u64 = 1;
+/// Mask of host offset in L1 and L2 table entries.
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The refcount of the cluster is exactly one.
+const ENTRY_COPIED: u64 = 1 << 63;
+/// The cluster is compressed.
+const L2_ENTRY_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as all zeros, only valid for version 3 image.
+const L2_ENTRY_ZERO: u64 = 1;
+/// Header extension types.
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+/// Max length of backing file name.
+const MAX_BACKING_NAME_LEN: u32 = 1023;
+/// Max depth of the backing file chain.
+const MAX_BACKING_DEPTH: u32 = 16;
+/// Count of L2 tables cached in memory.
+const L2_CACHE_SIZE: usize = 16;
+/// Count of refcount blocks cached in memory.
+const REFCOUNT_CACHE_SIZE: usize = 4;
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0_u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0_u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Read `buf.len()` bytes from `offset` of `file`, the part beyond the end of file
+/// is filled with zeros.
+fn read_file_at(file: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let len = file
+            .read_at(&mut buf[done..], offset + done as u64)
+            .chain_err(|| format!("Failed to read image at offset {}", offset + done as u64))?;
+        if len == 0 {
+            for byte in buf[done..].iter_mut() {
+                *byte = 0;
+            }
+            break;
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// Check whether the file is a qcow2 image by its magic number.
+pub fn is_qcow2(file: &File) -> Result<bool> {
+    let mut magic = [0_u8; 4];
+    read_file_at(file, &mut magic, 0)?;
+    Ok(u32::from_be_bytes(magic) == QCOW_MAGIC)
+}
+
+/// Header of qcow2 image, all fields are stored in big-endian on disk.
+#[derive(Default, Debug, Clone)]
+struct QcowHeader {
+    magic: u32,
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    compatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl QcowHeader {
+    fn from_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() < QCOW_HEADER_V2_LEN {
+            bail!("Invalid qcow2 header length {}", buf.len());
+        }
+
+        let mut header = QcowHeader {
+            magic: be_u32(buf, 0),
+            version: be_u32(buf, 4),
+            backing_file_offset: be_u64(buf, 8),
+            backing_file_size: be_u32(buf, 16),
+            cluster_bits: be_u32(buf, 20),
+            size: be_u64(buf, 24),
+            crypt_method: be_u32(buf, 32),
+            l1_size: be_u32(buf, 36),
+            l1_table_offset: be_u64(buf, 40),
+            refcount_table_offset: be_u64(buf, 48),
+            refcount_table_clusters: be_u32(buf, 56),
+            nb_snapshots: be_u32(buf, 60),
+            refcount_order: REFCOUNT_ORDER,
+            header_length: QCOW_HEADER_V2_LEN as u32,
+            ..Default::default()
+        };
+
+        if header.magic != QCOW_MAGIC {
+            bail!("Invalid qcow2 magic number 0x{:x}", header.magic);
+        }
+        if header.version == QCOW_VERSION_3 {
+            if buf.len() < QCOW_HEADER_V3_LEN {
+                bail!("Invalid qcow2 v3 header length {}", buf.len());
+            }
+            header.incompatible_features = be_u64(buf, 72);
+            header.compatible_features = be_u64(buf, 80);
+            header.autoclear_features = be_u64(buf, 88);
+            header.refcount_order = be_u32(buf, 96);
+            header.header_length = be_u32(buf, 100);
+        }
+
+        Ok(header)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.version != QCOW_VERSION_2 && self.version != QCOW_VERSION_3 {
+            bail!("Unsupported qcow2 version {}", self.version);
+        }
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            bail!("Invalid qcow2 cluster bits {}", self.cluster_bits);
+        }
+        if self.crypt_method != 0 {
+            bail!("Encrypted qcow2 image is not supported");
+        }
+        if self.incompatible_features & INCOMPAT_DIRTY != 0 {
+            bail!("Qcow2 image is dirty, repair it with 'qemu-img check -r all' first");
+        }
+        if self.incompatible_features & !INCOMPAT_DIRTY != 0 {
+            bail!(
+                "Unsupported qcow2 incompatible features 0x{:x}",
+                self.incompatible_features
+            );
+        }
+        if self.refcount_order != REFCOUNT_ORDER {
+            bail!(
+                "Unsupported qcow2 refcount order {}, only 16 bits refcount is supported",
+                self.refcount_order
+            );
+        }
+        if self.version == QCOW_VERSION_3 && (self.header_length as usize) < QCOW_HEADER_V3_LEN {
+            bail!("Invalid qcow2 header length {}", self.header_length);
+        }
+        if self.backing_file_size > MAX_BACKING_NAME_LEN {
+            bail!(
+                "Qcow2 backing file name is too long: {}",
+                self.backing_file_size
+            );
+        }
+
+        let cluster_mask = (1_u64 << self.cluster_bits) - 1;
+        if self.l1_table_offset & cluster_mask != 0
+            || self.refcount_table_offset & cluster_mask != 0
+        {
+            bail!("Qcow2 L1 table or refcount table is not aligned to cluster");
+        }
+        let l2_entries = 1_u64 << (self.cluster_bits - 3);
+        let l1_entries_needed = self
+            .size
+            .checked_add((l2_entries << self.cluster_bits) - 1)
+            .chain_err(|| "Qcow2 image size overflows")?
+            / (l2_entries << self.cluster_bits);
+        if u64::from(self.l1_size) < l1_entries_needed {
+            bail!(
+                "Qcow2 L1 table with {} entries is too small for size {}",
+                self.l1_size,
+                self.size
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Write-through cache of metadata tables, indexed by the host offset of the table.
+struct TableCache<T: Copy> {
+    tables: HashMap<u64, (Vec<T>, u64)>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl<T: Copy> TableCache<T> {
+    fn new(capacity: usize) -> Self {
+        TableCache {
+            tables: HashMap::new(),
+            capacity,
+            tick: 0,
+        }
+    }
+
+    fn get_mut(&mut self, offset: u64) -> Option<&mut Vec<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.tables.get_mut(&offset).map(|(table, used)| {
+            *used = tick;
+            table
+        })
+    }
+
+    fn insert(&mut self, offset: u64, table: Vec<T>) {
+        if self.tables.len() >= self.capacity {
+            // Evict the least recently used table, tables are never dirty.
+            let lru = self
+                .tables
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(offset, _)| *offset);
+            if let Some(lru) = lru {
+                self.tables.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.tables.insert(offset, (table, self.tick));
+    }
+}
+
+/// Image which provides data for clusters unallocated in qcow2 image.
+enum BackingImage {
+    Raw(File),
+    Qcow2(Box<Qcow2Driver>),
+}
+
+impl BackingImage {
+    fn open(path: &str, format: Option<&str>, depth: u32) -> Result<Self> {
+        if depth >= MAX_BACKING_DEPTH {
+            bail!("Qcow2 backing chain is deeper than {}", MAX_BACKING_DEPTH);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .chain_err(|| format!("Failed to open qcow2 backing file {}", path))?;
+
+        let is_qcow2 = match format {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(fmt) => bail!("Unsupported backing file format {}", fmt),
+            None => is_qcow2(&file)?,
+        };
+        if is_qcow2 {
+            let driver = Qcow2Driver::open(file, path, true, depth + 1)?;
+            Ok(BackingImage::Qcow2(Box::new(driver)))
+        } else {
+            Ok(BackingImage::Raw(file))
+        }
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match self {
+            BackingImage::Raw(file) => read_file_at(file, buf, offset),
+            BackingImage::Qcow2(driver) => {
+                // The backing image may be smaller than the overlay.
+                let size = driver.virtual_size();
+                if offset >= size {
+                    for byte in buf.iter_mut() {
+                        *byte = 0;
+                    }
+                    return Ok(());
+                }
+                let len = std::cmp::min(buf.len() as u64, size - offset) as usize;
+                for byte in buf[len..].iter_mut() {
+                    *byte = 0;
+                }
+                driver.read_at(&mut buf[..len], offset)
+            }
+        }
+    }
+}
+
+/// Driver of qcow2 image, which translates the guest offset to the host cluster
+/// through L1/L2 tables, allocates clusters on write and reads unallocated
+/// clusters from the backing image.
+pub struct Qcow2Driver {
+    /// The image file.
+    file: File,
+    /// Header of the image.
+    header: QcowHeader,
+    /// Size of a cluster in bytes.
+    cluster_size: u64,
+    /// Count of entries in a L2 table.
+    l2_entries: u64,
+    /// L1 table, kept in memory entirely.
+    l1_table: Vec<u64>,
+    /// Cache of L2 tables.
+    l2_cache: TableCache<u64>,
+    /// Refcount table, kept in memory entirely.
+    refcount_table: Vec<u64>,
+    /// Cache of refcount blocks.
+    refcount_cache: TableCache<u16>,
+    /// Count of entries in a refcount block.
+    refcount_block_entries: u64,
+    /// Backing image of this image.
+    backing: Option<BackingImage>,
+    /// Whether the image is opened read-only.
+    read_only: bool,
+    /// Host offset of the next cluster to allocate.
+    next_free_cluster: u64,
+    /// Host offsets of the clusters freed since the image is opened, which are
+    /// reused before growing the image file.
+    free_clusters: Vec<u64>,
+}
+
+impl Qcow2Driver {
+    /// Create qcow2 driver for the image.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The opened image file.
+    /// * `path` - Path of the image, used to locate relative backing file.
+    /// * `read_only` - Whether the image can be written.
+    pub fn new(file: File, path: &str, read_only: bool) -> Result<Self> {
+        Self::open(file, path, read_only, 0)
+    }
+
+    fn open(file: File, path: &str, read_only: bool, depth: u32) -> Result<Self> {
+        let mut buf = vec![0_u8; QCOW_HEADER_V3_LEN];
+        read_file_at(&file, &mut buf, 0)?;
+        let header = QcowHeader::from_buf(&buf)?;
+        header
+            .check()
+            .chain_err(|| format!("Invalid qcow2 image {}", path))?;
+        if header.nb_snapshots != 0 && !read_only {
+            bail!("Writing qcow2 image with internal snapshots is not supported");
+        }
+
+        let cluster_size = 1_u64 << header.cluster_bits;
+        let mut driver = Qcow2Driver {
+            file,
+            cluster_size,
+            l2_entries: cluster_size / 8,
+            l1_table: Vec::new(),
+            l2_cache: TableCache::new(L2_CACHE_SIZE),
+            refcount_table: Vec::new(),
+            refcount_cache: TableCache::new(REFCOUNT_CACHE_SIZE),
+            refcount_block_entries: cluster_size / 2,
+            backing: None,
+            read_only,
+            next_free_cluster: 0,
+            free_clusters: Vec::new(),
+            header,
+        };
+
+        driver.l1_table = driver.read_u64_table(
+            driver.header.l1_table_offset,
+            u64::from(driver.header.l1_size),
+        )?;
+        driver.refcount_table = driver.read_u64_table(
+            driver.header.refcount_table_offset,
+            u64::from(driver.header.refcount_table_clusters) * cluster_size / 8,
+        )?;
+
+        let file_size = driver
+            .file
+            .metadata()
+            .chain_err(|| "Failed to get metadata of qcow2 image")?
+            .len();
+        driver.next_free_cluster = (file_size + cluster_size - 1) & !(cluster_size - 1);
+
+        if driver.header.backing_file_offset != 0 {
+            let (backing_path, backing_format) = driver.backing_file_info(path)?;
+            driver.backing = Some(BackingImage::open(
+                &backing_path,
+                backing_format.as_deref(),
+                depth,
+            )?);
+        }
+
+        Ok(driver)
+    }
+
+    /// Get the virtual size of the image in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Get the backing file path and its format from header extensions.
+    fn backing_file_info(&self, path: &str) -> Result<(String, Option<String>)> {
+        let mut name = vec![0_u8; self.header.backing_file_size as usize];
+        read_file_at(&self.file, &mut name, self.header.backing_file_offset)?;
+        let name = String::from_utf8(name).chain_err(|| "Invalid qcow2 backing file name")?;
+
+        let backing_path = if Path::new(&name).is_absolute() {
+            name
+        } else {
+            match Path::new(path).parent() {
+                Some(dir) => dir.join(&name).to_string_lossy().to_string(),
+                None => name,
+            }
+        };
+
+        let mut format = None;
+        let mut ext_offset = u64::from(self.header.header_length);
+        while ext_offset + 8 <= self.cluster_size {
+            let mut ext_header = [0_u8; 8];
+            read_file_at(&self.file, &mut ext_header, ext_offset)?;
+            let ext_type = be_u32(&ext_header, 0);
+            let ext_len = be_u32(&ext_header, 4);
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+            if ext_type == HEADER_EXT_BACKING_FORMAT {
+                let mut fmt = vec![0_u8; ext_len as usize];
+                read_file_at(&self.file, &mut fmt, ext_offset + 8)?;
+                format =
+                    Some(String::from_utf8(fmt).chain_err(|| "Invalid qcow2 backing file format")?);
+            }
+            // Extension data is padded to multiple of 8 bytes.
+            ext_offset += 8 + ((u64::from(ext_len) + 7) & !7);
+        }
+
+        Ok((backing_path, format))
+    }
+
+    fn read_u64_table(&self, offset: u64, entries: u64) -> Result<Vec<u64>> {
+        let mut buf = vec![0_u8; (entries * 8) as usize];
+        read_file_at(&self.file, &mut buf, offset)?;
+        Ok(buf.chunks_exact(8).map(|b| be_u64(b, 0)).collect())
+    }
+
+    fn write_u64_entry(&self, table_offset: u64, index: u64, value: u64) -> Result<()> {
+        self.file
+            .write_all_at(&value.to_be_bytes(), table_offset + index * 8)
+            .chain_err(|| format!("Failed to update qcow2 table at offset {}", table_offset))
+    }
+
+    fn l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        if self.l2_cache.get_mut(l2_offset).is_none() {
+            let table = self.read_u64_table(l2_offset, self.l2_entries)?;
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache.get_mut(l2_offset).unwrap())
+    }
+
+    fn refcount_block(&mut self, block_offset: u64) -> Result<&mut Vec<u16>> {
+        if self.refcount_cache.get_mut(block_offset).is_none() {
+            let mut buf = vec![0_u8; self.cluster_size as usize];
+            read_file_at(&self.file, &mut buf, block_offset)?;
+            let block = buf
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            self.refcount_cache.insert(block_offset, block);
+        }
+        Ok(self.refcount_cache.get_mut(block_offset).unwrap())
+    }
+
+    /// Set the refcount of the cluster at host offset `cluster_offset`.
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u16) -> Result<()> {
+        let cluster_index = cluster_offset >> self.header.cluster_bits;
+        let table_index = cluster_index / self.refcount_block_entries;
+        let block_index = cluster_index % self.refcount_block_entries;
+        if table_index >= self.refcount_table.len() as u64 {
+            bail!(
+                "Qcow2 refcount table is full, failed to allocate cluster at {}",
+                cluster_offset
+            );
+        }
+
+        let mut block_offset = self.refcount_table[table_index as usize] & ENTRY_OFFSET_MASK;
+        if block_offset == 0 {
+            // Allocate a new refcount block, which may be described by itself.
+            block_offset = self.next_free_cluster;
+            self.next_free_cluster += self.cluster_size;
+            self.write_zero_cluster(block_offset)?;
+            self.metadata_barrier()?;
+            self.refcount_table[table_index as usize] = block_offset;
+            self.write_u64_entry(self.header.refcount_table_offset, table_index, block_offset)?;
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        self.refcount_block(block_offset)?[block_index as usize] = refcount;
+        self.file
+            .write_all_at(&refcount.to_be_bytes(), block_offset + block_index * 2)
+            .chain_err(|| "Failed to update qcow2 refcount block")
+    }
+
+    /// Get the refcount of the cluster at host offset `cluster_offset`.
+    fn refcount(&mut self, cluster_offset: u64) -> Result<u16> {
+        let cluster_index = cluster_offset >> self.header.cluster_bits;
+        let table_index = cluster_index / self.refcount_block_entries;
+        let block_index = cluster_index % self.refcount_block_entries;
+        let block_offset = match self.refcount_table.get(table_index as usize) {
+            Some(entry) => entry & ENTRY_OFFSET_MASK,
+            None => return Ok(0),
+        };
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.refcount_block(block_offset)?[block_index as usize])
+    }
+
+    /// Flush the metadata written so far to disk. Metadata is updated in dependency
+    /// order with barriers, so that a crash leaks clusters at worst: the refcount is
+    /// increased before the cluster is linked and decreased after it's unlinked, and
+    /// a new table is written before it's linked.
+    fn metadata_barrier(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .chain_err(|| "Failed to flush qcow2 metadata")
+    }
+
+    fn write_zero_cluster(&self, cluster_offset: u64) -> Result<()> {
+        let zeros = vec![0_u8; self.cluster_size as usize];
+        self.file
+            .write_all_at(&zeros, cluster_offset)
+            .chain_err(|| format!("Failed to write qcow2 cluster at {}", cluster_offset))
+    }
+
+    /// Allocate a new cluster, reuse the freed one or append it to the end of image
+    /// file, return its host offset.
+    fn alloc_cluster(&mut self) -> Result<u64> {
+        let cluster_offset = match self.free_clusters.pop() {
+            Some(offset) => offset,
+            None => {
+                let offset = self.next_free_cluster;
+                self.next_free_cluster += self.cluster_size;
+                offset
+            }
+        };
+        self.set_refcount(cluster_offset, 1)?;
+        self.metadata_barrier()?;
+        Ok(cluster_offset)
+    }
+
+    /// Drop a reference to the cluster at host offset `cluster_offset`, the space of
+    /// the cluster is released to host once it's no longer referenced.
+    fn free_cluster(&mut self, cluster_offset: u64) -> Result<()> {
+        let refcount = self.refcount(cluster_offset)?;
+        if refcount == 0 {
+            bail!("Qcow2 cluster at {} is already free", cluster_offset);
+        }
+        self.set_refcount(cluster_offset, refcount - 1)?;
+        if refcount == 1 {
+            // Punching hole is only to save host space, the cluster is free anyway.
+            raw_discard(
+                self.file.as_raw_fd(),
+                cluster_offset as usize,
+                self.cluster_size,
+            );
+            self.free_clusters.push(cluster_offset);
+        }
+        Ok(())
+    }
+
+    /// Get the L2 entry of the guest offset, 0 means unallocated.
+    fn get_l2_entry(&mut self, guest_offset: u64) -> Result<u64> {
+        let l1_index = (guest_offset >> self.header.cluster_bits) / self.l2_entries;
+        let l2_index = (guest_offset >> self.header.cluster_bits) % self.l2_entries;
+        let l2_offset = match self.l1_table.get(l1_index as usize) {
+            Some(entry) => entry & ENTRY_OFFSET_MASK,
+            None => return Ok(0),
+        };
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(l2_offset)?[l2_index as usize])
+    }
+
+    /// Get the host offset of L2 table for the guest offset, allocate it if needed.
+    fn get_or_alloc_l2_table(&mut self, guest_offset: u64) -> Result<u64> {
+        let l1_index = (guest_offset >> self.header.cluster_bits) / self.l2_entries;
+        if l1_index >= self.l1_table.len() as u64 {
+            bail!("Qcow2 guest offset {} is out of L1 table", guest_offset);
+        }
+
+        let l2_offset = self.l1_table[l1_index as usize] & ENTRY_OFFSET_MASK;
+        if l2_offset != 0 {
+            return Ok(l2_offset);
+        }
+
+        let l2_offset = self.alloc_cluster()?;
+        self.write_zero_cluster(l2_offset)?;
+        self.metadata_barrier()?;
+        self.l2_cache
+            .insert(l2_offset, vec![0_u64; self.l2_entries as usize]);
+        self.l1_table[l1_index as usize] = l2_offset | ENTRY_COPIED;
+        self.write_u64_entry(
+            self.header.l1_table_offset,
+            l1_index,
+            l2_offset | ENTRY_COPIED,
+        )?;
+        Ok(l2_offset)
+    }
+
+    /// Replace the L2 entry `old_entry` of the guest offset with `new_entry`, the host
+    /// cluster which is no longer referenced by the entry is freed.
+    fn set_l2_entry(&mut self, guest_offset: u64, old_entry: u64, new_entry: u64) -> Result<()> {
+        if old_entry == new_entry {
+            return Ok(());
+        }
+        let l2_offset = self.get_or_alloc_l2_table(guest_offset)?;
+        let l2_index = (guest_offset >> self.header.cluster_bits) % self.l2_entries;
+        self.l2_table(l2_offset)?[l2_index as usize] = new_entry;
+        self.write_u64_entry(l2_offset, l2_index, new_entry)?;
+
+        let old_host_offset = old_entry & ENTRY_OFFSET_MASK;
+        if old_host_offset != 0 && old_host_offset != new_entry & ENTRY_OFFSET_MASK {
+            self.metadata_barrier()?;
+            self.free_cluster(old_host_offset)?;
+        }
+        Ok(())
+    }
+
+    fn is_zero_entry(&self, entry: u64) -> bool {
+        self.header.version == QCOW_VERSION_3 && entry & L2_ENTRY_ZERO != 0
+    }
+
+    /// Read data inside one cluster.
+    fn read_cluster(&mut self, buf: &mut [u8], guest_offset: u64) -> Result<()> {
+        let entry = self.get_l2_entry(guest_offset)?;
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            bail!("Compressed qcow2 cluster is not supported");
+        }
+
+        let host_offset = entry & ENTRY_OFFSET_MASK;
+        if self.is_zero_entry(entry) || (host_offset == 0 && self.backing.is_none()) {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            Ok(())
+        } else if host_offset == 0 {
+            self.backing.as_mut().unwrap().read_at(buf, guest_offset)
+        } else {
+            let in_cluster = guest_offset & (self.cluster_size - 1);
+            read_file_at(&self.file, buf, host_offset + in_cluster)
+        }
+    }
+
+    /// Write data inside one cluster, allocate the cluster and copy the rest of it
+    /// from backing image if the cluster is unallocated.
+    fn write_cluster(&mut self, buf: &[u8], guest_offset: u64) -> Result<()> {
+        let l2_offset = self.get_or_alloc_l2_table(guest_offset)?;
+        let l2_index = (guest_offset >> self.header.cluster_bits) % self.l2_entries;
+        let entry = self.l2_table(l2_offset)?[l2_index as usize];
+        if entry & L2_ENTRY_COMPRESSED != 0 {
+            bail!("Compressed qcow2 cluster is not supported");
+        }
+
+        let in_cluster = guest_offset & (self.cluster_size - 1);
+        let mut host_offset = entry & ENTRY_OFFSET_MASK;
+        if host_offset != 0 && !self.is_zero_entry(entry) {
+            return self
+                .file
+                .write_all_at(buf, host_offset + in_cluster)
+                .chain_err(|| format!("Failed to write qcow2 cluster at {}", host_offset));
+        }
+
+        // Fill the whole cluster before linking it to L2 table.
+        let cluster_start = guest_offset - in_cluster;
+        let mut cluster = vec![0_u8; self.cluster_size as usize];
+        if buf.len() as u64 != self.cluster_size && !self.is_zero_entry(entry) {
+            if let Some(backing) = self.backing.as_mut() {
+                backing.read_at(&mut cluster, cluster_start)?;
+            }
+        }
+        cluster[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        if host_offset == 0 {
+            host_offset = self.alloc_cluster()?;
+        }
+        self.file
+            .write_all_at(&cluster, host_offset)
+            .chain_err(|| format!("Failed to write qcow2 cluster at {}", host_offset))?;
+
+        let new_entry = host_offset | ENTRY_COPIED;
+        self.l2_table(l2_offset)?[l2_index as usize] = new_entry;
+        self.write_u64_entry(l2_offset, l2_index, new_entry)
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.header.size => Ok(()),
+            _ => bail!(
+                "Qcow2 request offset {} len {} exceeds image size {}",
+                offset,
+                len,
+                self.header.size
+            ),
+        }
+    }
+
+    /// Read data from the guest offset of the image.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        let mut done = 0_usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let len = std::cmp::min((self.cluster_size - in_cluster) as usize, buf.len() - done);
+            self.read_cluster(&mut buf[done..done + len], pos)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write data to the guest offset of the image.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        if self.read_only {
+            bail!("Qcow2 image is read-only");
+        }
+        self.check_range(offset, buf.len() as u64)?;
+        let mut done = 0_usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let len = std::cmp::min((self.cluster_size - in_cluster) as usize, buf.len() - done);
+            self.write_cluster(&buf[done..done + len], pos)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Get the L2 entry which makes the whole cluster read as zeroes without data,
+    /// None if the image can't express it.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The current L2 entry of the cluster.
+    /// * `unmap` - Whether the host cluster can be deallocated.
+    fn zero_entry(&self, entry: u64, unmap: bool) -> Option<u64> {
+        let host_offset = entry & ENTRY_OFFSET_MASK;
+        if self.header.version == QCOW_VERSION_3 {
+            if host_offset != 0 && !unmap {
+                // Keep the cluster allocated, it's written without allocation later.
+                Some(entry & (ENTRY_OFFSET_MASK | ENTRY_COPIED) | L2_ENTRY_ZERO)
+            } else if self.backing.is_none() {
+                Some(0)
+            } else {
+                Some(L2_ENTRY_ZERO)
+            }
+        } else if self.backing.is_none() && (unmap || host_offset == 0) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Write zeroes to the guest range of the image, clusters which already read
+    /// as zeroes are left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The guest offset of the range.
+    /// * `len` - The length of the range.
+    /// * `unmap` - Whether the clusters covered entirely by the range can be deallocated.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> Result<()> {
+        if self.read_only {
+            bail!("Qcow2 image is read-only");
+        }
+        self.check_range(offset, len)?;
+        let zeros = vec![0_u8; self.cluster_size as usize];
+        let mut done = 0_u64;
+        while done < len {
+            let pos = offset + done;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = std::cmp::min(self.cluster_size - in_cluster, len - done);
+            let entry = self.get_l2_entry(pos)?;
+            if entry & L2_ENTRY_COMPRESSED != 0 {
+                bail!("Compressed qcow2 cluster is not supported");
+            }
+            let zero_entry = if chunk == self.cluster_size {
+                self.zero_entry(entry, unmap)
+            } else {
+                None
+            };
+            if let Some(new_entry) = zero_entry {
+                self.set_l2_entry(pos, entry, new_entry)?;
+            } else {
+                let unallocated = entry & ENTRY_OFFSET_MASK == 0 && self.backing.is_none();
+                if !unallocated && !self.is_zero_entry(entry) {
+                    self.write_cluster(&zeros[..chunk as usize], pos)?;
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Discard the guest range of the image, the clusters covered entirely by the
+    /// range are deallocated and the rest of the range is left untouched.
+    pub fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.read_only {
+            bail!("Qcow2 image is read-only");
+        }
+        self.check_range(offset, len)?;
+        let mut pos = (offset + self.cluster_size - 1) & !(self.cluster_size - 1);
+        while pos + self.cluster_size <= offset + len {
+            let entry = self.get_l2_entry(pos)?;
+            if entry & L2_ENTRY_COMPRESSED != 0 {
+                bail!("Compressed qcow2 cluster is not supported");
+            }
+            // Discarded data of version 2 image may be read from backing file, which
+            // is allowed as the content of discarded range is undefined.
+            let new_entry = self.zero_entry(entry, true).unwrap_or(0);
+            self.set_l2_entry(pos, entry, new_entry)?;
+            pos += self.cluster_size;
+        }
+        Ok(())
+    }
+
+    /// Read data to guest memory described by `iovec`, return the length read.
+    pub fn read_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<u64> {
+        let mut pos = offset;
+        for iov in iovec.iter() {
+            // Safe because the iovec is translated from guest memory which is mapped
+            // in host and keeps alive during the request.
+            let buf = unsafe {
+                std::slice::from_raw_parts_mut(iov.iov_base as *mut u8, iov.iov_len as usize)
+            };
+            self.read_at(buf, pos)?;
+            pos += iov.iov_len;
+        }
+        Ok(pos - offset)
+    }
+
+    /// Write data from guest memory described by `iovec`, return the length written.
+    pub fn write_vectored(&mut self, iovec: &[Iovec], offset: u64) -> Result<u64> {
+        let mut pos = offset;
+        for iov in iovec.iter() {
+            // Safe because the iovec is translated from guest memory which is mapped
+            // in host and keeps alive during the request.
+            let buf = unsafe {
+                std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize)
+            };
+            self.write_at(buf, pos)?;
+            pos += iov.iov_len;
+        }
+        Ok(pos - offset)
+    }
+
+    /// Flush data and metadata of the image to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file
+            .sync_data()
+            .chain_err(|| "Failed to flush qcow2 image")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const CLUSTER_BITS: u32 = 16;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    const IMAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+    // Create a qcow2 v3 image with layout: header, refcount table, refcount block, L1 table.
+    fn create_image(path: &str, backing: Option<&str>) {
+        let mut header = vec![0_u8; CLUSTER_SIZE as usize];
+        let put_u32 = |buf: &mut Vec<u8>, off: usize, val: u32| {
+            buf[off..off + 4].copy_from_slice(&val.to_be_bytes())
+        };
+        let put_u64 = |buf: &mut Vec<u8>, off: usize, val: u64| {
+            buf[off..off + 8].copy_from_slice(&val.to_be_bytes())
+        };
+        put_u32(&mut header, 0, QCOW_MAGIC);
+        put_u32(&mut header, 4, QCOW_VERSION_3);
+        put_u32(&mut header, 20, CLUSTER_BITS);
+        put_u64(&mut header, 24, IMAGE_SIZE);
+        put_u32(&mut header, 36, 1);
+        put_u64(&mut header, 40, 3 * CLUSTER_SIZE);
+        put_u64(&mut header, 48, CLUSTER_SIZE);
+        put_u32(&mut header, 56, 1);
+        put_u32(&mut header, 96, REFCOUNT_ORDER);
+        put_u32(&mut header, 100, QCOW_HEADER_V3_LEN as u32);
+        if let Some(name) = backing {
+            put_u64(&mut header, 8, 512);
+            put_u32(&mut header, 16, name.len() as u32);
+            header[512..512 + name.len()].copy_from_slice(name.as_bytes());
+        }
+
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all(&header).unwrap();
+        let mut refcount_table = vec![0_u8; CLUSTER_SIZE as usize];
+        refcount_table[..8].copy_from_slice(&(2 * CLUSTER_SIZE).to_be_bytes());
+        file.write_all(&refcount_table).unwrap();
+        let mut refcount_block = vec![0_u8; CLUSTER_SIZE as usize];
+        for i in 0..4 {
+            refcount_block[i * 2..i * 2 + 2].copy_from_slice(&1_u16.to_be_bytes());
+        }
+        file.write_all(&refcount_block).unwrap();
+        file.write_all(&vec![0_u8; CLUSTER_SIZE as usize]).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_read_write() {
+        let image = TempFile::new().unwrap();
+        let path = image.as_path().to_str().unwrap().to_string();
+        create_image(&path, None);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut driver = Qcow2Driver::new(file, &path, false).unwrap();
+        assert_eq!(driver.virtual_size(), IMAGE_SIZE);
+
+        // Unallocated clusters read as zeros.
+        let mut buf = vec![0xff_u8; 4096];
+        driver.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Write across the boundary of clusters.
+        let data = vec![0x5a_u8; 8192];
+        let offset = CLUSTER_SIZE - 4096;
+        driver.write_at(&data, offset).unwrap();
+        let mut buf = vec![0_u8; 8192];
+        driver.read_at(&mut buf, offset).unwrap();
+        assert_eq!(buf, data);
+        driver.flush().unwrap();
+
+        // Out of range request fails.
+        assert!(driver.read_at(&mut buf, IMAGE_SIZE - 4096).is_err());
+
+        // Data and metadata are persistent after reopening.
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        let mut driver = Qcow2Driver::new(file, &path, true).unwrap();
+        let mut buf = vec![0_u8; 8192];
+        driver.read_at(&mut buf, offset).unwrap();
+        assert_eq!(buf, data);
+        let mut buf = vec![0xff_u8; 4096];
+        driver.read_at(&mut buf, offset - 4096).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(driver.refcount_block(2 * CLUSTER_SIZE).unwrap()[4], 1);
+        assert!(driver.write_at(&data, 0).is_err());
+    }
+
+    #[test]
+    fn test_qcow2_write_zeroes() {
+        let image = TempFile::new().unwrap();
+        let path = image.as_path().to_str().unwrap().to_string();
+        create_image(&path, None);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut driver = Qcow2Driver::new(file, &path, false).unwrap();
+
+        let data = vec![0x5a_u8; 8192];
+        driver.write_at(&data, 0).unwrap();
+        driver.write_zeroes(1024, 4096, true).unwrap();
+        let mut buf = vec![0xff_u8; 8192];
+        driver.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..1024], data[..1024]);
+        assert!(buf[1024..5120].iter().all(|b| *b == 0));
+        assert_eq!(buf[5120..], data[5120..]);
+
+        // Unallocated clusters are not allocated by write zeroes.
+        driver
+            .write_zeroes(CLUSTER_SIZE * 4, CLUSTER_SIZE, false)
+            .unwrap();
+        assert_eq!(driver.get_l2_entry(CLUSTER_SIZE * 4).unwrap(), 0);
+
+        // Whole cluster is marked zero and keeps allocated without unmap.
+        let data = vec![0x5a_u8; CLUSTER_SIZE as usize];
+        driver.write_at(&data, CLUSTER_SIZE).unwrap();
+        let host_offset = driver.get_l2_entry(CLUSTER_SIZE).unwrap() & ENTRY_OFFSET_MASK;
+        driver
+            .write_zeroes(CLUSTER_SIZE, CLUSTER_SIZE, false)
+            .unwrap();
+        assert_eq!(
+            driver.get_l2_entry(CLUSTER_SIZE).unwrap(),
+            host_offset | ENTRY_COPIED | L2_ENTRY_ZERO
+        );
+        let mut buf = vec![0xff_u8; CLUSTER_SIZE as usize];
+        driver.read_at(&mut buf, CLUSTER_SIZE).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Whole cluster is deallocated with unmap, and reused by the next allocation.
+        driver
+            .write_zeroes(CLUSTER_SIZE, CLUSTER_SIZE, true)
+            .unwrap();
+        assert_eq!(driver.get_l2_entry(CLUSTER_SIZE).unwrap(), 0);
+        assert_eq!(driver.refcount(host_offset).unwrap(), 0);
+        driver.write_at(&data, CLUSTER_SIZE * 2).unwrap();
+        assert_eq!(
+            driver.get_l2_entry(CLUSTER_SIZE * 2).unwrap(),
+            host_offset | ENTRY_COPIED
+        );
+        assert_eq!(driver.refcount(host_offset).unwrap(), 1);
+
+        // Discard only deallocates the clusters covered entirely.
+        driver.write_at(&data, CLUSTER_SIZE * 3).unwrap();
+        driver
+            .discard(CLUSTER_SIZE * 3 - 512, CLUSTER_SIZE + 1024)
+            .unwrap();
+        assert_eq!(driver.get_l2_entry(CLUSTER_SIZE * 3).unwrap(), 0);
+        let mut buf = vec![0xff_u8; CLUSTER_SIZE as usize];
+        driver.read_at(&mut buf, CLUSTER_SIZE * 2).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_qcow2_backing_file() {
+        let base = TempFile::new().unwrap();
+        let base_path = base.as_path().to_str().unwrap().to_string();
+        let pattern: Vec<u8> = (0..CLUSTER_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        base.as_file().write_all(&pattern).unwrap();
+
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+        create_image(&overlay_path, Some(&base_path));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&overlay_path)
+            .unwrap();
+        let mut driver = Qcow2Driver::new(file, &overlay_path, false).unwrap();
+
+        // Unallocated clusters are read from backing file, beyond it reads zeros.
+        let mut buf = vec![0_u8; CLUSTER_SIZE as usize * 2];
+        driver.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, pattern);
+        driver.read_at(&mut buf, CLUSTER_SIZE * 2).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Partial write copies the rest of cluster from backing file.
+        let data = vec![0xa5_u8; 512];
+        driver.write_at(&data, 1024).unwrap();
+        let mut buf = vec![0_u8; CLUSTER_SIZE as usize];
+        driver.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..1024], pattern[..1024]);
+        assert_eq!(buf[1024..1536], data[..]);
+        assert_eq!(buf[1536..], pattern[1536..CLUSTER_SIZE as usize]);
+
+        // Backing file is not modified.
+        let mut buf = vec![0_u8; 512];
+        read_file_at(base.as_file(), &mut buf, 1024).unwrap();
+        assert_eq!(buf[..], pattern[1024..1536]);
+
+        // Unmapped clusters read as zeros instead of backing file.
+        driver.write_zeroes(0, CLUSTER_SIZE * 2, true).unwrap();
+        assert_eq!(driver.get_l2_entry(0).unwrap(), L2_ENTRY_ZERO);
+        assert_eq!(driver.get_l2_entry(CLUSTER_SIZE).unwrap(), L2_ENTRY_ZERO);
+        let mut buf = vec![0xff_u8; CLUSTER_SIZE as usize * 2];
+        driver.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        driver.write_at(&data, 1024).unwrap();
+
+        // Qcow2 image can be a backing file of another qcow2 image.
+        let top = TempFile::new().unwrap();
+        let top_path = top.as_path().to_str().unwrap().to_string();
+        create_image(&top_path, Some(&overlay_path));
+        let file = OpenOptions::new().read(true).open(&top_path).unwrap();
+        let mut driver = Qcow2Driver::new(file, &top_path, true).unwrap();
+        let mut buf = vec![0_u8; 512];
+        driver.read_at(&mut buf, 1024).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_qcow2_invalid_image() {
+        let image = TempFile::new().unwrap();
+        let path = image.as_path().to_str().unwrap().to_string();
+        image.as_file().write_all(&[0_u8; 4096]).unwrap();
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        assert!(!is_qcow2(&file).unwrap());
+        assert!(Qcow2Driver::new(file, &path, true).is_err());
+    }
+}
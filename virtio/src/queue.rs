@@ -845,6 +845,576 @@ impl VringOps for SplitVring {
     }
 }
 
+/// Mark a descriptor as available, compared with the avail wrap counter.
+const VRING_PACKED_DESC_F_AVAIL: u16 = 1 << 7;
+/// Mark a descriptor as used, compared with the used wrap counter.
+const VRING_PACKED_DESC_F_USED: u16 = 1 << 15;
+/// Disable events.
+const VRING_PACKED_EVENT_FLAG_DISABLE: u16 = 0x1;
+/// Enable events for a specific descriptor, only valid if VIRTIO_F_RING_EVENT_IDX is negotiated.
+const VRING_PACKED_EVENT_FLAG_DESC: u16 = 0x2;
+/// The bit of wrap counter in the `off_wrap` of event suppression structure.
+const VRING_PACKED_EVENT_F_WRAP_CTR: u16 = 15;
+/// The offset of len in the packed descriptor.
+const PACKED_DESC_LEN_OFFSET: u64 = 8;
+/// The offset of id in the packed descriptor.
+const PACKED_DESC_ID_OFFSET: u64 = 12;
+/// The offset of flags in the packed descriptor.
+const PACKED_DESC_FLAGS_OFFSET: u64 = 14;
+
+/// Descriptor of packed vring.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct PackedVringDesc {
+    /// Address (guest-physical).
+    pub addr: GuestAddress,
+    /// Length.
+    pub len: u32,
+    /// Buffer ID.
+    pub id: u16,
+    /// The flags depending on descriptor type.
+    pub flags: u16,
+}
+
+impl ByteCode for PackedVringDesc {}
+
+impl PackedVringDesc {
+    /// Return true if the descriptor is valid.
+    fn is_valid(&self, sys_mem: &Arc<AddressSpace>) -> bool {
+        if let Err(ref e) = checked_offset_mem(sys_mem, self.addr, u64::from(self.len)) {
+            error!(
+                "The memory of packed descriptor is invalid, {} ",
+                error_chain::ChainedError::display_chain(e),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Return true if this descriptor has next descriptor.
+    fn has_next(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_NEXT != 0
+    }
+
+    /// Check whether this descriptor is write-only or read-only.
+    fn write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+
+    /// Return true if this descriptor is a indirect descriptor.
+    fn is_indirect_desc(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_INDIRECT != 0
+    }
+
+    /// Put the buffer of this descriptor into the element.
+    fn push_iovec(&self, elem: &mut Element) {
+        let iovec = ElemIovec {
+            addr: self.addr,
+            len: self.len,
+        };
+        if self.write_only() {
+            elem.in_iovec.push(iovec);
+        } else {
+            elem.out_iovec.push(iovec);
+        }
+        elem.desc_num += 1;
+    }
+}
+
+/// Event suppression structure of packed vring, which is used by the driver
+/// and the device to reduce the number of notifications sent to each other.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct PackedVringEvent {
+    /// Descriptor ring change event offset and wrap counter.
+    off_wrap: u16,
+    /// Descriptor ring change event flags.
+    flags: u16,
+}
+
+impl ByteCode for PackedVringEvent {}
+
+/// The length of event suppression structure.
+const PACKED_EVENT_LEN: u64 = size_of::<PackedVringEvent>() as u64;
+
+/// Packed vring.
+#[derive(Default, Clone)]
+pub struct PackedVring {
+    /// Guest physical address of the descriptor ring.
+    /// The ring is composed of descriptors(PackedVringDesc).
+    pub desc_ring: GuestAddress,
+
+    /// Guest physical address of the driver event suppression structure,
+    /// which is written by the driver.
+    pub driver_event: GuestAddress,
+
+    /// Guest physical address of the device event suppression structure,
+    /// which is written by the device.
+    pub device_event: GuestAddress,
+
+    /// Indicate whether the queue configuration is finished.
+    pub ready: bool,
+
+    /// The maximal size in elements offered by the device.
+    pub max_size: u16,
+
+    /// The queue size set by frontend.
+    pub size: u16,
+
+    /// Interrupt vector index of the queue for msix
+    pub vector: u16,
+
+    /// The next index of descriptor ring which can be popped.
+    next_avail: u16,
+
+    /// The wrap counter of the driver ring.
+    avail_wrap_counter: bool,
+
+    /// The next index of descriptor ring which can be used.
+    next_used: u16,
+
+    /// The wrap counter of the device ring.
+    used_wrap_counter: bool,
+
+    /// The `next_used` which has triggered interrupt last time.
+    last_signal_used: u16,
+
+    /// Whether `last_signal_used` is valid.
+    signal_used_valid: bool,
+
+    /// The `next_avail` and `avail_wrap_counter` before the last popping,
+    /// used to rollback by `push_back`.
+    last_avail: (u16, bool),
+
+    /// The count of descriptors in the ring used by each buffer ID.
+    chain_len: Vec<u16>,
+}
+
+impl PackedVring {
+    /// Create a packed vring.
+    ///
+    /// The wrap counters are folded into `next_avail` and `next_used` of the
+    /// configuration, which count in the range of [0, 2 * size), the wrap
+    /// counter is set when the count is less than size.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_config` - Configuration of the vring.
+    pub fn new(queue_config: QueueConfig) -> Self {
+        let size = min(queue_config.size, queue_config.max_size);
+        let unfold = |count: u16| -> (u16, bool) {
+            if size != 0 && count >= size {
+                (count - size, false)
+            } else {
+                (count, true)
+            }
+        };
+        let (next_avail, avail_wrap_counter) = unfold(queue_config.next_avail);
+        let (next_used, used_wrap_counter) = unfold(queue_config.next_used);
+
+        PackedVring {
+            desc_ring: queue_config.desc_table,
+            driver_event: queue_config.avail_ring,
+            device_event: queue_config.used_ring,
+            ready: queue_config.ready,
+            max_size: queue_config.max_size,
+            size: queue_config.size,
+            vector: queue_config.vector,
+            next_avail,
+            avail_wrap_counter,
+            next_used,
+            used_wrap_counter,
+            last_signal_used: queue_config.last_signal_used,
+            signal_used_valid: false,
+            last_avail: (next_avail, avail_wrap_counter),
+            chain_len: vec![0; size as usize],
+        }
+    }
+
+    /// The actual size of the queue.
+    fn actual_size(&self) -> u16 {
+        min(self.size, self.max_size)
+    }
+
+    /// Fold the wrap counter into the index, see `PackedVring::new`.
+    fn fold(&self, index: u16, wrap_counter: bool) -> u16 {
+        if wrap_counter {
+            index
+        } else {
+            index + self.actual_size()
+        }
+    }
+
+    /// Get the address of descriptor in the descriptor ring.
+    fn get_desc_addr(&self, index: u16) -> Result<GuestAddress> {
+        self.desc_ring
+            .checked_add(u64::from(index) * DESCRIPTOR_LEN)
+            .ok_or_else(|| {
+                ErrorKind::Msg(format!(
+                    "Address overflows for packed descriptor: addr 0x{:X}, index {}",
+                    self.desc_ring.raw_value(),
+                    index
+                ))
+                .into()
+            })
+    }
+
+    /// Get the descriptor in the descriptor ring from guest memory.
+    fn get_desc(&self, sys_mem: &Arc<AddressSpace>, index: u16) -> Result<PackedVringDesc> {
+        let desc_addr = self.get_desc_addr(index)?;
+        let desc = sys_mem
+            .read_object::<PackedVringDesc>(desc_addr)
+            .chain_err(|| {
+                format!(
+                    "Failed to read object for a packed descriptor (index: {}, addr: 0x{:X})",
+                    index,
+                    desc_addr.raw_value()
+                )
+            })?;
+        if !desc.is_valid(sys_mem) {
+            return Err(ErrorKind::QueueDescInvalid.into());
+        }
+        Ok(desc)
+    }
+
+    /// Return true if the descriptor at `next_avail` is made available by the driver.
+    fn is_desc_avail(&self, sys_mem: &Arc<AddressSpace>) -> Result<bool> {
+        let flags_addr = self
+            .get_desc_addr(self.next_avail)?
+            .checked_add(PACKED_DESC_FLAGS_OFFSET)
+            .ok_or("Address overflows for the flags of packed descriptor")?;
+        let flags = sys_mem
+            .read_object::<u16>(flags_addr)
+            .chain_err(|| "Failed to read the flags of packed descriptor")?;
+
+        let avail = flags & VRING_PACKED_DESC_F_AVAIL != 0;
+        let used = flags & VRING_PACKED_DESC_F_USED != 0;
+        Ok(avail == self.avail_wrap_counter && used != self.avail_wrap_counter)
+    }
+
+    /// Get the event suppression structure of the driver from guest memory.
+    fn get_driver_event(&self, sys_mem: &Arc<AddressSpace>) -> Result<PackedVringEvent> {
+        sys_mem
+            .read_object::<PackedVringEvent>(self.driver_event)
+            .chain_err(|| {
+                format!(
+                    "Failed to get driver event, driver_event: 0x{:X}",
+                    self.driver_event.raw_value()
+                )
+            })
+    }
+
+    /// Ask the driver to notify the device when the descriptor at `next_avail`
+    /// is made available.
+    fn set_device_event(&self, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+        let event = PackedVringEvent {
+            off_wrap: self.next_avail
+                | (u16::from(self.avail_wrap_counter) << VRING_PACKED_EVENT_F_WRAP_CTR),
+            flags: VRING_PACKED_EVENT_FLAG_DESC,
+        };
+
+        fence(Ordering::Release);
+        sys_mem
+            .write_object(&event, self.device_event)
+            .chain_err(|| {
+                format!(
+                    "Failed to set device event, device_event: 0x{:X}",
+                    self.device_event.raw_value()
+                )
+            })
+    }
+
+    /// Return true if it's required to trigger interrupt with the event index
+    /// of the driver.
+    fn used_ring_need_event(&self, off_wrap: u16, new: u16, old: u16) -> bool {
+        let mut off = Wrapping(off_wrap & !(1 << VRING_PACKED_EVENT_F_WRAP_CTR));
+        let wrap_counter = off_wrap >> VRING_PACKED_EVENT_F_WRAP_CTR != 0;
+        if wrap_counter != self.used_wrap_counter {
+            off -= Wrapping(self.actual_size());
+        }
+        let (new, old) = (Wrapping(new), Wrapping(old));
+        (new - off - Wrapping(1)) < (new - old)
+    }
+
+    /// Move the index forward in the descriptor ring, and flip the wrap
+    /// counter when the end of the ring is reached.
+    fn next_index(&self, index: u16, wrap_counter: bool, step: u16) -> (u16, bool) {
+        let next = u32::from(index) + u32::from(step);
+        if next >= u32::from(self.actual_size()) {
+            ((next - u32::from(self.actual_size())) as u16, !wrap_counter)
+        } else {
+            (next as u16, wrap_counter)
+        }
+    }
+
+    fn is_invalid_memory(&self, sys_mem: &Arc<AddressSpace>, actual_size: u64) -> bool {
+        if let Err(ref e) =
+            checked_offset_mem(sys_mem, self.desc_ring, DESCRIPTOR_LEN * actual_size)
+        {
+            error!(
+                "descriptor ring is out of bounds: start:0x{:X} size:{} {}",
+                self.desc_ring.raw_value(),
+                DESCRIPTOR_LEN * actual_size,
+                error_chain::ChainedError::display_chain(e),
+            );
+            return true;
+        }
+
+        for (name, addr) in [
+            ("driver event", self.driver_event),
+            ("device event", self.device_event),
+        ]
+        .iter()
+        {
+            if let Err(ref e) = checked_offset_mem(sys_mem, *addr, PACKED_EVENT_LEN) {
+                error!(
+                    "{} is out of bounds: start:0x{:X} size:{} {}",
+                    name,
+                    addr.raw_value(),
+                    PACKED_EVENT_LEN,
+                    error_chain::ChainedError::display_chain(e),
+                );
+                return true;
+            }
+        }
+
+        if self.desc_ring.0 & 0xf != 0 {
+            error!(
+                "descriptor ring: 0x{:X} is not aligned",
+                self.desc_ring.raw_value()
+            );
+            true
+        } else if self.driver_event.0 & 0x3 != 0 {
+            error!(
+                "driver event: 0x{:X} is not aligned",
+                self.driver_event.raw_value()
+            );
+            true
+        } else if self.device_event.0 & 0x3 != 0 {
+            error!(
+                "device event: 0x{:X} is not aligned",
+                self.device_event.raw_value()
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get element from the indirect descriptor table.
+    fn get_indirect_element(
+        sys_mem: &Arc<AddressSpace>,
+        desc: &PackedVringDesc,
+        elem: &mut Element,
+    ) -> Result<()> {
+        if desc.write_only() || desc.has_next() {
+            bail!(
+                "Unexpected flags 0x{:x} of indirect packed descriptor",
+                desc.flags
+            );
+        }
+        if u64::from(desc.len) % DESCRIPTOR_LEN != 0 || desc.len == 0 {
+            error!("The indirect descriptor is invalid, len: {}", desc.len);
+            return Err(ErrorKind::QueueDescInvalid.into());
+        }
+
+        let desc_num = u64::from(desc.len) / DESCRIPTOR_LEN;
+        for index in 0..desc_num {
+            let desc_addr = GuestAddress(desc.addr.0 + index * DESCRIPTOR_LEN);
+            let indirect_desc = sys_mem
+                .read_object::<PackedVringDesc>(desc_addr)
+                .chain_err(|| {
+                    format!(
+                        "Failed to read indirect packed descriptor 0x{:X}",
+                        desc_addr.raw_value()
+                    )
+                })?;
+            if indirect_desc.is_indirect_desc() || !indirect_desc.is_valid(sys_mem) {
+                return Err(ErrorKind::QueueDescInvalid.into());
+            }
+            indirect_desc.push_iovec(elem);
+        }
+        Ok(())
+    }
+
+    fn get_vring_element(&mut self, sys_mem: &Arc<AddressSpace>) -> Result<(Element, u16)> {
+        let mut elem = Element::new(0);
+        let mut index = self.next_avail;
+        let mut wrap_counter = self.avail_wrap_counter;
+        let mut ring_desc_num = 0_u16;
+
+        loop {
+            if ring_desc_num >= self.actual_size() {
+                bail!("The descriptor chain of packed vring is too long");
+            }
+
+            let desc = self.get_desc(sys_mem, index)?;
+            if desc.is_indirect_desc() {
+                Self::get_indirect_element(sys_mem, &desc, &mut elem)
+                    .chain_err(|| "Failed to get indirect desc for popping avail ring")?;
+            } else {
+                desc.push_iovec(&mut elem);
+            }
+            ring_desc_num += 1;
+            let next = self.next_index(index, wrap_counter, 1);
+            index = next.0;
+            wrap_counter = next.1;
+
+            // The buffer ID is stored in the last descriptor of the chain.
+            if desc.is_indirect_desc() || !desc.has_next() {
+                elem.index = desc.id;
+                break;
+            }
+        }
+
+        if elem.index >= self.actual_size() {
+            return Err(ErrorKind::QueueIndex(elem.index, self.actual_size()).into());
+        }
+        Ok((elem, ring_desc_num))
+    }
+}
+
+impl VringOps for PackedVring {
+    fn is_valid(&self, sys_mem: &Arc<AddressSpace>) -> bool {
+        if !self.ready {
+            error!("The configuration of vring is not ready\n");
+            false
+        } else if self.size > self.max_size || self.size == 0 {
+            error!(
+                "vring with invalid size:{} max size:{}",
+                self.size, self.max_size
+            );
+            false
+        } else {
+            !self.is_invalid_memory(sys_mem, u64::from(self.actual_size()))
+        }
+    }
+
+    fn pop_avail(&mut self, sys_mem: &Arc<AddressSpace>, features: u64) -> Result<Element> {
+        if !self.is_desc_avail(sys_mem)? {
+            bail!("failed to pop avail: empty!");
+        }
+        // Read the descriptors after making sure that they are available.
+        fence(Ordering::Acquire);
+
+        let (elem, ring_desc_num) = match self.get_vring_element(sys_mem) {
+            Ok(elem) => elem,
+            Err(ref e) => {
+                error!(
+                    "Failed to get element from packed vring, {}",
+                    error_chain::ChainedError::display_chain(e),
+                );
+
+                return Err(e.to_string().into());
+            }
+        };
+
+        self.last_avail = (self.next_avail, self.avail_wrap_counter);
+        let (next_avail, avail_wrap_counter) =
+            self.next_index(self.next_avail, self.avail_wrap_counter, ring_desc_num);
+        self.next_avail = next_avail;
+        self.avail_wrap_counter = avail_wrap_counter;
+        self.chain_len[elem.index as usize] = ring_desc_num;
+
+        if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            self.set_device_event(sys_mem)
+                .chain_err(|| "Failed to set device event for popping avail ring")?;
+        }
+
+        Ok(elem)
+    }
+
+    fn push_back(&mut self) {
+        self.next_avail = self.last_avail.0;
+        self.avail_wrap_counter = self.last_avail.1;
+    }
+
+    fn add_used(&mut self, sys_mem: &Arc<AddressSpace>, index: u16, len: u32) -> Result<()> {
+        if index >= self.actual_size() {
+            return Err(ErrorKind::QueueIndex(index, self.actual_size()).into());
+        }
+
+        let desc_addr = self.get_desc_addr(self.next_used)?;
+        sys_mem
+            .write_object::<u32>(&len, GuestAddress(desc_addr.0 + PACKED_DESC_LEN_OFFSET))
+            .chain_err(|| "Failed to write the len of used descriptor")?;
+        sys_mem
+            .write_object::<u16>(&index, GuestAddress(desc_addr.0 + PACKED_DESC_ID_OFFSET))
+            .chain_err(|| "Failed to write the id of used descriptor")?;
+
+        // The flags must be updated after the other fields of the used descriptor.
+        fence(Ordering::Release);
+
+        let flags = if self.used_wrap_counter {
+            VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED
+        } else {
+            0
+        };
+        sys_mem
+            .write_object::<u16>(&flags, GuestAddress(desc_addr.0 + PACKED_DESC_FLAGS_OFFSET))
+            .chain_err(|| "Failed to write the flags of used descriptor")?;
+
+        let ring_desc_num = self.chain_len[index as usize].max(1);
+        let (next_used, used_wrap_counter) =
+            self.next_index(self.next_used, self.used_wrap_counter, ring_desc_num);
+        self.next_used = next_used;
+        self.used_wrap_counter = used_wrap_counter;
+
+        Ok(())
+    }
+
+    fn should_notify(&mut self, sys_mem: &Arc<AddressSpace>, features: u64) -> bool {
+        // Make sure that the used descriptors are visible before reading the event.
+        fence(Ordering::SeqCst);
+
+        let event = match self.get_driver_event(sys_mem) {
+            Ok(event) => event,
+            Err(ref e) => {
+                error!(
+                    "Failed to get the status for notifying packed vring {}",
+                    error_chain::ChainedError::display_chain(e)
+                );
+                return false;
+            }
+        };
+
+        let old = self.last_signal_used;
+        let new = self.next_used;
+        let valid = self.signal_used_valid;
+        self.last_signal_used = new;
+        self.signal_used_valid = true;
+
+        match event.flags {
+            VRING_PACKED_EVENT_FLAG_DISABLE => false,
+            VRING_PACKED_EVENT_FLAG_DESC
+                if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) =>
+            {
+                !valid || self.used_ring_need_event(event.off_wrap, new, old)
+            }
+            _ => true,
+        }
+    }
+
+    fn actual_size(&self) -> u16 {
+        self.actual_size()
+    }
+
+    fn get_queue_config(&self) -> QueueConfig {
+        QueueConfig {
+            desc_table: self.desc_ring,
+            avail_ring: self.driver_event,
+            used_ring: self.device_event,
+            ready: self.ready,
+            max_size: self.max_size,
+            size: self.size,
+            vector: self.vector,
+            next_avail: self.fold(self.next_avail, self.avail_wrap_counter),
+            next_used: self.fold(self.next_used, self.used_wrap_counter),
+            last_signal_used: self.last_signal_used,
+        }
+    }
+}
+
 /// Virtio queue.
 pub struct Queue {
     /// Vring structure.
@@ -861,6 +1431,7 @@ impl Queue {
     pub fn new(queue_config: QueueConfig, queue_type: u16) -> Result<Self> {
         let vring: Box<dyn VringOps + Send> = match queue_type {
             QUEUE_TYPE_SPLIT_VRING => Box::new(SplitVring::new(queue_config)),
+            QUEUE_TYPE_PACKED_VRING => Box::new(PackedVring::new(queue_config)),
             _ => {
                 bail!("Unsupported queue type {}", queue_type);
             }
@@ -1074,8 +1645,6 @@ mod tests {
         // failed when the type of queue is invalid
         let queue = Queue::new(queue_config, 0);
         assert!(queue.is_err());
-        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING);
-        assert!(queue.is_err());
 
         // it is valid
         queue_config.desc_table = GuestAddress(0);
@@ -1679,4 +2248,234 @@ mod tests {
         assert!(vring.set_used_event_idx(&sys_space, 4).is_ok()); //event_idx
         assert_eq!(vring.should_notify(&sys_space, features), false);
     }
+
+    fn packed_queue_config(size: u16) -> QueueConfig {
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN);
+        queue_config.used_ring =
+            GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN + PACKED_EVENT_LEN);
+        queue_config.ready = true;
+        queue_config.size = size;
+        queue_config
+    }
+
+    fn set_packed_desc(
+        sys_mem: &Arc<AddressSpace>,
+        desc_addr: GuestAddress,
+        addr: u64,
+        len: u32,
+        id: u16,
+        flags: u16,
+    ) {
+        let desc = PackedVringDesc {
+            addr: GuestAddress(addr),
+            len,
+            id,
+            flags,
+        };
+        sys_mem.write_object(&desc, desc_addr).unwrap();
+    }
+
+    fn avail_flags(wrap_counter: bool) -> u16 {
+        if wrap_counter {
+            VRING_PACKED_DESC_F_AVAIL
+        } else {
+            VRING_PACKED_DESC_F_USED
+        }
+    }
+
+    #[test]
+    fn test_packed_valid_queue() {
+        let sys_space = address_space_init();
+
+        let queue = Queue::new(packed_queue_config(QUEUE_SIZE), QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert_eq!(queue.is_valid(&sys_space), true);
+
+        // the size of packed vring is not required to be power of 2
+        let queue = Queue::new(packed_queue_config(15), QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert_eq!(queue.is_valid(&sys_space), true);
+
+        let queue = Queue::new(packed_queue_config(0), QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert_eq!(queue.is_valid(&sys_space), false);
+
+        // it is invalid when the descriptor ring is not aligned
+        let mut queue_config = packed_queue_config(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0x8);
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert_eq!(queue.is_valid(&sys_space), false);
+
+        // it is invalid when the device event is out of bounds
+        let mut queue_config = packed_queue_config(QUEUE_SIZE);
+        queue_config.used_ring = GuestAddress(SYSTEM_SPACE_SIZE - 2);
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert_eq!(queue.is_valid(&sys_space), false);
+    }
+
+    #[test]
+    fn test_packed_pop_avail_and_add_used() {
+        let sys_space = address_space_init();
+        let queue_size = 4_u16;
+        let mut vring = PackedVring::new(packed_queue_config(queue_size));
+        assert_eq!(vring.is_valid(&sys_space), true);
+        let features = 1 << VIRTIO_F_RING_EVENT_IDX as u64;
+
+        // it is empty when no descriptor is available
+        assert!(vring.pop_avail(&sys_space, features).is_err());
+
+        // a chain of two descriptors with buffer id 3
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(0),
+            0x2000,
+            16,
+            0,
+            avail_flags(true) | VIRTQ_DESC_F_NEXT,
+        );
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(DESCRIPTOR_LEN),
+            0x3000,
+            32,
+            3,
+            avail_flags(true) | VIRTQ_DESC_F_WRITE,
+        );
+        let elem = vring.pop_avail(&sys_space, features).unwrap();
+        assert_eq!(elem.index, 3);
+        assert_eq!(elem.desc_num, 2);
+        assert_eq!(elem.out_iovec[0].addr, GuestAddress(0x2000));
+        assert_eq!(elem.in_iovec[0].len, 32);
+        assert_eq!(vring.next_avail, 2);
+        let event = sys_space
+            .read_object::<PackedVringEvent>(vring.device_event)
+            .unwrap();
+        assert_eq!(event.off_wrap, 2 | 1 << VRING_PACKED_EVENT_F_WRAP_CTR);
+        assert_eq!(event.flags, VRING_PACKED_EVENT_FLAG_DESC);
+
+        // an indirect descriptor with buffer id 1 wrapping the ring
+        set_packed_desc(&sys_space, GuestAddress(0x4000), 0x5000, 8, 0, 0);
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(0x4000 + DESCRIPTOR_LEN),
+            0x6000,
+            64,
+            0,
+            VIRTQ_DESC_F_WRITE,
+        );
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(2 * DESCRIPTOR_LEN),
+            0x4000,
+            2 * DESCRIPTOR_LEN as u32,
+            1,
+            avail_flags(true) | VIRTQ_DESC_F_INDIRECT,
+        );
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(3 * DESCRIPTOR_LEN),
+            0x7000,
+            16,
+            2,
+            avail_flags(true),
+        );
+        let elem = vring.pop_avail(&sys_space, features).unwrap();
+        assert_eq!(elem.index, 1);
+        assert_eq!(elem.desc_num, 2);
+        assert_eq!(elem.in_iovec[0].addr, GuestAddress(0x6000));
+
+        // push back and pop the indirect descriptor again
+        vring.push_back();
+        assert_eq!(vring.next_avail, 2);
+        let elem = vring.pop_avail(&sys_space, features).unwrap();
+        assert_eq!(elem.index, 1);
+
+        let elem = vring.pop_avail(&sys_space, features).unwrap();
+        assert_eq!(elem.index, 2);
+        assert_eq!(vring.next_avail, 0);
+        assert_eq!(vring.avail_wrap_counter, false);
+
+        // the descriptor is not available until the driver flips the wrap counter
+        assert!(vring.pop_avail(&sys_space, features).is_err());
+        set_packed_desc(
+            &sys_space,
+            GuestAddress(0),
+            0x2000,
+            16,
+            0,
+            avail_flags(false),
+        );
+        let elem = vring.pop_avail(&sys_space, features).unwrap();
+        assert_eq!(elem.index, 0);
+
+        // the used descriptors are written in order, skipping the chain length
+        assert!(vring.add_used(&sys_space, 3, 100).is_ok());
+        let desc = sys_space
+            .read_object::<PackedVringDesc>(GuestAddress(0))
+            .unwrap();
+        assert_eq!(desc.id, 3);
+        assert_eq!(desc.len, 100);
+        assert_eq!(
+            desc.flags,
+            VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED
+        );
+        assert_eq!(vring.next_used, 2);
+        assert!(vring.add_used(&sys_space, 1, 0).is_ok());
+        assert!(vring.add_used(&sys_space, 2, 0).is_ok());
+        assert_eq!(vring.next_used, 0);
+        assert_eq!(vring.used_wrap_counter, false);
+        assert!(vring.add_used(&sys_space, 0, 0).is_ok());
+        let desc = sys_space
+            .read_object::<PackedVringDesc>(GuestAddress(0))
+            .unwrap();
+        assert_eq!(desc.flags, 0);
+        assert!(vring.add_used(&sys_space, queue_size, 0).is_err());
+
+        // the wrap counters are saved in the queue config
+        let queue_config = vring.get_queue_config();
+        assert_eq!(queue_config.next_avail, 1 + queue_size);
+        assert_eq!(queue_config.next_used, 1 + queue_size);
+        let vring = PackedVring::new(queue_config);
+        assert_eq!(vring.next_avail, 1);
+        assert_eq!(vring.avail_wrap_counter, false);
+    }
+
+    #[test]
+    fn test_packed_should_notify() {
+        let sys_space = address_space_init();
+        let mut vring = PackedVring::new(packed_queue_config(QUEUE_SIZE));
+        assert_eq!(vring.is_valid(&sys_space), true);
+        let features = 1 << VIRTIO_F_RING_EVENT_IDX as u64;
+        let set_driver_event = |off_wrap: u16, flags: u16| {
+            let event = PackedVringEvent { off_wrap, flags };
+            sys_space
+                .write_object(&event, GuestAddress(QUEUE_SIZE as u64 * DESCRIPTOR_LEN))
+                .unwrap();
+        };
+
+        set_driver_event(0, VRING_PACKED_EVENT_FLAG_DISABLE);
+        assert_eq!(vring.should_notify(&sys_space, features), false);
+        set_driver_event(0, 0);
+        assert_eq!(vring.should_notify(&sys_space, features), true);
+
+        // it's true when (new - event_idx - Wrapping(1) < new -old)
+        let wrap = 1 << VRING_PACKED_EVENT_F_WRAP_CTR;
+        set_driver_event(6 | wrap, VRING_PACKED_EVENT_FLAG_DESC);
+        vring.last_signal_used = 5;
+        vring.next_used = 10;
+        assert_eq!(vring.should_notify(&sys_space, features), true);
+
+        // it's false when (new - event_idx - Wrapping(1) >= new -old)
+        set_driver_event(1 | wrap, VRING_PACKED_EVENT_FLAG_DESC);
+        vring.last_signal_used = 5;
+        assert_eq!(vring.should_notify(&sys_space, features), false);
+
+        // the event index of the last round of the ring is always passed
+        set_driver_event(6, VRING_PACKED_EVENT_FLAG_DESC);
+        vring.last_signal_used = 5;
+        assert_eq!(vring.should_notify(&sys_space, features), false);
+
+        // the flag of descriptor event is treated as enable without event idx
+        set_driver_event(1 | wrap, VRING_PACKED_EVENT_FLAG_DESC);
+        assert_eq!(vring.should_notify(&sys_space, 0), true);
+    }
 }
@@ -32,8 +32,8 @@ use vmm_sys_util::eventfd::EventFd;
 
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    ElemIovec, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_VERSION_1,
-    VIRTIO_TYPE_RNG,
+    ElemIovec, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_PACKED,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_RNG,
 };
 
 const QUEUE_NUM_RNG: usize = 1;
@@ -298,7 +298,8 @@ impl VirtioDevice for Rng {
             .chain_err(|| "Failed to open file of random number generator")?;
 
         self.random_file = Some(file);
-        self.state.device_features = 1 << VIRTIO_F_VERSION_1 as u64;
+        self.state.device_features =
+            1 << VIRTIO_F_VERSION_1 as u64 | 1 << VIRTIO_F_RING_PACKED as u64;
         Ok(())
     }
 
@@ -18,6 +18,7 @@ use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use machine_manager::{config::NetworkInterfaceConfig, event_loop::EventLoop};
+use migration::MigrationManager;
 use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
 use util::num_ops::{read_u32, write_u32};
@@ -32,8 +33,8 @@ use super::super::super::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
     VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_TYPE_NET,
 };
-use super::super::{VhostNotify, VhostOps};
-use super::{VhostBackend, VhostIoHandler, VhostVringFile, VHOST_NET_SET_BACKEND};
+use super::super::{VhostIoHandler, VhostNotify, VhostOps, VHOST_MIGRATION_BLOCKER};
+use super::{VhostBackend, VhostVringFile, VHOST_NET_SET_BACKEND};
 
 /// Number of virtqueues.
 const QUEUE_NUM_NET: usize = 2;
@@ -141,15 +142,23 @@ impl VirtioDevice for Net {
             _ => Some(self.net_cfg.host_dev_name.as_str()),
         };
 
-        self.tap = create_tap(self.net_cfg.tap_fd, host_dev_name)
-            .chain_err(|| "Failed to create tap for vhost net")?;
+        self.tap = create_tap(self.net_cfg.tap_fd, host_dev_name, 1)
+            .chain_err(|| "Failed to create tap for vhost net")?
+            .map(|mut taps| taps.remove(0));
         self.backend = Some(backend);
         self.device_features = device_features;
         self.vhost_features = vhost_features;
+        MigrationManager::register_blocker(&self.net_cfg.id, VHOST_MIGRATION_BLOCKER, false);
 
         Ok(())
     }
 
+    /// Unrealize vhost-net device.
+    fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::unregister_blocker(&self.net_cfg.id);
+        Ok(())
+    }
+
     /// Get the virtio device type, refer to Virtio Spec.
     fn device_type(&self) -> u32 {
         VIRTIO_TYPE_NET
@@ -372,6 +381,8 @@ mod tests {
             tap_fd: Some(4),
             vhost_fd: Some(5),
             iothread: None,
+            queues: 1,
+            socket_path: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -390,6 +401,8 @@ mod tests {
             tap_fd: None,
             vhost_fd: None,
             iothread: None,
+            queues: 1,
+            socket_path: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);
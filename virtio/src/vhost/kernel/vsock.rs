@@ -27,8 +27,8 @@ use super::super::super::errors::{ErrorKind, Result, ResultExt};
 use super::super::super::{
     Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_TYPE_VSOCK,
 };
-use super::super::{VhostNotify, VhostOps};
-use super::{VhostBackend, VhostIoHandler, VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
+use super::super::{VhostIoHandler, VhostNotify, VhostOps};
+use super::{VhostBackend, VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
 
 /// Number of virtqueues.
 const QUEUE_NUM_VSOCK: usize = 3;
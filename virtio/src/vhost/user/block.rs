@@ -0,0 +1,317 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::AddressSpace;
+use machine_manager::config::VhostUserBlkDevConfig;
+use migration::MigrationManager;
+use util::num_ops::read_u32;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::super::errors::{ErrorKind, Result, ResultExt};
+use super::super::super::{
+    Queue, VirtioDevice, VirtioInterrupt, VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_CONFIG_WCE,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_GEOMETRY, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_TOPOLOGY,
+    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
+};
+use super::super::{VhostOps, VHOST_MIGRATION_BLOCKER};
+use super::{
+    read_config, VhostUserClient, VhostUserCommon, VHOST_USER_F_PROTOCOL_FEATURES,
+    VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_MQ,
+};
+
+/// Size of each virtqueue.
+const QUEUE_SIZE_BLK: u16 = 256;
+/// Size of the configuration space of virtio block device.
+const CONFIG_SPACE_SIZE: usize = 60;
+/// Offset of `num_queues` in the configuration space.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+/// Offset of `wce` in the configuration space.
+const CONFIG_WCE_OFFSET: u64 = 32;
+
+/// Block device structure with vhost-user backend.
+pub struct Block {
+    /// Configuration of the block device.
+    blk_cfg: VhostUserBlkDevConfig,
+    /// The client and features of the vhost-user backend.
+    common: VhostUserCommon,
+    /// Config space of the block device, which is read from the backend.
+    config_space: [u8; CONFIG_SPACE_SIZE],
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+}
+
+impl Block {
+    pub fn new(cfg: &VhostUserBlkDevConfig, mem_space: &Arc<AddressSpace>) -> Self {
+        Block {
+            blk_cfg: cfg.clone(),
+            common: VhostUserCommon::new("blk"),
+            config_space: [0_u8; CONFIG_SPACE_SIZE],
+            mem_space: mem_space.clone(),
+        }
+    }
+}
+
+impl VirtioDevice for Block {
+    /// Realize vhost-user virtio block device.
+    fn realize(&mut self) -> Result<()> {
+        let mut client = VhostUserClient::new(&self.mem_space, &self.blk_cfg.socket_path)
+            .chain_err(|| "Failed to create the client for vhost-user blk")?;
+        client
+            .set_owner()
+            .chain_err(|| "Failed to set owner for vhost-user blk")?;
+
+        let vhost_features = client
+            .get_features()
+            .chain_err(|| "Failed to get features for vhost-user blk")?;
+        if vhost_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            bail!("Vhost-user blk backend does not support protocol features");
+        }
+        let protocol_features = client
+            .get_protocol_features()
+            .chain_err(|| "Failed to get protocol features for vhost-user blk")?;
+        if protocol_features & (1 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            bail!("Vhost-user blk backend does not support getting config");
+        }
+        let mut acked_protocol_features = 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+
+        let queues = self.blk_cfg.queues;
+        if queues > 1 {
+            if protocol_features & (1 << VHOST_USER_PROTOCOL_F_MQ) == 0
+                || vhost_features & (1 << VIRTIO_BLK_F_MQ) == 0
+            {
+                bail!("Vhost-user blk backend does not support multi-queue");
+            }
+            acked_protocol_features |= 1 << VHOST_USER_PROTOCOL_F_MQ;
+        }
+        client
+            .set_protocol_features(acked_protocol_features)
+            .chain_err(|| "Failed to set protocol features for vhost-user blk")?;
+        if queues > 1 {
+            let max_queues = client
+                .get_queue_num()
+                .chain_err(|| "Failed to get queue num for vhost-user blk")?;
+            if u64::from(queues) > max_queues {
+                bail!(
+                    "The queue num {} exceeds the max {} of vhost-user blk backend",
+                    queues,
+                    max_queues
+                );
+            }
+        }
+
+        client
+            .get_config(&mut self.config_space)
+            .chain_err(|| "Failed to get config for vhost-user blk")?;
+        self.config_space[CONFIG_NUM_QUEUES_OFFSET..(CONFIG_NUM_QUEUES_OFFSET + 2)]
+            .copy_from_slice(&queues.to_le_bytes());
+
+        let mut device_features = vhost_features
+            & (1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_F_RING_INDIRECT_DESC
+                | 1 << VIRTIO_F_RING_EVENT_IDX
+                | 1 << VIRTIO_BLK_F_SIZE_MAX
+                | 1 << VIRTIO_BLK_F_SEG_MAX
+                | 1 << VIRTIO_BLK_F_GEOMETRY
+                | 1 << VIRTIO_BLK_F_RO
+                | 1 << VIRTIO_BLK_F_BLK_SIZE
+                | 1 << VIRTIO_BLK_F_FLUSH
+                | 1 << VIRTIO_BLK_F_TOPOLOGY
+                | 1 << VIRTIO_BLK_F_CONFIG_WCE
+                | 1 << VIRTIO_BLK_F_DISCARD
+                | 1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        if queues > 1 {
+            device_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+
+        self.common.client = Some(Arc::new(Mutex::new(client)));
+        self.common.device_features = device_features;
+        self.common.vhost_features = vhost_features;
+        MigrationManager::register_blocker(&self.blk_cfg.id, VHOST_MIGRATION_BLOCKER, false);
+
+        Ok(())
+    }
+
+    /// Unrealize vhost-user blk device.
+    fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::unregister_blocker(&self.blk_cfg.id);
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_BLOCK
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        self.blk_cfg.queues as usize
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_BLK
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.common.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.common.set_driver_features(page, value);
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config(&self.config_space, offset, data)
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let data_len = data.len();
+        let config_len = self.config_space.len();
+        if offset as usize + data_len > config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len as u64).into());
+        }
+
+        self.config_space[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+
+        // Only the writeback mode is writable, which is forwarded to the backend.
+        if offset == CONFIG_WCE_OFFSET && data_len == 1 {
+            if let Some(client) = &self.common.client {
+                client
+                    .lock()
+                    .unwrap()
+                    .set_config(offset as u32, data)
+                    .chain_err(|| "Failed to set config for vhost-user blk")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        _mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.common.activate(interrupt_cb, queues, queue_evts)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.common.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::super::tests::{address_space_init, fake_backend, FAKE_BACKEND_QUEUE_NUM};
+    use super::*;
+
+    const BACKEND_FEATURES: u64 = 1 << VIRTIO_F_VERSION_1
+        | 1 << VIRTIO_BLK_F_FLUSH
+        | 1 << VIRTIO_BLK_F_MQ
+        | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+    const DISK_SECTORS: u64 = 0x2000;
+
+    /// Config space of the fake backend with the capacity set.
+    fn backend_config() -> Vec<u8> {
+        let mut config = vec![0_u8; CONFIG_SPACE_SIZE];
+        config[..8].copy_from_slice(&DISK_SECTORS.to_le_bytes());
+        config
+    }
+
+    #[test]
+    fn test_vhost_user_blk_realize() {
+        let path = "/tmp/test_vhost_user_blk.sock";
+        let _ = std::fs::remove_file(path);
+        let mem_space = address_space_init(true);
+        let mut blk_cfg = VhostUserBlkDevConfig {
+            id: "blk0".to_string(),
+            socket_path: path.to_string(),
+            queues: 2,
+        };
+
+        // The backend does not support getting config.
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = fake_backend(
+            listener,
+            BACKEND_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ,
+            backend_config(),
+        );
+        let mut block = Block::new(&blk_cfg, &mem_space);
+        assert!(block.realize().is_err());
+        drop(block);
+        backend.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The queue num exceeds the max of the backend.
+        blk_cfg.queues = FAKE_BACKEND_QUEUE_NUM as u16 + 1;
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = fake_backend(
+            listener,
+            BACKEND_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG,
+            backend_config(),
+        );
+        let mut block = Block::new(&blk_cfg, &mem_space);
+        assert!(block.realize().is_err());
+        drop(block);
+        backend.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        blk_cfg.queues = 2;
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = fake_backend(
+            listener,
+            BACKEND_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG,
+            backend_config(),
+        );
+        let mut block = Block::new(&blk_cfg, &mem_space);
+        assert!(block.realize().is_ok());
+        assert_eq!(block.queue_num(), 2);
+        assert_eq!(
+            block.common.device_features,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_BLK_F_FLUSH | 1 << VIRTIO_BLK_F_MQ
+        );
+
+        // The capacity is read from the backend.
+        let mut capacity = [0_u8; 8];
+        assert!(block.read_config(0, &mut capacity).is_ok());
+        assert_eq!(u64::from_le_bytes(capacity), DISK_SECTORS);
+        let mut num_queues = [0_u8; 2];
+        assert!(block
+            .read_config(CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues)
+            .is_ok());
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+        assert!(block
+            .read_config(CONFIG_SPACE_SIZE as u64, &mut num_queues)
+            .is_err());
+
+        drop(block);
+        backend.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
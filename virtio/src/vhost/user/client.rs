@@ -0,0 +1,747 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use address_space::{
+    AddressSpace, FileBackend, FlatRange, GuestAddress, Listener, ListenerReqType, RegionIoEventFd,
+    RegionType,
+};
+use machine_manager::event_loop::EventLoop;
+use util::byte_code::ByteCode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::unix::UnixSock;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::super::errors::{ErrorKind, Result, ResultExt};
+use super::super::super::{Queue, QueueConfig};
+use super::super::VhostOps;
+use super::message::{
+    RegionMemInfo, VhostUserConfig, VhostUserMemHdr, VhostUserMsgHdr, VhostUserMsgReq,
+    VhostUserVringAddr, VhostUserVringState, VHOST_USER_F_PROTOCOL_FEATURES,
+    VHOST_USER_MAX_MEMORY_REGIONS, VHOST_USER_MAX_PAYLOAD_SIZE, VHOST_USER_VRING_IDX_MASK,
+};
+use crate::error_chain::ChainedError;
+
+/// Interval of trying to reconnect the vhost-user backend in nanoseconds.
+const VHOST_USER_RECONNECT_INTERVAL: u64 = 1_000_000_000;
+/// Offset of the index in the used ring.
+const VRING_USED_IDX_OFFSET: u64 = 2;
+
+#[derive(Clone)]
+struct VhostUserMemRegionInfo {
+    region: RegionMemInfo,
+    file_back: FileBackend,
+}
+
+#[derive(Clone)]
+struct VhostUserMemInfo {
+    regions: Arc<Mutex<Vec<VhostUserMemRegionInfo>>>,
+}
+
+impl VhostUserMemInfo {
+    fn new() -> Self {
+        VhostUserMemInfo {
+            regions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn addr_to_host(&self, addr: GuestAddress) -> Option<u64> {
+        let addr = addr.raw_value();
+        for reg_info in self.regions.lock().unwrap().iter() {
+            let region = &reg_info.region;
+            if addr >= region.guest_phys_addr && addr < region.guest_phys_addr + region.memory_size
+            {
+                let offset = addr - region.guest_phys_addr;
+                return Some(region.userspace_addr + offset);
+            }
+        }
+        None
+    }
+
+    fn add_mem_range(&self, fr: &FlatRange) -> address_space::errors::Result<()> {
+        let guest_phys_addr = fr.addr_range.base.raw_value();
+        let memory_size = fr.addr_range.size;
+        let host_address = match fr.owner.get_host_address() {
+            Some(addr) => addr,
+            None => bail!("Failed to get host address of memory region for vhost-user"),
+        };
+        // The backend maps the guest memory with the fd passed, so the memory
+        // must be backed by a shared file, that is `mem-share` must be on.
+        let file_back = match fr.owner.get_file_backend() {
+            Some(file_back) => file_back,
+            None => bail!("Vhost-user requires the guest memory to be shared, set mem-share=on"),
+        };
+
+        self.regions.lock().unwrap().push(VhostUserMemRegionInfo {
+            region: RegionMemInfo {
+                guest_phys_addr,
+                memory_size,
+                userspace_addr: host_address + fr.offset_in_region,
+                mmap_offset: file_back.offset + fr.offset_in_region,
+            },
+            file_back,
+        });
+
+        Ok(())
+    }
+
+    fn delete_mem_range(&self, fr: &FlatRange) {
+        let mut mem_regions = self.regions.lock().unwrap();
+        let host_address = match fr.owner.get_host_address() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let target = RegionMemInfo {
+            guest_phys_addr: fr.addr_range.base.raw_value(),
+            memory_size: fr.addr_range.size,
+            userspace_addr: host_address + fr.offset_in_region,
+            mmap_offset: 0,
+        };
+        for (index, reg_info) in mem_regions.iter().enumerate() {
+            let region = &reg_info.region;
+            if region.guest_phys_addr == target.guest_phys_addr
+                && region.memory_size == target.memory_size
+                && region.userspace_addr == target.userspace_addr
+            {
+                mem_regions.remove(index);
+                return;
+            }
+        }
+        debug!("Vhost-user: deleting mem region failed: not matched");
+    }
+}
+
+impl Listener for VhostUserMemInfo {
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    fn handle_request(
+        &self,
+        range: Option<&FlatRange>,
+        _evtfd: Option<&RegionIoEventFd>,
+        req_type: ListenerReqType,
+    ) -> std::result::Result<(), address_space::errors::Error> {
+        match req_type {
+            ListenerReqType::AddRegion => {
+                let fr = range.unwrap();
+                if fr.owner.region_type() == RegionType::Ram {
+                    self.add_mem_range(fr)?;
+                }
+            }
+            ListenerReqType::DeleteRegion => {
+                let fr = range.unwrap();
+                if fr.owner.region_type() == RegionType::Ram {
+                    self.delete_mem_range(fr);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The master of the vhost-user protocol, which communicates with the
+/// backend (slave) over an unix socket.
+pub struct VhostUserClient {
+    /// The socket connected to the backend.
+    sock: UnixSock,
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+    /// Memory layout of the guest, sent to the backend.
+    mem_info: VhostUserMemInfo,
+    /// Virtio features negotiated with the backend.
+    pub features: u64,
+    /// Protocol features negotiated with the backend.
+    protocol_features: u64,
+    /// Virtqueues of the device, used to restore the vrings after reconnection.
+    queues: Vec<Arc<Mutex<Queue>>>,
+    /// EventFds signaled by the guest when buffers are available.
+    queue_evts: Vec<EventFd>,
+    /// EventFds signaled by the backend when buffers are used.
+    call_evts: Vec<EventFd>,
+    /// EventFd to trigger reconnecting the backend.
+    reconnect_evt: EventFd,
+    /// The backend is disconnected and waiting for reconnection.
+    reconnecting: bool,
+}
+
+impl VhostUserClient {
+    pub fn new(mem_space: &Arc<AddressSpace>, path: &str) -> Result<Self> {
+        let mut sock = UnixSock::new(path);
+        sock.connect()
+            .chain_err(|| format!("Failed to connect the vhost-user backend {}", path))?;
+
+        let mem_info = VhostUserMemInfo::new();
+        mem_space.register_listener(Box::new(mem_info.clone()))?;
+
+        Ok(VhostUserClient {
+            sock,
+            mem_space: mem_space.clone(),
+            mem_info,
+            features: 0,
+            protocol_features: 0,
+            queues: Vec::new(),
+            queue_evts: Vec::new(),
+            call_evts: Vec::new(),
+            reconnect_evt: EventFd::new(libc::EFD_NONBLOCK)
+                .chain_err(|| ErrorKind::EventFdCreate)?,
+            reconnecting: false,
+        })
+    }
+
+    /// Save the virtqueues and the related eventfds of the device, which
+    /// are sent to the backend in `activate_vhost_user`.
+    ///
+    /// # Arguments
+    ///
+    /// * `queues` - Virtqueues of the device.
+    /// * `queue_evts` - EventFds signaled by the guest.
+    /// * `call_evts` - EventFds signaled by the backend.
+    pub fn set_queues(
+        &mut self,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<EventFd>,
+        call_evts: Vec<EventFd>,
+    ) {
+        self.queues = queues.to_vec();
+        self.queue_evts = queue_evts;
+        self.call_evts = call_evts;
+    }
+
+    fn send_request(&self, request: VhostUserMsgReq, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        let hdr = VhostUserMsgHdr::new(request as u32, 0, body.len() as u32);
+        let mut iovecs = vec![libc::iovec {
+            iov_base: hdr.as_bytes().as_ptr() as *mut libc::c_void,
+            iov_len: hdr.as_bytes().len(),
+        }];
+        if !body.is_empty() {
+            iovecs.push(libc::iovec {
+                iov_base: body.as_ptr() as *mut libc::c_void,
+                iov_len: body.len(),
+            });
+        }
+
+        let len = self
+            .sock
+            .send_msg(&mut iovecs, fds)
+            .chain_err(|| format!("Failed to send vhost-user request {:?}", request))?;
+        if len != hdr.as_bytes().len() + body.len() {
+            bail!(
+                "Failed to send vhost-user request {:?}, only {} bytes sent",
+                request,
+                len
+            );
+        }
+        Ok(())
+    }
+
+    fn recv_reply(&self, request: VhostUserMsgReq, body: &mut [u8]) -> Result<()> {
+        let mut hdr = VhostUserMsgHdr::default();
+        let mut iovecs = [libc::iovec {
+            iov_base: hdr.as_mut_bytes().as_mut_ptr() as *mut libc::c_void,
+            iov_len: std::mem::size_of::<VhostUserMsgHdr>(),
+        }];
+        let (len, _) = self
+            .sock
+            .recv_msg(&mut iovecs, &mut [])
+            .chain_err(|| format!("Failed to recv reply of vhost-user request {:?}", request))?;
+        if len != std::mem::size_of::<VhostUserMsgHdr>()
+            || !hdr.is_reply_valid(request as u32)
+            || hdr.size as usize != body.len()
+        {
+            bail!(
+                "Invalid reply of vhost-user request {:?}, request {} flags 0x{:x} size {}",
+                request,
+                hdr.request,
+                hdr.flags,
+                hdr.size
+            );
+        }
+
+        let mut iovecs = [libc::iovec {
+            iov_base: body.as_mut_ptr() as *mut libc::c_void,
+            iov_len: body.len(),
+        }];
+        let (len, _) = self
+            .sock
+            .recv_msg(&mut iovecs, &mut [])
+            .chain_err(|| format!("Failed to recv reply of vhost-user request {:?}", request))?;
+        if len != body.len() {
+            bail!(
+                "Invalid reply size {} of vhost-user request {:?}",
+                len,
+                request
+            );
+        }
+        Ok(())
+    }
+
+    fn get_u64(&self, request: VhostUserMsgReq) -> Result<u64> {
+        self.send_request(request, &[], &[])?;
+        let mut value = 0_u64;
+        self.recv_reply(request, value.as_mut_bytes())?;
+        Ok(value)
+    }
+
+    fn set_vring_fd(&self, request: VhostUserMsgReq, queue_idx: usize, fd: &EventFd) -> Result<()> {
+        let index = queue_idx as u64 & VHOST_USER_VRING_IDX_MASK;
+        self.send_request(request, index.as_bytes(), &[fd.as_raw_fd()])
+    }
+
+    /// Get the protocol features supported by the backend.
+    pub fn get_protocol_features(&self) -> Result<u64> {
+        self.get_u64(VhostUserMsgReq::GetProtocolFeatures)
+    }
+
+    /// Set the protocol features acknowledged by the master.
+    ///
+    /// # Arguments
+    /// * `features` - Bitmask of the protocol features to set.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(
+            VhostUserMsgReq::SetProtocolFeatures,
+            features.as_bytes(),
+            &[],
+        )?;
+        self.protocol_features = features;
+        Ok(())
+    }
+
+    /// Enable or disable the vring, only valid if VHOST_USER_F_PROTOCOL_FEATURES
+    /// has been negotiated.
+    ///
+    /// # Arguments
+    /// * `queue_idx` - Index of the queue to modify.
+    /// * `status` - Enable the vring if true.
+    pub fn set_vring_enable(&self, queue_idx: usize, status: bool) -> Result<()> {
+        let state = VhostUserVringState::new(queue_idx as u32, status as u32);
+        self.send_request(VhostUserMsgReq::SetVringEnable, state.as_bytes(), &[])
+    }
+
+    /// Set the socket through which the backend sends requests to the master,
+    /// only valid if VHOST_USER_PROTOCOL_F_SLAVE_REQ has been negotiated.
+    ///
+    /// # Arguments
+    /// * `fd` - One end of the connected sockets, passed to the backend.
+    pub fn set_slave_req_fd(&self, fd: RawFd) -> Result<()> {
+        self.send_request(VhostUserMsgReq::SetSlaveReqFd, &[], &[fd])
+    }
+
+    /// Get the max count of queues supported by the backend, only valid if
+    /// VHOST_USER_PROTOCOL_F_MQ has been negotiated.
+    pub fn get_queue_num(&self) -> Result<u64> {
+        self.get_u64(VhostUserMsgReq::GetQueueNum)
+    }
+
+    /// Get the device configuration space from the backend, only valid if
+    /// VHOST_USER_PROTOCOL_F_CONFIG has been negotiated.
+    ///
+    /// # Arguments
+    /// * `config` - Buffer of the configuration space, whose length is the size to get.
+    pub fn get_config(&self, config: &mut [u8]) -> Result<()> {
+        let size = std::mem::size_of::<VhostUserConfig>() + config.len();
+        if size > VHOST_USER_MAX_PAYLOAD_SIZE {
+            bail!(
+                "The size {} of vhost-user config is too large",
+                config.len()
+            );
+        }
+        let mut body = vec![0_u8; size];
+        let hdr = VhostUserConfig {
+            offset: 0,
+            size: config.len() as u32,
+            flags: 0,
+        };
+        body[..std::mem::size_of::<VhostUserConfig>()].copy_from_slice(hdr.as_bytes());
+        self.send_request(VhostUserMsgReq::GetConfig, &body, &[])?;
+        self.recv_reply(VhostUserMsgReq::GetConfig, &mut body)?;
+        config.copy_from_slice(&body[std::mem::size_of::<VhostUserConfig>()..]);
+        Ok(())
+    }
+
+    /// Set the device configuration space of the backend, only valid if
+    /// VHOST_USER_PROTOCOL_F_CONFIG has been negotiated.
+    ///
+    /// # Arguments
+    /// * `offset` - Offset in the configuration space.
+    /// * `data` - Data to be written to the configuration space.
+    pub fn set_config(&self, offset: u32, data: &[u8]) -> Result<()> {
+        let size = std::mem::size_of::<VhostUserConfig>() + data.len();
+        if size > VHOST_USER_MAX_PAYLOAD_SIZE {
+            bail!("The size {} of vhost-user config is too large", data.len());
+        }
+        let mut body = vec![0_u8; size];
+        let hdr = VhostUserConfig {
+            offset,
+            size: data.len() as u32,
+            flags: 0,
+        };
+        body[..std::mem::size_of::<VhostUserConfig>()].copy_from_slice(hdr.as_bytes());
+        body[std::mem::size_of::<VhostUserConfig>()..].copy_from_slice(data);
+        self.send_request(VhostUserMsgReq::SetConfig, &body, &[])
+    }
+
+    /// Send the features, the memory table and the vrings saved by
+    /// `set_queues` to the backend, this is also used to restore the backend
+    /// after reconnection.
+    pub fn activate_vhost_user(&mut self) -> Result<()> {
+        self.set_features(self.features)
+            .chain_err(|| "Failed to set features for vhost-user")?;
+        self.set_mem_table()
+            .chain_err(|| "Failed to set mem table for vhost-user")?;
+
+        for (queue_index, queue_mutex) in self.queues.iter().enumerate() {
+            let queue = queue_mutex.lock().unwrap();
+            let actual_size = queue.vring.actual_size();
+            let queue_config = queue.vring.get_queue_config();
+            drop(queue);
+
+            // The backend resumes processing from the used index, the requests
+            // which are in flight when the backend disconnects are dropped.
+            let last_avail_idx = self
+                .mem_space
+                .read_object::<u16>(GuestAddress(
+                    queue_config.used_ring.raw_value() + VRING_USED_IDX_OFFSET,
+                ))
+                .chain_err(|| "Failed to read used index of vring for vhost-user")?;
+
+            self.set_vring_num(queue_index, actual_size).chain_err(|| {
+                format!(
+                    "Failed to set vring num for vhost-user, index: {} size: {}",
+                    queue_index, actual_size,
+                )
+            })?;
+            self.set_vring_addr(&queue_config, queue_index, 0)
+                .chain_err(|| {
+                    format!(
+                        "Failed to set vring addr for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+            self.set_vring_base(queue_index, last_avail_idx)
+                .chain_err(|| {
+                    format!(
+                        "Failed to set vring base for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+            self.set_vring_kick(queue_index, &self.queue_evts[queue_index])
+                .chain_err(|| {
+                    format!(
+                        "Failed to set vring kick for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+            self.set_vring_call(queue_index, &self.call_evts[queue_index])
+                .chain_err(|| {
+                    format!(
+                        "Failed to set vring call for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+        }
+
+        // The vrings are initialized in disabled state if
+        // VHOST_USER_F_PROTOCOL_FEATURES has been negotiated.
+        if self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            for queue_index in 0..self.queues.len() {
+                self.set_vring_enable(queue_index, true).chain_err(|| {
+                    format!(
+                        "Failed to enable vring for vhost-user, index: {}",
+                        queue_index,
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop the vrings of the backend.
+    pub fn reset_vhost_user(&mut self) -> Result<()> {
+        if self.reconnecting {
+            return Ok(());
+        }
+
+        for queue_index in 0..self.queues.len() {
+            self.get_vring_base(queue_index).chain_err(|| {
+                format!(
+                    "Failed to get vring base for vhost-user, index: {}",
+                    queue_index,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Get the notifiers to remove the events of the client from the `EventLoop`.
+    pub fn delete_event(&self) -> Vec<EventNotifier> {
+        let mut notifiers = vec![EventNotifier::new(
+            NotifierOperation::Delete,
+            self.reconnect_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            Vec::new(),
+        )];
+        if !self.reconnecting {
+            if let Some(fd) = self.sock.get_stream_raw_fd() {
+                notifiers.push(EventNotifier::new(
+                    NotifierOperation::Delete,
+                    fd,
+                    None,
+                    EventSet::HANG_UP,
+                    Vec::new(),
+                ));
+            }
+        }
+        notifiers
+    }
+
+    fn sock_notifier(client: Arc<Mutex<Self>>, sock_fd: RawFd) -> EventNotifier {
+        let handler: Box<NotifierCallback> = Box::new(move |event, fd: RawFd| {
+            if event & EventSet::HANG_UP != EventSet::HANG_UP {
+                return None;
+            }
+
+            let mut locked_client = client.lock().unwrap();
+            warn!(
+                "Vhost-user backend {} is disconnected, try to reconnect it",
+                locked_client.sock.path
+            );
+            locked_client.reconnecting = true;
+            locked_client.delay_reconnect(0);
+
+            Some(vec![EventNotifier::new(
+                NotifierOperation::Delete,
+                fd,
+                None,
+                EventSet::HANG_UP,
+                Vec::new(),
+            )])
+        });
+
+        EventNotifier::new(
+            NotifierOperation::AddShared,
+            sock_fd,
+            None,
+            EventSet::HANG_UP,
+            vec![Arc::new(Mutex::new(handler))],
+        )
+    }
+
+    fn delay_reconnect(&self, nsec: u64) {
+        let reconnect_evt = match self.reconnect_evt.try_clone() {
+            Ok(evt) => evt,
+            Err(e) => {
+                error!("Failed to clone reconnect eventfd for vhost-user, {}", e);
+                return;
+            }
+        };
+        let func = Box::new(move || {
+            if let Err(e) = reconnect_evt.write(1) {
+                error!("Failed to trigger reconnection of vhost-user, {}", e);
+            }
+        });
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.delay_call(func, nsec);
+        }
+    }
+
+    fn restore_vhost_user(&mut self) -> Result<()> {
+        self.set_owner()
+            .chain_err(|| "Failed to set owner for vhost-user")?;
+        if self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            let protocol_features = self.protocol_features;
+            self.set_protocol_features(protocol_features)
+                .chain_err(|| "Failed to set protocol features for vhost-user")?;
+        }
+        self.activate_vhost_user()
+    }
+
+    fn reconnect(&mut self) -> bool {
+        if let Err(e) = self.sock.connect() {
+            debug!(
+                "Failed to reconnect vhost-user backend, {}",
+                e.display_chain()
+            );
+            return false;
+        }
+
+        if let Err(e) = self.restore_vhost_user() {
+            error!(
+                "Failed to restore vhost-user backend after reconnection, {}",
+                e.display_chain()
+            );
+            return false;
+        }
+
+        info!("Vhost-user backend {} is reconnected", self.sock.path);
+        self.reconnecting = false;
+        true
+    }
+}
+
+impl EventNotifierHelper for VhostUserClient {
+    fn internal_notifiers(client_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_client = client_handler.lock().unwrap();
+
+        if let Some(fd) = locked_client.sock.get_stream_raw_fd() {
+            notifiers.push(Self::sock_notifier(client_handler.clone(), fd));
+        }
+
+        let client = client_handler.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+
+            let mut locked_client = client.lock().unwrap();
+            if !locked_client.reconnecting {
+                return None;
+            }
+            if !locked_client.reconnect() {
+                locked_client.delay_reconnect(VHOST_USER_RECONNECT_INTERVAL);
+                return None;
+            }
+
+            locked_client
+                .sock
+                .get_stream_raw_fd()
+                .map(|sock_fd| vec![Self::sock_notifier(client.clone(), sock_fd)])
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            locked_client.reconnect_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        ));
+
+        notifiers
+    }
+}
+
+impl VhostOps for VhostUserClient {
+    fn set_owner(&self) -> Result<()> {
+        self.send_request(VhostUserMsgReq::SetOwner, &[], &[])
+    }
+
+    fn reset_owner(&self) -> Result<()> {
+        self.send_request(VhostUserMsgReq::ResetOwner, &[], &[])
+    }
+
+    fn get_features(&self) -> Result<u64> {
+        self.get_u64(VhostUserMsgReq::GetFeatures)
+    }
+
+    fn set_features(&self, features: u64) -> Result<()> {
+        self.send_request(VhostUserMsgReq::SetFeatures, features.as_bytes(), &[])
+    }
+
+    fn set_mem_table(&self) -> Result<()> {
+        let regions = self.mem_info.regions.lock().unwrap();
+        if regions.len() > VHOST_USER_MAX_MEMORY_REGIONS {
+            bail!(
+                "Too many memory regions {} for vhost-user, the max is {}",
+                regions.len(),
+                VHOST_USER_MAX_MEMORY_REGIONS
+            );
+        }
+
+        let hdr_size = std::mem::size_of::<VhostUserMemHdr>();
+        let region_size = std::mem::size_of::<RegionMemInfo>();
+        let mut body = vec![0_u8; hdr_size + regions.len() * region_size];
+        body[..hdr_size].copy_from_slice(
+            VhostUserMemHdr {
+                nregions: regions.len() as u32,
+                padding: 0,
+            }
+            .as_bytes(),
+        );
+
+        let mut fds = Vec::new();
+        for (index, reg_info) in regions.iter().enumerate() {
+            let start = hdr_size + index * region_size;
+            body[start..(start + region_size)].copy_from_slice(reg_info.region.as_bytes());
+            fds.push(reg_info.file_back.file.as_raw_fd());
+        }
+
+        self.send_request(VhostUserMsgReq::SetMemTable, &body, &fds)
+    }
+
+    fn set_vring_num(&self, queue_idx: usize, num: u16) -> Result<()> {
+        let state = VhostUserVringState::new(queue_idx as u32, u32::from(num));
+        self.send_request(VhostUserMsgReq::SetVringNum, state.as_bytes(), &[])
+    }
+
+    fn set_vring_addr(&self, queue: &QueueConfig, index: usize, flags: u32) -> Result<()> {
+        let desc_user_addr = self
+            .mem_info
+            .addr_to_host(queue.desc_table)
+            .ok_or_else(|| {
+                ErrorKind::Msg(format!(
+                    "Failed to transform desc-table address {}",
+                    queue.desc_table.0
+                ))
+            })?;
+        let used_user_addr = self.mem_info.addr_to_host(queue.used_ring).ok_or_else(|| {
+            ErrorKind::Msg(format!(
+                "Failed to transform used ring address {}",
+                queue.used_ring.0
+            ))
+        })?;
+        let avail_user_addr = self
+            .mem_info
+            .addr_to_host(queue.avail_ring)
+            .ok_or_else(|| {
+                ErrorKind::Msg(format!(
+                    "Failed to transform avail ring address {}",
+                    queue.avail_ring.0
+                ))
+            })?;
+
+        let vring_addr = VhostUserVringAddr {
+            index: index as u32,
+            flags,
+            desc_user_addr,
+            used_user_addr,
+            avail_user_addr,
+            log_guest_addr: 0_u64,
+        };
+        self.send_request(VhostUserMsgReq::SetVringAddr, vring_addr.as_bytes(), &[])
+    }
+
+    fn set_vring_base(&self, queue_idx: usize, last_avail_idx: u16) -> Result<()> {
+        let state = VhostUserVringState::new(queue_idx as u32, u32::from(last_avail_idx));
+        self.send_request(VhostUserMsgReq::SetVringBase, state.as_bytes(), &[])
+    }
+
+    fn get_vring_base(&self, queue_idx: usize) -> Result<u16> {
+        let mut state = VhostUserVringState::new(queue_idx as u32, 0);
+        self.send_request(VhostUserMsgReq::GetVringBase, state.as_bytes(), &[])?;
+        self.recv_reply(VhostUserMsgReq::GetVringBase, state.as_mut_bytes())?;
+        Ok(state.value as u16)
+    }
+
+    fn set_vring_call(&self, queue_idx: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_fd(VhostUserMsgReq::SetVringCall, queue_idx, fd)
+    }
+
+    fn set_vring_kick(&self, queue_idx: usize, fd: &EventFd) -> Result<()> {
+        self.set_vring_fd(VhostUserMsgReq::SetVringKick, queue_idx, fd)
+    }
+}
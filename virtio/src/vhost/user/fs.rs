@@ -0,0 +1,660 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+use error_chain::ChainedError;
+use machine_manager::{config::FsConfig, event_loop::EventLoop};
+use migration::MigrationManager;
+use util::byte_code::ByteCode;
+use util::loop_context::{EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation};
+use util::num_ops::read_u32;
+use util::unix::UnixSock;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::super::errors::{Result, ResultExt};
+use super::super::super::{
+    Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_FS,
+};
+use super::super::{VhostOps, VHOST_MIGRATION_BLOCKER};
+use super::{
+    read_config, VhostUserClient, VhostUserCommon, VhostUserFsSlaveMsg, VhostUserMsgHdr,
+    VhostUserSlaveMsgReq, VHOST_USER_FS_FLAG_MAP_R, VHOST_USER_FS_FLAG_MAP_W,
+    VHOST_USER_FS_SLAVE_ENTRIES, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_NEED_REPLY_MASK,
+    VHOST_USER_PROTOCOL_F_SLAVE_REQ, VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD, VHOST_USER_REPLY_MASK,
+};
+
+/// Number of virtqueues, one high priority queue and one request queue.
+const QUEUE_NUM_FS: usize = 2;
+/// Size of each virtqueue.
+const QUEUE_SIZE_FS: u16 = 128;
+/// Max length of the mount tag.
+const FS_TAG_SIZE: usize = 36;
+/// Id of the DAX cache window in shared memory regions, refer to Virtio Spec.
+const VIRTIO_FS_SHMCAP_ID_CACHE: u8 = 0;
+
+/// Configuration space of virtio-fs device.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtioFsConfig {
+    /// Name of the file system, encoded in UTF-8 and padded with NUL bytes.
+    tag: [u8; FS_TAG_SIZE],
+    /// Number of request queues.
+    num_request_queues: u32,
+}
+
+impl Default for VirtioFsConfig {
+    fn default() -> Self {
+        VirtioFsConfig {
+            tag: [0_u8; FS_TAG_SIZE],
+            num_request_queues: 0,
+        }
+    }
+}
+
+impl ByteCode for VirtioFsConfig {}
+
+impl VirtioFsConfig {
+    fn new(tag: &str) -> Self {
+        let mut config = VirtioFsConfig {
+            num_request_queues: (QUEUE_NUM_FS - 1) as u32,
+            ..Default::default()
+        };
+        let len = cmp::min(tag.len(), FS_TAG_SIZE);
+        config.tag[..len].copy_from_slice(&tag.as_bytes()[..len]);
+        config
+    }
+}
+
+/// Map an anonymous and inaccessible range at `addr`, which replaces the former mapping.
+fn mmap_none(addr: u64, len: u64) -> Result<()> {
+    let ret = unsafe {
+        libc::mmap(
+            addr as *mut libc::c_void,
+            len as libc::size_t,
+            libc::PROT_NONE,
+            libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        bail!(
+            "Failed to mmap none for DAX cache window, {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Handler of the requests sent from virtiofsd through the slave channel, which maps
+/// ranges of files into the DAX cache window.
+struct FsSlaveHandler {
+    /// The master end of the slave channel.
+    sock: UnixSock,
+    /// The host mapping of the DAX cache window.
+    cache: Arc<HostMemMapping>,
+}
+
+impl FsSlaveHandler {
+    /// Check the range in the DAX cache window and return its host address.
+    fn cache_range(&self, offset: u64, len: u64) -> Result<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.cache.size() => Ok(self.cache.host_address() + offset),
+            _ => bail!(
+                "Range (0x{:x}, 0x{:x}) overflows the DAX cache window",
+                offset,
+                len
+            ),
+        }
+    }
+
+    fn fs_map(&self, msg: &VhostUserFsSlaveMsg, file: Option<&File>) -> Result<()> {
+        let fd = match file {
+            Some(file) => file.as_raw_fd(),
+            None => bail!("No fd received with FS_MAP request"),
+        };
+        for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
+            if msg.len[i] == 0 {
+                continue;
+            }
+            let addr = self.cache_range(msg.cache_offset[i], msg.len[i])?;
+            let mut prot = 0;
+            if msg.flags[i] & VHOST_USER_FS_FLAG_MAP_R != 0 {
+                prot |= libc::PROT_READ;
+            }
+            if msg.flags[i] & VHOST_USER_FS_FLAG_MAP_W != 0 {
+                prot |= libc::PROT_WRITE;
+            }
+            let ret = unsafe {
+                libc::mmap(
+                    addr as *mut libc::c_void,
+                    msg.len[i] as libc::size_t,
+                    prot,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    fd,
+                    msg.fd_offset[i] as libc::off_t,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                bail!(
+                    "Failed to map file to DAX cache window, {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn fs_unmap(&self, msg: &VhostUserFsSlaveMsg) -> Result<()> {
+        for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
+            if msg.len[i] == 0 {
+                continue;
+            }
+            // Length of all ones means the whole DAX cache window.
+            let (offset, len) = if msg.len[i] == u64::MAX {
+                (0, self.cache.size())
+            } else {
+                (msg.cache_offset[i], msg.len[i])
+            };
+            let addr = self.cache_range(offset, len)?;
+            mmap_none(addr, len)?;
+        }
+        Ok(())
+    }
+
+    fn fs_sync(&self, msg: &VhostUserFsSlaveMsg) -> Result<()> {
+        for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
+            if msg.len[i] == 0 {
+                continue;
+            }
+            let addr = self.cache_range(msg.cache_offset[i], msg.len[i])?;
+            let ret = unsafe {
+                libc::msync(
+                    addr as *mut libc::c_void,
+                    msg.len[i] as usize,
+                    libc::MS_SYNC,
+                )
+            };
+            if ret != 0 {
+                bail!(
+                    "Failed to sync DAX cache window, {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self) -> Result<()> {
+        let mut hdr = VhostUserMsgHdr::default();
+        let mut fds = [-1 as RawFd; VHOST_USER_FS_SLAVE_ENTRIES];
+        let mut iovecs = [libc::iovec {
+            iov_base: hdr.as_mut_bytes().as_mut_ptr() as *mut libc::c_void,
+            iov_len: std::mem::size_of::<VhostUserMsgHdr>(),
+        }];
+        let (len, fd_num) = self
+            .sock
+            .recv_msg(&mut iovecs, &mut fds)
+            .chain_err(|| "Failed to recv vhost-user slave request")?;
+        // The received fds are closed when dropped.
+        let files: Vec<File> = fds[..fd_num]
+            .iter()
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        if len != std::mem::size_of::<VhostUserMsgHdr>() {
+            bail!("Invalid header of vhost-user slave request, len {}", len);
+        }
+
+        let mut msg = VhostUserFsSlaveMsg::default();
+        let size = hdr.size as usize;
+        if size > std::mem::size_of::<VhostUserFsSlaveMsg>() {
+            bail!("Invalid size {} of vhost-user slave request", size);
+        }
+        if size != 0 {
+            let mut iovecs = [libc::iovec {
+                iov_base: msg.as_mut_bytes().as_mut_ptr() as *mut libc::c_void,
+                iov_len: size,
+            }];
+            let (len, _) = self
+                .sock
+                .recv_msg(&mut iovecs, &mut [])
+                .chain_err(|| "Failed to recv payload of vhost-user slave request")?;
+            if len != size {
+                bail!("Invalid payload of vhost-user slave request, len {}", len);
+            }
+        }
+
+        let request = VhostUserSlaveMsgReq::from(hdr.request);
+        let ret = match request {
+            VhostUserSlaveMsgReq::FsMap => self.fs_map(&msg, files.first()),
+            VhostUserSlaveMsgReq::FsUnmap => self.fs_unmap(&msg),
+            VhostUserSlaveMsgReq::FsSync => self.fs_sync(&msg),
+            _ => Err(format!("Unsupported vhost-user slave request {:?}", request).into()),
+        };
+        let result = match ret {
+            Ok(()) => 0_u64,
+            Err(ref e) => {
+                error!(
+                    "Failed to handle vhost-user slave request {:?}, {}",
+                    request,
+                    e.display_chain()
+                );
+                1_u64
+            }
+        };
+
+        if hdr.flags & VHOST_USER_NEED_REPLY_MASK != 0 {
+            let reply_hdr = VhostUserMsgHdr::new(
+                hdr.request,
+                VHOST_USER_REPLY_MASK,
+                std::mem::size_of::<u64>() as u32,
+            );
+            let mut iovecs = [
+                libc::iovec {
+                    iov_base: reply_hdr.as_bytes().as_ptr() as *mut libc::c_void,
+                    iov_len: reply_hdr.as_bytes().len(),
+                },
+                libc::iovec {
+                    iov_base: result.as_bytes().as_ptr() as *mut libc::c_void,
+                    iov_len: result.as_bytes().len(),
+                },
+            ];
+            self.sock
+                .send_msg(&mut iovecs, &[])
+                .chain_err(|| "Failed to reply vhost-user slave request")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for FsSlaveHandler {
+    fn internal_notifiers(slave_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let sock_fd = match slave_handler.lock().unwrap().sock.get_stream_raw_fd() {
+            Some(fd) => fd,
+            None => return Vec::new(),
+        };
+
+        let handler: Box<NotifierCallback> = Box::new(move |event, fd: RawFd| {
+            if event & EventSet::HANG_UP == EventSet::HANG_UP {
+                warn!("Slave channel of vhost-user fs is closed");
+                return Some(vec![EventNotifier::new(
+                    NotifierOperation::Delete,
+                    fd,
+                    None,
+                    EventSet::IN | EventSet::HANG_UP,
+                    Vec::new(),
+                )]);
+            }
+
+            if let Err(ref e) = slave_handler.lock().unwrap().handle_request() {
+                error!("{}", e.display_chain());
+            }
+            None
+        });
+
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            sock_fd,
+            None,
+            EventSet::IN | EventSet::HANG_UP,
+            vec![Arc::new(Mutex::new(handler))],
+        )]
+    }
+}
+
+/// File system device structure with vhost-user backend.
+pub struct Fs {
+    /// Configuration of the file system device.
+    fs_cfg: FsConfig,
+    /// The client and features of the vhost-user backend.
+    common: VhostUserCommon,
+    /// Virtio fs configurations.
+    config: VirtioFsConfig,
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+    /// Guest physical address of the DAX cache window.
+    cache_base: GuestAddress,
+    /// The host mapping of the DAX cache window.
+    cache: Option<Arc<HostMemMapping>>,
+}
+
+impl Fs {
+    /// Create a vhost-user fs device.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Device configuration set by user.
+    /// * `mem_space` - System address space.
+    /// * `cache_base` - Guest physical address of the DAX cache window, only used if
+    ///   `cache_size` of the configuration is not zero.
+    pub fn new(cfg: &FsConfig, mem_space: &Arc<AddressSpace>, cache_base: GuestAddress) -> Self {
+        Fs {
+            fs_cfg: cfg.clone(),
+            common: VhostUserCommon::new("fs"),
+            config: VirtioFsConfig::new(&cfg.tag),
+            mem_space: mem_space.clone(),
+            cache_base,
+            cache: None,
+        }
+    }
+
+    /// Map the DAX cache window into guest and set up the slave channel, through
+    /// which virtiofsd maps files into the window.
+    fn realize_cache(&mut self, client: &VhostUserClient) -> Result<()> {
+        let cache = Arc::new(
+            HostMemMapping::new(
+                self.cache_base,
+                self.fs_cfg.cache_size,
+                None,
+                false,
+                false,
+                false,
+            )
+            .chain_err(|| "Failed to create DAX cache window for vhost-user fs")?,
+        );
+        // Nothing is accessible until virtiofsd maps files into the window.
+        mmap_none(cache.host_address(), cache.size())?;
+        self.mem_space
+            .root()
+            .add_subregion(
+                Region::init_ram_device_region(cache.clone()),
+                self.cache_base.raw_value(),
+            )
+            .chain_err(|| "Failed to add DAX cache window to guest address space")?;
+
+        let (master, slave) =
+            UnixStream::pair().chain_err(|| "Failed to create slave channel for vhost-user fs")?;
+        client
+            .set_slave_req_fd(slave.as_raw_fd())
+            .chain_err(|| "Failed to set slave request fd for vhost-user fs")?;
+        let handler = FsSlaveHandler {
+            sock: UnixSock::with_stream("vhost-user fs slave channel", master),
+            cache: cache.clone(),
+        };
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
+            None,
+        )?;
+
+        self.cache = Some(cache);
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Fs {
+    /// Realize vhost-user virtio fs device.
+    fn realize(&mut self) -> Result<()> {
+        let mut client = VhostUserClient::new(&self.mem_space, &self.fs_cfg.socket_path)
+            .chain_err(|| "Failed to create the client for vhost-user fs")?;
+        client
+            .set_owner()
+            .chain_err(|| "Failed to set owner for vhost-user fs")?;
+
+        let vhost_features = client
+            .get_features()
+            .chain_err(|| "Failed to get features for vhost-user fs")?;
+        let mut protocol_features = 0_u64;
+        if vhost_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            protocol_features = client
+                .get_protocol_features()
+                .chain_err(|| "Failed to get protocol features for vhost-user fs")?;
+        }
+
+        let dax_protocol_features =
+            1 << VHOST_USER_PROTOCOL_F_SLAVE_REQ | 1 << VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD;
+        let acked_protocol_features = if self.fs_cfg.cache_size != 0 {
+            if protocol_features & dax_protocol_features != dax_protocol_features {
+                bail!("Vhost-user fs backend does not support DAX cache window");
+            }
+            dax_protocol_features
+        } else {
+            0
+        };
+        if vhost_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            client
+                .set_protocol_features(acked_protocol_features)
+                .chain_err(|| "Failed to set protocol features for vhost-user fs")?;
+        }
+
+        if self.fs_cfg.cache_size != 0 {
+            self.realize_cache(&client)?;
+        }
+
+        self.common.client = Some(Arc::new(Mutex::new(client)));
+        self.common.device_features = vhost_features
+            & (1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_F_RING_INDIRECT_DESC
+                | 1 << VIRTIO_F_RING_EVENT_IDX);
+        self.common.vhost_features = vhost_features;
+        MigrationManager::register_blocker(&self.fs_cfg.id, VHOST_MIGRATION_BLOCKER, false);
+
+        Ok(())
+    }
+
+    /// Unrealize vhost-user fs device.
+    fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::unregister_blocker(&self.fs_cfg.id);
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_FS
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_FS
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_FS
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.common.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.common.set_driver_features(page, value);
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config(self.config.as_bytes(), offset, data)
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("Device config space for vhost-user fs is read only")
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        _mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.common.activate(interrupt_cb, queues, queue_evts)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.common.reset()
+    }
+
+    fn get_shm_region(&self, shm_id: u8) -> Option<(u64, u64)> {
+        match &self.cache {
+            Some(cache) if shm_id == VIRTIO_FS_SHMCAP_ID_CACHE => {
+                Some((self.cache_base.raw_value(), cache.size()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    use super::super::tests::{address_space_init, fake_backend};
+    use super::super::VhostUserMsgReq;
+    use super::*;
+
+    const CACHE_SIZE: u64 = 0x20_0000;
+
+    #[test]
+    fn test_vhost_user_fs_realize() {
+        let path = "/tmp/test_vhost_user_fs.sock";
+        let _ = std::fs::remove_file(path);
+        let mut fs_cfg = FsConfig {
+            id: "fs0".to_string(),
+            tag: "myfs".to_string(),
+            socket_path: path.to_string(),
+            cache_size: CACHE_SIZE,
+        };
+
+        // The backend does not support the slave channel, which is required by DAX.
+        let listener = UnixListener::bind(path).unwrap();
+        let backend_features = 1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        let backend = fake_backend(listener, backend_features, 0, Vec::new());
+        let mut fs = Fs::new(&fs_cfg, &address_space_init(true), GuestAddress(1 << 30));
+        assert!(fs.realize().is_err());
+        drop(fs);
+        backend.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        fs_cfg.cache_size = 0;
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = fake_backend(listener, backend_features, 0, Vec::new());
+        let mut fs = Fs::new(&fs_cfg, &address_space_init(true), GuestAddress(1 << 30));
+        assert!(fs.realize().is_ok());
+        assert_eq!(fs.common.device_features, 1 << VIRTIO_F_VERSION_1);
+        assert!(fs.get_shm_region(VIRTIO_FS_SHMCAP_ID_CACHE).is_none());
+
+        let mut tag = [0_u8; 4];
+        assert!(fs.read_config(0, &mut tag).is_ok());
+        assert_eq!(&tag, b"myfs");
+        let mut num_queues = [0_u8; 4];
+        assert!(fs.read_config(FS_TAG_SIZE as u64, &mut num_queues).is_ok());
+        assert_eq!(u32::from_le_bytes(num_queues), 1);
+        assert!(fs.write_config(0, &tag).is_err());
+
+        drop(fs);
+        assert_eq!(
+            backend.join().unwrap(),
+            vec![
+                VhostUserMsgReq::SetOwner as u32,
+                VhostUserMsgReq::GetFeatures as u32,
+                VhostUserMsgReq::GetProtocolFeatures as u32,
+                VhostUserMsgReq::SetProtocolFeatures as u32,
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_vhost_user_fs_slave_map() {
+        let cache = Arc::new(
+            HostMemMapping::new(GuestAddress(0), CACHE_SIZE, None, false, false, false).unwrap(),
+        );
+        assert!(mmap_none(cache.host_address(), cache.size()).is_ok());
+        let (master, mut slave) = UnixStream::pair().unwrap();
+        let handler = FsSlaveHandler {
+            sock: UnixSock::with_stream("test", master),
+            cache: cache.clone(),
+        };
+
+        let file_path = "/tmp/test_vhost_user_fs_slave_map";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(&[0xa5_u8; 0x2000]).unwrap();
+        let file = File::open(file_path).unwrap();
+
+        let mut msg = VhostUserFsSlaveMsg::default();
+        msg.fd_offset[0] = 0x1000;
+        msg.cache_offset[0] = 0x1000;
+        msg.len[0] = 0x1000;
+        msg.flags[0] = VHOST_USER_FS_FLAG_MAP_R;
+        let hdr = VhostUserMsgHdr::new(
+            VhostUserSlaveMsgReq::FsMap as u32,
+            VHOST_USER_NEED_REPLY_MASK,
+            std::mem::size_of::<VhostUserFsSlaveMsg>() as u32,
+        );
+        let slave_sock = UnixSock::with_stream("test", slave.try_clone().unwrap());
+        let mut iovecs = [
+            libc::iovec {
+                iov_base: hdr.as_bytes().as_ptr() as *mut libc::c_void,
+                iov_len: hdr.as_bytes().len(),
+            },
+            libc::iovec {
+                iov_base: msg.as_bytes().as_ptr() as *mut libc::c_void,
+                iov_len: msg.as_bytes().len(),
+            },
+        ];
+        assert!(slave_sock
+            .send_msg(&mut iovecs, &[file.as_raw_fd()])
+            .is_ok());
+        assert!(handler.handle_request().is_ok());
+
+        let mut reply_hdr = VhostUserMsgHdr::default();
+        slave.read_exact(reply_hdr.as_mut_bytes()).unwrap();
+        assert!(reply_hdr.is_reply_valid(VhostUserSlaveMsgReq::FsMap as u32));
+        let mut result = 1_u64;
+        slave.read_exact(result.as_mut_bytes()).unwrap();
+        assert_eq!(result, 0);
+        let data = unsafe { *((cache.host_address() + 0x1000) as *const u8) };
+        assert_eq!(data, 0xa5);
+
+        // The mapping overflows the DAX cache window.
+        msg.cache_offset[0] = CACHE_SIZE;
+        let hdr = VhostUserMsgHdr::new(
+            VhostUserSlaveMsgReq::FsMap as u32,
+            0,
+            std::mem::size_of::<VhostUserFsSlaveMsg>() as u32,
+        );
+        let mut iovecs = [
+            libc::iovec {
+                iov_base: hdr.as_bytes().as_ptr() as *mut libc::c_void,
+                iov_len: hdr.as_bytes().len(),
+            },
+            libc::iovec {
+                iov_base: msg.as_bytes().as_ptr() as *mut libc::c_void,
+                iov_len: msg.as_bytes().len(),
+            },
+        ];
+        assert!(slave_sock
+            .send_msg(&mut iovecs, &[file.as_raw_fd()])
+            .is_ok());
+        assert!(handler.handle_request().is_ok());
+
+        msg.cache_offset[0] = 0x1000;
+        assert!(handler.fs_sync(&msg).is_ok());
+        msg.len[0] = u64::MAX;
+        assert!(handler.fs_unmap(&msg).is_ok());
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
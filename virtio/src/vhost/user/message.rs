@@ -0,0 +1,329 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use util::byte_code::ByteCode;
+
+/// The version of the vhost-user protocol.
+pub const VHOST_USER_VERSION: u32 = 0x1;
+/// Mask of the version bits in the flags of the message header.
+pub const VHOST_USER_VERSION_MASK: u32 = 0x3;
+/// The message is a reply from the slave.
+pub const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+/// The master asks the slave to reply the result of the request.
+pub const VHOST_USER_NEED_REPLY_MASK: u32 = 0x1 << 3;
+/// The max count of memory regions which can be sent in one message.
+pub const VHOST_USER_MAX_MEMORY_REGIONS: usize = 8;
+/// The max size of the payload of a message.
+pub const VHOST_USER_MAX_PAYLOAD_SIZE: usize = 0x1000;
+/// Bit of vring index in the payload of SET_VRING_KICK/CALL/ERR.
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+/// The payload of SET_VRING_KICK/CALL/ERR carries no file descriptor.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x1 << 8;
+
+/// Virtio feature bit which indicates that the slave supports the protocol
+/// features negotiation.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+/// Protocol feature: the slave supports multiple queues.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+/// Protocol feature: the slave replies to the requests with NEED_REPLY flag.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+/// Protocol feature: the slave can send requests to the master through the
+/// channel set by SET_SLAVE_REQ_FD.
+pub const VHOST_USER_PROTOCOL_F_SLAVE_REQ: u32 = 5;
+/// Protocol feature: the slave supports GET_CONFIG and SET_CONFIG.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+/// Protocol feature: the slave can send file descriptors with its requests.
+pub const VHOST_USER_PROTOCOL_F_SLAVE_SEND_FD: u32 = 10;
+
+/// The max count of mappings in one virtio-fs slave request.
+pub const VHOST_USER_FS_SLAVE_ENTRIES: usize = 8;
+/// The mapping of virtio-fs slave request is readable.
+pub const VHOST_USER_FS_FLAG_MAP_R: u64 = 0x1;
+/// The mapping of virtio-fs slave request is writable.
+pub const VHOST_USER_FS_FLAG_MAP_W: u64 = 0x1 << 1;
+
+/// Type of requests sent from the master to the slave, refer to
+/// https://qemu-project.gitlab.io/qemu/interop/vhost-user.html.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VhostUserMsgReq {
+    None = 0,
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    ResetOwner = 4,
+    SetMemTable = 5,
+    SetLogBase = 6,
+    SetLogFd = 7,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    GetVringBase = 11,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    SetVringErr = 14,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    GetQueueNum = 17,
+    SetVringEnable = 18,
+    SendRarp = 19,
+    NetSetMtu = 20,
+    SetSlaveReqFd = 21,
+    IotlbMsg = 22,
+    SetVringEndian = 23,
+    GetConfig = 24,
+    SetConfig = 25,
+    MaxCmd = 26,
+}
+
+impl From<u32> for VhostUserMsgReq {
+    fn from(t: u32) -> Self {
+        match t {
+            0 => VhostUserMsgReq::None,
+            1 => VhostUserMsgReq::GetFeatures,
+            2 => VhostUserMsgReq::SetFeatures,
+            3 => VhostUserMsgReq::SetOwner,
+            4 => VhostUserMsgReq::ResetOwner,
+            5 => VhostUserMsgReq::SetMemTable,
+            6 => VhostUserMsgReq::SetLogBase,
+            7 => VhostUserMsgReq::SetLogFd,
+            8 => VhostUserMsgReq::SetVringNum,
+            9 => VhostUserMsgReq::SetVringAddr,
+            10 => VhostUserMsgReq::SetVringBase,
+            11 => VhostUserMsgReq::GetVringBase,
+            12 => VhostUserMsgReq::SetVringKick,
+            13 => VhostUserMsgReq::SetVringCall,
+            14 => VhostUserMsgReq::SetVringErr,
+            15 => VhostUserMsgReq::GetProtocolFeatures,
+            16 => VhostUserMsgReq::SetProtocolFeatures,
+            17 => VhostUserMsgReq::GetQueueNum,
+            18 => VhostUserMsgReq::SetVringEnable,
+            19 => VhostUserMsgReq::SendRarp,
+            20 => VhostUserMsgReq::NetSetMtu,
+            21 => VhostUserMsgReq::SetSlaveReqFd,
+            22 => VhostUserMsgReq::IotlbMsg,
+            23 => VhostUserMsgReq::SetVringEndian,
+            24 => VhostUserMsgReq::GetConfig,
+            25 => VhostUserMsgReq::SetConfig,
+            _ => VhostUserMsgReq::MaxCmd,
+        }
+    }
+}
+
+/// Type of requests sent from the slave to the master through the slave channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VhostUserSlaveMsgReq {
+    None = 0,
+    IotlbMsg = 1,
+    ConfigChangeMsg = 2,
+    VringHostNotifierMsg = 3,
+    VringCall = 4,
+    VringErr = 5,
+    FsMap = 6,
+    FsUnmap = 7,
+    FsSync = 8,
+    FsIo = 9,
+    MaxCmd = 10,
+}
+
+impl From<u32> for VhostUserSlaveMsgReq {
+    fn from(t: u32) -> Self {
+        match t {
+            0 => VhostUserSlaveMsgReq::None,
+            1 => VhostUserSlaveMsgReq::IotlbMsg,
+            2 => VhostUserSlaveMsgReq::ConfigChangeMsg,
+            3 => VhostUserSlaveMsgReq::VringHostNotifierMsg,
+            4 => VhostUserSlaveMsgReq::VringCall,
+            5 => VhostUserSlaveMsgReq::VringErr,
+            6 => VhostUserSlaveMsgReq::FsMap,
+            7 => VhostUserSlaveMsgReq::FsUnmap,
+            8 => VhostUserSlaveMsgReq::FsSync,
+            9 => VhostUserSlaveMsgReq::FsIo,
+            _ => VhostUserSlaveMsgReq::MaxCmd,
+        }
+    }
+}
+
+/// The header of the vhost-user message.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserMsgHdr {
+    /// The request type of the message.
+    pub request: u32,
+    /// Flags of the message, bits[0..1] is the version, bit[2] is the reply
+    /// flag and bit[3] is the need_reply flag.
+    pub flags: u32,
+    /// The size of the payload following the header.
+    pub size: u32,
+}
+
+impl ByteCode for VhostUserMsgHdr {}
+
+impl VhostUserMsgHdr {
+    /// Create a message header sent from the master.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request type of the message.
+    /// * `flags` - Extra flags besides the version.
+    /// * `size` - The size of the payload.
+    pub fn new(request: u32, flags: u32, size: u32) -> Self {
+        VhostUserMsgHdr {
+            request,
+            flags: flags | VHOST_USER_VERSION,
+            size,
+        }
+    }
+
+    /// Check whether the message header is a valid reply of `request`.
+    pub fn is_reply_valid(&self, request: u32) -> bool {
+        self.request == request
+            && self.flags & VHOST_USER_VERSION_MASK == VHOST_USER_VERSION
+            && self.flags & VHOST_USER_REPLY_MASK != 0
+            && self.size as usize <= VHOST_USER_MAX_PAYLOAD_SIZE
+    }
+}
+
+/// Memory region information in the payload of SET_MEM_TABLE.
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct RegionMemInfo {
+    /// GPA of the memory region.
+    pub guest_phys_addr: u64,
+    /// Size of the memory region.
+    pub memory_size: u64,
+    /// HVA of the memory region in the master.
+    pub userspace_addr: u64,
+    /// Offset of the memory region in the file passed with the message.
+    pub mmap_offset: u64,
+}
+
+impl ByteCode for RegionMemInfo {}
+
+/// The header of the payload of SET_MEM_TABLE, followed by `nregions`
+/// `RegionMemInfo`.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserMemHdr {
+    /// Count of memory regions.
+    pub nregions: u32,
+    /// Padding for alignment.
+    pub padding: u32,
+}
+
+impl ByteCode for VhostUserMemHdr {}
+
+/// Vring state in the payload of SET_VRING_NUM/SET_VRING_BASE/GET_VRING_BASE
+/// and SET_VRING_ENABLE.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserVringState {
+    /// Index of the vring.
+    pub index: u32,
+    /// The value of the state.
+    pub value: u32,
+}
+
+impl ByteCode for VhostUserVringState {}
+
+impl VhostUserVringState {
+    pub fn new(index: u32, value: u32) -> Self {
+        VhostUserVringState { index, value }
+    }
+}
+
+/// Vring address in the payload of SET_VRING_ADDR.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserVringAddr {
+    /// Index of the vring.
+    pub index: u32,
+    /// Option flags.
+    pub flags: u32,
+    /// HVA of the descriptor table.
+    pub desc_user_addr: u64,
+    /// HVA of the used ring.
+    pub used_user_addr: u64,
+    /// HVA of the available ring.
+    pub avail_user_addr: u64,
+    /// GPA where to write the logs.
+    pub log_guest_addr: u64,
+}
+
+impl ByteCode for VhostUserVringAddr {}
+
+/// The header of the payload of GET_CONFIG and SET_CONFIG, followed by
+/// `size` bytes of the device configuration space.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserConfig {
+    /// Offset in the device configuration space.
+    pub offset: u32,
+    /// Size of the configuration to get or set.
+    pub size: u32,
+    /// Flags of the configuration.
+    pub flags: u32,
+}
+
+impl ByteCode for VhostUserConfig {}
+
+/// The payload of virtio-fs slave requests FS_MAP, FS_UNMAP and FS_SYNC, each
+/// entry with non-zero `len` describes a range of the DAX cache window.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct VhostUserFsSlaveMsg {
+    /// Offset in the file to map.
+    pub fd_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// Offset in the DAX cache window.
+    pub cache_offset: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// Length of the range.
+    pub len: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+    /// Flags of the mapping, VHOST_USER_FS_FLAG_MAP_R and VHOST_USER_FS_FLAG_MAP_W.
+    pub flags: [u64; VHOST_USER_FS_SLAVE_ENTRIES],
+}
+
+impl ByteCode for VhostUserFsSlaveMsg {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_user_msg_hdr() {
+        let hdr = VhostUserMsgHdr::new(VhostUserMsgReq::GetFeatures as u32, 0, 0);
+        assert_eq!(hdr.flags, VHOST_USER_VERSION);
+        assert_eq!(hdr.as_bytes().len(), 12);
+        assert!(!hdr.is_reply_valid(VhostUserMsgReq::GetFeatures as u32));
+
+        let reply = VhostUserMsgHdr::new(
+            VhostUserMsgReq::GetFeatures as u32,
+            VHOST_USER_REPLY_MASK,
+            8,
+        );
+        assert!(reply.is_reply_valid(VhostUserMsgReq::GetFeatures as u32));
+        assert!(!reply.is_reply_valid(VhostUserMsgReq::GetProtocolFeatures as u32));
+
+        let reply = VhostUserMsgHdr::new(
+            VhostUserMsgReq::GetConfig as u32,
+            VHOST_USER_REPLY_MASK,
+            VHOST_USER_MAX_PAYLOAD_SIZE as u32 + 1,
+        );
+        assert!(!reply.is_reply_valid(VhostUserMsgReq::GetConfig as u32));
+
+        assert_eq!(VhostUserMsgReq::from(25), VhostUserMsgReq::SetConfig);
+        assert_eq!(VhostUserMsgReq::from(100), VhostUserMsgReq::MaxCmd);
+        assert_eq!(std::mem::size_of::<RegionMemInfo>(), 32);
+        assert_eq!(std::mem::size_of::<VhostUserVringAddr>(), 40);
+        assert_eq!(std::mem::size_of::<VhostUserFsSlaveMsg>(), 256);
+        assert_eq!(VhostUserSlaveMsgReq::from(6), VhostUserSlaveMsgReq::FsMap);
+    }
+}
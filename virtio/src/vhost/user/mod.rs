@@ -0,0 +1,265 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+mod block;
+mod client;
+mod fs;
+mod message;
+mod net;
+
+pub use block::Block;
+pub use client::VhostUserClient;
+pub use fs::Fs;
+pub use message::*;
+pub use net::Net;
+
+use std::cmp;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use machine_manager::event_loop::EventLoop;
+use util::loop_context::EventNotifierHelper;
+use util::num_ops::write_u32;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::errors::{ErrorKind, Result, ResultExt};
+use super::super::{Queue, VirtioInterrupt};
+use super::{VhostIoHandler, VhostNotify};
+
+/// The client and features shared by the virtio devices with vhost-user backend.
+struct VhostUserCommon {
+    /// Name of the device type, used in messages.
+    name: &'static str,
+    /// The client of the vhost-user backend.
+    client: Option<Arc<Mutex<VhostUserClient>>>,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Bit mask of features supported by the vhost-user backend.
+    vhost_features: u64,
+    /// EventFd for device reset.
+    reset_evt: EventFd,
+}
+
+impl VhostUserCommon {
+    fn new(name: &'static str) -> Self {
+        VhostUserCommon {
+            name,
+            client: None,
+            device_features: 0_u64,
+            driver_features: 0_u64,
+            vhost_features: 0_u64,
+            reset_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    /// Set driver features by guest, the features unsupported by the backend are ignored.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut features = write_u32(value, page);
+        let unsupported_features = features & !self.device_features;
+        if unsupported_features != 0 {
+            warn!(
+                "Received acknowledge request with unsupported feature for vhost-user {}: 0x{:x}",
+                self.name, features
+            );
+            features &= !unsupported_features;
+        }
+        self.driver_features |= features;
+    }
+
+    /// Pass the virtqueues to the backend, and handle the interrupts from it.
+    fn activate(
+        &self,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let client = match &self.client {
+            None => bail!("Failed to get client for vhost-user {}", self.name),
+            Some(client_) => client_,
+        };
+
+        let mut host_notifies = Vec::new();
+        let mut call_evts = Vec::new();
+        for queue_mutex in queues.iter() {
+            let host_notify = VhostNotify {
+                notify_evt: EventFd::new(libc::EFD_NONBLOCK)
+                    .chain_err(|| ErrorKind::EventFdCreate)?,
+                queue: queue_mutex.clone(),
+            };
+            call_evts.push(
+                host_notify
+                    .notify_evt
+                    .try_clone()
+                    .chain_err(|| ErrorKind::EventFdCreate)?,
+            );
+            host_notifies.push(host_notify);
+        }
+
+        let mut locked_client = client.lock().unwrap();
+        locked_client.features =
+            self.driver_features | (self.vhost_features & 1 << VHOST_USER_F_PROTOCOL_FEATURES);
+        locked_client.set_queues(queues, queue_evts, call_evts);
+        locked_client
+            .activate_vhost_user()
+            .chain_err(|| format!("Failed to activate vhost-user {}", self.name))?;
+        drop(locked_client);
+
+        let handler = VhostIoHandler {
+            interrupt_cb,
+            host_notifies,
+            reset_evt: self.reset_evt.as_raw_fd(),
+        };
+
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
+            None,
+        )?;
+        EventLoop::update_event(
+            EventNotifierHelper::internal_notifiers(client.clone()),
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Reset the backend and stop handling the interrupts from it.
+    fn reset(&self) -> Result<()> {
+        if let Some(client) = &self.client {
+            let mut locked_client = client.lock().unwrap();
+            locked_client
+                .reset_vhost_user()
+                .chain_err(|| format!("Failed to reset vhost-user {}", self.name))?;
+            EventLoop::update_event(locked_client.delete_event(), None)?;
+
+            self.reset_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        } else {
+            bail!("Failed to get client for vhost-user {}", self.name);
+        }
+
+        Ok(())
+    }
+}
+
+/// Read data of config space from guest.
+///
+/// # Arguments
+///
+/// * `config` - Config space of the device.
+/// * `offset` - Offset from the start of config space.
+/// * `data` - Buffer to read into, which is truncated at the end of config space.
+fn read_config(config: &[u8], offset: u64, mut data: &mut [u8]) -> Result<()> {
+    let config_len = config.len() as u64;
+    if offset >= config_len {
+        return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+    }
+    if let Some(end) = offset.checked_add(data.len() as u64) {
+        data.write_all(&config[offset as usize..cmp::min(end, config_len) as usize])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use address_space::*;
+    use util::byte_code::ByteCode;
+
+    use super::{VhostUserConfig, VhostUserMsgHdr, VhostUserMsgReq, VHOST_USER_REPLY_MASK};
+
+    const SYSTEM_SPACE_SIZE: u64 = (1024 * 1024) as u64;
+    /// Max number of queues supported by the fake backend.
+    pub const FAKE_BACKEND_QUEUE_NUM: u64 = 2;
+
+    pub fn address_space_init(shared: bool) -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let file_back = if shared {
+            Some(FileBackend::new("/tmp", SYSTEM_SPACE_SIZE).unwrap())
+        } else {
+            None
+        };
+        let host_mmap = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                SYSTEM_SPACE_SIZE,
+                file_back,
+                false,
+                shared,
+                false,
+            )
+            .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    /// A fake vhost-user backend which only replies to the GET requests, and
+    /// returns the requests received.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The socket which the frontend connects to.
+    /// * `features` - Features supported by the backend.
+    /// * `protocol_features` - Protocol features supported by the backend.
+    /// * `config` - Config space of the backend.
+    pub fn fake_backend(
+        listener: UnixListener,
+        features: u64,
+        protocol_features: u64,
+        config: Vec<u8>,
+    ) -> thread::JoinHandle<Vec<u32>> {
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut hdr = VhostUserMsgHdr::default();
+            while stream.read_exact(hdr.as_mut_bytes()).is_ok() {
+                let mut body = vec![0_u8; hdr.size as usize];
+                stream.read_exact(&mut body).unwrap();
+                requests.push(hdr.request);
+
+                let reply = match VhostUserMsgReq::from(hdr.request) {
+                    VhostUserMsgReq::GetFeatures => features.as_bytes().to_vec(),
+                    VhostUserMsgReq::GetProtocolFeatures => protocol_features.as_bytes().to_vec(),
+                    VhostUserMsgReq::GetQueueNum => FAKE_BACKEND_QUEUE_NUM.as_bytes().to_vec(),
+                    VhostUserMsgReq::GetConfig => {
+                        let cfg_size = std::mem::size_of::<VhostUserConfig>();
+                        let len = std::cmp::min(body.len() - cfg_size, config.len());
+                        body[cfg_size..(cfg_size + len)].copy_from_slice(&config[..len]);
+                        body
+                    }
+                    _ => continue,
+                };
+                let reply_hdr =
+                    VhostUserMsgHdr::new(hdr.request, VHOST_USER_REPLY_MASK, reply.len() as u32);
+                stream.write_all(reply_hdr.as_bytes()).unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+            requests
+        })
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::AddressSpace;
+use machine_manager::config::NetworkInterfaceConfig;
+use migration::MigrationManager;
+use util::byte_code::ByteCode;
+use util::num_ops::read_u32;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::super::errors::{ErrorKind, Result, ResultExt};
+use super::super::super::{
+    net::{build_device_config_space, VirtioNetConfig},
+    Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
+    VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_TYPE_NET,
+};
+use super::super::{VhostOps, VHOST_MIGRATION_BLOCKER};
+use super::{read_config, VhostUserClient, VhostUserCommon, VHOST_USER_F_PROTOCOL_FEATURES};
+
+/// Number of virtqueues.
+const QUEUE_NUM_NET: usize = 2;
+/// Size of each virtqueue.
+const QUEUE_SIZE_NET: u16 = 256;
+
+/// Network device structure with vhost-user backend.
+pub struct Net {
+    /// Configuration of the network device.
+    net_cfg: NetworkInterfaceConfig,
+    /// The client and features of the vhost-user backend.
+    common: VhostUserCommon,
+    /// Virtio net configurations.
+    device_config: VirtioNetConfig,
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+}
+
+impl Net {
+    pub fn new(cfg: &NetworkInterfaceConfig, mem_space: &Arc<AddressSpace>) -> Self {
+        Net {
+            net_cfg: cfg.clone(),
+            common: VhostUserCommon::new("net"),
+            device_config: VirtioNetConfig::default(),
+            mem_space: mem_space.clone(),
+        }
+    }
+}
+
+impl VirtioDevice for Net {
+    /// Realize vhost-user virtio network device.
+    fn realize(&mut self) -> Result<()> {
+        let socket_path = match self.net_cfg.socket_path.as_ref() {
+            Some(path) => path,
+            None => bail!("Socket path is not set for vhost-user net"),
+        };
+        let mut client = VhostUserClient::new(&self.mem_space, socket_path)
+            .chain_err(|| "Failed to create the client for vhost-user net")?;
+        client
+            .set_owner()
+            .chain_err(|| "Failed to set owner for vhost-user net")?;
+
+        let vhost_features = client
+            .get_features()
+            .chain_err(|| "Failed to get features for vhost-user net")?;
+        if vhost_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            client
+                .get_protocol_features()
+                .chain_err(|| "Failed to get protocol features for vhost-user net")?;
+            // No protocol features are needed by the net device with one queue pair.
+            client
+                .set_protocol_features(0)
+                .chain_err(|| "Failed to set protocol features for vhost-user net")?;
+        }
+
+        // The control virtqueue and the device status are not supported.
+        let mut device_features = vhost_features
+            & (1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_F_RING_INDIRECT_DESC
+                | 1 << VIRTIO_F_RING_EVENT_IDX
+                | 1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_UFO
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_UFO);
+
+        if let Some(mac) = &self.net_cfg.mac {
+            device_features |= build_device_config_space(&mut self.device_config, mac);
+        }
+
+        self.common.client = Some(Arc::new(Mutex::new(client)));
+        self.common.device_features = device_features;
+        self.common.vhost_features = vhost_features;
+        MigrationManager::register_blocker(&self.net_cfg.id, VHOST_MIGRATION_BLOCKER, false);
+
+        Ok(())
+    }
+
+    /// Unrealize vhost-user net device.
+    fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::unregister_blocker(&self.net_cfg.id);
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_NET
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_NET
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_NET
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.common.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.common.set_driver_features(page, value);
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        read_config(self.device_config.as_bytes(), offset, data)
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let data_len = data.len();
+        let config_slice = self.device_config.as_mut_bytes();
+        let config_len = config_slice.len();
+        if offset as usize + data_len > config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len as u64).into());
+        }
+
+        config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        _mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.common.activate(interrupt_cb, queues, queue_evts)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.common.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::super::super::super::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_MAC};
+    use super::super::tests::{address_space_init, fake_backend};
+    use super::super::VhostUserMsgReq;
+    use super::*;
+
+    #[test]
+    fn test_vhost_user_net_realize() {
+        let path = "/tmp/test_vhost_user_net.sock";
+        let _ = std::fs::remove_file(path);
+        let net_cfg = NetworkInterfaceConfig {
+            id: "net0".to_string(),
+            mac: Some("1A:2B:3C:4D:5E:6F".to_string()),
+            vhost_type: Some("vhost-user".to_string()),
+            socket_path: Some(path.to_string()),
+            ..Default::default()
+        };
+
+        // The backend is not listening.
+        let mut net = Net::new(&net_cfg, &address_space_init(true));
+        assert!(net.realize().is_err());
+
+        // The guest memory is not shared.
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = fake_backend(listener, 0, 0xff, Vec::new());
+        let mut net = Net::new(&net_cfg, &address_space_init(false));
+        assert!(net.realize().is_err());
+        assert!(backend.join().unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        let listener = UnixListener::bind(path).unwrap();
+        let backend_features = 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        let backend = fake_backend(listener, backend_features, 0xff, Vec::new());
+        let mut net = Net::new(&net_cfg, &address_space_init(true));
+        assert!(net.realize().is_ok());
+        assert_eq!(
+            net.common.device_features,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_MAC
+        );
+        assert_eq!(net.common.vhost_features, backend_features);
+        assert_eq!(net.device_config.mac, [0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x6F]);
+
+        drop(net);
+        assert_eq!(
+            backend.join().unwrap(),
+            vec![
+                VhostUserMsgReq::SetOwner as u32,
+                VhostUserMsgReq::GetFeatures as u32,
+                VhostUserMsgReq::GetProtocolFeatures as u32,
+                VhostUserMsgReq::SetProtocolFeatures as u32,
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
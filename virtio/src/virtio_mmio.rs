@@ -26,7 +26,7 @@ use super::{
     virtio_has_feature, Queue, QueueConfig, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
     CONFIG_STATUS_ACKNOWLEDGE, CONFIG_STATUS_DRIVER, CONFIG_STATUS_DRIVER_OK, CONFIG_STATUS_FAILED,
     CONFIG_STATUS_FEATURES_OK, NOTIFY_REG_OFFSET, QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING,
-    VIRTIO_F_RING_PACKED, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+    VIRTIO_F_RING_PACKED, VIRTIO_MAX_QUEUES, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
 };
 use crate::errors::{ErrorKind, Result, ResultExt};
 
@@ -73,6 +73,16 @@ const QUEUE_AVAIL_HIGH_REG: u64 = 0x94;
 const QUEUE_USED_LOW_REG: u64 = 0xa0;
 /// The high 32bit of queue's Used Ring address.
 const QUEUE_USED_HIGH_REG: u64 = 0xa4;
+/// Shared memory region selector - Write Only.
+const SHM_SEL_REG: u64 = 0xac;
+/// The low 32bit of selected shared memory region's length - Read Only.
+const SHM_LEN_LOW_REG: u64 = 0xb0;
+/// The high 32bit of selected shared memory region's length - Read Only.
+const SHM_LEN_HIGH_REG: u64 = 0xb4;
+/// The low 32bit of selected shared memory region's base address - Read Only.
+const SHM_BASE_LOW_REG: u64 = 0xb8;
+/// The high 32bit of selected shared memory region's base address - Read Only.
+const SHM_BASE_HIGH_REG: u64 = 0xbc;
 /// Configuration atomicity value.
 const CONFIG_GENERATION_REG: u64 = 0xfc;
 
@@ -80,9 +90,6 @@ const VENDOR_ID: u32 = 0;
 const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
 const MMIO_VERSION: u32 = 2;
 
-/// The maximum of virtio queue within a virtio device.
-const MAXIMUM_NR_QUEUES: usize = 8;
-
 /// HostNotifyInfo includes the info needed for notifying backend from guest.
 pub struct HostNotifyInfo {
     /// Eventfds which notify backend to use the avail ring.
@@ -103,7 +110,7 @@ impl HostNotifyInfo {
 /// The state of virtio-mmio device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.3.0", compat_version = "0.3.0")]
 pub struct VirtioMmioState {
     /// Identify if this device is activated by frontend driver.
     activated: bool,
@@ -112,7 +119,7 @@ pub struct VirtioMmioState {
 }
 
 /// The configuration of virtio-mmio device, the fields refer to Virtio Spec.
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone)]
 pub struct VirtioMmioCommonConfig {
     /// Bitmask of the features supported by the device (host)(32 bits per set).
     features_select: u32,
@@ -127,17 +134,36 @@ pub struct VirtioMmioCommonConfig {
     /// Queue selector.
     queue_select: u32,
     /// The configuration of queues.
-    queues_config: [QueueConfig; MAXIMUM_NR_QUEUES],
+    queues_config: [QueueConfig; VIRTIO_MAX_QUEUES],
     /// The number of queues.
     queue_num: usize,
     /// The type of queue, either be split ring or packed ring.
     queue_type: u16,
+    /// Shared memory region selector.
+    shm_select: u32,
+}
+
+impl Default for VirtioMmioCommonConfig {
+    fn default() -> Self {
+        VirtioMmioCommonConfig {
+            features_select: 0,
+            acked_features_select: 0,
+            interrupt_status: 0,
+            device_status: 0,
+            config_generation: 0,
+            queue_select: 0,
+            queues_config: [QueueConfig::default(); VIRTIO_MAX_QUEUES],
+            queue_num: 0,
+            queue_type: 0,
+            shm_select: 0,
+        }
+    }
 }
 
 impl VirtioMmioCommonConfig {
     pub fn new(device: &Arc<Mutex<dyn VirtioDevice>>) -> Self {
         let locked_device = device.lock().unwrap();
-        let mut queues_config = [QueueConfig::default(); 8];
+        let mut queues_config = [QueueConfig::default(); VIRTIO_MAX_QUEUES];
         let queue_size = locked_device.queue_size();
         let queue_num = locked_device.queue_num();
         for queue_config in queues_config.iter_mut().take(queue_num) {
@@ -234,6 +260,20 @@ impl VirtioMmioCommonConfig {
             }
             STATUS_REG => self.device_status,
             CONFIG_GENERATION_REG => self.config_generation,
+            SHM_LEN_LOW_REG | SHM_LEN_HIGH_REG | SHM_BASE_LOW_REG | SHM_BASE_HIGH_REG => {
+                // A non-existent shared memory region reads as length and base of all ones.
+                let (base, len) = device
+                    .lock()
+                    .unwrap()
+                    .get_shm_region(self.shm_select as u8)
+                    .unwrap_or((u64::MAX, u64::MAX));
+                match offset {
+                    SHM_LEN_LOW_REG => len as u32,
+                    SHM_LEN_HIGH_REG => (len >> 32) as u32,
+                    SHM_BASE_LOW_REG => base as u32,
+                    _ => (base >> 32) as u32,
+                }
+            }
             _ => {
                 return Err(ErrorKind::MmioRegErr(offset).into());
             }
@@ -267,14 +307,16 @@ impl VirtioMmioCommonConfig {
                     CONFIG_STATUS_DRIVER,
                     CONFIG_STATUS_FEATURES_OK | CONFIG_STATUS_FAILED,
                 ) {
-                    device
-                        .lock()
-                        .unwrap()
-                        .set_driver_features(self.acked_features_select, value);
-                    if self.acked_features_select == 1
-                        && virtio_has_feature(u64::from(value) << 32, VIRTIO_F_RING_PACKED)
-                    {
-                        self.queue_type = QUEUE_TYPE_PACKED_VRING;
+                    let mut locked_device = device.lock().unwrap();
+                    locked_device.set_driver_features(self.acked_features_select, value);
+                    if self.acked_features_select == 1 {
+                        let features =
+                            u64::from(value & locked_device.get_device_features(1)) << 32;
+                        self.queue_type = if virtio_has_feature(features, VIRTIO_F_RING_PACKED) {
+                            QUEUE_TYPE_PACKED_VRING
+                        } else {
+                            QUEUE_TYPE_SPLIT_VRING
+                        };
                     }
                 } else {
                     return Err(ErrorKind::DevStatErr(self.device_status).into());
@@ -294,6 +336,7 @@ impl VirtioMmioCommonConfig {
                 }
             }
             STATUS_REG => self.device_status = value,
+            SHM_SEL_REG => self.shm_select = value,
             QUEUE_DESC_LOW_REG => self.get_mut_queue_config().map(|config| {
                 config.desc_table = GuestAddress(config.desc_table.0 | u64::from(value));
             })?,
@@ -373,6 +416,13 @@ impl VirtioMmioDevice {
             .realize()
             .chain_err(|| "Failed to realize virtio.")?;
 
+        if self.state.config_space.queue_num > VIRTIO_MAX_QUEUES {
+            bail!(
+                "The number of queues {} exceeds the maximum {}",
+                self.state.config_space.queue_num,
+                VIRTIO_MAX_QUEUES
+            );
+        }
         if region_base >= sysbus.mmio_region.1 {
             bail!("Mmio region space exhausted.");
         }
@@ -396,8 +446,12 @@ impl VirtioMmioDevice {
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(&mut self) -> Result<()> {
-        let queues_config =
-            &self.state.config_space.queues_config[0..self.state.config_space.queue_num];
+        // The number of queues may depend on the negotiated features.
+        let queue_num = std::cmp::min(
+            self.device.lock().unwrap().queue_num(),
+            self.state.config_space.queue_num,
+        );
+        let queues_config = &self.state.config_space.queues_config[0..queue_num];
         for queue_config in queues_config {
             let queue = Queue::new(*queue_config, self.state.config_space.queue_type)?;
             if !queue.is_valid(&self.mem_space) {
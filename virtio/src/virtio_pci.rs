@@ -24,6 +24,7 @@ use pci::config::{
     VENDOR_ID,
 };
 use pci::errors::{ErrorKind, Result as PciResult, ResultExt};
+use pci::hotplug::handle_plug;
 use pci::msix::update_dev_id;
 use pci::{
     config::PciConfig, init_msix, init_multifunction, le_write_u16, ranges_overlap, PciBus,
@@ -38,7 +39,7 @@ use crate::{
 use crate::{
     CONFIG_STATUS_ACKNOWLEDGE, CONFIG_STATUS_DRIVER, CONFIG_STATUS_DRIVER_OK, CONFIG_STATUS_FAILED,
     CONFIG_STATUS_FEATURES_OK, QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING,
-    VIRTIO_F_RING_PACKED, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_NET,
+    VIRTIO_F_RING_PACKED, VIRTIO_MAX_QUEUES, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_NET,
 };
 
 const VIRTIO_QUEUE_MAX: u32 = 1024;
@@ -270,16 +271,16 @@ impl VirtioPciCommonConfig {
                 self.acked_features_select = value;
             }
             COMMON_GF_REG => {
-                device
-                    .lock()
-                    .unwrap()
-                    .set_driver_features(self.acked_features_select, value);
-
-                if self.acked_features_select == 1
-                    && virtio_has_feature(u64::from(value) << 32, VIRTIO_F_RING_PACKED)
-                {
-                    error!("Set packed virtqueue, which is not supported");
-                    self.queue_type = QUEUE_TYPE_PACKED_VRING;
+                let mut locked_device = device.lock().unwrap();
+                locked_device.set_driver_features(self.acked_features_select, value);
+
+                if self.acked_features_select == 1 {
+                    let features = u64::from(value & locked_device.get_device_features(1)) << 32;
+                    self.queue_type = if virtio_has_feature(features, VIRTIO_F_RING_PACKED) {
+                        QUEUE_TYPE_PACKED_VRING
+                    } else {
+                        QUEUE_TYPE_SPLIT_VRING
+                    };
                 }
             }
             COMMON_MSIX_REG => {
@@ -439,7 +440,7 @@ impl Clone for NotifyEventFds {
 /// The state of virtio-pci device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.3.0", compat_version = "0.3.0")]
 pub struct VirtioPciState {
     activated: bool,
     dev_id: u16,
@@ -457,10 +458,12 @@ pub struct VirtioPciState {
     config_generation: u32,
     queue_select: u16,
     msix_config: u16,
-    /// The configuration of queues. Max number of queues is 8.
-    queues_config: [QueueConfig; 8],
+    /// The configuration of queues. Max number of queues is `VIRTIO_MAX_QUEUES`.
+    queues_config: [QueueConfig; 64],
     /// The number of queues.
     queue_num: usize,
+    /// The type of queues, either be split ring or packed ring, since version 0.3.0.
+    queue_type: u16,
 }
 
 /// Virtio-PCI device structure
@@ -683,7 +686,9 @@ impl VirtioPciDevice {
                     .unwrap()
                     .queues_config;
                 let mut locked_queues = cloned_pci_device.queues.lock().unwrap();
-                for q_config in queues_config.iter() {
+                // The number of queues may depend on the negotiated features.
+                let queue_num = cloned_pci_device.device.lock().unwrap().queue_num();
+                for q_config in queues_config.iter().take(queue_num) {
                     let queue = Queue::new(*q_config, queue_type).unwrap();
                     if !queue.is_valid(&cloned_pci_device.sys_mem) {
                         error!("Failed to activate device: Invalid queue");
@@ -849,6 +854,14 @@ impl PciDevOps for VirtioPciDevice {
     }
 
     fn realize(mut self) -> PciResult<()> {
+        let queue_num = self.device.lock().unwrap().queue_num();
+        if queue_num > VIRTIO_MAX_QUEUES {
+            bail!(
+                "The number of queues {} exceeds the maximum {}",
+                queue_num,
+                VIRTIO_MAX_QUEUES
+            );
+        }
         self.init_write_mask()?;
         self.init_write_clear_mask()?;
 
@@ -965,6 +978,12 @@ impl PciDevOps for VirtioPciDevice {
                 pci_device.unwrap().lock().unwrap().name()
             );
         }
+        drop(locked_pci_bus);
+
+        if let Err(e) = handle_plug(&pci_bus, &(dev.clone() as Arc<Mutex<dyn PciDevOps>>)) {
+            pci_bus.lock().unwrap().devices.remove(&devfn);
+            return Err(e);
+        }
         MigrationManager::register_device_instance_mutex(VirtioPciState::descriptor(), dev);
 
         Ok(())
@@ -1019,6 +1038,42 @@ impl PciDevOps for VirtioPciDevice {
     fn name(&self) -> String {
         self.name.clone()
     }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn unrealize(&mut self) -> PciResult<()> {
+        if self.device_activated.load(Ordering::Acquire) {
+            self.device
+                .lock()
+                .unwrap()
+                .reset()
+                .chain_err(|| "Failed to reset virtio device")?;
+            self.queues.lock().unwrap().clear();
+            self.device_activated.store(false, Ordering::Release);
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .unrealize()
+            .chain_err(|| "Failed to unrealize virtio device")?;
+
+        // The removed device is not saved in snapshot.
+        MigrationManager::unregister_device_instance(&self.device);
+        if let Some(msix) = &self.config.msix {
+            MigrationManager::unregister_device_instance(msix);
+        }
+
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+        self.config.unregister_bars(
+            #[cfg(target_arch = "x86_64")]
+            &locked_parent_bus.io_region,
+            &locked_parent_bus.mem_region,
+        )?;
+        Ok(())
+    }
 }
 
 impl StateTransfer for VirtioPciDevice {
@@ -1050,6 +1105,7 @@ impl StateTransfer for VirtioPciDevice {
             state.device_status = common_config.device_status;
             state.config_generation = common_config.config_generation;
             state.queue_select = common_config.queue_select;
+            state.queue_type = common_config.queue_type;
         }
 
         // Save virtio pci state.
@@ -1093,6 +1149,7 @@ impl StateTransfer for VirtioPciDevice {
             common_config.device_status = pci_state.device_status;
             common_config.config_generation = pci_state.config_generation;
             common_config.queue_select = pci_state.queue_select;
+            common_config.queue_type = pci_state.queue_type;
         }
 
         // Set virtio pci state.
@@ -1123,18 +1180,20 @@ impl StateTransfer for VirtioPciDevice {
 
 impl MigrationHook for VirtioPciDevice {
     fn resume(&mut self) -> migration::errors::Result<()> {
-        if self.device_activated.load(Ordering::Relaxed) {
-            // Reregister ioevents for notifies.
-            let parent_bus = self.parent_bus.upgrade().unwrap();
-            let locked_parent_bus = parent_bus.lock().unwrap();
-            if let Err(e) = self.config.update_bar_mapping(
-                #[cfg(target_arch = "x86_64")]
-                &locked_parent_bus.io_region,
-                &locked_parent_bus.mem_region,
-            ) {
-                bail!("Failed to update bar, error is {}", e.display_chain());
-            }
+        // Map the BARs according to the restored config space, the ioevents for
+        // notifies are reregistered along with them.
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+        if let Err(e) = self.config.update_bar_mapping(
+            #[cfg(target_arch = "x86_64")]
+            &locked_parent_bus.io_region,
+            &locked_parent_bus.mem_region,
+        ) {
+            bail!("Failed to update bar, error is {}", e.display_chain());
+        }
+        drop(locked_parent_bus);
 
+        if self.device_activated.load(Ordering::Relaxed) {
             let queue_evts = self.notify_eventfds.clone().events;
             if let Some(cb) = self.interrupt_cb.clone() {
                 if let Err(e) = self.device.lock().unwrap().activate(